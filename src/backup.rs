@@ -0,0 +1,212 @@
+//! Backup files: every key and its value in one standalone file, to copy
+//! elsewhere and load back into a store
+//!
+//! Unlike a [snapshot](crate::snapshot), a backup doesn't depend on the WAL
+//! it was taken beside, so it can be loaded into any store. The format is
+//! binary, little-endian throughout:
+//!
+//! ```text
+//! magic     "RVBACKUP"
+//! version   u32   1
+//! count     u64
+//! entries   count x (key_len u32, key, value_len u64, value, expires_at u64)
+//! digest    u64   FNV-1a of every byte before it
+//! ```
+//!
+//! An `expires_at` of 0 means the key has no TTL. Keys are stored as the
+//! store holds them, namespace included, and their metadata isn't kept: a
+//! key loaded from a backup starts over at version 1.
+
+use crate::error::{RustVaultError, Result};
+use crate::snapshot::{self, DigestReader, DigestWriter, SnapshotEntry};
+use crate::store::FNV_OFFSET;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"RVBACKUP";
+
+/// Version of the format this build writes, and the only one it reads
+const VERSION: u32 = 1;
+
+/// One key in a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupEntry {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// Deadline in milliseconds since the epoch
+    pub expires_at: Option<u64>,
+}
+
+impl From<SnapshotEntry> for BackupEntry {
+    fn from(entry: SnapshotEntry) -> Self {
+        Self {
+            key: entry.key,
+            value: entry.value,
+            expires_at: entry.expires_at,
+        }
+    }
+}
+
+/// Write `entries` to a backup file at `path`
+///
+/// As with a snapshot, the file is written beside `path` and synced before
+/// being renamed over it, so a crash never leaves half a backup.
+pub fn write(path: &Path, entries: &[BackupEntry]) -> Result<()> {
+    snapshot::replace(path, |temp_path| write_file(temp_path, entries))
+}
+
+fn write_file(path: &Path, entries: &[BackupEntry]) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    let mut writer = DigestWriter {
+        inner: BufWriter::new(file),
+        digest: FNV_OFFSET,
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for entry in entries {
+        writer.write_all(&(entry.key.len() as u32).to_le_bytes())?;
+        writer.write_all(entry.key.as_bytes())?;
+        writer.write_all(&(entry.value.len() as u64).to_le_bytes())?;
+        writer.write_all(&entry.value)?;
+        writer.write_all(&entry.expires_at.unwrap_or(0).to_le_bytes())?;
+    }
+    let digest = writer.digest;
+    let mut inner = writer.inner;
+    inner.write_all(&digest.to_le_bytes())?;
+    inner.flush()?;
+    inner.get_ref().sync_all()
+}
+
+/// Read every entry of the backup file at `path`
+///
+/// The digest is checked before anything is returned, so a file that is
+/// truncated or damaged anywhere gives an error and no entries.
+pub fn read(path: &Path) -> Result<Vec<BackupEntry>> {
+    let file = File::open(path)?;
+    let mut remaining = file.metadata()?.len();
+    let mut reader = DigestReader {
+        inner: BufReader::new(file),
+        digest: FNV_OFFSET,
+    };
+    let mut take = |reader: &mut DigestReader<BufReader<File>>, len: u64| -> Result<Vec<u8>> {
+        // Checked against the file size so a corrupt length can't ask for
+        // more memory than the file could hold
+        if len > remaining {
+            return Err(corrupt("truncated"));
+        }
+        remaining -= len;
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    };
+    
+    if take(&mut reader, 8)? != MAGIC {
+        return Err(corrupt("not a backup file"));
+    }
+    let version = u32::from_le_bytes(take(&mut reader, 4)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(RustVaultError::Backup(format!(
+            "Backup file is version {}; this build reads version {}",
+            version, VERSION
+        )));
+    }
+    let count = u64_from(&take(&mut reader, 8)?);
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let key_len = u32::from_le_bytes(take(&mut reader, 4)?.try_into().unwrap());
+        let key = String::from_utf8(take(&mut reader, key_len as u64)?)
+            .map_err(|_| corrupt("key is not UTF-8"))?;
+        let value_len = u64_from(&take(&mut reader, 8)?);
+        let value = take(&mut reader, value_len)?;
+        let expires_at = u64_from(&take(&mut reader, 8)?);
+        entries.push(BackupEntry {
+            key,
+            value,
+            expires_at: (expires_at != 0).then_some(expires_at),
+        });
+    }
+    
+    let expected = reader.digest;
+    if u64_from(&take(&mut reader, 8)?) != expected {
+        return Err(corrupt("digest mismatch"));
+    }
+    if remaining != 0 {
+        return Err(corrupt("trailing bytes after the digest"));
+    }
+    Ok(entries)
+}
+
+fn u64_from(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn corrupt(reason: &str) -> RustVaultError {
+    RustVaultError::Backup(format!("Corrupt backup file: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    fn entries() -> Vec<BackupEntry> {
+        vec![
+            BackupEntry {
+                key: "key1".to_string(),
+                value: b"value1".to_vec(),
+                expires_at: None,
+            },
+            BackupEntry {
+                key: " app key2".to_string(),
+                value: vec![0, 1, 2, 255],
+                expires_at: Some(1_700_000_000_000),
+            },
+        ]
+    }
+    
+    #[test]
+    fn test_backup_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("backup");
+        write(&path, &entries()).unwrap();
+        assert_eq!(read(&path).unwrap(), entries());
+        
+        write(&path, &[]).unwrap();
+        assert!(read(&path).unwrap().is_empty());
+        assert!(!dir.path().join("backup.tmp").exists());
+    }
+    
+    #[test]
+    fn test_backup_rejects_torn_and_foreign_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("backup");
+        write(&path, &entries()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        
+        for len in [0, 7, 12, 30, bytes.len() - 9, bytes.len() - 1] {
+            std::fs::write(&path, &bytes[..len]).unwrap();
+            let err = read(&path).unwrap_err();
+            assert!(matches!(err, RustVaultError::Backup(_)), "{}", err);
+        }
+        
+        let mut flipped = bytes.clone();
+        flipped[30] ^= 1;
+        std::fs::write(&path, &flipped).unwrap();
+        assert!(matches!(read(&path), Err(RustVaultError::Backup(_))));
+        
+        let mut newer = bytes;
+        newer[8] = 2;
+        std::fs::write(&path, &newer).unwrap();
+        let err = read(&path).unwrap_err();
+        assert!(err.to_string().contains("version 2"), "{}", err);
+        
+        snapshot::write(&path, crate::wal::Checkpoint::START, &[]).unwrap();
+        assert!(matches!(read(&path), Err(RustVaultError::Backup(_))));
+    }
+}
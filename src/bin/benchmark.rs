@@ -2,7 +2,13 @@
 //! 
 //! Tests latency and throughput under various load conditions
 
-use rustvault::Client;
+use rustvault::store::HasherKind;
+use rustvault::wal::WriteAheadLog;
+use rustvault::{
+    Client, Command, MemoryStore, Pipeline, Response, RustVaultServer, ServerConfig, ShardedMemoryStore, Store, SyncPolicy,
+    WalFormat,
+};
+use std::hash::BuildHasher;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Semaphore;
@@ -56,6 +62,28 @@ impl BenchmarkResults {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // `benchmark hashers` runs the embedded store microbenchmarks without a server
+    if std::env::args().nth(1).as_deref() == Some("hashers") {
+        return run_hasher_benchmarks().await;
+    }
+    // `benchmark shards` compares a single-lock store with a sharded one
+    if std::env::args().nth(1).as_deref() == Some("shards") {
+        return run_shard_benchmarks().await;
+    }
+    // `benchmark wal` compares appending to a JSON and a binary WAL
+    if std::env::args().nth(1).as_deref() == Some("wal") {
+        return run_wal_format_benchmarks().await;
+    }
+    // `benchmark group-commit` compares WAL-bound SETs with and without a
+    // group commit delay
+    if std::env::args().nth(1).as_deref() == Some("group-commit") {
+        return run_group_commit_benchmarks().await;
+    }
+    // `benchmark large` measures the memory a server needs to take large SETs
+    if std::env::args().nth(1).as_deref() == Some("large") {
+        return run_large_value_benchmarks().await;
+    }
+    
     let server_addr = "127.0.0.1:8080";
     
     println!("RustVault Performance Benchmarks");
@@ -66,7 +94,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Wait for server to be ready
     println!("Waiting for server to be ready...");
     loop {
-        if let Ok(mut client) = Client::connect(server_addr).await {
+        if let Ok(client) = Client::connect(server_addr).await {
             let _ = client.close().await;
             break;
         }
@@ -89,6 +117,12 @@ async fn run_single_client_benchmarks(server_addr: &str) -> Result<(), Box<dyn s
     let set_results = benchmark_set_operations(server_addr, 10000).await?;
     set_results.print();
     
+    // Pipelined SET benchmark, for comparison with the one above
+    for batch_size in [10, 100] {
+        let pipelined_results = benchmark_pipelined_set_operations(server_addr, 10000, batch_size).await?;
+        pipelined_results.print();
+    }
+    
     // GET benchmark
     let get_results = benchmark_get_operations(server_addr, 10000).await?;
     get_results.print();
@@ -112,6 +146,278 @@ async fn run_concurrent_benchmarks(server_addr: &str) -> Result<(), Box<dyn std:
     Ok(())
 }
 
+async fn run_hasher_benchmarks() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Running embedded store hasher benchmarks...");
+    
+    let num_keys = 100_000;
+    benchmark_store_lookups("SipHash", MemoryStore::new(), num_keys).await?.print();
+    
+    #[cfg(feature = "ahash")]
+    benchmark_store_lookups("aHash", rustvault::store::AHashMemoryStore::default(), num_keys).await?.print();
+    
+    #[cfg(feature = "fxhash")]
+    benchmark_store_lookups("FxHash", rustvault::store::FxMemoryStore::default(), num_keys).await?.print();
+    
+    // The server's store picks its hasher at runtime, at a branch per hash
+    let kinds = [
+        HasherKind::SipHash,
+        #[cfg(feature = "ahash")]
+        HasherKind::AHash,
+        #[cfg(feature = "fxhash")]
+        HasherKind::FxHash,
+    ];
+    for kind in kinds {
+        let name = format!("{:?} (configured)", kind);
+        benchmark_store_lookups(&name, MemoryStore::with_hasher(kind.build()), num_keys).await?.print();
+    }
+    
+    Ok(())
+}
+
+async fn run_shard_benchmarks() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Running embedded store sharding benchmarks...");
+    
+    let (num_tasks, ops_per_task) = (100, 10_000);
+    benchmark_concurrent_store_sets("single lock", Arc::new(MemoryStore::new()), num_tasks, ops_per_task)
+        .await?
+        .print();
+    let sharded = ShardedMemoryStore::new();
+    let name = format!("{} shards", sharded.shards());
+    benchmark_concurrent_store_sets(&name, Arc::new(sharded), num_tasks, ops_per_task)
+        .await?
+        .print();
+    
+    Ok(())
+}
+
+async fn run_wal_format_benchmarks() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Running WAL format benchmarks...");
+    
+    for format in [WalFormat::Json, WalFormat::Binary] {
+        benchmark_wal_appends(format, 100_000).await?.print();
+    }
+    
+    Ok(())
+}
+
+async fn run_group_commit_benchmarks() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Running WAL group commit benchmarks...");
+    
+    let (num_tasks, ops_per_task) = (100, 200);
+    for delay in [Duration::ZERO, Duration::from_millis(1)] {
+        let path = std::env::temp_dir().join(format!("rustvault-bench-{}-group.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let wal = WriteAheadLog::new(&path, SyncPolicy::Always)?.with_group_delay(delay);
+        let store = ShardedMemoryStore::with_wal(Arc::new(wal), 0);
+        let name = format!("synced WAL, {:?} group delay", delay);
+        benchmark_concurrent_store_sets(&name, Arc::new(store), num_tasks, ops_per_task)
+            .await?
+            .print();
+        std::fs::remove_file(&path)?;
+    }
+    
+    Ok(())
+}
+
+async fn run_large_value_benchmarks() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Running large value benchmarks...");
+    
+    for format in [WalFormat::Json, WalFormat::Binary] {
+        for size in [16 * 1024 * 1024, 64 * 1024 * 1024] {
+            benchmark_large_sets(format, size, 4).await?.print();
+        }
+    }
+    
+    Ok(())
+}
+
+/// Peak resident set size of this process in bytes since it was last
+/// reset, where the OS reports it
+fn peak_rss() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Start [`peak_rss`] over from the current RSS
+fn reset_peak_rss() {
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
+
+/// SETs of `size`-byte binary values to an in-process server logging in
+/// `format`, each to its own key
+///
+/// The label reports how far the process's peak RSS rose past what the
+/// stored values themselves need, in copies of one value: every copy the
+/// read path makes of a value shows up here. The client shares the
+/// process, so its encoded request is counted too, as is the WAL record,
+/// which in JSON is several times the size of a binary value.
+async fn benchmark_large_sets(
+    format: WalFormat,
+    size: usize,
+    num_operations: usize,
+) -> Result<BenchmarkResults, Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("rustvault-bench-{}-large-{:?}.log", std::process::id(), format));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        wal_path: path.to_string_lossy().to_string(),
+        wal_sync: SyncPolicy::Never,
+        wal_format: format,
+        max_value_bytes: size,
+        // A compaction would copy every value, which isn't what's measured
+        compaction_threshold_bytes: None,
+        ..Default::default()
+    };
+    let server = Arc::new(RustVaultServer::new(config).await?);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let task = {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    
+    let mut client = Client::connect(&addr).await?;
+    let value: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    reset_peak_rss();
+    let baseline = peak_rss();
+    
+    let mut latencies = Vec::with_capacity(num_operations);
+    let start = Instant::now();
+    for i in 0..num_operations {
+        let op_start = Instant::now();
+        client.set_bytes(&format!("large_bench_key_{}", i), &value).await?;
+        latencies.push(op_start.elapsed());
+    }
+    let total_duration = start.elapsed();
+    
+    let overhead = match (baseline, peak_rss()) {
+        (Some(before), Some(after)) => {
+            let extra = after.saturating_sub(before).saturating_sub(size * num_operations);
+            format!("{:.1} extra copies at peak", extra as f64 / size as f64)
+        }
+        _ => "peak RSS not available".to_string(),
+    };
+    client.close().await?;
+    server.shutdown()?;
+    let _ = task.await;
+    std::fs::remove_file(&path)?;
+    
+    Ok(BenchmarkResults::new(
+        format!("Large SET ({:?}, {} MiB, {})", format, size / (1024 * 1024), overhead),
+        num_operations,
+        total_duration,
+        &mut latencies,
+    ))
+}
+
+/// Appends of a SET with a 256-byte binary value to a fresh WAL in `format`
+///
+/// The log never syncs, so this measures encoding and the write to the OS.
+async fn benchmark_wal_appends(
+    format: WalFormat,
+    num_operations: usize,
+) -> Result<BenchmarkResults, Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("rustvault-bench-{}-{:?}.log", std::process::id(), format));
+    let _ = std::fs::remove_file(&path);
+    let wal = WriteAheadLog::new(&path, SyncPolicy::Never)?.with_format(format)?;
+    let value: Vec<u8> = (0..=255).collect();
+    
+    let mut latencies = Vec::with_capacity(num_operations);
+    let start = Instant::now();
+    
+    for i in 0..num_operations {
+        let command = Command::Set {
+            key: format!("wal_bench_key_{}", i),
+            value: value.clone(),
+            condition: None,
+        };
+        let op_start = Instant::now();
+        wal.log_command(command).await?;
+        latencies.push(op_start.elapsed());
+    }
+    
+    let total_duration = start.elapsed();
+    let size = wal.size();
+    drop(wal);
+    std::fs::remove_file(&path)?;
+    
+    Ok(BenchmarkResults::new(
+        format!("WAL append ({:?}, {:.1} MiB on disk)", format, size as f64 / (1024.0 * 1024.0)),
+        num_operations,
+        total_duration,
+        &mut latencies,
+    ))
+}
+
+/// SETs to `store` from `num_tasks` tasks at once, each writing its own keys
+async fn benchmark_concurrent_store_sets<S: Store + 'static>(
+    store_name: &str,
+    store: Arc<S>,
+    num_tasks: usize,
+    ops_per_task: usize,
+) -> Result<BenchmarkResults, Box<dyn std::error::Error>> {
+    let mut handles = Vec::with_capacity(num_tasks);
+    let start = Instant::now();
+    
+    for task_id in 0..num_tasks {
+        let store = Arc::clone(&store);
+        handles.push(tokio::spawn(async move {
+            let mut latencies = Vec::with_capacity(ops_per_task);
+            for i in 0..ops_per_task {
+                let key = format!("shard_bench_key_{}_{}", task_id, i);
+                let op_start = Instant::now();
+                store.set(key, b"shard_bench_value".to_vec()).await?;
+                latencies.push(op_start.elapsed());
+            }
+            Ok::<Vec<Duration>, rustvault::RustVaultError>(latencies)
+        }));
+    }
+    
+    let mut all_latencies = Vec::with_capacity(num_tasks * ops_per_task);
+    for handle in handles {
+        all_latencies.extend(handle.await??);
+    }
+    let total_duration = start.elapsed();
+    
+    Ok(BenchmarkResults::new(
+        format!("Embedded concurrent SET ({}, {} tasks)", store_name, num_tasks),
+        num_tasks * ops_per_task,
+        total_duration,
+        &mut all_latencies,
+    ))
+}
+
+async fn benchmark_store_lookups<S: BuildHasher + Clone + Send + Sync + 'static>(
+    hasher_name: &str,
+    store: MemoryStore<S>,
+    num_keys: usize,
+) -> Result<BenchmarkResults, Box<dyn std::error::Error>> {
+    for i in 0..num_keys {
+        store.set(format!("hash_bench_key_{}", i), format!("hash_bench_value_{}", i).into_bytes()).await?;
+    }
+    
+    let mut latencies = Vec::with_capacity(num_keys);
+    let start = Instant::now();
+    
+    for i in 0..num_keys {
+        let key = format!("hash_bench_key_{}", i);
+        
+        let op_start = Instant::now();
+        let _value = store.get(&key).await?;
+        latencies.push(op_start.elapsed());
+    }
+    
+    let total_duration = start.elapsed();
+    
+    Ok(BenchmarkResults::new(
+        format!("Embedded GET ({})", hasher_name),
+        num_keys,
+        total_duration,
+        &mut latencies,
+    ))
+}
+
 async fn benchmark_set_operations(server_addr: &str, num_operations: usize) -> Result<BenchmarkResults, Box<dyn std::error::Error>> {
     let mut client = Client::connect(server_addr).await?;
     let mut latencies = Vec::with_capacity(num_operations);
@@ -140,6 +446,44 @@ async fn benchmark_set_operations(server_addr: &str, num_operations: usize) -> R
     ))
 }
 
+/// SETs sent `batch_size` per round trip; latencies are per batch
+async fn benchmark_pipelined_set_operations(
+    server_addr: &str,
+    num_operations: usize,
+    batch_size: usize,
+) -> Result<BenchmarkResults, Box<dyn std::error::Error>> {
+    let mut client = Client::connect(server_addr).await?;
+    let mut latencies = Vec::with_capacity(num_operations / batch_size + 1);
+    
+    let start = Instant::now();
+    
+    for batch_start in (0..num_operations).step_by(batch_size) {
+        let mut pipeline = Pipeline::new();
+        for i in batch_start..(batch_start + batch_size).min(num_operations) {
+            pipeline.set(&format!("pipeline_key_{}", i), format!("pipeline_value_{}", i));
+        }
+        
+        let op_start = Instant::now();
+        let responses = pipeline.execute(&mut client).await?;
+        let op_duration = op_start.elapsed();
+        
+        if let Some(failure) = responses.iter().find(|response| **response != Response::Ok) {
+            return Err(format!("Pipelined SET failed: {:?}", failure).into());
+        }
+        latencies.push(op_duration);
+    }
+    
+    let total_duration = start.elapsed();
+    client.close().await?;
+    
+    Ok(BenchmarkResults::new(
+        format!("Pipelined SET (batches of {})", batch_size),
+        num_operations,
+        total_duration,
+        &mut latencies,
+    ))
+}
+
 async fn benchmark_get_operations(server_addr: &str, num_operations: usize) -> Result<BenchmarkResults, Box<dyn std::error::Error>> {
     // First, populate the store with data
     let mut setup_client = Client::connect(server_addr).await?;
@@ -1,26 +1,158 @@
 //! Standalone client binary for testing RustVault server
 //! 
-//! Provides a command-line interface for interacting with the server
+//! Provides a command-line interface for interacting with the server.
+//! Several servers can be open at once; meta-commands starting with `\`
+//! manage the connections and commands go to the active one. Words after
+//! the address are run as one command instead, without the prompt:
+//!
+//! ```text
+//! client 127.0.0.1:8080 backup /var/backups/vault.backup
+//! ```
+//!
+//! `dump` and `load` copy keys between servers as `key<TAB>value` lines:
+//!
+//! ```text
+//! client 127.0.0.1:8080 dump user: > users.tsv
+//! client 127.0.0.1:9090 load users.tsv
+//! ```
 
-use rustvault::Client;
+use rustvault::client::split_command_line;
+use rustvault::{Client, LoadReport, Pipeline, RawResponse, Response, RustVaultError};
 use std::env;
-use std::io::{self, Write};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::{Duration, Instant};
+
+/// SETs `load` sends per pipeline
+const LOAD_BATCH: usize = 500;
+
+/// An open connection and the name it was registered under
+struct Connection<C> {
+    name: String,
+    addr: String,
+    client: C,
+}
+
+/// Open connections in the order they were made, one of them active
+struct Registry<C> {
+    connections: Vec<Connection<C>>,
+    active: usize,
+}
+
+impl<C> Registry<C> {
+    /// Start with a single active connection
+    fn new(name: String, addr: String, client: C) -> Self {
+        Self {
+            connections: vec![Connection { name, addr, client }],
+            active: 0,
+        }
+    }
+    
+    /// Register a new connection and make it active
+    fn add(&mut self, name: String, addr: String, client: C) {
+        self.connections.push(Connection { name, addr, client });
+        self.active = self.connections.len() - 1;
+    }
+    
+    /// Make the connection called `name` active, if there is one
+    fn switch(&mut self, name: &str) -> bool {
+        match self.connections.iter().position(|c| c.name == name) {
+            Some(index) => {
+                self.active = index;
+                true
+            }
+            None => false,
+        }
+    }
+    
+    fn active(&self) -> &Connection<C> {
+        &self.connections[self.active]
+    }
+    
+    fn active_mut(&mut self) -> &mut Connection<C> {
+        &mut self.connections[self.active]
+    }
+    
+    /// One line per connection, the active one marked with `*`
+    fn list(&self) -> Vec<String> {
+        let width = self.connections.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        self.connections
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let marker = if i == self.active { '*' } else { ' ' };
+                format!("{} {:width$}  {}", marker, c.name, c.addr, width = width)
+            })
+            .collect()
+    }
+}
+
+/// REPL commands that act on the connections rather than a server
+#[derive(Debug, PartialEq)]
+enum Meta<'a> {
+    /// `\connect <addr> [name]`; the name defaults to the address
+    Connect { addr: &'a str, name: &'a str },
+    /// `\list`
+    List,
+    /// `\use <name>`
+    Use(&'a str),
+    /// `\all <command>`
+    All(&'a str),
+}
+
+/// Parse a `\`-prefixed meta-command; `None` if `input` isn't one
+fn parse_meta(input: &str) -> Option<Result<Meta<'_>, String>> {
+    let rest = input.strip_prefix('\\')?;
+    let (name, args) = match rest.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (rest, ""),
+    };
+    let words: Vec<&str> = args.split_whitespace().collect();
+    
+    Some(match (name, words.as_slice()) {
+        ("connect", [addr]) => Ok(Meta::Connect { addr, name: addr }),
+        ("connect", [addr, name]) => Ok(Meta::Connect { addr, name }),
+        ("connect", _) => Err("Usage: \\connect <addr> [name]".to_string()),
+        ("list", []) => Ok(Meta::List),
+        ("list", _) => Err("Usage: \\list".to_string()),
+        ("use", [name]) => Ok(Meta::Use(name)),
+        ("use", _) => Err("Usage: \\use <name>".to_string()),
+        ("all", [_, ..]) => Ok(Meta::All(args)),
+        ("all", []) => Err("Usage: \\all <command>".to_string()),
+        _ => Err(format!("Unknown meta-command: \\{}. Type 'help' for available commands.", name)),
+    })
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
     let server_addr = args.get(1).unwrap_or(&"127.0.0.1:8080".to_string()).clone();
     
+    if args.len() > 2 {
+        let mut client = Client::connect(&server_addr).await?;
+        let output = handle_command(&mut client, &args[2..].join(" ")).await;
+        let _ = client.close().await;
+        let output = output?;
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+        return Ok(());
+    }
+    
     println!("Connecting to RustVault server at {}...", server_addr);
-    let mut client = Client::connect(&server_addr).await?;
+    let client = Client::connect(&server_addr).await?;
     println!("Connected! Type 'help' for available commands or 'quit' to exit.");
+    let mut registry = Registry::new(server_addr.clone(), server_addr, client);
     
     loop {
-        print!("> ");
+        print!("{}> ", registry.active().name);
         io::stdout().flush()?;
         
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            // End of input
+            break;
+        }
         let input = input.trim();
         
         if input.is_empty() {
@@ -35,74 +167,452 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "help" => {
                 print_help();
             }
-            _ => {
-                if let Err(e) = handle_command(&mut client, input).await {
-                    println!("Error: {}", e);
-                }
-            }
+            _ => match parse_meta(input) {
+                Some(Ok(meta)) => handle_meta(&mut registry, meta).await,
+                Some(Err(usage)) => println!("{}", usage),
+                None => match handle_command(&mut registry.active_mut().client, input).await {
+                    Ok(output) if output.is_empty() => {}
+                    Ok(output) => println!("{}", output),
+                    Err(e) => println!("Error: {}", e),
+                },
+            },
         }
     }
     
-    client.close().await?;
+    for connection in registry.connections {
+        let _ = connection.client.close().await;
+    }
     Ok(())
 }
 
-async fn handle_command(client: &mut Client, input: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let parts: Vec<&str> = input.split_whitespace().collect();
+async fn handle_meta(registry: &mut Registry<Client>, meta: Meta<'_>) {
+    match meta {
+        Meta::Connect { addr, name } => {
+            if registry.switch(name) {
+                println!("Switched to {}", name);
+                return;
+            }
+            match Client::connect(addr).await {
+                Ok(client) => {
+                    registry.add(name.to_string(), addr.to_string(), client);
+                    println!("Connected to {} as {}", addr, name);
+                }
+                Err(e) => println!("Error: failed to connect to {}: {}", addr, e),
+            }
+        }
+        Meta::List => {
+            for line in registry.list() {
+                println!("{}", line);
+            }
+        }
+        Meta::Use(name) => {
+            if !registry.switch(name) {
+                println!("No connection named {}. Use \\list to see open connections.", name);
+            }
+        }
+        Meta::All(command) => {
+            // Each server answers on its own; one failing doesn't stop the rest
+            for connection in &mut registry.connections {
+                let output = match handle_command(&mut connection.client, command).await {
+                    Ok(output) => output,
+                    Err(e) => format!("Error: {}", e),
+                };
+                for line in output.lines() {
+                    println!("[{}] {}", connection.name, line);
+                }
+            }
+        }
+    }
+}
+
+async fn handle_command(client: &mut Client, input: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let parts = split_command_line(input)?;
+    let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
     
-    match parts.get(0) {
+    let output = match parts.first() {
         Some(&"set") => {
-            if parts.len() != 3 {
-                println!("Usage: set <key> <value>");
-                return Ok(());
+            if parts.len() < 3 {
+                return Ok("Usage: set <key> <value>".to_string());
             }
             
+            // Unquoted words are joined with single spaces; quote the value
+            // to keep its exact whitespace
             let key = parts[1];
-            let value = parts[2];
+            let value = parts[2..].join(" ");
             
-            client.set(key, value).await?;
-            println!("OK");
+            client.set(key, &value).await?;
+            "OK".to_string()
         }
         Some(&"get") => {
             if parts.len() != 2 {
-                println!("Usage: get <key>");
-                return Ok(());
+                return Ok("Usage: get <key>".to_string());
             }
             
             let key = parts[1];
             
             match client.get(key).await? {
-                Some(value) => println!("{}", value),
-                None => println!("(nil)"),
+                Some(value) => value,
+                None => "(nil)".to_string(),
+            }
+        }
+        Some(&"getset") => {
+            if parts.len() < 3 {
+                return Ok("Usage: getset <key> <value>".to_string());
+            }
+            
+            let value = parts[2..].join(" ");
+            match client.getset(parts[1], value.as_bytes()).await? {
+                Some(old) => String::from_utf8_lossy(&old).into_owned(),
+                None => "(nil)".to_string(),
+            }
+        }
+        Some(&"getdel") => {
+            if parts.len() != 2 {
+                return Ok("Usage: getdel <key>".to_string());
+            }
+            
+            match client.getdel(parts[1]).await? {
+                Some(old) => String::from_utf8_lossy(&old).into_owned(),
+                None => "(nil)".to_string(),
+            }
+        }
+        Some(&"lock") => {
+            let ttl = match (parts.len(), parts.get(3).and_then(|ms| ms.parse().ok())) {
+                (4, Some(ms)) => Duration::from_millis(ms),
+                _ => return Ok("Usage: lock <key> <token> <ttl-ms>".to_string()),
+            };
+            
+            if client.try_lock(parts[1], parts[2], ttl).await? {
+                "OK".to_string()
+            } else {
+                "(locked)".to_string()
+            }
+        }
+        Some(&"unlock") => {
+            if parts.len() != 3 {
+                return Ok("Usage: unlock <key> <token>".to_string());
+            }
+            
+            if client.unlock(parts[1], parts[2]).await? {
+                "OK".to_string()
+            } else {
+                "(not held)".to_string()
+            }
+        }
+        Some(&"exists") => {
+            if parts.len() != 2 {
+                return Ok("Usage: exists <key>".to_string());
+            }
+            
+            let key = parts[1];
+            
+            if client.exists(key).await? {
+                "(integer) 1".to_string()
+            } else {
+                "(integer) 0".to_string()
             }
         }
         Some(&"delete") | Some(&"del") => {
             if parts.len() != 2 {
-                println!("Usage: delete <key>");
-                return Ok(());
+                return Ok("Usage: delete <key>".to_string());
             }
             
             let key = parts[1];
             
             if client.delete(key).await? {
-                println!("OK");
+                "OK".to_string()
             } else {
-                println!("Key not found");
+                "Key not found".to_string()
+            }
+        }
+        Some(&"backup") => {
+            if parts.len() != 2 {
+                return Ok("Usage: backup <server path>".to_string());
+            }
+            
+            format!("Backed up {} keys", client.backup(parts[1]).await?)
+        }
+        Some(&"restore") => {
+            let (path, merge) = match parts[1..] {
+                [path] => (path, false),
+                [path, "--merge"] | ["--merge", path] => (path, true),
+                _ => return Ok("Usage: restore <server path> [--merge]".to_string()),
+            };
+            
+            format!("Restored {} keys", client.restore(path, merge).await?)
+        }
+        Some(&"dump") => {
+            let (prefix, cursor) = match parts[1..] {
+                [] => ("", "0"),
+                [prefix] => (prefix, "0"),
+                ["--from", cursor] => ("", cursor),
+                [prefix, "--from", cursor] => (prefix, cursor),
+                _ => return Ok("Usage: dump [prefix] [--from <cursor>]".to_string()),
+            };
+            let Ok(cursor) = cursor.parse() else {
+                return Ok("Usage: dump [prefix] [--from <cursor>]".to_string());
+            };
+            
+            // The pairs are the output; the count goes to stderr so it
+            // doesn't end up in a redirected dump
+            let dumped = dump(client, prefix, cursor, &mut io::stdout().lock()).await?;
+            eprintln!("Dumped {} keys", dumped);
+            String::new()
+        }
+        Some(&"load") => {
+            let report = match parts[1..] {
+                [] => load(client, io::stdin().lock()).await?,
+                [path] => load(client, BufReader::new(File::open(path)?)).await?,
+                _ => return Ok("Usage: load [file]".to_string()),
+            };
+            
+            for (key, e) in &report.failed {
+                eprintln!("Failed to load {}: {}", key, e);
+            }
+            match report.failed.len() {
+                0 => format!("Loaded {} keys", report.loaded),
+                failed => format!("Loaded {} keys, {} failed", report.loaded, failed),
             }
         }
         _ => {
-            println!("Unknown command: {}. Type 'help' for available commands.", parts[0]);
+            // Not one of ours; let the server decide what it means
+            format_raw(client.execute_raw(&parts).await?)
         }
-    }
+    };
     
-    Ok(())
+    Ok(output)
+}
+
+/// Write every key starting with `prefix` to `out` as a dump line, from
+/// `cursor` on, returning how many were written
+///
+/// An interrupted scan fails with the cursor to pass as `--from` to finish
+/// the dump.
+async fn dump(client: &mut Client, prefix: &str, cursor: u64, out: &mut impl Write) -> rustvault::Result<usize> {
+    let mut pairs = client.scan_stream_from(prefix, cursor);
+    let mut dumped = 0;
+    while let Some(pair) = pairs.next().await {
+        let (key, value) = pair?;
+        writeln!(out, "{}\t{}", escape_field(&key), escape_field(&value))?;
+        dumped += 1;
+    }
+    out.flush()?;
+    Ok(dumped)
+}
+
+/// SET every pair in a dump read from `input`, [`LOAD_BATCH`] to a pipeline
+///
+/// A line that isn't a dump line stops the load, leaving the batches before
+/// it loaded.
+async fn load(client: &mut Client, input: impl BufRead) -> Result<LoadReport, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let mut loaded = 0;
+    let mut failed = Vec::new();
+    let mut batch = Vec::with_capacity(LOAD_BATCH);
+    let mut lines = input.lines().enumerate().peekable();
+    while let Some((number, line)) = lines.next() {
+        let line = line?;
+        match parse_dump_line(&line) {
+            Some(pair) => batch.push(pair),
+            None => return Err(format!("line {} is not a dump line", number + 1).into()),
+        }
+        
+        if batch.len() == LOAD_BATCH || lines.peek().is_none() {
+            let mut pipeline = Pipeline::new();
+            for (key, value) in &batch {
+                pipeline.set(key, value);
+            }
+            for ((key, _), response) in batch.drain(..).zip(pipeline.execute(client).await?) {
+                match response {
+                    Response::Ok => loaded += 1,
+                    Response::Error(e) => failed.push((key, RustVaultError::from_reply(e))),
+                    other => return Err(format!("unexpected reply to SET {}: {:?}", key, other).into()),
+                }
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    let rate = loaded as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    Ok(LoadReport { loaded, failed, unsent: Vec::new(), elapsed, rate })
+}
+
+/// Escape a key or value for a dump line, so it holds no tab or line break
+fn escape_field(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo [`escape_field`]; `None` for an escape it wouldn't have written
+fn unescape_field(text: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(unescaped)
+}
+
+/// The key and value of a `key<TAB>value` dump line
+fn parse_dump_line(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.split_once('\t')?;
+    Some((unescape_field(key)?, unescape_field(value)?))
+}
+
+/// Render a reply to a command the client passes through as it is
+fn format_raw(response: RawResponse) -> String {
+    match response {
+        RawResponse::Ok => "OK".to_string(),
+        RawResponse::Value(value) => String::from_utf8_lossy(&value).into_owned(),
+        RawResponse::Integer(n) => format!("(integer) {}", n),
+        RawResponse::NotFound => "(nil)".to_string(),
+        RawResponse::Conflict => "(conflict)".to_string(),
+        RawResponse::NotApplied => "(not applied)".to_string(),
+        RawResponse::Pong => "PONG".to_string(),
+        RawResponse::Hello(version) => format!("protocol {}", version),
+        RawResponse::Stat(stat) => format!(
+            "version {}, created {}, updated {}",
+            stat.version, stat.created_at, stat.updated_at
+        ),
+        RawResponse::Compacted(report) => format!(
+            "compacted {} -> {} bytes, {} keys",
+            report.before, report.after, report.keys
+        ),
+        RawResponse::Queued => "QUEUED".to_string(),
+        RawResponse::Results(responses) if responses.is_empty() => "(empty list)".to_string(),
+        RawResponse::Results(responses) => responses
+            .into_iter()
+            .enumerate()
+            .map(|(i, response)| format!("{}) {}", i + 1, format_raw(response)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        RawResponse::Error { code: Some(code), message } => {
+            format!("(error) {} {}", code, message)
+        }
+        RawResponse::Error { code: None, message } => format!("(error) {}", message),
+        RawResponse::Values(values) if values.is_empty() => "(empty list)".to_string(),
+        RawResponse::Values(values) => values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| match value {
+                Some(value) => format!("{}) {}", i + 1, String::from_utf8_lossy(value)),
+                None => format!("{}) (nil)", i + 1),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        RawResponse::Info(fields) => fields
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        RawResponse::Keys { keys, cursor } => {
+            let mut lines: Vec<String> = keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| format!("{}) {}", i + 1, key))
+                .collect();
+            lines.push(format!("(next cursor) {}", cursor));
+            lines.join("\n")
+        }
+    }
 }
 
 fn print_help() {
     println!("Available commands:");
-    println!("  set <key> <value>  - Set a key-value pair");
+    println!("  set <key> <value>  - Set a key-value pair (quote to keep spacing; \\n, \\t escapes)");
     println!("  get <key>          - Get value by key");
+    println!("  getset <key> <value> - Set a key and show the value it replaced");
+    println!("  getdel <key>       - Delete a key and show the value it held");
+    println!("  lock <key> <token> <ttl-ms> - Take a lock, or renew it, with <token>");
+    println!("  unlock <key> <token> - Release a lock held with <token>");
+    println!("  exists <key>       - Check whether a key holds a value");
     println!("  delete <key>       - Delete a key");
+    println!("  backup <path>      - Have the server write a backup file at <path>, on its host");
+    println!("  restore <path> [--merge] - Load a backup file on the server's host into it");
+    println!("  dump [prefix] [--from <cursor>] - Print keys and values as key<TAB>value lines");
+    println!("  load [file]        - Set the keys in a dump read from <file> or standard input");
+    println!("  <COMMAND> [args]   - Send any other command to the server as-is");
     println!("  help               - Show this help message");
     println!("  quit               - Exit the client");
+    println!();
+    println!("Connections:");
+    println!("  \\connect <addr> [name] - Open (or switch to) a connection");
+    println!("  \\list                  - Show open connections");
+    println!("  \\use <name>            - Switch the active connection");
+    println!("  \\all <command>         - Run a command on every connection");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_parse_meta() {
+        assert_eq!(parse_meta("get key"), None);
+        assert_eq!(
+            parse_meta("\\connect 10.0.0.2:8080 replica"),
+            Some(Ok(Meta::Connect { addr: "10.0.0.2:8080", name: "replica" }))
+        );
+        assert_eq!(
+            parse_meta("\\connect 10.0.0.2:8080"),
+            Some(Ok(Meta::Connect { addr: "10.0.0.2:8080", name: "10.0.0.2:8080" }))
+        );
+        assert_eq!(parse_meta("\\list"), Some(Ok(Meta::List)));
+        assert_eq!(parse_meta("\\use primary"), Some(Ok(Meta::Use("primary"))));
+        assert_eq!(
+            parse_meta("\\all  set k \"a b\""),
+            Some(Ok(Meta::All("set k \"a b\"")))
+        );
+        assert!(matches!(parse_meta("\\use"), Some(Err(_))));
+        assert!(matches!(parse_meta("\\all"), Some(Err(_))));
+        assert!(matches!(parse_meta("\\frob"), Some(Err(_))));
+    }
+    
+    #[test]
+    fn test_dump_lines_roundtrip() {
+        for (key, value) in [("k", "plain"), ("a:b", "tab\there"), ("k", "two\nlines\r\n"), ("k", "back\\t\\"), ("k", "")] {
+            let line = format!("{}\t{}", escape_field(key), escape_field(value));
+            assert!(!line.contains('\n') && line.matches('\t').count() == 1, "{:?}", line);
+            assert_eq!(parse_dump_line(&line), Some((key.to_string(), value.to_string())));
+        }
+        assert_eq!(escape_field("a\tb\\n"), "a\\tb\\\\n");
+        assert_eq!(parse_dump_line("no tab"), None);
+        assert_eq!(parse_dump_line("k\tbad \\x escape"), None);
+        assert_eq!(parse_dump_line("k\ttrailing \\"), None);
+    }
+    
+    #[test]
+    fn test_registry_switching() {
+        let mut registry = Registry::new("primary".to_string(), "a:1".to_string(), 1);
+        registry.add("replica".to_string(), "b:2".to_string(), 2);
+        
+        // Adding makes the new connection active
+        assert_eq!(registry.active().name, "replica");
+        assert_eq!(registry.active().client, 2);
+        
+        assert!(registry.switch("primary"));
+        assert_eq!(registry.active().addr, "a:1");
+        assert!(!registry.switch("missing"));
+        assert_eq!(registry.active().name, "primary");
+
+        assert_eq!(registry.list(), vec!["* primary  a:1", "  replica  b:2"]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,116 @@
+//! Recovery consistency checker for RustVault
+//!
+//! Replays a WAL into a fresh keyspace, rebuilds the store the way the server
+//! does on startup, and optionally compares both against a live server.
+//!
+//! Usage: rustvault-check <wal-path-or-data-dir> [server-addr]
+//!        rustvault-check verify-replica <primary-addr> <replica-addr>
+//!
+//! `verify-replica` compares two live servers by checksum and lists the keys
+//! that differ, without copying either dataset.
+//!
+//! Exits with 0 when everything agrees, 1 when divergences were found and 2
+//! when the check itself failed.
+
+use rustvault::recovery::{self, Divergence, Keyspace};
+use rustvault::{Client, Store};
+use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("verify-replica") => match (args.get(2), args.get(3)) {
+            (Some(primary), Some(replica)) => verify_replica(primary, replica).await,
+            _ => {
+                eprintln!("Usage: rustvault-check verify-replica <primary-addr> <replica-addr>");
+                return ExitCode::from(2);
+            }
+        },
+        Some(path) => run(wal_path(path), args.get(2).map(String::as_str)).await,
+        None => {
+            eprintln!("Usage: rustvault-check <wal-path-or-data-dir> [server-addr]");
+            return ExitCode::from(2);
+        }
+    };
+    
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("Check failed: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Resolve a data directory to the WAL file the server would use in it
+fn wal_path(path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_dir() {
+        path.join("vault.log")
+    } else {
+        path
+    }
+}
+
+async fn run(wal_path: PathBuf, server_addr: Option<&str>) -> Result<bool, Box<dyn std::error::Error>> {
+    println!("Checking WAL: {}", wal_path.display());
+    
+    let keyspace = Keyspace::replay(&wal_path)?;
+    println!(
+        "Replayed {} WAL entries: {} live keys, {} deleted keys",
+        keyspace.entries,
+        keyspace.live.len(),
+        keyspace.deleted.len()
+    );
+    
+    let store = recovery::restore_store(&wal_path).await?;
+    let mut consistent = report("startup restore", &recovery::diff_keyspaces(&keyspace, store.get_all().await?));
+    
+    if let Some(addr) = server_addr {
+        let mut client = Client::connect(addr).await?;
+        let divergences = recovery::diff_against_server(&keyspace, &mut client).await?;
+        consistent &= report(&format!("server {}", addr), &divergences);
+        println!("Note: keys that exist only on the server are not detected");
+        client.close().await?;
+    }
+    
+    Ok(consistent)
+}
+
+/// Compare two live servers and print where they differ
+async fn verify_replica(primary_addr: &str, replica_addr: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut primary = Client::connect(primary_addr).await?;
+    let mut replica = Client::connect(replica_addr).await?;
+    let divergences = recovery::verify_replica(&mut primary, &mut replica).await?;
+    primary.close().await?;
+    replica.close().await?;
+    
+    if divergences.is_empty() {
+        println!("[{} vs {}] consistent", primary_addr, replica_addr);
+        return Ok(true);
+    }
+    
+    println!("[{} vs {}] {} divergences", primary_addr, replica_addr, divergences.len());
+    for divergence in &divergences {
+        println!("  {}", divergence);
+    }
+    Ok(false)
+}
+
+/// Print the result of one comparison and return whether it was consistent
+fn report(target: &str, divergences: &[Divergence]) -> bool {
+    if divergences.is_empty() {
+        println!("[replay vs {}] consistent", target);
+        return true;
+    }
+    
+    println!("[replay vs {}] {} divergent keys", target, divergences.len());
+    for divergence in divergences {
+        println!("  {}", divergence);
+    }
+    false
+}
\ No newline at end of file
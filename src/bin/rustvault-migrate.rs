@@ -0,0 +1,415 @@
+//! Redis to RustVault migration tool
+//!
+//! SCANs a source Redis keyspace, copies string keys into RustVault over
+//! several connections, checkpoints the SCAN cursor so an interrupted run can
+//! resume, and finishes with a sampled verification pass.
+//!
+//! Values are sent length-prefixed, so any bytes Redis holds carry over.
+//! Keys with a TTL keep it: each is written with a SET and a PEXPIREAT to
+//! the deadline Redis reported, so time spent copying still counts against
+//! it. Only keys the protocol can't carry (empty, non-UTF-8, or holding
+//! whitespace) are skipped.
+//!
+//! Usage: rustvault-migrate --source <redis-url> [--target <addr>] [--pattern <glob>]
+//!        [--concurrency <n>] [--scan-count <n>] [--cursor-file <path>] [--verify-sample <n>]
+
+use futures_core::Stream;
+use redis::aio::MultiplexedConnection;
+use rustvault::{ClientPool, Command, LoadOptions, Pipeline, PoolConfig, Response, RustVaultError};
+use std::env;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Command-line options
+#[derive(Debug)]
+struct Options {
+    source: String,
+    target: String,
+    pattern: String,
+    concurrency: usize,
+    scan_count: usize,
+    cursor_file: Option<PathBuf>,
+    verify_sample: usize,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            source: String::new(),
+            target: "127.0.0.1:8080".to_string(),
+            pattern: "*".to_string(),
+            concurrency: 8,
+            scan_count: 1000,
+            cursor_file: None,
+            verify_sample: 100,
+        };
+        
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", flag))?;
+            let number = || value.parse::<usize>().map_err(|_| format!("Invalid number for {}: {}", flag, value));
+            match flag.as_str() {
+                "--source" => options.source = value.clone(),
+                "--target" => options.target = value.clone(),
+                "--pattern" => options.pattern = value.clone(),
+                "--concurrency" => options.concurrency = number()?.max(1),
+                "--scan-count" => options.scan_count = number()?.max(1),
+                "--cursor-file" => options.cursor_file = Some(PathBuf::from(value)),
+                "--verify-sample" => options.verify_sample = number()?,
+                _ => return Err(format!("Unknown option: {}", flag)),
+            }
+        }
+        
+        if options.source.is_empty() {
+            return Err("--source is required".to_string());
+        }
+        Ok(options)
+    }
+}
+
+/// Totals printed at the end of a run
+#[derive(Debug, Default)]
+struct MigrationReport {
+    scanned: usize,
+    migrated: usize,
+    with_ttl: usize,
+    skipped_types: usize,
+    skipped_keys: usize,
+    failed: Vec<(String, String)>,
+    verified: usize,
+    mismatched: Vec<String>,
+}
+
+impl MigrationReport {
+    fn print(&self, elapsed: f64) {
+        println!("=== Migration Summary ===");
+        println!("Keys scanned: {}", self.scanned);
+        println!("Keys migrated: {}", self.migrated);
+        println!("Migrated with their TTL: {}", self.with_ttl);
+        println!("Skipped (unsupported type): {}", self.skipped_types);
+        println!("Skipped (key not representable): {}", self.skipped_keys);
+        println!("Failed writes: {}", self.failed.len());
+        for (key, error) in &self.failed {
+            println!("  {}: {}", key, error);
+        }
+        println!("Verified sample: {} keys, {} mismatched", self.verified, self.mismatched.len());
+        for key in &self.mismatched {
+            println!("  mismatch: {}", key);
+        }
+        println!("Elapsed: {:.2}s", elapsed);
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: rustvault-migrate --source <redis-url> [--target <addr>] [--pattern <glob>] \
+                       [--concurrency <n>] [--scan-count <n>] [--cursor-file <path>] [--verify-sample <n>]");
+            return ExitCode::from(2);
+        }
+    };
+    
+    let start = Instant::now();
+    match migrate(&options).await {
+        Ok(report) => {
+            report.print(start.elapsed().as_secs_f64());
+            if report.failed.is_empty() && report.mismatched.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(1)
+            }
+        }
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+async fn migrate(options: &Options) -> Result<MigrationReport, BoxError> {
+    let mut redis = redis::Client::open(options.source.as_str())?
+        .get_multiplexed_async_connection()
+        .await?;
+    let pool_config = PoolConfig {
+        min: 1,
+        max: options.concurrency,
+        idle_timeout: Duration::from_secs(60),
+    };
+    let pool = ClientPool::connect(&options.target, pool_config).await?;
+    
+    let mut cursor = read_cursor(options)?;
+    if cursor != 0 {
+        println!("Resuming from SCAN cursor {}", cursor);
+    }
+    
+    let mut report = MigrationReport::default();
+    let mut sample = Sample::new(options.verify_sample);
+    
+    loop {
+        let (next_cursor, keys): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&options.pattern)
+            .arg("COUNT")
+            .arg(options.scan_count)
+            .query_async(&mut redis)
+            .await?;
+        report.scanned += keys.len();
+        
+        let entries = fetch_strings(&mut redis, keys, &mut report).await?;
+        for entry in &entries {
+            sample.offer(&entry.key);
+        }
+        write_entries(&pool, entries, options.concurrency, &mut report).await?;
+        
+        // Only checkpoint once every pair before the cursor is acknowledged
+        cursor = next_cursor;
+        write_cursor(options, cursor)?;
+        println!(
+            "Scanned {} keys, migrated {} (cursor {})",
+            report.scanned, report.migrated, cursor
+        );
+        
+        if cursor == 0 {
+            break;
+        }
+    }
+    
+    verify(&mut redis, &pool, sample.keys, &mut report).await?;
+    
+    if let Some(path) = &options.cursor_file {
+        let _ = std::fs::remove_file(path);
+    }
+    
+    Ok(report)
+}
+
+/// A string key read from Redis
+struct Entry {
+    key: String,
+    value: Vec<u8>,
+    /// Unix milliseconds the key expires at, if it has a TTL
+    expires_at: Option<u64>,
+}
+
+/// Read string values and TTLs for a batch of scanned keys
+async fn fetch_strings(
+    redis: &mut MultiplexedConnection,
+    keys: Vec<Vec<u8>>,
+    report: &mut MigrationReport,
+) -> Result<Vec<Entry>, BoxError> {
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.cmd("TYPE").arg(key);
+    }
+    let types: Vec<String> = pipe.query_async(redis).await?;
+    
+    let mut string_keys = Vec::new();
+    for (key, key_type) in keys.into_iter().zip(types) {
+        if key_type == "string" {
+            string_keys.push(key);
+        } else {
+            println!("Skipping {} ({})", String::from_utf8_lossy(&key), key_type);
+            report.skipped_types += 1;
+        }
+    }
+    
+    // Taken before PTTL is read, so deadlines err early rather than late
+    let now = now_millis();
+    let mut pipe = redis::pipe();
+    for key in &string_keys {
+        pipe.cmd("GET").arg(key).cmd("PTTL").arg(key);
+    }
+    let values: Vec<(Option<Vec<u8>>, i64)> = if string_keys.is_empty() {
+        Vec::new()
+    } else {
+        let flat: Vec<redis::Value> = pipe.query_async(redis).await?;
+        flat.chunks(2)
+            .map(|pair| {
+                let value = redis::from_redis_value(&pair[0]).unwrap_or(None);
+                let ttl = redis::from_redis_value(&pair[1]).unwrap_or(-1);
+                (value, ttl)
+            })
+            .collect()
+    };
+    
+    let mut entries = Vec::with_capacity(string_keys.len());
+    for (key, (value, ttl)) in string_keys.into_iter().zip(values) {
+        // The key may have expired or been deleted since SCAN returned it
+        let Some(value) = value else { continue };
+        match String::from_utf8(key) {
+            Ok(key) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+                let expires_at = (ttl > 0).then(|| now + ttl as u64);
+                entries.push(Entry { key, value, expires_at });
+            }
+            key => {
+                let key = key.unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+                println!("Skipping {:?} (key is not representable in RustVault)", key);
+                report.skipped_keys += 1;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Write a batch: keys without a TTL as MSETs across the pool, the rest as
+/// a SET and PEXPIREAT each
+async fn write_entries(
+    pool: &ClientPool,
+    entries: Vec<Entry>,
+    concurrency: usize,
+    report: &mut MigrationReport,
+) -> Result<(), BoxError> {
+    let (expiring, persistent): (Vec<_>, Vec<_>) = entries.into_iter().partition(|entry| entry.expires_at.is_some());
+    
+    let load_options = LoadOptions {
+        concurrency,
+        ..LoadOptions::default()
+    };
+    let pairs = persistent.into_iter().map(|entry| (entry.key, entry.value));
+    let load = pool.load_from_stream(Pairs(pairs), load_options).await?;
+    report.migrated += load.loaded;
+    report
+        .failed
+        .extend(load.failed.into_iter().map(|(key, e): (String, RustVaultError)| (key, e.to_string())));
+    
+    let mut tasks = JoinSet::new();
+    for chunk in expiring.chunks(expiring.len().div_ceil(concurrency).max(1)) {
+        let mut pipeline = Pipeline::new();
+        for entry in chunk {
+            pipeline.set(&entry.key, &entry.value).command(Command::ExpireAt {
+                key: entry.key.clone(),
+                unix_millis: entry.expires_at.unwrap_or_default(),
+            });
+        }
+        let keys: Vec<String> = chunk.iter().map(|entry| entry.key.clone()).collect();
+        let pool = pool.clone();
+        tasks.spawn(async move {
+            let mut client = pool.get().await?;
+            let responses = pipeline.execute(&mut client).await?;
+            Ok::<_, RustVaultError>((keys, responses))
+        });
+    }
+    
+    while let Some(joined) = tasks.join_next().await {
+        let (keys, responses) = joined??;
+        for (key, replies) in keys.into_iter().zip(responses.chunks(2)) {
+            match replies {
+                // NOT_FOUND: the deadline passed between the SET and the PEXPIREAT
+                [Response::Ok, Response::Ok | Response::NotFound] => {
+                    report.migrated += 1;
+                    report.with_ttl += 1;
+                }
+                [Response::Error(e), _] | [_, Response::Error(e)] => report.failed.push((key, e.clone())),
+                other => report.failed.push((key, format!("Unexpected responses: {:?}", other))),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A batch already in memory, handed to [`ClientPool::load_from_stream`]
+struct Pairs<I>(I);
+
+impl<I: Iterator + Unpin> Stream for Pairs<I> {
+    type Item = I::Item;
+    
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<I::Item>> {
+        Poll::Ready(self.0.next())
+    }
+}
+
+/// Compare the sampled keys between Redis and RustVault
+async fn verify(
+    redis: &mut MultiplexedConnection,
+    pool: &ClientPool,
+    keys: Vec<String>,
+    report: &mut MigrationReport,
+) -> Result<(), BoxError> {
+    let mut client = pool.get().await?;
+    for key in keys {
+        let source: Option<Vec<u8>> = redis::cmd("GET").arg(&key).query_async(redis).await?;
+        // Keys changed or removed in Redis since they were copied can't be judged
+        let Some(source) = source else { continue };
+        report.verified += 1;
+        if client.get_bytes(&key).await? != Some(source) {
+            report.mismatched.push(key);
+        }
+    }
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn read_cursor(options: &Options) -> Result<u64, BoxError> {
+    match &options.cursor_file {
+        Some(path) if path.exists() => Ok(std::fs::read_to_string(path)?.trim().parse()?),
+        _ => Ok(0),
+    }
+}
+
+fn write_cursor(options: &Options, cursor: u64) -> Result<(), BoxError> {
+    if let Some(path) = &options.cursor_file {
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, cursor.to_string())?;
+        std::fs::rename(&temp_path, path)?;
+    }
+    Ok(())
+}
+
+/// Reservoir sample of migrated keys for the verification pass
+struct Sample {
+    keys: Vec<String>,
+    capacity: usize,
+    seen: u64,
+    state: u64,
+}
+
+impl Sample {
+    fn new(capacity: usize) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self {
+            keys: Vec::with_capacity(capacity),
+            capacity,
+            seen: 0,
+            state: seed | 1,
+        }
+    }
+    
+    fn offer(&mut self, key: &str) {
+        self.seen += 1;
+        if self.keys.len() < self.capacity {
+            self.keys.push(key.to_string());
+            return;
+        }
+        
+        // xorshift64 is plenty for picking sample slots
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let slot = self.state % self.seen;
+        if (slot as usize) < self.capacity {
+            self.keys[slot as usize] = key.to_string();
+        }
+    }
+}
\ No newline at end of file
@@ -3,84 +3,477 @@
 //! Provides a simple interface for interacting with the key-value store
 
 use crate::error::{RustVaultError, Result};
-use crate::protocol::{Command, Response};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::TcpStream;
+use crate::protocol::{
+    info_header, keys_header, needs_length_prefix, payload_len, results_header, values_header, Command, CommandKind,
+    ConfigAction, ErrorCode, KeyEvent,
+    ProtocolError, ProtocolErrorKind, Response, SetCondition, MAX_MSET_PAIRS, MAX_VALUE_LEN, PROTOCOL_VERSION,
+};
+use crate::server::slowlog::SlowLogEntry;
+use crate::store::{CompactionReport, KeyStat, ScanPage};
+use crate::wal::WalStats;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::pin::Pin;
+use std::str;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+mod load;
+mod pool;
+mod transport;
+pub use load::{LoadOptions, OnLoadError};
+pub use pool::{ClientPool, PoolConfig, PoolStats, PooledClient};
+pub use transport::UNIX_SCHEME;
+pub(crate) use transport::check_addr;
+use transport::{ReadHalf, WriteHalf};
+
+/// Keys a [`ScanStream`] fetches per page
+const SCAN_STREAM_PAGE: usize = 100;
+
+/// Outcome of a bulk load via [`Client::load_from_iter`] or
+/// [`ClientPool::load_from_stream`]
+#[derive(Debug)]
+pub struct LoadReport {
+    /// Number of pairs the server accepted
+    pub loaded: usize,
+    /// Keys the server rejected, with the error it returned
+    pub failed: Vec<(String, RustVaultError)>,
+    /// Keys read but never sent, because [`OnLoadError::Stop`] ended the
+    /// load first; `loaded`, `failed` and `unsent` together account for
+    /// every pair read
+    pub unsent: Vec<String>,
+    /// Wall-clock time spent loading
+    pub elapsed: Duration,
+    /// Accepted pairs per second
+    pub rate: f64,
+}
+
+/// A response frame as it appeared on the wire, without command-specific
+/// interpretation
+///
+/// Returned by [`Client::execute_raw`], so commands the typed API doesn't
+/// know about can still be sent and their replies inspected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawResponse {
+    Ok,
+    Value(Vec<u8>),
+    Integer(i64),
+    NotFound,
+    /// `code` is the leading upper-case word of the message, if any
+    /// (e.g. `ERR_LOADING`), following the usual `ERROR <CODE> <message>` shape
+    Error { code: Option<String>, message: String },
+    /// A `KEYS` page and the cursor to continue from
+    Keys { keys: Vec<String>, cursor: u64 },
+    /// A `CAS` found a different value
+    Conflict,
+    /// A `SET` with `NX` or `XX` didn't write
+    NotApplied,
+    /// The answer to `PING`
+    Pong,
+    /// The protocol version a `HELLO` settled on
+    Hello(u32),
+    /// A `STAT` reply
+    Stat(KeyStat),
+    /// A `COMPACT` reply
+    Compacted(CompactionReport),
+    /// An `MGET` result, `None` for missing keys
+    Values(Vec<Option<Vec<u8>>>),
+    /// `INFO` figures as name/value pairs, in the order sent
+    Info(Vec<(String, String)>),
+    /// A command was queued for `EXEC`
+    Queued,
+    /// What an `EXEC` returned for each queued command
+    Results(Vec<RawResponse>),
+}
+
+/// How a [`Client`] connects, and recovers when its connection is lost
+///
+/// With `retries` above zero, a command whose connection turns out to be
+/// gone is sent again on a new one, after an exponential backoff with
+/// jitter. A command is only re-sent when the server can't have run it
+/// already: when the connection was found closed before it was written, or
+/// when the write itself failed. One whose response was lost after it was
+/// written is re-sent only if it is a read, or if `at_least_once` is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// Times a command is retried on a new connection; 0 never reconnects
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after it
+    pub backoff: Duration,
+    /// Longest wait between two retries
+    pub max_backoff: Duration,
+    /// Also re-send writes whose response was lost, which may apply them
+    /// twice
+    pub at_least_once: bool,
+    /// Token each connection authenticates with, for servers that require
+    /// `AUTH`
+    pub auth_token: Option<String>,
+    /// Give up on opening a connection after this long, with
+    /// `ConnectTimeout`; `None` waits as long as the OS does
+    pub connect_timeout: Option<Duration>,
+    /// Give up on a command whose response hasn't arrived after this long,
+    /// with `Timeout`; `None` waits for as long as it takes
+    ///
+    /// A late response would answer the next command, so a timed-out
+    /// connection isn't used again: with `retries` the next command opens
+    /// a new one, and without, commands fail until [`Client::reconnect`].
+    /// Pipelines and [`Client::get_streaming`] aren't covered; the stream
+    /// has its own [`Client::set_stream_timeout`].
+    pub request_timeout: Option<Duration>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            at_least_once: false,
+            auth_token: None,
+            connect_timeout: Some(Duration::from_secs(10)),
+            request_timeout: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Wait before retry number `retry`, counting from 1: the backoff for
+    /// that retry less up to half of it, so clients cut off together don't
+    /// all come back at once
+    fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        let delay = self.backoff.saturating_mul(factor).min(self.max_backoff);
+        let random = RandomState::new().build_hasher().finish();
+        delay - (delay / 2).mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+/// How far a round trip got before it failed
+enum Failure {
+    /// The command never reached the server whole, so it can't have run
+    Unsent(RustVaultError),
+    /// The command was written; the server may or may not have run it
+    Sent(RustVaultError),
+}
 
 /// Client for connecting to RustVault server
 pub struct Client {
-    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
-    writer: BufWriter<tokio::net::tcp::OwnedWriteHalf>,
+    reader: BufReader<ReadHalf>,
+    writer: BufWriter<WriteHalf>,
+    /// Where to reconnect to
+    addr: String,
+    config: ClientConfig,
+    /// Per-chunk timeout for [`Client::get_streaming`]
+    stream_timeout: Option<Duration>,
+    /// Set when a command, stream or pipeline failed or was abandoned
+    /// part-way, which can leave half a request or the rest of a response
+    /// on the socket
+    poisoned: bool,
+    /// Namespace chosen with [`Client::select`], selected again on every
+    /// new connection
+    namespace: Option<String>,
+    /// Protocol version the connection settled on with `HELLO`
+    protocol: u32,
 }
 
 impl Client {
     /// Connect to a RustVault server
+    ///
+    /// `addr` is a `host:port` to reach over TCP, or on Unix targets a
+    /// socket path such as `unix:///run/rustvault/vault.sock`; anything else
+    /// fails with `InvalidAddress`. A connect that hasn't finished after
+    /// 10 seconds fails with `ConnectTimeout`.
     pub async fn connect(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        let (read_half, write_half) = stream.into_split();
-        let reader = BufReader::new(read_half);
-        let writer = BufWriter::new(write_half);
+        Self::connect_with_config(addr, ClientConfig::default()).await
+    }
+    
+    /// Connect to a server that requires `AUTH`, authenticating with `token`
+    ///
+    /// Fails with `Client("invalid auth")` if the server rejects the token.
+    pub async fn connect_with_auth(addr: &str, token: &str) -> Result<Self> {
+        let config = ClientConfig {
+            auth_token: Some(token.to_string()),
+            ..Default::default()
+        };
+        Self::connect_with_config(addr, config).await
+    }
+    
+    /// Connect to a RustVault server, reconnecting according to `config`
+    ///
+    /// Every connection starts with a `HELLO` offering
+    /// [`PROTOCOL_VERSION`]; see [`Client::protocol_version`].
+    pub async fn connect_with_config(addr: &str, config: ClientConfig) -> Result<Self> {
+        let (reader, writer) = open(addr, config.connect_timeout).await?;
+        let mut client = Self {
+            reader,
+            writer,
+            addr: addr.to_string(),
+            config,
+            stream_timeout: None,
+            poisoned: false,
+            namespace: None,
+            protocol: 1,
+        };
+        client.handshake().await?;
+        client.authenticate().await?;
+        Ok(client)
+    }
+    
+    /// Agree on a protocol version with `HELLO` on a new connection
+    ///
+    /// A server older than `HELLO` can't parse it, and speaks version 1.
+    /// Any other error, such as a server at its connection limit turning
+    /// the connection away, fails the connect.
+    async fn handshake(&mut self) -> Result<()> {
+        let frame = self
+            .exchange(&encode_command(&Command::Hello { version: PROTOCOL_VERSION }))
+            .await
+            .map_err(|(Failure::Unsent(e) | Failure::Sent(e))| e)?;
         
-        Ok(Self { reader, writer })
+        self.protocol = match parse_response_frame(&frame)? {
+            Response::Hello(version) => version,
+            ref error @ Response::Error(_) if matches!(error.error_code(), None | Some(ErrorCode::Parse)) => 1,
+            Response::Error(e) => return Err(RustVaultError::from_reply(e)),
+            other => return Err(unexpected_response("HELLO", &other)),
+        };
+        Ok(())
     }
     
-    /// Send a command and receive a response
-    async fn send_command(&mut self, command: &Command) -> Result<Response> {
-        // Serialize command to protocol format
-        let command_bytes = match command {
-            Command::Set { key, value } => format!("SET {} {}\r\n", key, value).into_bytes(),
-            Command::Get { key } => format!("GET {}\r\n", key).into_bytes(),
-            Command::Delete { key } => format!("DELETE {}\r\n", key).into_bytes(),
+    /// Protocol version the connection speaks: the lower of
+    /// [`PROTOCOL_VERSION`] and the highest the server supports
+    ///
+    /// The typed API reads every version the same way, so this only
+    /// matters to code that inspects raw replies.
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol
+    }
+    
+    /// Send `AUTH` on a new connection, if the config has a token
+    async fn authenticate(&mut self) -> Result<()> {
+        let Some(token) = self.config.auth_token.clone() else {
+            return Ok(());
         };
+        let frame = self
+            .exchange(&encode_command(&Command::Auth { token }))
+            .await
+            .map_err(|(Failure::Unsent(e) | Failure::Sent(e))| e)?;
         
-        // Send command
-        self.writer.write_all(&command_bytes).await?;
-        self.writer.flush().await?;
+        match parse_response_frame(&frame)? {
+            Response::Ok => Ok(()),
+            // Older servers send the reason without a code
+            ref error @ Response::Error(ref e)
+                if error.error_code() == Some(ErrorCode::NoAuth) || e.starts_with("NOAUTH") =>
+            {
+                Err(RustVaultError::Client("invalid auth".to_string()))
+            }
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("AUTH", &other)),
+        }
+    }
+    
+    /// Replace the connection with a new one to the same server, authenticated
+    /// and in the selected namespace like the first
+    ///
+    /// Makes a client usable again after [`Client::is_broken`].
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let (reader, writer) = open(&self.addr, self.config.connect_timeout).await?;
+        self.reader = reader;
+        self.writer = writer;
+        self.poisoned = false;
+        self.handshake().await?;
+        self.authenticate().await?;
+        match self.namespace.clone() {
+            Some(namespace) => self.request_select(namespace).await,
+            None => Ok(()),
+        }
+    }
+    
+    /// Select `namespace` again on a new connection
+    async fn request_select(&mut self, namespace: String) -> Result<()> {
+        let frame = self
+            .exchange(&encode_command(&Command::Select { namespace }))
+            .await
+            .map_err(|(Failure::Unsent(e) | Failure::Sent(e))| e)?;
         
-        // Read response
-        let mut response_line = String::new();
-        self.reader.read_line(&mut response_line).await?;
-        
-        // Parse response
-        self.parse_response(&response_line.trim())
-    }
-    
-    /// Parse server response from string
-    fn parse_response(&self, response: &str) -> Result<Response> {
-        if response == "OK" {
-            Ok(Response::Ok)
-        } else if response == "NOT_FOUND" {
-            Ok(Response::NotFound)
-        } else if response.starts_with("VALUE ") {
-            let value = response.strip_prefix("VALUE ").unwrap_or("").to_string();
-            Ok(Response::Value(value))
-        } else if response.starts_with("ERROR ") {
-            let error = response.strip_prefix("ERROR ").unwrap_or("").to_string();
-            Ok(Response::Error(error))
-        } else {
-            Err(RustVaultError::Protocol(format!(
-                "Unknown response format: {}",
-                response
-            )))
+        match parse_response_frame(&frame)? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SELECT", &other)),
+        }
+    }
+    
+    /// Whether the connection can't carry another command: left mid-frame,
+    /// or closed by the server, as it is when the server restarts
+    fn connection_lost(&mut self) -> bool {
+        if self.poisoned || !self.reader.buffer().is_empty() {
+            return true;
+        }
+        let mut byte = [0; 1];
+        // Nothing is due between commands, so anything but "no data yet"
+        // means the connection is closed, failed, or out of step
+        !matches!(
+            self.reader.get_ref().try_read(&mut byte),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock
+        )
+    }
+    
+    /// Limit how long [`Client::get_streaming`] waits for each chunk of a
+    /// value, or to write it to the destination
+    pub fn set_stream_timeout(&mut self, timeout: Option<Duration>) {
+        self.stream_timeout = timeout;
+    }
+    
+    /// Whether the connection was left mid-frame and can't be used again
+    ///
+    /// Set by an IO or framing error, a request timeout, or by dropping a
+    /// call before its response had been read. Server errors leave it
+    /// usable.
+    pub fn is_broken(&self) -> bool {
+        self.poisoned
+    }
+    
+    /// Fail fast if an earlier command, stream or pipeline left the
+    /// connection mid-frame
+    fn check_usable(&self) -> Result<()> {
+        if self.poisoned {
+            return Err(RustVaultError::Client(
+                "Connection is unusable after an interrupted or timed out command, stream or pipeline; reconnect required"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+    
+    /// Send a command and receive a response, retrying on a new connection
+    /// as the [`ClientConfig`] allows
+    async fn send_command(&mut self, command: &Command) -> Result<Response> {
+        self.send_request(&encode_command(command), command.kind()).await
+    }
+    
+    /// [`Client::send_command`] for a command of `kind` already encoded as
+    /// `request`
+    async fn send_request(&mut self, request: &[u8], kind: CommandKind) -> Result<Response> {
+        let mut retries = 0;
+        loop {
+            let failure = match self.attempt(request).await {
+                Ok(frame) => return parse_response_frame(&frame),
+                Err(failure) => failure,
+            };
+            let (e, resend) = match failure {
+                Failure::Unsent(e) => (e, true),
+                Failure::Sent(e) => (e, kind == CommandKind::Read || self.config.at_least_once),
+            };
+            let lost = matches!(e, RustVaultError::Io(_) | RustVaultError::ConnectTimeout { .. });
+            if !resend || !lost || retries >= self.config.retries {
+                return Err(e);
+            }
+            retries += 1;
+            tokio::time::sleep(self.config.delay(retries)).await;
+        }
+    }
+    
+    /// One round trip, first reconnecting if the connection is gone and
+    /// retries are enabled
+    async fn attempt(&mut self, request: &[u8]) -> std::result::Result<Vec<u8>, Failure> {
+        if self.config.retries > 0 && self.connection_lost() {
+            self.reconnect().await.map_err(Failure::Unsent)?;
+        }
+        self.exchange(request).await
+    }
+    
+    /// Write `request` and read back the frame that answers it, within the
+    /// config's `request_timeout`
+    async fn exchange(&mut self, request: &[u8]) -> std::result::Result<Vec<u8>, Failure> {
+        self.check_usable().map_err(Failure::Unsent)?;
+        let Some(limit) = self.config.request_timeout else {
+            return self.round_trip(request).await;
+        };
+        match tokio::time::timeout(limit, self.round_trip(request)).await {
+            Ok(result) => result,
+            // The round trip leaves the connection poisoned, since the rest
+            // of the response may still be on its way
+            Err(_) => Err(Failure::Sent(RustVaultError::Timeout { op: verb(request), elapsed: limit })),
         }
     }
     
+    async fn round_trip(&mut self, request: &[u8]) -> std::result::Result<Vec<u8>, Failure> {
+        // Stays set if this fails or is dropped before the response is read
+        self.poisoned = true;
+        let sent = async {
+            self.writer.write_all(request).await?;
+            self.writer.flush().await
+        };
+        sent.await.map_err(|e| Failure::Unsent(e.into()))?;
+        let frame = read_frame(&mut self.reader).await.map_err(Failure::Sent)?;
+        self.poisoned = false;
+        Ok(frame)
+    }
+    
     /// Set a key-value pair
     pub async fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        let command = Command::Set {
-            key: key.to_string(),
-            value: value.to_string(),
-        };
-        
-        match self.send_command(&command).await? {
+        self.set_bytes(key, value.as_bytes()).await
+    }
+    
+    /// Set a key to an arbitrary byte value
+    pub async fn set_bytes(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        // Encoded straight from `value`, which a Command would have to copy
+        match self.send_request(&encode_set(key, value, None, None), CommandKind::Write).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SET", &other)),
+        }
+    }
+    
+    /// Set a key-value pair that expires after `seconds`
+    pub async fn set_with_ttl(&mut self, key: &str, value: &str, seconds: u64) -> Result<()> {
+        match self.send_request(&encode_set(key, value.as_bytes(), Some(seconds), None), CommandKind::Write).await? {
             Response::Ok => Ok(()),
-            Response::Error(e) => Err(RustVaultError::Server(e)),
-            _ => Err(RustVaultError::Protocol("Unexpected response for SET".to_string())),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SET", &other)),
+        }
+    }
+    
+    /// Set `key` only if it doesn't exist; false, leaving it alone, if it
+    /// does
+    ///
+    /// The check and the write are one step on the server, so of several
+    /// clients setting the same absent key exactly one gets true.
+    pub async fn set_nx(&mut self, key: &str, value: &[u8]) -> Result<bool> {
+        self.set_if(key, value, SetCondition::IfAbsent).await
+    }
+    
+    /// Set `key` only if it exists; false, creating nothing, if it doesn't
+    pub async fn set_xx(&mut self, key: &str, value: &[u8]) -> Result<bool> {
+        self.set_if(key, value, SetCondition::IfPresent).await
+    }
+    
+    async fn set_if(&mut self, key: &str, value: &[u8], condition: SetCondition) -> Result<bool> {
+        match self.send_request(&encode_set(key, value, None, Some(condition)), CommandKind::Write).await? {
+            Response::Ok => Ok(true),
+            Response::NotApplied => Ok(false),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SET", &other)),
         }
     }
     
     /// Get a value by key
+    ///
+    /// Fails if the value isn't UTF-8; use [`Client::get_bytes`] for
+    /// binary values.
     pub async fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key).await?.map(into_text).transpose()
+    }
+    
+    /// Get a value by key as raw bytes
+    pub async fn get_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
         let command = Command::Get {
             key: key.to_string(),
         };
@@ -88,8 +481,126 @@ impl Client {
         match self.send_command(&command).await? {
             Response::Value(value) => Ok(Some(value)),
             Response::NotFound => Ok(None),
-            Response::Error(e) => Err(RustVaultError::Server(e)),
-            _ => Err(RustVaultError::Protocol("Unexpected response for GET".to_string())),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("GET", &other)),
+        }
+    }
+    
+    /// Set a key to `value` serialized as JSON
+    ///
+    /// Sent as a byte value, so whatever the JSON holds, newlines included,
+    /// arrives intact.
+    pub async fn set_json<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<()> {
+        let json = serde_json::to_vec(value)?;
+        self.set_bytes(key, &json).await
+    }
+    
+    /// Get a value stored with [`Client::set_json`], deserialized as `T`
+    ///
+    /// A value that isn't JSON of that shape fails with
+    /// [`RustVaultError::Deserialize`], naming the key.
+    pub async fn get_json<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>> {
+        let Some(value) = self.get_bytes(key).await? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&value)
+            .map(Some)
+            .map_err(|source| RustVaultError::Deserialize { key: key.to_string(), source })
+    }
+    
+    /// Set several key-value pairs in one request
+    ///
+    /// The server applies them together: a reader never sees some of the
+    /// pairs without the others. At most [`MAX_MSET_PAIRS`] can be sent at
+    /// once.
+    pub async fn mset(&mut self, pairs: &[(&str, &str)]) -> Result<()> {
+        if pairs.len() > MAX_MSET_PAIRS {
+            return Err(RustVaultError::InvalidCommand(format!(
+                "MSET has more than {} pairs",
+                MAX_MSET_PAIRS
+            )));
+        }
+        let command = Command::MSet {
+            pairs: pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("MSET", &other)),
+        }
+    }
+    
+    /// Get several values in one request, `None` for keys that don't exist
+    ///
+    /// Fails if any value isn't UTF-8.
+    pub async fn mget(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let command = Command::MGet {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Values(values) if values.len() == keys.len() => values
+                .into_iter()
+                .map(|value| value.map(into_text).transpose())
+                .collect(),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("MGET", &other)),
+        }
+    }
+    
+    /// Check whether `key` holds a value, without fetching it
+    pub async fn exists(&mut self, key: &str) -> Result<bool> {
+        let command = Command::Exists {
+            key: key.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(true),
+            Response::NotFound => Ok(false),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("EXISTS", &other)),
+        }
+    }
+    
+    /// How many times `key` has been set, and when it was created and last
+    /// set; `None` if it doesn't exist
+    pub async fn stat(&mut self, key: &str) -> Result<Option<KeyStat>> {
+        let command = Command::Stat {
+            key: key.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Stat { version, created_at, updated_at } => {
+                Ok(Some(KeyStat { version, created_at, updated_at }))
+            }
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("STAT", &other)),
+        }
+    }
+    
+    /// Make the next [`Transaction::exec`] on this connection apply nothing
+    /// if any of `keys` changes before it
+    ///
+    /// The watches belong to the connection, so this is never retried on a
+    /// new one: a transaction sent after a reconnect would run unwatched.
+    pub async fn watch(&mut self, keys: &[&str]) -> Result<()> {
+        let command = Command::Watch {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+        };
+        let frame = self
+            .exchange(&encode_command(&command))
+            .await
+            .map_err(|(Failure::Unsent(e) | Failure::Sent(e))| e)?;
+        
+        match parse_response_frame(&frame)? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("WATCH", &other)),
         }
     }
     
@@ -102,62 +613,2368 @@ impl Client {
         match self.send_command(&command).await? {
             Response::Ok => Ok(true),
             Response::NotFound => Ok(false),
-            Response::Error(e) => Err(RustVaultError::Server(e)),
-            _ => Err(RustVaultError::Protocol("Unexpected response for DELETE".to_string())),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("DELETE", &other)),
         }
     }
     
-    /// Close the connection
-    pub async fn close(mut self) -> Result<()> {
-        self.writer.shutdown().await?;
-        Ok(())
+    /// Delete every key matching the glob `pattern` at once, returning how
+    /// many there were
+    ///
+    /// `*` in the pattern matches any run of characters and `?` any one. A
+    /// pattern of nothing but `*`s is refused unless the server allows
+    /// `FLUSHALL`.
+    pub async fn delete_pattern(&mut self, pattern: &str) -> Result<u64> {
+        let command = Command::DeletePattern {
+            pattern: pattern.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Integer(n) => Ok(n.max(0) as u64),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("DELPAT", &other)),
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_parse_response() {
-        // Create a dummy client for testing parse_response
-        // We can't easily create a real client for unit tests, so we'll test the logic separately
-        struct DummyClient;
-        impl DummyClient {
-            fn parse_response(&self, response: &str) -> crate::Result<crate::protocol::Response> {
-                use crate::protocol::Response;
-                use crate::error::RustVaultError;
-                
-                if response == "OK" {
-                    Ok(Response::Ok)
-                } else if response == "NOT_FOUND" {
-                    Ok(Response::NotFound)
-                } else if response.starts_with("VALUE ") {
-                    let value = response.strip_prefix("VALUE ").unwrap_or("").to_string();
-                    Ok(Response::Value(value))
-                } else if response.starts_with("ERROR ") {
-                    let error = response.strip_prefix("ERROR ").unwrap_or("").to_string();
-                    Ok(Response::Error(error))
-                } else {
-                    Err(RustVaultError::Protocol(format!(
-                        "Unknown response format: {}",
-                        response
-                    )))
+    
+    /// Add `delta` to the integer at `key`, counting from 0 if it doesn't
+    /// exist, and return the new value
+    ///
+    /// Fails if the value isn't an integer or the result would overflow.
+    pub async fn incr(&mut self, key: &str, delta: i64) -> Result<i64> {
+        let command = Command::Incr {
+            key: key.to_string(),
+            delta,
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Integer(n) => Ok(n),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("INCR", &other)),
+        }
+    }
+    
+    /// Append `value` to the value at `key`, creating the key if it doesn't
+    /// exist, and return the new length
+    ///
+    /// The server extends the value in place, so appends from several
+    /// clients at once all land.
+    pub async fn append(&mut self, key: &str, value: &[u8]) -> Result<usize> {
+        match self.send_request(&encode_with_value("APPEND", key, value), CommandKind::Write).await? {
+            Response::Integer(n) => Ok(n.max(0) as usize),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("APPEND", &other)),
+        }
+    }
+    
+    /// Set `key` to `value` and return the value it replaced, or `None` if
+    /// it didn't exist
+    ///
+    /// The swap is one step on the server, so of several clients swapping
+    /// the same key, each gets back a different old value. Like `set`, it
+    /// clears any TTL.
+    pub async fn getset(&mut self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.send_request(&encode_with_value("GETSET", key, value), CommandKind::Write).await? {
+            Response::Value(old) => Ok(Some(old)),
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("GETSET", &other)),
+        }
+    }
+    
+    /// Delete `key` and return the value it held, or `None` if it didn't
+    /// exist
+    pub async fn getdel(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let command = Command::GetDel {
+            key: key.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Value(old) => Ok(Some(old)),
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("GETDEL", &other)),
+        }
+    }
+    
+    /// Length of the value at `key`, or `None` if it doesn't exist
+    pub async fn strlen(&mut self, key: &str) -> Result<Option<usize>> {
+        let command = Command::Strlen {
+            key: key.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Integer(n) => Ok(Some(n.max(0) as usize)),
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("STRLEN", &other)),
+        }
+    }
+    
+    /// Subtract `delta` from the integer at `key` and return the new value
+    pub async fn decr(&mut self, key: &str, delta: i64) -> Result<i64> {
+        let command = Command::Decr {
+            key: key.to_string(),
+            delta,
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Integer(n) => Ok(n),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("DECR", &other)),
+        }
+    }
+    
+    /// Set `key` to `new` if its value is still `expected`
+    ///
+    /// Returns false, leaving the key alone, if another writer changed it
+    /// first or it doesn't exist. Like [`Client::set`], a swap clears any
+    /// TTL.
+    pub async fn cas(&mut self, key: &str, expected: &[u8], new: &[u8]) -> Result<bool> {
+        let command = Command::Cas {
+            key: key.to_string(),
+            expected: expected.to_vec(),
+            new: new.to_vec(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(true),
+            Response::Conflict => Ok(false),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("CAS", &other)),
+        }
+    }
+    
+    /// Take the lock `key` with `token` for `ttl`, or renew it if `token`
+    /// already holds it; false if another token holds it
+    ///
+    /// The check and the lease are one step on the server, so of several
+    /// clients after the same free lock exactly one gets it. Once the lease
+    /// runs out the lock is free again. [`Client::lock`] picks the token
+    /// and releases the lock itself.
+    pub async fn try_lock(&mut self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+        let command = Command::Lock {
+            key: key.to_string(),
+            token: token.to_string(),
+            ttl_ms: u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(true),
+            Response::Conflict => Ok(false),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("LOCK", &other)),
+        }
+    }
+    
+    /// Release the lock `key` if `token` holds it; false, leaving it alone,
+    /// if it is free or another token holds it
+    pub async fn unlock(&mut self, key: &str, token: &str) -> Result<bool> {
+        let command = Command::Unlock {
+            key: key.to_string(),
+            token: token.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(true),
+            Response::Conflict => Ok(false),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("UNLOCK", &other)),
+        }
+    }
+    
+    /// Take the lock `key` for `ttl` with a new random token, returning a
+    /// guard that releases it when dropped, or `None` if the lock is held
+    ///
+    /// ```no_run
+    /// # async fn example() -> rustvault::Result<()> {
+    /// use std::time::Duration;
+    ///
+    /// let mut client = rustvault::Client::connect("127.0.0.1:8080").await?;
+    /// if let Some(guard) = client.lock("jobs:nightly", Duration::from_secs(30)).await? {
+    ///     // ... run the job, renewing the lease if it takes a while ...
+    ///     guard.release(&mut client).await?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn lock(&mut self, key: &str, ttl: Duration) -> Result<Option<LockGuard>> {
+        let random = || RandomState::new().build_hasher().finish();
+        let token = format!("{:016x}{:016x}", random(), random());
+        if !self.try_lock(key, &token, ttl).await? {
+            return Ok(None);
+        }
+        Ok(Some(LockGuard {
+            addr: self.addr.clone(),
+            config: self.config.clone(),
+            namespace: self.namespace.clone(),
+            key: key.to_string(),
+            token,
+            released: false,
+        }))
+    }
+    
+    /// Make a key expire after `seconds`; false if the key doesn't exist
+    pub async fn expire(&mut self, key: &str, seconds: u64) -> Result<bool> {
+        let command = Command::Expire {
+            key: key.to_string(),
+            seconds,
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(true),
+            Response::NotFound => Ok(false),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("EXPIRE", &other)),
+        }
+    }
+    
+    /// Get a value, copying it straight from the socket into `dest`
+    ///
+    /// The value is never held in memory as a whole: it's forwarded one
+    /// read buffer at a time, so large values can be relayed without the
+    /// caller buffering them. Returns the number of bytes written, or `None`
+    /// if the key doesn't exist.
+    ///
+    /// If the copy fails part-way (a read or write error, or a chunk
+    /// exceeding the stream timeout), the rest of the value is still on the
+    /// socket and the connection is unusable; later calls return an error.
+    pub async fn get_streaming<W>(&mut self, key: &str, dest: &mut W) -> Result<Option<u64>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.check_usable()?;
+        
+        let request = format!("GET {}\r\n", key);
+        self.writer.write_all(request.as_bytes()).await?;
+        self.writer.flush().await?;
+        
+        // Read up to the end of the reply tag, leaving any value unread
+        let mut tag = Vec::new();
+        let end = loop {
+            let byte = self.reader.read_u8().await?;
+            if byte == b' ' || byte == b'\n' || tag.len() > "NOT_FOUND".len() {
+                break byte;
+            }
+            tag.push(byte);
+        };
+        
+        if tag == b"VALUE" && end == b' ' {
+            // Inline values never start with '$'; the server length-prefixes them
+            let next = with_timeout(self.stream_timeout, self.reader.fill_buf()).await?.first().copied();
+            let result = if next == Some(b'$') {
+                self.stream_payload(dest).await
+            } else {
+                self.stream_value(dest).await
+            };
+            if result.is_err() {
+                self.poisoned = true;
+            }
+            return result.map(Some);
+        }
+        
+        // Anything else is a short, single-line reply
+        tag.push(end);
+        if end != b'\n' {
+            self.reader.read_until(b'\n', &mut tag).await?;
+        }
+        let line = str::from_utf8(&tag).map_err(|_| {
+            RustVaultError::Client("Response is not valid UTF-8".to_string())
+        })?;
+        match parse_response(line.trim())? {
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("GET", &other)),
+        }
+    }
+    
+    /// Copy the rest of a VALUE line into `dest`, dropping its terminator
+    async fn stream_value<W>(&mut self, dest: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let timeout = self.stream_timeout;
+        let mut written = 0u64;
+        // A CR at the end of a chunk may be data or the start of the CRLF
+        // terminator; hold it back until the next chunk decides
+        let mut held_cr = false;
+        
+        loop {
+            let chunk = with_timeout(timeout, self.reader.fill_buf()).await?;
+            if chunk.is_empty() {
+                return Err(RustVaultError::Client(
+                    "Connection closed in the middle of a value".to_string(),
+                ));
+            }
+            
+            let newline = chunk.iter().position(|&b| b == b'\n');
+            let mut data = &chunk[..newline.unwrap_or(chunk.len())];
+            let mut ends_with_cr = false;
+            if data.last() == Some(&b'\r') {
+                data = &data[..data.len() - 1];
+                ends_with_cr = newline.is_none();
+            }
+            
+            if held_cr && newline != Some(0) {
+                with_timeout(timeout, dest.write_all(b"\r")).await?;
+                written += 1;
+            }
+            with_timeout(timeout, dest.write_all(data)).await?;
+            written += data.len() as u64;
+            held_cr = ends_with_cr;
+            
+            match newline {
+                Some(pos) => {
+                    self.reader.consume(pos + 1);
+                    break;
+                }
+                None => {
+                    let len = chunk.len();
+                    self.reader.consume(len);
                 }
             }
         }
         
-        let client = DummyClient;
+        with_timeout(timeout, dest.flush()).await?;
+        Ok(written)
+    }
+    
+    /// Copy a length-prefixed value into `dest`, reading exactly as many
+    /// bytes as its header announces
+    async fn stream_payload<W>(&mut self, dest: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let timeout = self.stream_timeout;
+        let mut header = b"VALUE ".to_vec();
+        with_timeout(timeout, self.reader.read_until(b'\n', &mut header)).await?;
+        let len = payload_len(&header)?
+            .ok_or_else(|| ProtocolError::new(ProtocolErrorKind::Malformed, &header, 6))?;
         
-        assert_eq!(client.parse_response("OK").unwrap(), Response::Ok);
-        assert_eq!(client.parse_response("NOT_FOUND").unwrap(), Response::NotFound);
-        assert_eq!(
-            client.parse_response("VALUE test").unwrap(),
-            Response::Value("test".to_string())
-        );
-        assert_eq!(
-            client.parse_response("ERROR test error").unwrap(),
-            Response::Error("test error".to_string())
-        );
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = with_timeout(timeout, self.reader.fill_buf()).await?;
+            if chunk.is_empty() {
+                return Err(RustVaultError::Client(
+                    "Connection closed in the middle of a value".to_string(),
+                ));
+            }
+            let n = chunk.len().min(remaining);
+            with_timeout(timeout, dest.write_all(&chunk[..n])).await?;
+            self.reader.consume(n);
+            remaining -= n;
+        }
+        
+        let mut terminator = [0; 2];
+        with_timeout(timeout, self.reader.read_exact(&mut terminator)).await?;
+        if &terminator != b"\r\n" {
+            return Err(ProtocolError::new(ProtocolErrorKind::ExpectedLineEnding, &terminator, 0).into());
+        }
+        with_timeout(timeout, dest.flush()).await?;
+        Ok(len as u64)
+    }
+    
+    /// Ask the server how it classifies a command
+    ///
+    /// Returns `None` for commands the server doesn't know.
+    pub async fn command_info(&mut self, name: &str) -> Result<Option<CommandKind>> {
+        let command = Command::CommandInfo {
+            name: name.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Value(kind) => match str::from_utf8(&kind).ok().and_then(|k| k.parse().ok()) {
+                Some(kind) => Ok(Some(kind)),
+                None => Err(unexpected_response("COMMAND", &Response::Value(kind))),
+            },
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("COMMAND", &other)),
+        }
+    }
+    
+    /// Digest of the server's entries whose keys start with `prefix`
+    ///
+    /// Two servers holding the same entries return the same digest; see
+    /// [`Store::checksum`](crate::Store::checksum).
+    pub async fn checksum(&mut self, prefix: &str) -> Result<u64> {
+        let command = Command::Checksum {
+            prefix: prefix.to_string(),
+        };
+        match self.send_command(&command).await? {
+            Response::Value(digest) => parse_digest(&into_text(digest)?),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("CHECKSUM", &other)),
+        }
+    }
+    
+    /// Per-bucket digests of the entries under `prefix`, as split by
+    /// [`Store::checksum_ranges`](crate::Store::checksum_ranges)
+    pub async fn checksum_ranges(&mut self, buckets: usize, prefix: &str) -> Result<Vec<u64>> {
+        let command = Command::ChecksumRanges {
+            buckets,
+            prefix: prefix.to_string(),
+        };
+        match self.send_command(&command).await? {
+            Response::Value(digests) => into_text(digests)?.split(' ').map(parse_digest).collect(),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("CHECKSUM", &other)),
+        }
+    }
+    
+    /// Get the server's one-line summary of its background jobs
+    pub async fn maintenance_status(&mut self) -> Result<String> {
+        match self.send_command(&Command::MaintenanceStatus).await? {
+            Response::Value(status) => into_text(status),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("MAINTENANCE", &other)),
+        }
+    }
+    
+    /// Check the server is answering, returning the round trip time
+    ///
+    /// The server answers `PING` without touching the store, so this works
+    /// while it is still replaying its WAL.
+    pub async fn ping(&mut self) -> Result<Duration> {
+        let started = Instant::now();
+        match self.send_command(&Command::Ping).await? {
+            Response::Pong => Ok(started.elapsed()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("PING", &other)),
+        }
+    }
+    
+    /// Get the server's counters and figures, keyed by name
+    ///
+    /// Includes `keys`, `connections`, `uptime_secs`, `wal_size_bytes`,
+    /// `get_hits`/`get_misses` and a `cmd_<verb>` count per command. While
+    /// the server is replaying its WAL, `loading` is `1`, `loading_progress`
+    /// has the percentage restored, and `keys` is missing.
+    pub async fn info(&mut self) -> Result<HashMap<String, String>> {
+        match self.send_command(&Command::Info).await? {
+            Response::Info(fields) => Ok(fields.into_iter().collect()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("INFO", &other)),
+        }
+    }
+    
+    /// Ask the server to release unused store capacity
+    ///
+    /// Returns the server's estimate of the bytes reclaimed.
+    pub async fn shrink(&mut self) -> Result<u64> {
+        match self.send_command(&Command::Shrink).await? {
+            Response::Integer(n) => Ok(n.max(0) as u64),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SHRINK", &other)),
+        }
+    }
+    
+    /// Ask the server to rewrite its WAL down to the live data now
+    ///
+    /// Fails with [`ErrorCode::Busy`] while another compaction is running,
+    /// whether asked for or started in the background. A server without a
+    /// WAL reports zero for everything.
+    pub async fn compact(&mut self) -> Result<CompactionReport> {
+        match self.send_command(&Command::Compact).await? {
+            Response::Compacted { before, after, keys } => Ok(CompactionReport { before, after, keys }),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("COMPACT", &other)),
+        }
+    }
+    
+    /// The server's WAL size, entries logged since it started and when the
+    /// WAL was last compacted
+    pub async fn wal_stats(&mut self) -> Result<WalStats> {
+        match self.send_command(&Command::WalStats).await? {
+            Response::Info(fields) => WalStats::from_fields(&fields)
+                .ok_or_else(|| RustVaultError::Client(format!("Invalid WALSTATS reply: {:?}", fields))),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("WALSTATS", &other)),
+        }
+    }
+    
+    /// Zero the server's per-command latency histograms, which `INFO`
+    /// reports as `latency_<verb>_*`
+    pub async fn reset_stats(&mut self) -> Result<()> {
+        match self.send_command(&Command::StatsReset).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("STATS", &other)),
+        }
+    }
+    
+    /// The latest `count` commands the server logged as slow, newest first
+    pub async fn slowlog_get(&mut self, count: usize) -> Result<Vec<SlowLogEntry>> {
+        match self.send_command(&Command::SlowLogGet { count: Some(count) }).await? {
+            Response::Info(lines) => lines
+                .iter()
+                .map(|(id, fields)| {
+                    SlowLogEntry::parse(id, fields).ok_or_else(|| {
+                        RustVaultError::Client(format!("Invalid slow log entry: {} {}", id, fields))
+                    })
+                })
+                .collect(),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SLOWLOG", &other)),
+        }
+    }
+    
+    /// Empty the server's slow log
+    pub async fn slowlog_reset(&mut self) -> Result<()> {
+        match self.send_command(&Command::SlowLogReset).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SLOWLOG", &other)),
+        }
+    }
+    
+    /// Remove every key on the server
+    ///
+    /// Servers refuse this with `ERROR command disabled` unless they were
+    /// started with `allow_flush_all`.
+    pub async fn flush_all(&mut self) -> Result<()> {
+        match self.send_command(&Command::FlushAll).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("FLUSHALL", &other)),
+        }
+    }
+    
+    /// Get a setting of the running server with `CONFIG GET`
+    ///
+    /// Settings are named as the [`ServerConfig`](crate::ServerConfig)
+    /// fields are, and given as in its config file.
+    pub async fn config_get(&mut self, key: &str) -> Result<String> {
+        let command = Command::Config { action: ConfigAction::Get, key: key.to_string() };
+        match self.send_command(&command).await? {
+            Response::Info(fields) => match fields.into_iter().next() {
+                Some((_, value)) => Ok(value),
+                None => Err(RustVaultError::Remote {
+                    code: ErrorCode::Invalid,
+                    message: format!("unknown config parameter {}", key),
+                }),
+            },
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("CONFIG", &other)),
+        }
+    }
+    
+    /// Change a setting of the running server with `CONFIG SET`
+    ///
+    /// The change applies to the commands that follow, on every connection,
+    /// and lasts until the server restarts. Settings fixed at startup, such
+    /// as `bind_addr`, are refused.
+    pub async fn config_set(&mut self, key: &str, value: &str) -> Result<()> {
+        let command = Command::Config {
+            action: ConfigAction::Set { value: value.to_string() },
+            key: key.to_string(),
+        };
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("CONFIG", &other)),
+        }
+    }
+    
+    /// Have the server write every key to a backup file at `path`, and
+    /// return how many it wrote
+    ///
+    /// The path is on the server's filesystem, not the client's.
+    pub async fn backup(&mut self, path: &str) -> Result<u64> {
+        let command = Command::Backup { path: path.to_string() };
+        match self.send_command(&command).await? {
+            Response::Integer(n) => Ok(n.max(0) as u64),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("BACKUP", &other)),
+        }
+    }
+    
+    /// Have the server load the backup file at `path`, and return how many
+    /// keys it loaded
+    ///
+    /// Refused unless the server holds no keys, or `merge` is set, when the
+    /// backup's keys replace any it already has.
+    pub async fn restore(&mut self, path: &str, merge: bool) -> Result<u64> {
+        let command = Command::Restore { path: path.to_string(), merge };
+        match self.send_command(&command).await? {
+            Response::Integer(n) => Ok(n.max(0) as u64),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("RESTORE", &other)),
+        }
+    }
+    
+    /// Work in the keyspace `namespace` from now on, on this connection and
+    /// any the client reconnects with
+    ///
+    /// Names are 1 to 64 ASCII letters, digits, `_` or `-`; connections
+    /// start in `0`, the default namespace.
+    pub async fn select(&mut self, namespace: &str) -> Result<()> {
+        let command = Command::Select {
+            namespace: namespace.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => {
+                self.namespace = Some(namespace.to_string());
+                Ok(())
+            }
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SELECT", &other)),
+        }
+    }
+    
+    /// Remove every key in the selected namespace
+    ///
+    /// Refused like [`Client::flush_all`] unless the server allows it.
+    pub async fn flush_db(&mut self) -> Result<()> {
+        match self.send_command(&Command::FlushDb { namespace: None }).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("FLUSHDB", &other)),
+        }
+    }
+    
+    /// Number of keys in the selected namespace, including expired keys not
+    /// yet removed
+    pub async fn db_size(&mut self) -> Result<usize> {
+        match self.send_command(&Command::DbSize { namespace: None }).await? {
+            Response::Integer(n) => Ok(n.max(0) as usize),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("DBSIZE", &other)),
+        }
+    }
+    
+    /// Receive an event for every change to a key matching `pattern`
+    ///
+    /// `*` in the pattern matches any run of characters and `?` any one.
+    /// The connection carries nothing but events from then on, so the
+    /// client is consumed; drop or close the subscription to stop.
+    pub async fn subscribe(mut self, pattern: &str) -> Result<Subscription> {
+        let command = Command::Subscribe {
+            pattern: pattern.to_string(),
+        };
+        
+        match self.send_command(&command).await? {
+            Response::Ok => Ok(Subscription { client: self }),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SUBSCRIBE", &other)),
+        }
+    }
+    
+    /// Poll `key` every `interval`, yielding its value each time it changes
+    ///
+    /// The value when the watch starts is read before this returns and
+    /// isn't yielded; each item after that differs from the one before,
+    /// with `None` for a deleted key. Changes between two polls are seen
+    /// as one. A consumer that falls behind holds up the polling rather
+    /// than letting values queue: at most one waits to be read. An error
+    /// ends the stream after it is yielded.
+    ///
+    /// The client moves into a background task. Drop the stream to stop;
+    /// the task notices between commands, never partway through one, and
+    /// closes the connection.
+    ///
+    /// ```no_run
+    /// # async fn example() -> rustvault::Result<()> {
+    /// use std::time::Duration;
+    ///
+    /// let client = rustvault::Client::connect("127.0.0.1:8080").await?;
+    /// let mut watch = client.watch_value("queue:depth", Duration::from_millis(500)).await?;
+    /// while let Some(depth) = watch.next().await {
+    ///     println!("queue depth is now {:?}", depth?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn watch_value(mut self, key: &str, interval: Duration) -> Result<ValueWatch> {
+        let mut last = self.get(key).await?;
+        let key = key.to_string();
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticks.tick().await;
+            loop {
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    _ = ticks.tick() => {}
+                }
+                match self.get(&key).await {
+                    Ok(value) if value == last => {}
+                    Ok(value) => {
+                        last.clone_from(&value);
+                        if tx.send(Ok(value)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        break;
+                    }
+                }
+            }
+            let _ = self.close().await;
+        });
+        Ok(ValueWatch { rx })
+    }
+    
+    /// Get a page of up to `count` keys starting with `prefix`
+    ///
+    /// Pass 0 as `cursor` to start, then the cursor of each page to
+    /// continue; the scan is complete when it comes back as 0. Keys present
+    /// for the whole scan are returned exactly once, while keys written or
+    /// deleted meanwhile may or may not be.
+    pub async fn scan(&mut self, prefix: &str, cursor: u64, count: usize) -> Result<ScanPage> {
+        let command = Command::Scan {
+            prefix: prefix.to_string(),
+            cursor,
+            count,
+        };
+        match self.send_command(&command).await? {
+            Response::Keys { keys, cursor } => Ok(ScanPage { keys, cursor }),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SCAN", &other)),
+        }
+    }
+    
+    /// Iterate over every key starting with `prefix`, fetching `count` at a
+    /// time with [`Client::scan`]
+    pub fn scan_iter(&mut self, prefix: &str, count: usize) -> ScanIter<'_> {
+        ScanIter {
+            client: self,
+            prefix: prefix.to_string(),
+            count,
+            cursor: Some(0),
+            page: Vec::new().into_iter(),
+        }
+    }
+    
+    /// Stream every key starting with `prefix` along with its value
+    ///
+    /// Each page of keys comes from a SCAN and its values from one MGET, so
+    /// a key deleted in between is left out; values must be UTF-8. When a
+    /// page can't be fetched, the stream yields a
+    /// [`RustVaultError::ScanInterrupted`] with the cursor to resume from
+    /// with [`Client::scan_stream_from`], and ends.
+    pub fn scan_stream(&mut self, prefix: &str) -> ScanStream<'_> {
+        self.scan_stream_from(prefix, 0)
+    }
+    
+    /// [`Client::scan_stream`] starting at `cursor`, as an interrupted one
+    /// reported
+    pub fn scan_stream_from(&mut self, prefix: &str, cursor: u64) -> ScanStream<'_> {
+        ScanStream {
+            state: ScanState::Idle(PagedScan {
+                client: self,
+                prefix: prefix.to_string(),
+                cursor: Some(cursor),
+                pairs: Vec::new().into_iter(),
+            }),
+        }
+    }
+    
+    /// Send an arbitrary command and return the response frame uninterpreted
+    ///
+    /// Parts are joined with single spaces. Only the last part may contain
+    /// spaces (it becomes the rest of the line, like a SET value), and no
+    /// part may contain a line break, since either would change how the
+    /// server splits the command. The exception is a `SET <key> <value>`
+    /// whose value the line can't carry, which is sent length-prefixed like
+    /// [`Client::set`] does. Exactly one response frame is read back, so the
+    /// connection stays usable even if the server rejects the command.
+    pub async fn execute_raw(&mut self, parts: &[&str]) -> Result<RawResponse> {
+        self.check_usable()?;
+        let line = encode_raw(parts)?;
+        self.poisoned = true;
+        self.writer.write_all(&line).await?;
+        self.writer.flush().await?;
+        
+        let frame = read_frame(&mut self.reader).await?;
+        self.poisoned = false;
+        let response = parse_raw_response(&frame)?;
+        if let RawResponse::Hello(version) = response {
+            self.protocol = version;
+        }
+        Ok(response)
+    }
+    
+    /// Like [`Client::execute_raw`], splitting `line` with [`split_command_line`]
+    pub async fn execute_raw_str(&mut self, line: &str) -> Result<RawResponse> {
+        let parts = split_command_line(line)?;
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        self.execute_raw(&parts).await
+    }
+    
+    /// Load key-value pairs from an iterator, one SET per pair
+    ///
+    /// For large loads, [`ClientPool::load_from_stream`] batches the pairs
+    /// into MSETs and spreads them across several connections.
+    ///
+    /// Errors returned by the server for an individual key are collected in
+    /// the report instead of aborting the load. Connection-level failures
+    /// (IO or framing errors) stop the load and are returned as `Err`, since
+    /// the connection can no longer be trusted.
+    pub async fn load_from_iter<I>(&mut self, items: I) -> Result<LoadReport>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let start = Instant::now();
+        let mut loaded = 0;
+        let mut failed = Vec::new();
+        
+        for (key, value) in items {
+            match self.set(&key, &value).await {
+                Ok(()) => loaded += 1,
+                Err(e @ (RustVaultError::Server(_) | RustVaultError::Remote { .. })) => failed.push((key, e)),
+                Err(e) => return Err(e),
+            }
+        }
+        
+        let elapsed = start.elapsed();
+        let rate = loaded as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        
+        Ok(LoadReport {
+            loaded,
+            failed,
+            unsent: Vec::new(),
+            elapsed,
+            rate,
+        })
+    }
+    
+    /// Close the connection
+    pub async fn close(mut self) -> Result<()> {
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Keys from a scan driven page by page; see [`Client::scan_iter`]
+pub struct ScanIter<'a> {
+    client: &'a mut Client,
+    prefix: String,
+    count: usize,
+    /// Cursor of the next page to fetch, or `None` once the last was fetched
+    cursor: Option<u64>,
+    page: std::vec::IntoIter<String>,
+}
+
+impl ScanIter<'_> {
+    /// The next key, or `None` once the scan is complete
+    pub async fn next(&mut self) -> Result<Option<String>> {
+        loop {
+            if let Some(key) = self.page.next() {
+                return Ok(Some(key));
+            }
+            let Some(cursor) = self.cursor else {
+                return Ok(None);
+            };
+            let page = self.client.scan(&self.prefix, cursor, self.count).await?;
+            self.cursor = (page.cursor != 0).then_some(page.cursor);
+            self.page = page.keys.into_iter();
+        }
+    }
+}
+
+/// Key-value pairs from a scan driven page by page; see
+/// [`Client::scan_stream`]
+///
+/// A [`Stream`](futures_core::Stream), or read it with [`ScanStream::next`].
+pub struct ScanStream<'a> {
+    state: ScanState<'a>,
+}
+
+/// A page being fetched, which hands the scan back when done
+type PageFetch<'a> = Pin<Box<dyn Future<Output = (PagedScan<'a>, Result<()>)> + Send + 'a>>;
+
+enum ScanState<'a> {
+    /// Handing out the pairs of the last page fetched
+    Idle(PagedScan<'a>),
+    Fetching(PageFetch<'a>),
+    /// The last page was handed out, or a fetch failed
+    Done,
+}
+
+struct PagedScan<'a> {
+    client: &'a mut Client,
+    prefix: String,
+    /// Cursor of the next page to fetch, or `None` once the last was fetched
+    cursor: Option<u64>,
+    pairs: std::vec::IntoIter<(String, String)>,
+}
+
+impl PagedScan<'_> {
+    /// Fetch the page at `cursor` and the values of its keys
+    async fn fetch(&mut self, cursor: u64) -> Result<()> {
+        let page = self.client.scan(&self.prefix, cursor, SCAN_STREAM_PAGE).await?;
+        let keys: Vec<&str> = page.keys.iter().map(String::as_str).collect();
+        let values = if keys.is_empty() { Vec::new() } else { self.client.mget(&keys).await? };
+        let pairs: Vec<_> = page
+            .keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        self.pairs = pairs.into_iter();
+        self.cursor = (page.cursor != 0).then_some(page.cursor);
+        Ok(())
+    }
+}
+
+impl ScanStream<'_> {
+    /// The next pair, or `None` once the scan is complete or has failed
+    pub async fn next(&mut self) -> Option<Result<(String, String)>> {
+        std::future::poll_fn(|cx| futures_core::Stream::poll_next(Pin::new(&mut *self), cx)).await
+    }
+}
+
+impl futures_core::Stream for ScanStream<'_> {
+    type Item = Result<(String, String)>;
+    
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match std::mem::replace(&mut this.state, ScanState::Done) {
+                ScanState::Idle(mut scan) => {
+                    if let Some(pair) = scan.pairs.next() {
+                        this.state = ScanState::Idle(scan);
+                        return Poll::Ready(Some(Ok(pair)));
+                    }
+                    let Some(cursor) = scan.cursor else {
+                        return Poll::Ready(None);
+                    };
+                    this.state = ScanState::Fetching(Box::pin(async move {
+                        let fetched = scan.fetch(cursor).await.map_err(|source| RustVaultError::ScanInterrupted {
+                            cursor,
+                            source: Box::new(source),
+                        });
+                        (scan, fetched)
+                    }));
+                }
+                ScanState::Fetching(mut fetch) => match fetch.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        this.state = ScanState::Fetching(fetch);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready((scan, Ok(()))) => this.state = ScanState::Idle(scan),
+                    Poll::Ready((_, Err(e))) => return Poll::Ready(Some(Err(e))),
+                },
+                ScanState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Change events from a subscribed connection; see [`Client::subscribe`]
+pub struct Subscription {
+    client: Client,
+}
+
+impl Subscription {
+    /// The next event, waiting for one; `None` once the server has closed
+    /// the connection
+    ///
+    /// [`KeyEvent::Lagged`] means the server dropped events because they
+    /// weren't read quickly enough, so any key may have changed unseen.
+    pub async fn next(&mut self) -> Result<Option<KeyEvent>> {
+        if self.client.reader.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
+        match parse_response_frame(&read_frame(&mut self.client.reader).await?)? {
+            Response::Event(event) => Ok(Some(event)),
+            Response::Error(e) => Err(RustVaultError::from_reply(e)),
+            other => Err(unexpected_response("SUBSCRIBE", &other)),
+        }
+    }
+    
+    /// Close the connection, ending the subscription
+    pub async fn close(self) -> Result<()> {
+        self.client.close().await
+    }
+}
+
+/// Changing values of a watched key; see [`Client::watch_value`]
+///
+/// A [`Stream`](futures_core::Stream), or read it with [`ValueWatch::next`].
+pub struct ValueWatch {
+    rx: mpsc::Receiver<Result<Option<String>>>,
+}
+
+impl ValueWatch {
+    /// The key's next value, waiting for it to change; `None` once the
+    /// watch has ended after an error
+    pub async fn next(&mut self) -> Option<Result<Option<String>>> {
+        self.rx.recv().await
+    }
+}
+
+impl futures_core::Stream for ValueWatch {
+    type Item = Result<Option<String>>;
+    
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// A lock taken with [`Client::lock`]
+///
+/// Dropping the guard releases the lock as best it can: from a task of its
+/// own, on a new connection, without waiting on it. If that fails the lease
+/// still runs out. [`LockGuard::release`] releases it on a client already
+/// connected and reports whether it was still held. The lease runs out
+/// after its TTL even while the guard is alive, so renew a long-held lock
+/// with [`LockGuard::renew`].
+#[derive(Debug)]
+pub struct LockGuard {
+    addr: String,
+    config: ClientConfig,
+    namespace: Option<String>,
+    key: String,
+    token: String,
+    released: bool,
+}
+
+impl LockGuard {
+    /// The lock's key
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    
+    /// The token the lock is held with
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+    
+    /// Extend the lease to `ttl` from now through `client`, which must be
+    /// in the namespace the lock was taken in; false if it had already run
+    /// out and another client has taken the lock
+    pub async fn renew(&self, client: &mut Client, ttl: Duration) -> Result<bool> {
+        client.try_lock(&self.key, &self.token, ttl).await
+    }
+    
+    /// Release the lock through `client`, which must be in the namespace
+    /// the lock was taken in; false if the lease had already run out
+    pub async fn release(mut self, client: &mut Client) -> Result<bool> {
+        self.released = true;
+        client.unlock(&self.key, &self.token).await
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let (addr, config, namespace) = (self.addr.clone(), self.config.clone(), self.namespace.take());
+        let (key, token) = (std::mem::take(&mut self.key), std::mem::take(&mut self.token));
+        runtime.spawn(async move {
+            let mut client = Client::connect_with_config(&addr, config).await?;
+            if let Some(namespace) = namespace {
+                client.select(&namespace).await?;
+            }
+            client.unlock(&key, &token).await?;
+            client.close().await
+        });
+    }
+}
+
+/// Commands queued to be sent to the server in one round trip
+///
+/// ```no_run
+/// # async fn example(client: &mut rustvault::Client) -> rustvault::Result<()> {
+/// let mut pipeline = rustvault::Pipeline::new();
+/// pipeline.set("a", "1").set("b", "2").get("a");
+/// let responses = pipeline.execute(client).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    commands: Vec<Command>,
+}
+
+impl Pipeline {
+    /// Create an empty pipeline
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Queue a SET
+    pub fn set(&mut self, key: &str, value: impl AsRef<[u8]>) -> &mut Self {
+        self.command(Command::Set {
+            key: key.to_string(),
+            value: value.as_ref().to_vec(),
+            condition: None,
+        })
+    }
+    
+    /// Queue a GET
+    pub fn get(&mut self, key: &str) -> &mut Self {
+        self.command(Command::Get { key: key.to_string() })
+    }
+    
+    /// Queue a DELETE
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.command(Command::Delete { key: key.to_string() })
+    }
+    
+    /// Queue any other command
+    pub fn command(&mut self, command: Command) -> &mut Self {
+        self.commands.push(command);
+        self
+    }
+    
+    /// Number of queued commands
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+    
+    /// Whether no commands are queued
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+    
+    /// Send every queued command at once and read back one response each,
+    /// in the order the commands were queued
+    ///
+    /// The server answers each command whether or not the one before it
+    /// failed, so a command it rejects gets a `Response::Error` in its slot
+    /// and the responses after it still line up. Responses are read while
+    /// the commands are still being written: otherwise a pipeline larger
+    /// than the socket buffers would stall with both sides waiting to
+    /// write. An IO or framing error fails the whole call and leaves the
+    /// connection unusable, since the remaining responses can no longer be
+    /// matched to their commands.
+    pub async fn execute(&self, client: &mut Client) -> Result<Vec<Response>> {
+        client.check_usable()?;
+        let mut frames = Vec::new();
+        for command in &self.commands {
+            frames.extend_from_slice(&encode_command(command));
+        }
+        
+        let Client { reader, writer, .. } = client;
+        let send = async {
+            writer.write_all(&frames).await?;
+            writer.flush().await
+        };
+        let receive = async {
+            let mut responses = Vec::with_capacity(self.commands.len());
+            for _ in &self.commands {
+                responses.push(read_response(reader).await?);
+            }
+            Ok::<_, RustVaultError>(responses)
+        };
+        match tokio::try_join!(async { send.await.map_err(RustVaultError::from) }, receive) {
+            Ok(((), responses)) => Ok(responses),
+            Err(e) => {
+                client.poisoned = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Commands applied together by the server, with no other client seeing
+/// some of them done and the rest not
+///
+/// Only SET, GET and DELETE can be part of a transaction. Its reads see the
+/// writes queued before them.
+///
+/// ```no_run
+/// # async fn example(client: &mut rustvault::Client) -> rustvault::Result<()> {
+/// client.watch(&["balance"]).await?;
+/// let mut transaction = rustvault::Transaction::new();
+/// transaction.set("balance", "90").set("log", "withdrew 10");
+/// if transaction.exec(client).await?.is_none() {
+///     // Someone else changed the balance first
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    commands: Vec<Command>,
+}
+
+impl Transaction {
+    /// Create an empty transaction
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Queue a SET
+    pub fn set(&mut self, key: &str, value: impl AsRef<[u8]>) -> &mut Self {
+        self.commands.push(Command::Set {
+            key: key.to_string(),
+            value: value.as_ref().to_vec(),
+            condition: None,
+        });
+        self
+    }
+    
+    /// Queue a SET that expires after `seconds`
+    pub fn set_with_ttl(&mut self, key: &str, value: impl AsRef<[u8]>, seconds: u64) -> &mut Self {
+        self.commands.push(Command::SetEx {
+            key: key.to_string(),
+            value: value.as_ref().to_vec(),
+            seconds,
+            condition: None,
+        });
+        self
+    }
+    
+    /// Queue a GET
+    pub fn get(&mut self, key: &str) -> &mut Self {
+        self.commands.push(Command::Get { key: key.to_string() });
+        self
+    }
+    
+    /// Queue a DELETE
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.commands.push(Command::Delete { key: key.to_string() });
+        self
+    }
+    
+    /// Number of queued commands
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+    
+    /// Whether no commands are queued
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+    
+    /// Apply the queued commands in one step and return one response each,
+    /// in the order they were queued
+    ///
+    /// Returns `None`, having applied nothing, if a key passed to
+    /// [`Client::watch`] changed since. Either way the watches are cleared.
+    /// A command the server refuses to queue fails the whole call, and
+    /// none of them are applied.
+    pub async fn exec(&self, client: &mut Client) -> Result<Option<Vec<Response>>> {
+        let mut pipeline = Pipeline::new();
+        pipeline.command(Command::Multi);
+        for command in &self.commands {
+            pipeline.command(command.clone());
+        }
+        pipeline.command(Command::Exec);
+        
+        let mut responses = pipeline.execute(client).await?;
+        let exec = responses.pop();
+        for response in responses {
+            match response {
+                Response::Ok | Response::Queued => {}
+                Response::Error(e) => return Err(RustVaultError::from_reply(e)),
+                other => return Err(unexpected_response("MULTI", &other)),
+            }
+        }
+        match exec {
+            Some(Response::Results(results)) if results.len() == self.commands.len() => Ok(Some(results)),
+            Some(Response::Conflict) => Ok(None),
+            Some(Response::Error(e)) => Err(RustVaultError::from_reply(e)),
+            Some(other) => Err(unexpected_response("EXEC", &other)),
+            None => Err(closed_early()),
+        }
+    }
+}
+
+/// Open a connection to `addr`, split into buffered halves
+///
+/// The address is checked first, so a malformed one fails with
+/// `InvalidAddress` rather than an IO error.
+async fn open(addr: &str, timeout: Option<Duration>) -> Result<(BufReader<ReadHalf>, BufWriter<WriteHalf>)> {
+    check_addr(addr)?;
+    let (read_half, write_half) = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, transport::connect(addr))
+            .await
+            .map_err(|_| RustVaultError::ConnectTimeout {
+                addr: addr.to_string(),
+                timeout,
+            })??,
+        None => transport::connect(addr).await?,
+    };
+    Ok((BufReader::new(read_half), BufWriter::new(write_half)))
+}
+
+/// The error for a response cut off by the server closing the connection
+fn closed_early() -> RustVaultError {
+    RustVaultError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Connection closed before a complete response",
+    ))
+}
+
+/// Interpret a value as text, for the string convenience methods
+fn into_text(value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value)
+        .map_err(|_| RustVaultError::Client("Value is not valid UTF-8".to_string()))
+}
+
+/// Parse a hex digest from a CHECKSUM reply
+fn parse_digest(digest: &str) -> Result<u64> {
+    u64::from_str_radix(digest, 16).map_err(|_| {
+        RustVaultError::Client(format!("Invalid checksum digest: {:?}", digest))
+    })
+}
+
+/// Await an IO operation, failing with `TimedOut` if it takes too long
+async fn with_timeout<T, F>(timeout: Option<Duration>, operation: F) -> Result<T>
+where
+    F: std::future::Future<Output = std::io::Result<T>>,
+{
+    let result = match timeout {
+        Some(limit) => tokio::time::timeout(limit, operation).await.unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out streaming a value",
+            ))
+        }),
+        None => operation.await,
+    };
+    Ok(result?)
+}
+
+/// Parse a server response line, without its line terminator
+fn parse_response(response: &str) -> Result<Response> {
+    let (head, rest) = match response.split_once(' ') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (response, None),
+    };
+    
+    match (head, rest) {
+        ("OK", None) => Ok(Response::Ok),
+        ("NOT_FOUND", None) => Ok(Response::NotFound),
+        ("CONFLICT", None) => Ok(Response::Conflict),
+        ("NOT_APPLIED", None) => Ok(Response::NotApplied),
+        ("QUEUED", None) => Ok(Response::Queued),
+        ("PONG", None) => Ok(Response::Pong),
+        ("HELLO", Some(version)) => version.parse().map(Response::Hello).map_err(|_| {
+            ProtocolError::new(ProtocolErrorKind::ExpectedArgument, response.as_bytes(), 6).into()
+        }),
+        ("VALUE", Some(value)) => Ok(Response::Value(value.as_bytes().to_vec())),
+        ("ERROR", Some(error)) => Ok(Response::Error(error.to_string())),
+        ("INT", Some(n)) => n.parse().map(Response::Integer).map_err(|_| {
+            ProtocolError::new(ProtocolErrorKind::ExpectedArgument, response.as_bytes(), 4).into()
+        }),
+        ("EVENT", Some(event)) => KeyEvent::parse(event).map(Response::Event).ok_or_else(|| {
+            ProtocolError::new(ProtocolErrorKind::ExpectedArgument, response.as_bytes(), 6).into()
+        }),
+        ("STAT", Some(fields)) => parse_stat(fields)
+            .map(|KeyStat { version, created_at, updated_at }| Response::Stat { version, created_at, updated_at })
+            .ok_or_else(|| ProtocolError::new(ProtocolErrorKind::ExpectedArgument, response.as_bytes(), 5).into()),
+        ("COMPACTED", Some(fields)) => parse_compacted(fields)
+            .map(|CompactionReport { before, after, keys }| Response::Compacted { before, after, keys })
+            .ok_or_else(|| ProtocolError::new(ProtocolErrorKind::ExpectedArgument, response.as_bytes(), 10).into()),
+        ("OK" | "NOT_FOUND" | "CONFLICT" | "QUEUED" | "PONG", Some(_)) => Err(ProtocolError::new(
+            ProtocolErrorKind::ExpectedLineEnding,
+            response.as_bytes(),
+            head.len(),
+        )
+        .into()),
+        _ => Err(ProtocolError::new(
+            ProtocolErrorKind::UnknownResponse,
+            response.as_bytes(),
+            0,
+        )
+        .into()),
+    }
+}
+
+/// The version and timestamps of a `STAT` reply, after its `STAT `
+fn parse_stat(fields: &str) -> Option<KeyStat> {
+    let mut fields = fields.split(' ').map(|field| field.parse::<u64>().ok());
+    let stat = KeyStat {
+        version: fields.next()??,
+        created_at: fields.next()??,
+        updated_at: fields.next()??,
+    };
+    fields.next().is_none().then_some(stat)
+}
+
+/// The sizes and key count of a `COMPACTED` reply, after its `COMPACTED `:
+/// `<before> -> <after> (<keys> entries)`
+fn parse_compacted(fields: &str) -> Option<CompactionReport> {
+    let (before, rest) = fields.split_once(" -> ")?;
+    let (after, rest) = rest.split_once(" (")?;
+    let keys = rest.strip_suffix(" entries)")?;
+    Some(CompactionReport { before: before.parse().ok()?, after: after.parse().ok()?, keys: keys.parse().ok()? })
+}
+
+/// Split a command line into words, honouring double quotes
+///
+/// `"hello world"` is one word; inside quotes `\"` and `\\` escape a quote
+/// and a backslash, and `\n`, `\r` and `\t` stand for a newline, carriage
+/// return and tab. This is the tokenizer the interactive client uses.
+pub fn split_command_line(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars();
+    
+    loop {
+        // Skip whitespace between words
+        let first = match chars.by_ref().find(|c| !c.is_whitespace()) {
+            Some(c) => c,
+            None => return Ok(words),
+        };
+        
+        let mut word = String::new();
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\')) => word.push(c),
+                        Some('n') => word.push('\n'),
+                        Some('r') => word.push('\r'),
+                        Some('t') => word.push('\t'),
+                        Some(c) => {
+                            word.push('\\');
+                            word.push(c);
+                        }
+                        None => break,
+                    },
+                    Some(c) => word.push(c),
+                    None => {
+                        return Err(RustVaultError::Client(
+                            "Unterminated quote in command".to_string(),
+                        ))
+                    }
+                }
+            }
+        } else {
+            word.push(first);
+            for c in chars.by_ref() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+/// Serialize a command to its protocol frame
+pub(crate) fn encode_command(command: &Command) -> Vec<u8> {
+    match command {
+        Command::Set { key, value, condition } => encode_set(key, value, None, *condition),
+        Command::SetEx { key, value, seconds, condition } => encode_set(key, value, Some(*seconds), *condition),
+        Command::Get { key } => format!("GET {}\r\n", key).into_bytes(),
+        Command::Exists { key } => format!("EXISTS {}\r\n", key).into_bytes(),
+        Command::Stat { key } => format!("STAT {}\r\n", key).into_bytes(),
+        Command::Subscribe { pattern } => format!("SUBSCRIBE {}\r\n", pattern).into_bytes(),
+        Command::Replicate => b"REPLICATE\r\n".to_vec(),
+        Command::Multi => b"MULTI\r\n".to_vec(),
+        Command::Exec => b"EXEC\r\n".to_vec(),
+        Command::Discard => b"DISCARD\r\n".to_vec(),
+        Command::Watch { keys } => format!("WATCH {}\r\n", keys.join(" ")).into_bytes(),
+        Command::Select { namespace } => format!("SELECT {}\r\n", namespace).into_bytes(),
+        Command::FlushDb { namespace: None } => b"FLUSHDB\r\n".to_vec(),
+        Command::FlushDb { namespace: Some(namespace) } => format!("FLUSHDB {}\r\n", namespace).into_bytes(),
+        Command::DbSize { namespace: None } => b"DBSIZE\r\n".to_vec(),
+        Command::DbSize { namespace: Some(namespace) } => format!("DBSIZE {}\r\n", namespace).into_bytes(),
+        Command::Delete { key } => format!("DELETE {}\r\n", key).into_bytes(),
+        Command::DeletePattern { pattern } => format!("DELPAT {}\r\n", pattern).into_bytes(),
+        Command::Expire { key, seconds } => format!("EXPIRE {} {}\r\n", key, seconds).into_bytes(),
+        Command::ExpireAt { key, unix_millis } => {
+            format!("PEXPIREAT {} {}\r\n", key, unix_millis).into_bytes()
+        }
+        Command::Shrink => b"SHRINK\r\n".to_vec(),
+        Command::Compact => b"COMPACT\r\n".to_vec(),
+        Command::WalStats => b"WALSTATS\r\n".to_vec(),
+        Command::CommandInfo { name } => format!("COMMAND INFO {}\r\n", name).into_bytes(),
+        Command::MaintenanceStatus => b"MAINTENANCE STATUS\r\n".to_vec(),
+        Command::Config { action: ConfigAction::Get, key } => format!("CONFIG GET {}\r\n", key).into_bytes(),
+        Command::Config { action: ConfigAction::Set { value }, key } => {
+            format!("CONFIG SET {} {}\r\n", key, value).into_bytes()
+        }
+        Command::Backup { path } => format!("BACKUP {}\r\n", path).into_bytes(),
+        Command::Restore { path, merge: false } => format!("RESTORE {}\r\n", path).into_bytes(),
+        Command::Restore { path, merge: true } => format!("RESTORE {} MERGE\r\n", path).into_bytes(),
+        Command::Info => b"INFO\r\n".to_vec(),
+        Command::StatsReset => b"STATS RESET\r\n".to_vec(),
+        Command::SlowLogGet { count: Some(count) } => format!("SLOWLOG GET {}\r\n", count).into_bytes(),
+        Command::SlowLogGet { count: None } => b"SLOWLOG GET\r\n".to_vec(),
+        Command::SlowLogReset => b"SLOWLOG RESET\r\n".to_vec(),
+        Command::Ping => b"PING\r\n".to_vec(),
+        Command::Ready => b"READY\r\n".to_vec(),
+        Command::FlushAll => b"FLUSHALL\r\n".to_vec(),
+        Command::Checksum { prefix } if prefix.is_empty() => b"CHECKSUM\r\n".to_vec(),
+        Command::Checksum { prefix } => format!("CHECKSUM {}\r\n", prefix).into_bytes(),
+        Command::ChecksumRanges { buckets, prefix } if prefix.is_empty() => {
+            format!("CHECKSUM RANGES {}\r\n", buckets).into_bytes()
+        }
+        Command::ChecksumRanges { buckets, prefix } => {
+            format!("CHECKSUM RANGES {} {}\r\n", buckets, prefix).into_bytes()
+        }
+        Command::Scan { prefix, cursor, count } if prefix.is_empty() => {
+            format!("SCAN {} {}\r\n", cursor, count).into_bytes()
+        }
+        Command::Scan { prefix, cursor, count } => {
+            format!("SCAN {} {} {}\r\n", cursor, count, prefix).into_bytes()
+        }
+        Command::Incr { key, delta } => format!("INCR {} {}\r\n", key, delta).into_bytes(),
+        Command::Decr { key, delta } => format!("DECR {} {}\r\n", key, delta).into_bytes(),
+        Command::Append { key, value } => encode_with_value("APPEND", key, value),
+        Command::Strlen { key } => format!("STRLEN {}\r\n", key).into_bytes(),
+        Command::GetSet { key, value } => encode_with_value("GETSET", key, value),
+        Command::GetDel { key } => format!("GETDEL {}\r\n", key).into_bytes(),
+        Command::Lock { key, token, ttl_ms } => format!("LOCK {} {} {}\r\n", key, token, ttl_ms).into_bytes(),
+        Command::Unlock { key, token } => format!("UNLOCK {} {}\r\n", key, token).into_bytes(),
+        Command::Auth { token } => format!("AUTH {}\r\n", token).into_bytes(),
+        Command::Hello { version } => format!("HELLO {}\r\n", version).into_bytes(),
+        Command::Cas { key, expected, new } => encode_cas(key, expected, new),
+        Command::MSet { pairs } => encode_mset(pairs),
+        Command::MGet { keys } => {
+            let mut line = String::from("MGET");
+            for key in keys {
+                line.push(' ');
+                line.push_str(key);
+            }
+            line.push_str("\r\n");
+            line.into_bytes()
+        }
+    }
+}
+
+/// Read one response frame and parse it
+async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Response> {
+    let frame = read_frame(reader).await?;
+    parse_response_frame(&frame)
+}
+
+/// Interpret a frame read by [`read_frame`]
+fn parse_response_frame(frame: &[u8]) -> Result<Response> {
+    if let Some(frames) = frame_results(frame) {
+        return frames
+            .into_iter()
+            .map(parse_response_frame)
+            .collect::<Result<_>>()
+            .map(Response::Results);
+    }
+    if let Some((keys, cursor)) = frame_keys(frame) {
+        return Ok(Response::Keys { keys, cursor });
+    }
+    if let Some(values) = frame_values(frame) {
+        return Ok(Response::Values(values));
+    }
+    if let Some(fields) = frame_info(frame) {
+        return Ok(Response::Info(fields));
+    }
+    if let Some(value) = frame_payload(frame) {
+        return Ok(Response::Value(value.to_vec()));
+    }
+    let line = str::from_utf8(frame).map_err(|_| {
+        RustVaultError::Client("Response is not valid UTF-8".to_string())
+    })?;
+    parse_response(line.trim())
+}
+
+/// Read one response frame; see [`ResponseReader`]
+async fn read_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    ResponseReader::new(reader).read_frame().await
+}
+
+/// Reads whole response frames off a connection
+///
+/// A frame is its header line plus everything the header announces: the
+/// value and CRLF that follow a length-prefixed `VALUE $<len>` line, the
+/// lines that follow `KEYS <n> <cursor>` or `INFO <n>`, the entries that
+/// follow `VALUES <n>`, or the nested frames that follow `RESULTS <n>`.
+/// Exactly that much is read whatever the values hold, so a value with a
+/// line break in it can't be taken for the next response.
+struct ResponseReader<'a, R> {
+    reader: &'a mut R,
+    frame: Vec<u8>,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> ResponseReader<'a, R> {
+    fn new(reader: &'a mut R) -> Self {
+        Self { reader, frame: Vec::new() }
+    }
+    
+    /// Read the next frame, as the bytes it came in
+    async fn read_frame(mut self) -> Result<Vec<u8>> {
+        let header = self.read_line().await?;
+        match results_header(&self.frame[header..]) {
+            Some(count) => {
+                for _ in 0..count {
+                    let nested = self.read_line().await?;
+                    self.read_body(nested).await?;
+                }
+            }
+            None => self.read_body(header).await?,
+        }
+        Ok(self.frame)
+    }
+    
+    /// Read whatever follows the header line starting at `header`
+    async fn read_body(&mut self, header: usize) -> Result<()> {
+        let line = &self.frame[header..];
+        if let Some(len) = payload_len(line)? {
+            self.read_payload(len).await?;
+        } else if let Some(lines) = keys_header(line).map(|(keys, _)| keys).or_else(|| info_header(line)) {
+            for _ in 0..lines {
+                self.read_line().await?;
+            }
+        } else if let Some(count) = values_header(line) {
+            for _ in 0..count {
+                let entry = self.read_line().await?;
+                if let Some(len) = entry_len(&self.frame[entry..])? {
+                    self.read_payload(len).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Read one line, returning where in the frame it starts
+    async fn read_line(&mut self) -> Result<usize> {
+        let start = self.frame.len();
+        self.reader.read_until(b'\n', &mut self.frame).await?;
+        if !self.frame.ends_with(b"\n") {
+            return Err(closed_early());
+        }
+        Ok(start)
+    }
+    
+    /// Read a `len`-byte value and the CRLF after it
+    async fn read_payload(&mut self, len: usize) -> Result<()> {
+        let start = self.frame.len();
+        self.frame.resize(start + len + 2, 0);
+        self.reader.read_exact(&mut self.frame[start..]).await?;
+        if !self.frame.ends_with(b"\r\n") {
+            let offset = self.frame.len() - 2;
+            return Err(ProtocolError::new(ProtocolErrorKind::ExpectedLineEnding, &self.frame, offset).into());
+        }
+        Ok(())
+    }
+}
+
+/// Length of the frame `bytes` starts with, read as [`ResponseReader`]
+/// reads a nested frame, or `None` if `bytes` is cut short
+fn frame_len(bytes: &[u8]) -> Option<usize> {
+    let line_end = |start: usize| Some(start + bytes.get(start..)?.iter().position(|&b| b == b'\n')? + 1);
+    let header_end = line_end(0)?;
+    let line = &bytes[..header_end];
+    let mut end = header_end;
+    if let Some(len) = payload_len(line).ok()? {
+        end += len + 2;
+    } else if let Some(lines) = keys_header(line).map(|(keys, _)| keys).or_else(|| info_header(line)) {
+        for _ in 0..lines {
+            end = line_end(end)?;
+        }
+    } else if let Some(count) = values_header(line) {
+        for _ in 0..count {
+            let entry_end = line_end(end)?;
+            end = match entry_len(&bytes[end..entry_end]).ok()? {
+                Some(len) => entry_end + len + 2,
+                None => entry_end,
+            };
+        }
+    }
+    (end <= bytes.len()).then_some(end)
+}
+
+/// Encode a SET, length-prefixing the value if the inline form can't
+/// carry it
+fn encode_set(key: &str, value: &[u8], ttl: Option<u64>, condition: Option<SetCondition>) -> Vec<u8> {
+    let mut options = ttl.map(|seconds| format!(" EX {}", seconds)).unwrap_or_default();
+    if let Some(condition) = condition {
+        options.push(' ');
+        options.push_str(condition.as_str());
+    }
+    let mut frame = Vec::with_capacity(key.len() + value.len() + options.len() + 32);
+    if needs_length_prefix(value) {
+        frame.extend_from_slice(format!("SET {} ${}{}\r\n", key, value.len(), options).as_bytes());
+        frame.extend_from_slice(value);
+    } else {
+        frame.extend_from_slice(format!("SET {} ", key).as_bytes());
+        frame.extend_from_slice(value);
+        frame.extend_from_slice(options.as_bytes());
+    }
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// Length of the value announced by a `VALUES` entry line, or `None` for
+/// `NIL`
+fn entry_len(line: &[u8]) -> Result<Option<usize>> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line);
+    if line == b"NIL" {
+        return Ok(None);
+    }
+    match line.strip_prefix(b"$").and_then(|digits| str::from_utf8(digits).ok()?.parse().ok()) {
+        Some(len) if len <= MAX_VALUE_LEN => Ok(Some(len)),
+        _ => Err(ProtocolError::new(ProtocolErrorKind::Malformed, line, 0).into()),
+    }
+}
+
+/// Encode an MSET, always length-prefixing every value
+fn encode_mset(pairs: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut line = String::from("MSET");
+    for (key, value) in pairs {
+        line.push_str(&format!(" {} ${}", key, value.len()));
+    }
+    line.push_str("\r\n");
+    
+    let mut frame = line.into_bytes();
+    for (i, (_, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            frame.extend_from_slice(b"\r\n");
+        }
+        frame.extend_from_slice(value);
+    }
+    if !pairs.is_empty() {
+        frame.extend_from_slice(b"\r\n");
+    }
+    frame
+}
+
+/// Encode an APPEND or GETSET, always length-prefixing the value
+fn encode_with_value(verb: &str, key: &str, value: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(key.len() + value.len() + 32);
+    frame.extend_from_slice(format!("{} {} ${}\r\n", verb, key, value.len()).as_bytes());
+    frame.extend_from_slice(value);
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// Encode a CAS, always length-prefixing both values
+fn encode_cas(key: &str, expected: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(key.len() + expected.len() + new.len() + 32);
+    frame.extend_from_slice(format!("CAS {} ${} ${}\r\n", key, expected.len(), new.len()).as_bytes());
+    frame.extend_from_slice(expected);
+    frame.extend_from_slice(b"\r\n");
+    frame.extend_from_slice(new);
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// Encode command parts as one protocol line, rejecting parts the line
+/// framing cannot carry
+fn encode_raw(parts: &[&str]) -> Result<Vec<u8>> {
+    if parts.is_empty() {
+        return Err(RustVaultError::Client("Empty command".to_string()));
+    }
+    
+    let last = parts.len() - 1;
+    // Checked like any other part below, except for the values themselves
+    let set_value = match parts {
+        ["SET", _, value] if needs_length_prefix(value.as_bytes()) => Some(value),
+        _ => None,
+    };
+    let cas_values = match parts {
+        ["CAS", _, expected, new] => Some((expected, new)),
+        _ => None,
+    };
+    for (i, part) in parts.iter().enumerate() {
+        if (i == last && set_value.is_some()) || (i >= 2 && cas_values.is_some()) {
+            continue;
+        }
+        if part.is_empty() {
+            return Err(RustVaultError::Client(format!("Argument {} is empty", i)));
+        }
+        if part.contains(['\r', '\n']) {
+            return Err(RustVaultError::Client(format!(
+                "Argument {} contains a line break",
+                i
+            )));
+        }
+        if i != last && part.contains(char::is_whitespace) {
+            return Err(RustVaultError::Client(format!(
+                "Argument {} contains whitespace; only the last argument may",
+                i
+            )));
+        }
+    }
+    
+    if let Some(value) = set_value {
+        return Ok(encode_set(parts[1], value.as_bytes(), None, None));
+    }
+    if let Some((expected, new)) = cas_values {
+        return Ok(encode_cas(parts[1], expected.as_bytes(), new.as_bytes()));
+    }
+    let mut line = parts.join(" ").into_bytes();
+    line.extend_from_slice(b"\r\n");
+    Ok(line)
+}
+
+/// The value carried after the header line of a length-prefixed frame, or
+/// `None` for a frame that is a single line
+fn frame_payload(frame: &[u8]) -> Option<&[u8]> {
+    let header_end = frame.iter().position(|&b| b == b'\n')? + 1;
+    if header_end == frame.len() {
+        return None;
+    }
+    frame[header_end..].strip_suffix(b"\r\n")
+}
+
+/// Keys and cursor of a `KEYS` frame, or `None` for any other frame
+fn frame_keys(frame: &[u8]) -> Option<(Vec<String>, u64)> {
+    let header_end = frame.iter().position(|&b| b == b'\n')? + 1;
+    let (_, cursor) = keys_header(&frame[..header_end])?;
+    let keys = frame[header_end..]
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).into_owned())
+        .collect();
+    Some((keys, cursor))
+}
+
+/// Fields of an `INFO` frame, or `None` for any other frame
+fn frame_info(frame: &[u8]) -> Option<Vec<(String, String)>> {
+    let header_end = frame.iter().position(|&b| b == b'\n')? + 1;
+    info_header(&frame[..header_end])?;
+    frame[header_end..]
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line));
+            let (name, value) = line.split_once(' ')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Values of a `VALUES` frame, or `None` for any other frame
+fn frame_values(frame: &[u8]) -> Option<Vec<Option<Vec<u8>>>> {
+    let header_end = frame.iter().position(|&b| b == b'\n')? + 1;
+    let count = values_header(&frame[..header_end])?;
+    let mut rest = &frame[header_end..];
+    let mut values = Vec::new();
+    for _ in 0..count {
+        let line_end = rest.iter().position(|&b| b == b'\n')? + 1;
+        let (line, after) = rest.split_at(line_end);
+        match entry_len(line).ok()? {
+            Some(len) => {
+                values.push(Some(after.get(..len)?.to_vec()));
+                rest = after.get(len + 2..)?;
+            }
+            None => {
+                values.push(None);
+                rest = after;
+            }
+        }
+    }
+    Some(values)
+}
+
+/// The nested frames of a `RESULTS` frame, or `None` for any other frame
+fn frame_results(frame: &[u8]) -> Option<Vec<&[u8]>> {
+    let header_end = frame.iter().position(|&b| b == b'\n')? + 1;
+    let count = results_header(&frame[..header_end])?;
+    let mut rest = &frame[header_end..];
+    let mut frames = Vec::with_capacity(count.min(rest.len()));
+    for _ in 0..count {
+        let (nested, after) = rest.split_at(frame_len(rest)?);
+        frames.push(nested);
+        rest = after;
+    }
+    Some(frames)
+}
+
+/// Split a raw response frame into its frame type and payload
+fn parse_raw_response(frame: &[u8]) -> Result<RawResponse> {
+    if let Some(frames) = frame_results(frame) {
+        return frames
+            .into_iter()
+            .map(parse_raw_response)
+            .collect::<Result<_>>()
+            .map(RawResponse::Results);
+    }
+    if let Some((keys, cursor)) = frame_keys(frame) {
+        return Ok(RawResponse::Keys { keys, cursor });
+    }
+    if let Some(values) = frame_values(frame) {
+        return Ok(RawResponse::Values(values));
+    }
+    if let Some(fields) = frame_info(frame) {
+        return Ok(RawResponse::Info(fields));
+    }
+    if let Some(value) = frame_payload(frame) {
+        return Ok(RawResponse::Value(value.to_vec()));
+    }
+    
+    let line = frame
+        .strip_suffix(b"\r\n")
+        .or_else(|| frame.strip_suffix(b"\n"))
+        .unwrap_or(frame);
+    
+    if line == b"OK" {
+        Ok(RawResponse::Ok)
+    } else if line == b"NOT_FOUND" {
+        Ok(RawResponse::NotFound)
+    } else if line == b"CONFLICT" {
+        Ok(RawResponse::Conflict)
+    } else if line == b"NOT_APPLIED" {
+        Ok(RawResponse::NotApplied)
+    } else if line == b"QUEUED" {
+        Ok(RawResponse::Queued)
+    } else if line == b"PONG" {
+        Ok(RawResponse::Pong)
+    } else if let Some(version) = line.strip_prefix(b"HELLO ") {
+        str::from_utf8(version)
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .map(RawResponse::Hello)
+            .ok_or_else(|| ProtocolError::new(ProtocolErrorKind::ExpectedArgument, line, 6).into())
+    } else if let Some(value) = line.strip_prefix(b"VALUE ") {
+        Ok(RawResponse::Value(value.to_vec()))
+    } else if let Some(fields) = line.strip_prefix(b"STAT ") {
+        str::from_utf8(fields)
+            .ok()
+            .and_then(parse_stat)
+            .map(RawResponse::Stat)
+            .ok_or_else(|| ProtocolError::new(ProtocolErrorKind::ExpectedArgument, line, 5).into())
+    } else if let Some(fields) = line.strip_prefix(b"COMPACTED ") {
+        str::from_utf8(fields)
+            .ok()
+            .and_then(parse_compacted)
+            .map(RawResponse::Compacted)
+            .ok_or_else(|| ProtocolError::new(ProtocolErrorKind::ExpectedArgument, line, 10).into())
+    } else if let Some(n) = line.strip_prefix(b"INT ") {
+        str::from_utf8(n)
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .map(RawResponse::Integer)
+            .ok_or_else(|| {
+                ProtocolError::new(ProtocolErrorKind::ExpectedArgument, line, 4).into()
+            })
+    } else if let Some(error) = line.strip_prefix(b"ERROR ") {
+        let error = String::from_utf8_lossy(error);
+        let (code, message) = match error.split_once(' ') {
+            Some((code, message))
+                if code.len() > 1
+                    && code.bytes().all(|b| b.is_ascii_uppercase() || b == b'_') =>
+            {
+                (Some(code.to_string()), message.to_string())
+            }
+            _ => (None, error.into_owned()),
+        };
+        Ok(RawResponse::Error { code, message })
+    } else {
+        Err(ProtocolError::new(ProtocolErrorKind::UnknownResponse, line, 0).into())
+    }
+}
+
+/// The verb an encoded request starts with, to name it in errors
+fn verb(request: &[u8]) -> String {
+    let end = request.iter().position(|&b| b == b' ' || b == b'\r' || b == b'\n').unwrap_or(request.len());
+    String::from_utf8_lossy(&request[..end]).into_owned()
+}
+
+/// Error for a well-formed response that doesn't answer `command`
+fn unexpected_response(command: &'static str, response: &Response) -> RustVaultError {
+    ProtocolError::new(
+        ProtocolErrorKind::UnexpectedResponse(command),
+        &response.to_bytes(),
+        0,
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(parse_response("OK").unwrap(), Response::Ok);
+        assert_eq!(parse_response("NOT_FOUND").unwrap(), Response::NotFound);
+        assert_eq!(parse_response("CONFLICT").unwrap(), Response::Conflict);
+        assert_eq!(parse_response("NOT_APPLIED").unwrap(), Response::NotApplied);
+        assert_eq!(
+            parse_response("VALUE test").unwrap(),
+            Response::Value(b"test".to_vec())
+        );
+        assert_eq!(parse_response("INT 42").unwrap(), Response::Integer(42));
+        assert!(parse_response("INT many").is_err());
+        assert_eq!(
+            parse_response("EVENT SET user:1").unwrap(),
+            Response::Event(KeyEvent::Set("user:1".to_string()))
+        );
+        assert!(parse_response("EVENT RENAME a b").is_err());
+        assert_eq!(
+            parse_response("ERROR test error").unwrap(),
+            Response::Error("test error".to_string())
+        );
+    }
+    
+    #[test]
+    fn test_parse_response_errors() {
+        let err = match parse_response("VALU\u{1b}x") {
+            Err(RustVaultError::Protocol(e)) => e,
+            other => panic!("expected a protocol error, got {:?}", other),
+        };
+        assert_eq!(err.kind, ProtocolErrorKind::UnknownResponse);
+        assert_eq!(err.offset, 0);
+        assert_eq!(err.snippet, "VALU\\x1bx");
+        
+        let err = match parse_response("OK extra") {
+            Err(RustVaultError::Protocol(e)) => e,
+            other => panic!("expected a protocol error, got {:?}", other),
+        };
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+        assert_eq!(err.offset, 2);
+        
+        assert_eq!(
+            unexpected_response("SET", &Response::NotFound).to_string(),
+            "Protocol error: unexpected response for SET near `NOT_FOUND`"
+        );
+    }
+    
+    #[test]
+    fn test_split_command_line() {
+        assert_eq!(
+            split_command_line("  set key  value ").unwrap(),
+            vec!["set", "key", "value"]
+        );
+        assert_eq!(
+            split_command_line(r#"SET greeting "hello \"big\" world""#).unwrap(),
+            vec!["SET", "greeting", r#"hello "big" world"#]
+        );
+        assert_eq!(split_command_line("").unwrap(), Vec::<String>::new());
+        assert!(split_command_line(r#"SET k "open"#).is_err());
+        assert_eq!(
+            split_command_line(r#"set k "a\tb\r\nc\d""#).unwrap(),
+            vec!["set", "k", "a\tb\r\nc\\d"]
+        );
+    }
+    
+    #[test]
+    fn test_encode_raw() {
+        assert_eq!(encode_raw(&["SET", "k", "a b"]).unwrap(), b"SET k a b\r\n");
+        assert!(encode_raw(&[]).is_err());
+        assert!(encode_raw(&["SET", "a b", "v"]).is_err());
+        assert!(encode_raw(&["GET", "k\r\nDELETE k"]).is_err());
+        
+        // SET values the line can't carry are length-prefixed instead
+        assert_eq!(
+            encode_raw(&["SET", "k", "v\r\nDELETE k"]).unwrap(),
+            b"SET k $11\r\nv\r\nDELETE k\r\n"
+        );
+        assert_eq!(encode_raw(&["SET", "k", ""]).unwrap(), b"SET k $0\r\n\r\n");
+        assert!(encode_raw(&["SET", "a b", " v"]).is_err());
+        assert!(encode_raw(&["GET", ""]).is_err());
+        
+        // ...and CAS values always are
+        assert_eq!(encode_raw(&["CAS", "k", "a b", ""]).unwrap(), b"CAS k $3 $0\r\na b\r\n\r\n");
+        assert!(encode_raw(&["CAS", "a b", "x", "y"]).is_err());
+    }
+    
+    #[test]
+    fn test_parse_raw_response() {
+        assert_eq!(parse_raw_response(b"OK\r\n").unwrap(), RawResponse::Ok);
+        assert_eq!(parse_raw_response(b"NOT_FOUND\r\n").unwrap(), RawResponse::NotFound);
+        assert_eq!(parse_raw_response(b"CONFLICT\r\n").unwrap(), RawResponse::Conflict);
+        assert_eq!(parse_raw_response(b"NOT_APPLIED\r\n").unwrap(), RawResponse::NotApplied);
+        assert_eq!(
+            parse_raw_response(b"VALUES 2\r\nNIL\r\n$3\r\na\nb\r\n").unwrap(),
+            RawResponse::Values(vec![None, Some(b"a\nb".to_vec())])
+        );
+        assert_eq!(parse_raw_response(b"VALUES 0\r\n").unwrap(), RawResponse::Values(Vec::new()));
+        assert_eq!(
+            parse_raw_response(b"VALUE \xff\x00\r\n").unwrap(),
+            RawResponse::Value(vec![0xff, 0x00])
+        );
+        assert_eq!(
+            parse_raw_response(b"ERROR LOADING dataset is being restored\r\n").unwrap(),
+            RawResponse::Error {
+                code: Some("LOADING".to_string()),
+                message: "dataset is being restored".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_raw_response(b"ERROR ERR_TOO_LARGE key too large (max 4)\r\n").unwrap(),
+            RawResponse::Error {
+                code: Some("ERR_TOO_LARGE".to_string()),
+                message: "key too large (max 4)".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_raw_response(b"ERROR parse error at byte 0\r\n").unwrap(),
+            RawResponse::Error {
+                code: None,
+                message: "parse error at byte 0".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_raw_response(b"VALUE $4\r\na\r\nb\r\n").unwrap(),
+            RawResponse::Value(b"a\r\nb".to_vec())
+        );
+        assert_eq!(
+            parse_raw_response(b"KEYS 2 9\r\nuser:1\r\nuser:2\r\n").unwrap(),
+            RawResponse::Keys { keys: vec!["user:1".to_string(), "user:2".to_string()], cursor: 9 }
+        );
+        assert_eq!(
+            parse_raw_response(b"KEYS 0 0\r\n").unwrap(),
+            RawResponse::Keys { keys: Vec::new(), cursor: 0 }
+        );
+        assert_eq!(
+            parse_raw_response(b"INFO 2\r\nkeys 3\r\nuptime_secs 12\r\n").unwrap(),
+            RawResponse::Info(vec![
+                ("keys".to_string(), "3".to_string()),
+                ("uptime_secs".to_string(), "12".to_string()),
+            ])
+        );
+        assert_eq!(parse_raw_response(b"INT -7\r\n").unwrap(), RawResponse::Integer(-7));
+        assert_eq!(parse_raw_response(b"QUEUED\r\n").unwrap(), RawResponse::Queued);
+        assert_eq!(
+            parse_raw_response(b"RESULTS 3\r\nOK\r\nVALUE $3\r\na\nb\r\nNOT_FOUND\r\n").unwrap(),
+            RawResponse::Results(vec![RawResponse::Ok, RawResponse::Value(b"a\nb".to_vec()), RawResponse::NotFound])
+        );
+        assert_eq!(
+            parse_raw_response(b"COMPACTED 10485760 -> 524288 (1200 entries)\r\n").unwrap(),
+            RawResponse::Compacted(CompactionReport { before: 10485760, after: 524288, keys: 1200 })
+        );
+        assert!(parse_raw_response(b"COMPACTED 10 -> 5\r\n").is_err());
+        assert!(parse_raw_response(b"INT x\r\n").is_err());
+        assert!(parse_raw_response(b"WAT\r\n").is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_reader_takes_exactly_one_frame() {
+        let mut wire: &[u8] = b"VALUE $4\r\n\nOK\n\r\n\
+            RESULTS 3\r\nVALUES 2\r\n$3\r\nOK\n\r\nNIL\r\nINFO 1\r\nkeys 1\r\nKEYS 1 0\r\na\r\n\
+            OK\r\n\
+            VALUE $3\r\nab";
+        assert_eq!(read_response(&mut wire).await.unwrap(), Response::Value(b"\nOK\n".to_vec()));
+        assert_eq!(
+            read_response(&mut wire).await.unwrap(),
+            Response::Results(vec![
+                Response::Values(vec![Some(b"OK\n".to_vec()), None]),
+                Response::Info(vec![("keys".to_string(), "1".to_string())]),
+                Response::Keys { keys: vec!["a".to_string()], cursor: 0 },
+            ])
+        );
+        assert_eq!(read_response(&mut wire).await.unwrap(), Response::Ok);
+        // A value cut short is an error, not a shorter value
+        assert!(read_frame(&mut wire).await.is_err());
+        assert!(read_frame(&mut &b"VALUE $1\r\nab\r\n"[..]).await.is_err());
+        assert_eq!(frame_len(b"VALUES 1\r\n$2\r\nab\r\nOK\r\n"), Some(18));
+        assert_eq!(frame_len(b"VALUES 1\r\n$2\r\na"), None);
+    }
+    
+    /// A server that hangs up on its first `dropped` connections as soon as
+    /// they send a command after HELLO, then answers SET with OK and GET
+    /// with a value, returning its address and every command line but HELLO
+    /// it received
+    async fn flaky_server(dropped: usize) -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = std::sync::Arc::clone(&received);
+        tokio::spawn(async move {
+            let mut connections = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                connections += 1;
+                let hang_up = connections <= dropped;
+                let log = std::sync::Arc::clone(&log);
+                tokio::spawn(async move {
+                    let (read_half, mut write_half) = stream.into_split();
+                    let mut lines = BufReader::new(read_half).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line.starts_with("HELLO") {
+                            write_half.write_all(b"HELLO 2\n").await.unwrap();
+                            continue;
+                        }
+                        let reply: &[u8] = if line.starts_with("GET") { b"VALUE v\n" } else { b"OK\n" };
+                        log.lock().unwrap().push(line);
+                        if hang_up {
+                            return;
+                        }
+                        write_half.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, received)
+    }
+    
+    fn retrying(at_least_once: bool) -> ClientConfig {
+        ClientConfig {
+            retries: 3,
+            backoff: Duration::from_millis(1),
+            at_least_once,
+            ..Default::default()
+        }
+    }
+    
+    /// A server that answers GET of `slow` with `late` after 300ms, and any
+    /// other GET with `fast` straight away
+    async fn slow_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (read_half, mut write_half) = stream.into_split();
+                    let mut lines = BufReader::new(read_half).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply: &[u8] = match line.as_str() {
+                            "HELLO 2" => b"HELLO 2\r\n",
+                            "GET slow" => {
+                                tokio::time::sleep(Duration::from_millis(300)).await;
+                                b"VALUE late\r\n"
+                            }
+                            _ => b"VALUE fast\r\n",
+                        };
+                        if write_half.write_all(reply).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+    
+    #[tokio::test]
+    async fn test_request_timeout_poisons_the_connection() {
+        let addr = slow_server().await;
+        let timeout = Duration::from_millis(100);
+        let config = ClientConfig { request_timeout: Some(timeout), ..Default::default() };
+        let mut client = Client::connect_with_config(&addr, config.clone()).await.unwrap();
+        
+        match client.get("slow").await {
+            Err(RustVaultError::Timeout { op, elapsed }) => assert_eq!((op.as_str(), elapsed), ("GET", timeout)),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(client.is_broken());
+        // Without retries it stays unusable, rather than reading the late
+        // response as the answer to the next command
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let e = client.get("other").await.unwrap_err();
+        assert!(e.to_string().contains("reconnect required"), "{}", e);
+        client.reconnect().await.unwrap();
+        assert_eq!(client.get("other").await.unwrap().as_deref(), Some("fast"));
+        
+        // With retries the next command goes out on a new connection
+        let config = ClientConfig { retries: 1, ..config };
+        let mut client = Client::connect_with_config(&addr, config).await.unwrap();
+        assert!(matches!(client.get("slow").await, Err(RustVaultError::Timeout { .. })));
+        assert_eq!(client.get("other").await.unwrap().as_deref(), Some("fast"));
+        assert!(!client.is_broken());
+    }
+    
+    #[tokio::test]
+    async fn test_write_with_lost_response_is_not_resent() {
+        let (addr, received) = flaky_server(1).await;
+        let mut client = Client::connect_with_config(&addr, retrying(false)).await.unwrap();
+        
+        assert!(matches!(client.set("a", "1").await, Err(RustVaultError::Io(_))));
+        assert_eq!(received.lock().unwrap().len(), 1);
+        
+        // The broken connection is replaced before the next command
+        client.set("b", "2").await.unwrap();
+        assert_eq!(*received.lock().unwrap(), ["SET a 1", "SET b 2"]);
+    }
+    
+    #[tokio::test]
+    async fn test_at_least_once_resends_write_with_lost_response() {
+        let (addr, received) = flaky_server(1).await;
+        let mut client = Client::connect_with_config(&addr, retrying(true)).await.unwrap();
+        
+        client.set("a", "1").await.unwrap();
+        assert_eq!(*received.lock().unwrap(), ["SET a 1", "SET a 1"]);
+    }
+    
+    #[tokio::test]
+    async fn test_read_with_lost_response_is_retried() {
+        let (addr, received) = flaky_server(2).await;
+        let mut client = Client::connect_with_config(&addr, retrying(false)).await.unwrap();
+        
+        assert_eq!(client.get("a").await.unwrap(), Some("v".to_string()));
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_scan_stream_reports_where_it_was_interrupted() {
+        // One page with a key deleted before its MGET, then a hang-up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read_half, mut write_half) = stream.into_split();
+            let mut lines = BufReader::new(read_half).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply: &[u8] = match line.as_str() {
+                    "HELLO 2" => b"HELLO 2\r\n",
+                    "SCAN 0 100 p" => b"KEYS 2 5\r\np1\r\np2\r\n",
+                    "MGET p1 p2" => b"VALUES 2\r\n$1\r\n1\r\nNIL\r\n",
+                    _ => return,
+                };
+                write_half.write_all(reply).await.unwrap();
+            }
+        });
+        
+        let mut client = Client::connect(&addr).await.unwrap();
+        let mut pairs = client.scan_stream("p");
+        assert_eq!(pairs.next().await.unwrap().unwrap(), ("p1".to_string(), "1".to_string()));
+        match pairs.next().await {
+            Some(Err(RustVaultError::ScanInterrupted { cursor, source })) => {
+                assert_eq!(cursor, 5);
+                assert!(matches!(*source, RustVaultError::Io(_)), "{}", source);
+            }
+            other => panic!("expected an interrupted scan, got {:?}", other),
+        }
+        assert!(pairs.next().await.is_none());
+    }
+    
+    #[test]
+    fn test_addresses_are_checked_without_a_lookup() {
+        for good in ["127.0.0.1:0", "localhost:8080", "no.such.host:1", "[::1]:8080", "unix:///run/vault.sock"] {
+            assert!(check_addr(good).is_ok(), "{}", good);
+        }
+        for bad in ["", "localhost", "127.0.0.1:", "127.0.0.1:99999", ":8080", "::1:8080", "unix://"] {
+            assert!(matches!(check_addr(bad), Err(RustVaultError::InvalidAddress(_))), "{}", bad);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_connect_gives_up_after_connect_timeout() {
+        // A listener whose backlog is full leaves further connects hanging
+        // on most platforms; anywhere it doesn't, the connect is refused
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let timeout = Duration::from_millis(100);
+        let mut held = Vec::new();
+        for _ in 0..16 {
+            match open(&addr, Some(timeout)).await {
+                // Taken into the backlog
+                Ok(halves) => held.push(halves),
+                Err(RustVaultError::ConnectTimeout { addr: failed, timeout: waited }) => {
+                    assert_eq!((failed, waited), (addr, timeout));
+                    return;
+                }
+                Err(e) => {
+                    assert!(matches!(e, RustVaultError::Io(_)), "{}", e);
+                    return;
+                }
+            }
+        }
+        panic!("{} connects never stalled", held.len());
+    }
+    
+    #[test]
+    fn test_backoff_doubles_up_to_the_limit() {
+        let config = ClientConfig {
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            ..Default::default()
+        };
+        for (retry, full) in [(1, 100), (2, 200), (3, 300), (40, 300)] {
+            let delay = config.delay(retry);
+            let full = Duration::from_millis(full);
+            assert!(delay <= full && delay >= full / 2, "retry {}: {:?}", retry, delay);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+//! Bulk loading across a [`ClientPool`]
+//!
+//! [`ClientPool::load_from_stream`] groups pairs into MSET batches and keeps
+//! up to `concurrency` of them in flight, each on its own pooled connection.
+//! The source is only read while there is room for another batch, so a fast
+//! source waits for the server instead of piling up in memory.
+//!
+//! An MSET is all or nothing, so a batch the server refuses is sent again a
+//! SET at a time to find the keys at fault. Dropping the load part way
+//! through drops its batches mid-command, and the pool closes those
+//! connections rather than handing them out again.
+
+use super::{encode_mset, unexpected_response, ClientPool, LoadReport};
+use crate::error::{RustVaultError, Result};
+use crate::protocol::{CommandKind, Response, MAX_MSET_PAIRS};
+use futures_core::Stream;
+use std::future::poll_fn;
+use std::mem;
+use std::pin::pin;
+use std::time::Instant;
+use tokio::task::JoinSet;
+
+/// What [`ClientPool::load_from_stream`] does once the server refuses a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnLoadError {
+    /// Record the key in the report and carry on
+    #[default]
+    Skip,
+    /// Record the key and read no more of the source; batches already
+    /// sent still finish, and pairs read but not yet sent are listed in
+    /// [`LoadReport::unsent`]
+    Stop,
+}
+
+/// How [`ClientPool::load_from_stream`] spreads a load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadOptions {
+    /// Batches in flight at once, each on its own connection; the pool's
+    /// `max` caps it too
+    pub concurrency: usize,
+    /// Most pairs per MSET, up to [`MAX_MSET_PAIRS`]
+    pub batch_size: usize,
+    /// Value bytes past which a batch is sent without waiting for
+    /// `batch_size` pairs; it must stay under twice the server's
+    /// `max_value_bytes`, the most one command may carry
+    pub batch_bytes: usize,
+    pub on_error: OnLoadError,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            batch_size: 1000,
+            batch_bytes: 1024 * 1024,
+            on_error: OnLoadError::Skip,
+        }
+    }
+}
+
+/// What one batch loaded and which of its keys were refused
+type BatchOutcome = (usize, Vec<(String, RustVaultError)>);
+
+impl ClientPool {
+    /// Load key-value pairs from `items` as MSETs spread across the pool
+    ///
+    /// Keys the server refuses, and keys that can't be sent at all because
+    /// they are empty or hold spaces or line breaks, are collected in the
+    /// report with their error; `options.on_error` decides whether the load
+    /// carries on. A connection-level failure (IO or framing) stops the
+    /// load and is returned as `Err`.
+    pub async fn load_from_stream<St, V>(&self, items: St, options: LoadOptions) -> Result<LoadReport>
+    where
+        St: Stream<Item = (String, V)>,
+        V: Into<Vec<u8>>,
+    {
+        let start = Instant::now();
+        let concurrency = options.concurrency.max(1);
+        let batch_size = options.batch_size.clamp(1, MAX_MSET_PAIRS);
+        let stop_on_error = options.on_error == OnLoadError::Stop;
+        
+        let mut items = pin!(items);
+        let mut in_flight: JoinSet<Result<BatchOutcome>> = JoinSet::new();
+        let mut batch: Vec<(String, Vec<u8>)> = Vec::new();
+        let mut batch_len = 0;
+        let mut loaded = 0;
+        let mut failed = Vec::new();
+        let mut stopped = false;
+        
+        while !stopped {
+            let next = poll_fn(|cx| items.as_mut().poll_next(cx)).await.map(|(key, value)| (key, value.into()));
+            let send = match &next {
+                Some((_, value)) => {
+                    batch.len() == batch_size || (!batch.is_empty() && batch_len + value.len() > options.batch_bytes)
+                }
+                None => !batch.is_empty(),
+            };
+            if send {
+                // Wait for room before taking more of the source
+                while in_flight.len() >= concurrency && !stopped {
+                    let (n, refused) = joined(in_flight.join_next().await)?;
+                    loaded += n;
+                    stopped = stop_on_error && !refused.is_empty();
+                    failed.extend(refused);
+                }
+                if stopped {
+                    batch.extend(next);
+                    break;
+                }
+                let pool = self.clone();
+                let batch = mem::take(&mut batch);
+                batch_len = 0;
+                in_flight.spawn(async move { load_batch(&pool, batch).await });
+            }
+            
+            let Some((key, value)) = next else {
+                break;
+            };
+            if key.is_empty() || key.contains([' ', '\r', '\n']) {
+                let e = RustVaultError::InvalidCommand("Key is empty or holds spaces or line breaks".to_string());
+                failed.push((key, e));
+                stopped = stop_on_error;
+                continue;
+            }
+            batch_len += value.len();
+            batch.push((key, value));
+        }
+        
+        while !in_flight.is_empty() {
+            let (n, refused) = joined(in_flight.join_next().await)?;
+            loaded += n;
+            failed.extend(refused);
+        }
+        // Only a stop leaves pairs behind
+        let unsent = batch.into_iter().map(|(key, _)| key).collect();
+        
+        let elapsed = start.elapsed();
+        Ok(LoadReport {
+            loaded,
+            failed,
+            unsent,
+            elapsed,
+            rate: loaded as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        })
+    }
+}
+
+/// The outcome of a finished batch task
+fn joined(result: Option<std::result::Result<Result<BatchOutcome>, tokio::task::JoinError>>) -> Result<BatchOutcome> {
+    result
+        .expect("only joined while batches are in flight")
+        .map_err(|e| RustVaultError::Client(format!("Load task failed: {}", e)))?
+}
+
+/// Send `batch` as one MSET, or a SET per pair if the server refuses it
+async fn load_batch(pool: &ClientPool, batch: Vec<(String, Vec<u8>)>) -> Result<BatchOutcome> {
+    let mut client = pool.get().await?;
+    match client.send_request(&encode_mset(&batch), CommandKind::Write).await? {
+        Response::Ok => return Ok((batch.len(), Vec::new())),
+        Response::Error(_) => {}
+        other => return Err(unexpected_response("MSET", &other)),
+    }
+    
+    let mut loaded = 0;
+    let mut failed = Vec::new();
+    for (key, value) in batch {
+        match client.set_bytes(&key, &value).await {
+            Ok(()) => loaded += 1,
+            Err(e @ (RustVaultError::Server(_) | RustVaultError::Remote { .. })) => failed.push((key, e)),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((loaded, failed))
+}
@@ -0,0 +1,285 @@
+//! Pool of client connections shared by concurrent tasks
+//!
+//! Tasks check a [`Client`] out of the pool, use it, and hand it back by
+//! dropping the guard. The pool opens connections lazily up to its maximum,
+//! keeps idle ones for reuse, and closes those that sit idle too long.
+//! A connection left mid-command, by an IO error or a cancelled call, is
+//! closed instead of being handed to the next task.
+
+use super::Client;
+use crate::error::{RustVaultError, Result};
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Sizing of a [`ClientPool`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Connections opened up front and kept however long they sit idle
+    pub min: usize,
+    /// Most connections open at once; further checkouts wait for one
+    pub max: usize,
+    /// How long a connection beyond `min` may sit idle before it is closed
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min: 0,
+            max: 8,
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Snapshot of pool counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolStats {
+    /// Connections open, idle or checked out
+    pub connections: usize,
+    /// Connections waiting in the pool to be checked out
+    pub idle: usize,
+    /// Connections closed because they came back broken
+    pub discarded: u64,
+}
+
+/// A connection waiting in the pool, with when it was returned
+struct Idle {
+    client: Client,
+    since: Instant,
+}
+
+struct Inner {
+    addr: String,
+    config: PoolConfig,
+    /// Idle connections, least recently returned first
+    idle: Mutex<VecDeque<Idle>>,
+    /// One permit per connection that may be checked out
+    checkouts: Arc<Semaphore>,
+    connections: AtomicUsize,
+    discarded: AtomicU64,
+}
+
+impl Inner {
+    /// Take back a connection, unless it can't be reused
+    fn give_back(&self, client: Client) {
+        if client.is_broken() {
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.idle.lock().unwrap().push_back(Idle {
+            client,
+            since: Instant::now(),
+        });
+    }
+    
+    /// The most recently returned idle connection, after closing any that
+    /// were idle too long
+    fn take_idle(&self) -> Option<Client> {
+        let mut idle = self.idle.lock().unwrap();
+        while idle.len() > self.config.min
+            && idle.front().is_some_and(|oldest| oldest.since.elapsed() >= self.config.idle_timeout)
+        {
+            idle.pop_front();
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+        }
+        idle.pop_back().map(|idle| idle.client)
+    }
+}
+
+/// Pool of connections to one RustVault server
+///
+/// Cloning the pool is cheap and shares its connections.
+#[derive(Clone)]
+pub struct ClientPool {
+    inner: Arc<Inner>,
+}
+
+impl ClientPool {
+    /// Create a pool for the server at `addr`, opening `config.min`
+    /// connections straight away
+    pub async fn connect(addr: &str, config: PoolConfig) -> Result<Self> {
+        if config.max == 0 || config.min > config.max {
+            return Err(RustVaultError::Client(format!(
+                "Invalid pool size: min {} and max {}",
+                config.min, config.max
+            )));
+        }
+        
+        let pool = Self {
+            inner: Arc::new(Inner {
+                addr: addr.to_string(),
+                config,
+                idle: Mutex::new(VecDeque::with_capacity(config.max)),
+                checkouts: Arc::new(Semaphore::new(config.max)),
+                connections: AtomicUsize::new(0),
+                discarded: AtomicU64::new(0),
+            }),
+        };
+        for _ in 0..config.min {
+            let client = Client::connect(addr).await?;
+            pool.inner.connections.fetch_add(1, Ordering::Relaxed);
+            pool.inner.give_back(client);
+        }
+        Ok(pool)
+    }
+    
+    /// Check out a connection, waiting while all `max` are in use
+    ///
+    /// An idle connection is reused if there is one; otherwise a new one is
+    /// opened. The connection goes back to the pool when the guard is
+    /// dropped.
+    pub async fn get(&self) -> Result<PooledClient> {
+        let permit = Arc::clone(&self.inner.checkouts)
+            .acquire_owned()
+            .await
+            .expect("pool semaphore is never closed");
+        let client = match self.inner.take_idle() {
+            Some(client) => client,
+            None => {
+                let client = Client::connect(&self.inner.addr).await?;
+                self.inner.connections.fetch_add(1, Ordering::Relaxed);
+                client
+            }
+        };
+        
+        Ok(PooledClient {
+            client: Some(client),
+            pool: Arc::clone(&self.inner),
+            _permit: permit,
+        })
+    }
+    
+    /// Get a snapshot of the pool counters
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            connections: self.inner.connections.load(Ordering::Relaxed),
+            idle: self.inner.idle.lock().unwrap().len(),
+            discarded: self.inner.discarded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A connection checked out of a [`ClientPool`], returned to it on drop
+pub struct PooledClient {
+    client: Option<Client>,
+    pool: Arc<Inner>,
+    /// Released after the connection is back in the pool, so the next
+    /// checkout can reuse it
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+    
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("pooled client already returned")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("pooled client already returned")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.give_back(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Accept connections at an address that never answers a command,
+    /// only the HELLO each client opens with
+    async fn silent_listener() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (read_half, mut write_half) = stream.into_split();
+                    let mut lines = tokio::io::BufReader::new(read_half).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line.starts_with("HELLO") {
+                            let _ = write_half.write_all(b"HELLO 2\r\n").await;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+    
+    #[tokio::test]
+    async fn test_rejects_invalid_sizes() {
+        let addr = silent_listener().await;
+        for (min, max) in [(0, 0), (3, 2)] {
+            let config = PoolConfig { min, max, ..Default::default() };
+            assert!(ClientPool::connect(&addr, config).await.is_err());
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_reuses_returned_connections() {
+        let addr = silent_listener().await;
+        let config = PoolConfig { min: 1, max: 2, ..Default::default() };
+        let pool = ClientPool::connect(&addr, config).await.unwrap();
+        
+        for _ in 0..3 {
+            drop(pool.get().await.unwrap());
+        }
+        let (a, b) = (pool.get().await.unwrap(), pool.get().await.unwrap());
+        drop((a, b));
+        
+        assert_eq!(pool.stats(), PoolStats { connections: 2, idle: 2, discarded: 0 });
+    }
+    
+    #[tokio::test]
+    async fn test_closes_connections_idle_past_timeout() {
+        let addr = silent_listener().await;
+        let config = PoolConfig {
+            min: 1,
+            max: 3,
+            idle_timeout: Duration::ZERO,
+        };
+        let pool = ClientPool::connect(&addr, config).await.unwrap();
+        let held: Vec<_> = [pool.get().await.unwrap(), pool.get().await.unwrap()].into();
+        drop(held);
+        assert_eq!(pool.stats().idle, 2);
+        
+        // The checkout closes the expired one beyond `min`
+        let _client = pool.get().await.unwrap();
+        assert_eq!(pool.stats(), PoolStats { connections: 1, idle: 0, discarded: 0 });
+    }
+    
+    #[tokio::test]
+    async fn test_discards_connection_left_mid_command() {
+        let addr = silent_listener().await;
+        let pool = ClientPool::connect(&addr, PoolConfig::default()).await.unwrap();
+        
+        // Nothing answers, so the command is still waiting when it's given up on
+        let mut client = pool.get().await.unwrap();
+        let get = tokio::time::timeout(Duration::from_millis(20), client.get("key")).await;
+        assert!(get.is_err());
+        assert!(client.is_broken());
+        drop(client);
+        assert_eq!(pool.stats(), PoolStats { connections: 0, idle: 0, discarded: 1 });
+        
+        let client = pool.get().await.unwrap();
+        assert!(!client.is_broken());
+        assert_eq!(pool.stats().connections, 1);
+    }
+}
\ No newline at end of file
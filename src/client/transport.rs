@@ -0,0 +1,127 @@
+//! The socket under a client connection
+//!
+//! Servers are reached over TCP at `host:port`, or on Unix targets over a
+//! Unix domain socket at `unix:///path/to/vault.sock`. Either way the
+//! stream is split into halves the client reads and writes separately.
+
+use crate::error::{Result, RustVaultError};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{tcp, TcpStream};
+#[cfg(unix)]
+use tokio::net::{unix, UnixStream};
+
+/// Scheme that makes an address a Unix socket path
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// Reading half of a connection
+#[derive(Debug)]
+pub(crate) enum ReadHalf {
+    Tcp(tcp::OwnedReadHalf),
+    #[cfg(unix)]
+    Unix(unix::OwnedReadHalf),
+}
+
+/// Writing half of a connection
+#[derive(Debug)]
+pub(crate) enum WriteHalf {
+    Tcp(tcp::OwnedWriteHalf),
+    #[cfg(unix)]
+    Unix(unix::OwnedWriteHalf),
+}
+
+/// Check that `addr` is a `host:port` with a port that fits in 16 bits, or
+/// a `unix://` followed by a path
+///
+/// The host isn't looked up, so a name that doesn't resolve still passes;
+/// it fails when connecting or binding.
+pub(crate) fn check_addr(addr: &str) -> Result<()> {
+    let invalid = |why: &str| Err(RustVaultError::InvalidAddress(format!("{:?} {}", addr, why)));
+    if let Some(path) = addr.strip_prefix(UNIX_SCHEME) {
+        return match path.is_empty() {
+            true => invalid("has no socket path"),
+            false => Ok(()),
+        };
+    }
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        return invalid("has no port; expected host:port or unix:///path");
+    };
+    if host.is_empty() {
+        return invalid("has no host");
+    }
+    if port.parse::<u16>().is_err() {
+        return invalid("has a port outside 0-65535");
+    }
+    // A bare IPv6 address has to be bracketed to be told from its port
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return invalid("has an IPv6 host without brackets");
+    }
+    Ok(())
+}
+
+/// Connect to `addr`, a `host:port` or a `unix://` path
+pub(crate) async fn connect(addr: &str) -> io::Result<(ReadHalf, WriteHalf)> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix(UNIX_SCHEME) {
+        let (read, write) = UnixStream::connect(path).await?.into_split();
+        return Ok((ReadHalf::Unix(read), WriteHalf::Unix(write)));
+    }
+    #[cfg(not(unix))]
+    if let Some(path) = addr.strip_prefix(UNIX_SCHEME) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't connect to {}: Unix sockets aren't supported here", path),
+        ));
+    }
+    let (read, write) = TcpStream::connect(addr).await?.into_split();
+    Ok((ReadHalf::Tcp(read), WriteHalf::Tcp(write)))
+}
+
+impl ReadHalf {
+    /// Read whatever has already arrived, without waiting
+    pub(crate) fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ReadHalf::Tcp(half) => half.try_read(buf),
+            #[cfg(unix)]
+            ReadHalf::Unix(half) => half.try_read(buf),
+        }
+    }
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ReadHalf::Tcp(half) => Pin::new(half).poll_read(cx, buf),
+            #[cfg(unix)]
+            ReadHalf::Unix(half) => Pin::new(half).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_write(cx, buf),
+            #[cfg(unix)]
+            WriteHalf::Unix(half) => Pin::new(half).poll_write(cx, buf),
+        }
+    }
+    
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_flush(cx),
+            #[cfg(unix)]
+            WriteHalf::Unix(half) => Pin::new(half).poll_flush(cx),
+        }
+    }
+    
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_shutdown(cx),
+            #[cfg(unix)]
+            WriteHalf::Unix(half) => Pin::new(half).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,7 +1,9 @@
 //! Error types for RustVault
 
+use crate::protocol::{ErrorCode, ProtocolError};
 use thiserror::Error;
 use std::io;
+use std::time::Duration;
 
 /// Result type alias for RustVault operations
 pub type Result<T> = std::result::Result<T, RustVaultError>;
@@ -15,8 +17,15 @@ pub enum RustVaultError {
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
     
-    #[error("Protocol parse error: {0}")]
-    Protocol(String),
+    #[error("Can't deserialize the value of {key}: {source}")]
+    Deserialize {
+        key: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
     
     #[error("Key not found: {0}")]
     KeyNotFound(String),
@@ -24,18 +33,83 @@ pub enum RustVaultError {
     #[error("Invalid command: {0}")]
     InvalidCommand(String),
     
+    /// An `ERROR` reply without a code, as older servers send them
     #[error("Server error: {0}")]
     Server(String),
     
+    /// An `ERROR` reply, split into its code and message
+    #[error("Server error {code}: {message}")]
+    Remote { code: ErrorCode, message: String },
+    
     #[error("Client error: {0}")]
     Client(String),
     
     #[error("WAL error: {0}")]
     Wal(String),
+    
+    #[error("Persistence error: {0}")]
+    Persistence(String),
+    
+    #[error("Snapshot error: {0}")]
+    Snapshot(String),
+    
+    #[error("Backup error: {0}")]
+    Backup(String),
+    
+    /// A WAL compaction was asked for while another was running
+    #[error("compaction in progress")]
+    CompactionInProgress,
+    
+    /// A listener couldn't be bound, as when another process holds the
+    /// port
+    #[error("Can't listen on {addr}: {source}")]
+    BindFailed {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+    
+    /// An address that is neither a `host:port` nor a `unix://` path
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+    
+    /// Connecting didn't finish within the client's `connect_timeout`
+    #[error("Timed out connecting to {addr} after {timeout:?}")]
+    ConnectTimeout { addr: String, timeout: Duration },
+    
+    /// A command's response didn't arrive within the client's
+    /// `request_timeout`; the server may still have run it
+    #[error("Timed out waiting for {op} after {elapsed:?}")]
+    Timeout { op: String, elapsed: Duration },
+    
+    /// A [`ScanStream`](crate::client::ScanStream) couldn't fetch a page;
+    /// every pair before it was delivered, so scanning again from `cursor`
+    /// picks up where it stopped
+    #[error("Scan interrupted, resume from cursor {cursor}: {source}")]
+    ScanInterrupted {
+        cursor: u64,
+        #[source]
+        source: Box<RustVaultError>,
+    },
+    
+    #[error("out of memory")]
+    OutOfMemory,
 }
 
-impl From<nom::Err<nom::error::Error<&[u8]>>> for RustVaultError {
-    fn from(err: nom::Err<nom::error::Error<&[u8]>>) -> Self {
-        RustVaultError::Protocol(format!("Parse error: {:?}", err))
+impl RustVaultError {
+    /// The error for the text of an `ERROR` reply
+    pub fn from_reply(text: String) -> Self {
+        match ErrorCode::split(&text) {
+            (Some(code), message) => RustVaultError::Remote { code, message: message.to_string() },
+            (None, _) => RustVaultError::Server(text),
+        }
     }
-}
\ No newline at end of file
+    
+    /// Code of an error the server replied with, if it sent one
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            RustVaultError::Remote { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
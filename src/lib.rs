@@ -5,16 +5,31 @@
 //! - Write-ahead logging for durability
 //! - Zero-copy parsing for performance
 //! - Concurrent client support
+//! - An embedded [`Vault`] for using the store in-process, without the server
+//! - Standalone backup files, written and loaded while the store is served
 
+pub mod backup;
 pub mod client;
 pub mod error;
 pub mod protocol;
+pub mod recovery;
 pub mod server;
+pub mod snapshot;
 pub mod store;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod vault;
 pub mod wal;
 
 pub use error::{RustVaultError, Result};
-pub use store::{Store, MemoryStore};
-pub use protocol::{Command, Response};
-pub use client::Client;
-pub use server::{RustVaultServer, ServerConfig};
\ No newline at end of file
+pub use store::{
+    Store, MemoryStore, ShardedMemoryStore, ScanPage, EntryPage, CompactionReport, BatchOp, BatchOutcome, KeyStat,
+};
+pub use protocol::{Command, CommandKind, ErrorCode, KeyEvent, Response, SetCondition};
+pub use client::{
+    Client, ClientConfig, ClientPool, LoadOptions, LoadReport, LockGuard, OnLoadError, Pipeline, PoolConfig,
+    RawResponse, ScanIter, ScanStream, Subscription, Transaction, ValueWatch,
+};
+pub use server::{RustVaultServer, ServerConfig, ServerStats, SlowLogEntry};
+pub use vault::Vault;
+pub use wal::{RecoveryMode, SyncPolicy, WalFormat, WalStats};
\ No newline at end of file
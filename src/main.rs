@@ -1,15 +1,598 @@
 //! RustVault Server Binary
 //!
 //! Main entry point for the RustVault TCP server
+//!
+//! Usage: server [--config <path>] [--<setting> <value> ...]
+//!
+//! Each setting is taken from its flag, else the `--config` TOML file, else
+//! its `RUSTVAULT_*` environment variable, else the default. Run with
+//! `--help` for the list.
 
-use rustvault::{Result, RustVaultServer, ServerConfig};
+use rustvault::client::UNIX_SCHEME;
+use rustvault::protocol::command_spec;
+use rustvault::server::{activation, ConnectionLimitAction, HungCommandAction, RateLimit, RateLimitMode};
+use rustvault::store::compression::DEFAULT_MIN_SIZE_BYTES;
+use rustvault::store::{CompressionAlgorithm, CompressionConfig, EvictionPolicy, HasherKind};
+use rustvault::{RecoveryMode, Result, RustVaultServer, ServerConfig, SyncPolicy, WalFormat};
+use std::env;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
 
+/// A `ServerConfig` field that can be set from the command line, the
+/// environment or the config file
+struct Setting {
+    /// Field name, and the key in the config file
+    field: &'static str,
+    /// Command-line flag; the environment variable is `RUSTVAULT_` and the
+    /// flag in upper snake case
+    flag: &'static str,
+    /// What the value looks like, for `--help`
+    value: &'static str,
+    help: &'static str,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        field: "bind_addr",
+        flag: "--bind",
+        value: "<addr>|unix://<path>",
+        help: "Address or Unix socket to listen on",
+    },
+    Setting {
+        field: "wal_path",
+        flag: "--wal-path",
+        value: "<path>",
+        help: "WAL file",
+    },
+    Setting {
+        field: "wal_sync",
+        flag: "--wal-sync",
+        value: "always|never|<ms>",
+        help: "When WAL appends are synced to disk",
+    },
+    Setting {
+        field: "wal_group_delay_micros",
+        flag: "--wal-group-delay-micros",
+        value: "<us>",
+        help: "How long WAL appends wait to share a sync",
+    },
+    Setting {
+        field: "wal_format",
+        flag: "--wal-format",
+        value: "json|binary",
+        help: "Encoding of a new WAL",
+    },
+    Setting {
+        field: "wal_segment_size_bytes",
+        flag: "--wal-segment-size-bytes",
+        value: "<n>|none",
+        help: "Size at which the WAL rolls to a new segment",
+    },
+    Setting {
+        field: "recovery_mode",
+        flag: "--recovery-mode",
+        value: "strict|truncate-corrupt",
+        help: "What replay does about a corrupt entry",
+    },
+    Setting {
+        field: "replay_progress_interval",
+        flag: "--replay-progress-interval",
+        value: "<n>|none",
+        help: "Log replay progress every this many entries",
+    },
+    Setting {
+        field: "startup_timeout",
+        flag: "--startup-timeout",
+        value: "<secs>|none",
+        help: "Give up if replay takes longer",
+    },
+    Setting {
+        field: "max_connections",
+        flag: "--max-connections",
+        value: "<n>",
+        help: "Most connections served at once",
+    },
+    Setting {
+        field: "connection_limit_action",
+        flag: "--connection-limit-action",
+        value: "reject|queue",
+        help: "What happens to a client beyond that",
+    },
+    Setting {
+        field: "rate_limit",
+        flag: "--rate-limit",
+        value: "<ops>[:<burst>]|none",
+        help: "Commands per second per connection",
+    },
+    Setting {
+        field: "ip_rate_limit",
+        flag: "--ip-rate-limit",
+        value: "<ops>[:<burst>]|none",
+        help: "Commands per second per client address",
+    },
+    Setting {
+        field: "rate_limit_mode",
+        flag: "--rate-limit-mode",
+        value: "delay|reject",
+        help: "What happens to commands over a rate limit",
+    },
+    Setting {
+        field: "max_key_bytes",
+        flag: "--max-key-bytes",
+        value: "<n>",
+        help: "Longest key accepted",
+    },
+    Setting {
+        field: "max_value_bytes",
+        flag: "--max-value-bytes",
+        value: "<n>",
+        help: "Largest value accepted",
+    },
+    Setting {
+        field: "shards",
+        flag: "--shards",
+        value: "<n>",
+        help: "Store shards; 0, the default, picks four per CPU",
+    },
+    Setting {
+        field: "hasher",
+        flag: "--hasher",
+        value: "siphash|ahash|fxhash",
+        help: "Store map hasher; ahash and fxhash need their cargo features",
+    },
+    Setting {
+        field: "max_memory_bytes",
+        flag: "--max-memory-bytes",
+        value: "<n>|none",
+        help: "Memory limit for keys and values",
+    },
+    Setting {
+        field: "eviction_policy",
+        flag: "--eviction-policy",
+        value: "noeviction|lru",
+        help: "What happens to writes at the limit",
+    },
+    Setting {
+        field: "compression",
+        flag: "--compression",
+        value: "lz4|zstd[:<n>]|none",
+        help: "Compress values of n bytes or more (1024)",
+    },
+    Setting {
+        field: "idle_timeout",
+        flag: "--idle-timeout",
+        value: "<secs>|none",
+        help: "Close connections idle this long",
+    },
+    Setting {
+        field: "read_timeout",
+        flag: "--read-timeout",
+        value: "<secs>|none",
+        help: "Close connections stalled mid-command this long",
+    },
+    Setting {
+        field: "shutdown_drain_timeout",
+        flag: "--shutdown-drain-timeout",
+        value: "<secs>",
+        help: "How long running commands get on shutdown",
+    },
+    Setting {
+        field: "hung_command_threshold_secs",
+        flag: "--hung-command-threshold-secs",
+        value: "<secs>|none",
+        help: "Report commands running this long",
+    },
+    Setting {
+        field: "hung_command_action",
+        flag: "--hung-command-action",
+        value: "warn|kill",
+        help: "What to do about a hung command",
+    },
+    Setting {
+        field: "slowlog_threshold",
+        flag: "--slowlog-threshold",
+        value: "<secs>|none",
+        help: "Keep commands slower than this for SLOWLOG GET",
+    },
+    Setting {
+        field: "slowlog_max_len",
+        flag: "--slowlog-max-len",
+        value: "<n>",
+        help: "Most slow log entries kept",
+    },
+    Setting {
+        field: "slowlog_max_key_len",
+        flag: "--slowlog-max-key-len",
+        value: "<bytes>",
+        help: "Longest key kept in a slow log entry",
+    },
+    Setting {
+        field: "shrink_interval_secs",
+        flag: "--shrink-interval-secs",
+        value: "<secs>|none",
+        help: "Background SHRINK interval",
+    },
+    Setting {
+        field: "shrink_live_ratio",
+        flag: "--shrink-live-ratio",
+        value: "<ratio>",
+        help: "Background SHRINK only below this live/allocated ratio",
+    },
+    Setting {
+        field: "expiry_sweep_interval_secs",
+        flag: "--expiry-sweep-interval-secs",
+        value: "<secs>|none",
+        help: "How often expired keys are removed",
+    },
+    Setting {
+        field: "wal_probe_interval_secs",
+        flag: "--wal-probe-interval-secs",
+        value: "<secs>|none",
+        help: "How often a failed WAL is probed",
+    },
+    Setting {
+        field: "compaction_threshold_bytes",
+        flag: "--compaction-threshold-bytes",
+        value: "<n>|none",
+        help: "WAL size that triggers compaction",
+    },
+    Setting {
+        field: "snapshot_path",
+        flag: "--snapshot-path",
+        value: "<path>|none",
+        help: "Snapshot file",
+    },
+    Setting {
+        field: "snapshot_interval_secs",
+        flag: "--snapshot-interval-secs",
+        value: "<secs>|none",
+        help: "Background snapshot interval",
+    },
+    Setting {
+        field: "auth_token",
+        flag: "--auth-token",
+        value: "<token>|none",
+        help: "Token clients must AUTH with",
+    },
+    Setting {
+        field: "allow_flush_all",
+        flag: "--allow-flush-all",
+        value: "true|false",
+        help: "Serve FLUSHALL",
+    },
+    Setting {
+        field: "metrics_addr",
+        flag: "--metrics-addr",
+        value: "<addr>|none",
+        help: "Prometheus metrics address",
+    },
+    Setting {
+        field: "replica_of",
+        flag: "--replica-of",
+        value: "<addr>|none",
+        help: "Primary to replicate, read-only",
+    },
+    Setting {
+        field: "read_only",
+        flag: "--read-only",
+        value: "true|false",
+        help: "Refuse writes",
+    },
+    Setting {
+        field: "allowed_commands",
+        flag: "--allowed-commands",
+        value: "<verb,...>|all",
+        help: "Only commands served",
+    },
+];
+
+impl Setting {
+    fn env_var(&self) -> String {
+        format!("RUSTVAULT_{}", self.flag.trim_start_matches('-').replace('-', "_").to_uppercase())
+    }
+}
+
+/// Set `field` of `config` from its text form
+fn apply(config: &mut ServerConfig, field: &str, value: &str) -> std::result::Result<(), String> {
+    fn number<T: std::str::FromStr>(value: &str) -> std::result::Result<T, String> {
+        value.parse().map_err(|_| format!("expected a number, got {:?}", value))
+    }
+    fn optional<T>(
+        value: &str,
+        parse: impl Fn(&str) -> std::result::Result<T, String>,
+    ) -> std::result::Result<Option<T>, String> {
+        match value {
+            "none" => Ok(None),
+            value => parse(value).map(Some),
+        }
+    }
+    fn secs(value: &str) -> std::result::Result<Duration, String> {
+        value
+            .parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .ok_or_else(|| format!("expected a number of seconds, got {:?}", value))
+    }
+    fn addr(value: &str) -> std::result::Result<String, String> {
+        match value.parse::<SocketAddr>() {
+            Ok(_) => Ok(value.to_string()),
+            Err(_) => Err(format!("expected an address such as 127.0.0.1:8080, got {:?}", value)),
+        }
+    }
+    fn rate_limit(value: &str) -> std::result::Result<RateLimit, String> {
+        let (ops, burst) = value.split_once(':').unwrap_or((value, ""));
+        let ops_per_sec = number::<f64>(ops)?;
+        if !(ops_per_sec > 0.0 && ops_per_sec.is_finite()) {
+            return Err(format!("expected a positive rate, got {:?}", ops));
+        }
+        let burst = match burst {
+            // One second's worth
+            "" => ops_per_sec.ceil() as u32,
+            burst => number(burst)?,
+        };
+        Ok(RateLimit { ops_per_sec, burst })
+    }
+    fn one_of<T: Copy>(value: &str, choices: &[(&str, T)]) -> std::result::Result<T, String> {
+        match choices.iter().find(|(name, _)| *name == value) {
+            Some(&(_, choice)) => Ok(choice),
+            None => {
+                let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+                Err(format!("expected one of {}, got {:?}", names.join(", "), value))
+            }
+        }
+    }
+    
+    match field {
+        "bind_addr" => {
+            config.bind_addr = match value.strip_prefix(UNIX_SCHEME) {
+                Some(path) if !path.is_empty() => value.to_string(),
+                _ => addr(value)?,
+            }
+        }
+        "wal_path" => config.wal_path = value.to_string(),
+        "wal_sync" => {
+            config.wal_sync = match value {
+                "always" => SyncPolicy::Always,
+                "never" => SyncPolicy::Never,
+                millis => SyncPolicy::EveryMillis(
+                    millis.parse().map_err(|_| format!("expected always, never or a number of milliseconds, got {:?}", value))?,
+                ),
+            }
+        }
+        "wal_group_delay_micros" => config.wal_group_delay_micros = number(value)?,
+        "wal_format" => config.wal_format = one_of(value, &[("json", WalFormat::Json), ("binary", WalFormat::Binary)])?,
+        "wal_segment_size_bytes" => config.wal_segment_size_bytes = optional(value, number)?,
+        "recovery_mode" => {
+            config.recovery_mode = one_of(
+                value,
+                &[("strict", RecoveryMode::Strict), ("truncate-corrupt", RecoveryMode::TruncateCorrupt)],
+            )?
+        }
+        "replay_progress_interval" => config.replay_progress_interval = optional(value, number)?,
+        "startup_timeout" => config.startup_timeout = optional(value, secs)?,
+        "max_connections" => config.max_connections = number(value)?,
+        "connection_limit_action" => {
+            config.connection_limit_action = one_of(
+                value,
+                &[("reject", ConnectionLimitAction::Reject), ("queue", ConnectionLimitAction::Queue)],
+            )?
+        }
+        "rate_limit" => config.rate_limit = optional(value, rate_limit)?,
+        "ip_rate_limit" => config.ip_rate_limit = optional(value, rate_limit)?,
+        "rate_limit_mode" => {
+            config.rate_limit_mode = one_of(value, &[("delay", RateLimitMode::Delay), ("reject", RateLimitMode::Reject)])?
+        }
+        "max_key_bytes" => config.max_key_bytes = number(value)?,
+        "max_value_bytes" => config.max_value_bytes = number(value)?,
+        "shards" => config.shards = number(value)?,
+        "hasher" => {
+            config.hasher = one_of(
+                value,
+                &[
+                    ("siphash", HasherKind::SipHash),
+                    #[cfg(feature = "ahash")]
+                    ("ahash", HasherKind::AHash),
+                    #[cfg(feature = "fxhash")]
+                    ("fxhash", HasherKind::FxHash),
+                ],
+            )?
+        }
+        "max_memory_bytes" => config.max_memory_bytes = optional(value, number)?,
+        "eviction_policy" => {
+            config.eviction_policy =
+                one_of(value, &[("noeviction", EvictionPolicy::NoEviction), ("lru", EvictionPolicy::Lru)])?
+        }
+        "compression" => {
+            config.compression = optional(value, |value| {
+                let (algorithm, min_size) = value.split_once(':').unwrap_or((value, ""));
+                Ok(CompressionConfig {
+                    algorithm: one_of(algorithm, &[("lz4", CompressionAlgorithm::Lz4), ("zstd", CompressionAlgorithm::Zstd)])?,
+                    min_size_bytes: match min_size {
+                        "" => DEFAULT_MIN_SIZE_BYTES,
+                        min_size => number(min_size)?,
+                    },
+                })
+            })?
+        }
+        "idle_timeout" => config.idle_timeout = optional(value, secs)?,
+        "read_timeout" => config.read_timeout = optional(value, secs)?,
+        "shutdown_drain_timeout" => config.shutdown_drain_timeout = secs(value)?,
+        "hung_command_threshold_secs" => config.hung_command_threshold_secs = optional(value, number)?,
+        "hung_command_action" => {
+            config.hung_command_action =
+                one_of(value, &[("warn", HungCommandAction::Warn), ("kill", HungCommandAction::Kill)])?
+        }
+        "slowlog_threshold" => config.slowlog_threshold = optional(value, secs)?,
+        "slowlog_max_len" => config.slowlog_max_len = number(value)?,
+        "slowlog_max_key_len" => config.slowlog_max_key_len = number(value)?,
+        "shrink_interval_secs" => config.shrink_interval_secs = optional(value, number)?,
+        "shrink_live_ratio" => config.shrink_live_ratio = number(value)?,
+        "expiry_sweep_interval_secs" => config.expiry_sweep_interval_secs = optional(value, number)?,
+        "wal_probe_interval_secs" => config.wal_probe_interval_secs = optional(value, number)?,
+        "compaction_threshold_bytes" => config.compaction_threshold_bytes = optional(value, number)?,
+        "snapshot_path" => config.snapshot_path = optional(value, |path| Ok(path.to_string()))?,
+        "snapshot_interval_secs" => config.snapshot_interval_secs = optional(value, number)?,
+        "auth_token" => config.auth_token = optional(value, |token| Ok(token.to_string()))?,
+        "allow_flush_all" => config.allow_flush_all = one_of(value, &[("true", true), ("false", false)])?,
+        "metrics_addr" => config.metrics_addr = optional(value, addr)?,
+        "replica_of" => config.replica_of = optional(value, |primary| Ok(primary.to_string()))?,
+        "read_only" => config.read_only = one_of(value, &[("true", true), ("false", false)])?,
+        "allowed_commands" => {
+            config.allowed_commands = match value {
+                "all" => None,
+                verbs => Some(
+                    verbs
+                        .split(',')
+                        .map(|verb| match command_spec(verb.trim()) {
+                            Some(spec) => Ok(spec.name.to_string()),
+                            None => Err(format!("unknown command {:?}", verb.trim())),
+                        })
+                        .collect::<std::result::Result<_, _>>()?,
+                ),
+            }
+        }
+        _ => return Err("unknown setting".to_string()),
+    }
+    Ok(())
+}
+
+/// `key = value` pairs of a flat TOML document, with their line numbers
+///
+/// Strings, numbers and booleans are supported; tables and arrays are not,
+/// since every setting is a plain value.
+fn parse_toml(text: &str) -> std::result::Result<Vec<(usize, String, String)>, String> {
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(format!("line {}: tables are not supported", number));
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected key = value", number));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let value = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let mut text = String::new();
+                let mut chars = quoted.chars();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('"') => text.push('"'),
+                            Some('\\') => text.push('\\'),
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            _ => return Err(format!("line {}: unsupported escape", number)),
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(format!("line {}: unterminated string", number)),
+                    }
+                }
+                let rest = chars.as_str().trim();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(format!("line {}: unexpected text after the string", number));
+                }
+                text
+            }
+            None => {
+                let value = value.split('#').next().unwrap_or("").trim();
+                if value.is_empty() || value.starts_with('[') || value.starts_with('{') {
+                    return Err(format!("line {}: expected a string, number or boolean", number));
+                }
+                value.to_string()
+            }
+        };
+        entries.push((number, key.to_string(), value));
+    }
+    Ok(entries)
+}
+
+/// Build the server's configuration from `args` (without the program name)
+/// and the environment variables `env` looks up
+///
+/// `None` means `--help` was asked for.
+fn load_config(
+    args: &[String],
+    env: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<Option<ServerConfig>, String> {
+    let mut config_path = None;
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if flag == "--help" || flag == "-h" {
+            return Ok(None);
+        }
+        let value = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+        if flag == "--config" {
+            config_path = Some(value.clone());
+            continue;
+        }
+        match SETTINGS.iter().find(|setting| setting.flag == flag) {
+            Some(setting) => flags.push((setting, value)),
+            None => return Err(format!("Unknown option: {}", flag)),
+        }
+    }
+    
+    let mut config = ServerConfig::default();
+    for setting in SETTINGS {
+        let name = setting.env_var();
+        if let Some(value) = env(&name) {
+            apply(&mut config, setting.field, &value).map_err(|e| format!("Invalid {}: {}", name, e))?;
+        }
+    }
+    if let Some(path) = config_path {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read config file {}: {}", path, e))?;
+        let entries = parse_toml(&text).map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+        for (line, key, value) in entries {
+            apply(&mut config, &key, &value)
+                .map_err(|e| format!("Invalid config file {}: line {}: {}: {}", path, line, key, e))?;
+        }
+    }
+    for (setting, value) in flags {
+        apply(&mut config, setting.field, value).map_err(|e| format!("Invalid {}: {}", setting.flag, e))?;
+    }
+    Ok(Some(config))
+}
+
+fn print_help() {
+    println!("Usage: server [--config <path>] [--<setting> <value> ...]");
+    println!();
+    println!("A flag beats the config file, which beats the environment, which beats");
+    println!("the default.");
+    println!();
+    println!("  {:<42} TOML file of settings, keyed by field name", "--config <path>");
+    for setting in SETTINGS {
+        let usage = format!("{} {}", setting.flag, setting.value);
+        println!("  {:<42} {} [env: {}]", usage, setting.help, setting.env_var());
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Parse command line arguments or use defaults
-    let config = ServerConfig::default();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match load_config(&args, |name| env::var(name).ok()) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            print_help();
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Run with --help for the list of settings");
+            std::process::exit(2);
+        }
+    };
+    
+    // Sockets passed by systemd take the place of bind_addr
+    let activated = activation::listen_fds()?;
     
     // Create and start server
     let server = RustVaultServer::new(config).await?;
@@ -30,7 +613,183 @@ async fn main() -> Result<()> {
     });
     
     // Run the server
-    server.run().await?;
+    match activated {
+        Some(listeners) => {
+            println!(
+                "Using {} socket(s) passed by systemd socket activation",
+                listeners.len()
+            );
+            server.run_with_listeners(listeners).await?;
+        }
+        None => server.run().await?,
+    }
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::Write;
+    
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+    
+    fn env_of(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+    
+    fn config_file(text: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+        file
+    }
+    
+    #[test]
+    fn test_precedence() {
+        let file = config_file(
+            "# Settings for the test\n\
+             bind_addr = \"127.0.0.1:7000\"\n\
+             wal_path = \"file.log\"  # trailing comment\n\
+             max_connections = 20\n",
+        );
+        let env = env_of(&[
+            ("RUSTVAULT_BIND", "127.0.0.1:6000"),
+            ("RUSTVAULT_WAL_PATH", "env.log"),
+            ("RUSTVAULT_MAX_CONNECTIONS", "10"),
+            ("RUSTVAULT_SHARDS", "4"),
+        ]);
+        let path = file.path().to_str().unwrap();
+        
+        // Defaults alone
+        let config = load_config(&[], env_of(&[])).unwrap().unwrap();
+        assert_eq!(config.bind_addr, ServerConfig::default().bind_addr);
+        
+        // The environment beats the defaults
+        let config = load_config(&[], &env).unwrap().unwrap();
+        assert_eq!(config.bind_addr, "127.0.0.1:6000");
+        assert_eq!(config.max_connections, 10);
+        
+        // The file beats the environment, where it says anything
+        let config = load_config(&args(&["--config", path]), &env).unwrap().unwrap();
+        assert_eq!(config.bind_addr, "127.0.0.1:7000");
+        assert_eq!(config.wal_path, "file.log");
+        assert_eq!(config.max_connections, 20);
+        assert_eq!(config.shards, 4);
+        
+        // Flags beat everything, wherever --config appears
+        let flags = args(&["--bind", "127.0.0.1:8000", "--config", path, "--shards", "2"]);
+        let config = load_config(&flags, &env).unwrap().unwrap();
+        assert_eq!(config.bind_addr, "127.0.0.1:8000");
+        assert_eq!(config.wal_path, "file.log");
+        assert_eq!(config.shards, 2);
+        
+        assert!(load_config(&args(&["--wal-path", "x", "--help"]), &env).unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_setting_values() {
+        let flags = args(&[
+            "--wal-sync", "always",
+            "--wal-format", "binary",
+            "--wal-group-delay-micros", "500",
+            "--idle-timeout", "2.5",
+            "--startup-timeout", "600",
+            "--replay-progress-interval", "none",
+            "--snapshot-interval-secs", "none",
+            "--auth-token", "s3cr3t",
+            "--allow-flush-all", "true",
+            "--hung-command-action", "kill",
+            "--slowlog-threshold", "0.25",
+            "--max-memory-bytes", "1048576",
+            "--eviction-policy", "lru",
+            "--hasher", "siphash",
+            "--compression", "zstd:4096",
+            "--rate-limit", "250:50",
+            "--ip-rate-limit", "1000",
+            "--rate-limit-mode", "reject",
+            "--read-only", "true",
+            "--bind", "unix:///run/rustvault.sock",
+            "--allowed-commands", "get,SCAN, mget",
+        ]);
+        let config = load_config(&flags, env_of(&[])).unwrap().unwrap();
+        assert_eq!(config.wal_sync, SyncPolicy::Always);
+        assert_eq!(config.wal_format, WalFormat::Binary);
+        assert_eq!(config.wal_group_delay_micros, 500);
+        assert_eq!(config.idle_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(config.startup_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(config.replay_progress_interval, None);
+        assert_eq!(config.snapshot_interval_secs, None);
+        assert_eq!(config.auth_token.as_deref(), Some("s3cr3t"));
+        assert!(config.allow_flush_all);
+        assert_eq!(config.hung_command_action, HungCommandAction::Kill);
+        assert_eq!(config.slowlog_threshold, Some(Duration::from_millis(250)));
+        assert_eq!(config.max_memory_bytes, Some(1 << 20));
+        assert_eq!(config.eviction_policy, EvictionPolicy::Lru);
+        assert_eq!(config.hasher, HasherKind::SipHash);
+        let compression = CompressionConfig { algorithm: CompressionAlgorithm::Zstd, min_size_bytes: 4096 };
+        assert_eq!(config.compression, Some(compression));
+        assert_eq!(config.rate_limit, Some(RateLimit { ops_per_sec: 250.0, burst: 50 }));
+        assert_eq!(config.ip_rate_limit, Some(RateLimit { ops_per_sec: 1000.0, burst: 1000 }));
+        assert_eq!(config.rate_limit_mode, RateLimitMode::Reject);
+        assert!(config.read_only);
+        assert_eq!(config.bind_addr, "unix:///run/rustvault.sock");
+        let allowed = config.allowed_commands.unwrap();
+        assert_eq!(allowed, ["GET", "SCAN", "MGET"].into_iter().map(String::from).collect());
+        
+        let config = load_config(&args(&["--wal-sync", "250"]), env_of(&[])).unwrap().unwrap();
+        assert_eq!(config.wal_sync, SyncPolicy::EveryMillis(250));
+        
+        let config = load_config(&args(&["--compression", "lz4"]), env_of(&[])).unwrap().unwrap();
+        assert_eq!(config.compression.map(|c| (c.algorithm, c.min_size_bytes)), Some((CompressionAlgorithm::Lz4, 1024)));
+        
+        // Every setting is known to `apply`
+        for setting in SETTINGS {
+            let error = apply(&mut ServerConfig::default(), setting.field, "\u{0}");
+            assert_ne!(error, Err("unknown setting".to_string()), "{}", setting.field);
+        }
+    }
+    
+    #[test]
+    fn test_bad_values_are_reported() {
+        let error = load_config(&args(&["--bind", "localhost"]), env_of(&[])).unwrap_err();
+        assert!(error.starts_with("Invalid --bind: expected an address"), "{}", error);
+        
+        let error = load_config(&[], env_of(&[("RUSTVAULT_MAX_CONNECTIONS", "lots")])).unwrap_err();
+        assert!(error.starts_with("Invalid RUSTVAULT_MAX_CONNECTIONS"), "{}", error);
+        
+        let error = load_config(&args(&["--config", "/nonexistent/rustvault.toml"]), env_of(&[])).unwrap_err();
+        assert!(error.starts_with("Can't read config file"), "{}", error);
+        
+        let file = config_file("shards = 2\nbind_adr = \"127.0.0.1:1\"\n");
+        let flags = args(&["--config", file.path().to_str().unwrap()]);
+        let error = load_config(&flags, env_of(&[])).unwrap_err();
+        assert!(error.ends_with("line 2: bind_adr: unknown setting"), "{}", error);
+        
+        let file = config_file("[server]\nshards = 2\n");
+        let flags = args(&["--config", file.path().to_str().unwrap()]);
+        let error = load_config(&flags, env_of(&[])).unwrap_err();
+        assert!(error.ends_with("line 1: tables are not supported"), "{}", error);
+        
+        assert!(load_config(&args(&["--nope", "1"]), env_of(&[])).is_err());
+        assert!(load_config(&args(&["--bind"]), env_of(&[])).is_err());
+        assert!(load_config(&args(&["--allow-flush-all", "yes"]), env_of(&[])).is_err());
+        assert!(load_config(&args(&["--rate-limit", "0"]), env_of(&[])).is_err());
+        
+        let error = load_config(&args(&["--allowed-commands", "GET,FROB"]), env_of(&[])).unwrap_err();
+        assert!(error.ends_with("unknown command \"FROB\""), "{}", error);
+    }
+    
+    #[test]
+    fn test_parse_toml_strings() {
+        let entries = parse_toml("auth_token = \"a \\\"quoted\\\" # token\"\n").unwrap();
+        assert_eq!(entries, vec![(1, "auth_token".to_string(), "a \"quoted\" # token".to_string())]);
+        assert!(parse_toml("auth_token = \"open\n").is_err());
+        assert!(parse_toml("auth_token = \"a\" b\n").is_err());
+        assert!(parse_toml("shards\n").is_err());
+    }
+}
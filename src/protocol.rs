@@ -3,109 +3,1523 @@
 //! Implements zero-copy parsing using nom for high performance
 
 use crate::error::{RustVaultError, Result};
+use bytes::BufMut;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while1},
-    character::complete::space1,
-    combinator::map,
-    sequence::{terminated, tuple},
+    bytes::complete::{tag, take, take_until, take_while1},
+    character::complete::{digit1, line_ending, space1},
+    combinator::{cut, map, map_res, opt, recognize, success},
+    error::ErrorKind,
+    multi::{many0, many1},
+    sequence::{preceded, tuple},
     IResult,
 };
 use serde::{Deserialize, Serialize};
-use std::str;
+use std::fmt;
+use std::str::{self, FromStr};
+
+/// Maximum number of input bytes quoted in a [`ProtocolError`] snippet
+const SNIPPET_LEN: usize = 32;
+
+/// Largest value accepted in length-prefixed form
+pub const MAX_VALUE_LEN: usize = 512 * 1024 * 1024;
+
+/// Most pairs one MSET may carry, inline or length-prefixed
+pub const MAX_MSET_PAIRS: usize = 10_000;
+
+/// Highest protocol version this build speaks, offered and accepted with
+/// `HELLO`
+///
+/// Version 1 is what a connection speaks until it sends `HELLO`: values
+/// are sent inline unless they need a length prefix. Version 2 sends every
+/// `VALUE` length-prefixed, so a client reads them all the same way.
+pub const PROTOCOL_VERSION: u32 = 2;
 
 /// Commands supported by the RustVault protocol
+///
+/// Keys are text; values are arbitrary bytes.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Command {
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        #[serde(with = "value_format")]
+        value: Vec<u8>,
+        /// Write only if the key is absent, or only if it is present;
+        /// `None` writes either way
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        condition: Option<SetCondition>,
+    },
+    /// SET with an expiry `seconds` from now
+    SetEx {
+        key: String,
+        #[serde(with = "value_format")]
+        value: Vec<u8>,
+        seconds: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        condition: Option<SetCondition>,
+    },
     Get { key: String },
+    /// Whether `key` holds an unexpired value, without sending it back
+    Exists { key: String },
+    /// How many times `key` has been set, and when it was created and last
+    /// set
+    Stat { key: String },
     Delete { key: String },
+    /// Remove every key matching the glob `pattern`, all at once; logged
+    /// as itself, so replay removes the same keys at the same point
+    DeletePattern { pattern: String },
+    /// Expire a key `seconds` from now
+    Expire { key: String, seconds: u64 },
+    /// Expire a key at an absolute time, in milliseconds since the Unix
+    /// epoch; this is how expiries are logged to the WAL
+    ExpireAt { key: String, unix_millis: u64 },
+    /// Admin: release unused store capacity
+    Shrink,
+    /// Admin: rewrite the WAL down to the live data now
+    Compact,
+    /// Admin: report the WAL's size and what it has done since it was
+    /// opened
+    WalStats,
+    /// Look up a command's classification in the command table
+    CommandInfo { name: String },
+    /// Admin: report on background maintenance jobs
+    MaintenanceStatus,
+    /// Admin: server counters and figures such as the key count
+    Info,
+    /// Admin: zero the per-command latency histograms `INFO` reports
+    StatsReset,
+    /// Admin: the latest `count` entries of the slow log, newest first, or
+    /// the server's default number of them
+    SlowLogGet { count: Option<usize> },
+    /// Admin: empty the slow log
+    SlowLogReset,
+    /// Liveness check, answered `PONG` without touching the store
+    Ping,
+    /// Readiness check: `OK` once the WAL has been replayed
+    Ready,
+    /// Digest of the keys starting with `prefix` (every key if empty)
+    Checksum { prefix: String },
+    /// Digests of the keys starting with `prefix`, split into `buckets`
+    /// ranges by the next byte of the key
+    ChecksumRanges { buckets: usize, prefix: String },
+    /// Up to `count` keys starting with `prefix`, resuming the scan at
+    /// `cursor` (0 to start)
+    Scan { prefix: String, cursor: u64, count: usize },
+    /// Add `delta` to the integer stored at `key`, counting from 0 if the
+    /// key doesn't exist
+    Incr { key: String, delta: i64 },
+    /// Subtract `delta` from the integer stored at `key`
+    Decr { key: String, delta: i64 },
+    /// Append `value` to the value stored at `key`, creating the key if it
+    /// doesn't exist; logged as itself
+    Append {
+        key: String,
+        #[serde(with = "value_format")]
+        value: Vec<u8>,
+    },
+    /// Length of the value stored at `key`
+    Strlen { key: String },
+    /// Set `key` to `value` and reply with the value it replaces; logged as
+    /// a `Set`
+    GetSet {
+        key: String,
+        #[serde(with = "value_format")]
+        value: Vec<u8>,
+    },
+    /// Delete `key` and reply with the value it held; logged as a `Delete`
+    GetDel { key: String },
+    /// Set several keys at once; readers see all of them change or none
+    MSet { pairs: Vec<(String, Vec<u8>)> },
+    /// Get several keys at once
+    MGet { keys: Vec<String> },
+    /// Set `key` to `new` only if its value is currently `expected`
+    Cas {
+        key: String,
+        #[serde(with = "value_format")]
+        expected: Vec<u8>,
+        #[serde(with = "value_format")]
+        new: Vec<u8>,
+    },
+    /// Take the lock `key` for `ttl_ms`, if it is free or already held with
+    /// `token`; logged as a `Set` of the token with its deadline
+    Lock { key: String, token: String, ttl_ms: u64 },
+    /// Release the lock `key` if it is held with `token`; logged as a
+    /// `Delete`
+    Unlock { key: String, token: String },
+    /// Authenticate the connection; answered by the connection itself and
+    /// never logged
+    Auth { token: String },
+    /// Agree on a protocol version: the server answers with the lower of
+    /// `version` and its own [`PROTOCOL_VERSION`], which the connection
+    /// speaks from then on
+    Hello { version: u32 },
+    /// Remove every key; logged as itself, so replay empties the store at
+    /// the same point
+    FlushAll,
+    /// Switch the connection to receiving an event for every change to a
+    /// key matching the glob `pattern`
+    Subscribe { pattern: String },
+    /// Switch the connection to carrying a copy of every change, for a
+    /// replica to apply
+    Replicate,
+    /// Start queuing commands on the connection, for `EXEC` to apply
+    /// together
+    Multi,
+    /// Apply the queued commands as one atomic step
+    Exec,
+    /// Drop the queued commands
+    Discard,
+    /// Have the next `EXEC` do nothing if any of `keys` has changed by then
+    Watch { keys: Vec<String> },
+    /// Switch the connection to the keyspace `namespace`
+    Select { namespace: String },
+    /// Remove every key in `namespace`, or in the connection's own when
+    /// `None`; logged with the namespace filled in
+    FlushDb { namespace: Option<String> },
+    /// Count the keys in `namespace`, or in the connection's own when `None`
+    DbSize { namespace: Option<String> },
+    /// Admin: read the server settings named by `key`, or change one while
+    /// the server runs; never logged, so a restart goes back to the
+    /// configured value
+    Config { action: ConfigAction, key: String },
+    /// Admin: write every key to a backup file at `path`, on the server
+    Backup { path: String },
+    /// Load the backup file at `path`, on the server, into an empty store,
+    /// or over the keys already there with `merge`; logged as the SETs it
+    /// performs
+    Restore { path: String, merge: bool },
+}
+
+/// What a `CONFIG` command does with its key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConfigAction {
+    /// Report every setting whose name matches the key, as a glob
+    Get,
+    /// Change the setting to `value`
+    Set { value: String },
+}
+
+/// When a `SET` sent with `NX` or `XX` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SetCondition {
+    /// `NX`: only if the key doesn't exist
+    IfAbsent,
+    /// `XX`: only if it does
+    IfPresent,
+}
+
+impl SetCondition {
+    /// The flag as sent on the wire
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SetCondition::IfAbsent => "NX",
+            SetCondition::IfPresent => "XX",
+        }
+    }
+}
+
+/// How values are written in the JSON of a WAL entry
+///
+/// Text values are plain JSON strings, as they were before values became
+/// binary-safe, so older logs still replay. Values that aren't UTF-8 are
+/// written as arrays of bytes.
+mod value_format {
+    use serde::{Deserialize, Deserializer, Serializer};
+    
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(value) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.serialize_bytes(value),
+        }
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Text(String),
+            Bytes(Vec<u8>),
+        }
+        
+        Ok(match Value::deserialize(deserializer)? {
+            Value::Text(text) => text.into_bytes(),
+            Value::Bytes(bytes) => bytes,
+        })
+    }
+}
+
+/// How a command interacts with the dataset
+///
+/// Read-only and replica-aware code must agree on this, so it lives in
+/// the command table rather than being decided at each call site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CommandKind {
+    /// Never modifies data; safe to serve from a replica
+    Read,
+    /// Modifies data
+    Write,
+    /// Operates on the server itself rather than the data
+    Admin,
+}
+
+impl fmt::Display for CommandKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandKind::Read => write!(f, "read"),
+            CommandKind::Write => write!(f, "write"),
+            CommandKind::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for CommandKind {
+    type Err = RustVaultError;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(CommandKind::Read),
+            "write" => Ok(CommandKind::Write),
+            "admin" => Ok(CommandKind::Admin),
+            _ => Err(RustVaultError::InvalidCommand(format!("Unknown command kind: {}", s))),
+        }
+    }
+}
+
+/// Class of failure an `ERROR` reply reports, sent as its first word
+///
+/// The set is part of the protocol: codes may be added, but never renamed
+/// or reused, so clients can branch on them instead of on the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The command couldn't be parsed
+    Parse,
+    /// A key, value or command line over the server's limits
+    TooLarge,
+    /// A write sent to a replica
+    ReadOnly,
+    /// Refused by the server's `read_only` or `allowed_commands` setting,
+    /// or a command it has disabled
+    NotPermitted,
+    /// Authentication is required, or the token was wrong
+    NoAuth,
+    /// The server is still replaying its WAL
+    Loading,
+    /// Too many connections
+    Busy,
+    /// Over the client's rate limit; the message says when to retry, as
+    /// `retry_after_ms=<n>`
+    RateLimited,
+    /// The connection sat idle, or stalled partway through a command
+    Timeout,
+    /// A write refused under the memory limit
+    OutOfMemory,
+    /// The WAL can't be written, so writes are refused until it can
+    Persistence,
+    /// `EXEC` of a transaction that had a command refused
+    ExecAbort,
+    /// A well-formed command with arguments or in a state the server
+    /// doesn't accept
+    Invalid,
+    /// The command failed inside the server
+    Internal,
+}
+
+/// Every code and its wire form
+const ERROR_CODES: &[(ErrorCode, &str)] = &[
+    (ErrorCode::Parse, "ERR_PARSE"),
+    (ErrorCode::TooLarge, "ERR_TOO_LARGE"),
+    (ErrorCode::ReadOnly, "ERR_READONLY"),
+    (ErrorCode::NotPermitted, "ERR_NOT_PERMITTED"),
+    (ErrorCode::NoAuth, "ERR_NOAUTH"),
+    (ErrorCode::Loading, "ERR_LOADING"),
+    (ErrorCode::Busy, "ERR_BUSY"),
+    (ErrorCode::RateLimited, "ERR_RATELIMIT"),
+    (ErrorCode::Timeout, "ERR_TIMEOUT"),
+    (ErrorCode::OutOfMemory, "ERR_OOM"),
+    (ErrorCode::Persistence, "ERR_PERSISTENCE"),
+    (ErrorCode::ExecAbort, "ERR_EXECABORT"),
+    (ErrorCode::Invalid, "ERR_INVALID"),
+    (ErrorCode::Internal, "ERR_INTERNAL"),
+];
+
+impl ErrorCode {
+    /// The code as sent on the wire
+    pub fn as_str(self) -> &'static str {
+        ERROR_CODES
+            .iter()
+            .find(|(code, _)| *code == self)
+            .map(|(_, name)| *name)
+            .expect("every code has a wire form")
+    }
+    
+    /// The code and message of an `ERROR` reply's text, or no code for a
+    /// reply from a server that doesn't send them
+    pub fn split(text: &str) -> (Option<ErrorCode>, &str) {
+        let (word, message) = text.split_once(' ').unwrap_or((text, ""));
+        match word.parse() {
+            Ok(code) => (Some(code), message),
+            Err(_) => (None, text),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = RustVaultError;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        ERROR_CODES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(code, _)| *code)
+            .ok_or_else(|| RustVaultError::InvalidCommand(format!("Unknown error code: {}", s)))
+    }
+}
+
+/// Static description of a protocol command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandSpec {
+    /// Verb as sent on the wire
+    pub name: &'static str,
+    pub kind: CommandKind,
+    pub syntax: &'static str,
+}
+
+/// Every command the server understands
+pub const COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec {
+        name: "SET",
+        kind: CommandKind::Write,
+        syntax: "SET <key> <value> [EX <seconds>] [NX|XX] | SET <key> $<len> [EX <seconds>] [NX|XX]",
+    },
+    CommandSpec { name: "GET", kind: CommandKind::Read, syntax: "GET <key>" },
+    CommandSpec { name: "EXISTS", kind: CommandKind::Read, syntax: "EXISTS <key>" },
+    CommandSpec { name: "STAT", kind: CommandKind::Read, syntax: "STAT <key>" },
+    CommandSpec { name: "SUBSCRIBE", kind: CommandKind::Read, syntax: "SUBSCRIBE <pattern>" },
+    CommandSpec { name: "REPLICATE", kind: CommandKind::Admin, syntax: "REPLICATE" },
+    CommandSpec { name: "DELETE", kind: CommandKind::Write, syntax: "DELETE <key>" },
+    CommandSpec { name: "DELPAT", kind: CommandKind::Write, syntax: "DELPAT <pattern>" },
+    CommandSpec { name: "EXPIRE", kind: CommandKind::Write, syntax: "EXPIRE <key> <seconds>" },
+    CommandSpec { name: "PEXPIREAT", kind: CommandKind::Write, syntax: "PEXPIREAT <key> <unix-millis>" },
+    CommandSpec { name: "SHRINK", kind: CommandKind::Admin, syntax: "SHRINK" },
+    CommandSpec { name: "COMPACT", kind: CommandKind::Admin, syntax: "COMPACT" },
+    CommandSpec { name: "WALSTATS", kind: CommandKind::Admin, syntax: "WALSTATS" },
+    CommandSpec { name: "COMMAND", kind: CommandKind::Read, syntax: "COMMAND INFO <name>" },
+    CommandSpec { name: "MAINTENANCE", kind: CommandKind::Admin, syntax: "MAINTENANCE STATUS" },
+    CommandSpec { name: "INFO", kind: CommandKind::Admin, syntax: "INFO" },
+    CommandSpec { name: "STATS", kind: CommandKind::Admin, syntax: "STATS RESET" },
+    CommandSpec { name: "SLOWLOG", kind: CommandKind::Admin, syntax: "SLOWLOG GET [n] | SLOWLOG RESET" },
+    CommandSpec { name: "PING", kind: CommandKind::Read, syntax: "PING" },
+    CommandSpec { name: "READY", kind: CommandKind::Read, syntax: "READY" },
+    CommandSpec {
+        name: "CHECKSUM",
+        kind: CommandKind::Read,
+        syntax: "CHECKSUM [prefix] | CHECKSUM RANGES <n> [prefix]",
+    },
+    CommandSpec { name: "SCAN", kind: CommandKind::Read, syntax: "SCAN <cursor> <count> [prefix]" },
+    CommandSpec { name: "INCR", kind: CommandKind::Write, syntax: "INCR <key> [delta]" },
+    CommandSpec { name: "DECR", kind: CommandKind::Write, syntax: "DECR <key> [delta]" },
+    CommandSpec {
+        name: "APPEND",
+        kind: CommandKind::Write,
+        syntax: "APPEND <key> <value> | APPEND <key> $<len>",
+    },
+    CommandSpec { name: "STRLEN", kind: CommandKind::Read, syntax: "STRLEN <key>" },
+    CommandSpec {
+        name: "GETSET",
+        kind: CommandKind::Write,
+        syntax: "GETSET <key> <value> | GETSET <key> $<len>",
+    },
+    CommandSpec { name: "GETDEL", kind: CommandKind::Write, syntax: "GETDEL <key>" },
+    CommandSpec {
+        name: "MSET",
+        kind: CommandKind::Write,
+        syntax: "MSET [<key> <value> ...] | MSET <key> $<len> [<key> $<len> ...]",
+    },
+    CommandSpec { name: "MGET", kind: CommandKind::Read, syntax: "MGET [<key> ...]" },
+    CommandSpec {
+        name: "CAS",
+        kind: CommandKind::Write,
+        syntax: "CAS <key> <expected> <new> | CAS <key> $<len> $<len>",
+    },
+    CommandSpec { name: "LOCK", kind: CommandKind::Write, syntax: "LOCK <key> <token> <ttl-ms>" },
+    CommandSpec { name: "UNLOCK", kind: CommandKind::Write, syntax: "UNLOCK <key> <token>" },
+    CommandSpec { name: "AUTH", kind: CommandKind::Admin, syntax: "AUTH <token>" },
+    CommandSpec { name: "HELLO", kind: CommandKind::Admin, syntax: "HELLO <version>" },
+    CommandSpec { name: "FLUSHALL", kind: CommandKind::Write, syntax: "FLUSHALL" },
+    CommandSpec { name: "MULTI", kind: CommandKind::Read, syntax: "MULTI" },
+    CommandSpec { name: "EXEC", kind: CommandKind::Write, syntax: "EXEC" },
+    CommandSpec { name: "DISCARD", kind: CommandKind::Read, syntax: "DISCARD" },
+    CommandSpec { name: "WATCH", kind: CommandKind::Read, syntax: "WATCH <key> [<key> ...]" },
+    CommandSpec { name: "SELECT", kind: CommandKind::Read, syntax: "SELECT <namespace>" },
+    CommandSpec { name: "FLUSHDB", kind: CommandKind::Write, syntax: "FLUSHDB [<namespace>]" },
+    CommandSpec { name: "DBSIZE", kind: CommandKind::Read, syntax: "DBSIZE [<namespace>]" },
+    CommandSpec { name: "CONFIG", kind: CommandKind::Admin, syntax: "CONFIG GET <key> | CONFIG SET <key> <value>" },
+    CommandSpec { name: "BACKUP", kind: CommandKind::Admin, syntax: "BACKUP <path>" },
+    CommandSpec { name: "RESTORE", kind: CommandKind::Write, syntax: "RESTORE <path> [MERGE]" },
+];
+
+/// Look up a command by verb, ignoring case
+pub fn command_spec(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE.iter().find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+impl Command {
+    /// Verb of this command on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } | Command::SetEx { .. } => "SET",
+            Command::Get { .. } => "GET",
+            Command::Exists { .. } => "EXISTS",
+            Command::Stat { .. } => "STAT",
+            Command::Subscribe { .. } => "SUBSCRIBE",
+            Command::Replicate => "REPLICATE",
+            Command::Delete { .. } => "DELETE",
+            Command::DeletePattern { .. } => "DELPAT",
+            Command::Expire { .. } => "EXPIRE",
+            Command::ExpireAt { .. } => "PEXPIREAT",
+            Command::Shrink => "SHRINK",
+            Command::Compact => "COMPACT",
+            Command::WalStats => "WALSTATS",
+            Command::CommandInfo { .. } => "COMMAND",
+            Command::MaintenanceStatus => "MAINTENANCE",
+            Command::Info => "INFO",
+            Command::StatsReset => "STATS",
+            Command::SlowLogGet { .. } | Command::SlowLogReset => "SLOWLOG",
+            Command::Ping => "PING",
+            Command::Ready => "READY",
+            Command::Checksum { .. } | Command::ChecksumRanges { .. } => "CHECKSUM",
+            Command::Scan { .. } => "SCAN",
+            Command::Incr { .. } => "INCR",
+            Command::Decr { .. } => "DECR",
+            Command::Append { .. } => "APPEND",
+            Command::Strlen { .. } => "STRLEN",
+            Command::GetSet { .. } => "GETSET",
+            Command::GetDel { .. } => "GETDEL",
+            Command::MSet { .. } => "MSET",
+            Command::MGet { .. } => "MGET",
+            Command::Cas { .. } => "CAS",
+            Command::Lock { .. } => "LOCK",
+            Command::Unlock { .. } => "UNLOCK",
+            Command::Auth { .. } => "AUTH",
+            Command::Hello { .. } => "HELLO",
+            Command::FlushAll => "FLUSHALL",
+            Command::Multi => "MULTI",
+            Command::Exec => "EXEC",
+            Command::Discard => "DISCARD",
+            Command::Watch { .. } => "WATCH",
+            Command::Select { .. } => "SELECT",
+            Command::FlushDb { .. } => "FLUSHDB",
+            Command::DbSize { .. } => "DBSIZE",
+            Command::Config { .. } => "CONFIG",
+            Command::Backup { .. } => "BACKUP",
+            Command::Restore { .. } => "RESTORE",
+        }
+    }
+    
+    /// Classification of this command, from [`COMMAND_TABLE`]
+    pub fn kind(&self) -> CommandKind {
+        command_spec(self.name())
+            .map(|spec| spec.kind)
+            .expect("every command has a COMMAND_TABLE entry")
+    }
+    
+    /// The first key the command names, in the form it was parsed or
+    /// qualified to; `None` for a command that names no key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::Get { key }
+            | Command::Exists { key }
+            | Command::Stat { key }
+            | Command::Delete { key }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Decr { key, .. }
+            | Command::Append { key, .. }
+            | Command::Strlen { key }
+            | Command::GetSet { key, .. }
+            | Command::GetDel { key }
+            | Command::Cas { key, .. }
+            | Command::Lock { key, .. }
+            | Command::Unlock { key, .. } => Some(key),
+            Command::MSet { pairs } => pairs.first().map(|(key, _)| key.as_str()),
+            Command::MGet { keys } | Command::Watch { keys } => keys.first().map(String::as_str),
+            _ => None,
+        }
+    }
 }
 
 /// Response types from the server
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Response {
     Ok,
-    Value(String),
+    Value(Vec<u8>),
     NotFound,
+    Integer(i64),
+    /// A failure, as its code and then the message; build it with
+    /// [`Response::error`]
     Error(String),
+    /// A page of keys, one per line, and the cursor to continue from; 0
+    /// once the scan is complete
+    Keys { keys: Vec<String>, cursor: u64 },
+    /// A `CAS` found a value other than the one it expected, or a `LOCK` or
+    /// `UNLOCK` found the lock held with another token
+    Conflict,
+    /// A `SET` with `NX` found the key, or one with `XX` didn't
+    NotApplied,
+    /// The answer to `PING`
+    Pong,
+    /// The protocol version a `HELLO` settled on
+    Hello(u32),
+    /// A key's version and when it was created and last set, in
+    /// milliseconds since the Unix epoch
+    Stat { version: u64, created_at: u64, updated_at: u64 },
+    /// What a `COMPACT` did: the WAL's size in bytes before and after, and
+    /// the live keys written
+    Compacted { before: u64, after: u64, keys: u64 },
+    /// One entry per requested key, `None` for keys that don't exist
+    Values(Vec<Option<Vec<u8>>>),
+    /// `INFO` figures as name/value pairs, one per line
+    Info(Vec<(String, String)>),
+    /// A change pushed to a subscribed connection
+    Event(KeyEvent),
+    /// A command was queued for `EXEC`
+    Queued,
+    /// One response per command an `EXEC` applied, in the order they were
+    /// queued
+    Results(Vec<Response>),
+}
+
+/// A change to the data, as pushed to connections that sent `SUBSCRIBE`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KeyEvent {
+    /// The key was given a value: by SET, MSET, a successful CAS, INCR,
+    /// DECR, APPEND or GETSET
+    Set(String),
+    /// The key was deleted
+    Del(String),
+    /// Every key was deleted
+    FlushAll,
+    /// This many events were dropped because the subscriber fell behind
+    Lagged(u64),
+}
+
+impl KeyEvent {
+    /// The key the event is about, if it is about one
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            KeyEvent::Set(key) | KeyEvent::Del(key) => Some(key),
+            KeyEvent::FlushAll | KeyEvent::Lagged(_) => None,
+        }
+    }
+    
+    /// Parse the part of an `EVENT` line after the verb
+    pub fn parse(event: &str) -> Option<Self> {
+        match event.split_once(' ') {
+            Some(("SET", key)) if !key.is_empty() => Some(KeyEvent::Set(key.to_string())),
+            Some(("DEL", key)) if !key.is_empty() => Some(KeyEvent::Del(key.to_string())),
+            Some(("LAGGED", n)) => n.parse().ok().map(KeyEvent::Lagged),
+            None if event == "FLUSHALL" => Some(KeyEvent::FlushAll),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyEvent::Set(key) => write!(f, "SET {}", key),
+            KeyEvent::Del(key) => write!(f, "DEL {}", key),
+            KeyEvent::FlushAll => write!(f, "FLUSHALL"),
+            KeyEvent::Lagged(n) => write!(f, "LAGGED {}", n),
+        }
+    }
+}
+
+/// Whether `text` matches the glob `pattern`: `*` stands for any run of
+/// bytes, `?` for any one byte, and everything else for itself
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and where in `text` it would resume
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` swallow one more byte and try again
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Whether the glob `pattern` has no byte [`glob_match`] takes literally,
+/// so it matches any text of a length it allows, such as `*`, `?*` or `*?*`
+pub fn glob_is_wildcard(pattern: &[u8]) -> bool {
+    pattern.iter().all(|&c| c == b'*' || c == b'?')
 }
 
 impl Response {
+    /// An `ERROR` reply with `code`
+    pub fn error(code: ErrorCode, message: impl fmt::Display) -> Self {
+        Response::Error(format!("{} {}", code, message))
+    }
+    
+    /// Code of an `ERROR` reply, if it has one
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Response::Error(text) => ErrorCode::split(text).0,
+            _ => None,
+        }
+    }
+    
     /// Serialize response to bytes for network transmission
     pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+    
+    /// Encode the response into an existing buffer, as protocol version 1
+    /// has it
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.encode_as(1, buf)
+    }
+    
+    /// Encode the response as protocol `version` has it
+    pub fn encode_as<B: BufMut>(&self, version: u32, buf: &mut B) {
+        match self {
+            Response::Ok => buf.put_slice(b"OK\r\n"),
+            Response::Value(v) if version >= 2 || needs_length_prefix(v) => {
+                buf.put_slice(format!("VALUE ${}\r\n", v.len()).as_bytes());
+                buf.put_slice(v);
+                buf.put_slice(b"\r\n");
+            }
+            Response::Value(v) => {
+                buf.put_slice(b"VALUE ");
+                buf.put_slice(v);
+                buf.put_slice(b"\r\n");
+            }
+            Response::NotFound => buf.put_slice(b"NOT_FOUND\r\n"),
+            Response::Conflict => buf.put_slice(b"CONFLICT\r\n"),
+            Response::NotApplied => buf.put_slice(b"NOT_APPLIED\r\n"),
+            Response::Pong => buf.put_slice(b"PONG\r\n"),
+            Response::Hello(version) => buf.put_slice(format!("HELLO {}\r\n", version).as_bytes()),
+            Response::Compacted { before, after, keys } => {
+                buf.put_slice(format!("COMPACTED {} -> {} ({} entries)\r\n", before, after, keys).as_bytes());
+            }
+            Response::Stat { version, created_at, updated_at } => {
+                buf.put_slice(format!("STAT {} {} {}\r\n", version, created_at, updated_at).as_bytes());
+            }
+            Response::Integer(n) => {
+                buf.put_slice(b"INT ");
+                buf.put_slice(n.to_string().as_bytes());
+                buf.put_slice(b"\r\n");
+            }
+            Response::Error(e) => {
+                buf.put_slice(b"ERROR ");
+                buf.put_slice(e.as_bytes());
+                buf.put_slice(b"\r\n");
+            }
+            Response::Values(values) => {
+                buf.put_slice(format!("VALUES {}\r\n", values.len()).as_bytes());
+                for value in values {
+                    match value {
+                        Some(value) => {
+                            buf.put_slice(format!("${}\r\n", value.len()).as_bytes());
+                            buf.put_slice(value);
+                            buf.put_slice(b"\r\n");
+                        }
+                        None => buf.put_slice(b"NIL\r\n"),
+                    }
+                }
+            }
+            Response::Keys { keys, cursor } => {
+                buf.put_slice(format!("KEYS {} {}\r\n", keys.len(), cursor).as_bytes());
+                for key in keys {
+                    buf.put_slice(key.as_bytes());
+                    buf.put_slice(b"\r\n");
+                }
+            }
+            Response::Info(fields) => {
+                buf.put_slice(format!("INFO {}\r\n", fields.len()).as_bytes());
+                for (name, value) in fields {
+                    buf.put_slice(format!("{} {}\r\n", name, value).as_bytes());
+                }
+            }
+            Response::Event(event) => buf.put_slice(format!("EVENT {}\r\n", event).as_bytes()),
+            Response::Queued => buf.put_slice(b"QUEUED\r\n"),
+            Response::Results(responses) => {
+                buf.put_slice(format!("RESULTS {}\r\n", responses.len()).as_bytes());
+                for response in responses {
+                    response.encode_as(version, buf);
+                }
+            }
+        }
+    }
+}
+
+/// What went wrong while parsing a command or response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// The line does not start with a known command
+    UnknownCommand,
+    /// A space was required between arguments
+    ExpectedSpace,
+    /// A key or value was missing
+    ExpectedArgument,
+    /// Extra input where the line should have ended
+    ExpectedLineEnding,
+    /// Input left over after a complete command
+    TrailingInput,
+    /// A key or other text argument that isn't valid UTF-8
+    InvalidUtf8,
+    /// A response line the client does not understand
+    UnknownResponse,
+    /// A well-formed response of the wrong type for the named command
+    UnexpectedResponse(&'static str),
+    /// Any other parser failure
+    Malformed,
+}
+
+impl fmt::Display for ProtocolErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Response::Ok => b"OK\r\n".to_vec(),
-            Response::Value(v) => format!("VALUE {}\r\n", v).into_bytes(),
-            Response::NotFound => b"NOT_FOUND\r\n".to_vec(),
-            Response::Error(e) => format!("ERROR {}\r\n", e).into_bytes(),
+            ProtocolErrorKind::UnknownCommand => write!(f, "unknown command"),
+            ProtocolErrorKind::ExpectedSpace => write!(f, "expected a space"),
+            ProtocolErrorKind::ExpectedArgument => write!(f, "expected an argument"),
+            ProtocolErrorKind::ExpectedLineEnding => write!(f, "expected end of line"),
+            ProtocolErrorKind::TrailingInput => write!(f, "unexpected input after the command"),
+            ProtocolErrorKind::InvalidUtf8 => write!(f, "expected UTF-8 text"),
+            ProtocolErrorKind::UnknownResponse => write!(f, "unknown response"),
+            ProtocolErrorKind::UnexpectedResponse(command) => {
+                write!(f, "unexpected response for {}", command)
+            }
+            ProtocolErrorKind::Malformed => write!(f, "malformed input"),
+        }
+    }
+}
+
+/// A parse failure with the byte offset it occurred at and the input around it
+///
+/// Renders as ``parse error at byte 4 near `SETT my`: unknown command``. The
+/// snippet is escaped, so binary garbage on the wire cannot corrupt an
+/// `ERROR` line or a terminal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+    /// Offset of the failure from the start of the input
+    pub offset: usize,
+    /// Escaped excerpt of at most 32 input bytes around the offset
+    pub snippet: String,
+}
+
+impl ProtocolError {
+    /// Build an error for `input` failing at `offset`
+    pub fn new(kind: ProtocolErrorKind, input: &[u8], offset: usize) -> Self {
+        Self {
+            kind,
+            offset,
+            snippet: snippet(input, offset),
+        }
+    }
+    
+    /// Translate a nom error back into a position within `input`
+    fn from_nom(input: &[u8], err: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        let err = match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+            nom::Err::Incomplete(_) => {
+                return Self::new(ProtocolErrorKind::ExpectedLineEnding, input, input.len())
+            }
+        };
+        
+        // nom hands back the unconsumed remainder, which is always a suffix
+        // of the original input
+        let offset = input.len() - err.input.len();
+        let kind = match err.code {
+            ErrorKind::Tag | ErrorKind::TakeWhile1 if offset == 0 => {
+                ProtocolErrorKind::UnknownCommand
+            }
+            ErrorKind::Space => ProtocolErrorKind::ExpectedSpace,
+            ErrorKind::TakeWhile1 => ProtocolErrorKind::ExpectedArgument,
+            ErrorKind::TakeUntil | ErrorKind::CrLf => ProtocolErrorKind::ExpectedLineEnding,
+            ErrorKind::Verify => ProtocolErrorKind::InvalidUtf8,
+            _ => ProtocolErrorKind::Malformed,
+        };
+        Self::new(kind, input, offset)
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ProtocolErrorKind::UnexpectedResponse(_) => {
+                write!(f, "{} near `{}`", self.kind, self.snippet)
+            }
+            _ => write!(
+                f,
+                "parse error at byte {} near `{}`: {}",
+                self.offset, self.snippet, self.kind
+            ),
         }
     }
 }
 
+impl std::error::Error for ProtocolError {}
+
+/// Escape a window of up to [`SNIPPET_LEN`] bytes around `offset`
+///
+/// The trailing line terminator is left out; it is implied by the framing
+/// and would only push useful context out of the window.
+fn snippet(input: &[u8], offset: usize) -> String {
+    let content = input
+        .strip_suffix(b"\r\n")
+        .or_else(|| input.strip_suffix(b"\n"))
+        .unwrap_or(input);
+    let end = (offset.min(content.len()) + SNIPPET_LEN / 2)
+        .max(SNIPPET_LEN)
+        .min(content.len());
+    let start = end.saturating_sub(SNIPPET_LEN);
+    
+    content[start..end]
+        .iter()
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .map(char::from)
+        .collect()
+}
+
+/// Whether `value` has to be sent length-prefixed rather than inline
+///
+/// Command lines must be UTF-8, and an inline value ends at the first line
+/// break, loses surrounding whitespace to trimming, and could be mistaken
+/// for a `$<len>` marker or, in a SET, for a trailing ` EX <seconds>`,
+/// ` NX` or ` XX`.
+pub fn needs_length_prefix(value: &[u8]) -> bool {
+    let Ok(text) = str::from_utf8(value) else {
+        return true;
+    };
+    text.is_empty()
+        || value.contains(&b'\n')
+        || value.contains(&b'\r')
+        || text.starts_with(char::is_whitespace)
+        || text.ends_with(char::is_whitespace)
+        || text.starts_with('$')
+        || split_options(value).0.len() < value.len()
+}
+
+/// Length of the payload that follows `line` on the wire, if any
+///
+/// A `SET <key> $<len> [EX <seconds>] [NX|XX]` command and a `VALUE $<len>` reply
+/// are followed by exactly `len` bytes of value and a CRLF. A
+/// `CAS <key> $<len> $<len>` is followed by both values and an
+/// `MSET <key> $<len> ...` by all of its values, each ending in a CRLF;
+/// every other frame ends with its line. The length does not include the
+/// final CRLF. Fails if a `len` exceeds [`MAX_VALUE_LEN`].
+///
+/// For SET and CAS only the part of the line after the key is examined, and
+/// only if it is short enough to be a marker, so long inline values cost
+/// nothing here. An MSET line is all keys and values, so all of it is.
+pub fn payload_len(line: &[u8]) -> Result<Option<usize>> {
+    let lens = payload_lens(line)?;
+    if lens.is_empty() {
+        return Ok(None);
+    }
+    // Every value but the last ends in its own CRLF
+    Ok(Some(lens.iter().sum::<usize>() + 2 * (lens.len() - 1)))
+}
+
+/// Length of each value that follows `line` on the wire, in order
+///
+/// Empty when the frame ends with its line; see [`payload_len`].
+pub fn payload_lens(line: &[u8]) -> Result<Vec<usize>> {
+    /// Longest marker tail: `$<len> EX <seconds> NX` with some spacing to spare
+    const TAIL_MAX: usize = 64;
+    
+    fn words(tail: &[u8]) -> Vec<&[u8]> {
+        tail.split(|&b| b == b' ').filter(|w| !w.is_empty()).collect()
+    }
+    
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line);
+    let Some(verb_end) = line.iter().position(|&b| b == b' ') else {
+        return Ok(Vec::new());
+    };
+    let (verb, args) = (&line[..verb_end], &line[verb_end + 1..]);
+    let markers = if verb == b"MSET" {
+        // Every second word is a value, and all of them have to be markers
+        let words = words(args);
+        if !words.len().is_multiple_of(2) {
+            return Ok(Vec::new());
+        }
+        words.into_iter().skip(1).step_by(2).collect()
+    } else {
+        let tail = match verb {
+            b"VALUE" => args,
+            b"SET" | b"CAS" | b"APPEND" | b"GETSET" => {
+                // Skip past the key
+                let key_start = args.iter().position(|&b| b != b' ').unwrap_or(args.len());
+                let args = &args[key_start..];
+                let key_end = args.iter().position(|&b| b == b' ').unwrap_or(args.len());
+                &args[key_end..]
+            }
+            _ => return Ok(Vec::new()),
+        };
+        if tail.len() > TAIL_MAX {
+            return Ok(Vec::new());
+        }
+        match (verb, words(tail).as_slice()) {
+            (b"VALUE" | b"SET" | b"APPEND" | b"GETSET", [marker]) => vec![*marker],
+            (b"SET", [marker, options @ ..]) if are_set_options(options) => vec![*marker],
+            (b"CAS", [expected, new]) => vec![*expected, *new],
+            _ => return Ok(Vec::new()),
+        }
+    };
+    
+    let mut lens = Vec::with_capacity(markers.len());
+    for marker in &markers {
+        let digits = match marker.strip_prefix(b"$") {
+            Some(digits) if !digits.is_empty() && digits.iter().all(u8::is_ascii_digit) => digits,
+            _ => return Ok(Vec::new()),
+        };
+        match str::from_utf8(digits).unwrap_or("").parse::<usize>() {
+            Ok(len) if len <= MAX_VALUE_LEN => lens.push(len),
+            _ => {
+                return Err(RustVaultError::InvalidCommand(format!(
+                    "Value length exceeds the {} byte limit",
+                    MAX_VALUE_LEN
+                )))
+            }
+        }
+    }
+    Ok(lens)
+}
+
+/// Whether the words after a length-prefixed SET's marker are its options:
+/// `EX <seconds>` and `NX` or `XX`, each at most once, in either order
+fn are_set_options(words: &[&[u8]]) -> bool {
+    let seconds = |word: &[u8]| word.iter().all(u8::is_ascii_digit);
+    let condition = |word: &[u8]| word == b"NX" || word == b"XX";
+    match words {
+        [b"EX", s] => seconds(s),
+        [c] => condition(c),
+        [b"EX", s, c] | [c, b"EX", s] => seconds(s) && condition(c),
+        _ => false,
+    }
+}
+
+/// Entry count of a `VALUES <n>` reply line
+///
+/// Each of the `n` entries follows as `$<len>\r\n<value>\r\n`, or as
+/// `NIL\r\n` for a missing key.
+pub fn values_header(line: &[u8]) -> Option<usize> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line);
+    str::from_utf8(line.strip_prefix(b"VALUES ")?).ok()?.parse().ok()
+}
+
+/// Response count of a `RESULTS <n>` reply line
+///
+/// Each of the `n` responses follows as a frame of its own, read like any
+/// other. Only SET, DELETE and GET are queued, so today those are single
+/// lines or a length-prefixed `VALUE`.
+pub fn results_header(line: &[u8]) -> Option<usize> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line);
+    str::from_utf8(line.strip_prefix(b"RESULTS ")?).ok()?.parse().ok()
+}
+
+/// Field count of an `INFO <n>` reply line
+///
+/// Each of the `n` fields follows on a line of its own as `<name> <value>`.
+pub fn info_header(line: &[u8]) -> Option<usize> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line);
+    str::from_utf8(line.strip_prefix(b"INFO ")?).ok()?.parse().ok()
+}
+
+/// Key count and cursor of a `KEYS <n> <cursor>` reply line
+///
+/// The `n` keys follow on lines of their own; keys never contain spaces or
+/// line breaks, so each takes exactly one.
+pub fn keys_header(line: &[u8]) -> Option<(usize, u64)> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line);
+    let rest = str::from_utf8(line.strip_prefix(b"KEYS ")?).ok()?;
+    let (n, cursor) = rest.split_once(' ')?;
+    Some((n.parse().ok()?, cursor.parse().ok()?))
+}
+
 /// Parse a complete command from input bytes using zero-copy techniques
+///
+/// `input` must be exactly one frame: anything after the command's line
+/// ending, or after its payload, is an error rather than being ignored.
+/// Keys and other text arguments must be UTF-8; only values may be binary.
 pub fn parse_command(input: &[u8]) -> Result<Command> {
-    let (_, command) = command_parser(input)
-        .map_err(|e| RustVaultError::Protocol(format!("Failed to parse command: {:?}", e)))?;
+    let (rest, command) = command_parser(input)
+        .map_err(|e| RustVaultError::Protocol(ProtocolError::from_nom(input, e)))?;
+    if !rest.is_empty() {
+        let offset = input.len() - rest.len();
+        return Err(ProtocolError::new(ProtocolErrorKind::TrailingInput, input, offset).into());
+    }
     Ok(command)
 }
 
-/// Main command parser using nom combinators
+/// [`parse_command`] for a frame the caller owns
+///
+/// A length-prefixed SET keeps its value in `frame`'s allocation, moved to
+/// the front of it, rather than copying it out, so a large value is held
+/// once. Any other frame is parsed as [`parse_command`] parses it.
+pub fn parse_command_owned(mut frame: Vec<u8>) -> Result<Command> {
+    // Only a frame that is exactly the header, the value and a CRLF
+    let header = preceded(tag(b"SET"), set_header)(&frame)
+        .ok()
+        .filter(|(rest, (_, len, _))| {
+            rest.len().checked_sub(2) == usize::try_from(*len).ok() && rest.ends_with(b"\r\n")
+        })
+        .map(|(rest, header)| (frame.len() - rest.len(), header));
+    let Some((start, (key, _, options))) = header else {
+        return parse_command(&frame);
+    };
+    frame.truncate(frame.len() - 2);
+    frame.drain(..start);
+    frame.shrink_to_fit();
+    Ok(options.command(key, frame))
+}
+
+/// Main command parser: dispatch on the verb, then parse its arguments
+///
+/// Once the verb is recognised the rest of the line is parsed under `cut`,
+/// so errors point at the argument that is wrong instead of backtracking to
+/// the start of the line.
 fn command_parser(input: &[u8]) -> IResult<&[u8], Command> {
-    terminated(
-        alt((set_command, get_command, delete_command)),
-        alt((tag(b"\r\n"), tag(b"\n"))),
-    )(input)
+    let (rest, verb) = take_while1(|c: u8| c.is_ascii_alphabetic())(input)?;
+    let (rest, command) = match verb {
+        b"SET" => cut(set_command)(rest)?,
+        b"GET" => cut(get_command)(rest)?,
+        b"EXISTS" => cut(map(preceded(space1, text), |key| Command::Exists { key }))(rest)?,
+        b"STAT" => cut(map(preceded(space1, text), |key| Command::Stat { key }))(rest)?,
+        b"DELETE" => cut(delete_command)(rest)?,
+        b"DELPAT" => cut(map(preceded(space1, text), |pattern| Command::DeletePattern { pattern }))(rest)?,
+        b"EXPIRE" => cut(expire_command)(rest)?,
+        b"PEXPIREAT" => cut(expire_at_command)(rest)?,
+        b"SHRINK" => (rest, Command::Shrink),
+        b"COMPACT" => (rest, Command::Compact),
+        b"WALSTATS" => (rest, Command::WalStats),
+        b"INFO" => (rest, Command::Info),
+        b"PING" => (rest, Command::Ping),
+        b"READY" => (rest, Command::Ready),
+        b"FLUSHALL" => (rest, Command::FlushAll),
+        b"REPLICATE" => (rest, Command::Replicate),
+        b"MULTI" => (rest, Command::Multi),
+        b"EXEC" => (rest, Command::Exec),
+        b"DISCARD" => (rest, Command::Discard),
+        b"WATCH" => cut(map(many1(preceded(space1, text)), |keys| Command::Watch { keys }))(rest)?,
+        b"SELECT" => cut(map(preceded(space1, text), |namespace| Command::Select { namespace }))(rest)?,
+        b"FLUSHDB" => cut(map(opt(preceded(space1, text)), |namespace| Command::FlushDb { namespace }))(rest)?,
+        b"DBSIZE" => cut(map(opt(preceded(space1, text)), |namespace| Command::DbSize { namespace }))(rest)?,
+        b"COMMAND" => cut(command_info_command)(rest)?,
+        b"CONFIG" => cut(config_command)(rest)?,
+        b"BACKUP" => cut(map(preceded(space1, text), |path| Command::Backup { path }))(rest)?,
+        b"RESTORE" => cut(restore_command)(rest)?,
+        b"MAINTENANCE" => cut(map(tuple((space1, tag(b"STATUS"))), |_| Command::MaintenanceStatus))(rest)?,
+        b"STATS" => cut(map(tuple((space1, tag(b"RESET"))), |_| Command::StatsReset))(rest)?,
+        b"SLOWLOG" => cut(slowlog_command)(rest)?,
+        b"CHECKSUM" => cut(checksum_command)(rest)?,
+        b"SCAN" => cut(scan_command)(rest)?,
+        b"CAS" => cut(cas_command)(rest)?,
+        b"LOCK" => cut(lock_command)(rest)?,
+        b"UNLOCK" => cut(map(tuple((space1, text, space1, text)), |(_, key, _, token)| Command::Unlock { key, token }))(rest)?,
+        b"MSET" => cut(mset_command)(rest)?,
+        b"MGET" => cut(map(many0(preceded(space1, text)), |keys| Command::MGet { keys }))(rest)?,
+        b"INCR" => cut(map(counter_args, |(key, delta)| Command::Incr { key, delta }))(rest)?,
+        b"DECR" => cut(map(counter_args, |(key, delta)| Command::Decr { key, delta }))(rest)?,
+        b"APPEND" => cut(map(key_and_value, |(key, value)| Command::Append { key, value }))(rest)?,
+        b"STRLEN" => cut(map(preceded(space1, text), |key| Command::Strlen { key }))(rest)?,
+        b"GETSET" => cut(map(key_and_value, |(key, value)| Command::GetSet { key, value }))(rest)?,
+        b"GETDEL" => cut(map(preceded(space1, text), |key| Command::GetDel { key }))(rest)?,
+        b"AUTH" => cut(map(preceded(space1, text), |token| Command::Auth { token }))(rest)?,
+        b"HELLO" => cut(hello_command)(rest)?,
+        b"SUBSCRIBE" => cut(map(preceded(space1, text), |pattern| Command::Subscribe { pattern }))(rest)?,
+        _ => {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                input,
+                ErrorKind::Tag,
+            )))
+        }
+    };
+    let (rest, _) = cut(line_ending)(rest)?;
+    Ok((rest, command))
 }
 
-/// Parse SET command: SET <key> <value>
+/// Parse SET arguments, with the value length-prefixed or inline
 fn set_command(input: &[u8]) -> IResult<&[u8], Command> {
+    alt((set_length_prefixed, set_inline))(input)
+}
+
+/// Parse a length-prefixed SET: SET <key> $<len> [EX <seconds>] [NX|XX]\r\n<value>
+///
+/// The value is taken byte for byte, spaces and line breaks included; the
+/// CRLF ending the frame follows it.
+fn set_length_prefixed(input: &[u8]) -> IResult<&[u8], Command> {
+    let (rest, (key, len, options)) = set_header(input)?;
+    let (rest, value_bytes) = cut(take(len as usize))(rest)?;
+    Ok((rest, options.command(key, value_bytes.to_vec())))
+}
+
+/// Parse the line of a length-prefixed SET after the verb into its key,
+/// value length and options
+fn set_header(input: &[u8]) -> IResult<&[u8], (String, u64, SetOptions)> {
     map(
-        tuple((
-            tag(b"SET"),
-            space1,
-            take_while1(|c| c != b' ' && c != b'\r' && c != b'\n'),
+        tuple((space1, text, space1, tag(b"$"), number, set_options, line_ending)),
+        |(_, key, _, _, len, options, _)| (key, len, options),
+    )(input)
+}
+
+/// What may follow a SET's value
+#[derive(Debug, Default, Clone, Copy)]
+struct SetOptions {
+    ttl: Option<u64>,
+    condition: Option<SetCondition>,
+}
+
+impl SetOptions {
+    fn command(self, key: String, value: Vec<u8>) -> Command {
+        let condition = self.condition;
+        match self.ttl {
+            Some(seconds) => Command::SetEx { key, value, seconds, condition },
+            None => Command::Set { key, value, condition },
+        }
+    }
+}
+
+/// Parse ` EX <seconds>` and ` NX` or ` XX`, each at most once, in either
+/// order
+fn set_options(input: &[u8]) -> IResult<&[u8], SetOptions> {
+    let ttl = || preceded(tuple((space1, tag(b"EX"), space1)), number);
+    let condition = || {
+        preceded(
             space1,
-            take_until("\r\n"),
-        )),
-        |(_, _, key_bytes, _, value_bytes)| {
-            let key = str::from_utf8(key_bytes).unwrap_or("").to_string();
-            let value = str::from_utf8(value_bytes).unwrap_or("").to_string();
-            Command::Set { key, value }
+            alt((
+                map(tag(b"NX"), |_| SetCondition::IfAbsent),
+                map(tag(b"XX"), |_| SetCondition::IfPresent),
+            )),
+        )
+    };
+    alt((
+        map(tuple((ttl(), opt(condition()))), |(ttl, condition)| SetOptions { ttl: Some(ttl), condition }),
+        map(tuple((condition(), opt(ttl()))), |(condition, ttl)| SetOptions { ttl, condition: Some(condition) }),
+        success(SetOptions::default()),
+    ))(input)
+}
+
+/// Parse an inline SET: SET <key> <value> [EX <seconds>] [NX|XX]
+fn set_inline(input: &[u8]) -> IResult<&[u8], Command> {
+    map(
+        tuple((space1, text, space1, take_until("\r\n"))),
+        |(_, key, _, value_bytes)| {
+            let (value_bytes, options) = split_options(value_bytes);
+            options.command(key, value_bytes.to_vec())
         },
     )(input)
 }
 
-/// Parse GET command: GET <key>
-fn get_command(input: &[u8]) -> IResult<&[u8], Command> {
+/// Split the options an inline SET value ends with off it, as
+/// [`set_options`] would parse them
+///
+/// A value that itself ends in one of them has to be sent length-prefixed.
+fn split_options(value: &[u8]) -> (&[u8], SetOptions) {
+    let mut options = SetOptions::default();
+    let mut value = value;
+    loop {
+        if options.ttl.is_none() {
+            if let (head, Some(seconds)) = split_ttl(value) {
+                (value, options.ttl) = (head, Some(seconds));
+                continue;
+            }
+        }
+        if options.condition.is_none() {
+            let flags = [(&b" NX"[..], SetCondition::IfAbsent), (b" XX", SetCondition::IfPresent)];
+            if let Some((head, condition)) =
+                flags.into_iter().find_map(|(flag, condition)| Some((value.strip_suffix(flag)?, condition)))
+            {
+                (value, options.condition) = (head, Some(condition));
+                continue;
+            }
+        }
+        return (value, options);
+    }
+}
+
+/// Split a trailing ` EX <seconds>` off a SET value
+///
+/// The value runs to the end of the line, so the option can only be
+/// recognised at the end. A value that itself ends in ` EX <digits>` has to
+/// be sent length-prefixed.
+fn split_ttl(value: &[u8]) -> (&[u8], Option<u64>) {
+    // Work back from the end, so long values aren't scanned
+    let digit_count = value.iter().rev().take_while(|b| b.is_ascii_digit()).count();
+    let (head, digits) = value.split_at(value.len() - digit_count);
+    let Some(at) = head.strip_suffix(b" EX ").map(<[u8]>::len) else {
+        return (value, None);
+    };
+    if digits.is_empty() {
+        return (value, None);
+    }
+    match str::from_utf8(digits).unwrap_or("").parse() {
+        Ok(seconds) => (&value[..at], Some(seconds)),
+        Err(_) => (value, None),
+    }
+}
+
+/// Parse MSET arguments, with every value length-prefixed or every value
+/// inline
+fn mset_command(input: &[u8]) -> IResult<&[u8], Command> {
+    alt((mset_length_prefixed, mset_inline))(input)
+}
+
+/// Parse a length-prefixed MSET:
+/// MSET <key> $<len> [<key> $<len> ...]\r\n<value>\r\n...
+///
+/// The values follow in the order of their keys, each taken byte for byte;
+/// the CRLF after the last one ends the frame.
+fn mset_length_prefixed(input: &[u8]) -> IResult<&[u8], Command> {
+    let (mut rest, (markers, _)) =
+        tuple((many1(tuple((space1, text, space1, tag(b"$"), number))), line_ending))(input)?;
+    let mut pairs = Vec::with_capacity(markers.len());
+    for (i, (_, key, _, _, len)) in markers.into_iter().enumerate() {
+        if i > 0 {
+            rest = cut(tag(b"\r\n"))(rest)?.0;
+        }
+        let (after, value) = cut(take(len as usize))(rest)?;
+        rest = after;
+        pairs.push((key, value.to_vec()));
+    }
+    Ok((rest, Command::MSet { pairs }))
+}
+
+/// Parse an inline MSET: MSET [<key> <value> ...], values without spaces
+fn mset_inline(input: &[u8]) -> IResult<&[u8], Command> {
+    map(many0(tuple((space1, text, space1, word))), |pairs| Command::MSet {
+        pairs: pairs.into_iter().map(|(_, key, _, value)| (key, value.to_vec())).collect(),
+    })(input)
+}
+
+/// Parse CAS arguments, with both values length-prefixed or both inline
+fn cas_command(input: &[u8]) -> IResult<&[u8], Command> {
+    alt((cas_length_prefixed, cas_inline))(input)
+}
+
+/// Parse a length-prefixed CAS: CAS <key> $<len> $<len>\r\n<expected>\r\n<new>
+fn cas_length_prefixed(input: &[u8]) -> IResult<&[u8], Command> {
+    let (rest, (_, key, _, _, expected_len, _, _, new_len, _)) = tuple((
+        space1,
+        text,
+        space1,
+        tag(b"$"),
+        number,
+        space1,
+        tag(b"$"),
+        number,
+        line_ending,
+    ))(input)?;
+    let (rest, (expected, _, new)) =
+        cut(tuple((take(expected_len as usize), tag(b"\r\n"), take(new_len as usize))))(rest)?;
+    Ok((rest, Command::Cas { key, expected: expected.to_vec(), new: new.to_vec() }))
+}
+
+/// Parse an inline CAS: CAS <key> <expected> <new>, values without spaces
+fn cas_inline(input: &[u8]) -> IResult<&[u8], Command> {
     map(
-        tuple((
-            tag(b"GET"),
-            space1,
-            take_while1(|c| c != b' ' && c != b'\r' && c != b'\n'),
-        )),
-        |(_, _, key_bytes)| {
-            let key = str::from_utf8(key_bytes).unwrap_or("").to_string();
-            Command::Get { key }
-        },
+        tuple((space1, text, space1, word, space1, word)),
+        |(_, key, _, expected, _, new)| Command::Cas { key, expected: expected.to_vec(), new: new.to_vec() },
     )(input)
 }
 
-/// Parse DELETE command: DELETE <key>
+/// Parse GET arguments: GET <key>
+fn get_command(input: &[u8]) -> IResult<&[u8], Command> {
+    map(preceded(space1, text), |key| Command::Get { key })(input)
+}
+
+/// Parse DELETE arguments: DELETE <key>
 fn delete_command(input: &[u8]) -> IResult<&[u8], Command> {
+    map(preceded(space1, text), |key| Command::Delete { key })(input)
+}
+
+/// Parse EXPIRE arguments: EXPIRE <key> <seconds>
+fn expire_command(input: &[u8]) -> IResult<&[u8], Command> {
+    map(tuple((space1, text, space1, number)), |(_, key, _, seconds)| Command::Expire { key, seconds })(input)
+}
+
+/// Parse LOCK arguments: LOCK <key> <token> <ttl-ms>
+fn lock_command(input: &[u8]) -> IResult<&[u8], Command> {
+    map(tuple((space1, text, space1, text, space1, number)), |(_, key, _, token, _, ttl_ms)| {
+        Command::Lock { key, token, ttl_ms }
+    })(input)
+}
+
+/// Parse PEXPIREAT arguments: PEXPIREAT <key> <unix-millis>
+fn expire_at_command(input: &[u8]) -> IResult<&[u8], Command> {
+    map(tuple((space1, text, space1, number)), |(_, key, _, unix_millis)| {
+        Command::ExpireAt { key, unix_millis }
+    })(input)
+}
+
+/// Parse the `<key> <value>` of APPEND and GETSET, with the value
+/// length-prefixed or, as for SET, running to the end of the line
+fn key_and_value(input: &[u8]) -> IResult<&[u8], (String, Vec<u8>)> {
+    let length_prefixed = |input| {
+        let (rest, (_, key, _, _, len, _)) = tuple((space1, text, space1, tag(b"$"), number, line_ending))(input)?;
+        let (rest, value) = cut(take(len as usize))(rest)?;
+        Ok((rest, (key, value.to_vec())))
+    };
+    let inline = map(tuple((space1, text, space1, take_until("\r\n"))), |(_, key, _, value): (_, _, _, &[u8])| {
+        (key, value.to_vec())
+    });
+    alt((length_prefixed, inline))(input)
+}
+
+/// Parse INCR and DECR arguments: <key> [delta], the delta defaulting to 1
+fn counter_args(input: &[u8]) -> IResult<&[u8], (String, i64)> {
+    map(tuple((space1, text, opt(preceded(space1, signed)))), |(_, key, delta)| {
+        (key, delta.unwrap_or(1))
+    })(input)
+}
+
+/// A decimal integer with an optional leading minus
+fn signed(input: &[u8]) -> IResult<&[u8], i64> {
+    map_res(recognize(tuple((opt(tag(b"-")), digit1))), |digits: &[u8]| {
+        str::from_utf8(digits).unwrap_or("").parse::<i64>()
+    })(input)
+}
+
+/// An unsigned decimal integer
+fn number(input: &[u8]) -> IResult<&[u8], u64> {
+    map_res(digit1, |digits: &[u8]| str::from_utf8(digits).unwrap_or("").parse::<u64>())(input)
+}
+
+/// Parse SLOWLOG arguments: GET [n] | RESET
+fn slowlog_command(input: &[u8]) -> IResult<&[u8], Command> {
+    let get = map(preceded(tuple((space1, tag(b"GET"))), opt(preceded(space1, number))), |count| {
+        Command::SlowLogGet { count: count.map(|count| count as usize) }
+    });
+    let reset = map(tuple((space1, tag(b"RESET"))), |_| Command::SlowLogReset);
+    alt((get, reset))(input)
+}
+
+/// Parse COMMAND arguments: COMMAND INFO <name>
+fn command_info_command(input: &[u8]) -> IResult<&[u8], Command> {
+    map(tuple((space1, tag(b"INFO"), space1, text)), |(_, _, _, name)| Command::CommandInfo { name })(input)
+}
+
+/// Parse CONFIG arguments: GET <key> | SET <key> <value>
+fn config_command(input: &[u8]) -> IResult<&[u8], Command> {
+    let get = map(tuple((tag(b"GET"), space1, text)), |(_, _, key)| Command::Config {
+        action: ConfigAction::Get,
+        key,
+    });
+    let set = map(tuple((tag(b"SET"), space1, text, space1, text)), |(_, _, key, _, value)| {
+        Command::Config { action: ConfigAction::Set { value }, key }
+    });
+    preceded(space1, alt((get, set)))(input)
+}
+
+/// A run of bytes up to the next space or line ending
+fn word(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    take_while1(|c| c != b' ' && c != b'\r' && c != b'\n')(input)
+}
+
+/// A [`word`] that has to be UTF-8, such as a key
+///
+/// Invalid UTF-8 fails outright, pointing at the first bad byte, since no
+/// other reading of the line could accept it either.
+fn text(input: &[u8]) -> IResult<&[u8], String> {
+    let (rest, bytes) = word(input)?;
+    match str::from_utf8(bytes) {
+        Ok(text) => Ok((rest, text.to_string())),
+        Err(e) => Err(nom::Err::Failure(nom::error::Error::new(
+            &input[e.valid_up_to()..],
+            ErrorKind::Verify,
+        ))),
+    }
+}
+
+/// Parse CHECKSUM arguments: CHECKSUM [prefix] or CHECKSUM RANGES <n> [prefix]
+///
+/// A lone `RANGES` is read as a prefix, so keys starting with "RANGES" can
+/// still be checksummed.
+fn checksum_command(input: &[u8]) -> IResult<&[u8], Command> {
+    let prefix = |input| map(opt(preceded(space1, text)), Option::unwrap_or_default)(input);
+    let buckets = map_res(digit1, |digits: &[u8]| {
+        str::from_utf8(digits).unwrap_or("").parse::<usize>()
+    });
+    
+    alt((
+        map(
+            tuple((space1, tag(b"RANGES"), space1, buckets, prefix)),
+            |(_, _, _, buckets, prefix)| Command::ChecksumRanges { buckets, prefix },
+        ),
+        map(prefix, |prefix| Command::Checksum { prefix }),
+    ))(input)
+}
+
+/// Parse SCAN arguments: SCAN <cursor> <count> [prefix]
+fn scan_command(input: &[u8]) -> IResult<&[u8], Command> {
+    let count = map_res(digit1, |digits: &[u8]| {
+        str::from_utf8(digits).unwrap_or("").parse::<usize>()
+    });
     map(
-        tuple((
-            tag(b"DELETE"),
-            space1,
-            take_while1(|c| c != b' ' && c != b'\r' && c != b'\n'),
-        )),
-        |(_, _, key_bytes)| {
-            let key = str::from_utf8(key_bytes).unwrap_or("").to_string();
-            Command::Delete { key }
-        },
+        tuple((space1, number, space1, count, opt(preceded(space1, text)))),
+        |(_, cursor, _, count, prefix)| Command::Scan { prefix: prefix.unwrap_or_default(), cursor, count },
     )(input)
 }
 
+/// Parse RESTORE arguments: <path> [MERGE]
+fn restore_command(input: &[u8]) -> IResult<&[u8], Command> {
+    map(
+        tuple((preceded(space1, text), opt(preceded(space1, tag(b"MERGE"))))),
+        |(path, merge)| Command::Restore { path, merge: merge.is_some() },
+    )(input)
+}
+
+/// Parse HELLO arguments: HELLO <version>
+fn hello_command(input: &[u8]) -> IResult<&[u8], Command> {
+    let version = map_res(digit1, |digits: &[u8]| {
+        str::from_utf8(digits).unwrap_or("").parse::<u32>()
+    });
+    map(preceded(space1, version), |version| Command::Hello { version })(input)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -118,7 +1532,8 @@ mod tests {
             result,
             Command::Set {
                 key: "mykey".to_string(),
-                value: "myvalue".to_string()
+                value: b"myvalue".to_vec(),
+                condition: None
             }
         );
     }
@@ -135,6 +1550,115 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_subscribe_command() {
+        assert_eq!(
+            parse_command(b"SUBSCRIBE user:*\r\n").unwrap(),
+            Command::Subscribe { pattern: "user:*".to_string() }
+        );
+        assert_eq!(parse_error(b"SUBSCRIBE\r\n").kind, ProtocolErrorKind::ExpectedSpace);
+        assert_eq!(parse_error(b"SUBSCRIBE a b\r\n").kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+    
+    #[test]
+    fn test_parse_transaction_commands() {
+        assert_eq!(parse_command(b"MULTI\r\n").unwrap(), Command::Multi);
+        assert_eq!(parse_command(b"EXEC\r\n").unwrap(), Command::Exec);
+        assert_eq!(parse_command(b"DISCARD\r\n").unwrap(), Command::Discard);
+        assert_eq!(
+            parse_command(b"WATCH a b\r\n").unwrap(),
+            Command::Watch { keys: vec!["a".to_string(), "b".to_string()] }
+        );
+        assert_eq!(parse_error(b"WATCH\r\n").kind, ProtocolErrorKind::ExpectedSpace);
+        assert_eq!(parse_error(b"EXEC now\r\n").kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+    
+    #[test]
+    fn test_parse_namespace_commands() {
+        assert_eq!(
+            parse_command(b"SELECT app\r\n").unwrap(),
+            Command::Select { namespace: "app".to_string() }
+        );
+        assert_eq!(parse_command(b"FLUSHDB\r\n").unwrap(), Command::FlushDb { namespace: None });
+        assert_eq!(
+            parse_command(b"DBSIZE app\r\n").unwrap(),
+            Command::DbSize { namespace: Some("app".to_string()) }
+        );
+        assert_eq!(parse_error(b"SELECT\r\n").kind, ProtocolErrorKind::ExpectedSpace);
+        assert_eq!(parse_error(b"FLUSHDB a b\r\n").kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+    
+    #[test]
+    fn test_results_encoding() {
+        let results = Response::Results(vec![
+            Response::Ok,
+            Response::Value(b"one".to_vec()),
+            Response::NotFound,
+        ]);
+        assert_eq!(results.to_bytes(), b"RESULTS 3\r\nOK\r\nVALUE one\r\nNOT_FOUND\r\n");
+        assert_eq!(results_header(b"RESULTS 3\r\n"), Some(3));
+        assert_eq!(results_header(b"RESULTS\r\n"), None);
+        assert_eq!(Response::Queued.to_bytes(), b"QUEUED\r\n");
+    }
+    
+    #[test]
+    fn test_key_events_round_trip() {
+        let events = [
+            KeyEvent::Set("user:1".to_string()),
+            KeyEvent::Del("user:1".to_string()),
+            KeyEvent::FlushAll,
+            KeyEvent::Lagged(7),
+        ];
+        for event in events {
+            let line = Response::Event(event.clone()).to_bytes();
+            let line = str::from_utf8(&line).unwrap();
+            let body = line.strip_prefix("EVENT ").unwrap().strip_suffix("\r\n").unwrap();
+            assert_eq!(KeyEvent::parse(body), Some(event));
+        }
+        assert_eq!(KeyEvent::parse("SET"), None);
+        assert_eq!(KeyEvent::parse("LAGGED many"), None);
+    }
+    
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"user:*", b"user:"));
+        assert!(!glob_match(b"user:*", b"users:1"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(glob_match(b"*:*:name", b"user:1:name"));
+        assert!(glob_match(b"*b*b", b"abab"));
+        assert!(!glob_match(b"*b*b", b"abba!"));
+        assert!(glob_match(b"exact", b"exact"));
+        assert!(!glob_match(b"exact", b"exactly"));
+        
+        for pattern in [&b"*"[..], b"**", b"?*", b"*?*", b"???"] {
+            assert!(glob_is_wildcard(pattern), "{:?}", pattern);
+        }
+        assert!(!glob_is_wildcard(b"a*"));
+        assert!(!glob_is_wildcard(b"*:?"));
+    }
+    
+    #[test]
+    fn test_parse_exists_command() {
+        assert_eq!(
+            parse_command(b"EXISTS mykey\r\n").unwrap(),
+            Command::Exists { key: "mykey".to_string() }
+        );
+        assert_eq!(Command::Exists { key: String::new() }.kind(), CommandKind::Read);
+        
+        // The whole verb is matched, so neither a longer word nor one
+        // sharing its start is taken for EXISTS
+        assert_eq!(parse_error(b"EXISTSX mykey\r\n").kind, ProtocolErrorKind::UnknownCommand);
+        assert!(matches!(parse_command(b"EXPIRE mykey 5\r\n").unwrap(), Command::Expire { .. }));
+        
+        let err = parse_error(b"EXISTS\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedSpace);
+        let err = parse_error(b"EXISTS a b\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+
     #[test]
     fn test_parse_delete_command() {
         let input = b"DELETE mykey\r\n";
@@ -147,17 +1671,799 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_ttl_commands() {
+        assert_eq!(
+            parse_command(b"SET session abc EX 30\r\n").unwrap(),
+            Command::SetEx { key: "session".to_string(), value: b"abc".to_vec(), seconds: 30, condition: None }
+        );
+        assert_eq!(
+            parse_command(b"SET name Rex EXPRESS\r\n").unwrap(),
+            Command::Set { key: "name".to_string(), value: b"Rex EXPRESS".to_vec(), condition: None }
+        );
+        assert_eq!(
+            parse_command(b"SET k a EX b\r\n").unwrap(),
+            Command::Set { key: "k".to_string(), value: b"a EX b".to_vec(), condition: None }
+        );
+        assert_eq!(
+            parse_command(b"SET k EX 5\r\n").unwrap(),
+            Command::Set { key: "k".to_string(), value: b"EX 5".to_vec(), condition: None }
+        );
+        assert_eq!(
+            parse_command(b"EXPIRE session 30\r\n").unwrap(),
+            Command::Expire { key: "session".to_string(), seconds: 30 }
+        );
+        assert_eq!(
+            parse_command(b"PEXPIREAT session 1700000000000\r\n").unwrap(),
+            Command::ExpireAt { key: "session".to_string(), unix_millis: 1_700_000_000_000 }
+        );
+        
+        assert!(parse_command(b"EXPIRE session\r\n").is_err());
+        assert!(parse_command(b"EXPIRE session -1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_length_prefixed_set() {
+        let set = |value: &str| Command::Set { key: "k".to_string(), value: value.as_bytes().to_vec(), condition: None };
+        
+        assert_eq!(parse_command(b"SET k $11\r\nhello world\r\n").unwrap(), set("hello world"));
+        assert_eq!(parse_command(b"SET k $6\r\na\r\nb\tc\r\n").unwrap(), set("a\r\nb\tc"));
+        assert_eq!(parse_command(b"SET k $0\r\n\r\n").unwrap(), set(""));
+        assert_eq!(parse_command(b"SET k $2\r\n$5\r\n").unwrap(), set("$5"));
+        assert_eq!(
+            parse_command(b"SET k $3 EX 10\r\n a \r\n").unwrap(),
+            Command::SetEx { key: "k".to_string(), value: b" a ".to_vec(), seconds: 10, condition: None }
+        );
+        
+        // Anything that isn't a length marker stays an inline value
+        assert_eq!(parse_command(b"SET k $abc\r\n").unwrap(), set("$abc"));
+        assert_eq!(parse_command(b"SET k $5 x\r\n").unwrap(), set("$5 x"));
+        
+        // The payload must be exactly as long as announced
+        assert!(parse_command(b"SET k $5\r\nhello world\r\n").is_err());
+        assert!(parse_command(b"SET k $20\r\nshort\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_payload_len() {
+        assert_eq!(payload_len(b"SET k $11\r\n").unwrap(), Some(11));
+        assert_eq!(payload_len(b"SET  k  $3 EX 10\n").unwrap(), Some(3));
+        assert_eq!(payload_len(b"VALUE $0\r\n").unwrap(), Some(0));
+        assert_eq!(payload_len(b"SET k $abc\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"SET k $5 x\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"GET $5\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"VALUE hello\r\n").unwrap(), None);
+        assert!(payload_len(b"SET k $99999999999999999999\r\n").is_err());
+        assert_eq!(payload_len(b"APPEND k $4\r\n").unwrap(), Some(4));
+        assert_eq!(payload_len(b"APPEND k $4 EX 10\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"GETSET k $4\r\n").unwrap(), Some(4));
+        assert_eq!(payload_len(b"CAS k $3 $5\r\n").unwrap(), Some(10));
+        assert_eq!(payload_len(b"CAS k $0 $0\r\n").unwrap(), Some(2));
+        assert_eq!(payload_len(b"CAS k $3\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"CAS k old new\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"MSET a $3 b $0 c $4\r\n").unwrap(), Some(11));
+        assert_eq!(payload_lens(b"MSET a $3 b $0 c $4\r\n").unwrap(), vec![3, 0, 4]);
+        assert!(payload_lens(b"GET k\r\n").unwrap().is_empty());
+        assert_eq!(payload_len(b"MSET a $3 b v\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"MSET a $3 b\r\n").unwrap(), None);
+        assert_eq!(payload_len(b"MSET\r\n").unwrap(), None);
+        
+        // Everything the inline form can't carry goes length-prefixed...
+        for value in ["", " lead", "trail\t", "a\r\nb", "$5", "x EX 10"] {
+            assert!(needs_length_prefix(value.as_bytes()), "{:?}", value);
+            let encoded = Response::Value(value.as_bytes().to_vec()).to_bytes();
+            let header_end = encoded.iter().position(|&b| b == b'\n').unwrap() + 1;
+            assert_eq!(payload_len(&encoded[..header_end]).unwrap(), Some(value.len()));
+        }
+        // ...and the rest keeps the old inline encoding
+        for value in ["hello world", "tab\tinside", "a$b"] {
+            assert!(!needs_length_prefix(value.as_bytes()), "{:?}", value);
+        }
+        assert_eq!(Response::Value(b" x".to_vec()).to_bytes(), b"VALUE $2\r\n x\r\n");
+    }
+
+    #[test]
+    fn test_parse_shrink_command() {
+        assert_eq!(parse_command(b"SHRINK\r\n").unwrap(), Command::Shrink);
+        
+        let err = parse_error(b"SHRINK now\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+        assert_eq!(err.offset, 6);
+        
+        assert_eq!(parse_command(b"COMPACT\r\n").unwrap(), Command::Compact);
+        assert_eq!(parse_command(b"WALSTATS\r\n").unwrap(), Command::WalStats);
+        assert_eq!(parse_error(b"COMPACT now\r\n").kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+
+    #[test]
+    fn test_parse_delete_pattern_command() {
+        assert_eq!(
+            parse_command(b"DELPAT user:*\r\n").unwrap(),
+            Command::DeletePattern { pattern: "user:*".to_string() }
+        );
+        assert_eq!(Command::DeletePattern { pattern: "*".to_string() }.kind(), CommandKind::Write);
+        assert_eq!(parse_error(b"DELPAT\r\n").kind, ProtocolErrorKind::ExpectedSpace);
+        assert_eq!(parse_error(b"DELPAT a b\r\n").kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+    
+    #[test]
+    fn test_parse_flush_all_command() {
+        assert_eq!(parse_command(b"FLUSHALL\r\n").unwrap(), Command::FlushAll);
+        assert_eq!(Command::FlushAll.kind(), CommandKind::Write);
+        
+        let err = parse_error(b"FLUSHALL keys\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+        assert_eq!(err.offset, 8);
+    }
+
+    /// One instance of every command variant
+    ///
+    /// The match makes adding a variant without listing it here a compile
+    /// error, so the classification tests below cover every command.
+    fn every_command() -> Vec<Command> {
+        let commands = vec![
+            Command::Set { key: "k".to_string(), value: b"v".to_vec(), condition: None },
+            Command::SetEx { key: "k".to_string(), value: b"v".to_vec(), seconds: 10, condition: None },
+            Command::Get { key: "k".to_string() },
+            Command::Exists { key: "k".to_string() },
+            Command::Stat { key: "k".to_string() },
+            Command::Delete { key: "k".to_string() },
+            Command::DeletePattern { pattern: "user:*".to_string() },
+            Command::Expire { key: "k".to_string(), seconds: 10 },
+            Command::ExpireAt { key: "k".to_string(), unix_millis: 0 },
+            Command::Shrink,
+            Command::Compact,
+            Command::WalStats,
+            Command::CommandInfo { name: "GET".to_string() },
+            Command::MaintenanceStatus,
+            Command::Info,
+            Command::StatsReset,
+            Command::SlowLogGet { count: Some(5) },
+            Command::SlowLogReset,
+            Command::Ping,
+            Command::Ready,
+            Command::Checksum { prefix: String::new() },
+            Command::ChecksumRanges { buckets: 16, prefix: String::new() },
+            Command::Scan { prefix: String::new(), cursor: 0, count: 10 },
+            Command::MSet { pairs: vec![("k".to_string(), b"v".to_vec())] },
+            Command::MGet { keys: vec!["k".to_string()] },
+            Command::Incr { key: "k".to_string(), delta: 1 },
+            Command::Decr { key: "k".to_string(), delta: 1 },
+            Command::Append { key: "k".to_string(), value: b"v".to_vec() },
+            Command::Strlen { key: "k".to_string() },
+            Command::GetSet { key: "k".to_string(), value: b"v".to_vec() },
+            Command::GetDel { key: "k".to_string() },
+            Command::Cas { key: "k".to_string(), expected: b"a".to_vec(), new: b"b".to_vec() },
+            Command::Lock { key: "k".to_string(), token: "t".to_string(), ttl_ms: 1000 },
+            Command::Unlock { key: "k".to_string(), token: "t".to_string() },
+            Command::Auth { token: "secret".to_string() },
+            Command::Hello { version: 2 },
+            Command::FlushAll,
+            Command::Subscribe { pattern: "user:*".to_string() },
+            Command::Replicate,
+            Command::Multi,
+            Command::Exec,
+            Command::Discard,
+            Command::Watch { keys: vec!["k".to_string()] },
+            Command::Select { namespace: "app".to_string() },
+            Command::FlushDb { namespace: None },
+            Command::DbSize { namespace: None },
+            Command::Config { action: ConfigAction::Get, key: "*".to_string() },
+            Command::Backup { path: "/tmp/vault.backup".to_string() },
+            Command::Restore { path: "/tmp/vault.backup".to_string(), merge: false },
+        ];
+        for command in &commands {
+            match command {
+                Command::Set { .. }
+                | Command::SetEx { .. }
+                | Command::Get { .. }
+                | Command::Exists { .. }
+                | Command::Stat { .. }
+                | Command::Delete { .. }
+                | Command::DeletePattern { .. }
+                | Command::Expire { .. }
+                | Command::ExpireAt { .. }
+                | Command::Shrink
+                | Command::Compact
+                | Command::WalStats
+                | Command::CommandInfo { .. }
+                | Command::MaintenanceStatus
+                | Command::Info
+                | Command::StatsReset
+                | Command::SlowLogGet { .. }
+                | Command::SlowLogReset
+                | Command::Ping
+                | Command::Ready
+                | Command::Checksum { .. }
+                | Command::ChecksumRanges { .. }
+                | Command::Scan { .. }
+                | Command::MSet { .. }
+                | Command::MGet { .. }
+                | Command::Incr { .. }
+                | Command::Decr { .. }
+                | Command::Append { .. }
+                | Command::Strlen { .. }
+                | Command::GetSet { .. }
+                | Command::GetDel { .. }
+                | Command::Cas { .. }
+                | Command::Lock { .. }
+                | Command::Unlock { .. }
+                | Command::Auth { .. }
+                | Command::Hello { .. }
+                | Command::FlushAll
+                | Command::Subscribe { .. }
+                | Command::Replicate
+                | Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch { .. }
+                | Command::Select { .. }
+                | Command::FlushDb { .. }
+                | Command::DbSize { .. }
+                | Command::Config { .. }
+                | Command::Backup { .. }
+                | Command::Restore { .. } => {}
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn test_every_command_is_classified() {
+        let commands = every_command();
+        for command in &commands {
+            let spec = command_spec(command.name()).unwrap();
+            assert_eq!(spec.kind, command.kind());
+        }
+        // ...and the table has nothing that isn't a command
+        let mut names: Vec<&str> = commands.iter().map(Command::name).collect();
+        names.dedup();
+        assert_eq!(COMMAND_TABLE.len(), names.len());
+        
+        assert_eq!(Command::Get { key: "k".to_string() }.kind(), CommandKind::Read);
+        assert_eq!(Command::Set { key: "k".to_string(), value: b"v".to_vec(), condition: None }.kind(), CommandKind::Write);
+        assert_eq!(Command::Delete { key: "k".to_string() }.kind(), CommandKind::Write);
+        assert_eq!(Command::Shrink.kind(), CommandKind::Admin);
+        
+        for kind in [CommandKind::Read, CommandKind::Write, CommandKind::Admin] {
+            assert_eq!(kind.to_string().parse::<CommandKind>().unwrap(), kind);
+        }
+    }
+
+    #[test]
+    fn test_parse_backup_and_restore() {
+        assert_eq!(
+            parse_command(b"BACKUP /var/backups/vault.backup\r\n").unwrap(),
+            Command::Backup { path: "/var/backups/vault.backup".to_string() }
+        );
+        assert_eq!(
+            parse_command(b"RESTORE vault.backup\r\n").unwrap(),
+            Command::Restore { path: "vault.backup".to_string(), merge: false }
+        );
+        assert_eq!(
+            parse_command(b"RESTORE vault.backup MERGE\r\n").unwrap(),
+            Command::Restore { path: "vault.backup".to_string(), merge: true }
+        );
+        assert!(parse_command(b"BACKUP\r\n").is_err());
+        assert!(parse_command(b"RESTORE vault.backup OVER\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_parse_command_info() {
+        assert_eq!(
+            parse_command(b"COMMAND INFO get\r\n").unwrap(),
+            Command::CommandInfo { name: "get".to_string() }
+        );
+        assert_eq!(
+            parse_command(b"MAINTENANCE STATUS\r\n").unwrap(),
+            Command::MaintenanceStatus
+        );
+        assert_eq!(parse_command(b"STATS RESET\r\n").unwrap(), Command::StatsReset);
+        assert!(parse_command(b"STATS\r\n").is_err());
+        assert_eq!(parse_command(b"SLOWLOG GET\r\n").unwrap(), Command::SlowLogGet { count: None });
+        assert_eq!(parse_command(b"SLOWLOG GET 25\r\n").unwrap(), Command::SlowLogGet { count: Some(25) });
+        assert_eq!(parse_command(b"SLOWLOG RESET\r\n").unwrap(), Command::SlowLogReset);
+        assert!(parse_command(b"SLOWLOG GET all\r\n").is_err());
+        assert!(parse_command(b"SLOWLOG\r\n").is_err());
+        assert_eq!(
+            parse_command(b"CONFIG SET readonly 1\r\n").unwrap(),
+            Command::Config {
+                action: ConfigAction::Set { value: "1".to_string() },
+                key: "readonly".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_command(b"CONFIG GET *_timeout\r\n").unwrap(),
+            Command::Config { action: ConfigAction::Get, key: "*_timeout".to_string() }
+        );
+        assert!(parse_command(b"CONFIG SET readonly\r\n").is_err());
+        assert!(parse_command(b"CONFIG RESET\r\n").is_err());
+        assert!(command_spec("get").is_some());
+        assert!(command_spec("FROB").is_none());
+        
+        let err = parse_error(b"COMMAND LIST\r\n");
+        assert_eq!(err.offset, 8);
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        let checksum = |prefix: &str| Command::Checksum { prefix: prefix.to_string() };
+        let ranges = |buckets, prefix: &str| Command::ChecksumRanges { buckets, prefix: prefix.to_string() };
+        
+        assert_eq!(parse_command(b"CHECKSUM\r\n").unwrap(), checksum(""));
+        assert_eq!(parse_command(b"CHECKSUM user:\r\n").unwrap(), checksum("user:"));
+        assert_eq!(parse_command(b"CHECKSUM RANGES 16\r\n").unwrap(), ranges(16, ""));
+        assert_eq!(parse_command(b"CHECKSUM RANGES 4 user:\r\n").unwrap(), ranges(4, "user:"));
+        assert_eq!(parse_command(b"CHECKSUM RANGES\r\n").unwrap(), checksum("RANGES"));
+        
+        assert!(parse_command(b"CHECKSUM RANGES x\r\n").is_err());
+        assert!(parse_command(b"CHECKSUM a b\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_parse_scan() {
+        let scan = |cursor, count, prefix: &str| Command::Scan { prefix: prefix.to_string(), cursor, count };
+        
+        assert_eq!(parse_command(b"SCAN 0 10\r\n").unwrap(), scan(0, 10, ""));
+        assert_eq!(parse_command(b"SCAN 42 5 user:\r\n").unwrap(), scan(42, 5, "user:"));
+        assert!(parse_command(b"SCAN 0\r\n").is_err());
+        assert!(parse_command(b"SCAN x 10\r\n").is_err());
+        assert!(parse_command(b"SCAN 0 10 a b\r\n").is_err());
+        
+        let keys = Response::Keys { keys: vec!["a".to_string(), "b".to_string()], cursor: 7 };
+        let encoded = keys.to_bytes();
+        assert_eq!(encoded, b"KEYS 2 7\r\na\r\nb\r\n");
+        assert_eq!(keys_header(&encoded[..10]), Some((2, 7)));
+        assert_eq!(keys_header(b"KEYS 0 0\r\n"), Some((0, 0)));
+        assert_eq!(keys_header(b"KEYS 2\r\n"), None);
+        assert_eq!(keys_header(b"VALUE KEYS 2 7\r\n"), None);
+    }
+    
+    #[test]
+    fn test_parse_info() {
+        assert_eq!(parse_command(b"INFO\r\n").unwrap(), Command::Info);
+        assert!(parse_command(b"INFO all\r\n").is_err());
+        assert_eq!(parse_command(b"PING\r\n").unwrap(), Command::Ping);
+        assert_eq!(parse_command(b"READY\r\n").unwrap(), Command::Ready);
+        assert_eq!(Response::Pong.to_bytes(), b"PONG\r\n");
+        
+        let info = Response::Info(vec![
+            ("keys".to_string(), "3".to_string()),
+            ("cmd_get".to_string(), "10".to_string()),
+        ]);
+        let encoded = info.to_bytes();
+        assert_eq!(encoded, b"INFO 2\r\nkeys 3\r\ncmd_get 10\r\n");
+        assert_eq!(info_header(&encoded[..8]), Some(2));
+        assert_eq!(info_header(b"INFO 0\r\n"), Some(0));
+        assert_eq!(info_header(b"VALUE INFO 2\r\n"), None);
+    }
+    
+    #[test]
+    fn test_hello_and_versioned_encoding() {
+        assert_eq!(parse_command(b"HELLO 2\r\n").unwrap(), Command::Hello { version: 2 });
+        assert!(parse_command(b"HELLO\r\n").is_err());
+        assert!(parse_command(b"HELLO two\r\n").is_err());
+        assert_eq!(Response::Hello(2).to_bytes(), b"HELLO 2\r\n");
+        
+        let value = Response::Value(b"v".to_vec());
+        let encoded = |response: &Response, version| {
+            let mut buf = Vec::new();
+            response.encode_as(version, &mut buf);
+            buf
+        };
+        assert_eq!(value.to_bytes(), b"VALUE v\r\n");
+        assert_eq!(encoded(&value, 1), b"VALUE v\r\n");
+        assert_eq!(encoded(&value, 2), b"VALUE $1\r\nv\r\n");
+        assert_eq!(
+            encoded(&Response::Results(vec![value, Response::Ok]), 2),
+            b"RESULTS 2\r\nVALUE $1\r\nv\r\nOK\r\n"
+        );
+    }
+    
+    #[test]
+    fn test_parse_incr_decr() {
+        let incr = |delta| Command::Incr { key: "hits".to_string(), delta };
+        
+        assert_eq!(parse_command(b"INCR hits\r\n").unwrap(), incr(1));
+        assert_eq!(parse_command(b"INCR hits 10\r\n").unwrap(), incr(10));
+        assert_eq!(parse_command(b"INCR hits -3\r\n").unwrap(), incr(-3));
+        assert_eq!(
+            parse_command(b"DECR hits 2\r\n").unwrap(),
+            Command::Decr { key: "hits".to_string(), delta: 2 }
+        );
+        assert!(parse_command(b"INCR hits x\r\n").is_err());
+        assert!(parse_command(b"INCR hits 99999999999999999999\r\n").is_err());
+        assert!(parse_command(b"INCR\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_parse_append_strlen() {
+        let append = |value: &[u8]| Command::Append { key: "log".to_string(), value: value.to_vec() };
+        
+        assert_eq!(parse_command(b"APPEND log line one\r\n").unwrap(), append(b"line one"));
+        assert_eq!(parse_command(b"APPEND log $6\r\n\r\nend \r\n").unwrap(), append(b"\r\nend "));
+        assert_eq!(parse_command(b"APPEND log $0\r\n\r\n").unwrap(), append(b""));
+        assert_eq!(parse_command(b"STRLEN log\r\n").unwrap(), Command::Strlen { key: "log".to_string() });
+        assert!(parse_command(b"APPEND log\r\n").is_err());
+        assert!(parse_command(b"APPEND log $9\r\nshort\r\n").is_err());
+        assert!(parse_command(b"STRLEN\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_parse_getset_getdel() {
+        let getset = |value: &[u8]| Command::GetSet { key: "k".to_string(), value: value.to_vec() };
+        
+        assert_eq!(parse_command(b"GETSET k new value\r\n").unwrap(), getset(b"new value"));
+        assert_eq!(parse_command(b"GETSET k $3\r\na\r\n\r\n").unwrap(), getset(b"a\r\n"));
+        assert_eq!(parse_command(b"GETDEL k\r\n").unwrap(), Command::GetDel { key: "k".to_string() });
+        assert!(parse_command(b"GETSET k\r\n").is_err());
+        assert!(parse_command(b"GETDEL\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_parse_set_conditions() {
+        let set = |value: &[u8], condition| Command::Set { key: "k".to_string(), value: value.to_vec(), condition };
+        let setex = |value: &[u8], condition| Command::SetEx {
+            key: "k".to_string(),
+            value: value.to_vec(),
+            seconds: 30,
+            condition,
+        };
+        let (nx, xx) = (Some(SetCondition::IfAbsent), Some(SetCondition::IfPresent));
+        
+        assert_eq!(parse_command(b"SET k v NX\r\n").unwrap(), set(b"v", nx));
+        assert_eq!(parse_command(b"SET k a b XX\r\n").unwrap(), set(b"a b", xx));
+        assert_eq!(parse_command(b"SET k v EX 30 NX\r\n").unwrap(), setex(b"v", nx));
+        assert_eq!(parse_command(b"SET k v XX EX 30\r\n").unwrap(), setex(b"v", xx));
+        assert_eq!(parse_command(b"SET k $1 NX\r\nv\r\n").unwrap(), set(b"v", nx));
+        assert_eq!(parse_command(b"SET k $1 EX 30 XX\r\nv\r\n").unwrap(), setex(b"v", xx));
+        assert_eq!(parse_command(b"SET k $1 NX EX 30\r\nv\r\n").unwrap(), setex(b"v", nx));
+        assert_eq!(parse_command_owned(b"SET k $3 NX\r\na b\r\n".to_vec()).unwrap(), set(b"a b", nx));
+        assert_eq!(payload_lens(b"SET k $3 XX EX 5\r\n").unwrap(), [3]);
+        
+        // Without a flag, or with anything else where it would be, the
+        // words are part of the value
+        assert_eq!(parse_command(b"SET k v\r\n").unwrap(), set(b"v", None));
+        assert_eq!(parse_command(b"SET k NX\r\n").unwrap(), set(b"NX", None));
+        assert_eq!(parse_command(b"SET k v nx\r\n").unwrap(), set(b"v nx", None));
+        assert_eq!(parse_command(b"SET k v NXX\r\n").unwrap(), set(b"v NXX", None));
+        assert_eq!(parse_command(b"SET k v NX XX\r\n").unwrap(), set(b"v NX", xx));
+        assert_eq!(parse_command(b"SET k $1 NX NX\r\n").unwrap(), set(b"$1 NX", nx));
+        assert!(needs_length_prefix(b"a NX") && needs_length_prefix(b"a EX 1 XX"));
+        assert!(!needs_length_prefix(b"a NXT"));
+        
+        // Conditions reach the WAL's JSON only when set
+        let json = serde_json::to_string(&set(b"v", nx)).unwrap();
+        assert_eq!(json, r#"{"Set":{"key":"k","value":"v","condition":"IfAbsent"}}"#);
+        assert_eq!(serde_json::from_str::<Command>(r#"{"Set":{"key":"k","value":"v"}}"#).unwrap(), set(b"v", None));
+    }
+    
+    #[test]
+    fn test_parse_lock_unlock() {
+        assert_eq!(
+            parse_command(b"LOCK jobs:1 worker-7 30000\r\n").unwrap(),
+            Command::Lock { key: "jobs:1".to_string(), token: "worker-7".to_string(), ttl_ms: 30000 }
+        );
+        assert_eq!(
+            parse_command(b"UNLOCK jobs:1 worker-7\r\n").unwrap(),
+            Command::Unlock { key: "jobs:1".to_string(), token: "worker-7".to_string() }
+        );
+        assert!(parse_command(b"LOCK jobs:1 worker-7\r\n").is_err());
+        assert!(parse_command(b"LOCK jobs:1 worker-7 soon\r\n").is_err());
+        assert!(parse_command(b"UNLOCK jobs:1\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_parse_auth() {
+        assert_eq!(
+            parse_command(b"AUTH s3cr3t\r\n").unwrap(),
+            Command::Auth { token: "s3cr3t".to_string() }
+        );
+        assert_eq!(Command::Auth { token: String::new() }.kind(), CommandKind::Admin);
+        
+        let err = parse_error(b"AUTH\r\n");
+        assert_eq!(err.offset, 4);
+        let err = parse_error(b"AUTH a b\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+    
+    #[test]
+    fn test_parse_mset_mget() {
+        let pairs = |pairs: &[(&str, &[u8])]| Command::MSet {
+            pairs: pairs.iter().map(|(k, v)| (k.to_string(), v.to_vec())).collect(),
+        };
+        
+        assert_eq!(parse_command(b"MSET a 1 b 2\r\n").unwrap(), pairs(&[("a", b"1"), ("b", b"2")]));
+        assert_eq!(
+            parse_command(b"MSET a $3 b $0 c $2\r\nx y\r\n\r\n\r\n\r\n").unwrap(),
+            pairs(&[("a", b"x y"), ("b", b""), ("c", b"\r\n")])
+        );
+        assert_eq!(parse_command(b"MSET\r\n").unwrap(), pairs(&[]));
+        assert!(parse_command(b"MSET a\r\n").is_err());
+        assert!(parse_command(b"MSET a 1 b\r\n").is_err());
+        // Every announced value must be there
+        assert!(parse_command(b"MSET a $3 b $1\r\nxyz\r\n").is_err());
+        
+        let keys = |keys: &[&str]| Command::MGet { keys: keys.iter().map(|k| k.to_string()).collect() };
+        assert_eq!(parse_command(b"MGET a b c\r\n").unwrap(), keys(&["a", "b", "c"]));
+        assert_eq!(parse_command(b"MGET\r\n").unwrap(), keys(&[]));
+        
+        let values = Response::Values(vec![Some(b"x\r\ny".to_vec()), None, Some(Vec::new())]);
+        assert_eq!(values.to_bytes(), b"VALUES 3\r\n$4\r\nx\r\ny\r\nNIL\r\n$0\r\n\r\n");
+        assert_eq!(values_header(b"VALUES 3\r\n"), Some(3));
+        assert_eq!(values_header(b"VALUES x\r\n"), None);
+        assert_eq!(Response::Values(Vec::new()).to_bytes(), b"VALUES 0\r\n");
+    }
+    
+    #[test]
+    fn test_parse_cas() {
+        let cas = |expected: &[u8], new: &[u8]| Command::Cas {
+            key: "k".to_string(),
+            expected: expected.to_vec(),
+            new: new.to_vec(),
+        };
+        
+        assert_eq!(parse_command(b"CAS k old new\r\n").unwrap(), cas(b"old", b"new"));
+        assert_eq!(parse_command(b"CAS k $3 $6\r\na b\r\nx\r\ny\xffz\r\n").unwrap(), cas(b"a b", b"x\r\ny\xffz"));
+        assert_eq!(parse_command(b"CAS k $0 $1\r\n\r\nv\r\n").unwrap(), cas(b"", b"v"));
+        assert!(parse_command(b"CAS k old\r\n").is_err());
+        assert!(parse_command(b"CAS k old new extra\r\n").is_err());
+        // The values must be exactly as long as announced
+        assert!(parse_command(b"CAS k $3 $1\r\nab\r\nv\r\n").is_err());
+        
+        assert_eq!(Response::Conflict.to_bytes(), b"CONFLICT\r\n");
+    }
+
     #[test]
     fn test_response_serialization() {
         assert_eq!(Response::Ok.to_bytes(), b"OK\r\n");
         assert_eq!(
-            Response::Value("test".to_string()).to_bytes(),
+            Response::Value(b"test".to_vec()).to_bytes(),
             b"VALUE test\r\n"
         );
         assert_eq!(Response::NotFound.to_bytes(), b"NOT_FOUND\r\n");
+        assert_eq!(Response::Integer(-42).to_bytes(), b"INT -42\r\n");
+        assert_eq!(
+            Response::Stat { version: 3, created_at: 1000, updated_at: 2000 }.to_bytes(),
+            b"STAT 3 1000 2000\r\n"
+        );
+        assert_eq!(
+            Response::Compacted { before: 10485760, after: 524288, keys: 1200 }.to_bytes(),
+            b"COMPACTED 10485760 -> 524288 (1200 entries)\r\n"
+        );
         assert_eq!(
             Response::Error("test error".to_string()).to_bytes(),
             b"ERROR test error\r\n"
         );
+        assert_eq!(
+            Response::error(ErrorCode::TooLarge, "key too large (max 4)").to_bytes(),
+            b"ERROR ERR_TOO_LARGE key too large (max 4)\r\n"
+        );
+    }
+    
+    #[test]
+    fn test_error_codes() {
+        for &(code, name) in ERROR_CODES {
+            assert_eq!(code.as_str(), name);
+            assert_eq!(name.parse::<ErrorCode>().ok(), Some(code));
+        }
+        assert_eq!(
+            ErrorCode::split("ERR_PARSE Parse error: bad"),
+            (Some(ErrorCode::Parse), "Parse error: bad")
+        );
+        // Replies from servers that predate codes keep their whole text
+        assert_eq!(ErrorCode::split("key too large (max 4)"), (None, "key too large (max 4)"));
+        assert_eq!(ErrorCode::split("ERR_UNHEARD_OF what"), (None, "ERR_UNHEARD_OF what"));
+        assert_eq!(Response::error(ErrorCode::Busy, "busy").error_code(), Some(ErrorCode::Busy));
+        assert_eq!(Response::Error("busy".to_string()).error_code(), None);
+    }
+    
+    #[test]
+    fn test_binary_values() {
+        let value = vec![0xff, 0x00, b'\r', b'\n', b'a'];
+        assert!(needs_length_prefix(&value));
+        assert_eq!(Response::Value(value.clone()).to_bytes(), b"VALUE $5\r\n\xff\x00\r\na\r\n");
+        
+        let mut input = b"SET k $5\r\n".to_vec();
+        input.extend_from_slice(&value);
+        input.extend_from_slice(b"\r\n");
+        let command = Command::Set { key: "k".to_string(), value: value.clone(), condition: None };
+        assert_eq!(parse_command(&input).unwrap(), command);
+        
+        // WAL entries keep text values as strings and fall back to a byte array
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(json, r#"{"Set":{"key":"k","value":[255,0,13,10,97]}}"#);
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), command);
+        let text = Command::Set { key: "k".to_string(), value: b"v".to_vec(), condition: None };
+        let json = serde_json::to_string(&text).unwrap();
+        assert_eq!(json, r#"{"Set":{"key":"k","value":"v"}}"#);
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), text);
+    }
+    
+    fn parse_error(input: &[u8]) -> ProtocolError {
+        match parse_command(input) {
+            Err(RustVaultError::Protocol(e)) => e,
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_unknown_command_reports_start_of_line() {
+        let err = parse_error(b"SETT mykey myvalue\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::UnknownCommand);
+        assert_eq!(err.offset, 0);
+        assert_eq!(err.snippet, "SETT mykey myvalue");
+        assert_eq!(
+            err.to_string(),
+            "parse error at byte 0 near `SETT mykey myvalue`: unknown command"
+        );
+    }
+    
+    #[test]
+    fn test_error_offsets_point_at_bad_argument() {
+        let err = parse_error(b"SET mykey\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedSpace);
+        assert_eq!(err.offset, 9);
+        
+        let err = parse_error(b"GET\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedSpace);
+        assert_eq!(err.offset, 3);
+        
+        let err = parse_error(b"GET a b\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+        assert_eq!(err.offset, 5);
+        
+        let err = parse_error(b"SET key value");
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+        assert_eq!(err.offset, 8);
+    }
+    
+    #[test]
+    fn test_binary_garbage_is_escaped() {
+        let err = parse_error(b"\xff\x00\x1b[2J\r\n");
+        assert_eq!(err.kind, ProtocolErrorKind::UnknownCommand);
+        assert_eq!(err.offset, 0);
+        assert_eq!(err.snippet, "\\xff\\x00\\x1b[2J");
+        assert!(err.to_string().is_ascii());
+        
+        let err = parse_error(b"");
+        assert_eq!(err.kind, ProtocolErrorKind::UnknownCommand);
+        assert_eq!(err.snippet, "");
+    }
+    
+    #[test]
+    fn test_text_arguments_must_be_utf8() {
+        for input in [
+            &b"SET k\xffy value\r\n"[..],
+            b"SET k\xff $1\r\nv\r\n",
+            b"GET \xff\r\n",
+            b"MSET a 1 \xc3 2\r\n",
+            b"SELECT \xe2\x82\r\n",
+        ] {
+            let err = parse_error(input);
+            assert_eq!(err.kind, ProtocolErrorKind::InvalidUtf8, "{:?}", input);
+            assert!(input[err.offset] >= 0x80, "{:?} at {}", input, err.offset);
+        }
+        // Values are still taken byte for byte
+        assert_eq!(
+            parse_command(b"SET key \xff\xfe\r\n").unwrap(),
+            Command::Set { key: "key".to_string(), value: vec![0xff, 0xfe], condition: None }
+        );
+    }
+    
+    #[test]
+    fn test_parse_owned_matches_parse() {
+        assert_eq!(
+            parse_command_owned(b"SET key $5 EX 9\r\nab\r\nc\r\n".to_vec()).unwrap(),
+            Command::SetEx { key: "key".to_string(), value: b"ab\r\nc".to_vec(), seconds: 9, condition: None }
+        );
+        for frame in [
+            &b"SET key $0\r\n\r\n"[..],
+            b"SET key inline value\r\n",
+            b"MSET a $1 b $1\r\n1\r\n2\r\n",
+            b"SET key $3\r\nabc\r\nGET key\r\n",
+            b"SET key $3\r\nabcd\r\n",
+            b"SET k\xff $1\r\nv\r\n",
+            b"SETX key $1\r\nv\r\n",
+        ] {
+            let owned = parse_command_owned(frame.to_vec()).map_err(|e| e.to_string());
+            assert_eq!(owned, parse_command(frame).map_err(|e| e.to_string()), "{:?}", frame);
+        }
+    }
+    
+    #[test]
+    fn test_trailing_input_is_rejected() {
+        let err = parse_error(b"GET key\r\nGET other\r\n");
+        assert_eq!((err.kind, err.offset), (ProtocolErrorKind::TrailingInput, 9));
+        assert_eq!(parse_error(b"SET k $1\r\nv\r\nx").kind, ProtocolErrorKind::TrailingInput);
+        assert_eq!(parse_error(b"GET key extra\r\n").kind, ProtocolErrorKind::ExpectedLineEnding);
+        assert_eq!(parse_error(b"SHRINK now\r\n").kind, ProtocolErrorKind::ExpectedLineEnding);
+    }
+    
+    /// Every key a parsed command names
+    fn keys_of(command: &Command) -> Vec<&str> {
+        match command {
+            Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::Get { key }
+            | Command::Exists { key }
+            | Command::Stat { key }
+            | Command::Delete { key }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Decr { key, .. }
+            | Command::Append { key, .. }
+            | Command::Strlen { key }
+            | Command::GetSet { key, .. }
+            | Command::GetDel { key }
+            | Command::Cas { key, .. }
+            | Command::Lock { key, .. }
+            | Command::Unlock { key, .. } => vec![key],
+            Command::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            Command::MGet { keys } | Command::Watch { keys } => keys.iter().map(String::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+    
+    #[test]
+    fn test_random_input_never_yields_an_empty_key() {
+        const SEEDS: &[&[u8]] = &[
+            b"SET key value\r\n",
+            b"SET key $5 EX 10\r\nva\r\nl\r\n",
+            b"GET key\r\n",
+            b"MSET a $1 b $2\r\n1\r\n22\r\n",
+            b"MGET a b c\r\n",
+            b"CAS key $1 $1\r\na\r\nb\r\n",
+            b"INCR counter -5\r\n",
+            b"SCAN 0 10 user:\r\n",
+            b"CONFIG SET read_only 1\r\n",
+            b"WATCH a b\r\n",
+        ];
+        // xorshift64, so failures reproduce
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = move |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+        
+        for _ in 0..20_000 {
+            let mut input = SEEDS[next(SEEDS.len())].to_vec();
+            for _ in 0..1 + next(4) {
+                let at = next(input.len() + 1);
+                match next(4) {
+                    0 if at < input.len() => input[at] = next(256) as u8,
+                    1 => input.insert(at, [b' ', b'\r', b'\n', b'$', 0xff][next(5)]),
+                    2 if at < input.len() => {
+                        input.remove(at);
+                    }
+                    _ => input.truncate(at),
+                }
+            }
+            if next(10) == 0 {
+                input = (0..next(32)).map(|_| next(256) as u8).collect();
+            }
+            
+            if let Ok(command) = parse_command(&input) {
+                for key in keys_of(&command) {
+                    assert!(!key.is_empty(), "{:?} parsed to {:?}", input, command);
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn test_snippet_is_windowed_around_offset() {
+        let mut input = b"GET ".to_vec();
+        input.extend_from_slice(&[b'k'; 60]);
+        input.extend_from_slice(b" trailing\r\n");
+        
+        let err = parse_error(&input);
+        assert_eq!(err.kind, ProtocolErrorKind::ExpectedLineEnding);
+        assert_eq!(err.offset, 64);
+        assert_eq!(err.snippet.len(), SNIPPET_LEN);
+        assert!(err.snippet.ends_with("kkkk trailing"));
     }
 }
\ No newline at end of file
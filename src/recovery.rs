@@ -0,0 +1,435 @@
+//! Read-only recovery and consistency checking for RustVault
+//!
+//! Rebuilds keyspaces from a WAL without modifying it and diffs them against
+//! each other or against a live server, reporting which WAL entry last
+//! touched each divergent key. Live servers can also be compared with each
+//! other by checksum, without transferring their data.
+
+use crate::client::Client;
+use crate::error::Result;
+use crate::protocol::Command;
+use crate::store::{namespace, MemoryStore};
+use crate::wal::{self, now_millis};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// Live value of a key and the WAL sequence number that last wrote it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyState {
+    pub value: Vec<u8>,
+    pub seq: u64,
+}
+
+/// Keyspace rebuilt from a WAL, with per-key provenance
+#[derive(Debug, Clone, Default)]
+pub struct Keyspace {
+    /// Keys that exist after replay
+    pub live: BTreeMap<String, KeyState>,
+    /// Keys whose last WAL entry was a delete, with that entry's sequence number
+    pub deleted: BTreeMap<String, u64>,
+    /// Number of committed entries replayed
+    pub entries: u64,
+}
+
+impl Keyspace {
+    /// Replay the WAL at `path` into a keyspace without modifying the file
+    pub fn replay<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut keyspace = Self::default();
+        wal::read_committed(path, |seq, entry| {
+            keyspace.entries = seq;
+            match entry.command {
+                Command::Set { key, value, .. } | Command::SetEx { key, value, .. } => {
+                    keyspace.deleted.remove(&key);
+                    keyspace.live.insert(key, KeyState { value, seq });
+                }
+                Command::Append { key, value } => {
+                    keyspace.deleted.remove(&key);
+                    let state = keyspace.live.entry(key).or_insert(KeyState { value: Vec::new(), seq });
+                    state.value.extend_from_slice(&value);
+                    state.seq = seq;
+                }
+                Command::Delete { key } => {
+                    keyspace.live.remove(&key);
+                    keyspace.deleted.insert(key, seq);
+                }
+                // Keys whose deadline has passed are gone, as they would be
+                // from a restored store
+                Command::ExpireAt { key, unix_millis } if unix_millis <= now_millis() => {
+                    if keyspace.live.remove(&key).is_some() {
+                        keyspace.deleted.insert(key, seq);
+                    }
+                }
+                Command::ExpireAt { .. }
+                | Command::Expire { .. }
+                | Command::Get { .. }
+                | Command::Exists { .. }
+                | Command::Strlen { .. }
+                | Command::GetSet { .. }
+                | Command::GetDel { .. }
+                | Command::Lock { .. }
+                | Command::Unlock { .. }
+                | Command::Stat { .. }
+                | Command::Subscribe { .. }
+                | Command::Replicate
+                | Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch { .. }
+                | Command::Select { .. }
+                | Command::FlushDb { namespace: None }
+                | Command::DbSize { .. }
+                | Command::Shrink
+                | Command::Compact
+                | Command::WalStats
+                | Command::CommandInfo { .. }
+                | Command::MaintenanceStatus
+                | Command::Config { .. }
+                | Command::Backup { .. }
+                // A restore is logged as the SETs it performs
+                | Command::Restore { .. }
+                | Command::Info
+                | Command::StatsReset
+                | Command::SlowLogGet { .. }
+                | Command::SlowLogReset
+                | Command::Ping
+                | Command::Ready
+                | Command::Checksum { .. }
+                | Command::ChecksumRanges { .. }
+                | Command::Scan { .. }
+                | Command::MGet { .. }
+                // Multi-sets, counters and successful swaps are logged as
+                // the SETs they perform
+                | Command::MSet { .. }
+                | Command::Incr { .. }
+                | Command::Decr { .. }
+                | Command::Cas { .. }
+                | Command::Auth { .. }
+                | Command::Hello { .. } => {}
+                Command::FlushAll => {
+                    let flushed = std::mem::take(&mut keyspace.live);
+                    keyspace.deleted.extend(flushed.into_keys().map(|key| (key, seq)));
+                }
+                Command::FlushDb { namespace: Some(namespace) } => {
+                    let (flushed, kept): (BTreeMap<_, _>, _) = std::mem::take(&mut keyspace.live)
+                        .into_iter()
+                        .partition(|(key, _)| namespace::contains(&namespace, key));
+                    keyspace.live = kept;
+                    keyspace.deleted.extend(flushed.into_keys().map(|key| (key, seq)));
+                }
+                Command::DeletePattern { pattern } => {
+                    let (removed, kept): (BTreeMap<_, _>, _) = std::mem::take(&mut keyspace.live)
+                        .into_iter()
+                        .partition(|(key, _)| namespace::matches(&pattern, key));
+                    keyspace.live = kept;
+                    keyspace.deleted.extend(removed.into_keys().map(|key| (key, seq)));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(keyspace)
+    }
+}
+
+/// Rebuild a store from the WAL at `path` through the same path the server
+/// uses on startup, without modifying the file
+pub async fn restore_store<P: AsRef<Path>>(path: P) -> Result<MemoryStore> {
+    let store = MemoryStore::new();
+    store.restore_from_path(path).await?;
+    Ok(store)
+}
+
+/// A key whose state differs between the expected and the actual keyspace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// Expected key absent from the actual keyspace
+    Missing { key: String, expected: KeyState },
+    /// Actual key that the WAL does not contain, with the sequence number of
+    /// the delete that removed it, if any
+    Extra { key: String, value: Vec<u8>, deleted_seq: Option<u64> },
+    /// Key present on both sides with different values
+    Differs { key: String, expected: KeyState, actual: Vec<u8> },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::Missing { key, expected } => {
+                write!(f, "missing  {} (last written at WAL seq {})", key, expected.seq)
+            }
+            Divergence::Extra { key, deleted_seq: Some(seq), .. } => {
+                write!(f, "extra    {} (deleted at WAL seq {})", key, seq)
+            }
+            Divergence::Extra { key, deleted_seq: None, .. } => {
+                write!(f, "extra    {} (never written to the WAL)", key)
+            }
+            Divergence::Differs { key, expected, actual } => write!(
+                f,
+                "differs  {} expected {:?} (WAL seq {}), found {:?}",
+                key,
+                String::from_utf8_lossy(&expected.value),
+                expected.seq,
+                String::from_utf8_lossy(actual)
+            ),
+        }
+    }
+}
+
+/// Diff a replayed keyspace against a full set of actual key-value pairs
+pub fn diff_keyspaces(expected: &Keyspace, actual: Vec<(String, Vec<u8>)>) -> Vec<Divergence> {
+    let mut actual: BTreeMap<String, Vec<u8>> = actual.into_iter().collect();
+    let mut divergences = Vec::new();
+    
+    for (key, state) in &expected.live {
+        match actual.remove(key) {
+            None => divergences.push(Divergence::Missing {
+                key: key.clone(),
+                expected: state.clone(),
+            }),
+            Some(value) if value != state.value => divergences.push(Divergence::Differs {
+                key: key.clone(),
+                expected: state.clone(),
+                actual: value,
+            }),
+            Some(_) => {}
+        }
+    }
+    
+    for (key, value) in actual {
+        let deleted_seq = expected.deleted.get(&key).copied();
+        divergences.push(Divergence::Extra { key, value, deleted_seq });
+    }
+    
+    divergences
+}
+
+/// Diff a replayed keyspace against a live server
+///
+/// Every key the WAL knows about (live or deleted) is fetched from the server.
+/// Keys that exist only on the server and never appear in the WAL cannot be
+/// detected this way. Keys are fetched from their own namespace, so the
+/// client is left with the last one selected.
+pub async fn diff_against_server(expected: &Keyspace, client: &mut Client) -> Result<Vec<Divergence>> {
+    let mut divergences = Vec::new();
+    let mut selected = None;
+    
+    for (key, state) in &expected.live {
+        match get_qualified(client, &mut selected, key).await? {
+            None => divergences.push(Divergence::Missing {
+                key: key.clone(),
+                expected: state.clone(),
+            }),
+            Some(value) if value != state.value => divergences.push(Divergence::Differs {
+                key: key.clone(),
+                expected: state.clone(),
+                actual: value,
+            }),
+            Some(_) => {}
+        }
+    }
+    
+    for (key, seq) in &expected.deleted {
+        if let Some(value) = get_qualified(client, &mut selected, key).await? {
+            divergences.push(Divergence::Extra {
+                key: key.clone(),
+                value,
+                deleted_seq: Some(*seq),
+            });
+        }
+    }
+    
+    Ok(divergences)
+}
+
+/// Fetch the stored key `stored` from its namespace, selecting it first
+/// unless it is `selected` already
+async fn get_qualified<'a>(
+    client: &mut Client,
+    selected: &mut Option<&'a str>,
+    stored: &'a str,
+) -> Result<Option<Vec<u8>>> {
+    let (namespace, key) = namespace::split(stored);
+    if *selected != Some(namespace) {
+        client.select(namespace).await?;
+        *selected = Some(namespace);
+    }
+    client.get_bytes(key).await
+}
+
+/// Difference between two servers found by [`verify_replica`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaDivergence {
+    /// Key with a different value on each side, or present on only one
+    Key { key: String, primary: Option<Vec<u8>>, replica: Option<Vec<u8>> },
+    /// Keys under `prefix` whose next byte is `byte` differ, but can't be
+    /// narrowed down: a prefix ending in that byte can't be sent as text
+    Range { prefix: String, byte: u8 },
+}
+
+impl fmt::Display for ReplicaDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicaDivergence::Key { key, primary: Some(value), replica: None } => {
+                write!(f, "missing  {} on replica (primary has {:?})", key, String::from_utf8_lossy(value))
+            }
+            ReplicaDivergence::Key { key, primary: None, replica: Some(value) } => {
+                write!(f, "extra    {} on replica ({:?})", key, String::from_utf8_lossy(value))
+            }
+            ReplicaDivergence::Key { key, primary, replica } => write!(
+                f,
+                "differs  {} primary {:?}, replica {:?}",
+                key,
+                String::from_utf8_lossy(primary.as_deref().unwrap_or_default()),
+                String::from_utf8_lossy(replica.as_deref().unwrap_or_default())
+            ),
+            ReplicaDivergence::Range { prefix, byte } => {
+                write!(f, "range    keys after {:?} continuing with byte 0x{:02x}", prefix, byte)
+            }
+        }
+    }
+}
+
+/// Compare two live servers by checksum, descending into ranges that differ
+///
+/// The whole-keyspace digests are compared first, so matching servers cost
+/// one round trip each. Where they differ, both servers report a digest per
+/// next key byte and only mismatched bytes are explored, extending the
+/// prefix one byte at a time. Values are fetched only for keys that are
+/// themselves a differing prefix. Both servers should be quiet while this
+/// runs, or writes in flight will show up as divergences. Only the
+/// namespace each client has selected is compared.
+pub async fn verify_replica(primary: &mut Client, replica: &mut Client) -> Result<Vec<ReplicaDivergence>> {
+    let mut divergences = Vec::new();
+    if primary.checksum("").await? == replica.checksum("").await? {
+        return Ok(divergences);
+    }
+    
+    let mut pending = vec![String::new()];
+    while let Some(prefix) = pending.pop() {
+        if !prefix.is_empty() {
+            let primary_value = primary.get_bytes(&prefix).await?;
+            let replica_value = replica.get_bytes(&prefix).await?;
+            if primary_value != replica_value {
+                divergences.push(ReplicaDivergence::Key {
+                    key: prefix.clone(),
+                    primary: primary_value,
+                    replica: replica_value,
+                });
+            }
+        }
+        
+        let primary_digests = primary.checksum_ranges(256, &prefix).await?;
+        let replica_digests = replica.checksum_ranges(256, &prefix).await?;
+        // Pushed in reverse so prefixes are visited in key order
+        for (byte, (a, b)) in primary_digests.iter().zip(&replica_digests).enumerate().rev() {
+            if a == b {
+                continue;
+            }
+            let byte = byte as u8;
+            if byte.is_ascii_graphic() {
+                pending.push(format!("{}{}", prefix, byte as char));
+            } else {
+                divergences.push(ReplicaDivergence::Range {
+                    prefix: prefix.clone(),
+                    byte,
+                });
+            }
+        }
+    }
+    
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Store;
+    use crate::wal::{SyncPolicy, WriteAheadLog};
+    use tempfile::NamedTempFile;
+
+    async fn write_wal(wal: &WriteAheadLog, commands: &[(&str, Option<&str>)]) {
+        for (key, value) in commands {
+            let command = match value {
+                Some(value) => Command::Set {
+                    key: key.to_string(),
+                    value: value.as_bytes().to_vec(),
+                    condition: None,
+                },
+                None => Command::Delete { key: key.to_string() },
+            };
+            wal.log_command(command).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_tracks_provenance() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        write_wal(&wal, &[("a", Some("1")), ("b", Some("2")), ("a", Some("3")), ("b", None)]).await;
+        
+        let keyspace = Keyspace::replay(temp_file.path()).unwrap();
+        assert_eq!(keyspace.entries, 4);
+        assert_eq!(keyspace.live["a"], KeyState { value: b"3".to_vec(), seq: 3 });
+        assert!(!keyspace.live.contains_key("b"));
+        assert_eq!(keyspace.deleted["b"], 4);
+        
+        // The server's restore path agrees with the entry-by-entry replay
+        let store = restore_store(temp_file.path()).await.unwrap();
+        assert!(diff_keyspaces(&keyspace, store.get_all().await.unwrap()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flush_all_deletes_every_live_key() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        write_wal(&wal, &[("a", Some("1")), ("b", Some("2"))]).await;
+        wal.log_command(Command::FlushAll).await.unwrap();
+        write_wal(&wal, &[("c", Some("3"))]).await;
+        
+        let keyspace = Keyspace::replay(temp_file.path()).unwrap();
+        assert_eq!(keyspace.live.keys().collect::<Vec<_>>(), ["c"]);
+        assert_eq!((keyspace.deleted["a"], keyspace.deleted["b"]), (3, 3));
+        
+        let store = restore_store(temp_file.path()).await.unwrap();
+        assert!(diff_keyspaces(&keyspace, store.get_all().await.unwrap()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_diff_pinpoints_stale_keys() {
+        let stale_file = NamedTempFile::new().unwrap();
+        let current_file = NamedTempFile::new().unwrap();
+        let history = [("a", Some("1")), ("b", Some("2")), ("c", Some("3"))];
+        
+        // The stale copy stops early; the current one keeps going
+        let stale_wal = WriteAheadLog::new(stale_file.path(), SyncPolicy::Never).unwrap();
+        write_wal(&stale_wal, &history).await;
+        let current_wal = WriteAheadLog::new(current_file.path(), SyncPolicy::Never).unwrap();
+        write_wal(&current_wal, &history).await;
+        write_wal(&current_wal, &[("b", Some("20")), ("c", None), ("d", Some("4"))]).await;
+        
+        let stale = Keyspace::replay(stale_file.path()).unwrap();
+        let current = restore_store(current_file.path()).await.unwrap();
+        let mut divergences = diff_keyspaces(&stale, current.get_all().await.unwrap());
+        divergences.sort_by(|a, b| format!("{}", a).cmp(&format!("{}", b)));
+        
+        assert_eq!(
+            divergences,
+            vec![
+                Divergence::Differs {
+                    key: "b".to_string(),
+                    expected: KeyState { value: b"2".to_vec(), seq: 2 },
+                    actual: b"20".to_vec(),
+                },
+                Divergence::Extra {
+                    key: "d".to_string(),
+                    value: b"4".to_vec(),
+                    deleted_seq: None,
+                },
+                Divergence::Missing {
+                    key: "c".to_string(),
+                    expected: KeyState { value: b"3".to_vec(), seq: 3 },
+                },
+            ]
+        );
+    }
+}
\ No newline at end of file
@@ -3,25 +3,214 @@
 //! High-performance key-value store with TCP interface, WAL persistence,
 //! and concurrent client support using tokio async I/O.
 
+pub mod activation;
+pub mod buf_pool;
+pub mod events;
+pub mod latency;
+pub mod maintenance;
+pub mod metrics;
+pub mod ratelimit;
+pub mod replication;
+pub mod runtime;
+pub mod slowlog;
+pub mod watchdog;
+
 use crate::{
+    client::{check_addr, UNIX_SCHEME},
     error::{Result, RustVaultError},
-    protocol::{parse_command, Command, Response},
-    store::{MemoryStore, Store},
-    wal::WriteAheadLog,
+    protocol::{
+        command_spec, glob_is_wildcard, parse_command, parse_command_owned, payload_lens, Command, CommandKind,
+        ConfigAction, ErrorCode, KeyEvent, Response, MAX_MSET_PAIRS, PROTOCOL_VERSION,
+    },
+    store::{namespace, BatchOp, BatchOutcome, CompressionConfig, EvictionPolicy, HasherKind, KeyStat, ShardedMemoryStore, Store},
+    vault::Vault,
+    wal::{self, RecoveryMode, SyncPolicy, WalFormat},
+};
+use buf_pool::{BufPool, BufPoolStats};
+use events::{Events, Subscription};
+use maintenance::{
+    CompactJob, ExpirySweepJob, JobStatus, Scheduler, ShrinkJob, SnapshotJob, StatusTable, WalProbeJob,
 };
-use std::sync::Arc;
+use metrics::{answer_scrape, Gauges, Metrics, StoreGauges};
+pub use metrics::ServerStats;
+use ratelimit::{Admission, RateLimiter};
+pub use ratelimit::{RateLimit, RateLimitMode};
+use replication::{Change, ChangeFeed};
+pub use runtime::RuntimeConfig;
+use slowlog::SlowLog;
+pub use slowlog::SlowLogEntry;
+use watchdog::{ConnTable, WatchdogJob};
+pub use watchdog::HungCommandAction;
+use bytes::BytesMut;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
-    sync::broadcast,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{broadcast, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
 };
 
+/// Capacity reserved for each socket read
+const READ_BUFFER_SIZE: usize = 4 * 1024;
+
+/// How often the WAL size is checked against the compaction threshold
+const COMPACTION_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Most keys a single `SCAN` page may ask for
+const MAX_SCAN_COUNT: usize = 10_000;
+
+/// Room on a command line beyond its key and value, for the verb, the
+/// spacing and the `EX <seconds>` and `NX` options
+const LINE_OVERHEAD: usize = 64;
+
+/// What the server does with a client that arrives while `max_connections`
+/// are already open
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionLimitAction {
+    /// Reply `ERROR server busy` and close the connection
+    #[default]
+    Reject,
+    /// Stop accepting until a connection closes; new clients wait in the
+    /// listen backlog
+    Queue,
+}
+
 /// RustVault server configuration
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
+    /// `host:port` to listen on over TCP, or on Unix targets
+    /// `unix:///path/to/vault.sock` for a Unix socket
     pub bind_addr: String,
     pub wal_path: String,
+    /// When WAL appends are synced to disk; see [`SyncPolicy`] for what each
+    /// policy can lose
+    pub wal_sync: SyncPolicy,
+    /// Hold each group of WAL appends this many microseconds for more to
+    /// join it before writing it, so concurrent writers share a sync; 0
+    /// writes as soon as nothing more is queued
+    pub wal_group_delay_micros: u64,
+    /// Encoding of a new WAL, and of the WAL once compacted; an existing
+    /// WAL is read in whichever format it was written in
+    pub wal_format: WalFormat,
+    /// Split the WAL into numbered segment files, starting a new one once
+    /// the last passes this many bytes; `None` keeps it in one file
+    pub wal_segment_size_bytes: Option<u64>,
+    /// What startup replay does about a corrupt WAL entry that isn't the
+    /// last one; a torn final entry is always truncated
+    pub recovery_mode: RecoveryMode,
+    /// Log how far startup replay has got every this many entries; `None`
+    /// only logs when it's done
+    pub replay_progress_interval: Option<u64>,
+    /// Give up on startup, with an error, if the snapshot and WAL haven't
+    /// been loaded within this long; `None` waits however long it takes
+    pub startup_timeout: Option<Duration>,
+    /// Most connections served at once
     pub max_connections: usize,
+    /// What happens to a client beyond `max_connections`
+    pub connection_limit_action: ConnectionLimitAction,
+    /// Commands each connection may send; `None` lets it send as many as
+    /// the server keeps up with
+    pub rate_limit: Option<RateLimit>,
+    /// Commands every connection from one address may send between them;
+    /// `None` limits only each connection
+    pub ip_rate_limit: Option<RateLimit>,
+    /// What happens to a command over either rate limit
+    pub rate_limit_mode: RateLimitMode,
+    /// Longest key a command may name, in bytes
+    pub max_key_bytes: usize,
+    /// Largest value a command may carry, in bytes; a command line may be
+    /// as long as the largest key and value together, the length-prefixed
+    /// values of one command may add up to twice it, and a connection that
+    /// sends more is closed
+    pub max_value_bytes: usize,
+    /// Split the store into this many independently locked shards; 0, the
+    /// default, picks [`default_shards`](crate::store::sharded::default_shards)
+    /// (four per CPU), and 1 keeps it behind a single lock
+    pub shards: usize,
+    /// Hasher for the store's maps; see [`HasherKind`] before trading
+    /// SipHash's HashDoS resistance for the speed of `AHash` or `FxHash`
+    pub hasher: HasherKind,
+    /// Keep the keys and values within this many bytes, counted as their
+    /// lengths; `None` lets the store grow without limit
+    pub max_memory_bytes: Option<usize>,
+    /// What happens to writes once `max_memory_bytes` is reached
+    pub eviction_policy: EvictionPolicy,
+    /// Keep large values compressed in memory and in the WAL; `None` keeps
+    /// every value as it was sent
+    pub compression: Option<CompressionConfig>,
+    /// Close a connection that sends nothing for this long between
+    /// commands; `None` disables it
+    pub idle_timeout: Option<Duration>,
+    /// Close a connection that stalls this long partway through a command;
+    /// `None` disables it
+    pub read_timeout: Option<Duration>,
+    /// On shutdown, how long commands already running get to finish before
+    /// their connections are dropped
+    pub shutdown_drain_timeout: Duration,
+    /// Commands running longer than this many seconds are reported by the
+    /// watchdog; `None` disables it
+    pub hung_command_threshold_secs: Option<u64>,
+    /// What the watchdog does about a hung command
+    pub hung_command_action: HungCommandAction,
+    /// Keep commands that take longer than this in the slow log, read with
+    /// `SLOWLOG GET`; `None` keeps none
+    pub slowlog_threshold: Option<Duration>,
+    /// Most entries the slow log holds before dropping the oldest
+    pub slowlog_max_len: usize,
+    /// Longest key the slow log keeps, in bytes; longer ones are cut short
+    pub slowlog_max_key_len: usize,
+    /// Run `SHRINK` in the background every this many seconds; `None`
+    /// disables it
+    pub shrink_interval_secs: Option<u64>,
+    /// The background `SHRINK` is skipped while the store's live bytes are
+    /// at least this fraction of the bytes it has allocated, as INFO
+    /// reports them; 1.0 shrinks on every run
+    pub shrink_live_ratio: f64,
+    /// Remove keys whose TTL has run out, expired lock leases among them,
+    /// every this many seconds; `None` leaves them until they are next
+    /// touched or the store is restored
+    pub expiry_sweep_interval_secs: Option<u64>,
+    /// While the WAL can't be written and writes are refused, probe it this
+    /// often to notice when it has room again; `None` disables it
+    pub wal_probe_interval_secs: Option<u64>,
+    /// Compact the WAL in the background once it reaches this many bytes
+    /// (and has doubled since it was last compacted); `None` disables it
+    pub compaction_threshold_bytes: Option<u64>,
+    /// Snapshot file restarts load before replaying only the WAL written
+    /// since; `None` disables snapshots
+    pub snapshot_path: Option<String>,
+    /// Write a snapshot in the background every this many seconds, when
+    /// `snapshot_path` is set; `None` only writes one on request
+    pub snapshot_interval_secs: Option<u64>,
+    /// Token clients must send with `AUTH` before any other command; `None`
+    /// lets every connection in
+    pub auth_token: Option<String>,
+    /// Serve `FLUSHALL`, which wipes every key; refused with `ERROR command
+    /// disabled` when off
+    pub allow_flush_all: bool,
+    /// Serve Prometheus metrics over HTTP at `/metrics` on this address;
+    /// `None` disables it
+    pub metrics_addr: Option<String>,
+    /// Run as a read-only replica of the server at this address, copying
+    /// its data and refusing client writes; `None` runs a primary
+    pub replica_of: Option<String>,
+    /// Refuse writes with `ERROR command not permitted`
+    pub read_only: bool,
+    /// Serve only these commands, named by their verbs in the
+    /// [`COMMAND_TABLE`](crate::protocol::COMMAND_TABLE), and refuse the
+    /// rest with `ERROR command not permitted`; `None` serves every command.
+    /// Leave out `CONFIG` to keep clients from lifting `read_only`
+    pub allowed_commands: Option<HashSet<String>>,
 }
 
 impl Default for ServerConfig {
@@ -29,71 +218,682 @@ impl Default for ServerConfig {
         Self {
             bind_addr: "127.0.0.1:8080".to_string(),
             wal_path: "vault.log".to_string(),
+            wal_sync: SyncPolicy::EveryMillis(1000),
+            wal_group_delay_micros: 0,
+            wal_format: WalFormat::Json,
+            wal_segment_size_bytes: None,
+            recovery_mode: RecoveryMode::Strict,
+            replay_progress_interval: Some(100_000),
+            startup_timeout: None,
             max_connections: 1000,
+            connection_limit_action: ConnectionLimitAction::Reject,
+            rate_limit: None,
+            ip_rate_limit: None,
+            rate_limit_mode: RateLimitMode::Delay,
+            max_key_bytes: 1024,
+            max_value_bytes: 16 * 1024 * 1024,
+            shards: 0,
+            hasher: HasherKind::SipHash,
+            max_memory_bytes: None,
+            eviction_policy: EvictionPolicy::NoEviction,
+            compression: None,
+            idle_timeout: None,
+            read_timeout: None,
+            shutdown_drain_timeout: Duration::from_secs(10),
+            hung_command_threshold_secs: None,
+            hung_command_action: HungCommandAction::Warn,
+            slowlog_threshold: Some(Duration::from_millis(10)),
+            slowlog_max_len: 128,
+            slowlog_max_key_len: 64,
+            shrink_interval_secs: None,
+            shrink_live_ratio: 0.5,
+            expiry_sweep_interval_secs: Some(60),
+            wal_probe_interval_secs: Some(1),
+            compaction_threshold_bytes: Some(64 * 1024 * 1024),
+            snapshot_path: None,
+            snapshot_interval_secs: Some(300),
+            auth_token: None,
+            allow_flush_all: false,
+            metrics_addr: None,
+            replica_of: None,
+            read_only: false,
+            allowed_commands: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Check the addresses the server will bind or connect to, without
+    /// touching the network
+    pub fn validate(&self) -> Result<()> {
+        check_addr(&self.bind_addr)?;
+        if let Some(addr) = &self.metrics_addr {
+            check_addr(addr)?;
+            if addr.starts_with(UNIX_SCHEME) {
+                return Err(RustVaultError::InvalidAddress(format!(
+                    "{:?} is a Unix socket; metrics are only served over TCP",
+                    addr
+                )));
+            }
+        }
+        if let Some(addr) = &self.replica_of {
+            check_addr(addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bind a TCP listener, naming the address if it fails
+async fn bind_tcp(addr: &str) -> Result<TcpListener> {
+    TcpListener::bind(addr).await.map_err(|source| RustVaultError::BindFailed {
+        addr: addr.to_string(),
+        source,
+    })
+}
+
+/// A bound socket the server accepts clients on
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Human-readable local address, for logging
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp socket".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix socket".to_string(),
+                },
+                Err(_) => "unix socket".to_string(),
+            },
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+/// Progress of the startup WAL replay
+///
+/// The listener is bound before the WAL is replayed so health checks see a
+/// live server during a long recovery; data commands are answered with
+/// `ERR_LOADING` until `ready` flips.
+#[derive(Debug, Default)]
+struct LoadState {
+    /// Percentage of the WAL read so far
+    progress: AtomicU8,
+    /// Set once the replay has finished
+    ready: AtomicBool,
+}
+
+impl LoadState {
+    /// Record that `read` of `total` WAL bytes have been replayed
+    fn set_progress(&self, read: u64, total: u64) {
+        let pct = (read * 100).checked_div(total).unwrap_or(100).min(100);
+        self.progress.store(pct as u8, Ordering::Relaxed);
+    }
+    
+    /// Mark the replay complete, making the store visible to data commands
+    fn mark_ready(&self) {
+        self.progress.store(100, Ordering::Relaxed);
+        self.ready.store(true, Ordering::Release);
+    }
+    
+    fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+    
+    fn progress(&self) -> u8 {
+        self.progress.load(Ordering::Relaxed)
+    }
+}
+
+/// Largest keys and values the server accepts
+#[derive(Debug, Clone, Copy)]
+struct SizeLimits {
+    max_key: usize,
+    max_value: usize,
+}
+
+impl SizeLimits {
+    /// Longest command line read before giving up on the connection
+    fn max_line(&self) -> usize {
+        self.max_key.saturating_add(self.max_value).saturating_add(LINE_OVERHEAD)
+    }
+    
+    /// Most bytes of length-prefixed values one command may announce: as
+    /// much as a CAS of two values at the limit
+    fn max_payload(&self) -> usize {
+        self.max_value.saturating_mul(2)
+    }
+    
+    fn line_too_long(&self) -> Response {
+        Response::error(ErrorCode::TooLarge, format!("line too long (max {})", self.max_line()))
+    }
+    
+    fn key_too_large(&self) -> Response {
+        Response::error(ErrorCode::TooLarge, format!("key too large (max {})", self.max_key))
+    }
+    
+    fn value_too_large(&self) -> Response {
+        Response::error(ErrorCode::TooLarge, format!("value too large (max {})", self.max_value))
+    }
+    
+    fn payload_too_large(&self) -> Response {
+        Response::error(ErrorCode::TooLarge, format!("values too large together (max {})", self.max_payload()))
+    }
+    
+    /// The error for a command naming a key or carrying a value over the
+    /// limits, or an MSET of more than [`MAX_MSET_PAIRS`] pairs, if it does
+    ///
+    /// Keys are measured as the client sent them, without their namespace.
+    fn check(&self, command: &Command) -> Option<Response> {
+        if let Command::MSet { pairs } = command {
+            if pairs.len() > MAX_MSET_PAIRS {
+                return Some(Response::error(
+                    ErrorCode::TooLarge,
+                    format!("too many pairs (max {})", MAX_MSET_PAIRS),
+                ));
+            }
+        }
+        let key_over = |key: &String| namespace::split(key).1.len() > self.max_key;
+        let value_over = |value: &Vec<u8>| value.len() > self.max_value;
+        let (key, value) = match command {
+            Command::Set { key, value, .. }
+            | Command::SetEx { key, value, .. }
+            | Command::Append { key, value }
+            | Command::GetSet { key, value } => {
+                (key_over(key), value_over(value))
+            }
+            Command::Cas { key, expected, new } => {
+                (key_over(key), value_over(expected) || value_over(new))
+            }
+            Command::Get { key }
+            | Command::Exists { key }
+            | Command::Stat { key }
+            | Command::Delete { key }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Decr { key, .. }
+            | Command::Strlen { key }
+            | Command::GetDel { key }
+            | Command::Unlock { key, .. } => (key_over(key), false),
+            Command::Lock { key, token, .. } => (key_over(key), token.len() > self.max_value),
+            Command::MSet { pairs } => (
+                pairs.iter().any(|(key, _)| key_over(key)),
+                pairs.iter().any(|(_, value)| value_over(value)),
+            ),
+            Command::MGet { keys } | Command::Watch { keys } => (keys.iter().any(key_over), false),
+            _ => (false, false),
+        };
+        if key {
+            Some(self.key_too_large())
+        } else if value {
+            Some(self.value_too_large())
+        } else {
+            None
         }
     }
 }
 
+/// State shared by the server handle, its accept loops and every connection
+struct Shared<S = ShardedMemoryStore> {
+    /// The store served, and the WAL it logs to when it has one
+    vault: Vault<S>,
+    buf_pool: Arc<BufPool>,
+    load: LoadState,
+    conns: Arc<ConnTable>,
+    /// One permit per connection that may be served at once
+    conn_limit: Arc<Semaphore>,
+    conn_limit_action: ConnectionLimitAction,
+    rate_limiter: RateLimiter,
+    metrics: Metrics,
+    slowlog: SlowLog,
+    /// Settings `CONFIG SET` can change
+    config: RwLock<RuntimeConfig>,
+    /// Changes published to subscribed connections
+    events: Events,
+    /// Changes published to attached replicas
+    replication: ChangeFeed,
+    /// Set on a replica, which refuses client writes
+    replica: bool,
+    /// Verbs of the only commands served, when limited
+    allowed_commands: Option<HashSet<&'static str>>,
+    maintenance: Arc<StatusTable>,
+    shutdown_tx: broadcast::Sender<()>,
+    /// Token a connection must present before it is served
+    auth_token: Option<String>,
+    allow_flush_all: bool,
+    /// Artificial delay before each command, to simulate a wedged handler
+    #[cfg(test)]
+    command_delay: Option<std::time::Duration>,
+}
+
+impl<S> Shared<S> {
+    /// The current settings; not to be held across an `await`
+    fn runtime(&self) -> RwLockReadGuard<'_, RuntimeConfig> {
+        self.config.read().unwrap()
+    }
+    
+    fn limits(&self) -> SizeLimits {
+        self.runtime().limits()
+    }
+}
+
 /// RustVault TCP server
-pub struct RustVaultServer {
+///
+/// Serves a [`Vault`] around any [`Store`]; by default a
+/// [`ShardedMemoryStore`] of `shards` shards, persisted to the WAL at
+/// `wal_path`.
+pub struct RustVaultServer<S = ShardedMemoryStore> {
     config: ServerConfig,
-    store: Arc<MemoryStore>,
-    shutdown_tx: broadcast::Sender<()>,
+    shared: Arc<Shared<S>>,
+    /// Where the metrics endpoint was bound, once it is
+    metrics_addr: std::sync::OnceLock<SocketAddr>,
+    /// Artificial delay per replayed WAL line, to observe the loading phase
+    #[cfg(test)]
+    replay_delay: Option<std::time::Duration>,
 }
 
 impl RustVaultServer {
     /// Create a new server instance
+    ///
+    /// The WAL is opened here but replayed by `run`, after the listener is
+    /// bound.
     pub async fn new(config: ServerConfig) -> Result<Self> {
-        // Initialize WAL
-        let wal = Arc::new(WriteAheadLog::new(&config.wal_path)?);
-        
-        // Initialize store with WAL
-        let store = MemoryStore::with_wal(wal);
-        
-        // Restore state from WAL
-        println!("Restoring state from WAL: {}", config.wal_path);
-        store.restore_from_wal().await?;
-        let restored_count = store.len().await?;
-        println!("Restored {} key-value pairs from WAL", restored_count);
-        
+        config.validate()?;
+        let vault = Vault::from_config(&config)?;
+        Ok(Self::with_vault(config, vault))
+    }
+    
+    /// Create a server around an already-open WAL
+    ///
+    /// `config.wal_path` is only used for logging.
+    #[cfg(feature = "test-util")]
+    pub(crate) fn with_wal(config: ServerConfig, wal: Arc<crate::wal::WriteAheadLog>) -> Self {
+        let vault = Vault::with_wal(&config, wal);
+        Self::with_vault(config, vault)
+    }
+}
+
+impl<S: Store + 'static> RustVaultServer<S> {
+    /// Create a server around any store
+    ///
+    /// The store keeps its own data: `wal_path` and `wal_sync` are unused,
+    /// no WAL compaction or probing runs, and the store is asked to load
+    /// what it persists through [`Store::restore_blocking`] once `run` has
+    /// bound the listener.
+    pub fn with_store(config: ServerConfig, store: S) -> Self {
+        let mut vault = Vault::with_store(store);
+        if let Some(path) = &config.snapshot_path {
+            vault = vault.with_snapshot_path(path);
+        }
+        Self::with_vault(config, vault)
+    }
+    
+    /// Create a server around a vault that hasn't been loaded yet; `run`
+    /// restores it once the listener is bound
+    fn with_vault(config: ServerConfig, vault: Vault<S>) -> Self {
         let (shutdown_tx, _) = broadcast::channel(1);
         
-        Ok(Self {
+        Self {
+            shared: Arc::new(Shared {
+                vault,
+                buf_pool: Arc::new(BufPool::default()),
+                load: LoadState::default(),
+                conns: Arc::new(ConnTable::default()),
+                conn_limit: Arc::new(Semaphore::new(
+                    config.max_connections.min(Semaphore::MAX_PERMITS),
+                )),
+                conn_limit_action: config.connection_limit_action,
+                rate_limiter: RateLimiter::new(config.rate_limit, config.ip_rate_limit, config.rate_limit_mode),
+                metrics: Metrics::default(),
+                slowlog: SlowLog::new(config.slowlog_threshold, config.slowlog_max_len, config.slowlog_max_key_len),
+                config: RwLock::new(RuntimeConfig::new(&config)),
+                events: Events::default(),
+                replication: ChangeFeed::default(),
+                replica: config.replica_of.is_some(),
+                allowed_commands: config.allowed_commands.as_ref().map(|names| allowed_verbs(names)),
+                maintenance: Arc::new(StatusTable::default()),
+                shutdown_tx,
+                auth_token: config.auth_token.clone(),
+                allow_flush_all: config.allow_flush_all,
+                #[cfg(test)]
+                command_delay: None,
+            }),
             config,
-            store: Arc::new(store),
-            shutdown_tx,
-        })
+            metrics_addr: std::sync::OnceLock::new(),
+            #[cfg(test)]
+            replay_delay: None,
+        }
     }
     
-    /// Start the server
+    /// Start the server on `bind_addr`
+    ///
+    /// A `unix://` address is bound as a Unix socket, which is removed
+    /// again once the server stops.
     pub async fn run(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.config.bind_addr).await?;
-        println!("RustVault server listening on {}", self.config.bind_addr);
+        self.config.validate()?;
+        if let Some(path) = self.config.bind_addr.strip_prefix(UNIX_SCHEME) {
+            return self.run_on_unix_socket(Path::new(path)).await;
+        }
+        let listener = bind_tcp(&self.config.bind_addr).await?;
+        self.run_with_listener(listener).await
+    }
+    
+    #[cfg(unix)]
+    async fn run_on_unix_socket(&self, path: &Path) -> Result<()> {
+        // A file left by a server that didn't stop cleanly is replaced, but
+        // one a server still answers on is not
+        let bind_failed = |source| RustVaultError::BindFailed {
+            addr: self.config.bind_addr.clone(),
+            source,
+        };
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(bind_failed(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "in use by a running server",
+                )));
+            }
+            std::fs::remove_file(path).map_err(bind_failed)?;
+        }
+        let listener = UnixListener::bind(path).map_err(bind_failed)?;
+        let result = self.run_with_listener(listener).await;
+        if let Err(e) = std::fs::remove_file(path) {
+            eprintln!("Failed to remove socket {}: {}", path.display(), e);
+        }
+        result
+    }
+    
+    #[cfg(not(unix))]
+    async fn run_on_unix_socket(&self, path: &Path) -> Result<()> {
+        Err(RustVaultError::Server(format!(
+            "can't listen on {}: Unix sockets aren't supported here",
+            path.display()
+        )))
+    }
+    
+    /// Serve clients from an already-bound listener instead of `bind_addr`
+    pub async fn run_with_listener(&self, listener: impl Into<Listener>) -> Result<()> {
+        self.run_with_listeners(vec![listener.into()]).await
+    }
+    
+    /// Serve clients from several listeners until shutdown
+    ///
+    /// Used for sockets passed in by systemd socket activation, where a unit
+    /// may hand over more than one. Clients are accepted straight away; the
+    /// WAL is replayed once the listeners are running.
+    pub async fn run_with_listeners(&self, listeners: Vec<Listener>) -> Result<()> {
+        if listeners.is_empty() {
+            return Err(RustVaultError::Server("No listeners to serve".to_string()));
+        }
+        
+        let metrics_listener = match &self.config.metrics_addr {
+            Some(addr) => {
+                let listener = bind_tcp(addr).await?;
+                let _ = self.metrics_addr.set(listener.local_addr()?);
+                Some(listener)
+            }
+            None => None,
+        };
+        
+        let mut accept_loops = JoinSet::new();
+        if let Some(listener) = metrics_listener {
+            println!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+            accept_loops.spawn(Self::metrics_loop(
+                listener,
+                Arc::clone(&self.shared),
+                self.shared.shutdown_tx.subscribe(),
+            ));
+        }
+        for listener in listeners {
+            println!("RustVault server listening on {}", listener.describe());
+            accept_loops.spawn(Self::accept_loop(
+                listener,
+                Arc::clone(&self.shared),
+                self.config.shutdown_drain_timeout,
+                self.shared.shutdown_tx.subscribe(),
+            ));
+        }
+        
+        let scheduler = self.scheduler();
+        if !scheduler.is_empty() {
+            accept_loops.spawn(scheduler.run(self.shared.shutdown_tx.subscribe()));
+        }
+        
+        if !self.shared.load.is_ready() {
+            if let Err(e) = self.restore().await {
+                let _ = self.shared.shutdown_tx.send(());
+                while accept_loops.join_next().await.is_some() {}
+                return Err(e);
+            }
+        }
+        
+        if let Some(primary) = &self.config.replica_of {
+            accept_loops.spawn(replication::follow(
+                primary.clone(),
+                Arc::clone(&self.shared),
+                self.shared.shutdown_tx.subscribe(),
+            ));
+        }
+        
+        while let Some(result) = accept_loops.join_next().await {
+            if let Err(e) = result {
+                eprintln!("Accept loop failed: {}", e);
+            }
+        }
+        
+        // Every connection is closed, so nothing more will be appended
+        if self.shared.vault.wal().is_some() {
+            if let Err(e) = self.shared.vault.flush().await {
+                eprintln!("Failed to sync WAL on shutdown: {}", e);
+                return Err(e);
+            }
+        }
+        
+        println!("Server stopped");
+        Ok(())
+    }
+    
+    /// Background jobs enabled by the config
+    fn scheduler(&self) -> Scheduler {
+        let mut scheduler = Scheduler::new(Arc::clone(&self.shared.maintenance));
+        if let Some(secs) = self.config.hung_command_threshold_secs {
+            scheduler.add(WatchdogJob::new(
+                Arc::clone(&self.shared.conns),
+                std::time::Duration::from_secs(secs),
+                self.config.hung_command_action,
+            ));
+        }
+        if let Some(secs) = self.config.shrink_interval_secs {
+            scheduler.add(ShrinkJob::new(
+                Arc::clone(self.shared.vault.store()),
+                self.config.shrink_live_ratio,
+                std::time::Duration::from_secs(secs),
+            ));
+        }
+        if let Some(secs) = self.config.expiry_sweep_interval_secs {
+            scheduler.add(ExpirySweepJob::new(
+                Arc::clone(self.shared.vault.store()),
+                std::time::Duration::from_secs(secs),
+            ));
+        }
+        let Some(wal) = self.shared.vault.wal() else {
+            return scheduler;
+        };
+        if let Some(threshold) = self.config.compaction_threshold_bytes {
+            scheduler.add(CompactJob::new(
+                Arc::clone(self.shared.vault.store()),
+                Arc::clone(wal),
+                threshold,
+                COMPACTION_CHECK_INTERVAL,
+            ));
+        }
+        if let (Some(path), Some(secs)) = (&self.config.snapshot_path, self.config.snapshot_interval_secs) {
+            scheduler.add(SnapshotJob::new(
+                Arc::clone(self.shared.vault.store()),
+                PathBuf::from(path),
+                std::time::Duration::from_secs(secs),
+            ));
+        }
+        if let Some(secs) = self.config.wal_probe_interval_secs {
+            scheduler.add(WalProbeJob::new(
+                Arc::clone(wal),
+                std::time::Duration::from_secs(secs),
+            ));
+        }
+        scheduler
+    }
+    
+    /// Load the snapshot and replay the WAL into the store, then start
+    /// serving data commands
+    async fn restore(&self) -> Result<()> {
+        if self.shared.vault.wal().is_some() {
+            println!("Restoring state from WAL: {}", self.config.wal_path);
+        }
+        
+        let shared = Arc::clone(&self.shared);
+        let mut log = wal::every_entries(self.config.replay_progress_interval.unwrap_or(0), |progress| {
+            println!(
+                "Replayed {} entries ({} of {} bytes, {:.1}%)",
+                progress.entries,
+                progress.bytes_read,
+                progress.total_bytes,
+                progress.percent()
+            )
+        });
+        #[cfg(test)]
+        let replay_delay = self.replay_delay;
+        let restore = self.shared.vault.restore(move |progress| {
+            shared.load.set_progress(progress.bytes_read, progress.total_bytes);
+            log(progress);
+            #[cfg(test)]
+            if let Some(delay) = replay_delay {
+                std::thread::sleep(delay);
+            }
+        });
+        match self.config.startup_timeout {
+            // The replay can't be interrupted, so a timed-out one is left
+            // running on its thread while the server shuts down
+            Some(timeout) => tokio::time::timeout(timeout, restore).await.map_err(|_| {
+                RustVaultError::Server(format!(
+                    "Startup replay didn't finish within {:?}, after {}% of the WAL",
+                    timeout,
+                    self.shared.load.progress()
+                ))
+            })??,
+            None => restore.await?,
+        }
         
-        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        let restored_count = self.shared.vault.len().await?;
+        println!("Restored {} key-value pairs", restored_count);
+        self.shared.load.mark_ready();
+        
+        // Tell systemd we can serve, if it is waiting to hear it
+        match activation::notify_ready() {
+            Ok(true) => println!("Notified service manager of readiness"),
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to notify service manager: {}", e),
+        }
         
+        Ok(())
+    }
+    
+    /// Whether the startup WAL replay has finished
+    pub fn is_ready(&self) -> bool {
+        self.shared.load.is_ready()
+    }
+    
+    /// Percentage of the WAL replayed so far; 100 once ready
+    pub fn loading_progress(&self) -> u8 {
+        self.shared.load.progress()
+    }
+    
+    /// Number of commands the watchdog has reported as hung
+    pub fn hung_commands(&self) -> u64 {
+        self.shared.conns.hung_commands()
+    }
+    
+    /// Why writes are being refused, if the WAL can't be written
+    pub fn persistence_error(&self) -> Option<String> {
+        self.shared.vault.wal().and_then(|wal| wal.persistence_error())
+    }
+    
+    /// Number of times the WAL has become unwritable and writes were refused
+    pub fn persistence_failures(&self) -> u64 {
+        self.shared.vault.wal().map_or(0, |wal| wal.persistence_failures())
+    }
+    
+    /// Write a snapshot to `snapshot_path` now
+    pub async fn snapshot(&self) -> Result<()> {
+        self.shared.vault.snapshot().await
+    }
+    
+    /// The settings as they are now, after any `CONFIG SET`
+    pub fn runtime_config(&self) -> RuntimeConfig {
+        self.shared.runtime().clone()
+    }
+    
+    /// Get a snapshot of the connection counters
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            connections: self.shared.conns.open_connections(),
+            rejected_connections: self.shared.metrics.rejected_connections(),
+        }
+    }
+    
+    /// Address the metrics endpoint is listening on, once `run` has bound it
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr.get().copied()
+    }
+    
+    /// Status of each background maintenance job
+    pub fn maintenance_status(&self) -> Vec<JobStatus> {
+        self.shared.maintenance.snapshot()
+    }
+    
+    /// Accept connections from one listener until shutdown
+    ///
+    /// On shutdown the listener is closed first, then the connections it
+    /// accepted get up to `drain_timeout` to finish the commands they are
+    /// running before they are dropped.
+    async fn accept_loop(
+        listener: Listener,
+        shared: Arc<Shared<S>>,
+        drain_timeout: Duration,
+        mut shutdown_rx: broadcast::Receiver<()>,
+    ) {
+        let mut clients = JoinSet::new();
         loop {
             tokio::select! {
                 // Accept new connections
-                result = listener.accept() => {
-                    match result {
-                        Ok((stream, addr)) => {
-                            println!("New client connected: {}", addr);
-                            let store = Arc::clone(&self.store);
-                            let shutdown_rx = self.shutdown_tx.subscribe();
-                            
-                            // Spawn a task to handle the client
-                            tokio::spawn(async move {
-                                if let Err(e) = Self::handle_client(stream, store, shutdown_rx).await {
-                                    eprintln!("Error handling client {}: {}", addr, e);
-                                }
-                                println!("Client disconnected: {}", addr);
-                            });
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to accept connection: {}", e);
-                        }
+                result = Self::accept_one(&listener, &shared, &mut clients) => {
+                    if let Err(e) = result {
+                        eprintln!("Failed to accept connection: {}", e);
                     }
+                    // Forget connections that have since closed
+                    while clients.try_join_next().is_some() {}
                 }
                 
                 // Handle shutdown signal
@@ -103,47 +903,330 @@ impl RustVaultServer {
                 }
             }
         }
+        drop(listener);
         
-        println!("Server stopped");
+        let drained = tokio::time::timeout(drain_timeout, async {
+            while clients.join_next().await.is_some() {}
+        });
+        if drained.await.is_err() {
+            eprintln!(
+                "Dropping {} connection(s) still busy after {:?}",
+                clients.len(),
+                drain_timeout
+            );
+            clients.shutdown().await;
+        }
+    }
+    
+    /// Answer Prometheus scrapes until shutdown
+    async fn metrics_loop(
+        listener: TcpListener,
+        shared: Arc<Shared<S>>,
+        mut shutdown_rx: broadcast::Receiver<()>,
+    ) {
+        loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    let (mut stream, peer) = match result {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            eprintln!("Failed to accept metrics connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let shared = Arc::clone(&shared);
+                    tokio::spawn(async move {
+                        let body = async {
+                            // The store is locked for the whole replay
+                            if !shared.load.is_ready() {
+                                return None;
+                            }
+                            let store = Self::store_gauges(&shared).await.ok()?;
+                            Some(shared.metrics.prometheus(Self::gauges(&shared), store))
+                        };
+                        if let Err(e) = answer_scrape(&mut stream, body).await {
+                            eprintln!("Failed to answer metrics scrape from {}: {}", peer, e);
+                        }
+                    });
+                }
+                
+                _ = shutdown_rx.recv() => break,
+            }
+        }
+    }
+    
+    /// Figures reported alongside the counters that don't need the store
+    fn gauges(shared: &Shared<S>) -> Gauges {
+        Gauges {
+            connections: shared.conns.open_connections(),
+            wal_size: shared.vault.wal().map_or(0, |wal| wal.size()),
+        }
+    }
+    
+    /// Figures reported alongside the counters that are read from the store
+    async fn store_gauges(shared: &Shared<S>) -> Result<StoreGauges> {
+        Ok(StoreGauges {
+            keys: shared.vault.len().await?,
+            pending_free: shared.vault.pending_free(),
+            memory: shared.vault.memory_usage().await,
+            compression: shared.vault.compression_stats(),
+        })
+    }
+    
+    /// Accept a single connection and spawn a task to handle it
+    async fn accept_one(
+        listener: &Listener,
+        shared: &Arc<Shared<S>>,
+        clients: &mut JoinSet<()>,
+    ) -> io::Result<()> {
+        // A queued client isn't accepted until a connection slot is free
+        let permit = match shared.conn_limit_action {
+            ConnectionLimitAction::Queue => Some(
+                Arc::clone(&shared.conn_limit)
+                    .acquire_owned()
+                    .await
+                    .expect("connection semaphore is never closed"),
+            ),
+            ConnectionLimitAction::Reject => None,
+        };
+        match listener {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Self::spawn_client(stream, addr.to_string(), shared, permit, clients);
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                let peer = match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix client".to_string(),
+                };
+                Self::spawn_client(stream, peer, shared, permit, clients);
+            }
+        }
         Ok(())
     }
     
+    /// Spawn a task to handle a freshly accepted client
+    ///
+    /// Without a connection slot already in hand, the client is turned away
+    /// if none is free.
+    fn spawn_client<T>(
+        mut stream: T,
+        peer: String,
+        shared: &Arc<Shared<S>>,
+        permit: Option<OwnedSemaphorePermit>,
+        clients: &mut JoinSet<()>,
+    ) where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let permit = match permit {
+            Some(permit) => permit,
+            None => match Arc::clone(&shared.conn_limit).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    println!("Rejected client {}: connection limit reached", peer);
+                    shared.metrics.rejected();
+                    clients.spawn(async move {
+                        let busy = Response::error(ErrorCode::Busy, "server busy");
+                        let _ = stream.write_all(&busy.to_bytes()).await;
+                        let _ = stream.shutdown().await;
+                    });
+                    return;
+                }
+            },
+        };
+        
+        println!("New client connected: {}", peer);
+        shared.metrics.accepted();
+        let shared = Arc::clone(shared);
+        let shutdown_rx = shared.shutdown_tx.subscribe();
+        
+        clients.spawn(async move {
+            if let Err(e) = Self::handle_client(stream, &peer, shared, permit, shutdown_rx).await {
+                eprintln!("Error handling client {}: {}", peer, e);
+            }
+            println!("Client disconnected: {}", peer);
+        });
+    }
+    
+    /// Get a snapshot of the connection buffer pool counters
+    pub fn buf_pool_stats(&self) -> BufPoolStats {
+        self.shared.buf_pool.stats()
+    }
+    
     /// Handle a single client connection
-    async fn handle_client(
-        mut stream: TcpStream,
-        store: Arc<MemoryStore>,
+    async fn handle_client<T>(
+        mut stream: T,
+        peer: &str,
+        shared: Arc<Shared<S>>,
+        permit: OwnedSemaphorePermit,
         mut shutdown_rx: broadcast::Receiver<()>,
-    ) -> Result<()> {
-        let (reader, mut writer) = stream.split();
-        let mut buf_reader = BufReader::new(reader);
-        let mut line = String::new();
+    ) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let conn = shared.conns.register(peer.to_string());
+        // Held until the connection closes; dropped before `conn`, so the
+        // slot is free by the time the connection stops being counted
+        let _permit = permit;
+        let mut session = Session {
+            peer: peer.to_string(),
+            ..Default::default()
+        };
+        let mut read_buf = shared.buf_pool.checkout(READ_BUFFER_SIZE);
+        // Bytes of read_buf already known not to contain a newline
+        let mut scanned = 0;
+        let mut bucket = shared.rate_limiter.connection_bucket();
+        let ip = peer.parse::<SocketAddr>().ok().map(|addr| addr.ip());
         
-        loop {
-            line.clear();
+        'connection: loop {
+            // End of the frame, when the buffered line announces a payload not
+            // fully read yet
+            let mut awaiting_frame = None;
+            
+            // Answer every complete frame already buffered before reading more
+            while let Some(pos) = read_buf[scanned..].iter().position(|&b| b == b'\n') {
+                let line_end = scanned + pos + 1;
+                let limits = shared.limits();
+                if line_end > limits.max_line() {
+                    let response = limits.line_too_long();
+                    let _ = stream.write_all(&response.to_bytes()).await;
+                    break 'connection;
+                }
+                // The payload can't be skipped without reading it, so the
+                // rest of the stream can't be framed if it is refused
+                let values = match payload_lens(&read_buf[..line_end]) {
+                    Ok(values) if values.iter().any(|&len| len > limits.max_value) => {
+                        Err(limits.value_too_large())
+                    }
+                    Ok(values) if values.iter().sum::<usize>() > limits.max_payload() => {
+                        Err(limits.payload_too_large())
+                    }
+                    Ok(values) => Ok(values),
+                    Err(e) => Err(Response::error(ErrorCode::Parse, e)),
+                };
+                let frame_end = match values {
+                    Ok(values) if values.is_empty() => line_end,
+                    // Each value ends in its own CRLF
+                    Ok(values) => line_end + values.iter().sum::<usize>() + 2 * values.len(),
+                    Err(response) => {
+                        let _ = stream.write_all(&response.to_bytes()).await;
+                        break 'connection;
+                    }
+                };
+                if read_buf.len() < frame_end {
+                    awaiting_frame = Some(frame_end);
+                    break;
+                }
+                
+                // A frame filling the buffer, as a large value's usually does,
+                // is taken whole so its value can stay where it was read
+                let frame = if frame_end == read_buf.len() && frame_end > READ_BUFFER_SIZE {
+                    std::mem::take(&mut *read_buf)
+                } else {
+                    read_buf.split_to(frame_end)
+                };
+                scanned = 0;
+                let admission = shared.rate_limiter.admit(bucket.as_mut(), ip);
+                // The command line must be text; only a payload may be binary
+                let response = match (admission, str::from_utf8(&frame[..line_end])) {
+                    (Admission::Refused { retry_after }, _) => {
+                        shared.metrics.rate_limit_rejected();
+                        let millis = retry_after.as_micros().div_ceil(1000).max(1);
+                        Response::error(ErrorCode::RateLimited, format!("retry_after_ms={}", millis))
+                    }
+                    (_, Ok(line)) => {
+                        // Held back before it starts, so the watchdog doesn't
+                        // take the wait for a hung command
+                        if let Admission::After(wait) = admission {
+                            shared.metrics.rate_limit_delayed();
+                            tokio::time::sleep(wait).await;
+                        }
+                        conn.begin(line.split_whitespace().next().unwrap_or(""));
+                        let execute = async {
+                            #[cfg(test)]
+                            if let Some(delay) = shared.command_delay {
+                                tokio::time::sleep(delay).await;
+                            }
+                            Self::process_frame(frame, &shared, &mut session).await
+                        };
+                        
+                        // Abandoning a wedged command can leave a write in the
+                        // WAL that never reached the map; that is the price of
+                        // the kill action, and replay settles it on restart
+                        let response = tokio::select! {
+                            response = execute => Some(response),
+                            _ = conn.killed() => None,
+                        };
+                        conn.end();
+                        match response {
+                            Some(response) => response,
+                            None => break 'connection,
+                        }
+                    }
+                    (_, Err(_)) => Response::error(ErrorCode::Parse, "Command is not valid UTF-8"),
+                };
+                
+                let mut response_buf = shared.buf_pool.checkout(READ_BUFFER_SIZE);
+                response.encode_as(session.protocol(), &mut *response_buf);
+                
+                if let Err(e) = stream.write_all(&response_buf).await {
+                    eprintln!("Failed to write response: {}", e);
+                    break 'connection;
+                }
+                
+                if let Err(e) = stream.flush().await {
+                    eprintln!("Failed to flush response: {}", e);
+                    break 'connection;
+                }
+                
+                if let Some(subscription) = session.subscription.take() {
+                    Self::push_events(&mut stream, subscription, &mut shutdown_rx).await;
+                    break 'connection;
+                }
+                if let Some(changes) = session.replica.take() {
+                    replication::feed(&mut stream, changes, &shared, &mut shutdown_rx).await;
+                    break 'connection;
+                }
+            }
+            
+            // A line that hasn't ended yet is refused as soon as it is too
+            // long, rather than buffered until it does
+            let limits = shared.limits();
+            if awaiting_frame.is_none() && read_buf.len() > limits.max_line() {
+                let response = limits.line_too_long();
+                let _ = stream.write_all(&response.to_bytes()).await;
+                break 'connection;
+            }
+            
+            // A pending frame is rescanned from its header once more arrives.
+            // Room for all of it is made now, so a large value is read into
+            // one allocation instead of being copied each time it outgrows one.
+            scanned = if awaiting_frame.is_some() { 0 } else { read_buf.len() };
+            let missing = awaiting_frame.map_or(0, |frame_end| frame_end - read_buf.len());
+            read_buf.reserve(missing.max(READ_BUFFER_SIZE));
+            
+            // Between commands the client may be idle; partway through one
+            // it must keep sending
+            let (limit, timed_out) = if read_buf.is_empty() {
+                (shared.runtime().idle_timeout, "idle timeout")
+            } else {
+                (shared.runtime().read_timeout, "read timeout")
+            };
+            let mut expired = false;
             
             tokio::select! {
-                // Read command from client
-                result = buf_reader.read_line(&mut line) => {
+                // Read more command bytes from client
+                result = within(limit, stream.read_buf(&mut *read_buf)) => {
                     match result {
-                        Ok(0) => {
+                        None => expired = true,
+                        Some(Ok(0)) => {
                             // Client disconnected
                             break;
                         }
-                        Ok(_) => {
-                            let response = Self::process_command(&line, &store).await;
-                            let response_bytes = response.to_bytes();
-                            
-                            if let Err(e) = writer.write_all(&response_bytes).await {
-                                eprintln!("Failed to write response: {}", e);
-                                break;
-                            }
-                            
-                            if let Err(e) = writer.flush().await {
-                                eprintln!("Failed to flush response: {}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => {
                             eprintln!("Failed to read from client: {}", e);
                             break;
                         }
@@ -156,70 +1239,856 @@ impl RustVaultServer {
                     break;
                 }
             }
+            
+            if expired {
+                // Any partial command is dropped with the connection
+                println!("Closing client {}: {}", peer, timed_out);
+                let response = Response::error(ErrorCode::Timeout, timed_out);
+                let _ = stream.write_all(&response.to_bytes()).await;
+                let _ = stream.shutdown().await;
+                break;
+            }
         }
         
         Ok(())
     }
     
-    /// Process a command from a client
-    async fn process_command(line: &str, store: &Arc<MemoryStore>) -> Response {
-        let command_bytes = line.trim().as_bytes();
+    /// Forward `subscription` to a connection that has subscribed, until it
+    /// closes or the server shuts down
+    ///
+    /// The client unsubscribes by closing the connection; anything else it
+    /// sends is ignored.
+    async fn push_events<T>(
+        stream: &mut T,
+        mut subscription: Subscription,
+        shutdown_rx: &mut broadcast::Receiver<()>,
+    ) where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut ignored = [0; 256];
+        loop {
+            let event = tokio::select! {
+                event = subscription.next() => event,
+                read = stream.read(&mut ignored) => match read {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => continue,
+                },
+                _ = shutdown_rx.recv() => return,
+            };
+            let Some(event) = event else {
+                return;
+            };
+            let sent = async {
+                stream.write_all(&Response::Event(event).to_bytes()).await?;
+                stream.flush().await
+            };
+            if let Err(e) = sent.await {
+                eprintln!("Failed to send event: {}", e);
+                return;
+            }
+        }
+    }
+    
+    /// Process a command frame from a client
+    async fn process_command(frame: &[u8], shared: &Shared<S>, session: &mut Session) -> Response {
+        // A length-prefixed value is passed through byte for byte. A single
+        // line is trimmed, keeping the CRLF after it if it has one.
+        let command_bytes = match frame.iter().position(|&b| b == b'\n') {
+            Some(end) if end + 1 < frame.len() => frame,
+            _ => {
+                let line = frame.trim_ascii();
+                let start = frame.len() - frame.trim_ascii_start().len();
+                let end = start + line.len();
+                if line.is_empty() || !frame[end..].starts_with(b"\r\n") {
+                    line
+                } else {
+                    &frame[start..end + 2]
+                }
+            }
+        };
         if command_bytes.is_empty() {
-            return Response::Error("Empty command".to_string());
+            return Response::error(ErrorCode::Parse, "Empty command");
         }
         
-        // Add \r\n if not present for parser compatibility
-        let mut full_command = command_bytes.to_vec();
-        if !full_command.ends_with(b"\r\n") && !full_command.ends_with(b"\n") {
-            full_command.extend_from_slice(b"\r\n");
-        }
+        // Add \r\n if not present for parser compatibility. A frame with a
+        // payload already ends in one, so a large value isn't copied here.
+        let full_command = if command_bytes.ends_with(b"\n") {
+            Cow::Borrowed(command_bytes)
+        } else {
+            Cow::Owned([command_bytes, b"\r\n"].concat())
+        };
         
-        match parse_command(&full_command) {
-            Ok(command) => Self::execute_command(command, store).await,
-            Err(e) => Response::Error(format!("Parse error: {}", e)),
-        }
+        Self::respond(parse_command(&full_command), shared, session).await
     }
     
-    /// Execute a parsed command
-    async fn execute_command(command: Command, store: &Arc<MemoryStore>) -> Response {
-        match command {
-            Command::Set { key, value } => {
-                match store.set(key, value).await {
-                    Ok(()) => Response::Ok,
-                    Err(e) => Response::Error(format!("SET failed: {}", e)),
-                }
+    /// [`Self::process_command`] for a frame the connection hands over
+    ///
+    /// A frame carrying a value is parsed in place, so a length-prefixed
+    /// SET's value is stored in the allocation it was read into. The frame
+    /// has to be the only handle on its buffer for that, as one split off a
+    /// buffer still holding later input isn't; such frames are copied.
+    async fn process_frame(frame: BytesMut, shared: &Shared<S>, session: &mut Session) -> Response {
+        match frame.iter().position(|&b| b == b'\n') {
+            Some(end) if end + 1 < frame.len() => {
+                Self::respond(parse_command_owned(frame.into()), shared, session).await
+            }
+            _ => Self::process_command(&frame, shared, session).await,
+        }
+    }
+    
+    /// Answer a parsed command
+    async fn respond(parsed: Result<Command>, shared: &Shared<S>, session: &mut Session) -> Response {
+        // Everything from here on sees the keys as they are stored
+        let parsed = parsed.map(|command| namespace::qualify_command(session.namespace(), command));
+        match parsed {
+            // AUTH is answered here so the token never reaches the store or
+            // the WAL
+            Ok(Command::Auth { token }) => match &shared.auth_token {
+                Some(expected) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                    session.authenticated = true;
+                    Response::Ok
+                }
+                Some(_) => Response::error(ErrorCode::NoAuth, "Invalid token"),
+                None => Response::error(ErrorCode::Invalid, "AUTH is not enabled on this server"),
+            },
+            // HELLO comes before AUTH, so a client can agree on a version
+            // before it authenticates
+            Ok(Command::Hello { version: 0 }) => Response::error(ErrorCode::Invalid, "protocol versions start at 1"),
+            Ok(Command::Hello { version }) => {
+                let version = version.min(PROTOCOL_VERSION);
+                session.protocol = Some(version);
+                Response::Hello(version)
+            }
+            Ok(_) if shared.auth_token.is_some() && !session.authenticated => {
+                Response::error(ErrorCode::NoAuth, "Authentication required")
+            }
+            // Commands that use the store wait for the replay to finish
+            Ok(ref command) if !shared.load.is_ready() && uses_store(command) => {
+                Response::error(ErrorCode::Loading, format!("{}% restored", shared.load.progress()))
+            }
+            Ok(ref command) if session.transaction.is_none() && !permitted(shared, command) => not_permitted(),
+            Ok(ref command)
+                if shared.replica && session.transaction.is_none() && command.kind() == CommandKind::Write =>
+            {
+                read_only()
+            }
+            Ok(command) if session.transaction.is_some() || is_transaction_command(&command) => {
+                Self::transaction_command(command, shared, session).await
+            }
+            Ok(Command::Select { namespace }) => {
+                shared.metrics.command("SELECT");
+                if !namespace::is_valid(&namespace) {
+                    return invalid_namespace();
+                }
+                session.namespace = Some(namespace);
+                Response::Ok
+            }
+            Ok(Command::Subscribe { pattern }) => {
+                shared.metrics.command("SUBSCRIBE");
+                session.subscription = Some(shared.events.subscribe(session.namespace(), pattern));
+                Response::Ok
+            }
+            Ok(Command::Replicate) => {
+                shared.metrics.command("REPLICATE");
+                session.replica = Some(shared.replication.subscribe());
+                Response::Ok
+            }
+            Ok(command) => Self::execute_command(command, shared, &session.peer).await,
+            Err(RustVaultError::Protocol(e)) => Response::error(ErrorCode::Parse, e),
+            Err(e) => Response::error(ErrorCode::Parse, format!("Parse error: {}", e)),
+        }
+    }
+    
+    /// Answer `MULTI`, `EXEC`, `DISCARD` and `WATCH`, and queue the
+    /// commands sent between `MULTI` and `EXEC`
+    ///
+    /// Only SET, DELETE and GET can be queued. Any other command, or one
+    /// over the size limits, is refused and makes the `EXEC` fail.
+    async fn transaction_command(command: Command, shared: &Shared<S>, session: &mut Session) -> Response {
+        shared.metrics.command(command.name());
+        let Some(transaction) = session.transaction.as_mut() else {
+            return match command {
+                Command::Multi => {
+                    session.transaction = Some(Transaction::default());
+                    Response::Ok
+                }
+                Command::Watch { keys } => {
+                    if let Some(response) = shared.limits().check(&Command::Watch { keys: keys.clone() }) {
+                        return response;
+                    }
+                    for key in keys {
+                        match shared.vault.stat(&key).await {
+                            Ok(stat) => session.watched.push((key, stat)),
+                            Err(e) => return failed("WATCH", e),
+                        }
+                    }
+                    Response::Ok
+                }
+                command => Response::error(ErrorCode::Invalid, format!("{} without MULTI", command.name())),
+            };
+        };
+        match command {
+            Command::Multi => Response::error(ErrorCode::Invalid, "MULTI calls can not be nested"),
+            Command::Watch { .. } => Response::error(ErrorCode::Invalid, "WATCH inside MULTI is not allowed"),
+            Command::Discard => {
+                session.transaction = None;
+                session.watched.clear();
+                Response::Ok
+            }
+            Command::Exec => {
+                let transaction = session.transaction.take().unwrap_or_default();
+                let watched = std::mem::take(&mut session.watched);
+                Self::exec(transaction, watched, shared).await
+            }
+            command => {
+                let refused = shared
+                    .limits()
+                    .check(&command)
+                    .or_else(|| (!permitted(shared, &command)).then(not_permitted))
+                    .or_else(|| (shared.replica && command.kind() == CommandKind::Write).then(read_only));
+                if let Some(response) = refused {
+                    transaction.failed = true;
+                    return response;
+                }
+                let op = match command {
+                    Command::Set { condition: Some(condition), .. } | Command::SetEx { condition: Some(condition), .. } => {
+                        transaction.failed = true;
+                        let message = format!("SET {} can't be queued in MULTI", condition.as_str());
+                        return Response::error(ErrorCode::Invalid, message);
+                    }
+                    Command::Set { key, value, condition: None } => BatchOp::Set { key, value, ttl: None },
+                    Command::SetEx { key, value, seconds, condition: None } => {
+                        BatchOp::Set { key, value, ttl: Some(Duration::from_secs(seconds)) }
+                    }
+                    Command::Delete { key } => BatchOp::Delete { key },
+                    Command::Get { key } => BatchOp::Get { key },
+                    command => {
+                        transaction.failed = true;
+                        let message = format!("{} can't be queued in MULTI", command.name());
+                        return Response::error(ErrorCode::Invalid, message);
+                    }
+                };
+                transaction.ops.push(op);
+                Response::Queued
+            }
+        }
+    }
+    
+    /// Apply a transaction's queued commands, unless a watched key changed
+    async fn exec(transaction: Transaction, watched: Vec<(String, Option<KeyStat>)>, shared: &Shared<S>) -> Response {
+        if transaction.failed {
+            return Response::error(ErrorCode::ExecAbort, "Transaction discarded because of previous errors");
+        }
+        let keys: Vec<String> = transaction.ops.iter().map(|op| op.key().to_string()).collect();
+        let outcomes = match shared.vault.apply_batch(watched, transaction.ops).await {
+            Ok(Some(outcomes)) => outcomes,
+            Ok(None) => return Response::Conflict,
+            Err(e) => return failed("EXEC", e),
+        };
+        let mut changes = Vec::new();
+        let mut replicated = Vec::new();
+        let responses = keys
+            .into_iter()
+            .zip(outcomes)
+            .map(|(key, outcome)| match outcome {
+                BatchOutcome::Set => {
+                    replicated.push(Change::Key(key.clone()));
+                    changes.push(KeyEvent::Set(key).into());
+                    Response::Ok
+                }
+                BatchOutcome::Deleted(true) => {
+                    replicated.push(Change::Key(key.clone()));
+                    changes.push(KeyEvent::Del(key).into());
+                    Response::Ok
+                }
+                BatchOutcome::Deleted(false) => Response::NotFound,
+                BatchOutcome::Value(value) => {
+                    shared.metrics.get(value.is_some());
+                    match value {
+                        Some(value) => Response::Value(value),
+                        None => Response::NotFound,
+                    }
+                }
+            })
+            .collect();
+        shared.events.publish(changes);
+        shared.replication.publish(replicated);
+        Response::Results(responses)
+    }
+    
+    /// Execute a parsed command, publishing the changes it made, timing it
+    /// into the latency histograms and, if it was slow, logging it for
+    /// `SLOWLOG GET`
+    async fn execute_command(command: Command, shared: &Shared<S>, peer: &str) -> Response {
+        let started = Instant::now();
+        let name = command.name();
+        // Worked out up front, since running the command consumes it
+        let changes = shared.events.changes(&command);
+        let replicated = shared.replication.changes(&command);
+        let key = shared.slowlog.key_of(&command);
+        // GETSET and GETDEL reply with the old value, and a GETSET writes
+        // even when there wasn't one
+        let getset = matches!(command, Command::GetSet { .. });
+        let response = Self::run_command(command, shared).await;
+        let elapsed = started.elapsed();
+        shared.metrics.latency(name, elapsed);
+        if shared.slowlog.is_slow(elapsed) {
+            shared.slowlog.record(name, key, elapsed, peer, wal::now_millis());
+        }
+        let changed = match response {
+            Response::Ok | Response::Integer(_) | Response::Value(_) => true,
+            Response::NotFound => getset,
+            _ => false,
+        };
+        if changed {
+            shared.events.publish(changes);
+            shared.replication.publish(replicated);
+        }
+        response
+    }
+    
+    async fn run_command(command: Command, shared: &Shared<S>) -> Response {
+        let store = shared.vault.store();
+        shared.metrics.command(command.name());
+        if let Some(response) = shared.limits().check(&command) {
+            return response;
+        }
+        match command {
+            Command::Set { key, value, condition: Some(condition) } => {
+                match store.set_if(key, value, None, condition).await {
+                    Ok(true) => Response::Ok,
+                    Ok(false) => Response::NotApplied,
+                    Err(e) => failed("SET", e),
+                }
+            }
+            Command::SetEx { key, value, seconds, condition: Some(condition) } => {
+                match store.set_if(key, value, Some(Duration::from_secs(seconds)), condition).await {
+                    Ok(true) => Response::Ok,
+                    Ok(false) => Response::NotApplied,
+                    Err(e) => failed("SET", e),
+                }
+            }
+            Command::Set { key, value, condition: None } => {
+                match store.set(key, value).await {
+                    Ok(()) => Response::Ok,
+                    Err(e) => failed("SET", e),
+                }
+            }
+            Command::SetEx { key, value, seconds, condition: None } => {
+                match store.set_with_ttl(key, value, std::time::Duration::from_secs(seconds)).await {
+                    Ok(()) => Response::Ok,
+                    Err(e) => failed("SET", e),
+                }
             }
             Command::Get { key } => {
-                match store.get(&key).await {
+                let result = store.get(&key).await;
+                if let Ok(value) = &result {
+                    shared.metrics.get(value.is_some());
+                }
+                match result {
                     Ok(Some(value)) => Response::Value(value),
                     Ok(None) => Response::NotFound,
-                    Err(e) => Response::Error(format!("GET failed: {}", e)),
+                    Err(e) => failed("GET", e),
                 }
             }
+            Command::Exists { key } => match store.exists(&key).await {
+                Ok(true) => Response::Ok,
+                Ok(false) => Response::NotFound,
+                Err(e) => failed("EXISTS", e),
+            },
+            Command::Stat { key } => match store.stat(&key).await {
+                Ok(Some(stat)) => Response::Stat {
+                    version: stat.version,
+                    created_at: stat.created_at,
+                    updated_at: stat.updated_at,
+                },
+                Ok(None) => Response::NotFound,
+                Err(e) => failed("STAT", e),
+            },
             Command::Delete { key } => {
                 match store.delete(&key).await {
                     Ok(true) => Response::Ok,
                     Ok(false) => Response::NotFound,
-                    Err(e) => Response::Error(format!("DELETE failed: {}", e)),
+                    Err(e) => failed("DELETE", e),
+                }
+            }
+            Command::Expire { key, seconds } => {
+                match store.expire(&key, std::time::Duration::from_secs(seconds)).await {
+                    Ok(true) => Response::Ok,
+                    Ok(false) => Response::NotFound,
+                    Err(e) => failed("EXPIRE", e),
+                }
+            }
+            Command::ExpireAt { key, unix_millis } => {
+                match store.expire_at(&key, unix_millis).await {
+                    Ok(true) => Response::Ok,
+                    Ok(false) => Response::NotFound,
+                    Err(e) => failed("PEXPIREAT", e),
+                }
+            }
+            Command::MSet { pairs } => match store.mset(pairs).await {
+                Ok(()) => Response::Ok,
+                Err(e) => failed("MSET", e),
+            },
+            Command::MGet { keys } => match store.mget(&keys).await {
+                Ok(values) => Response::Values(values),
+                Err(e) => failed("MGET", e),
+            },
+            Command::Incr { key, delta } => match store.incr(&key, delta).await {
+                Ok(n) => Response::Integer(n),
+                Err(e) => failed("INCR", e),
+            },
+            Command::Decr { key, delta } => {
+                let Some(delta) = delta.checked_neg() else {
+                    return Response::error(ErrorCode::Invalid, "DECR failed: Decrement would overflow");
+                };
+                match store.incr(&key, delta).await {
+                    Ok(n) => Response::Integer(n),
+                    Err(e) => failed("DECR", e),
+                }
+            }
+            Command::Append { key, value } => match store.append(&key, &value).await {
+                Ok(len) => Response::Integer(len as i64),
+                Err(e) => failed("APPEND", e),
+            },
+            Command::Strlen { key } => match store.strlen(&key).await {
+                Ok(Some(len)) => Response::Integer(len as i64),
+                Ok(None) => Response::NotFound,
+                Err(e) => failed("STRLEN", e),
+            },
+            Command::GetSet { key, value } => match store.getset(key, value).await {
+                Ok(Some(old)) => Response::Value(old),
+                Ok(None) => Response::NotFound,
+                Err(e) => failed("GETSET", e),
+            },
+            Command::GetDel { key } => match store.getdel(&key).await {
+                Ok(Some(old)) => Response::Value(old),
+                Ok(None) => Response::NotFound,
+                Err(e) => failed("GETDEL", e),
+            },
+            Command::Cas { key, expected, new } => match store.cas(key, &expected, new).await {
+                Ok(true) => Response::Ok,
+                Ok(false) => Response::Conflict,
+                Err(e) => failed("CAS", e),
+            },
+            Command::Lock { key, token, ttl_ms } => {
+                match store.lock(key, &token, Duration::from_millis(ttl_ms)).await {
+                    Ok(true) => Response::Ok,
+                    Ok(false) => Response::Conflict,
+                    Err(e) => failed("LOCK", e),
+                }
+            }
+            Command::Unlock { key, token } => match store.unlock(&key, &token).await {
+                Ok(true) => Response::Ok,
+                Ok(false) => Response::Conflict,
+                Err(e) => failed("UNLOCK", e),
+            },
+            Command::CommandInfo { name } => match command_spec(&name) {
+                Some(spec) => Response::Value(spec.kind.to_string().into_bytes()),
+                None => Response::NotFound,
+            },
+            Command::MaintenanceStatus => Response::Value(shared.maintenance.render().into_bytes()),
+            Command::Config { action: ConfigAction::Get, key } => {
+                let fields = shared.runtime().matching(&key);
+                if fields.is_empty() && !key.contains(['*', '?']) {
+                    return Response::error(ErrorCode::Invalid, format!("unknown config parameter {}", key));
+                }
+                Response::Info(fields)
+            }
+            Command::Config { action: ConfigAction::Set { value }, key } => {
+                match shared.config.write().unwrap().set(&key, &value) {
+                    Ok(()) => {
+                        println!("CONFIG SET {} {}", key, value);
+                        Response::Ok
+                    }
+                    Err(e) => Response::error(ErrorCode::Invalid, e),
                 }
             }
+            Command::Info => {
+                // The store is locked for the whole replay, so its figures
+                // are left out until then
+                let store = if shared.load.is_ready() {
+                    match Self::store_gauges(shared).await {
+                        Ok(store) => Some(store),
+                        Err(e) => return failed("INFO", e),
+                    }
+                } else {
+                    None
+                };
+                let mut report = shared.metrics.report(Self::gauges(shared), store);
+                let loading = if store.is_some() { "0" } else { "1" };
+                report.splice(1..1, [
+                    ("loading".to_string(), loading.to_string()),
+                    ("loading_progress".to_string(), shared.load.progress().to_string()),
+                ]);
+                Response::Info(report)
+            }
+            Command::Ping => Response::Pong,
+            // Refused as loading until the replay is done, like a command
+            // that reads the store
+            Command::Ready => Response::Ok,
+            Command::Checksum { prefix } => match checksum_in_namespace(&**store, &prefix).await {
+                Ok(digest) => Response::Value(format!("{:016x}", digest).into_bytes()),
+                Err(e) => failed("CHECKSUM", e),
+            },
+            Command::ChecksumRanges { buckets, prefix } => {
+                if !(1..=256).contains(&buckets) {
+                    return Response::error(ErrorCode::Invalid, "CHECKSUM RANGES takes 1 to 256 buckets");
+                }
+                match checksum_ranges_in_namespace(&**store, buckets, &prefix).await {
+                    Ok(digests) => {
+                        let digests: Vec<String> =
+                            digests.iter().map(|digest| format!("{:016x}", digest)).collect();
+                        Response::Value(digests.join(" ").into_bytes())
+                    }
+                    Err(e) => failed("CHECKSUM", e),
+                }
+            }
+            Command::Scan { prefix, cursor, count } => {
+                if !(1..=MAX_SCAN_COUNT).contains(&count) {
+                    return Response::error(ErrorCode::Invalid, format!("SCAN takes a count of 1 to {}", MAX_SCAN_COUNT));
+                }
+                match store.scan(&prefix, cursor, count).await {
+                    Ok(page) => {
+                        // A scan of the default namespace finds every other
+                        // namespace's keys too
+                        let (namespace, _) = namespace::split(&prefix);
+                        let keys = page
+                            .keys
+                            .iter()
+                            .filter(|key| namespace::contains(namespace, key))
+                            .map(|key| namespace::split(key).1.to_string())
+                            .collect();
+                        Response::Keys { keys, cursor: page.cursor }
+                    }
+                    Err(e) => failed("SCAN", e),
+                }
+            }
+            Command::Auth { .. } | Command::Hello { .. } => {
+                unreachable!("AUTH and HELLO are answered by process_command")
+            }
+            Command::Subscribe { .. } => {
+                unreachable!("SUBSCRIBE is answered by process_command")
+            }
+            Command::Multi | Command::Exec | Command::Discard | Command::Watch { .. } => {
+                unreachable!("transactions are answered by process_command")
+            }
+            Command::Replicate => {
+                unreachable!("REPLICATE is answered by process_command")
+            }
+            Command::Select { .. } => {
+                unreachable!("SELECT is answered by process_command")
+            }
+            Command::FlushAll if !shared.allow_flush_all => {
+                Response::error(ErrorCode::NotPermitted, "command disabled")
+            }
+            Command::FlushAll => match store.clear().await {
+                Ok(()) => {
+                    println!("FLUSHALL removed every key");
+                    Response::Ok
+                }
+                Err(e) => failed("FLUSHALL", e),
+            },
+            Command::FlushDb { .. } if !shared.allow_flush_all => {
+                Response::error(ErrorCode::NotPermitted, "command disabled")
+            }
+            Command::FlushDb { namespace: Some(namespace) } if namespace::is_valid(&namespace) => {
+                match store.clear_namespace(&namespace).await {
+                    Ok(()) => {
+                        println!("FLUSHDB removed every key in namespace {}", namespace);
+                        Response::Ok
+                    }
+                    Err(e) => failed("FLUSHDB", e),
+                }
+            }
+            Command::DbSize { namespace: Some(namespace) } if namespace::is_valid(&namespace) => {
+                match store.namespace_len(&namespace).await {
+                    Ok(len) => Response::Integer(len as i64),
+                    Err(e) => failed("DBSIZE", e),
+                }
+            }
+            Command::FlushDb { .. } | Command::DbSize { .. } => invalid_namespace(),
+            // A pattern that matches every key is a FLUSHDB by another name
+            Command::DeletePattern { pattern }
+                if !shared.allow_flush_all && glob_is_wildcard(namespace::split(&pattern).1.as_bytes()) =>
+            {
+                Response::error(ErrorCode::NotPermitted, "DELPAT of every key is disabled, as FLUSHALL is")
+            }
+            Command::DeletePattern { pattern } => match store.delete_pattern(&pattern).await {
+                Ok(removed) => {
+                    let count = removed.len();
+                    shared.events.publish(shared.events.deleted(&removed));
+                    shared.replication.publish(removed.into_iter().map(Change::Key).collect());
+                    Response::Integer(count as i64)
+                }
+                Err(e) => failed("DELPAT", e),
+            },
+            Command::StatsReset => {
+                shared.metrics.reset_latencies();
+                Response::Ok
+            }
+            Command::SlowLogGet { count } => {
+                let entries = shared.slowlog.latest(count.unwrap_or(slowlog::DEFAULT_GET_COUNT));
+                Response::Info(entries.iter().map(|entry| (entry.id.to_string(), entry.render())).collect())
+            }
+            Command::SlowLogReset => {
+                shared.slowlog.reset();
+                Response::Ok
+            }
+            Command::Shrink => {
+                let report = store.shrink().await;
+                println!(
+                    "Shrunk store from ~{} to ~{} bytes",
+                    report.before, report.after
+                );
+                Response::Integer(report.reclaimed() as i64)
+            }
+            Command::Compact => match shared.vault.compact().await {
+                Ok(report) => {
+                    println!(
+                        "Compacted WAL from {} to {} bytes ({} keys)",
+                        report.before, report.after, report.keys
+                    );
+                    Response::Compacted { before: report.before, after: report.after, keys: report.keys }
+                }
+                Err(e) => failed("COMPACT", e),
+            },
+            Command::WalStats => match shared.vault.wal() {
+                Some(wal) => Response::Info(wal.stats().fields()),
+                None => Response::error(ErrorCode::Invalid, "WALSTATS needs a WAL, and the server has none"),
+            },
+            Command::Backup { path } => match shared.vault.backup(&path).await {
+                Ok(count) => {
+                    println!("Backed up {} keys to {}", count, path);
+                    Response::Integer(count as i64)
+                }
+                Err(e) => failed("BACKUP", e),
+            },
+            Command::Restore { path, merge } => match shared.vault.restore_backup(&path, merge).await {
+                Ok(loaded) => {
+                    println!("Restored {} keys from {}", loaded.len(), path);
+                    let count = loaded.len();
+                    shared.events.publish(shared.events.set(&loaded));
+                    shared.replication.publish(loaded.into_iter().map(Change::Key).collect());
+                    Response::Integer(count as i64)
+                }
+                Err(e) => failed("RESTORE", e),
+            },
         }
     }
     
     /// Trigger graceful shutdown
     pub fn shutdown(&self) -> Result<()> {
-        self.shutdown_tx.send(()).map_err(|_| {
+        self.shared.shutdown_tx.send(()).map_err(|_| {
             RustVaultError::Server("Failed to send shutdown signal".to_string())
         })?;
         Ok(())
     }
 }
 
+/// Reply for a command the store failed to carry out
+fn failed(command: &str, e: RustVaultError) -> Response {
+    match e {
+        // The server stays up read-only while the WAL can't be written
+        RustVaultError::Persistence(detail) => Response::error(ErrorCode::Persistence, detail),
+        RustVaultError::OutOfMemory => Response::error(ErrorCode::OutOfMemory, e),
+        RustVaultError::CompactionInProgress => Response::error(ErrorCode::Busy, e),
+        // A backup that is damaged, or would land on existing keys
+        e @ (RustVaultError::InvalidCommand(_) | RustVaultError::Backup(_)) => {
+            Response::error(ErrorCode::Invalid, format!("{} failed: {}", command, e))
+        }
+        e => Response::error(ErrorCode::Internal, format!("{} failed: {}", command, e)),
+    }
+}
+
+/// Digest of the keys under the stored prefix `prefix`, leaving out every
+/// other namespace's when it is the whole default namespace
+///
+/// Only keys outside the default namespace start with the marker, and entry
+/// digests are summed, so their share can be taken back out.
+async fn checksum_in_namespace<S: Store>(store: &S, prefix: &str) -> Result<u64> {
+    let digest = store.checksum(prefix).await?;
+    if !prefix.is_empty() {
+        return Ok(digest);
+    }
+    Ok(digest.wrapping_sub(store.checksum(namespace::MARKER).await?))
+}
+
+/// [`checksum_in_namespace`] split into ranges, as `CHECKSUM RANGES` splits
+/// it
+async fn checksum_ranges_in_namespace<S: Store>(store: &S, buckets: usize, prefix: &str) -> Result<Vec<u64>> {
+    let mut digests = store.checksum_ranges(buckets, prefix).await?;
+    if prefix.is_empty() {
+        let bucket = namespace::MARKER.as_bytes()[0] as usize * buckets / 256;
+        digests[bucket] = digests[bucket].wrapping_sub(store.checksum(namespace::MARKER).await?);
+    }
+    Ok(digests)
+}
+
+/// Run `future`, giving up after `limit` if there is one
+async fn within<F: std::future::Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// State a connection carries between its commands
+#[derive(Debug, Default)]
+struct Session {
+    /// Address of the client, for the slow log
+    peer: String,
+    /// Whether the connection has sent the right `AUTH` token
+    authenticated: bool,
+    /// Set by `SUBSCRIBE`; the connection only carries events from then on
+    subscription: Option<Subscription>,
+    /// Set by `REPLICATE`; the connection only carries changes from then on
+    replica: Option<broadcast::Receiver<Change>>,
+    /// Set by `MULTI` until the `EXEC` or `DISCARD` that ends it
+    transaction: Option<Transaction>,
+    /// The stats `WATCH` saw, which must be unchanged for `EXEC` to apply
+    watched: Vec<(String, Option<KeyStat>)>,
+    /// Set by `SELECT`; `None` until then, for the default namespace
+    namespace: Option<String>,
+    /// Set by `HELLO`; `None` until then, for version 1
+    protocol: Option<u32>,
+}
+
+impl Session {
+    /// The namespace the connection's keys are in
+    fn namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(namespace::DEFAULT)
+    }
+    
+    /// The protocol version the connection's responses are encoded in
+    fn protocol(&self) -> u32 {
+        self.protocol.unwrap_or(1)
+    }
+}
+
+/// The commands a connection has queued since `MULTI`
+#[derive(Debug, Default)]
+struct Transaction {
+    ops: Vec<BatchOp>,
+    /// A command couldn't be queued, so `EXEC` must refuse to apply the rest
+    failed: bool,
+}
+
+/// The answer to a write sent to a replica
+fn read_only() -> Response {
+    Response::error(ErrorCode::ReadOnly, "read only replica")
+}
+
+/// Whether the server's `allowed_commands` and `read_only` setting let
+/// `command` through
+fn permitted<S>(shared: &Shared<S>, command: &Command) -> bool {
+    if let Some(allowed) = &shared.allowed_commands {
+        if !allowed.contains(command.name()) {
+            return false;
+        }
+    }
+    !(command.kind() == CommandKind::Write && shared.runtime().read_only)
+}
+
+/// The answer to a command `permitted` refuses
+fn not_permitted() -> Response {
+    Response::error(ErrorCode::NotPermitted, "command not permitted")
+}
+
+/// The table's spelling of each verb in `names`, skipping any that aren't
+/// commands
+fn allowed_verbs(names: &HashSet<String>) -> HashSet<&'static str> {
+    names
+        .iter()
+        .filter_map(|name| match command_spec(name) {
+            Some(spec) => Some(spec.name),
+            None => {
+                eprintln!("Ignoring unknown command {:?} in allowed_commands", name);
+                None
+            }
+        })
+        .collect()
+}
+
+fn invalid_namespace() -> Response {
+    Response::error(
+        ErrorCode::Invalid,
+        format!("invalid namespace: use 1 to {} letters, digits, '_' or '-'", namespace::MAX_NAME_LEN),
+    )
+}
+
+fn is_transaction_command(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Multi | Command::Exec | Command::Discard | Command::Watch { .. }
+    )
+}
+
+/// Compare `a` and `b` in time that depends only on their lengths, so a
+/// client guessing the auth token can't learn it a byte at a time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b)
+        .fold(0u8, |diff, (x, y)| std::hint::black_box(diff | (x ^ y)))
+        == 0
+}
+
+/// Whether answering `command` needs the restored store
+fn uses_store(command: &Command) -> bool {
+    !matches!(
+        command,
+        Command::Ping
+            | Command::Info
+            | Command::CommandInfo { .. }
+            | Command::MaintenanceStatus
+            | Command::StatsReset
+            | Command::SlowLogGet { .. }
+            | Command::SlowLogReset
+            | Command::Config { .. }
+            | Command::Subscribe { .. }
+            | Command::Select { .. }
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::SetCondition;
+    use crate::store::MemoryStore;
+    use crate::wal::WriteAheadLog;
     use tempfile::NamedTempFile;
-
+    
+    /// Connection-side state around `store`, already past its replay
+    fn shared_for<S: Store>(store: Arc<S>) -> Shared<S> {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let shared = Shared {
+            vault: Vault::around(store),
+            buf_pool: Arc::new(BufPool::default()),
+            load: LoadState::default(),
+            conns: Arc::new(ConnTable::default()),
+            conn_limit: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            conn_limit_action: ConnectionLimitAction::Reject,
+            rate_limiter: RateLimiter::new(None, None, RateLimitMode::Delay),
+            metrics: Metrics::default(),
+            slowlog: SlowLog::new(None, 0, 0),
+            config: RwLock::new(RuntimeConfig::new(&ServerConfig::default())),
+            events: Events::default(),
+            replication: ChangeFeed::default(),
+            replica: false,
+            allowed_commands: None,
+            maintenance: Arc::new(StatusTable::default()),
+            shutdown_tx,
+            auth_token: None,
+            allow_flush_all: false,
+            command_delay: None,
+        };
+        shared.load.mark_ready();
+        shared
+    }
+    
     #[tokio::test]
     async fn test_server_creation() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -227,6 +2096,7 @@ mod tests {
             bind_addr: "127.0.0.1:0".to_string(), // Use port 0 for testing
             wal_path: temp_file.path().to_string_lossy().to_string(),
             max_connections: 10,
+            ..Default::default()
         };
         
         let server = RustVaultServer::new(config).await.unwrap();
@@ -234,26 +2104,1136 @@ mod tests {
         let _ = server.shutdown();
     }
     
+    #[tokio::test]
+    async fn test_bad_and_taken_addresses_are_refused() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let config = |bind_addr: &str| ServerConfig {
+            bind_addr: bind_addr.to_string(),
+            wal_path: temp_file.path().to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        for bad in ["localhost", "127.0.0.1:65536", ":8080", "unix://", "::1:8080"] {
+            let result = RustVaultServer::new(config(bad)).await;
+            assert!(matches!(result, Err(RustVaultError::InvalidAddress(_))), "{}", bad);
+        }
+        let metrics = ServerConfig {
+            metrics_addr: Some("unix:///tmp/metrics.sock".to_string()),
+            ..config("127.0.0.1:0")
+        };
+        assert!(matches!(metrics.validate(), Err(RustVaultError::InvalidAddress(_))));
+        
+        // The same port can't be bound twice
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        let server = RustVaultServer::new(config(&addr)).await.unwrap();
+        match server.run().await {
+            Err(RustVaultError::BindFailed { addr: failed, source }) => {
+                assert_eq!(failed, addr);
+                assert_eq!(source.kind(), io::ErrorKind::AddrInUse);
+            }
+            other => panic!("expected BindFailed, got {:?}", other),
+        }
+    }
+    
     #[tokio::test]
     async fn test_command_processing() {
         let temp_file = NamedTempFile::new().unwrap();
-        let wal = Arc::new(WriteAheadLog::new(temp_file.path()).unwrap());
-        let store = Arc::new(MemoryStore::with_wal(wal));
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let shared = shared_for(Arc::new(MemoryStore::with_wal(wal)));
+        let mut session = Session::default();
         
         // Test SET command
-        let response = RustVaultServer::process_command("SET key1 value1", &store).await;
+        let response = RustVaultServer::process_command(b"SET key1 value1", &shared, &mut session).await;
         assert_eq!(response, Response::Ok);
         
         // Test GET command
-        let response = RustVaultServer::process_command("GET key1", &store).await;
-        assert_eq!(response, Response::Value("value1".to_string()));
+        let response = RustVaultServer::process_command(b"GET key1", &shared, &mut session).await;
+        assert_eq!(response, Response::Value(b"value1".to_vec()));
         
         // Test DELETE command
-        let response = RustVaultServer::process_command("DELETE key1", &store).await;
+        let response = RustVaultServer::process_command(b"DELETE key1", &shared, &mut session).await;
         assert_eq!(response, Response::Ok);
         
         // Test GET after DELETE
-        let response = RustVaultServer::process_command("GET key1", &store).await;
+        let response = RustVaultServer::process_command(b"GET key1", &shared, &mut session).await;
+        assert_eq!(response, Response::NotFound);
+        
+        let response = RustVaultServer::process_command(b"MAINTENANCE STATUS", &shared, &mut session).await;
+        assert_eq!(response, Response::Value(b"no jobs scheduled".to_vec()));
+    }
+    
+    #[tokio::test]
+    async fn test_flush_all_needs_enabling() {
+        let mut shared = shared_for(Arc::new(MemoryStore::new()));
+        let mut session = Session::default();
+        RustVaultServer::process_command(b"SET key1 value1", &shared, &mut session).await;
+        
+        let response = RustVaultServer::process_command(b"FLUSHALL", &shared, &mut session).await;
+        assert_eq!(response, Response::error(ErrorCode::NotPermitted, "command disabled"));
+        assert_eq!(shared.vault.len().await.unwrap(), 1);
+        
+        shared.allow_flush_all = true;
+        let response = RustVaultServer::process_command(b"FLUSHALL", &shared, &mut session).await;
+        assert_eq!(response, Response::Ok);
+        assert_eq!(shared.vault.len().await.unwrap(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_size_limits() {
+        let mut shared = shared_for(Arc::new(MemoryStore::new()));
+        shared.config.get_mut().unwrap().max_key_bytes = 4;
+        shared.config.get_mut().unwrap().max_value_bytes = 8;
+        let mut session = Session::default();
+        let key_error = Response::error(ErrorCode::TooLarge, "key too large (max 4)");
+        let value_error = Response::error(ErrorCode::TooLarge, "value too large (max 8)");
+        
+        let response = RustVaultServer::process_command(b"SET abcd 12345678", &shared, &mut session).await;
+        assert_eq!(response, Response::Ok);
+        let response = RustVaultServer::process_command(b"SET abcde 1", &shared, &mut session).await;
+        assert_eq!(response, key_error);
+        let response = RustVaultServer::process_command(b"SET abcd 123456789", &shared, &mut session).await;
+        assert_eq!(response, value_error);
+        let response = RustVaultServer::process_command(b"CAS abcd 12345678 123456789", &shared, &mut session).await;
+        assert_eq!(response, value_error);
+        let response = RustVaultServer::process_command(b"MSET a 1 b 123456789", &shared, &mut session).await;
+        assert_eq!(response, value_error);
+        let response = RustVaultServer::process_command(b"MGET a abcde", &shared, &mut session).await;
+        assert_eq!(response, key_error);
+        let mut mset = b"MSET".to_vec();
+        for i in 0..=MAX_MSET_PAIRS {
+            mset.extend_from_slice(format!(" k{} v", i).as_bytes());
+        }
+        let response = RustVaultServer::process_command(&mset, &shared, &mut session).await;
+        assert_eq!(response, Response::error(ErrorCode::TooLarge, format!("too many pairs (max {})", MAX_MSET_PAIRS)));
+        let response = RustVaultServer::process_command(b"GET abcde", &shared, &mut session).await;
+        assert_eq!(response, key_error);
+        
+        // Nothing refused reached the store
+        let response = RustVaultServer::process_command(b"GET abcd", &shared, &mut session).await;
+        assert_eq!(response, Response::Value(b"12345678".to_vec()));
+        assert_eq!(shared.vault.len().await.unwrap(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_auth_gates_commands() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let mut shared = shared_for(Arc::new(MemoryStore::with_wal(wal)));
+        shared.auth_token = Some("s3cr3t".to_string());
+        let mut session = Session::default();
+        let noauth = Response::error(ErrorCode::NoAuth, "Authentication required");
+        
+        let response = RustVaultServer::process_command(b"SET key1 value1", &shared, &mut session).await;
+        assert_eq!(response, noauth);
+        let response = RustVaultServer::process_command(b"AUTH wrong", &shared, &mut session).await;
+        assert_eq!(response, Response::error(ErrorCode::NoAuth, "Invalid token"));
+        let response = RustVaultServer::process_command(b"GET key1", &shared, &mut session).await;
+        assert_eq!(response, noauth);
+        
+        let response = RustVaultServer::process_command(b"AUTH s3cr3t", &shared, &mut session).await;
+        assert_eq!(response, Response::Ok);
+        let response = RustVaultServer::process_command(b"SET key1 value1", &shared, &mut session).await;
+        assert_eq!(response, Response::Ok);
+        
+        // Other connections are still locked out, and no AUTH was logged
+        let response =
+            RustVaultServer::process_command(b"GET key1", &shared, &mut Session::default()).await;
+        assert_eq!(response, noauth);
+        let logged = std::fs::read_to_string(temp_file.path()).unwrap();
+        assert_eq!(logged.lines().count(), 1);
+        assert!(!logged.contains("s3cr3t"));
+        
+        assert!(constant_time_eq(b"s3cr3t", b"s3cr3t"));
+        assert!(!constant_time_eq(b"s3cr3t", b"s3cr3T"));
+        assert!(!constant_time_eq(b"s3cr3t", b"s3cr3"));
+    }
+    
+    #[tokio::test]
+    async fn test_shrink_command() {
+        let shared = shared_for(Arc::new(MemoryStore::new()));
+        let store = shared.vault.store();
+        let mut session = Session::default();
+        
+        for i in 0..500 {
+            store.set(format!("key{}", i), b"value".to_vec()).await.unwrap();
+        }
+        for i in 0..490 {
+            store.delete(&format!("key{}", i)).await.unwrap();
+        }
+        
+        match RustVaultServer::process_command(b"SHRINK", &shared, &mut session).await {
+            Response::Integer(reclaimed) => assert!(reclaimed > 0),
+            other => panic!("expected an integer, got {:?}", other),
+        }
+        assert_eq!(store.len().await.unwrap(), 10);
+    }
+    
+    #[tokio::test]
+    async fn test_commands_are_timed_per_verb() {
+        let shared = shared_for(Arc::new(MemoryStore::new()));
+        let mut session = Session::default();
+        RustVaultServer::process_command(b"SET key value", &shared, &mut session).await;
+        for _ in 0..1000 {
+            RustVaultServer::process_command(b"GET key", &shared, &mut session).await;
+        }
+        
+        let get = shared.metrics.latency_summary("GET").unwrap();
+        assert_eq!(get.count, 1000);
+        assert!(get.p50 <= get.p99 && get.p99 <= get.max, "{:?}", get);
+        assert_eq!(shared.metrics.latency_summary("SET").unwrap().count, 1);
+        let Response::Info(fields) = RustVaultServer::process_command(b"INFO", &shared, &mut session).await else {
+            panic!("INFO didn't reply with fields");
+        };
+        assert!(fields.contains(&("latency_get_count".to_string(), "1000".to_string())));
+        assert!(fields.iter().any(|(name, _)| name == "latency_get_p99_us"));
+        
+        assert_eq!(RustVaultServer::process_command(b"STATS RESET", &shared, &mut session).await, Response::Ok);
+        assert_eq!(shared.metrics.latency_summary("GET").unwrap().count, 0);
+    }
+    
+    /// Store that logs each call before handing it to a `MemoryStore`, and
+    /// keeps the trait's defaults for everything else
+    #[derive(Default)]
+    struct RecordingStore {
+        inner: MemoryStore,
+        calls: std::sync::Mutex<Vec<String>>,
+        /// Artificial delay in `set` and `get`, to make them slow
+        delay: Option<Duration>,
+    }
+    
+    impl RecordingStore {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+        
+        async fn stall(&self) {
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+    
+    impl Store for RecordingStore {
+        async fn set(&self, key: String, value: Vec<u8>) -> Result<()> {
+            self.record(format!("set {}", key));
+            self.stall().await;
+            self.inner.set(key, value).await
+        }
+        
+        async fn set_with_ttl(&self, key: String, value: Vec<u8>, ttl: Duration) -> Result<()> {
+            self.record(format!("set_with_ttl {}", key));
+            self.inner.set_with_ttl(key, value, ttl).await
+        }
+        
+        async fn set_if(&self, key: String, value: Vec<u8>, ttl: Option<Duration>, condition: SetCondition) -> Result<bool> {
+            self.record(format!("set_if {}", key));
+            self.inner.set_if(key, value, ttl, condition).await
+        }
+        
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.record(format!("get {}", key));
+            self.stall().await;
+            self.inner.get(key).await
+        }
+        
+        async fn mset(&self, pairs: Vec<(String, Vec<u8>)>) -> Result<()> {
+            self.record("mset".to_string());
+            self.inner.mset(pairs).await
+        }
+        
+        async fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>> {
+            self.record("mget".to_string());
+            self.inner.mget(keys).await
+        }
+        
+        async fn delete(&self, key: &str) -> Result<bool> {
+            self.record(format!("delete {}", key));
+            self.inner.delete(key).await
+        }
+        
+        async fn cas(&self, key: String, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+            self.record(format!("cas {}", key));
+            self.inner.cas(key, expected, new).await
+        }
+        
+        async fn incr(&self, key: &str, delta: i64) -> Result<i64> {
+            self.record(format!("incr {}", key));
+            self.inner.incr(key, delta).await
+        }
+        
+        async fn append(&self, key: &str, value: &[u8]) -> Result<usize> {
+            self.record(format!("append {}", key));
+            self.inner.append(key, value).await
+        }
+        
+        async fn getset(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+            self.record(format!("getset {}", key));
+            self.inner.getset(key, value).await
+        }
+        
+        async fn getdel(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.record(format!("getdel {}", key));
+            self.inner.getdel(key).await
+        }
+        
+        async fn lock(&self, key: String, token: &str, ttl: Duration) -> Result<bool> {
+            self.record(format!("lock {}", key));
+            self.inner.lock(key, token, ttl).await
+        }
+        
+        async fn unlock(&self, key: &str, token: &str) -> Result<bool> {
+            self.record(format!("unlock {}", key));
+            self.inner.unlock(key, token).await
+        }
+        
+        async fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+            self.record(format!("expire {}", key));
+            self.inner.expire(key, ttl).await
+        }
+        
+        async fn exists(&self, key: &str) -> Result<bool> {
+            self.record(format!("exists {}", key));
+            self.inner.exists(key).await
+        }
+        
+        async fn get_all(&self) -> Result<Vec<(String, Vec<u8>)>> {
+            self.record("get_all".to_string());
+            self.inner.get_all().await
+        }
+        
+        async fn scan(&self, prefix: &str, cursor: u64, count: usize) -> Result<crate::store::ScanPage> {
+            self.record(format!("scan {}", prefix));
+            self.inner.scan(prefix, cursor, count).await
+        }
+        
+        async fn clear(&self) -> Result<()> {
+            self.record("clear".to_string());
+            self.inner.clear().await
+        }
+        
+        async fn len(&self) -> Result<usize> {
+            self.record("len".to_string());
+            self.inner.len().await
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_serves_any_store() {
+        let server = Arc::new(RustVaultServer::with_store(
+            ServerConfig::default(),
+            RecordingStore::default(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        while !server.is_ready() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Startup counts what was restored
+        assert_eq!(server.shared.vault.calls(), ["len"]);
+        
+        let mut client = crate::client::Client::connect(&addr).await.unwrap();
+        client.set("key1", "value1").await.unwrap();
+        assert_eq!(client.get("key1").await.unwrap(), Some("value1".to_string()));
+        assert!(client.delete("key1").await.unwrap());
+        client.set("key2", "value2").await.unwrap();
+        assert!(client.expire("key2", 3600).await.unwrap());
+        
+        // Defaults built on the required methods agree with MemoryStore's own
+        let expected = MemoryStore::new();
+        expected.set("key2".to_string(), b"value2".to_vec()).await.unwrap();
+        assert_eq!(client.checksum("").await.unwrap(), expected.checksum("").await.unwrap());
+        // The second digest is of other namespaces' keys, to take back out
+        assert_eq!(
+            server.shared.vault.calls()[1..],
+            ["set key1", "get key1", "delete key1", "set key2", "expire key2", "get_all", "get_all"]
+        );
+        
+        // No WAL: nothing to report and nothing to sync on the way out
+        assert_eq!(server.persistence_failures(), 0);
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_slow_commands_are_logged_without_values() {
+        let config = ServerConfig {
+            slowlog_threshold: Some(Duration::from_millis(50)),
+            slowlog_max_key_len: 8,
+            ..Default::default()
+        };
+        let store = RecordingStore {
+            delay: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let server = Arc::new(RustVaultServer::with_store(config, store));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        while !server.is_ready() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        
+        let mut client = crate::client::Client::connect(&addr).await.unwrap();
+        client.select("app").await.unwrap();
+        client.set("user:1:profile", "secret value").await.unwrap();
+        assert_eq!(client.get("user:1:profile").await.unwrap(), Some("secret value".to_string()));
+        client.ping().await.unwrap();
+        assert!(client.exists("user:1:profile").await.unwrap());
+        
+        let entries = client.slowlog_get(10).await.unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.command.as_str()).collect::<Vec<_>>(), ["GET", "SET"]);
+        for entry in &entries {
+            assert_eq!(entry.key.as_deref(), Some("user:1:p"));
+            assert!(entry.duration >= Duration::from_millis(100), "{:?}", entry.duration);
+            assert!(entry.duration < Duration::from_secs(10), "{:?}", entry.duration);
+            assert!(entry.client.starts_with("127.0.0.1:"), "{}", entry.client);
+            assert!(!entry.render().contains("secret"), "{}", entry.render());
+        }
+        assert!(entries[0].id > entries[1].id && entries[0].timestamp >= entries[1].timestamp);
+        assert_eq!(client.slowlog_get(1).await.unwrap(), entries[..1]);
+        
+        client.slowlog_reset().await.unwrap();
+        assert!(client.slowlog_get(10).await.unwrap().is_empty());
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    /// Serve `config` on an ephemeral port, with a fresh WAL
+    async fn start_server(
+        config: ServerConfig,
+    ) -> (Arc<RustVaultServer>, tokio::task::JoinHandle<Result<()>>, String, NamedTempFile) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let config = ServerConfig {
+            wal_path: temp_file.path().to_string_lossy().to_string(),
+            ..config
+        };
+        let server = Arc::new(RustVaultServer::new(config).await.unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        while !server.is_ready() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        (server, server_task, addr, temp_file)
+    }
+    
+    /// Wait for the server to notice connections opening or closing
+    async fn wait_for_connections(server: &RustVaultServer, expected: usize) {
+        for _ in 0..100 {
+            if server.stats().connections == expected {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("expected {} connections, have {}", expected, server.stats().connections);
+    }
+    
+    #[tokio::test]
+    async fn test_connection_limit_rejects() {
+        let config = ServerConfig {
+            max_connections: 2,
+            connection_limit_action: ConnectionLimitAction::Reject,
+            ..Default::default()
+        };
+        let (server, server_task, addr, _wal) = start_server(config).await;
+        
+        let mut first = crate::Client::connect(&addr).await.unwrap();
+        let mut second = crate::Client::connect(&addr).await.unwrap();
+        first.set("key", "value").await.unwrap();
+        second.get("key").await.unwrap();
+        assert_eq!(server.stats().connections, 2);
+        
+        // The refusal answers the client's HELLO, so it fails to connect
+        assert!(matches!(
+            crate::Client::connect(&addr).await,
+            Err(RustVaultError::Remote { code: ErrorCode::Busy, message }) if message == "server busy"
+        ));
+        assert_eq!(server.stats().rejected_connections, 1);
+        
+        // A closed connection frees its slot
+        first.close().await.unwrap();
+        wait_for_connections(&server, 1).await;
+        let mut fourth = crate::Client::connect(&addr).await.unwrap();
+        assert_eq!(fourth.get("key").await.unwrap(), Some("value".to_string()));
+        assert_eq!(server.stats(), ServerStats { connections: 2, rejected_connections: 1 });
+        
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_connection_limit_queues() {
+        let config = ServerConfig {
+            max_connections: 1,
+            connection_limit_action: ConnectionLimitAction::Queue,
+            ..Default::default()
+        };
+        let (server, server_task, addr, _wal) = start_server(config).await;
+        
+        let mut first = crate::Client::connect(&addr).await.unwrap();
+        first.set("key", "value").await.unwrap();
+        
+        // The second client is left waiting rather than turned away
+        let queued = tokio::spawn({
+            let addr = addr.clone();
+            async move { crate::Client::connect(&addr).await?.get("key").await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!queued.is_finished());
+        assert_eq!(server.stats(), ServerStats { connections: 1, rejected_connections: 0 });
+        
+        first.close().await.unwrap();
+        assert_eq!(queued.await.unwrap().unwrap(), Some("value".to_string()));
+        
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let (server, server_task, addr, _wal) = start_server(config).await;
+        
+        let mut silent = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        
+        // A client that keeps issuing commands is never idle for long
+        let mut busy = crate::Client::connect(&addr).await.unwrap();
+        for i in 0..10 {
+            busy.set("key", &i.to_string()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(busy.get("key").await.unwrap(), Some("9".to_string()));
+        assert_eq!(server.stats().connections, 1);
+        
+        // ...while the silent one was told why and disconnected
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), silent.read_to_end(&mut received));
+        read.await.unwrap().unwrap();
+        assert_eq!(received, b"ERROR ERR_TIMEOUT idle timeout\r\n");
+        
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_oversized_frames_close_the_connection() {
+        let config = ServerConfig {
+            max_key_bytes: 16,
+            max_value_bytes: 1024,
+            ..Default::default()
+        };
+        let (server, server_task, addr, _wal) = start_server(config).await;
+        let max_line = 16 + 1024 + LINE_OVERHEAD;
+        
+        // A line that never ends is refused once it outgrows the limit
+        let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(&vec![b'x'; max_line + 1]).await.unwrap();
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received));
+        read.await.unwrap().unwrap();
+        assert_eq!(received, format!("ERROR ERR_TOO_LARGE line too long (max {})\r\n", max_line).as_bytes());
+        
+        // A payload over the limit is refused from its header alone
+        let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(b"SET key $1025\r\n").await.unwrap();
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received));
+        read.await.unwrap().unwrap();
+        assert_eq!(received, b"ERROR ERR_TOO_LARGE value too large (max 1024)\r\n");
+        
+        // So are values each within the limit that add up to more than two
+        // of them
+        let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(b"MSET a $1024 b $1024 c $1\r\n").await.unwrap();
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received));
+        read.await.unwrap().unwrap();
+        assert_eq!(received, b"ERROR ERR_TOO_LARGE values too large together (max 2048)\r\n");
+        
+        // One exactly at the limit is stored
+        let mut client = crate::Client::connect(&addr).await.unwrap();
+        let value = vec![b'v'; 1024];
+        client.set_bytes("key", &value).await.unwrap();
+        assert_eq!(client.get_bytes("key").await.unwrap(), Some(value));
+        
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_read_timeout_drops_partial_command() {
+        let config = ServerConfig {
+            read_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let (server, server_task, addr, _wal) = start_server(config).await;
+        
+        let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(b"SET key $10\r\nhalf").await.unwrap();
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received));
+        read.await.unwrap().unwrap();
+        assert_eq!(received, b"ERROR ERR_TIMEOUT read timeout\r\n");
+        
+        // An idle connection isn't subject to the read timeout
+        let mut client = crate::Client::connect(&addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(client.get("key").await.unwrap(), None);
+        
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_write() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let config = ServerConfig {
+            wal_path: temp_file.path().to_string_lossy().to_string(),
+            wal_sync: SyncPolicy::Never,
+            ..Default::default()
+        };
+        let mut server = RustVaultServer::new(config).await.unwrap();
+        Arc::get_mut(&mut server.shared).unwrap().command_delay = Some(Duration::from_millis(300));
+        let server = Arc::new(server);
+        
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        while !server.is_ready() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        
+        // Shut down while the SET is still running
+        let mut client = crate::Client::connect(&addr).await.unwrap();
+        let write = tokio::spawn(async move { client.set("key", "value").await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+        
+        // By the time the server has stopped the write is in the WAL and
+        // was answered, and nothing new is accepted
+        let restored = MemoryStore::new();
+        restored.restore_from_path(temp_file.path()).await.unwrap();
+        assert_eq!(restored.get("key").await.unwrap(), Some(b"value".to_vec()));
+        write.await.unwrap().unwrap();
+        assert!(crate::Client::connect(&addr).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_watchdog_kills_hung_command() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let config = ServerConfig {
+            wal_path: temp_file.path().to_string_lossy().to_string(),
+            hung_command_threshold_secs: Some(1),
+            hung_command_action: HungCommandAction::Kill,
+            ..Default::default()
+        };
+        let mut server = RustVaultServer::new(config).await.unwrap();
+        Arc::get_mut(&mut server.shared).unwrap().command_delay =
+            Some(std::time::Duration::from_secs(3));
+        let server = Arc::new(server);
+        
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        while !server.is_ready() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        
+        // The connection is closed before the command would have finished.
+        // A raw one, as the delay would hold up a client's HELLO as well.
+        let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let started = std::time::Instant::now();
+        stream.write_all(b"GET key\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+        assert_eq!(server.hung_commands(), 1);
+        
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_commands_wait_for_replay() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let mut shared = shared_for(Arc::new(MemoryStore::with_wal(wal)));
+        shared.load = LoadState::default();
+        shared.load.set_progress(42, 100);
+        let mut session = Session::default();
+        
+        let response = RustVaultServer::process_command(b"GET key1", &shared, &mut session).await;
+        assert_eq!(response, Response::error(ErrorCode::Loading, "42% restored"));
+        
+        // Malformed input is still reported as such
+        let response = RustVaultServer::process_command(b"GETX key1", &shared, &mut session).await;
+        assert_eq!(response.error_code(), Some(ErrorCode::Parse));
+        assert!(matches!(response, Response::Error(e) if e.starts_with("ERR_PARSE parse error")));
+        
+        shared.load.mark_ready();
+        let response = RustVaultServer::process_command(b"GET key1", &shared, &mut session).await;
         assert_eq!(response, Response::NotFound);
     }
+    
+    #[tokio::test]
+    async fn test_malformed_frames_write_nothing() {
+        let store = Arc::new(MemoryStore::new());
+        let shared = shared_for(Arc::clone(&store));
+        let mut session = Session::default();
+        
+        let frames = [&b"SET \xff\xfe $1\r\nv\r\n"[..], b"SET k $1\r\nv\r\nSET k2 v\r\n", b"GET a\r\nGET b\r\n"];
+        for frame in frames {
+            let response = RustVaultServer::process_command(frame, &shared, &mut session).await;
+            assert_eq!(response.error_code(), Some(ErrorCode::Parse), "{:?}", frame);
+        }
+        assert_eq!(store.len().await.unwrap(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_accepts_clients_during_replay() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal_path = temp_file.path().to_string_lossy().to_string();
+        {
+            let wal = WriteAheadLog::new(&wal_path, SyncPolicy::Never).unwrap();
+            for i in 0..40 {
+                wal.log_command(Command::Set {
+                    key: format!("key{}", i),
+                    value: format!("value{}", i).into_bytes(),
+                    condition: None,
+                }).await.unwrap();
+            }
+        }
+        
+        let config = ServerConfig {
+            wal_path,
+            ..Default::default()
+        };
+        let mut server = RustVaultServer::new(config).await.unwrap();
+        server.replay_delay = Some(std::time::Duration::from_millis(25));
+        let server = Arc::new(server);
+        
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        
+        // The listener is up well before the replay finishes
+        let mut client = crate::Client::connect(&addr).await.unwrap();
+        let mut seen = Vec::new();
+        let value = loop {
+            match client.get("key39").await {
+                Ok(value) => break value,
+                Err(RustVaultError::Remote { code: ErrorCode::Loading, message: e }) => {
+                    let pct: u8 = e
+                        .strip_suffix("% restored")
+                        .and_then(|pct| pct.parse().ok())
+                        .unwrap_or_else(|| panic!("unexpected error: {}", e));
+                    seen.push(pct);
+                    
+                    // INFO answers meanwhile, without the store's figures
+                    let info = client.info().await.unwrap();
+                    assert_eq!(info["loading"], "1");
+                    let progress: u8 = info["loading_progress"].parse().unwrap();
+                    assert!(progress >= pct, "{} after {}", progress, pct);
+                    assert!(!info.contains_key("keys"));
+                    assert!(info.contains_key("uptime_secs"));
+                }
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        };
+        
+        assert_eq!(value, Some("value39".to_string()));
+        assert!(server.is_ready());
+        assert_eq!(server.loading_progress(), 100);
+        let info = client.info().await.unwrap();
+        assert_eq!(info["loading"], "0");
+        assert_eq!(info["loading_progress"], "100");
+        assert_eq!(info["keys"], "40");
+        assert!(seen.len() >= 2, "saw {:?}", seen);
+        assert!(seen.windows(2).all(|w| w[0] <= w[1]), "saw {:?}", seen);
+        assert!(seen.first() < seen.last(), "saw {:?}", seen);
+        
+        client.close().await.unwrap();
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_startup_gives_up_on_a_slow_replay() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal_path = temp_file.path().to_string_lossy().to_string();
+        {
+            let wal = WriteAheadLog::new(&wal_path, SyncPolicy::Never).unwrap();
+            for i in 0..20 {
+                wal.log_command(Command::Set { key: format!("key{}", i), value: b"v".to_vec(), condition: None }).await.unwrap();
+            }
+        }
+        let config = ServerConfig {
+            wal_path,
+            replay_progress_interval: Some(5),
+            startup_timeout: Some(std::time::Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut server = RustVaultServer::new(config).await.unwrap();
+        server.replay_delay = Some(std::time::Duration::from_millis(25));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        
+        let err = server.run_with_listener(listener).await.unwrap_err();
+        assert!(err.to_string().contains("didn't finish within 100ms"), "{}", err);
+        assert!(!server.is_ready());
+    }
+    
+    #[tokio::test]
+    async fn test_ping_answers_while_ready_waits_for_replay() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal_path = temp_file.path().to_string_lossy().to_string();
+        {
+            let wal = WriteAheadLog::new(&wal_path, SyncPolicy::Never).unwrap();
+            for i in 0..20 {
+                wal.log_command(Command::Set { key: format!("key{}", i), value: b"v".to_vec(), condition: None }).await.unwrap();
+            }
+        }
+        let mut server = RustVaultServer::new(ServerConfig { wal_path, ..Default::default() }).await.unwrap();
+        server.replay_delay = Some(std::time::Duration::from_millis(25));
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        
+        let mut client = crate::Client::connect(&addr).await.unwrap();
+        client.ping().await.unwrap();
+        match client.execute_raw(&["READY"]).await.unwrap() {
+            crate::RawResponse::Error { code, .. } => assert_eq!(code.as_deref(), Some("ERR_LOADING")),
+            other => panic!("unexpected reply {:?}", other),
+        }
+        assert!(!server.is_ready());
+        
+        while client.execute_raw(&["READY"]).await.unwrap() != crate::RawResponse::Ok {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(server.is_ready());
+        assert!(client.ping().await.unwrap() < std::time::Duration::from_secs(5));
+        
+        client.close().await.unwrap();
+        server.shutdown().unwrap();
+        server_task.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_transactions() {
+        let shared = shared_for(Arc::new(MemoryStore::new()));
+        let mut session = Session::default();
+        assert_eq!(RustVaultServer::process_command(b"EXEC", &shared, &mut session).await, Response::error(ErrorCode::Invalid, "EXEC without MULTI"));
+        assert_eq!(RustVaultServer::process_command(b"MULTI", &shared, &mut session).await, Response::Ok);
+        assert_eq!(RustVaultServer::process_command(b"SET a 1", &shared, &mut session).await, Response::Queued);
+        assert_eq!(RustVaultServer::process_command(b"GET a", &shared, &mut session).await, Response::Queued);
+        assert_eq!(RustVaultServer::process_command(b"DELETE b", &shared, &mut session).await, Response::Queued);
+        assert_eq!(RustVaultServer::process_command(b"MULTI", &shared, &mut session).await, Response::error(ErrorCode::Invalid, "MULTI calls can not be nested"));
+        assert_eq!(
+            RustVaultServer::process_command(b"EXEC", &shared, &mut session).await,
+            Response::Results(vec![Response::Ok, Response::Value(b"1".to_vec()), Response::NotFound])
+        );
+        
+        // A command that can't be queued throws the transaction away
+        assert_eq!(RustVaultServer::process_command(b"MULTI", &shared, &mut session).await, Response::Ok);
+        assert_eq!(RustVaultServer::process_command(b"SET a 2", &shared, &mut session).await, Response::Queued);
+        assert_eq!(RustVaultServer::process_command(b"INCR n", &shared, &mut session).await, Response::error(ErrorCode::Invalid, "INCR can't be queued in MULTI"));
+        assert_eq!(
+            RustVaultServer::process_command(b"EXEC", &shared, &mut session).await,
+            Response::error(ErrorCode::ExecAbort, "Transaction discarded because of previous errors")
+        );
+        assert_eq!(RustVaultServer::process_command(b"GET a", &shared, &mut session).await, Response::Value(b"1".to_vec()));
+        
+        assert_eq!(RustVaultServer::process_command(b"MULTI", &shared, &mut session).await, Response::Ok);
+        assert_eq!(RustVaultServer::process_command(b"SET a 3", &shared, &mut session).await, Response::Queued);
+        assert_eq!(RustVaultServer::process_command(b"DISCARD", &shared, &mut session).await, Response::Ok);
+        assert_eq!(RustVaultServer::process_command(b"GET a", &shared, &mut session).await, Response::Value(b"1".to_vec()));
+    }
+    
+    #[tokio::test]
+    async fn test_watch_aborts_exec_after_a_change() {
+        let shared = shared_for(Arc::new(MemoryStore::new()));
+        let mut session = Session::default();
+        let mut other = Session::default();
+        
+        assert_eq!(RustVaultServer::process_command(b"WATCH a", &shared, &mut session).await, Response::Ok);
+        RustVaultServer::process_command(b"SET a changed", &shared, &mut other).await;
+        RustVaultServer::process_command(b"MULTI", &shared, &mut session).await;
+        RustVaultServer::process_command(b"SET b 1", &shared, &mut session).await;
+        assert_eq!(RustVaultServer::process_command(b"EXEC", &shared, &mut session).await, Response::Conflict);
+        assert_eq!(RustVaultServer::process_command(b"GET b", &shared, &mut session).await, Response::NotFound);
+        
+        // EXEC cleared the watch, so the retry applies
+        RustVaultServer::process_command(b"MULTI", &shared, &mut session).await;
+        RustVaultServer::process_command(b"SET b 1", &shared, &mut session).await;
+        assert_eq!(
+            RustVaultServer::process_command(b"EXEC", &shared, &mut session).await,
+            Response::Results(vec![Response::Ok])
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_watch_aborts_exec_after_a_change_back() {
+        let shared = shared_for(Arc::new(MemoryStore::new()));
+        let mut session = Session::default();
+        let mut other = Session::default();
+        
+        RustVaultServer::process_command(b"SET a A", &shared, &mut other).await;
+        assert_eq!(RustVaultServer::process_command(b"WATCH a", &shared, &mut session).await, Response::Ok);
+        RustVaultServer::process_command(b"SET a B", &shared, &mut other).await;
+        RustVaultServer::process_command(b"SET a A", &shared, &mut other).await;
+        RustVaultServer::process_command(b"MULTI", &shared, &mut session).await;
+        RustVaultServer::process_command(b"SET b 1", &shared, &mut session).await;
+        assert_eq!(RustVaultServer::process_command(b"EXEC", &shared, &mut session).await, Response::Conflict);
+        assert_eq!(RustVaultServer::process_command(b"GET b", &shared, &mut session).await, Response::NotFound);
+    }
+    
+    #[tokio::test]
+    async fn test_replica_refuses_writes() {
+        let mut shared = shared_for(Arc::new(MemoryStore::new()));
+        shared.replica = true;
+        let mut session = Session::default();
+        let refused = Response::error(ErrorCode::ReadOnly, "read only replica");
+        
+        assert_eq!(RustVaultServer::process_command(b"SET a 1", &shared, &mut session).await, refused);
+        assert_eq!(RustVaultServer::process_command(b"INCR n", &shared, &mut session).await, refused);
+        assert_eq!(RustVaultServer::process_command(b"GET a", &shared, &mut session).await, Response::NotFound);
+        assert_eq!(RustVaultServer::process_command(b"INFO", &shared, &mut session).await.to_bytes()[..4], *b"INFO");
+        
+        // A write queued in a transaction fails the whole of it
+        RustVaultServer::process_command(b"MULTI", &shared, &mut session).await;
+        assert_eq!(RustVaultServer::process_command(b"GET a", &shared, &mut session).await, Response::Queued);
+        assert_eq!(RustVaultServer::process_command(b"DELETE a", &shared, &mut session).await, refused);
+        assert_eq!(
+            RustVaultServer::process_command(b"EXEC", &shared, &mut session).await,
+            Response::error(ErrorCode::ExecAbort, "Transaction discarded because of previous errors")
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_allowed_commands() {
+        let mut shared = shared_for(Arc::new(MemoryStore::new()));
+        let names: HashSet<String> = ["get", "SCAN"].into_iter().map(String::from).collect();
+        shared.allowed_commands = Some(allowed_verbs(&names));
+        shared.vault.set("a".to_string(), b"1".to_vec()).await.unwrap();
+        let mut session = Session::default();
+        let refused = Response::error(ErrorCode::NotPermitted, "command not permitted");
+        
+        assert_eq!(
+            RustVaultServer::process_command(b"GET a", &shared, &mut session).await,
+            Response::Value(b"1".to_vec())
+        );
+        assert!(matches!(
+            RustVaultServer::process_command(b"SCAN 0 10", &shared, &mut session).await,
+            Response::Keys { .. }
+        ));
+        assert_eq!(RustVaultServer::process_command(b"SET a 2", &shared, &mut session).await, refused);
+        assert_eq!(RustVaultServer::process_command(b"INFO", &shared, &mut session).await, refused);
+        assert_eq!(RustVaultServer::process_command(b"CONFIG SET readonly 0", &shared, &mut session).await, refused);
+        assert_eq!(shared.vault.get("a").await.unwrap(), Some(b"1".to_vec()));
+    }
+    
+    #[tokio::test]
+    async fn test_read_only_mode() {
+        let mut shared = shared_for(Arc::new(MemoryStore::new()));
+        shared.config.get_mut().unwrap().read_only = true;
+        let mut session = Session::default();
+        let refused = Response::error(ErrorCode::NotPermitted, "command not permitted");
+        
+        assert_eq!(RustVaultServer::process_command(b"SET a 1", &shared, &mut session).await, refused);
+        assert_eq!(RustVaultServer::process_command(b"GET a", &shared, &mut session).await, Response::NotFound);
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG SET readonly 0", &shared, &mut session).await,
+            Response::Ok
+        );
+        assert_eq!(RustVaultServer::process_command(b"SET a 1", &shared, &mut session).await, Response::Ok);
+        
+        // A write queued before the switch still goes through with its EXEC
+        let mut queued = Session::default();
+        RustVaultServer::process_command(b"MULTI", &shared, &mut queued).await;
+        assert_eq!(RustVaultServer::process_command(b"SET b 2", &shared, &mut queued).await, Response::Queued);
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG SET readonly 1", &shared, &mut session).await,
+            Response::Ok
+        );
+        assert_eq!(RustVaultServer::process_command(b"SET c 3", &shared, &mut session).await, refused);
+        assert_eq!(
+            RustVaultServer::process_command(b"EXEC", &shared, &mut queued).await,
+            Response::Results(vec![Response::Ok])
+        );
+        assert_eq!(shared.vault.get("b").await.unwrap(), Some(b"2".to_vec()));
+        
+        // ...but one queued after it fails the transaction
+        RustVaultServer::process_command(b"MULTI", &shared, &mut queued).await;
+        assert_eq!(RustVaultServer::process_command(b"DELETE b", &shared, &mut queued).await, refused);
+        assert!(matches!(
+            RustVaultServer::process_command(b"EXEC", &shared, &mut queued).await,
+            Response::Error(e) if e.starts_with("ERR_EXECABORT")
+        ));
+        
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG SET readonly yes", &shared, &mut session).await,
+            Response::error(ErrorCode::Invalid, "CONFIG SET readonly takes 1 or 0")
+        );
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG SET maxclients 1", &shared, &mut session).await,
+            Response::error(ErrorCode::Invalid, "unknown config parameter maxclients")
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_config_get_and_set() {
+        let shared = shared_for(Arc::new(MemoryStore::new()));
+        let mut session = Session::default();
+        let info = |fields: &[(&str, &str)]| {
+            Response::Info(fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+        };
+        
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG GET max_value_bytes", &shared, &mut session).await,
+            info(&[("max_value_bytes", "16777216")])
+        );
+        assert_eq!(RustVaultServer::process_command(b"SET k 123456", &shared, &mut session).await, Response::Ok);
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG SET max_value_bytes 4", &shared, &mut session).await,
+            Response::Ok
+        );
+        assert_eq!(
+            RustVaultServer::process_command(b"SET k 123456", &shared, &mut session).await,
+            Response::error(ErrorCode::TooLarge, "value too large (max 4)")
+        );
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG GET *_timeout", &shared, &mut session).await,
+            info(&[("idle_timeout", "none"), ("read_timeout", "none")])
+        );
+        
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG SET wal_path other.log", &shared, &mut session).await,
+            Response::error(ErrorCode::Invalid, "wal_path can't be changed while the server runs")
+        );
+        assert_eq!(
+            RustVaultServer::process_command(b"CONFIG GET nope", &shared, &mut session).await,
+            Response::error(ErrorCode::Invalid, "unknown config parameter nope")
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_select_isolates_namespaces() {
+        let mut shared = shared_for(Arc::new(MemoryStore::new()));
+        shared.allow_flush_all = true;
+        let mut plain = Session::default();
+        let mut app = Session::default();
+        
+        assert_eq!(RustVaultServer::process_command(b"SELECT app", &shared, &mut app).await, Response::Ok);
+        assert!(matches!(
+            RustVaultServer::process_command(b"SELECT a:b", &shared, &mut app).await,
+            Response::Error(_)
+        ));
+        RustVaultServer::process_command(b"SET k plain", &shared, &mut plain).await;
+        RustVaultServer::process_command(b"SET k app", &shared, &mut app).await;
+        RustVaultServer::process_command(b"SET other v", &shared, &mut app).await;
+        
+        assert_eq!(
+            RustVaultServer::process_command(b"GET k", &shared, &mut plain).await,
+            Response::Value(b"plain".to_vec())
+        );
+        assert_eq!(
+            RustVaultServer::process_command(b"GET k", &shared, &mut app).await,
+            Response::Value(b"app".to_vec())
+        );
+        assert_eq!(
+            RustVaultServer::process_command(b"SCAN 0 10", &shared, &mut plain).await,
+            Response::Keys { keys: vec!["k".to_string()], cursor: 0 }
+        );
+        assert_eq!(RustVaultServer::process_command(b"DBSIZE", &shared, &mut app).await, Response::Integer(2));
+        
+        // The default namespace's digest is the one a store of only its keys has
+        let alone = MemoryStore::new();
+        alone.set("k".to_string(), b"plain".to_vec()).await.unwrap();
+        assert_eq!(
+            RustVaultServer::process_command(b"CHECKSUM", &shared, &mut plain).await,
+            Response::Value(format!("{:016x}", alone.checksum("").await.unwrap()).into_bytes())
+        );
+        
+        assert_eq!(RustVaultServer::process_command(b"FLUSHDB", &shared, &mut app).await, Response::Ok);
+        assert_eq!(RustVaultServer::process_command(b"DBSIZE app", &shared, &mut plain).await, Response::Integer(0));
+        assert_eq!(RustVaultServer::process_command(b"DBSIZE", &shared, &mut plain).await, Response::Integer(1));
+    }
+    
+    #[tokio::test]
+    async fn test_delete_pattern_stays_in_its_namespace() {
+        let mut shared = shared_for(Arc::new(MemoryStore::new()));
+        let mut plain = Session::default();
+        let mut app = Session::default();
+        RustVaultServer::process_command(b"SELECT app", &shared, &mut app).await;
+        for key in ["user:1", "user:2", "users:1"] {
+            RustVaultServer::process_command(format!("SET {} v", key).as_bytes(), &shared, &mut plain).await;
+            RustVaultServer::process_command(format!("SET {} v", key).as_bytes(), &shared, &mut app).await;
+        }
+        
+        assert_eq!(
+            RustVaultServer::process_command(b"DELPAT user:*", &shared, &mut app).await,
+            Response::Integer(2)
+        );
+        assert_eq!(RustVaultServer::process_command(b"DBSIZE", &shared, &mut app).await, Response::Integer(1));
+        assert_eq!(RustVaultServer::process_command(b"DBSIZE", &shared, &mut plain).await, Response::Integer(3));
+        
+        // Every key at once needs the same opt-in as FLUSHALL, however the
+        // wildcards are spelled
+        for pattern in ["**", "?*", "*?*"] {
+            assert_eq!(
+                RustVaultServer::process_command(format!("DELPAT {}", pattern).as_bytes(), &shared, &mut plain).await,
+                Response::error(ErrorCode::NotPermitted, "DELPAT of every key is disabled, as FLUSHALL is")
+            );
+        }
+        assert_eq!(RustVaultServer::process_command(b"DBSIZE", &shared, &mut plain).await, Response::Integer(3));
+        shared.allow_flush_all = true;
+        assert_eq!(RustVaultServer::process_command(b"DELPAT *", &shared, &mut plain).await, Response::Integer(3));
+        assert_eq!(RustVaultServer::process_command(b"DBSIZE", &shared, &mut app).await, Response::Integer(1));
+    }
+    
+    #[tokio::test]
+    async fn test_writes_past_memory_limit_are_refused() {
+        let store = MemoryStore::new().with_memory_limit(16, EvictionPolicy::NoEviction);
+        let shared = shared_for(Arc::new(store));
+        let mut session = Session::default();
+        
+        assert_eq!(RustVaultServer::process_command(b"SET key1 value1", &shared, &mut session).await, Response::Ok);
+        let refused = RustVaultServer::process_command(b"SET key2 value2", &shared, &mut session).await;
+        assert_eq!(refused, Response::error(ErrorCode::OutOfMemory, "out of memory"));
+        assert_eq!(refused.to_bytes(), b"ERROR ERR_OOM out of memory\r\n");
+        assert_eq!(RustVaultServer::process_command(b"DELETE key1", &shared, &mut session).await, Response::Ok);
+        assert_eq!(RustVaultServer::process_command(b"SET key2 value2", &shared, &mut session).await, Response::Ok);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,191 @@
+//! systemd socket activation and readiness notification
+//!
+//! When started from a `.socket` unit, systemd passes already-bound
+//! listening sockets as file descriptors starting at 3 and describes them in
+//! `LISTEN_PID`/`LISTEN_FDS` (see sd_listen_fds(3)). The server adopts those
+//! instead of binding `bind_addr`, so the socket survives restarts. Once the
+//! WAL has been restored it reports `READY=1` on `NOTIFY_SOCKET`
+//! (see sd_notify(3)). Outside systemd, and on non-Unix targets, both are
+//! no-ops.
+
+use super::Listener;
+use std::env;
+use std::io;
+
+/// First descriptor passed by socket activation (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take the listening sockets passed by systemd, if any
+///
+/// Returns `Ok(None)` when the process was not socket-activated. The
+/// activation variables are removed from the environment so they are not
+/// inherited by anything this process spawns.
+pub fn listen_fds() -> io::Result<Option<Vec<Listener>>> {
+    let count = passed_fd_count(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    
+    if count == 0 {
+        return Ok(None);
+    }
+    
+    #[cfg(unix)]
+    {
+        (0..count)
+            .map(|i| adopt(LISTEN_FDS_START + i as i32))
+            .collect::<io::Result<Vec<_>>>()
+            .map(Some)
+    }
+    
+    #[cfg(not(unix))]
+    {
+        Ok(None)
+    }
+}
+
+/// Number of descriptors meant for this process according to the
+/// activation variables
+fn passed_fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    // LISTEN_PID guards against acting on variables inherited from a parent
+    match listen_pid.and_then(|p| p.parse::<u32>().ok()) {
+        Some(listen_pid) if listen_pid == pid => {
+            listen_fds.and_then(|n| n.parse().ok()).unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+/// Wrap a passed descriptor in a tokio listener of the right socket family
+#[cfg(unix)]
+fn adopt(fd: std::os::unix::io::RawFd) -> io::Result<Listener> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    
+    // SAFETY: systemd passes this descriptor to us for our exclusive use,
+    // and each index is adopted exactly once.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if listener.local_addr().is_ok() {
+        listener.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?));
+    }
+    
+    // Not an inet socket; hand ownership over to a Unix listener instead
+    // SAFETY: the descriptor was just released by `into_raw_fd`.
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(listener.into_raw_fd()) };
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok(Listener::Unix(tokio::net::UnixListener::from_std(listener)?))
+}
+
+/// Tell the service manager the server is ready to serve
+///
+/// Returns whether a notification was sent, i.e. whether `NOTIFY_SOCKET`
+/// was set.
+pub fn notify_ready() -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => {
+            notify(&socket, "READY=1")?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Send a state string to the notification socket at `socket`
+#[cfg(unix)]
+fn notify(socket: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+    
+    let sender = UnixDatagram::unbound()?;
+    
+    // A leading '@' names a socket in Linux's abstract namespace
+    if let Some(name) = socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            
+            let addr = SocketAddr::from_abstract_name(name)?;
+            sender.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract notification sockets are only supported on Linux",
+            ));
+        }
+    }
+    
+    sender.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify(_socket: &std::ffi::OsStr, _state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_passed_fd_count() {
+        assert_eq!(passed_fd_count(Some("42"), Some("2"), 42), 2);
+        // Variables meant for another process are ignored
+        assert_eq!(passed_fd_count(Some("41"), Some("2"), 42), 0);
+        assert_eq!(passed_fd_count(None, Some("2"), 42), 0);
+        assert_eq!(passed_fd_count(Some("42"), None, 42), 0);
+        assert_eq!(passed_fd_count(Some("42"), Some("many"), 42), 0);
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_sends_state_to_socket() {
+        use std::os::unix::net::UnixDatagram;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        
+        notify(path.as_os_str(), "READY=1").unwrap();
+        
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_adopt_tcp_and_unix_descriptors() {
+        use std::os::unix::io::IntoRawFd;
+        
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        match adopt(tcp.into_raw_fd()).unwrap() {
+            Listener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), tcp_addr),
+            other => panic!("expected a TCP listener, got {:?}", other),
+        }
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        match adopt(unix.into_raw_fd()).unwrap() {
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr().unwrap();
+                assert_eq!(addr.as_pathname(), Some(path.as_path()));
+            }
+            other => panic!("expected a Unix listener, got {:?}", other),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,232 @@
+//! Reusable buffer pool for connection I/O
+//!
+//! Connections check `BytesMut` buffers out of a shared pool for reading
+//! requests and encoding responses instead of allocating fresh ones. Buffers
+//! are grouped into size classes and the total capacity held by the pool is
+//! bounded; oversized buffers (e.g. after a huge value) are dropped rather
+//! than pooled.
+
+use bytes::BytesMut;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Buffer capacities served by the pool, smallest first
+const SIZE_CLASSES: [usize; 3] = [4 * 1024, 64 * 1024, 1024 * 1024];
+
+/// Default bound on the total capacity held by idle pooled buffers
+pub const DEFAULT_MAX_RESIDENT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Snapshot of pool counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BufPoolStats {
+    /// Checkouts served from an idle pooled buffer
+    pub hits: u64,
+    /// Checkouts that had to allocate
+    pub misses: u64,
+    /// Returned buffers dropped because they were oversized or the pool was full
+    pub discarded: u64,
+    /// Total capacity of idle buffers currently held by the pool
+    pub resident_bytes: usize,
+}
+
+/// Pool of reusable `BytesMut` buffers grouped by size class
+pub struct BufPool {
+    classes: Vec<Mutex<Vec<BytesMut>>>,
+    max_resident_bytes: usize,
+    resident_bytes: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl BufPool {
+    /// Create a pool holding at most `max_resident_bytes` of idle buffers
+    pub fn new(max_resident_bytes: usize) -> Self {
+        Self {
+            classes: SIZE_CLASSES.iter().map(|_| Mutex::new(Vec::new())).collect(),
+            max_resident_bytes,
+            resident_bytes: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        }
+    }
+    
+    /// Check out an empty buffer with at least `min_capacity` bytes of capacity
+    ///
+    /// The buffer goes back to the pool when the returned guard is dropped,
+    /// including when the owning task is aborted.
+    pub fn checkout(self: &Arc<Self>, min_capacity: usize) -> PooledBuf {
+        let buf = match SIZE_CLASSES.iter().position(|&size| size >= min_capacity) {
+            Some(class) => {
+                let pooled = self.classes[class].lock().unwrap().pop();
+                match pooled {
+                    Some(buf) => {
+                        self.resident_bytes.fetch_sub(buf.capacity(), Ordering::Relaxed);
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                        buf
+                    }
+                    None => {
+                        self.misses.fetch_add(1, Ordering::Relaxed);
+                        BytesMut::with_capacity(SIZE_CLASSES[class])
+                    }
+                }
+            }
+            None => {
+                // Larger than any class, never pooled
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(min_capacity)
+            }
+        };
+        
+        PooledBuf {
+            buf: Some(buf),
+            pool: Arc::clone(self),
+        }
+    }
+    
+    /// Get a snapshot of the pool counters
+    pub fn stats(&self) -> BufPoolStats {
+        BufPoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            resident_bytes: self.resident_bytes.load(Ordering::Relaxed),
+        }
+    }
+    
+    /// Return a buffer to its size class, or drop it if it doesn't fit
+    fn give_back(&self, mut buf: BytesMut) {
+        buf.clear();
+        let capacity = buf.capacity();
+        
+        // Buffers that grew well past the largest class are not worth keeping
+        let largest = SIZE_CLASSES[SIZE_CLASSES.len() - 1];
+        let class = SIZE_CLASSES.iter().rposition(|&size| size <= capacity);
+        let class = match class {
+            Some(class) if capacity <= largest * 2 => class,
+            _ => {
+                self.discarded.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        
+        let resident = self.resident_bytes.fetch_add(capacity, Ordering::Relaxed);
+        if resident + capacity > self.max_resident_bytes {
+            self.resident_bytes.fetch_sub(capacity, Ordering::Relaxed);
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        
+        self.classes[class].lock().unwrap().push(buf);
+    }
+}
+
+impl Default for BufPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_RESIDENT_BYTES)
+    }
+}
+
+/// A buffer checked out of a `BufPool`, returned to it on drop
+pub struct PooledBuf {
+    buf: Option<BytesMut>,
+    pool: Arc<BufPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+    
+    fn deref(&self) -> &BytesMut {
+        self.buf.as_ref().expect("pooled buffer already returned")
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        self.buf.as_mut().expect("pooled buffer already returned")
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.give_back(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkout_reuses_returned_buffers() {
+        let pool = Arc::new(BufPool::default());
+        
+        let mut buf = pool.checkout(100);
+        buf.extend_from_slice(b"hello");
+        drop(buf);
+        
+        let buf = pool.checkout(100);
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 100);
+        
+        let stats = pool.stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.resident_bytes, 0);
+        
+        drop(buf);
+        assert_eq!(pool.stats().resident_bytes, SIZE_CLASSES[0]);
+    }
+    
+    #[test]
+    fn test_oversized_buffers_are_discarded() {
+        let pool = Arc::new(BufPool::default());
+        
+        // A buffer that grew to hold a huge value
+        let mut buf = pool.checkout(16);
+        buf.reserve(8 * 1024 * 1024);
+        drop(buf);
+        
+        // Requests beyond the largest class are never pooled
+        drop(pool.checkout(4 * 1024 * 1024));
+        
+        let stats = pool.stats();
+        assert_eq!(stats.discarded, 2);
+        assert_eq!(stats.resident_bytes, 0);
+    }
+    
+    #[test]
+    fn test_resident_bytes_are_bounded() {
+        let pool = Arc::new(BufPool::new(2 * SIZE_CLASSES[0]));
+        
+        let bufs: Vec<_> = (0..3).map(|_| pool.checkout(16)).collect();
+        drop(bufs);
+        
+        let stats = pool.stats();
+        assert_eq!(stats.resident_bytes, 2 * SIZE_CLASSES[0]);
+        assert_eq!(stats.discarded, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_aborted_task_returns_buffer() {
+        let pool = Arc::new(BufPool::default());
+        
+        let task_pool = Arc::clone(&pool);
+        let (checked_out_tx, checked_out_rx) = tokio::sync::oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let _buf = task_pool.checkout(16);
+            let _ = checked_out_tx.send(());
+            std::future::pending::<()>().await;
+        });
+        
+        checked_out_rx.await.unwrap();
+        handle.abort();
+        let _ = handle.await;
+        
+        assert_eq!(pool.stats().resident_bytes, SIZE_CLASSES[0]);
+    }
+}
\ No newline at end of file
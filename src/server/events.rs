@@ -0,0 +1,207 @@
+//! Change events for subscribed connections
+//!
+//! Commands that change keys publish a [`KeyEvent`] once they succeed, and
+//! every connection that sent `SUBSCRIBE` forwards the ones matching its
+//! pattern. Events are only built while someone is subscribed.
+//!
+//! The channel holds the last [`EVENT_BUFFER`] events. A subscriber that
+//! falls further behind than that loses the oldest of the events it hasn't
+//! read, and is sent `EVENT LAGGED <n>` with how many it missed; it must
+//! then assume any key may have changed. Writers never wait for
+//! subscribers.
+//!
+//! Events carry stored keys down the channel. A subscriber only sees the
+//! keys of the namespace it subscribed from, named as that namespace names
+//! them, and a `FLUSHDB` of that namespace reaches it as `FLUSHALL`.
+
+use crate::protocol::{glob_match, Command, KeyEvent};
+use crate::store::namespace;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Events kept for subscribers that haven't read them yet
+pub const EVENT_BUFFER: usize = 1024;
+
+/// An event as it goes down the channel
+#[derive(Debug, Clone)]
+pub(crate) enum Published {
+    /// An event naming stored keys
+    Event(KeyEvent),
+    /// Every key in the namespace was removed
+    FlushDb(String),
+}
+
+impl From<KeyEvent> for Published {
+    fn from(event: KeyEvent) -> Self {
+        Published::Event(event)
+    }
+}
+
+/// Where commands publish their changes
+#[derive(Debug)]
+pub(crate) struct Events {
+    tx: broadcast::Sender<Published>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        Self { tx }
+    }
+}
+
+impl Events {
+    /// The events `command` causes if it succeeds, or none if nobody is
+    /// subscribed to them
+    pub(crate) fn changes(&self, command: &Command) -> Vec<Published> {
+        if self.tx.receiver_count() == 0 {
+            return Vec::new();
+        }
+        let event = match command {
+            Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::Cas { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Decr { key, .. }
+            | Command::Append { key, .. }
+            | Command::GetSet { key, .. }
+            | Command::Lock { key, .. } => KeyEvent::Set(key.clone()),
+            Command::MSet { pairs } => {
+                return pairs.iter().map(|(key, _)| KeyEvent::Set(key.clone()).into()).collect();
+            }
+            Command::Delete { key } | Command::GetDel { key } | Command::Unlock { key, .. } => KeyEvent::Del(key.clone()),
+            Command::FlushAll => KeyEvent::FlushAll,
+            Command::FlushDb { namespace: Some(namespace) } => return vec![Published::FlushDb(namespace.clone())],
+            _ => return Vec::new(),
+        };
+        vec![event.into()]
+    }
+    
+    /// The events for setting each of the stored keys `keys`, or none if
+    /// nobody is subscribed to them
+    pub(crate) fn set(&self, keys: &[String]) -> Vec<Published> {
+        if self.tx.receiver_count() == 0 {
+            return Vec::new();
+        }
+        keys.iter().map(|key| KeyEvent::Set(key.clone()).into()).collect()
+    }
+    
+    /// The events for removing each of the stored keys `keys`, or none if
+    /// nobody is subscribed to them
+    pub(crate) fn deleted(&self, keys: &[String]) -> Vec<Published> {
+        if self.tx.receiver_count() == 0 {
+            return Vec::new();
+        }
+        keys.iter().map(|key| KeyEvent::Del(key.clone()).into()).collect()
+    }
+    
+    pub(crate) fn publish(&self, events: Vec<Published>) {
+        for event in events {
+            // Fails only when the last subscriber has just gone
+            let _ = self.tx.send(event);
+        }
+    }
+    
+    /// Start receiving the events for keys in `namespace` matching
+    /// `pattern`
+    pub(crate) fn subscribe(&self, namespace: &str, pattern: String) -> Subscription {
+        Subscription {
+            namespace: namespace.to_string(),
+            pattern,
+            rx: self.tx.subscribe(),
+        }
+    }
+}
+
+/// One connection's feed of events
+#[derive(Debug)]
+pub(crate) struct Subscription {
+    namespace: String,
+    pattern: String,
+    rx: broadcast::Receiver<Published>,
+}
+
+impl Subscription {
+    /// The next event for this subscriber; `None` once no more can come
+    ///
+    /// Events about every key, such as `FLUSHALL` and `LAGGED`, always
+    /// match.
+    pub(crate) async fn next(&mut self) -> Option<KeyEvent> {
+        loop {
+            let event = match self.rx.recv().await {
+                Ok(Published::Event(KeyEvent::Set(stored))) => self.matching(&stored).map(KeyEvent::Set),
+                Ok(Published::Event(KeyEvent::Del(stored))) => self.matching(&stored).map(KeyEvent::Del),
+                Ok(Published::Event(event)) => Some(event),
+                Ok(Published::FlushDb(namespace)) => (namespace == self.namespace).then_some(KeyEvent::FlushAll),
+                Err(RecvError::Lagged(missed)) => Some(KeyEvent::Lagged(missed)),
+                Err(RecvError::Closed) => return None,
+            };
+            if event.is_some() {
+                return event;
+            }
+        }
+    }
+    
+    /// The key the stored key `stored` is known by to this subscriber, if
+    /// it is one the subscriber wants
+    fn matching(&self, stored: &str) -> Option<String> {
+        let (namespace, key) = namespace::split(stored);
+        (namespace == self.namespace && glob_match(self.pattern.as_bytes(), key.as_bytes()))
+            .then(|| key.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn test_subscription_filters_by_pattern() {
+        let events = Events::default();
+        let set = Command::Set { key: "user:1".to_string(), value: b"a".to_vec(), condition: None };
+        assert!(events.changes(&set).is_empty());
+        
+        let mut subscription = events.subscribe(namespace::DEFAULT, "user:*".to_string());
+        events.publish(events.changes(&set));
+        events.publish(events.changes(&Command::Delete { key: "order:1".to_string() }));
+        events.publish(events.changes(&Command::Get { key: "user:2".to_string() }));
+        events.publish(events.changes(&Command::Delete { key: "user:2".to_string() }));
+        events.publish(events.changes(&Command::FlushAll));
+        
+        assert_eq!(subscription.next().await, Some(KeyEvent::Set("user:1".to_string())));
+        assert_eq!(subscription.next().await, Some(KeyEvent::Del("user:2".to_string())));
+        assert_eq!(subscription.next().await, Some(KeyEvent::FlushAll));
+    }
+    
+    #[tokio::test]
+    async fn test_slow_subscriber_is_told_what_it_missed() {
+        let events = Events::default();
+        let mut subscription = events.subscribe(namespace::DEFAULT, "*".to_string());
+        for i in 0..EVENT_BUFFER + 10 {
+            events.publish(vec![KeyEvent::Set(format!("key{}", i)).into()]);
+        }
+        
+        assert_eq!(subscription.next().await, Some(KeyEvent::Lagged(10)));
+        assert_eq!(subscription.next().await, Some(KeyEvent::Set("key10".to_string())));
+        
+        drop(events);
+        for _ in 0..EVENT_BUFFER - 1 {
+            assert!(subscription.next().await.is_some());
+        }
+        assert_eq!(subscription.next().await, None);
+    }
+    
+    #[tokio::test]
+    async fn test_subscribers_only_see_their_namespace() {
+        let events = Events::default();
+        let mut subscription = events.subscribe("app", "*".to_string());
+        let set = |key: &str| Command::Set { key: key.to_string(), value: b"v".to_vec(), condition: None };
+        events.publish(events.changes(&set("plain")));
+        events.publish(events.changes(&set(&namespace::qualify("other", "k"))));
+        events.publish(events.changes(&set(&namespace::qualify("app", "k"))));
+        events.publish(events.changes(&Command::FlushDb { namespace: Some("other".to_string()) }));
+        events.publish(events.changes(&Command::FlushDb { namespace: Some("app".to_string()) }));
+        
+        assert_eq!(subscription.next().await, Some(KeyEvent::Set("k".to_string())));
+        assert_eq!(subscription.next().await, Some(KeyEvent::FlushAll));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,183 @@
+//! Per-command latency histograms
+//!
+//! Each command run against the store is timed and counted into a
+//! fixed-bucket histogram for its verb. Buckets split every power of two of
+//! nanoseconds into four, so a percentile read back is within a quarter of
+//! the true value. Recording is a relaxed add into one of [`SHARDS`] copies
+//! of every histogram, picked per thread, so threads recording at once
+//! rarely touch the same cache line; [`Latencies::summary`] adds the copies
+//! up when asked.
+
+use crate::protocol::COMMAND_TABLE;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Copies of every histogram, spread across threads
+pub const SHARDS: usize = 4;
+
+/// Buckets per histogram; the last takes every sample past ~18 minutes
+const BUCKETS: usize = 160;
+
+/// Slots per histogram: the buckets, then the sum and maximum in
+/// nanoseconds
+const SLOTS: usize = BUCKETS + 2;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The shard this thread records into
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+}
+
+/// The bucket `nanos` is counted in
+fn bucket(nanos: u64) -> usize {
+    if nanos < 4 {
+        return nanos as usize;
+    }
+    let exp = 63 - nanos.leading_zeros() as usize;
+    let sub = (nanos >> (exp - 2)) as usize & 3;
+    ((exp - 1) * 4 + sub).min(BUCKETS - 1)
+}
+
+/// The largest value counted in bucket `index`
+fn upper_bound(index: usize) -> u64 {
+    if index < 4 {
+        return index as u64;
+    }
+    let (exp, sub) = (index / 4 + 1, (index % 4) as u64);
+    ((4 + sub + 1) << (exp - 2)) - 1
+}
+
+/// What a command's histogram held when it was read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub sum: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// A latency histogram per command in `COMMAND_TABLE`
+#[derive(Debug)]
+pub struct Latencies {
+    /// `SHARDS` runs of one histogram per command, `SLOTS` apart
+    slots: Box<[AtomicU64]>,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Self {
+            slots: (0..SHARDS * COMMAND_TABLE.len() * SLOTS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Latencies {
+    fn histogram(&self, shard: usize, command: usize) -> &[AtomicU64] {
+        let start = (shard * COMMAND_TABLE.len() + command) * SLOTS;
+        &self.slots[start..start + SLOTS]
+    }
+    
+    /// Count a run of the command at `index` in `COMMAND_TABLE` that took
+    /// `elapsed`
+    pub fn record(&self, index: usize, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let histogram = self.histogram(SHARD.with(|shard| *shard), index);
+        histogram[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        histogram[BUCKETS].fetch_add(nanos, Ordering::Relaxed);
+        histogram[BUCKETS + 1].fetch_max(nanos, Ordering::Relaxed);
+    }
+    
+    /// The histogram of the command at `index` in `COMMAND_TABLE`, summed
+    /// across shards
+    ///
+    /// A percentile is the top of the bucket it falls in, never more than
+    /// the largest sample.
+    pub fn summary(&self, index: usize) -> LatencySummary {
+        let mut counts = [0u64; BUCKETS];
+        let (mut sum, mut max) = (0u64, 0u64);
+        for shard in 0..SHARDS {
+            let histogram = self.histogram(shard, index);
+            for (count, slot) in counts.iter_mut().zip(histogram) {
+                *count += slot.load(Ordering::Relaxed);
+            }
+            sum = sum.wrapping_add(histogram[BUCKETS].load(Ordering::Relaxed));
+            max = max.max(histogram[BUCKETS + 1].load(Ordering::Relaxed));
+        }
+        let count: u64 = counts.iter().sum();
+        let percentile = |q: f64| {
+            let rank = ((q * count as f64).ceil() as u64).max(1);
+            let mut seen = 0;
+            let index = counts.iter().position(|&n| {
+                seen += n;
+                seen >= rank
+            });
+            Duration::from_nanos(index.map_or(0, upper_bound).min(max))
+        };
+        LatencySummary {
+            count,
+            sum: Duration::from_nanos(sum),
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: Duration::from_nanos(max),
+        }
+    }
+    
+    /// Zero every histogram
+    ///
+    /// Samples recorded while the reset runs may be kept or dropped.
+    pub fn reset(&self) {
+        for slot in self.slots.iter() {
+            slot.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_buckets_cover_every_value_in_order() {
+        let mut last = 0;
+        for nanos in (0..10_000).chain([1 << 20, (1 << 20) + 1, 123_456_789]) {
+            let index = bucket(nanos);
+            assert!(index >= last);
+            assert!(nanos <= upper_bound(index), "{} above bucket {}", nanos, index);
+            assert!(index == 0 || nanos > upper_bound(index - 1), "{} below bucket {}", nanos, index);
+            last = index;
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+    
+    #[test]
+    fn test_summary_adds_up_shards() {
+        let latencies = Latencies::default();
+        std::thread::scope(|scope| {
+            for _ in 0..SHARDS {
+                // Every thread records the same 1µs..=100µs spread
+                scope.spawn(|| {
+                    for micros in 1..=100 {
+                        latencies.record(0, Duration::from_micros(micros));
+                    }
+                });
+            }
+        });
+        
+        let summary = latencies.summary(0);
+        assert_eq!(summary.count, 100 * SHARDS as u64);
+        assert_eq!(summary.max, Duration::from_micros(100));
+        assert_eq!(summary.sum, Duration::from_micros(5050 * SHARDS as u64));
+        for (percentile, micros) in [(summary.p50, 50), (summary.p95, 95), (summary.p99, 99)] {
+            let micros = Duration::from_micros(micros);
+            assert!(percentile >= micros && percentile <= micros * 5 / 4, "{:?} for {:?}", percentile, micros);
+        }
+        assert_eq!(latencies.summary(1), LatencySummary::default());
+        
+        latencies.reset();
+        assert_eq!(latencies.summary(0), LatencySummary::default());
+    }
+}
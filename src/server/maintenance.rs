@@ -0,0 +1,639 @@
+//! Scheduler for recurring background work
+//!
+//! Background jobs run from a single scheduler task rather than each owning
+//! a timer loop. Jobs marked heavy walk or rebuild the whole store; at most
+//! one of those runs at a time, so they can't all land on the store at
+//! once. Light jobs run alongside them.
+
+use crate::error::Result;
+use crate::store::{MemoryStore, Store};
+use crate::wal::WriteAheadLog;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// Future returned by [`MaintenanceJob::run`]
+pub type JobFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// A recurring piece of background work
+pub trait MaintenanceJob: Send + Sync + 'static {
+    /// Name shown in `MAINTENANCE STATUS`
+    fn name(&self) -> &'static str;
+    
+    /// Time between the end of one run and the start of the next
+    fn interval(&self) -> Duration;
+    
+    /// Up to this much random delay is added to each interval, so jobs
+    /// with equal intervals drift apart
+    fn jitter(&self) -> Duration {
+        Duration::ZERO
+    }
+    
+    /// Whether the job works over the whole store
+    fn heavy(&self) -> bool {
+        false
+    }
+    
+    fn run(&self) -> JobFuture<'_>;
+}
+
+/// Last known state of one scheduled job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobStatus {
+    pub name: &'static str,
+    pub heavy: bool,
+    /// Completed runs, successful or not
+    pub runs: u64,
+    pub running: bool,
+    pub last_started: Option<SystemTime>,
+    pub last_duration: Option<Duration>,
+    /// Error from the most recent run, if it failed
+    pub last_error: Option<String>,
+}
+
+/// Per-job status, shared between the scheduler and the server
+#[derive(Debug, Default)]
+pub struct StatusTable {
+    jobs: Mutex<Vec<JobStatus>>,
+}
+
+impl StatusTable {
+    /// Copy of every job's status, in registration order
+    pub fn snapshot(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().clone()
+    }
+    
+    /// One-line summary for `MAINTENANCE STATUS`
+    pub fn render(&self) -> String {
+        let jobs = self.jobs.lock().unwrap();
+        if jobs.is_empty() {
+            return "no jobs scheduled".to_string();
+        }
+        
+        let mut out = String::new();
+        for (i, job) in jobs.iter().enumerate() {
+            if i > 0 {
+                out.push_str("; ");
+            }
+            let _ = write!(out, "{} runs={}", job.name, job.runs);
+            if let Some(duration) = job.last_duration {
+                let _ = write!(out, " last={:.1?}", duration);
+            }
+            if job.running {
+                out.push_str(" running");
+            }
+            match &job.last_error {
+                Some(e) => {
+                    let _ = write!(out, " error={}", e);
+                }
+                None if job.runs > 0 => out.push_str(" ok"),
+                None => {}
+            }
+        }
+        out
+    }
+    
+    fn update(&self, idx: usize, f: impl FnOnce(&mut JobStatus)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(idx) {
+            f(job);
+        }
+    }
+}
+
+/// Runs registered jobs on their intervals until shutdown
+pub struct Scheduler {
+    jobs: Vec<Arc<dyn MaintenanceJob>>,
+    status: Arc<StatusTable>,
+}
+
+impl Scheduler {
+    /// Create a scheduler reporting into `status`
+    pub fn new(status: Arc<StatusTable>) -> Self {
+        Self {
+            jobs: Vec::new(),
+            status,
+        }
+    }
+    
+    /// Register a job; its first run is one interval from `run`
+    pub fn add(&mut self, job: impl MaintenanceJob) {
+        self.status.jobs.lock().unwrap().push(JobStatus {
+            name: job.name(),
+            heavy: job.heavy(),
+            runs: 0,
+            running: false,
+            last_started: None,
+            last_duration: None,
+            last_error: None,
+        });
+        self.jobs.push(Arc::new(job));
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+    
+    /// Run jobs until shutdown
+    ///
+    /// Jobs already running when shutdown arrives are allowed to finish, so
+    /// none is cut off half-way through rewriting the store.
+    pub async fn run(self, mut shutdown_rx: broadcast::Receiver<()>) {
+        let mut queue = BinaryHeap::new();
+        for (idx, job) in self.jobs.iter().enumerate() {
+            queue.push(Reverse((Instant::now() + next_delay(job.as_ref()), idx)));
+        }
+        
+        let mut running = JoinSet::new();
+        let mut task_jobs = HashMap::new();
+        let mut heavy_running = false;
+        // Heavy jobs that came due while another heavy job was running
+        let mut waiting = VecDeque::new();
+        
+        loop {
+            let next_due = queue.peek().map(|Reverse((at, _))| *at);
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                    let Some(Reverse((_, idx))) = queue.pop() else { continue };
+                    if self.jobs[idx].heavy() {
+                        if heavy_running {
+                            waiting.push_back(idx);
+                            continue;
+                        }
+                        heavy_running = true;
+                    }
+                    self.start(idx, &mut running, &mut task_jobs);
+                }
+                Some(done) = running.join_next_with_id(), if !running.is_empty() => {
+                    let idx = self.finish(done, &mut task_jobs);
+                    if self.jobs[idx].heavy() {
+                        heavy_running = false;
+                        if let Some(next) = waiting.pop_front() {
+                            heavy_running = true;
+                            self.start(next, &mut running, &mut task_jobs);
+                        }
+                    }
+                    queue.push(Reverse((Instant::now() + next_delay(self.jobs[idx].as_ref()), idx)));
+                }
+                _ = shutdown_rx.recv() => break,
+            }
+        }
+        
+        while let Some(done) = running.join_next_with_id().await {
+            self.finish(done, &mut task_jobs);
+        }
+    }
+    
+    fn start(
+        &self,
+        idx: usize,
+        running: &mut JoinSet<(Duration, Result<()>)>,
+        task_jobs: &mut HashMap<tokio::task::Id, usize>,
+    ) {
+        self.status.update(idx, |status| {
+            status.running = true;
+            status.last_started = Some(SystemTime::now());
+        });
+        let job = Arc::clone(&self.jobs[idx]);
+        let handle = running.spawn(async move {
+            let started = Instant::now();
+            let result = job.run().await;
+            (started.elapsed(), result)
+        });
+        task_jobs.insert(handle.id(), idx);
+    }
+    
+    /// Record a finished run, returning the job's index
+    fn finish(
+        &self,
+        done: std::result::Result<(tokio::task::Id, (Duration, Result<()>)), tokio::task::JoinError>,
+        task_jobs: &mut HashMap<tokio::task::Id, usize>,
+    ) -> usize {
+        let (id, duration, error) = match done {
+            Ok((id, (duration, result))) => (id, Some(duration), result.err().map(|e| e.to_string())),
+            Err(e) => (e.id(), None, Some(format!("job panicked: {}", e))),
+        };
+        let idx = task_jobs.remove(&id).expect("finished task was started by the scheduler");
+        
+        if let Some(e) = &error {
+            eprintln!("Maintenance job {} failed: {}", self.jobs[idx].name(), e);
+        }
+        self.status.update(idx, |status| {
+            status.running = false;
+            status.runs += 1;
+            status.last_duration = duration;
+            status.last_error = error;
+        });
+        idx
+    }
+}
+
+/// Delay before a job's next run: its interval plus random jitter
+fn next_delay(job: &dyn MaintenanceJob) -> Duration {
+    jittered(job.interval(), job.jitter())
+}
+
+fn jittered(interval: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return interval;
+    }
+    // A freshly keyed hasher is random enough to spread timers out
+    let random = RandomState::new().build_hasher().finish();
+    interval + jitter.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// Periodically release capacity left behind by deletes, like `SHRINK`,
+/// once the live data fills less than `ratio` of what is allocated
+pub struct ShrinkJob<S = MemoryStore> {
+    store: Arc<S>,
+    ratio: f64,
+    interval: Duration,
+}
+
+impl<S: Store> ShrinkJob<S> {
+    pub fn new(store: Arc<S>, ratio: f64, interval: Duration) -> Self {
+        Self { store, ratio, interval }
+    }
+}
+
+impl<S: Store + 'static> MaintenanceJob for ShrinkJob<S> {
+    fn name(&self) -> &'static str {
+        "shrink"
+    }
+    
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+    
+    fn jitter(&self) -> Duration {
+        self.interval / 10
+    }
+    
+    fn heavy(&self) -> bool {
+        true
+    }
+    
+    fn run(&self) -> JobFuture<'_> {
+        Box::pin(async move {
+            if self.store.memory_usage().await.live_ratio() >= self.ratio {
+                return Ok(());
+            }
+            let report = self.store.shrink().await;
+            if report.reclaimed() > 0 {
+                println!(
+                    "Shrunk store from ~{} to ~{} bytes",
+                    report.before, report.after
+                );
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Periodically remove keys whose TTL has run out, which are otherwise
+/// only removed as they are next touched
+pub struct ExpirySweepJob<S = MemoryStore> {
+    store: Arc<S>,
+    interval: Duration,
+}
+
+impl<S: Store> ExpirySweepJob<S> {
+    pub fn new(store: Arc<S>, interval: Duration) -> Self {
+        Self { store, interval }
+    }
+}
+
+impl<S: Store + 'static> MaintenanceJob for ExpirySweepJob<S> {
+    fn name(&self) -> &'static str {
+        "expiry-sweep"
+    }
+    
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+    
+    fn jitter(&self) -> Duration {
+        self.interval / 10
+    }
+    
+    fn heavy(&self) -> bool {
+        true
+    }
+    
+    fn run(&self) -> JobFuture<'_> {
+        Box::pin(async move {
+            self.store.sweep_expired().await;
+            Ok(())
+        })
+    }
+}
+
+/// Compact the WAL once it has grown past a threshold
+///
+/// A log is compacted when it has reached `threshold` bytes and at least
+/// doubled since the last compaction, so a dataset whose snapshot alone is
+/// over the threshold isn't rewritten on every check.
+pub struct CompactJob<S = MemoryStore> {
+    store: Arc<S>,
+    wal: Arc<WriteAheadLog>,
+    threshold: u64,
+    interval: Duration,
+    /// Size of the log right after the last compaction
+    compacted: AtomicU64,
+}
+
+impl<S: Store> CompactJob<S> {
+    pub fn new(store: Arc<S>, wal: Arc<WriteAheadLog>, threshold: u64, interval: Duration) -> Self {
+        Self {
+            store,
+            wal,
+            threshold,
+            interval,
+            compacted: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<S: Store + 'static> MaintenanceJob for CompactJob<S> {
+    fn name(&self) -> &'static str {
+        "compact"
+    }
+    
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+    
+    fn heavy(&self) -> bool {
+        true
+    }
+    
+    fn run(&self) -> JobFuture<'_> {
+        Box::pin(async move {
+            let due = self.threshold.max(self.compacted.load(Ordering::Relaxed).saturating_mul(2));
+            if self.wal.size() < due {
+                return Ok(());
+            }
+            let report = self.store.compact_wal().await?;
+            self.compacted.store(report.after, Ordering::Relaxed);
+            println!("Compacted WAL from {} to {} bytes ({} keys)", report.before, report.after, report.keys);
+            Ok(())
+        })
+    }
+}
+
+/// Write a snapshot of the store to `path` every `interval`
+pub struct SnapshotJob<S = MemoryStore> {
+    store: Arc<S>,
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl<S: Store> SnapshotJob<S> {
+    pub fn new(store: Arc<S>, path: PathBuf, interval: Duration) -> Self {
+        Self { store, path, interval }
+    }
+}
+
+impl<S: Store + 'static> MaintenanceJob for SnapshotJob<S> {
+    fn name(&self) -> &'static str {
+        "snapshot"
+    }
+    
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+    
+    fn heavy(&self) -> bool {
+        true
+    }
+    
+    fn run(&self) -> JobFuture<'_> {
+        Box::pin(async move { self.store.snapshot_to(&self.path).await })
+    }
+}
+
+/// Retry a small write while the WAL refuses appends, so writes resume on
+/// their own once the disk has room again
+///
+/// Each failed probe is reported as the job's error, which keeps the reason
+/// visible in `MAINTENANCE STATUS` for as long as the server is read-only.
+pub struct WalProbeJob {
+    wal: Arc<WriteAheadLog>,
+    interval: Duration,
+}
+
+impl WalProbeJob {
+    pub fn new(wal: Arc<WriteAheadLog>, interval: Duration) -> Self {
+        Self { wal, interval }
+    }
+}
+
+impl MaintenanceJob for WalProbeJob {
+    fn name(&self) -> &'static str {
+        "wal-probe"
+    }
+    
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+    
+    fn run(&self) -> JobFuture<'_> {
+        Box::pin(async move {
+            self.wal.probe().await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RustVaultError;
+    use crate::store::Store;
+    use std::sync::atomic::AtomicUsize;
+    
+    /// Job that sleeps for `work`, tracking how many heavy jobs overlap
+    struct TestJob {
+        name: &'static str,
+        heavy: bool,
+        work: Duration,
+        fail: bool,
+        active: Arc<AtomicUsize>,
+        max_active: Arc<AtomicUsize>,
+    }
+    
+    impl TestJob {
+        fn new(name: &'static str, heavy: bool, work: Duration, active: &Arc<AtomicUsize>, max_active: &Arc<AtomicUsize>) -> Self {
+            Self {
+                name,
+                heavy,
+                work,
+                fail: false,
+                active: Arc::clone(active),
+                max_active: Arc::clone(max_active),
+            }
+        }
+    }
+    
+    impl MaintenanceJob for TestJob {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+        
+        fn interval(&self) -> Duration {
+            Duration::from_millis(5)
+        }
+        
+        fn heavy(&self) -> bool {
+            self.heavy
+        }
+        
+        fn run(&self) -> JobFuture<'_> {
+            Box::pin(async move {
+                let now = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_active.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(self.work).await;
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                if self.fail {
+                    return Err(RustVaultError::Server("out of tea".to_string()));
+                }
+                Ok(())
+            })
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_heavy_jobs_never_overlap() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let status = Arc::new(StatusTable::default());
+        let mut scheduler = Scheduler::new(Arc::clone(&status));
+        let work = Duration::from_millis(30);
+        scheduler.add(TestJob::new("first", true, work, &active, &max_active));
+        scheduler.add(TestJob::new("second", true, work, &active, &max_active));
+        
+        // A light job is free to run alongside them
+        let light_active = Arc::new(AtomicUsize::new(0));
+        let light_max = Arc::new(AtomicUsize::new(0));
+        scheduler.add(TestJob::new("light", false, work, &light_active, &light_max));
+        
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let task = tokio::spawn(scheduler.run(shutdown_rx));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        shutdown_tx.send(()).unwrap();
+        task.await.unwrap();
+        
+        assert_eq!(max_active.load(Ordering::SeqCst), 1);
+        let jobs = status.snapshot();
+        assert!(jobs.iter().all(|job| job.runs >= 2 && !job.running), "{:?}", jobs);
+        // Neither heavy job starves the other
+        assert!(jobs[0].runs.abs_diff(jobs[1].runs) <= 1, "{:?}", jobs);
+    }
+    
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let interval = Duration::from_millis(100);
+        let jitter = Duration::from_millis(50);
+        let delays: Vec<Duration> = (0..50).map(|_| jittered(interval, jitter)).collect();
+        assert!(delays.iter().all(|d| *d >= interval && *d <= interval + jitter));
+        assert!(delays.iter().any(|d| *d != delays[0]), "no jitter applied");
+        
+        assert_eq!(jittered(interval, Duration::ZERO), interval);
+    }
+    
+    #[tokio::test]
+    async fn test_shutdown_waits_for_running_job() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let status = Arc::new(StatusTable::default());
+        let mut scheduler = Scheduler::new(Arc::clone(&status));
+        let mut job = TestJob::new("slow", true, Duration::from_millis(200), &active, &max_active);
+        job.fail = true;
+        scheduler.add(job);
+        
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let task = tokio::spawn(scheduler.run(shutdown_rx));
+        while !status.snapshot()[0].running {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .expect("scheduler did not stop")
+            .unwrap();
+        
+        // The in-flight run finished, and nothing started after shutdown
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+        let job = &status.snapshot()[0];
+        assert_eq!(job.runs, 1);
+        assert!(!job.running);
+        assert_eq!(job.last_error.as_deref(), Some("Server error: out of tea"));
+        assert!(status.render().starts_with("slow runs=1 last="));
+    }
+    
+    #[tokio::test]
+    async fn test_compact_job_waits_for_the_log_to_double() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), crate::wal::SyncPolicy::Never).unwrap());
+        let store = Arc::new(MemoryStore::with_wal(Arc::clone(&wal)));
+        let job = CompactJob::new(Arc::clone(&store), Arc::clone(&wal), 512, Duration::from_secs(1));
+        
+        // Below the threshold nothing happens
+        store.set("key".to_string(), b"value".to_vec()).await.unwrap();
+        let size = wal.size();
+        job.run().await.unwrap();
+        assert_eq!(wal.size(), size);
+        
+        for i in 0..200 {
+            store.set(format!("key{}", i % 20), b"value".to_vec()).await.unwrap();
+        }
+        let before = wal.size();
+        job.run().await.unwrap();
+        let compacted = wal.size();
+        assert!(512 < compacted && compacted < before);
+        
+        // Still past the threshold, but not yet twice the compacted size
+        for i in 0..4 {
+            store.set(format!("key{}", i), b"value".to_vec()).await.unwrap();
+        }
+        job.run().await.unwrap();
+        assert!(wal.size() > compacted);
+    }
+    
+    #[tokio::test]
+    async fn test_shrink_job_waits_for_the_live_ratio_to_drop() {
+        let store = Arc::new(MemoryStore::new());
+        let job = ShrinkJob::new(Arc::clone(&store), 0.5, Duration::from_secs(1));
+        for i in 0..1000 {
+            store.set(format!("key{}", i), b"value".repeat(20)).await.unwrap();
+        }
+        
+        // A few deletes leave most of what is allocated live
+        for i in 0..100 {
+            store.delete(&format!("key{}", i)).await.unwrap();
+        }
+        let usage = store.memory_usage().await;
+        job.run().await.unwrap();
+        assert_eq!(store.memory_usage().await, usage);
+        
+        for i in 100..900 {
+            store.delete(&format!("key{}", i)).await.unwrap();
+        }
+        assert!(store.memory_usage().await.live_ratio() < 0.5);
+        job.run().await.unwrap();
+        let shrunk = store.memory_usage().await;
+        assert!(shrunk.allocated < usage.allocated / 2);
+        assert!(shrunk.live_ratio() >= 0.5);
+        assert_eq!(store.len().await.unwrap(), 100);
+    }
+}
\ No newline at end of file
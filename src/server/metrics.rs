@@ -0,0 +1,432 @@
+//! Server counters reported by `INFO` and the Prometheus endpoint
+//!
+//! Connections and commands bump relaxed atomics as they go, so counting
+//! costs no locking on the hot path, and commands record how long they took
+//! into the [`Latencies`] histograms the same way. [`Metrics::report`] and
+//! [`Metrics::prometheus`] read them all, together with figures that are
+//! cheap to look up when asked for: [`Gauges`], which are always at hand, and
+//! [`StoreGauges`], which read the store and so wait for its replay. [`answer_scrape`]
+//! speaks just enough HTTP to serve `GET /metrics`.
+
+use super::latency::{Latencies, LatencySummary};
+use crate::protocol::COMMAND_TABLE;
+use crate::store::{CompressionStats, MemoryUsage};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest request head accepted from a scraper
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// How long a scraper gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Snapshot of server counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerStats {
+    /// Connections currently being served
+    pub connections: usize,
+    /// Clients turned away because `max_connections` were already open
+    pub rejected_connections: u64,
+}
+
+/// Figures `INFO` reports that aren't counted here and don't need the store
+#[derive(Debug, Clone, Copy)]
+pub struct Gauges {
+    pub connections: usize,
+    pub wal_size: u64,
+}
+
+/// Figures `INFO` reports that are read from the store, so are only
+/// available once it has been restored
+#[derive(Debug, Clone, Copy)]
+pub struct StoreGauges {
+    pub keys: usize,
+    /// Removed entries whose memory is still being freed in the background
+    pub pending_free: usize,
+    pub memory: MemoryUsage,
+    /// `None` when the store doesn't compress values
+    pub compression: Option<CompressionStats>,
+}
+
+/// Counters shared by every connection of a server
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    /// Commands executed, indexed like `COMMAND_TABLE`
+    commands: [AtomicU64; COMMAND_TABLE.len()],
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+    accepted_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    ratelimit_delayed: AtomicU64,
+    ratelimit_rejected: AtomicU64,
+    latencies: Latencies,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            commands: std::array::from_fn(|_| AtomicU64::new(0)),
+            get_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+            accepted_connections: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            ratelimit_delayed: AtomicU64::new(0),
+            ratelimit_rejected: AtomicU64::new(0),
+            latencies: Latencies::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a command by its verb
+    pub fn command(&self, name: &str) {
+        if let Some(i) = command_index(name) {
+            self.commands[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    
+    /// Record that a command, by its verb, took `elapsed` to run
+    pub fn latency(&self, name: &str, elapsed: Duration) {
+        if let Some(i) = command_index(name) {
+            self.latencies.record(i, elapsed);
+        }
+    }
+    
+    /// What the latency histogram of a command, by its verb, holds
+    pub fn latency_summary(&self, name: &str) -> Option<LatencySummary> {
+        command_index(name).map(|i| self.latencies.summary(i))
+    }
+    
+    /// Zero every latency histogram, for `STATS RESET`
+    pub fn reset_latencies(&self) {
+        self.latencies.reset();
+    }
+    
+    /// Count a GET that found its key, or didn't
+    pub fn get(&self, hit: bool) {
+        let counter = if hit { &self.get_hits } else { &self.get_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Count a connection accepted and served
+    pub fn accepted(&self) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Count a client turned away at the connection limit
+    pub fn rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Clients turned away so far
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+    
+    /// Count a command held back by its client's rate limit
+    pub fn rate_limit_delayed(&self) {
+        self.ratelimit_delayed.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Count a command refused by its client's rate limit
+    pub fn rate_limit_rejected(&self) {
+        self.ratelimit_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Every counter, with `gauges` and `store`, as the name/value pairs
+    /// `INFO` replies with
+    ///
+    /// Without `store` the key count, pending frees, memory estimates and
+    /// compression figures are left out.
+    /// Each command in the table is listed, as `cmd_<verb>`, even if it has
+    /// never been run. Commands that have run since the latencies were last
+    /// reset are followed by `latency_<verb>_count` and their p50, p95, p99
+    /// and maximum, in microseconds.
+    pub fn report(&self, gauges: Gauges, store: Option<StoreGauges>) -> Vec<(String, String)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        let mut report = vec![("uptime_secs".to_string(), self.started.elapsed().as_secs().to_string())];
+        if let Some(store) = store {
+            report.push(("keys".to_string(), store.keys.to_string()));
+            report.extend([
+                ("pending_free".to_string(), store.pending_free.to_string()),
+                ("memory_allocated_bytes".to_string(), store.memory.allocated.to_string()),
+                ("memory_live_bytes".to_string(), store.memory.live.to_string()),
+                ("memory_live_ratio".to_string(), format!("{:.2}", store.memory.live_ratio())),
+            ]);
+        }
+        report.extend([
+            ("connections".to_string(), gauges.connections.to_string()),
+            ("total_connections".to_string(), load(&self.accepted_connections)),
+            ("rejected_connections".to_string(), load(&self.rejected_connections)),
+            ("wal_size_bytes".to_string(), gauges.wal_size.to_string()),
+            ("get_hits".to_string(), load(&self.get_hits)),
+            ("get_misses".to_string(), load(&self.get_misses)),
+            ("ratelimit_delayed_commands".to_string(), load(&self.ratelimit_delayed)),
+            ("ratelimit_rejected_commands".to_string(), load(&self.ratelimit_rejected)),
+        ]);
+        if let Some(stats) = store.and_then(|store| store.compression) {
+            report.extend([
+                ("compressed_values".to_string(), stats.values.to_string()),
+                ("compressed_raw_bytes".to_string(), stats.raw_bytes.to_string()),
+                ("compressed_stored_bytes".to_string(), stats.stored_bytes.to_string()),
+                ("incompressible_values".to_string(), stats.incompressible.to_string()),
+                ("compression_ratio".to_string(), format!("{:.2}", stats.ratio())),
+            ]);
+        }
+        for (spec, count) in COMMAND_TABLE.iter().zip(&self.commands) {
+            report.push((format!("cmd_{}", spec.name.to_ascii_lowercase()), load(count)));
+        }
+        for (i, spec) in COMMAND_TABLE.iter().enumerate() {
+            let summary = self.latencies.summary(i);
+            if summary.count == 0 {
+                continue;
+            }
+            let verb = spec.name.to_ascii_lowercase();
+            report.push((format!("latency_{}_count", verb), summary.count.to_string()));
+            for (name, value) in [("p50", summary.p50), ("p95", summary.p95), ("p99", summary.p99), ("max", summary.max)] {
+                report.push((format!("latency_{}_{}_us", verb, name), format!("{:.1}", value.as_secs_f64() * 1e6)));
+            }
+        }
+        report
+    }
+    
+    /// Every counter, with `gauges` and `store`, in the Prometheus text
+    /// format
+    pub fn prometheus(&self, gauges: Gauges, store: StoreGauges) -> String {
+        let mut out = String::from(
+            "# HELP rustvault_commands_total Commands executed, by verb\n\
+             # TYPE rustvault_commands_total counter\n",
+        );
+        for (spec, count) in COMMAND_TABLE.iter().zip(&self.commands) {
+            let _ = writeln!(
+                out,
+                "rustvault_commands_total{{op=\"{}\"}} {}",
+                spec.name.to_ascii_lowercase(),
+                count.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str(
+            "# HELP rustvault_command_duration_seconds Time taken to run commands, by verb\n\
+             # TYPE rustvault_command_duration_seconds summary\n",
+        );
+        for (i, spec) in COMMAND_TABLE.iter().enumerate() {
+            let summary = self.latencies.summary(i);
+            if summary.count == 0 {
+                continue;
+            }
+            let op = spec.name.to_ascii_lowercase();
+            for (quantile, value) in [("0.5", summary.p50), ("0.95", summary.p95), ("0.99", summary.p99), ("1", summary.max)] {
+                let _ = writeln!(
+                    out,
+                    "rustvault_command_duration_seconds{{op=\"{}\",quantile=\"{}\"}} {}",
+                    op,
+                    quantile,
+                    value.as_secs_f64()
+                );
+            }
+            let _ = writeln!(out, "rustvault_command_duration_seconds_sum{{op=\"{}\"}} {}", op, summary.sum.as_secs_f64());
+            let _ = writeln!(out, "rustvault_command_duration_seconds_count{{op=\"{}\"}} {}", op, summary.count);
+        }
+        let gauges = [
+            ("rustvault_keys", "Keys in the store", store.keys as u64),
+            ("rustvault_pending_free", "Removed entries still being freed", store.pending_free as u64),
+            ("rustvault_connections", "Connections currently open", gauges.connections as u64),
+            ("rustvault_wal_bytes", "Size of the write-ahead log in bytes", gauges.wal_size),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+        }
+        out
+    }
+}
+
+/// Where the command `name` is in `COMMAND_TABLE`
+fn command_index(name: &str) -> Option<usize> {
+    COMMAND_TABLE.iter().position(|spec| spec.name == name)
+}
+
+/// Answer one HTTP request on `stream`, serving `GET /metrics` from `body`
+///
+/// `body` yields `None` while the figures can't be gathered, which is
+/// answered with 503. Every response closes the connection.
+pub async fn answer_scrape<S, F>(stream: &mut S, body: F) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future<Output = Option<String>>,
+{
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "scrape request timed out")),
+    };
+    let request_line = head.lines().next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match body.await {
+            Some(body) => ("200 OK", body),
+            None => ("503 Service Unavailable", "loading\n".to_string()),
+        },
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read an HTTP request up to the blank line ending its headers
+async fn read_request_head<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut chunk = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "scrape request too large"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "scraper closed the connection"));
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_report_counts() {
+        let metrics = Metrics::default();
+        metrics.command("GET");
+        metrics.command("GET");
+        metrics.command("SET");
+        metrics.command("FROB");
+        metrics.get(true);
+        metrics.get(false);
+        metrics.get(false);
+        metrics.accepted();
+        metrics.rejected();
+        metrics.rate_limit_rejected();
+        
+        let gauges = Gauges { connections: 1, wal_size: 42 };
+        let memory = MemoryUsage { allocated: 4000, live: 1000 };
+        let report = metrics.report(gauges, Some(StoreGauges { keys: 3, pending_free: 5000, memory, compression: None }));
+        let value = |name: &str| {
+            report.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str()).unwrap()
+        };
+        assert_eq!(value("keys"), "3");
+        assert_eq!(value("pending_free"), "5000");
+        assert_eq!(value("memory_allocated_bytes"), "4000");
+        assert_eq!(value("memory_live_bytes"), "1000");
+        assert_eq!(value("memory_live_ratio"), "0.25");
+        assert_eq!(value("connections"), "1");
+        assert_eq!(value("total_connections"), "1");
+        assert_eq!(value("rejected_connections"), "1");
+        assert_eq!(value("wal_size_bytes"), "42");
+        assert_eq!(value("get_hits"), "1");
+        assert_eq!(value("get_misses"), "2");
+        assert_eq!(value("cmd_get"), "2");
+        assert_eq!(value("cmd_set"), "1");
+        assert_eq!(value("cmd_delete"), "0");
+        assert_eq!(value("ratelimit_delayed_commands"), "0");
+        assert_eq!(value("ratelimit_rejected_commands"), "1");
+        assert_eq!(report.len(), 14 + COMMAND_TABLE.len());
+        
+        // Without the store its figures are left out, and the rest still
+        // reported
+        let report = metrics.report(gauges, None);
+        assert!(!report.iter().any(|(name, _)| name == "keys" || name == "pending_free"));
+        assert!(report.contains(&("wal_size_bytes".to_string(), "42".to_string())));
+        assert_eq!(report.len(), 9 + COMMAND_TABLE.len());
+        
+        let compression = CompressionStats { values: 2, raw_bytes: 9000, stored_bytes: 1200, incompressible: 1 };
+        let report = metrics.report(gauges, Some(StoreGauges { keys: 3, pending_free: 0, memory, compression: Some(compression) }));
+        let value = |name: &str| {
+            report.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str()).unwrap()
+        };
+        assert_eq!(value("compressed_values"), "2");
+        assert_eq!(value("compressed_stored_bytes"), "1200");
+        assert_eq!(value("incompressible_values"), "1");
+        assert_eq!(value("compression_ratio"), "7.50");
+    }
+    
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = Metrics::default();
+        metrics.command("SET");
+        metrics.command("SET");
+        
+        let text = metrics.prometheus(
+            Gauges { connections: 1, wal_size: 42 },
+            StoreGauges { keys: 2, pending_free: 0, memory: MemoryUsage::default(), compression: None },
+        );
+        assert!(text.contains("# TYPE rustvault_commands_total counter\n"));
+        assert!(text.contains("\nrustvault_commands_total{op=\"set\"} 2\n"));
+        assert!(text.contains("\nrustvault_commands_total{op=\"get\"} 0\n"));
+        assert!(text.contains("# TYPE rustvault_keys gauge\nrustvault_keys 2\n"));
+        assert!(text.contains("\nrustvault_pending_free 0\n"));
+        assert!(text.contains("\nrustvault_connections 1\n"));
+        assert!(text.contains("\nrustvault_wal_bytes 42\n"));
+        assert!(!text.contains("rustvault_command_duration_seconds{"));
+    }
+    
+    #[test]
+    fn test_latencies_are_reported_per_command() {
+        let metrics = Metrics::default();
+        for micros in [10, 20, 30, 40] {
+            metrics.latency("GET", Duration::from_micros(micros));
+        }
+        metrics.latency("FROB", Duration::from_secs(1));
+        assert_eq!(metrics.latency_summary("GET").unwrap().count, 4);
+        assert_eq!(metrics.latency_summary("FROB"), None);
+        
+        let gauges = Gauges { connections: 0, wal_size: 0 };
+        let store = StoreGauges { keys: 0, pending_free: 0, memory: MemoryUsage::default(), compression: None };
+        let report = metrics.report(gauges, Some(store));
+        let value = |name: &str| report.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone());
+        assert_eq!(value("latency_get_count").as_deref(), Some("4"));
+        assert_eq!(value("latency_get_max_us").as_deref(), Some("40.0"));
+        assert_eq!(value("latency_set_count"), None);
+        
+        let text = metrics.prometheus(gauges, store);
+        assert!(text.contains("# TYPE rustvault_command_duration_seconds summary\n"));
+        assert!(text.contains("\nrustvault_command_duration_seconds{op=\"get\",quantile=\"1\"} 0.00004\n"));
+        assert!(text.contains("\nrustvault_command_duration_seconds_count{op=\"get\"} 4\n"));
+        assert!(text.contains("\nrustvault_command_duration_seconds_sum{op=\"get\"} 0.0001\n"));
+        
+        metrics.reset_latencies();
+        assert_eq!(metrics.latency_summary("GET").unwrap().count, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_answer_scrape() {
+        async fn scrape(request: &[u8]) -> String {
+            let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+            client.write_all(request).await.unwrap();
+            answer_scrape(&mut server, async { Some("rustvault_keys 1\n".to_string()) })
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        }
+        
+        let ok = scrape(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.contains("Content-Length: 17\r\n"));
+        assert!(ok.ends_with("\r\n\r\nrustvault_keys 1\n"));
+        assert!(scrape(b"GET / HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+        assert!(scrape(b"POST /metrics HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405"));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,221 @@
+//! Rate limits on the commands clients send
+//!
+//! Every command takes a token from its connection's bucket, which holds up
+//! to `burst` tokens and refills at `ops_per_sec`. With an `ip_rate_limit`
+//! it also takes one from a bucket shared by every connection from the same
+//! address, so opening more connections doesn't buy more commands. Once a
+//! bucket is empty the command is either held back until its token is due,
+//! slowing the client down without failing anything, or refused with
+//! `ERR_RATELIMIT`, as the [`RateLimitMode`] says.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Addresses tracked before buckets that have refilled are dropped; a full
+/// bucket is the same as a new one, so nothing is lost by dropping it
+const PRUNE_AT: usize = 1024;
+
+/// How many commands a client may send
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Commands per second, on average
+    pub ops_per_sec: f64,
+    /// Commands that may be sent at once after a quiet spell
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Time to refill one token
+    fn per_token(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.ops_per_sec.max(f64::MIN_POSITIVE))
+    }
+}
+
+/// What the server does with a command over its client's rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitMode {
+    /// Hold the command back until its token is due
+    #[default]
+    Delay,
+    /// Refuse it with `ERROR ERR_RATELIMIT retry_after_ms=<n>`
+    Reject,
+}
+
+/// Tokens a client has left under one [`RateLimit`]
+///
+/// The count goes below zero when commands are delayed: each of them has
+/// taken a token that isn't there yet, and waits for it to be refilled.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self { limit, tokens: limit.burst.max(1) as f64, refilled: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.ops_per_sec).min(self.limit.burst.max(1) as f64);
+        self.refilled = now;
+    }
+
+    /// How long until the next token is due; zero if one is there now
+    fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            self.limit.per_token().mul_f64(1.0 - self.tokens)
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.limit.burst.max(1) as f64
+    }
+}
+
+/// Whether a command may run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Its client had a token to spare
+    Now,
+    /// Once this long has passed
+    After(Duration),
+    /// Not at all; the client may try again this long from now
+    Refused { retry_after: Duration },
+}
+
+/// The rate limits of one server, and the buckets of every address
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_connection: Option<RateLimit>,
+    per_ip: Option<RateLimit>,
+    mode: RateLimitMode,
+    ips: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_connection: Option<RateLimit>, per_ip: Option<RateLimit>, mode: RateLimitMode) -> Self {
+        Self {
+            per_connection,
+            per_ip,
+            mode,
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The bucket a new connection draws on, if connections are limited
+    pub fn connection_bucket(&self) -> Option<TokenBucket> {
+        self.per_connection.map(|limit| TokenBucket::new(limit, Instant::now()))
+    }
+
+    /// Take the tokens for one command on a connection with `bucket`, from
+    /// `ip`; a Unix socket client has no address, and only its connection
+    /// is limited
+    pub fn admit(&self, bucket: Option<&mut TokenBucket>, ip: Option<IpAddr>) -> Admission {
+        if bucket.is_none() && (self.per_ip.is_none() || ip.is_none()) {
+            return Admission::Now;
+        }
+        let now = Instant::now();
+        let mut ips = self.ips.lock().unwrap();
+        let shared = match (self.per_ip, ip) {
+            (Some(limit), Some(ip)) => {
+                if ips.len() >= PRUNE_AT && !ips.contains_key(&ip) {
+                    ips.retain(|_, bucket| !bucket.is_full(now));
+                }
+                Some(ips.entry(ip).or_insert_with(|| TokenBucket::new(limit, now)))
+            }
+            _ => None,
+        };
+        let mut buckets: Vec<&mut TokenBucket> = bucket.into_iter().chain(shared).collect();
+        let wait = buckets.iter_mut().map(|bucket| bucket.wait(now)).max().unwrap_or_default();
+
+        let admission = match self.mode {
+            _ if wait.is_zero() => Admission::Now,
+            RateLimitMode::Delay => Admission::After(wait),
+            RateLimitMode::Reject => return Admission::Refused { retry_after: wait },
+        };
+        for bucket in buckets {
+            bucket.take();
+        }
+        admission
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `wait` is `millis`, give or take rounding
+    fn about(wait: Duration, millis: u64) -> bool {
+        (wait.as_secs_f64() - millis as f64 / 1000.0).abs() < 1e-6
+    }
+
+    #[test]
+    fn test_bucket_refills_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit { ops_per_sec: 10.0, burst: 3 }, start);
+        for _ in 0..3 {
+            assert_eq!(bucket.wait(start), Duration::ZERO);
+            bucket.take();
+        }
+        assert!(about(bucket.wait(start), 100));
+        assert!(about(bucket.wait(start + Duration::from_millis(40)), 60));
+        assert_eq!(bucket.wait(start + Duration::from_millis(150)), Duration::ZERO);
+
+        // A delayed command takes a token that isn't there yet
+        bucket.take();
+        bucket.take();
+        assert!(about(bucket.wait(start + Duration::from_millis(150)), 150));
+
+        // Never more than the burst, however long it is left alone
+        assert!(bucket.is_full(start + Duration::from_secs(60)));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn test_limiter_delays_or_refuses() {
+        let limit = RateLimit { ops_per_sec: 1.0, burst: 2 };
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let delaying = RateLimiter::new(Some(limit), None, RateLimitMode::Delay);
+        let mut bucket = delaying.connection_bucket();
+        assert_eq!(delaying.admit(bucket.as_mut(), Some(ip)), Admission::Now);
+        assert_eq!(delaying.admit(bucket.as_mut(), Some(ip)), Admission::Now);
+        assert!(matches!(delaying.admit(bucket.as_mut(), Some(ip)), Admission::After(wait) if wait > Duration::from_millis(900)));
+        assert!(matches!(delaying.admit(bucket.as_mut(), Some(ip)), Admission::After(wait) if wait > Duration::from_millis(1900)));
+        // Another connection has a bucket of its own
+        assert_eq!(delaying.admit(delaying.connection_bucket().as_mut(), Some(ip)), Admission::Now);
+
+        // Connections from one address share its bucket; refusals take nothing
+        let refusing = RateLimiter::new(None, Some(limit), RateLimitMode::Reject);
+        assert_eq!(refusing.connection_bucket().map(|_| ()), None);
+        assert_eq!(refusing.admit(None, Some(ip)), Admission::Now);
+        assert_eq!(refusing.admit(None, Some(ip)), Admission::Now);
+        for _ in 0..3 {
+            let Admission::Refused { retry_after } = refusing.admit(None, Some(ip)) else {
+                panic!("expected a refusal");
+            };
+            assert!(retry_after > Duration::from_millis(900) && retry_after <= Duration::from_secs(1));
+        }
+        assert_eq!(refusing.admit(None, Some("10.0.0.2".parse().unwrap())), Admission::Now);
+        assert_eq!(refusing.admit(None, None), Admission::Now);
+
+        let unlimited = RateLimiter::new(None, None, RateLimitMode::Reject);
+        for _ in 0..100 {
+            assert_eq!(unlimited.admit(None, Some(ip)), Admission::Now);
+        }
+    }
+}
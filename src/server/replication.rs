@@ -0,0 +1,427 @@
+//! Replication to read-only replicas
+//!
+//! A replica connects to its primary and sends `REPLICATE`. Once that is
+//! answered `OK`, the connection carries commands one way, for the replica
+//! to apply: a `FLUSHALL`, a `SET` for every key, then a `SET` or `DELETE`
+//! for each key as it changes. A key with a TTL is followed by its
+//! `PEXPIREAT`, so both servers expire it at the same wall-clock time.
+//! Keys outside the default namespace are sent after a `SELECT` of theirs,
+//! as a client in that namespace would send them, and a `FLUSHDB` names
+//! the namespace it empties.
+//!
+//! A change is sent as the key's value at the time it is sent, not as the
+//! command that made it. Writers publish their changes after applying
+//! them, so two writes to one key may be published in either order; the
+//! one sent last reads the value both left behind, and the replica ends up
+//! holding what the primary holds. A replica more than
+//! [`REPLICATION_BUFFER`] changes behind is sent everything again.
+//!
+//! The replica applies the stream through its store, so its own WAL records
+//! it, and publishes the changes in turn, to its subscribers and to any
+//! replica of its own. It reconnects with a backoff whenever the
+//! connection is lost.
+
+use super::Shared;
+use crate::client::encode_command;
+use crate::error::{Result, RustVaultError};
+use crate::protocol::{parse_command, payload_len, Command};
+use crate::store::{namespace, Store};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Changes kept for replicas that haven't been sent them yet
+pub const REPLICATION_BUFFER: usize = 16 * 1024;
+
+/// Keys read from the store at a time during a full sync
+const SYNC_PAGE: usize = 1000;
+
+/// Wait before a replica's first reconnect, doubled for each one after it
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest wait between two reconnects
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// What a replica must be sent again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// The key's current value, or its absence
+    Key(String),
+    FlushAll,
+    /// Every key in the namespace was removed
+    FlushDb(String),
+}
+
+/// Where commands publish the keys they changed, for replicas
+#[derive(Debug)]
+pub(crate) struct ChangeFeed {
+    tx: broadcast::Sender<Change>,
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(REPLICATION_BUFFER);
+        Self { tx }
+    }
+}
+
+impl ChangeFeed {
+    /// The changes `command` makes if it succeeds
+    ///
+    /// Worked out whether or not a replica is attached, since one that
+    /// attaches while the command runs may already have been sent the key.
+    pub(crate) fn changes(&self, command: &Command) -> Vec<Change> {
+        match command {
+            Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::Cas { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Decr { key, .. }
+            | Command::Append { key, .. }
+            | Command::GetSet { key, .. }
+            | Command::GetDel { key }
+            | Command::Lock { key, .. }
+            | Command::Unlock { key, .. }
+            | Command::Delete { key }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. } => vec![Change::Key(key.clone())],
+            Command::MSet { pairs } => pairs.iter().map(|(key, _)| Change::Key(key.clone())).collect(),
+            Command::FlushAll => vec![Change::FlushAll],
+            Command::FlushDb { namespace: Some(namespace) } => vec![Change::FlushDb(namespace.clone())],
+            _ => Vec::new(),
+        }
+    }
+    
+    pub(crate) fn publish(&self, changes: Vec<Change>) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        for change in changes {
+            // Fails only when the last replica has just gone
+            let _ = self.tx.send(change);
+        }
+    }
+    
+    /// Start receiving changes, for a replica that is about to be sent
+    /// everything
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.tx.subscribe()
+    }
+}
+
+/// Send every key and then every change to a connection that sent
+/// `REPLICATE`, until it closes or the server shuts down
+pub(super) async fn feed<S, T>(
+    stream: &mut T,
+    mut changes: broadcast::Receiver<Change>,
+    shared: &Shared<S>,
+    shutdown_rx: &mut broadcast::Receiver<()>,
+) where
+    S: Store,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut ignored = [0; 256];
+    // The replica starts in the default namespace, as any connection does
+    let mut selected = namespace::DEFAULT.to_string();
+    'sync: loop {
+        let synced = tokio::select! {
+            synced = full_sync(stream, &*shared.vault, &mut selected) => synced,
+            _ = shutdown_rx.recv() => return,
+        };
+        if let Err(e) = synced {
+            eprintln!("Failed to sync replica: {}", e);
+            return;
+        }
+        
+        loop {
+            let change = tokio::select! {
+                change = changes.recv() => change,
+                read = stream.read(&mut ignored) => match read {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => continue,
+                },
+                _ = shutdown_rx.recv() => return,
+            };
+            let frames = match change {
+                Ok(Change::Key(key)) => key_frames(&*shared.vault, &key, &mut selected).await,
+                Ok(Change::FlushAll) => Ok(encode_command(&Command::FlushAll)),
+                Ok(Change::FlushDb(namespace)) => {
+                    Ok(encode_command(&Command::FlushDb { namespace: Some(namespace) }))
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    println!("Replica fell {} changes behind; sending everything again", missed);
+                    continue 'sync;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let sent = async {
+                stream.write_all(&frames?).await?;
+                stream.flush().await?;
+                Ok::<_, RustVaultError>(())
+            };
+            if let Err(e) = sent.await {
+                eprintln!("Failed to send change to replica: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Send a `FLUSHALL` and then every live key, from the namespace
+/// `selected` on
+async fn full_sync<S: Store, T: AsyncWrite + Unpin>(stream: &mut T, store: &S, selected: &mut String) -> Result<()> {
+    stream.write_all(&encode_command(&Command::FlushAll)).await?;
+    let mut cursor = 0;
+    loop {
+        let page = store.scan("", cursor, SYNC_PAGE).await?;
+        let mut frames = Vec::new();
+        for key in page.keys {
+            // A key deleted since the scan found it is sent as a change
+            if let Some(entry) = store.get_with_deadline(&key).await? {
+                frames.extend_from_slice(&entry_frames(&key, entry, selected));
+            }
+        }
+        stream.write_all(&frames).await?;
+        if page.cursor == 0 {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+/// The commands that give a replica's copy of the stored key `stored` its
+/// current state, from the namespace `selected` on
+async fn key_frames<S: Store>(store: &S, stored: &str, selected: &mut String) -> Result<Vec<u8>> {
+    Ok(match store.get_with_deadline(stored).await? {
+        Some(entry) => entry_frames(stored, entry, selected),
+        None => {
+            let mut frames = Vec::new();
+            let key = select_frames(stored, selected, &mut frames);
+            frames.extend_from_slice(&encode_command(&Command::Delete { key }));
+            frames
+        }
+    })
+}
+
+fn entry_frames(stored: &str, (value, deadline): (Vec<u8>, Option<u64>), selected: &mut String) -> Vec<u8> {
+    let mut frames = Vec::new();
+    let key = select_frames(stored, selected, &mut frames);
+    frames.extend_from_slice(&encode_command(&Command::Set { key: key.clone(), value, condition: None }));
+    if let Some(unix_millis) = deadline {
+        frames.extend_from_slice(&encode_command(&Command::ExpireAt { key, unix_millis }));
+    }
+    frames
+}
+
+/// Add a `SELECT` to `frames` unless the stream is in the stored key's
+/// namespace already, and return the key as that namespace names it
+fn select_frames(stored: &str, selected: &mut String, frames: &mut Vec<u8>) -> String {
+    let (namespace, key) = namespace::split(stored);
+    if namespace != selected {
+        *selected = namespace.to_string();
+        frames.extend_from_slice(&encode_command(&Command::Select { namespace: selected.clone() }));
+    }
+    key.to_string()
+}
+
+/// Keep the store a copy of the one at `primary`, reconnecting whenever the
+/// connection is lost, until shutdown
+pub(super) async fn follow<S: Store>(
+    primary: String,
+    shared: Arc<Shared<S>>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let mut backoff = RETRY_BACKOFF;
+    loop {
+        let mut attached = false;
+        let result = tokio::select! {
+            result = replicate_from(&primary, &shared, &mut attached) => result,
+            _ = shutdown_rx.recv() => return,
+        };
+        match result {
+            Ok(()) => eprintln!("Primary {} closed the replication stream", primary),
+            Err(e) => eprintln!("Replication from {} failed: {}", primary, e),
+        }
+        if attached {
+            backoff = RETRY_BACKOFF;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown_rx.recv() => return,
+        }
+        backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+    }
+}
+
+/// Attach to `primary` and apply what it sends until the connection ends;
+/// `attached` is set once the primary has accepted the replica
+async fn replicate_from<S: Store>(primary: &str, shared: &Shared<S>, attached: &mut bool) -> Result<()> {
+    let mut stream = BufReader::new(TcpStream::connect(primary).await?);
+    // A replica shares its primary's token
+    if let Some(token) = &shared.auth_token {
+        request(&mut stream, Command::Auth { token: token.clone() }).await?;
+    }
+    request(&mut stream, Command::Replicate).await?;
+    println!("Replicating from {}", primary);
+    *attached = true;
+    
+    let mut selected = namespace::DEFAULT.to_string();
+    while let Some(frame) = read_frame(&mut stream).await? {
+        apply(shared, &mut selected, parse_command(&frame)?).await?;
+    }
+    Ok(())
+}
+
+/// Send `command` and check that it is answered `OK`
+async fn request(stream: &mut BufReader<TcpStream>, command: Command) -> Result<()> {
+    stream.get_mut().write_all(&encode_command(&command)).await?;
+    let mut reply = Vec::new();
+    stream.read_until(b'\n', &mut reply).await?;
+    match reply.trim_ascii() {
+        b"OK" => Ok(()),
+        b"" => Err(closed_early()),
+        reply => Err(RustVaultError::Server(format!(
+            "Primary refused {}: {}",
+            command.name(),
+            String::from_utf8_lossy(reply)
+        ))),
+    }
+}
+
+/// Read one command from the stream, with its value if it has one; `None`
+/// once the primary has closed it
+async fn read_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut frame = Vec::new();
+    if reader.read_until(b'\n', &mut frame).await? == 0 {
+        return Ok(None);
+    }
+    if !frame.ends_with(b"\n") {
+        return Err(closed_early());
+    }
+    if let Some(len) = payload_len(&frame)? {
+        let start = frame.len();
+        frame.resize(start + len + 2, 0);
+        reader.read_exact(&mut frame[start..]).await?;
+    }
+    Ok(Some(frame))
+}
+
+/// Apply a command from the primary in the namespace `selected`, and
+/// publish what it changed
+async fn apply<S: Store>(shared: &Shared<S>, selected: &mut String, command: Command) -> Result<()> {
+    let command = match command {
+        Command::Select { namespace } if namespace::is_valid(&namespace) => {
+            *selected = namespace;
+            return Ok(());
+        }
+        command => namespace::qualify_command(selected, command),
+    };
+    let events = shared.events.changes(&command);
+    let changes = shared.replication.changes(&command);
+    let store = shared.vault.store();
+    match command {
+        Command::Set { key, value, .. } => store.set(key, value).await?,
+        Command::ExpireAt { key, unix_millis } => {
+            store.expire_at(&key, unix_millis).await?;
+        }
+        Command::Delete { key } => {
+            store.delete(&key).await?;
+        }
+        Command::FlushAll => store.clear().await?,
+        Command::FlushDb { namespace: Some(namespace) } => store.clear_namespace(&namespace).await?,
+        command => {
+            return Err(RustVaultError::Server(format!(
+                "Unexpected {} in the replication stream",
+                command.name()
+            )))
+        }
+    }
+    shared.events.publish(events);
+    shared.replication.publish(changes);
+    Ok(())
+}
+
+fn closed_early() -> RustVaultError {
+    RustVaultError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Primary closed the connection partway through a frame",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    
+    #[test]
+    fn test_changes_name_every_written_key() {
+        let feed = ChangeFeed::default();
+        let key = |key: &str| Change::Key(key.to_string());
+        assert_eq!(
+            feed.changes(&Command::MSet { pairs: vec![("a".to_string(), b"1".to_vec()), ("b".to_string(), b"2".to_vec())] }),
+            vec![key("a"), key("b")]
+        );
+        assert_eq!(feed.changes(&Command::Expire { key: "a".to_string(), seconds: 5 }), vec![key("a")]);
+        assert_eq!(feed.changes(&Command::FlushAll), vec![Change::FlushAll]);
+        assert!(feed.changes(&Command::Get { key: "a".to_string() }).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_full_sync_sends_values_and_deadlines() {
+        let store = MemoryStore::new();
+        store.set("plain".to_string(), b"a b".to_vec()).await.unwrap();
+        store.set("ttl".to_string(), b"v".to_vec()).await.unwrap();
+        store.expire_at("ttl", 4_102_444_800_000).await.unwrap();
+        
+        let mut sent = Vec::new();
+        full_sync(&mut sent, &store, &mut namespace::DEFAULT.to_string()).await.unwrap();
+        let mut reader = &sent[..];
+        let mut commands = Vec::new();
+        while let Some(frame) = read_frame(&mut reader).await.unwrap() {
+            commands.push(parse_command(&frame).unwrap());
+        }
+        
+        assert_eq!(commands[0], Command::FlushAll);
+        assert_eq!(commands.len(), 4);
+        assert!(commands.contains(&Command::Set { key: "plain".to_string(), value: b"a b".to_vec(), condition: None }));
+        let ttl = commands.iter().position(|c| *c == Command::Set { key: "ttl".to_string(), value: b"v".to_vec(), condition: None });
+        assert_eq!(
+            commands[ttl.unwrap() + 1],
+            Command::ExpireAt { key: "ttl".to_string(), unix_millis: 4_102_444_800_000 }
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_keys_are_sent_from_their_namespace() {
+        let store = MemoryStore::new();
+        store.set(namespace::qualify("app", "k"), b"a".to_vec()).await.unwrap();
+        store.set("k".to_string(), b"b".to_vec()).await.unwrap();
+        
+        let mut selected = namespace::DEFAULT.to_string();
+        let mut sent = key_frames(&store, &namespace::qualify("app", "k"), &mut selected).await.unwrap();
+        sent.extend(key_frames(&store, &namespace::qualify("app", "gone"), &mut selected).await.unwrap());
+        sent.extend(key_frames(&store, "k", &mut selected).await.unwrap());
+        let mut reader = &sent[..];
+        let mut commands = Vec::new();
+        while let Some(frame) = read_frame(&mut reader).await.unwrap() {
+            commands.push(parse_command(&frame).unwrap());
+        }
+        
+        let select = |namespace: &str| Command::Select { namespace: namespace.to_string() };
+        assert_eq!(
+            commands,
+            vec![
+                select("app"),
+                Command::Set { key: "k".to_string(), value: b"a".to_vec(), condition: None },
+                Command::Delete { key: "gone".to_string() },
+                select(namespace::DEFAULT),
+                Command::Set { key: "k".to_string(), value: b"b".to_vec(), condition: None },
+            ]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Settings reported by `CONFIG GET` and changed by `CONFIG SET`
+//!
+//! The server keeps one [`RuntimeConfig`], filled in from its
+//! [`ServerConfig`] at startup. Connections read it as each command comes
+//! in, so a change applies from the next command on every connection,
+//! while a command already running finishes under the old settings.
+//! Changes aren't logged: a restart goes back to the configured values.
+//!
+//! Parameters are named as the `ServerConfig` fields are, and their values
+//! are written as in the config file. Only some of them can be changed;
+//! the rest are there to be read.
+
+use super::{ServerConfig, SizeLimits};
+use crate::protocol::glob_match;
+use crate::wal::SyncPolicy;
+use std::time::Duration;
+
+/// The server's current settings
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    pub bind_addr: String,
+    pub wal_path: String,
+    pub wal_sync: SyncPolicy,
+    pub max_key_bytes: usize,
+    pub max_value_bytes: usize,
+    pub idle_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    /// Refuse writes with `ERROR command not permitted`
+    pub read_only: bool,
+}
+
+/// Every parameter in the order `CONFIG GET *` lists them, and whether
+/// `CONFIG SET` may change it
+const PARAMETERS: &[(&str, bool)] = &[
+    ("bind_addr", false),
+    ("wal_path", false),
+    ("wal_sync", false),
+    ("max_key_bytes", true),
+    ("max_value_bytes", true),
+    ("idle_timeout", true),
+    ("read_timeout", true),
+    ("read_only", true),
+];
+
+/// The parameter `name` stands for, ignoring case; `readonly` is accepted
+/// for `read_only`
+fn parameter(name: &str) -> Option<(&'static str, bool)> {
+    let name = if name.eq_ignore_ascii_case("readonly") { "read_only" } else { name };
+    PARAMETERS.iter().copied().find(|(parameter, _)| parameter.eq_ignore_ascii_case(name))
+}
+
+fn secs(timeout: Option<Duration>) -> String {
+    match timeout {
+        Some(timeout) => timeout.as_secs_f64().to_string(),
+        None => "none".to_string(),
+    }
+}
+
+impl RuntimeConfig {
+    pub(super) fn new(config: &ServerConfig) -> Self {
+        Self {
+            bind_addr: config.bind_addr.clone(),
+            wal_path: config.wal_path.clone(),
+            wal_sync: config.wal_sync,
+            max_key_bytes: config.max_key_bytes,
+            max_value_bytes: config.max_value_bytes,
+            idle_timeout: config.idle_timeout,
+            read_timeout: config.read_timeout,
+            read_only: config.read_only,
+        }
+    }
+    
+    pub(super) fn limits(&self) -> SizeLimits {
+        SizeLimits {
+            max_key: self.max_key_bytes,
+            max_value: self.max_value_bytes,
+        }
+    }
+    
+    /// Value of the parameter `name`, or `None` if there is no such
+    /// parameter
+    pub fn get(&self, name: &str) -> Option<String> {
+        let (name, _) = parameter(name)?;
+        let value = match name {
+            "bind_addr" => self.bind_addr.clone(),
+            "wal_path" => self.wal_path.clone(),
+            "wal_sync" => match self.wal_sync {
+                SyncPolicy::Always => "always".to_string(),
+                SyncPolicy::Never => "never".to_string(),
+                SyncPolicy::EveryMillis(millis) => millis.to_string(),
+            },
+            "max_key_bytes" => self.max_key_bytes.to_string(),
+            "max_value_bytes" => self.max_value_bytes.to_string(),
+            "idle_timeout" => secs(self.idle_timeout),
+            "read_timeout" => secs(self.read_timeout),
+            "read_only" => if self.read_only { "1" } else { "0" }.to_string(),
+            _ => unreachable!("every parameter has a value"),
+        };
+        Some(value)
+    }
+    
+    /// Every parameter whose name matches the glob `pattern` and its value,
+    /// or just the one named, when `pattern` has no wildcards
+    pub fn matching(&self, pattern: &str) -> Vec<(String, String)> {
+        if !pattern.contains(['*', '?']) {
+            return parameter(pattern)
+                .and_then(|(name, _)| Some((name.to_string(), self.get(name)?)))
+                .into_iter()
+                .collect();
+        }
+        PARAMETERS
+            .iter()
+            .filter(|(name, _)| glob_match(pattern.to_ascii_lowercase().as_bytes(), name.as_bytes()))
+            .filter_map(|(name, _)| Some((name.to_string(), self.get(name)?)))
+            .collect()
+    }
+    
+    /// Set the parameter `name` from its text form
+    ///
+    /// Refused, leaving every setting as it was, for an unknown or
+    /// unchangeable parameter and for a value that doesn't parse.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let Some((parameter, mutable)) = parameter(name) else {
+            return Err(format!("unknown config parameter {}", name));
+        };
+        if !mutable {
+            return Err(format!("{} can't be changed while the server runs", parameter));
+        }
+        let invalid = |expected: &str| format!("CONFIG SET {} takes {}", name, expected);
+        let number = |value: &str| value.parse::<usize>().map_err(|_| invalid("a number of bytes"));
+        let timeout = |value: &str| match value {
+            "none" => Ok(None),
+            secs => secs
+                .parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .map(Some)
+                .ok_or_else(|| invalid("a number of seconds or none")),
+        };
+        match parameter {
+            "max_key_bytes" => self.max_key_bytes = number(value)?,
+            "max_value_bytes" => self.max_value_bytes = number(value)?,
+            "idle_timeout" => self.idle_timeout = timeout(value)?,
+            "read_timeout" => self.read_timeout = timeout(value)?,
+            "read_only" => {
+                self.read_only = match value {
+                    "1" => true,
+                    "0" => false,
+                    _ => return Err(invalid("1 or 0")),
+                }
+            }
+            _ => unreachable!("every mutable parameter can be set"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_get_and_set_parameters() {
+        let mut config = RuntimeConfig::new(&ServerConfig::default());
+        assert_eq!(config.get("wal_sync").as_deref(), Some("1000"));
+        assert_eq!(config.get("IDLE_TIMEOUT").as_deref(), Some("none"));
+        assert_eq!(config.get("nope"), None);
+        
+        config.set("idle_timeout", "2.5").unwrap();
+        config.set("readonly", "1").unwrap();
+        assert_eq!(config.idle_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(config.matching("read_only"), vec![("read_only".to_string(), "1".to_string())]);
+        
+        let timeouts: Vec<String> = config.matching("*_TIMEOUT").into_iter().map(|(name, _)| name).collect();
+        assert_eq!(timeouts, vec!["idle_timeout", "read_timeout"]);
+        assert_eq!(config.matching("*").len(), PARAMETERS.len());
+        assert!(config.matching("nope").is_empty());
+        
+        assert_eq!(
+            config.set("bind_addr", "0.0.0.0:1"),
+            Err("bind_addr can't be changed while the server runs".to_string())
+        );
+        assert_eq!(
+            config.set("max_value_bytes", "lots"),
+            Err("CONFIG SET max_value_bytes takes a number of bytes".to_string())
+        );
+        assert_eq!(config.max_value_bytes, ServerConfig::default().max_value_bytes);
+    }
+}
@@ -0,0 +1,197 @@
+//! The slow log: the latest commands that took longer than
+//! `slowlog_threshold`
+//!
+//! An entry names the command, the first key it was sent with and the
+//! client that sent it, but never a value, and the key is cut short at
+//! `slowlog_max_key_len` bytes. The log holds up to `slowlog_max_len`
+//! entries, dropping the oldest to make room. `SLOWLOG GET` reads the
+//! newest entries and `SLOWLOG RESET` empties it.
+
+use crate::protocol::Command;
+use crate::store::namespace;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Entries `SLOWLOG GET` sends when it isn't given a count
+pub const DEFAULT_GET_COUNT: usize = 10;
+
+/// One command that ran slowly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowLogEntry {
+    /// Counts up from 1 across the server's lifetime; a reset doesn't start
+    /// it over
+    pub id: u64,
+    /// When the command finished, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub duration: Duration,
+    /// The command's verb
+    pub command: String,
+    /// The first key the command named, as the client named it, or `None`
+    /// for a command without one
+    pub key: Option<String>,
+    /// Address of the connection the command came in on
+    pub client: String,
+}
+
+impl SlowLogEntry {
+    /// The entry as a `SLOWLOG GET` line sends it after the id:
+    /// `<timestamp> <micros> <command> <key> <client>`, the key empty for a
+    /// command without one
+    pub fn render(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.timestamp,
+            self.duration.as_micros(),
+            self.command,
+            self.key.as_deref().unwrap_or(""),
+            self.client
+        )
+    }
+    
+    /// Read back an entry from its id and [`SlowLogEntry::render`] form
+    pub fn parse(id: &str, fields: &str) -> Option<Self> {
+        let mut fields = fields.splitn(5, ' ');
+        let mut next = || fields.next();
+        let (timestamp, micros, command, key, client) = (next()?, next()?, next()?, next()?, next()?);
+        Some(Self {
+            id: id.parse().ok()?,
+            timestamp: timestamp.parse().ok()?,
+            duration: Duration::from_micros(micros.parse().ok()?),
+            command: command.to_string(),
+            key: (!key.is_empty()).then(|| key.to_string()),
+            client: client.to_string(),
+        })
+    }
+}
+
+/// The slow log of one server
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold: Option<Duration>,
+    max_len: usize,
+    max_key_len: usize,
+    last_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+}
+
+impl SlowLog {
+    /// A log of commands slower than `threshold`, keeping the latest
+    /// `max_len`; `None` or a `max_len` of 0 logs nothing
+    pub fn new(threshold: Option<Duration>, max_len: usize, max_key_len: usize) -> Self {
+        Self {
+            threshold: threshold.filter(|_| max_len > 0),
+            max_len,
+            max_key_len,
+            last_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+    
+    /// Whether a command that took `elapsed` belongs in the log
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        self.threshold.is_some_and(|threshold| elapsed > threshold)
+    }
+    
+    /// The key to log `command` under if it turns out slow, cut short and
+    /// without its namespace; taken before the command runs, since running
+    /// it consumes it
+    ///
+    /// Always `None` when nothing is logged, so a command costs no copy of
+    /// its key then.
+    pub fn key_of(&self, command: &Command) -> Option<String> {
+        self.threshold?;
+        let key = namespace::split(command.key()?).1;
+        let mut end = key.len().min(self.max_key_len);
+        while !key.is_char_boundary(end) {
+            end -= 1;
+        }
+        Some(key[..end].to_string()).filter(|key| !key.is_empty())
+    }
+    
+    /// Log a command that took `duration`, finishing at `timestamp`
+    pub fn record(&self, command: &str, key: Option<String>, duration: Duration, client: &str, timestamp: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        // Taken under the lock, so ids are in the order entries are kept
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        if entries.len() >= self.max_len {
+            entries.pop_front();
+        }
+        entries.push_back(SlowLogEntry {
+            id,
+            timestamp,
+            duration,
+            command: command.to_string(),
+            key,
+            client: client.to_string(),
+        });
+    }
+    
+    /// Up to `count` entries, newest first
+    pub fn latest(&self, count: usize) -> Vec<SlowLogEntry> {
+        self.entries.lock().unwrap().iter().rev().take(count).cloned().collect()
+    }
+    
+    /// Drop every entry
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_keeps_the_latest_entries() {
+        let log = SlowLog::new(Some(Duration::from_millis(10)), 3, 4);
+        assert!(!log.is_slow(Duration::from_millis(10)));
+        assert!(log.is_slow(Duration::from_millis(11)));
+        
+        let get = Command::Get { key: namespace::qualify("app", "abcdefgh") };
+        assert_eq!(log.key_of(&get).as_deref(), Some("abcd"));
+        assert_eq!(log.key_of(&Command::Ping), None);
+        for i in 0..5 {
+            log.record("GET", log.key_of(&get), Duration::from_millis(20 + i), "127.0.0.1:1", 1000 + i);
+        }
+        let latest = log.latest(10);
+        assert_eq!(latest.iter().map(|entry| entry.id).collect::<Vec<_>>(), [5, 4, 3]);
+        assert_eq!(latest[0].key.as_deref(), Some("abcd"));
+        assert_eq!(log.latest(1).len(), 1);
+        
+        log.reset();
+        assert!(log.latest(10).is_empty());
+        log.record("PING", None, Duration::from_millis(20), "unix client", 1);
+        assert_eq!(log.latest(10)[0].id, 6);
+        
+        // Keys are cut at a character boundary
+        let log = SlowLog::new(Some(Duration::ZERO), 1, 3);
+        assert_eq!(log.key_of(&Command::Get { key: "aé!".to_string() }).as_deref(), Some("aé"));
+        assert_eq!(log.key_of(&Command::Get { key: "éé".to_string() }).as_deref(), Some("é"));
+        let log = SlowLog::new(Some(Duration::ZERO), 1, 1);
+        assert_eq!(log.key_of(&Command::Get { key: "é".to_string() }), None);
+        
+        assert!(!SlowLog::new(None, 3, 4).is_slow(Duration::MAX));
+        assert_eq!(SlowLog::new(None, 3, 4).key_of(&get), None);
+        assert!(!SlowLog::new(Some(Duration::ZERO), 0, 4).is_slow(Duration::MAX));
+    }
+    
+    #[test]
+    fn test_entry_roundtrip() {
+        let entry = SlowLogEntry {
+            id: 7,
+            timestamp: 1_700_000_000_000,
+            duration: Duration::from_micros(250_123),
+            command: "GET".to_string(),
+            key: Some("user:1".to_string()),
+            client: "127.0.0.1:5000".to_string(),
+        };
+        assert_eq!(entry.render(), "1700000000000 250123 GET user:1 127.0.0.1:5000");
+        assert_eq!(SlowLogEntry::parse("7", &entry.render()), Some(entry.clone()));
+        
+        let keyless = SlowLogEntry { key: None, client: "unix client".to_string(), ..entry };
+        assert_eq!(SlowLogEntry::parse("7", &keyless.render()), Some(keyless));
+        assert_eq!(SlowLogEntry::parse("7", "12 34 GET"), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Watchdog for commands that stop making progress
+//!
+//! Every connection registers in a shared [`ConnTable`] and records the
+//! command it is executing. [`WatchdogJob`] scans the table from the
+//! maintenance scheduler and reports commands that have been running longer
+//! than the configured threshold, optionally closing their connection.
+
+use super::maintenance::{JobFuture, MaintenanceJob};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// What the watchdog does about a command past the threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HungCommandAction {
+    /// Log a warning and count it
+    #[default]
+    Warn,
+    /// Also close the connection running it
+    Kill,
+}
+
+/// A command currently being executed on a connection
+#[derive(Debug)]
+struct InFlight {
+    name: String,
+    started: Instant,
+    /// Whether the watchdog has already reported this command
+    reported: bool,
+}
+
+#[derive(Debug)]
+struct ConnState {
+    peer: String,
+    in_flight: Option<InFlight>,
+    kill: Arc<Notify>,
+}
+
+/// Registry of open connections and what each is executing
+#[derive(Debug, Default)]
+pub struct ConnTable {
+    next_id: AtomicU64,
+    conns: Mutex<HashMap<u64, ConnState>>,
+    hung_commands: AtomicU64,
+}
+
+impl ConnTable {
+    /// Register a connection; it is removed again when the guard drops
+    pub fn register(&self, peer: String) -> ConnGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let kill = Arc::new(Notify::new());
+        self.conns.lock().unwrap().insert(
+            id,
+            ConnState {
+                peer,
+                in_flight: None,
+                kill: Arc::clone(&kill),
+            },
+        );
+        ConnGuard { table: self, id, kill }
+    }
+    
+    /// Number of connections currently registered
+    pub fn open_connections(&self) -> usize {
+        self.conns.lock().unwrap().len()
+    }
+    
+    /// Number of commands the watchdog has reported as hung
+    pub fn hung_commands(&self) -> u64 {
+        self.hung_commands.load(Ordering::Relaxed)
+    }
+    
+    /// Report commands running longer than `threshold`
+    ///
+    /// Each command is reported once. Returns the number newly reported.
+    pub fn scan(&self, threshold: Duration, action: HungCommandAction) -> usize {
+        let mut found = 0;
+        let mut conns = self.conns.lock().unwrap();
+        for (id, conn) in conns.iter_mut() {
+            let in_flight = match &mut conn.in_flight {
+                Some(in_flight) if !in_flight.reported => in_flight,
+                _ => continue,
+            };
+            let elapsed = in_flight.started.elapsed();
+            if elapsed < threshold {
+                continue;
+            }
+            
+            in_flight.reported = true;
+            found += 1;
+            eprintln!(
+                "Watchdog: {} on connection {} ({}) has been running for {:.1?}",
+                in_flight.name, id, conn.peer, elapsed
+            );
+            if action == HungCommandAction::Kill {
+                eprintln!("Watchdog: closing connection {} ({})", id, conn.peer);
+                conn.kill.notify_one();
+            }
+        }
+        self.hung_commands.fetch_add(found as u64, Ordering::Relaxed);
+        found
+    }
+}
+
+/// A connection's entry in the [`ConnTable`]
+pub struct ConnGuard<'a> {
+    table: &'a ConnTable,
+    id: u64,
+    kill: Arc<Notify>,
+}
+
+impl ConnGuard<'_> {
+    /// Record that `name` started executing
+    pub fn begin(&self, name: &str) {
+        if let Some(conn) = self.table.conns.lock().unwrap().get_mut(&self.id) {
+            conn.in_flight = Some(InFlight {
+                name: name.to_string(),
+                started: Instant::now(),
+                reported: false,
+            });
+        }
+    }
+    
+    /// Record that the current command finished
+    pub fn end(&self) {
+        if let Some(conn) = self.table.conns.lock().unwrap().get_mut(&self.id) {
+            conn.in_flight = None;
+        }
+    }
+    
+    /// Resolves once the watchdog decides to close this connection
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+}
+
+impl Drop for ConnGuard<'_> {
+    fn drop(&mut self) {
+        self.table.conns.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Scans a [`ConnTable`] every half `threshold`
+pub struct WatchdogJob {
+    table: Arc<ConnTable>,
+    threshold: Duration,
+    action: HungCommandAction,
+}
+
+impl WatchdogJob {
+    pub fn new(table: Arc<ConnTable>, threshold: Duration, action: HungCommandAction) -> Self {
+        Self {
+            table,
+            threshold,
+            action,
+        }
+    }
+}
+
+impl MaintenanceJob for WatchdogJob {
+    fn name(&self) -> &'static str {
+        "watchdog"
+    }
+    
+    fn interval(&self) -> Duration {
+        (self.threshold / 2).max(Duration::from_millis(10))
+    }
+    
+    fn run(&self) -> JobFuture<'_> {
+        Box::pin(async move {
+            self.table.scan(self.threshold, self.action);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_scan_reports_each_command_once() {
+        let table = ConnTable::default();
+        let idle = table.register("idle".to_string());
+        let busy = table.register("busy".to_string());
+        busy.begin("GET");
+        
+        // Nothing has run for an hour yet
+        assert_eq!(table.scan(Duration::from_secs(3600), HungCommandAction::Warn), 0);
+        
+        assert_eq!(table.scan(Duration::ZERO, HungCommandAction::Warn), 1);
+        assert_eq!(table.scan(Duration::ZERO, HungCommandAction::Warn), 0);
+        assert_eq!(table.hung_commands(), 1);
+        
+        // The next command is a new one
+        busy.end();
+        assert_eq!(table.scan(Duration::ZERO, HungCommandAction::Warn), 0);
+        busy.begin("SET");
+        assert_eq!(table.scan(Duration::ZERO, HungCommandAction::Warn), 1);
+        assert_eq!(table.hung_commands(), 2);
+        
+        drop(idle);
+        drop(busy);
+        assert!(table.conns.lock().unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_kill_action_signals_connection() {
+        let table = ConnTable::default();
+        let conn = table.register("busy".to_string());
+        conn.begin("GET");
+        
+        table.scan(Duration::ZERO, HungCommandAction::Kill);
+        tokio::time::timeout(Duration::from_secs(1), conn.killed())
+            .await
+            .expect("connection was not signalled");
+    }
+}
\ No newline at end of file
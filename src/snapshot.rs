@@ -0,0 +1,307 @@
+//! Snapshot files: the whole store in one file, for restarts that replay only
+//! the WAL written since
+//!
+//! The format is binary, little-endian throughout:
+//!
+//! ```text
+//! magic     "RVSNAP02"
+//! offset    u64   checkpoint the entries reflect
+//! tail      u64
+//! count     u64
+//! entries   count x (key_len u32, key, value_len u64, value, expires_at u64,
+//!                    version u64, created_at u64, updated_at u64)
+//! digest    u64   FNV-1a of every byte before it
+//! ```
+//!
+//! An `expires_at` of 0 means the key has no TTL, and a `version` of 0 that
+//! its metadata isn't known. Snapshots written before
+//! keys had metadata start with "RVSNAP01" and end each entry at
+//! `expires_at`; they are still read.
+
+use crate::error::{RustVaultError, Result};
+use crate::store::{fnv1a, KeyStat, FNV_OFFSET};
+use crate::wal::Checkpoint;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"RVSNAP02";
+
+/// Magic of snapshots without key metadata
+const MAGIC_V1: &[u8; 8] = b"RVSNAP01";
+
+/// One key in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// Deadline in milliseconds since the epoch
+    pub expires_at: Option<u64>,
+    /// `None` when read from a snapshot that predates key metadata
+    pub stat: Option<KeyStat>,
+}
+
+/// Write `entries` to a snapshot file at `path`, as of `checkpoint`
+///
+/// The file is written beside `path` and synced before being renamed over
+/// it, so a crash leaves either the old snapshot or the new one.
+pub fn write(path: &Path, checkpoint: Checkpoint, entries: &[SnapshotEntry]) -> Result<()> {
+    replace(path, |temp_path| write_file(temp_path, checkpoint, entries))
+}
+
+/// Have `write_file` write a file beside `path`, then rename it over `path`
+pub(crate) fn replace<F>(path: &Path, write_file: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> io::Result<()>,
+{
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let result = write_file(Path::new(&temp_path)).and_then(|()| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    Ok(result?)
+}
+
+fn write_file(path: &Path, checkpoint: Checkpoint, entries: &[SnapshotEntry]) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    let mut writer = DigestWriter {
+        inner: BufWriter::new(file),
+        digest: FNV_OFFSET,
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_all(&checkpoint.offset.to_le_bytes())?;
+    writer.write_all(&checkpoint.tail.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for entry in entries {
+        writer.write_all(&(entry.key.len() as u32).to_le_bytes())?;
+        writer.write_all(entry.key.as_bytes())?;
+        writer.write_all(&(entry.value.len() as u64).to_le_bytes())?;
+        writer.write_all(&entry.value)?;
+        writer.write_all(&entry.expires_at.unwrap_or(0).to_le_bytes())?;
+        let stat = entry.stat.unwrap_or(KeyStat { version: 0, created_at: 0, updated_at: 0 });
+        for field in [stat.version, stat.created_at, stat.updated_at] {
+            writer.write_all(&field.to_le_bytes())?;
+        }
+    }
+    let digest = writer.digest;
+    let mut inner = writer.inner;
+    inner.write_all(&digest.to_le_bytes())?;
+    inner.flush()?;
+    inner.get_ref().sync_all()
+}
+
+/// Read the snapshot at `path`, handing each entry to `apply_fn`
+///
+/// Returns the checkpoint the entries reflect, or `None` if there is no
+/// snapshot. Entries are handed over as they are read, before the digest is
+/// checked, so on an error the caller must discard what it was given.
+pub fn read<F>(path: &Path, mut apply_fn: F) -> Result<Option<Checkpoint>>
+where
+    F: FnMut(SnapshotEntry),
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut remaining = file.metadata()?.len();
+    let mut reader = DigestReader {
+        inner: BufReader::new(file),
+        digest: FNV_OFFSET,
+    };
+    let mut take = |reader: &mut DigestReader<BufReader<File>>, len: u64| -> Result<Vec<u8>> {
+        // Checked against the file size so a corrupt length can't ask for
+        // more memory than the file could hold
+        if len > remaining {
+            return Err(corrupt("truncated"));
+        }
+        remaining -= len;
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    };
+    
+    let has_stats = match take(&mut reader, 8)? {
+        magic if magic == MAGIC => true,
+        magic if magic == MAGIC_V1 => false,
+        _ => return Err(corrupt("not a snapshot file")),
+    };
+    let checkpoint = Checkpoint {
+        offset: u64_from(&take(&mut reader, 8)?),
+        tail: u64_from(&take(&mut reader, 8)?),
+    };
+    let count = u64_from(&take(&mut reader, 8)?);
+    for _ in 0..count {
+        let key_len = u32::from_le_bytes(take(&mut reader, 4)?.try_into().unwrap());
+        let key = String::from_utf8(take(&mut reader, key_len as u64)?)
+            .map_err(|_| corrupt("key is not UTF-8"))?;
+        let value_len = u64_from(&take(&mut reader, 8)?);
+        let value = take(&mut reader, value_len)?;
+        let expires_at = u64_from(&take(&mut reader, 8)?);
+        let stat = match has_stats {
+            true => Some(KeyStat {
+                version: u64_from(&take(&mut reader, 8)?),
+                created_at: u64_from(&take(&mut reader, 8)?),
+                updated_at: u64_from(&take(&mut reader, 8)?),
+            }),
+            false => None,
+        };
+        let stat = stat.filter(|stat| stat.version != 0);
+        apply_fn(SnapshotEntry {
+            key,
+            value,
+            expires_at: (expires_at != 0).then_some(expires_at),
+            stat,
+        });
+    }
+    
+    let expected = reader.digest;
+    if u64_from(&take(&mut reader, 8)?) != expected {
+        return Err(corrupt("digest mismatch"));
+    }
+    if remaining != 0 {
+        return Err(corrupt("trailing bytes after the digest"));
+    }
+    Ok(Some(checkpoint))
+}
+
+fn u64_from(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn corrupt(reason: &str) -> RustVaultError {
+    RustVaultError::Snapshot(format!("Corrupt snapshot: {}", reason))
+}
+
+/// Writer that keeps a digest of everything written through it
+pub(crate) struct DigestWriter<W> {
+    pub(crate) inner: W,
+    pub(crate) digest: u64,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest = fnv1a(self.digest, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that keeps a digest of everything read through it
+pub(crate) struct DigestReader<R> {
+    pub(crate) inner: R,
+    pub(crate) digest: u64,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.digest = fnv1a(self.digest, &buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entries() -> Vec<SnapshotEntry> {
+        vec![
+            SnapshotEntry {
+                key: "key1".to_string(),
+                value: b"value1".to_vec(),
+                expires_at: None,
+                stat: Some(KeyStat { version: 1, created_at: 1_600_000_000_000, updated_at: 1_600_000_000_000 }),
+            },
+            SnapshotEntry {
+                key: "key2".to_string(),
+                value: vec![0, 1, 2, 255],
+                expires_at: Some(1_700_000_000_000),
+                stat: Some(KeyStat { version: 3, created_at: 1_600_000_000_000, updated_at: 1_650_000_000_000 }),
+            },
+        ]
+    }
+
+    fn read_all(path: &Path) -> Result<Option<(Checkpoint, Vec<SnapshotEntry>)>> {
+        let mut read_entries = Vec::new();
+        let checkpoint = read(path, |entry| read_entries.push(entry))?;
+        Ok(checkpoint.map(|checkpoint| (checkpoint, read_entries)))
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("snapshot");
+        let checkpoint = Checkpoint { offset: 1234, tail: 42 };
+        
+        assert!(read_all(&path).unwrap().is_none());
+        write(&path, checkpoint, &entries()).unwrap();
+        assert_eq!(read_all(&path).unwrap(), Some((checkpoint, entries())));
+        
+        // Rewriting replaces the old snapshot
+        write(&path, Checkpoint::START, &[]).unwrap();
+        assert_eq!(read_all(&path).unwrap(), Some((Checkpoint::START, Vec::new())));
+        assert!(!dir.path().join("snapshot.tmp").exists());
+    }
+
+    #[test]
+    fn test_snapshot_without_metadata_is_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("snapshot");
+        let mut bytes = MAGIC_V1.to_vec();
+        for field in [1234u64, 42, 1] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(b"key1");
+        bytes.extend_from_slice(&6u64.to_le_bytes());
+        bytes.extend_from_slice(b"value1");
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&fnv1a(FNV_OFFSET, &bytes).to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        
+        let expected = SnapshotEntry { stat: None, ..entries()[0].clone() };
+        assert_eq!(
+            read_all(&path).unwrap(),
+            Some((Checkpoint { offset: 1234, tail: 42 }, vec![expected.clone()]))
+        );
+        
+        // An entry without metadata is written so that it reads back as such
+        write(&path, Checkpoint::START, std::slice::from_ref(&expected)).unwrap();
+        assert_eq!(read_all(&path).unwrap(), Some((Checkpoint::START, vec![expected])));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_torn_and_corrupt_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("snapshot");
+        write(&path, Checkpoint { offset: 1234, tail: 42 }, &entries()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        
+        for len in [0, 7, 30, bytes.len() - 9, bytes.len() - 1] {
+            std::fs::write(&path, &bytes[..len]).unwrap();
+            let err = read_all(&path).unwrap_err();
+            assert!(matches!(err, RustVaultError::Snapshot(_) | RustVaultError::Io(_)), "{}", err);
+        }
+        
+        let mut flipped = bytes.clone();
+        flipped[40] ^= 1;
+        std::fs::write(&path, &flipped).unwrap();
+        assert!(matches!(read_all(&path), Err(RustVaultError::Snapshot(_))));
+        
+        let mut extended = bytes;
+        extended.push(0);
+        std::fs::write(&path, &extended).unwrap();
+        assert!(matches!(read_all(&path), Err(RustVaultError::Snapshot(_))));
+    }
+}
\ No newline at end of file
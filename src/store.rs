@@ -2,221 +2,2589 @@
 //! 
 //! Provides a thread-safe store using Arc and RwLock for concurrent access
 
-use crate::error::Result;
-use crate::protocol::Command;
-use crate::wal::WriteAheadLog;
+pub mod compression;
+pub mod eviction;
+pub mod hasher;
+pub mod namespace;
+pub mod sharded;
+
+use crate::error::{Result, RustVaultError};
+use crate::protocol::{Command, SetCondition};
+use crate::snapshot::{self, SnapshotEntry};
+use crate::wal::{self, now_millis, Checkpoint, KeyHistory, ReplayProgress, WalEntry, WriteAheadLog};
+use compression::{Compressor, StoredValue};
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::mem;
+use std::ops::DerefMut;
+use std::path::Path;
+use std::slice;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
+use eviction::Memory;
+pub use compression::{CompressionAlgorithm, CompressionConfig, CompressionStats};
+pub use eviction::EvictionPolicy;
+pub use hasher::{ConfiguredHasher, HasherKind};
+pub use sharded::ShardedMemoryStore;
 
 /// Trait defining the interface for key-value storage operations
+///
+/// Every future is `Send`, so a server can drive the store from spawned
+/// tasks.
 pub trait Store: Send + Sync {
     /// Set a key-value pair
-    async fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: Vec<u8>) -> impl Future<Output = Result<()>> + Send;
+    
+    /// Set a key-value pair that expires after `ttl`
+    fn set_with_ttl(&self, key: String, value: Vec<u8>, ttl: Duration) -> impl Future<Output = Result<()>> + Send;
+    
+    /// Set a key-value pair, expiring after `ttl` if one is given, only if
+    /// the key is absent or present as `condition` says, checked and
+    /// written as one step; false, with nothing changed, if it isn't
+    fn set_if(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        condition: SetCondition,
+    ) -> impl Future<Output = Result<bool>> + Send;
     
     /// Get a value by key
-    async fn get(&self, key: &str) -> Result<Option<String>>;
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+    
+    /// Set several key-value pairs at once, clearing any TTLs they had
+    ///
+    /// Readers see either none of the pairs or all of them. A key listed
+    /// twice ends up with its last value.
+    fn mset(&self, pairs: Vec<(String, Vec<u8>)>) -> impl Future<Output = Result<()>> + Send;
+    
+    /// Get a value and its expiry deadline, in milliseconds since the Unix
+    /// epoch; the deadline is `None` for a key without a TTL
+    ///
+    /// The default reports every key as having no TTL.
+    fn get_with_deadline(&self, key: &str) -> impl Future<Output = Result<Option<(Vec<u8>, Option<u64>)>>> + Send {
+        async move { Ok(self.get(key).await?.map(|value| (value, None))) }
+    }
+    
+    /// How many times `key` has been set and when, or `None` if it doesn't
+    /// exist; the default refuses
+    fn stat(&self, key: &str) -> impl Future<Output = Result<Option<KeyStat>>> + Send {
+        let _ = key;
+        async {
+            Err(RustVaultError::InvalidCommand(
+                "This store doesn't keep key metadata".to_string(),
+            ))
+        }
+    }
+    
+    /// Get several values at once, `None` for keys that don't exist
+    fn mget(&self, keys: &[String]) -> impl Future<Output = Result<Vec<Option<Vec<u8>>>>> + Send;
     
     /// Delete a key-value pair
-    async fn delete(&self, key: &str) -> Result<bool>;
+    fn delete(&self, key: &str) -> impl Future<Output = Result<bool>> + Send;
+    
+    /// Set `key` to `new` only if its value is currently `expected`; false,
+    /// with nothing changed, if it isn't or the key doesn't exist
+    fn cas(&self, key: String, expected: &[u8], new: Vec<u8>) -> impl Future<Output = Result<bool>> + Send;
+    
+    /// Take the lock `key` for `ttl`, as one step, if it is free, its lease
+    /// has run out or it is already held with `token`, which renews it;
+    /// false if another token holds it
+    ///
+    /// A lock is a key holding its token until its lease expires.
+    fn lock(&self, key: String, token: &str, ttl: Duration) -> impl Future<Output = Result<bool>> + Send;
+    
+    /// Release the lock `key` if it is held with `token`; false, with
+    /// nothing changed, if it is free or held with another token
+    fn unlock(&self, key: &str, token: &str) -> impl Future<Output = Result<bool>> + Send;
+    
+    /// Add `delta` to the integer stored at `key`, counting from 0 if the
+    /// key doesn't exist, and return the result
+    ///
+    /// Fails, leaving the value alone, if it isn't a decimal integer or the
+    /// result would overflow an `i64`.
+    fn incr(&self, key: &str, delta: i64) -> impl Future<Output = Result<i64>> + Send;
+    
+    /// Append `value` to the value stored at `key`, creating the key if it
+    /// doesn't exist, and return the new length
+    fn append(&self, key: &str, value: &[u8]) -> impl Future<Output = Result<usize>> + Send;
+    
+    /// Set `key` to `value`, clearing any TTL, and return the value it
+    /// replaces as one step, so concurrent callers each see a different
+    /// old value
+    fn getset(&self, key: String, value: Vec<u8>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+    
+    /// Delete `key` and return the value it held as one step
+    fn getdel(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+    
+    /// Length of the value stored at `key`, or `None` if it doesn't exist
+    fn strlen(&self, key: &str) -> impl Future<Output = Result<Option<usize>>> + Send {
+        async move { Ok(self.get(key).await?.map(|value| value.len())) }
+    }
+    
+    /// Make an existing key expire after `ttl`; false if the key doesn't exist
+    fn expire(&self, key: &str, ttl: Duration) -> impl Future<Output = Result<bool>> + Send;
     
     /// Check if a key exists
-    async fn exists(&self, key: &str) -> Result<bool>;
+    fn exists(&self, key: &str) -> impl Future<Output = Result<bool>> + Send;
     
     /// Get all key-value pairs (for WAL compaction)
-    async fn get_all(&self) -> Result<Vec<(String, String)>>;
+    fn get_all(&self) -> impl Future<Output = Result<Vec<(String, Vec<u8>)>>> + Send;
     
-    /// Clear all data
-    async fn clear(&self) -> Result<()>;
+    /// Get up to `count` keys starting with `prefix`, resuming at `cursor`
+    /// (0 to start)
+    fn scan(&self, prefix: &str, cursor: u64, count: usize) -> impl Future<Output = Result<ScanPage>> + Send;
+    
+    /// Get up to `limit` key-value pairs in key order, starting just after
+    /// the key `cursor` (`None` to start)
+    ///
+    /// The default sorts a copy of [`Store::get_all`] for every page.
+    fn scan_page(&self, cursor: Option<&str>, limit: usize) -> impl Future<Output = Result<EntryPage>> + Send {
+        async move {
+            let mut all = self.get_all().await?;
+            all.retain(|(key, _)| cursor.is_none_or(|cursor| key.as_str() > cursor));
+            Ok(EntryPage::first(all, limit))
+        }
+    }
+    
+    /// Copies of every live key's value, deadline and metadata, as of one
+    /// moment, for a backup
+    ///
+    /// The default copies [`Store::get_all`], reporting every key as having
+    /// no TTL and no metadata.
+    fn live_entries(&self) -> impl Future<Output = Result<Vec<SnapshotEntry>>> + Send {
+        async move {
+            let all = self.get_all().await?;
+            Ok(all
+                .into_iter()
+                .map(|(key, value)| SnapshotEntry { key, value, expires_at: None, stat: None })
+                .collect())
+        }
+    }
+    
+    /// Clear all data, logging it so a restart doesn't bring it back
+    fn clear(&self) -> impl Future<Output = Result<()>> + Send;
+    
+    /// Remove every key in `namespace`, logging it as `clear` is logged;
+    /// the default refuses
+    fn clear_namespace(&self, namespace: &str) -> impl Future<Output = Result<()>> + Send {
+        let _ = namespace;
+        async {
+            Err(RustVaultError::InvalidCommand(
+                "This store doesn't support namespaces".to_string(),
+            ))
+        }
+    }
+    
+    /// Remove every key matching the glob `pattern` as one step, and return
+    /// the live ones removed; the default refuses
+    ///
+    /// The pattern is in stored form, as for [`namespace::matches`], so it
+    /// only reaches keys in its own namespace.
+    fn delete_pattern(&self, pattern: &str) -> impl Future<Output = Result<Vec<String>>> + Send {
+        let _ = pattern;
+        async {
+            Err(RustVaultError::InvalidCommand(
+                "This store doesn't support deleting by pattern".to_string(),
+            ))
+        }
+    }
+    
+    /// Apply `ops` in order as one step, if every key in `watched` still
+    /// has the [`KeyStat`] given for it (`None` for a missing key)
+    ///
+    /// Readers see none of the writes or all of them, and the writes are
+    /// logged as one batch. Each op sees the ones before it. `None`, with
+    /// nothing changed, if a watched key has been set or removed since its
+    /// stat was taken, even if it was set back to the same value. The
+    /// default refuses, since it can't make the ops atomic.
+    fn apply_batch(
+        &self,
+        watched: Vec<(String, Option<KeyStat>)>,
+        ops: Vec<BatchOp>,
+    ) -> impl Future<Output = Result<Option<Vec<BatchOutcome>>>> + Send {
+        let _ = (watched, ops);
+        async {
+            Err(RustVaultError::InvalidCommand(
+                "This store doesn't support transactions".to_string(),
+            ))
+        }
+    }
     
     /// Get the number of stored items
-    async fn len(&self) -> Result<usize>;
+    fn len(&self) -> impl Future<Output = Result<usize>> + Send;
+    
+    /// Get the number of stored items in `namespace`; the default counts
+    /// a snapshot from [`Store::get_all`]
+    fn namespace_len(&self, namespace: &str) -> impl Future<Output = Result<usize>> + Send {
+        async move {
+            let entries = self.get_all().await?;
+            Ok(entries.iter().filter(|(key, _)| namespace::contains(namespace, key)).count())
+        }
+    }
+    
+    /// Check if the store holds no items
+    fn is_empty(&self) -> impl Future<Output = Result<bool>> + Send {
+        async move { Ok(self.len().await? == 0) }
+    }
+    
+    /// Make `key` expire at `unix_millis`, in milliseconds since the Unix
+    /// epoch; false if the key doesn't exist
+    ///
+    /// A deadline already in the past expires the key at once. The default
+    /// sets the equivalent TTL through [`Store::expire`].
+    fn expire_at(&self, key: &str, unix_millis: u64) -> impl Future<Output = Result<bool>> + Send {
+        async move {
+            let ttl = Duration::from_millis(unix_millis.saturating_sub(now_millis()));
+            self.expire(key, ttl).await
+        }
+    }
+    
+    /// Digest of every entry whose key starts with `prefix`
+    ///
+    /// Entry digests are summed, wrapping, so any two stores holding the
+    /// same entries agree, whatever their implementation, and the digest of
+    /// a subset of the entries can be taken back out. Only
+    /// values are digested, not TTLs, and expired keys are skipped. The
+    /// default digests a snapshot from [`Store::get_all`].
+    fn checksum(&self, prefix: &str) -> impl Future<Output = Result<u64>> + Send {
+        async move {
+            let entries = self.get_all().await?;
+            Ok(prefix_digest(
+                prefix,
+                entries.iter().map(|(key, value)| (key.as_str(), value.as_slice())),
+            ))
+        }
+    }
+    
+    /// Digests of the entries under `prefix`, split into `buckets` ranges
+    ///
+    /// A key goes in bucket `b * buckets / 256`, where `b` is its first byte
+    /// after the prefix, so buckets are contiguous lexicographic ranges and
+    /// 256 buckets give one per next byte. A key equal to `prefix` has no
+    /// such byte and is in no bucket. `buckets` is clamped to 1..=256.
+    /// Expired keys are left out, as in [`Store::checksum`].
+    fn checksum_ranges(&self, buckets: usize, prefix: &str) -> impl Future<Output = Result<Vec<u64>>> + Send {
+        async move {
+            let entries = self.get_all().await?;
+            Ok(range_digests(
+                buckets,
+                prefix,
+                entries.iter().map(|(key, value)| (key.as_str(), value.as_slice())),
+            ))
+        }
+    }
+    
+    /// Give back memory still held for data that is gone; the default has
+    /// nothing to give back
+    fn shrink(&self) -> impl Future<Output = ShrinkReport> + Send {
+        async { ShrinkReport { before: 0, after: 0 } }
+    }
+    
+    /// Estimated bytes held against those the live data needs; the
+    /// default reports nothing held
+    fn memory_usage(&self) -> impl Future<Output = MemoryUsage> + Send {
+        async { MemoryUsage::default() }
+    }
+    
+    /// Remove every key whose TTL has run out, returning how many; the
+    /// default, for a store without TTLs, has none
+    fn sweep_expired(&self) -> impl Future<Output = usize> + Send {
+        async { 0 }
+    }
+    
+    /// What compression has done since the store was created, or `None` for
+    /// a store that doesn't compress values
+    fn compression_stats(&self) -> Option<CompressionStats> {
+        None
+    }
+    
+    /// Entries removed from the store whose memory hasn't been freed yet;
+    /// the default, for a store that frees them as they go, is 0
+    fn pending_free(&self) -> usize {
+        0
+    }
+    
+    /// Rewrite the store's log down to its live data; the default, for a
+    /// store that keeps no log, does nothing
+    fn compact_wal(&self) -> impl Future<Output = Result<CompactionReport>> + Send {
+        async { Ok(CompactionReport { before: 0, after: 0, keys: 0 }) }
+    }
+    
+    /// Write the store's contents to a snapshot file at `path`, for
+    /// [`Store::restore_blocking`] to start from; the default refuses
+    fn snapshot_to(&self, path: &Path) -> impl Future<Output = Result<()>> + Send {
+        let _ = path;
+        async { Err(RustVaultError::Snapshot("This store doesn't take snapshots".to_string())) }
+    }
+    
+    /// Load whatever the store persists itself, before it is served
+    ///
+    /// Called once at startup from a blocking thread, with the snapshot
+    /// file to start from, if one is configured, and `progress` reported as
+    /// it goes. The default has nothing to load.
+    fn restore_blocking<P>(&self, snapshot: Option<&Path>, progress: P) -> Result<()>
+    where
+        P: FnMut(ReplayProgress),
+    {
+        let _ = (snapshot, progress);
+        Ok(())
+    }
 }
 
 /// Thread-safe in-memory key-value store
-pub struct MemoryStore {
-    data: Arc<RwLock<HashMap<String, String>>>,
+///
+/// The map hasher is pluggable through `S`. The default, SipHash
+/// (`RandomState`), is randomly seeded and resists HashDoS: a client that
+/// controls key names cannot craft collisions to degrade lookups. The
+/// faster hashers behind the `ahash` and `fxhash` features trade some or
+/// all of that resistance for speed — aHash is seeded but not
+/// cryptographically strong, and FxHash is unseeded and trivially
+/// collidable. Only pick them when keys come from trusted sources.
+///
+/// Keys given a TTL expire lazily: once past their deadline they read as
+/// missing, and are removed by the next read that finds them or by replay.
+/// Deadlines are wall-clock times, so they hold across restarts.
+pub struct MemoryStore<S = RandomState> {
+    data: Arc<RwLock<HashMap<String, Entry, S>>>,
     wal: Option<Arc<WriteAheadLog>>,
+    /// Entries in maps handed off to be freed in the background
+    pending_free: Arc<AtomicUsize>,
+    /// Held shared by each write from logging it until it is applied, so
+    /// [`Store::compact_wal`] can find a moment when none is halfway
+    in_flight: Arc<RwLock<()>>,
+    /// Usage and eviction order, shared by every shard, when the store has
+    /// a memory limit
+    memory: Option<Arc<Memory<S>>>,
+    /// Shared by every shard, when large values are compressed
+    compressor: Option<Arc<Compressor>>,
+}
+
+/// A stored value, when it expires, and its key's metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    value: StoredValue,
+    /// Milliseconds since the Unix epoch, if the key has a TTL
+    expires_at: Option<u64>,
+    /// Times the key has been set since it was created
+    version: u64,
+    /// Milliseconds since the Unix epoch
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl Entry {
+    /// `value` set at `at` over `previous`, the entry the key held before
+    ///
+    /// Over an entry still live at `at` this is its next version, keeping
+    /// when the key was created; otherwise the key starts over at 1.
+    fn written(previous: Option<&Entry>, value: StoredValue, expires_at: Option<u64>, at: u64) -> Self {
+        let (version, created_at) = match previous.filter(|entry| !entry.is_expired(at)) {
+            Some(entry) => (entry.version + 1, entry.created_at),
+            None => (1, at),
+        };
+        Self { value, expires_at, version, created_at, updated_at: at }
+    }
+    
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+    
+    fn stat(&self) -> KeyStat {
+        KeyStat {
+            version: self.version,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+/// Wall-clock deadline `ttl` from now, in milliseconds since the Unix epoch
+fn deadline(ttl: Duration) -> u64 {
+    now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Append `value` to the value `key` holds in `data` at `at`, and return
+/// the key's entry
+///
+/// A key with no live value is created with `value` and no TTL; one that
+/// has a value keeps its TTL. An uncompressed value is extended in place
+/// while it stays too short for `compressor`; otherwise the whole value is
+/// stored over again.
+fn append_to<'a, S: BuildHasher>(
+    data: &'a mut HashMap<String, Entry, S>,
+    key: &str,
+    value: &[u8],
+    at: u64,
+    compressor: Option<&Compressor>,
+) -> &'a Entry {
+    match data.get_mut(key).filter(|entry| !entry.is_expired(at)) {
+        Some(entry) => {
+            let len = entry.value.len() + value.len();
+            match &mut entry.value {
+                StoredValue::Raw(bytes) if compressor.is_none_or(|compressor| len < compressor.min_size()) => {
+                    bytes.extend_from_slice(value);
+                }
+                stored => {
+                    let mut appended = mem::replace(stored, StoredValue::Raw(Vec::new())).into_raw();
+                    appended.extend_from_slice(value);
+                    *stored = compression::store(compressor, appended);
+                }
+            }
+            (entry.version, entry.updated_at) = (entry.version + 1, at);
+        }
+        None => {
+            let value = compression::store(compressor, value.to_vec());
+            data.insert(key.to_string(), Entry::written(None, value, None, at));
+        }
+    }
+    &data[key]
+}
+
+/// Remove the keys matching `pattern` from `maps`, logging one
+/// `DeletePattern` to `wal` first if any match, and return the live ones
+async fn remove_matching<S, M>(maps: &mut [M], pattern: &str, wal: Option<&WriteAheadLog>) -> Result<Vec<String>>
+where
+    M: DerefMut<Target = HashMap<String, Entry, S>>,
+{
+    if !maps.iter().any(|data| data.keys().any(|key| namespace::matches(pattern, key))) {
+        return Ok(Vec::new());
+    }
+    if let Some(wal) = wal {
+        wal.log_command(Command::DeletePattern { pattern: pattern.to_string() }).await?;
+    }
+    let now = now_millis();
+    let mut removed = Vec::new();
+    for data in maps.iter_mut() {
+        data.retain(|key, entry| {
+            if !namespace::matches(pattern, key) {
+                return true;
+            }
+            if !entry.is_expired(now) {
+                removed.push(key.clone());
+            }
+            false
+        });
+    }
+    Ok(removed)
+}
+
+/// Maps with fewer entries than this are freed inline; below it the hand-off
+/// to a blocking thread costs more than the drop
+const LAZY_FREE_THRESHOLD: usize = 1024;
+
+/// Keys copied into a compacted log each time writes are quieted
+const COMPACTION_PAGE_KEYS: usize = 1024;
+
+/// Estimated allocation of the store before and after [`MemoryStore::shrink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkReport {
+    /// Estimated bytes held before shrinking
+    pub before: usize,
+    /// Estimated bytes held after shrinking
+    pub after: usize,
+}
+
+impl ShrinkReport {
+    /// Estimated bytes given back to the allocator
+    pub fn reclaimed(&self) -> usize {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+/// Estimated heap use of the store, from [`Store::memory_usage`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    /// Estimated bytes held, counting capacity left behind by deletes
+    pub allocated: usize,
+    /// Estimated bytes the live keys and values need, sized to fit
+    pub live: usize,
+}
+
+impl MemoryUsage {
+    /// Live bytes per byte allocated; 1 when nothing is allocated
+    pub fn live_ratio(&self) -> f64 {
+        if self.allocated == 0 {
+            1.0
+        } else {
+            self.live as f64 / self.allocated as f64
+        }
+    }
+}
+
+/// WAL size before and after [`Store::compact_wal`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Bytes in the log before compacting
+    pub before: u64,
+    /// Bytes in the log after compacting, including writes carried over
+    pub after: u64,
+    /// Live keys written to the compacted log, leaving out writes carried
+    /// over
+    pub keys: u64,
+}
+
+/// A key's metadata, from [`Store::stat`]
+///
+/// Every write that gives the key a value counts as a set: SET, SETEX,
+/// MSET, a successful CAS, INCR and DECR. Changing a TTL doesn't. A key
+/// that is deleted or expires starts over at version 1 when it is next set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStat {
+    /// Times the key has been set since it was created, from 1
+    pub version: u64,
+    /// When it was first set, in milliseconds since the Unix epoch
+    pub created_at: u64,
+    /// When it was last set, in milliseconds since the Unix epoch
+    pub updated_at: u64,
+}
+
+/// One page of key-value pairs from [`Store::scan_page`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryPage {
+    pub entries: Vec<(String, Vec<u8>)>,
+    /// Cursor to pass for the next page; `None` once every key has been
+    /// returned
+    pub next: Option<String>,
+}
+
+impl EntryPage {
+    /// The first `limit` of `found` in key order
+    fn first(found: Vec<(String, Vec<u8>)>, limit: usize) -> Self {
+        let (entries, more) = first_by_key(found, limit);
+        let next = if more { entries.last().map(|(key, _)| key.clone()) } else { None };
+        Self { entries, next }
+    }
+}
+
+/// The first `limit` (at least one) of `found` in key order, and whether
+/// any were left out
+fn first_by_key<K: Ord, V>(mut found: Vec<(K, V)>, limit: usize) -> (Vec<(K, V)>, bool) {
+    let limit = limit.max(1);
+    let more = found.len() > limit;
+    if more {
+        found.select_nth_unstable_by(limit - 1, |a, b| a.0.cmp(&b.0));
+        found.truncate(limit);
+    }
+    found.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    (found, more)
+}
+
+/// One page of keys from [`Store::scan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanPage {
+    pub keys: Vec<String>,
+    /// Cursor to pass for the next page; 0 once the scan is complete
+    pub cursor: u64,
+}
+
+/// One step of a transaction, for [`Store::apply_batch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Set `key`, expiring after `ttl` if there is one
+    Set { key: String, value: Vec<u8>, ttl: Option<Duration> },
+    Delete { key: String },
+    Get { key: String },
+}
+
+impl BatchOp {
+    pub fn key(&self) -> &str {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Delete { key } | BatchOp::Get { key } => key,
+        }
+    }
+}
+
+/// What a [`BatchOp`] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOutcome {
+    Set,
+    /// Whether there was a live key to delete
+    Deleted(bool),
+    /// The value read, `None` for a missing key
+    Value(Option<Vec<u8>>),
 }
 
+/// Log a SET of `value` to `wal`, with its PEXPIREAT in the same batch if
+/// it has a deadline, and hand the value back
+///
+/// The value is moved into the entry and out again rather than cloned, so
+/// logging a large value doesn't cost a copy of it.
+async fn log_set(wal: &WriteAheadLog, key: &str, value: StoredValue, expires_at: Option<u64>) -> Result<StoredValue> {
+    let mut entries = vec![set_entry(key, value)];
+    match expires_at {
+        Some(unix_millis) => {
+            entries.push(WalEntry::new(Command::ExpireAt { key: key.to_string(), unix_millis }));
+            wal.write_entries(&entries).await?;
+        }
+        None => wal.write_entry(&entries[0]).await?,
+    }
+    let entry = entries.swap_remove(0);
+    match entry.command {
+        Command::Set { value, .. } => Ok(StoredValue::logged(entry.compression, value)),
+        _ => unreachable!("the first entry is the SET"),
+    }
+}
+
+/// The entry logging a SET of `value`, compressed as it is stored
+fn set_entry(key: &str, value: StoredValue) -> WalEntry {
+    let (compression, value) = value.into_logged();
+    WalEntry {
+        compression,
+        ..WalEntry::new(Command::Set { key: key.to_string(), value, condition: None })
+    }
+}
+
+/// A [`BatchOp`] ready to apply, a SET's value stored and its TTL turned
+/// into a deadline
+enum Step {
+    Set { key: String, value: StoredValue, expires_at: Option<u64> },
+    Delete { key: String },
+    Get { key: String },
+}
+
+impl Step {
+    fn key(&self) -> &str {
+        match self {
+            Step::Set { key, .. } | Step::Delete { key } | Step::Get { key } => key,
+        }
+    }
+}
+
+/// The steps of `ops`, and the entries that log their writes
+///
+/// A SET with a TTL is logged as a SET followed by its PEXPIREAT, as
+/// [`Store::set_with_ttl`] logs it.
+fn plan_batch(ops: Vec<BatchOp>, compressor: Option<&Compressor>) -> (Vec<Step>, Vec<WalEntry>) {
+    let mut entries = Vec::new();
+    let steps = ops
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value, ttl } => {
+                let value = compression::store(compressor, value);
+                entries.push(set_entry(&key, value.clone()));
+                let expires_at = ttl.map(deadline);
+                if let Some(unix_millis) = expires_at {
+                    entries.push(WalEntry::new(Command::ExpireAt { key: key.clone(), unix_millis }));
+                }
+                Step::Set { key, value, expires_at }
+            }
+            BatchOp::Delete { key } => {
+                entries.push(WalEntry::new(Command::Delete { key: key.clone() }));
+                Step::Delete { key }
+            }
+            BatchOp::Get { key } => Step::Get { key },
+        })
+        .collect();
+    (steps, entries)
+}
+
+/// The key and new value length of every SET in `ops`, for
+/// [`Memory::admit`]
+fn batch_writes(ops: &[BatchOp]) -> impl Iterator<Item = (&str, usize)> {
+    ops.iter().filter_map(|op| match op {
+        BatchOp::Set { key, value, .. } => Some((key.as_str(), value.len())),
+        _ => None,
+    })
+}
+
+/// Keys `ops` write or delete
+fn batch_keys(ops: &[BatchOp]) -> Vec<String> {
+    ops.iter()
+        .filter(|op| !matches!(op, BatchOp::Get { .. }))
+        .map(|op| op.key().to_string())
+        .collect()
+}
+
+/// `MemoryStore` using aHash (seeded, not HashDoS-proof)
+#[cfg(feature = "ahash")]
+pub type AHashMemoryStore = MemoryStore<ahash::RandomState>;
+
+/// `MemoryStore` using FxHash (unseeded, no HashDoS resistance)
+#[cfg(feature = "fxhash")]
+pub type FxMemoryStore = MemoryStore<rustc_hash::FxBuildHasher>;
+
 impl MemoryStore {
     /// Create a new memory store without WAL
     pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+    
+    /// Create a new memory store with WAL for persistence
+    pub fn with_wal(wal: Arc<WriteAheadLog>) -> Self {
+        Self::with_wal_and_hasher(wal, RandomState::new())
+    }
+}
+
+impl<S: BuildHasher + Send + Sync + 'static> MemoryStore<S> {
+    /// Create a new memory store without WAL using the given hasher
+    pub fn with_hasher(hasher: S) -> Self {
         Self {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            data: Arc::new(RwLock::new(HashMap::with_hasher(hasher))),
             wal: None,
+            pending_free: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(RwLock::new(())),
+            memory: None,
+            compressor: None,
         }
     }
     
-    /// Create a new memory store with WAL for persistence
-    pub fn with_wal(wal: Arc<WriteAheadLog>) -> Self {
+    /// Create a new memory store with WAL using the given hasher
+    pub fn with_wal_and_hasher(wal: Arc<WriteAheadLog>, hasher: S) -> Self {
         Self {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            data: Arc::new(RwLock::new(HashMap::with_hasher(hasher))),
             wal: Some(wal),
+            pending_free: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(RwLock::new(())),
+            memory: None,
+            compressor: None,
         }
     }
     
+    /// Keep the keys and values within `max_bytes`, as `policy` says
+    ///
+    /// Set it up before the store is restored or written to; a restore
+    /// recounts whatever it loads.
+    pub fn with_memory_limit(mut self, max_bytes: usize, policy: EvictionPolicy) -> Self {
+        let maps = Box::new([Arc::clone(&self.data)]);
+        self.memory = Some(Arc::new(Memory::new(max_bytes, policy, maps, self.wal.clone())));
+        self
+    }
+    
+    /// Keep values of at least `config.min_size_bytes` compressed, in
+    /// memory and in the WAL
+    ///
+    /// Set it up before the store is restored or written to. Values already
+    /// stored or logged are left as they are, and a log compressed
+    /// differently or not at all still replays.
+    pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+        self.compressor = Some(Arc::new(Compressor::new(config)));
+        self
+    }
+    
+    /// `value` as the store keeps it
+    fn store_value(&self, value: Vec<u8>) -> StoredValue {
+        compression::store(self.compressor.as_deref(), value)
+    }
+    
     /// Restore state from WAL
     pub async fn restore_from_wal(&self) -> Result<()> {
         if let Some(wal) = &self.wal {
-            let store_clone = self.clone();
-            wal.replay(move |command| {
-                // Use blocking operations for WAL replay since it's synchronous
-                let rt = tokio::runtime::Handle::current();
-                rt.block_on(async {
-                    match command {
-                        Command::Set { key, value } => {
-                            // Direct insertion without WAL logging during replay
-                            let mut data = store_clone.data.write().await;
-                            data.insert(key, value);
-                            Ok(())
-                        }
-                        Command::Delete { key } => {
-                            // Direct deletion without WAL logging during replay
-                            let mut data = store_clone.data.write().await;
-                            data.remove(&key);
-                            Ok(())
-                        }
-                        Command::Get { .. } => {
-                            // GET commands don't modify state, skip during replay
-                            Ok(())
-                        }
-                    }
-                })
+            // Apply entries straight to the map under one write lock, without WAL logging
+            let mut data = self.data.write().await;
+            wal.replay(|entry| {
+                Self::apply_replayed(entry, slice::from_mut(&mut data), |_| 0, self.compressor.as_deref());
+                Ok(())
             })?;
+            purge_expired(slice::from_mut(&mut data));
+            self.recount(slice::from_ref(&data));
+        }
+        Ok(())
+    }
+    
+    /// Restore state from WAL on the calling thread, reporting progress
+    ///
+    /// `progress` is called as the WAL is read. The whole replay runs
+    /// synchronously, so call this from a blocking thread (e.g.
+    /// `tokio::task::spawn_blocking`), never from a runtime worker.
+    pub fn restore_from_wal_blocking<P>(&self, progress: P) -> Result<()>
+    where
+        P: FnMut(ReplayProgress),
+    {
+        if let Some(wal) = &self.wal {
+            let mut data = self.data.blocking_write();
+            wal.replay_with_progress(
+                |entry| {
+                    Self::apply_replayed(entry, slice::from_mut(&mut data), |_| 0, self.compressor.as_deref());
+                    Ok(())
+                },
+                progress,
+            )?;
+            purge_expired(slice::from_mut(&mut data));
+            self.recount(slice::from_ref(&data));
         }
         Ok(())
     }
     
-    /// Apply a command without WAL logging (used during replay)
-    async fn apply_command_direct(&self, command: Command) -> Result<()> {
+    /// Restore state from the WAL file at `path` without modifying the file
+    ///
+    /// Applies the same entries as `restore_from_wal`, for read-only tooling.
+    pub async fn restore_from_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut data = self.data.write().await;
+        wal::read_committed(path, |_, entry| {
+            Self::apply_replayed(entry, slice::from_mut(&mut data), |_| 0, self.compressor.as_deref());
+            Ok(())
+        })?;
+        purge_expired(slice::from_mut(&mut data));
+        self.recount(slice::from_ref(&data));
+        Ok(())
+    }
+    
+    /// Apply a replayed entry without WAL logging, to the map of `maps` at
+    /// `index` of the key it changes, storing values as `compressor` says
+    ///
+    /// Each entry is applied as of its timestamp, so a key's version counts
+    /// the sets it had while it was live. Keys that have expired since are
+    /// left in place for the sets after them to count, and should be
+    /// purged once the replay is done.
+    fn apply_replayed<M>(entry: WalEntry, maps: &mut [M], index: impl Fn(&str) -> usize, compressor: Option<&Compressor>)
+    where
+        M: DerefMut<Target = HashMap<String, Entry, S>>,
+    {
+        // Replayed entries come decompressed
+        let WalEntry { timestamp, command, history, .. } = entry;
         match command {
-            Command::Set { key, value } => {
-                let mut data = self.data.write().await;
-                data.insert(key, value);
-                Ok(())
+            // The store logs a SET with a TTL as a SET followed by its
+            // PEXPIREAT, so a logged SetEx carries no expiry of its own
+            Command::Set { key, value, .. } | Command::SetEx { key, value, .. } => {
+                let data = &mut maps[index(&key)];
+                let value = compression::store(compressor, value);
+                let mut entry = Entry::written(data.get(&key), value, None, timestamp);
+                if let Some(history) = history {
+                    entry.version = history.version;
+                    entry.created_at = history.created_at;
+                }
+                data.insert(key, entry);
+            }
+            // Checked for expiry at the time it was logged, as it was when
+            // it ran
+            Command::Append { key, value } => {
+                append_to(&mut maps[index(&key)], &key, &value, timestamp, compressor);
             }
             Command::Delete { key } => {
-                let mut data = self.data.write().await;
-                data.remove(&key);
-                Ok(())
+                maps[index(&key)].remove(&key);
             }
-            Command::Get { .. } => {
-                // GET commands don't modify state
-                Ok(())
+            Command::ExpireAt { key, unix_millis } => {
+                let data = &mut maps[index(&key)];
+                if unix_millis <= timestamp {
+                    data.remove(&key);
+                } else if let Some(entry) = data.get_mut(&key) {
+                    entry.expires_at = Some(unix_millis);
+                }
+            }
+            Command::FlushAll => {
+                for data in maps.iter_mut() {
+                    data.clear();
+                }
+            }
+            Command::FlushDb { namespace: Some(namespace) } => {
+                for data in maps.iter_mut() {
+                    data.retain(|key, _| !namespace::contains(&namespace, key));
+                }
+            }
+            Command::DeletePattern { pattern } => {
+                for data in maps.iter_mut() {
+                    data.retain(|key, _| !namespace::matches(&pattern, key));
+                }
+            }
+            // Relative expiries are logged as PEXPIREAT, never as themselves
+            Command::Expire { .. }
+            | Command::Get { .. }
+            | Command::Exists { .. }
+            | Command::Strlen { .. }
+            | Command::Stat { .. }
+            | Command::Subscribe { .. }
+            | Command::Replicate
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Select { .. }
+            | Command::FlushDb { namespace: None }
+            | Command::DbSize { .. }
+            | Command::Shrink
+            | Command::Compact
+            | Command::WalStats
+            | Command::CommandInfo { .. }
+            | Command::MaintenanceStatus
+            | Command::Config { .. }
+            | Command::Backup { .. }
+            // A restore is logged as the SETs it performs
+            | Command::Restore { .. }
+            | Command::Info
+            | Command::StatsReset
+            | Command::SlowLogGet { .. }
+            | Command::SlowLogReset
+            | Command::Ping
+            | Command::Ready
+            | Command::Checksum { .. }
+            | Command::ChecksumRanges { .. }
+            | Command::Scan { .. }
+            | Command::MGet { .. }
+            // Multi-sets, counters and successful swaps are logged as the
+            // SETs they perform, and GETSET, GETDEL, LOCK and UNLOCK as
+            // their SET or DELETE
+            | Command::MSet { .. }
+            | Command::Incr { .. }
+            | Command::Decr { .. }
+            | Command::Cas { .. }
+            | Command::GetSet { .. }
+            | Command::GetDel { .. }
+            | Command::Lock { .. }
+            | Command::Unlock { .. }
+            | Command::Auth { .. }
+            | Command::Hello { .. } => {
+                // Reads and maintenance commands don't modify state
             }
         }
     }
-}
-
-impl Clone for MemoryStore {
-    fn clone(&self) -> Self {
-        Self {
-            data: Arc::clone(&self.data),
-            wal: self.wal.clone(),
+    
+    /// Whether every key in `watched` still has the stat given for it, in
+    /// the map of `maps` at `index` of the key
+    ///
+    /// Stats rather than values are compared, so a key set to another
+    /// value and back counts as changed.
+    fn watched_unchanged<M>(watched: &[(String, Option<KeyStat>)], maps: &[M], index: impl Fn(&str) -> usize) -> bool
+    where
+        M: DerefMut<Target = HashMap<String, Entry, S>>,
+    {
+        let now = now_millis();
+        watched.iter().all(|(key, expected)| {
+            let live = maps[index(key)].get(key).filter(|entry| !entry.is_expired(now));
+            live.map(Entry::stat) == *expected
+        })
+    }
+    
+    /// Apply the steps of a planned batch, each to the map of `maps` at
+    /// `index` of its key
+    fn apply_steps<M>(steps: Vec<Step>, maps: &mut [M], index: impl Fn(&str) -> usize) -> Vec<BatchOutcome>
+    where
+        M: DerefMut<Target = HashMap<String, Entry, S>>,
+    {
+        let now = now_millis();
+        steps
+            .into_iter()
+            .map(|step| {
+                let data = &mut maps[index(step.key())];
+                match step {
+                    Step::Set { key, value, expires_at } => {
+                        let entry = Entry::written(data.get(&key), value, expires_at, now);
+                        data.insert(key, entry);
+                        BatchOutcome::Set
+                    }
+                    Step::Delete { key } => {
+                        BatchOutcome::Deleted(data.remove(&key).is_some_and(|entry| !entry.is_expired(now)))
+                    }
+                    Step::Get { key } => BatchOutcome::Value(
+                        data.get(&key)
+                            .filter(|entry| !entry.is_expired(now))
+                            .map(|entry| entry.value.to_raw()),
+                    ),
+                }
+            })
+            .collect()
+    }
+    
+    /// Add log entries that rebuild the live keys to the compaction running
+    /// on `wal`, a page of keys at a time in key order: a `Set` per key,
+    /// stamped with when it was last set and carrying its history, plus an
+    /// `ExpireAt` for keys with a TTL
+    ///
+    /// Writes are quieted while each page is copied, so it lands in the
+    /// compacted log after every write it reflects and before the rest.
+    /// Returns the number of keys written.
+    async fn add_compaction_pages(&self, wal: &WriteAheadLog) -> Result<u64> {
+        // Once writes have been quiet, everything logged before the
+        // compaction began has been applied, so a key missing from this
+        // list was created since and is carried over
+        drop(self.in_flight.write().await);
+        let mut keys: Vec<String> = self.data.read().await.keys().cloned().collect();
+        keys.sort_unstable();
+        
+        let mut written = 0;
+        for page in keys.chunks(COMPACTION_PAGE_KEYS) {
+            {
+                let _quiet = self.in_flight.write().await;
+                let data = self.data.read().await;
+                let now = now_millis();
+                let mut entries = Vec::with_capacity(page.len());
+                for (key, entry) in page.iter().filter_map(|key| data.get_key_value(key)) {
+                    if entry.is_expired(now) {
+                        continue;
+                    }
+                    // Carried over as it is held, without compressing it again
+                    entries.push(WalEntry {
+                        timestamp: entry.updated_at,
+                        history: Some(KeyHistory { version: entry.version, created_at: entry.created_at }),
+                        ..set_entry(key, entry.value.clone())
+                    });
+                    written += 1;
+                    if let Some(unix_millis) = entry.expires_at {
+                        entries.push(WalEntry::new(Command::ExpireAt { key: key.clone(), unix_millis }));
+                    }
+                }
+                wal.add_compaction_page(&entries)?;
+            }
+            wal.flush_compaction().await?;
         }
+        Ok(written)
     }
-}
-
-impl Store for MemoryStore {
-    async fn set(&self, key: String, value: String) -> Result<()> {
-        // Log to WAL first for durability
-        if let Some(wal) = &self.wal {
-            let command = Command::Set {
+    
+    /// Copies of the live entries, for a snapshot
+    async fn snapshot_entries(&self) -> Vec<SnapshotEntry> {
+        let data = self.data.read().await;
+        let now = now_millis();
+        data.iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| SnapshotEntry {
                 key: key.clone(),
-                value: value.clone(),
-            };
-            wal.log_command(command).await?;
+                value: entry.value.to_raw(),
+                expires_at: entry.expires_at,
+                stat: Some(entry.stat()),
+            })
+            .collect()
+    }
+    
+    /// Size of the WAL in bytes; 0 without one
+    pub fn wal_size(&self) -> u64 {
+        self.wal.as_ref().map_or(0, |wal| wal.size())
+    }
+    
+    /// Bytes of keys and values held, counted only with a memory limit
+    ///
+    /// An estimate: the map's own overhead isn't included. Shards of a
+    /// [`ShardedMemoryStore`] share one count.
+    pub fn memory_used(&self) -> Option<usize> {
+        self.memory.as_ref().map(|memory| memory.used())
+    }
+    
+    /// Keys evicted to stay within the memory limit
+    pub fn evicted_keys(&self) -> u64 {
+        self.memory.as_ref().map_or(0, |memory| memory.evicted())
+    }
+    
+    /// Account for `key` now holding `entry`, or for its removal, when the
+    /// store has a memory limit
+    fn track(&self, key: &str, entry: Option<&Entry>) {
+        if let Some(memory) = &self.memory {
+            memory.track(key, entry);
         }
-        
-        // Then update in-memory store
-        let mut data = self.data.write().await;
-        data.insert(key, value);
-        Ok(())
     }
     
-    async fn get(&self, key: &str) -> Result<Option<String>> {
-        let data = self.data.read().await;
-        Ok(data.get(key).cloned())
+    /// Note a read of `key`, for the eviction order
+    fn touch(&self, key: &str) {
+        if let Some(memory) = &self.memory {
+            memory.touch(key);
+        }
     }
     
-    async fn delete(&self, key: &str) -> Result<bool> {
-        // Log to WAL first for durability
-        if let Some(wal) = &self.wal {
-            let command = Command::Delete {
-                key: key.to_string(),
-            };
-            wal.log_command(command).await?;
+    /// Refuse `writes`, pairs of a key and its new value's length, if the
+    /// memory limit has no room for them
+    fn admit<'a>(&self, writes: impl IntoIterator<Item = (&'a str, usize)>) -> Result<()> {
+        match &self.memory {
+            Some(memory) => memory.admit(writes),
+            None => Ok(()),
         }
-        
-        // Then update in-memory store
-        let mut data = self.data.write().await;
-        Ok(data.remove(key).is_some())
     }
     
-    async fn exists(&self, key: &str) -> Result<bool> {
-        let data = self.data.read().await;
-        Ok(data.contains_key(key))
+    /// Evict keys until the store is back within its memory limit; call
+    /// with no shard locked
+    async fn evict(&self) -> Result<()> {
+        match &self.memory {
+            Some(memory) => memory.evict().await,
+            None => Ok(()),
+        }
     }
     
-    async fn get_all(&self) -> Result<Vec<(String, String)>> {
-        let data = self.data.read().await;
-        Ok(data.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    /// Recount memory use after `maps` were changed wholesale
+    fn recount<M>(&self, maps: &[M])
+    where
+        M: DerefMut<Target = HashMap<String, Entry, S>>,
+    {
+        if let Some(memory) = &self.memory {
+            memory.reset(maps);
+        }
     }
     
-    async fn clear(&self) -> Result<()> {
-        let mut data = self.data.write().await;
-        data.clear();
-        Ok(())
+    /// Drop `removed`, which holds `entries` entries, on a blocking thread,
+    /// unless it is small or there is no runtime to run it on
+    fn free_lazily<T: Send + 'static>(&self, entries: usize, removed: T) {
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) if entries >= LAZY_FREE_THRESHOLD => handle,
+            _ => return drop(removed),
+        };
+        
+        self.pending_free.fetch_add(entries, Ordering::Relaxed);
+        let pending_free = Arc::clone(&self.pending_free);
+        handle.spawn_blocking(move || {
+            drop(removed);
+            pending_free.fetch_sub(entries, Ordering::Relaxed);
+        });
     }
     
-    async fn len(&self) -> Result<usize> {
+    /// Time left before `key` expires, or `None` if it doesn't exist or has
+    /// no TTL
+    pub async fn ttl(&self, key: &str) -> Option<Duration> {
         let data = self.data.read().await;
-        Ok(data.len())
+        let now = now_millis();
+        let at = data.get(key)?.expires_at?;
+        (at > now).then(|| Duration::from_millis(at - now))
+    }
+    
+    /// Live value of `key`, removing the key if it has expired
+    async fn live_value(&self, key: &str) -> Option<Vec<u8>> {
+        {
+            let data = self.data.read().await;
+            match data.get(key) {
+                Some(entry) if !entry.is_expired(now_millis()) => {
+                    self.touch(key);
+                    return Some(entry.value.to_raw());
+                }
+                Some(_) => {}
+                None => return None,
+            }
+        }
+        
+        // Expired: take the write lock to remove it, unless it was
+        // rewritten in the meantime
+        let mut data = self.data.write().await;
+        match data.get(key) {
+            Some(entry) if entry.is_expired(now_millis()) => {
+                data.remove(key);
+                self.track(key, None);
+                None
+            }
+            entry => entry.map(|entry| entry.value.to_raw()),
+        }
     }
 }
 
-#[cfg(test)]
+/// Order-independent digest of the entries whose key starts with `prefix`
+fn prefix_digest<'a, V: AsRef<[u8]>>(prefix: &str, entries: impl Iterator<Item = (&'a str, V)>) -> u64 {
+    entries
+        .filter(|(key, _)| key.starts_with(prefix))
+        .fold(0, |sum, (key, value)| sum.wrapping_add(entry_digest(key, value.as_ref())))
+}
+
+/// Digests of the entries under `prefix`, bucketed as [`Store::checksum_ranges`]
+/// describes
+fn range_digests<'a, V: AsRef<[u8]>>(
+    buckets: usize,
+    prefix: &str,
+    entries: impl Iterator<Item = (&'a str, V)>,
+) -> Vec<u64> {
+    let buckets = buckets.clamp(1, 256);
+    let mut digests = vec![0u64; buckets];
+    for (key, value) in entries {
+        let Some(rest) = key.strip_prefix(prefix) else { continue };
+        let Some(&next) = rest.as_bytes().first() else { continue };
+        let bucket = next as usize * buckets / 256;
+        digests[bucket] = digests[bucket].wrapping_add(entry_digest(key, value.as_ref()));
+    }
+    digests
+}
+
+/// Stable 64-bit digest of one entry: FNV-1a over the length-prefixed key
+/// and value, then a final mix so summed digests don't cancel out
+///
+/// Must not change between releases, since digests from different servers
+/// are compared.
+fn entry_digest(key: &str, value: &[u8]) -> u64 {
+    let mut hash = FNV_OFFSET;
+    for part in [key.as_bytes(), value] {
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(part) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    finalize(hash)
+}
+
+/// Where `key` falls in the order [`Store::scan`] visits keys: FNV-1a over
+/// the key, mixed like [`entry_digest`]
+///
+/// Independent of the map's hasher, so a cursor stays meaningful while the
+/// map grows and across restarts.
+fn scan_position(key: &str) -> u64 {
+    let hash = key.bytes().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    finalize(hash)
+}
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Fold `bytes` into the FNV-1a `hash`
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// splitmix64 finalizer
+fn finalize(mut hash: u64) -> u64 {
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Estimate the heap bytes held by `map`: its table plus every key and
+/// value buffer
+fn allocated_bytes<S>(map: &HashMap<String, Entry, S>) -> usize {
+    // The table stores each slot inline plus one control byte
+    let table = map.capacity() * (mem::size_of::<(String, Entry)>() + 1);
+    let strings: usize = map.iter().map(|(k, e)| k.capacity() + e.value.capacity()).sum();
+    table + strings
+}
+
+/// Estimate the heap bytes `map` would hold with its table and every key
+/// and value sized to fit, as [`allocated_bytes`] counts them
+fn live_bytes<S>(map: &HashMap<String, Entry, S>) -> usize {
+    let table = map.len() * (mem::size_of::<(String, Entry)>() + 1);
+    let strings: usize = map.iter().map(|(k, e)| k.len() + e.value.held().len()).sum();
+    table + strings
+}
+
+impl<S: BuildHasher + Default + Send + Sync + 'static> Default for MemoryStore<S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<S> Clone for MemoryStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            data: Arc::clone(&self.data),
+            wal: self.wal.clone(),
+            pending_free: Arc::clone(&self.pending_free),
+            in_flight: Arc::clone(&self.in_flight),
+            memory: self.memory.clone(),
+            compressor: self.compressor.clone(),
+        }
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync + 'static> Store for MemoryStore<S> {
+    async fn set(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let value = self.store_value(value);
+        self.admit([(key.as_str(), value.held().len())])?;
+        let _in_flight = self.in_flight.read().await;
+        // Locked before logging, as every write is, so writes to one key
+        // reach the WAL in the order they reach the map
+        let mut data = self.data.write().await;
+        let value = match &self.wal {
+            Some(wal) => log_set(wal, &key, value, None).await?,
+            None => value,
+        };
+        
+        // A plain SET drops any TTL
+        let entry = Entry::written(data.get(&key), value, None, now_millis());
+        self.track(&key, Some(&entry));
+        data.insert(key, entry);
+        drop(data);
+        self.evict().await
+    }
+    
+    async fn set_with_ttl(&self, key: String, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let value = self.store_value(value);
+        self.admit([(key.as_str(), value.held().len())])?;
+        let _in_flight = self.in_flight.read().await;
+        let expires_at = deadline(ttl);
+        let mut data = self.data.write().await;
+        
+        // Log the value and its deadline as one batch, so a crash can't keep
+        // the value without its expiry
+        let value = match &self.wal {
+            Some(wal) => log_set(wal, &key, value, Some(expires_at)).await?,
+            None => value,
+        };
+        
+        let entry = Entry::written(data.get(&key), value, Some(expires_at), now_millis());
+        self.track(&key, Some(&entry));
+        data.insert(key, entry);
+        drop(data);
+        self.evict().await
+    }
+    
+    /// The write lock is held from looking for the key until the value is
+    /// in place, so of several clients setting an absent key `IfAbsent`
+    /// exactly one does. Only a write that happens is logged, as a plain
+    /// `Set`.
+    async fn set_if(&self, key: String, value: Vec<u8>, ttl: Option<Duration>, condition: SetCondition) -> Result<bool> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        let exists = data.get(&key).is_some_and(|entry| !entry.is_expired(now));
+        if exists != (condition == SetCondition::IfPresent) {
+            return Ok(false);
+        }
+        let value = self.store_value(value);
+        self.admit([(key.as_str(), value.held().len())])?;
+        let expires_at = ttl.map(deadline);
+        
+        let value = match &self.wal {
+            Some(wal) => log_set(wal, &key, value, expires_at).await?,
+            None => value,
+        };
+        let entry = Entry::written(data.get(&key), value, expires_at, now);
+        self.track(&key, Some(&entry));
+        data.insert(key, entry);
+        drop(data);
+        self.evict().await?;
+        Ok(true)
+    }
+    
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.live_value(key).await)
+    }
+    
+    async fn get_with_deadline(&self, key: &str) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let data = self.data.read().await;
+        Ok(data
+            .get(key)
+            .filter(|entry| !entry.is_expired(now_millis()))
+            .map(|entry| (entry.value.to_raw(), entry.expires_at)))
+    }
+    
+    async fn stat(&self, key: &str) -> Result<Option<KeyStat>> {
+        let data = self.data.read().await;
+        Ok(data.get(key).filter(|entry| !entry.is_expired(now_millis())).map(Entry::stat))
+    }
+    
+    /// The pairs are logged as one batch of `Set`s and applied under one
+    /// write-lock acquisition, which is held while they are logged so they
+    /// land in the log in the same order as in the map.
+    async fn mset(&self, pairs: Vec<(String, Vec<u8>)>) -> Result<()> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let pairs: Vec<_> = pairs.into_iter().map(|(key, value)| (key, self.store_value(value))).collect();
+        self.admit(pairs.iter().map(|(key, value)| (key.as_str(), value.held().len())))?;
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        if let Some(wal) = &self.wal {
+            let entries: Vec<_> = pairs.iter().map(|(key, value)| set_entry(key, value.clone())).collect();
+            wal.write_entries(&entries).await?;
+        }
+        let now = now_millis();
+        for (key, value) in pairs {
+            let entry = Entry::written(data.get(&key), value, None, now);
+            self.track(&key, Some(&entry));
+            data.insert(key, entry);
+        }
+        drop(data);
+        self.evict().await
+    }
+    
+    /// All keys are read under one read lock, so the values are a
+    /// consistent snapshot. Expired keys read as missing but, unlike with
+    /// `get`, are left for a later read or replay to remove.
+    async fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>> {
+        let data = self.data.read().await;
+        let now = now_millis();
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let value = data
+                    .get(key)
+                    .filter(|entry| !entry.is_expired(now))
+                    .map(|entry| entry.value.to_raw());
+                if value.is_some() {
+                    self.touch(key);
+                }
+                value
+            })
+            .collect())
+    }
+    
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        if let Some(wal) = &self.wal {
+            let command = Command::Delete {
+                key: key.to_string(),
+            };
+            wal.log_command(command).await?;
+        }
+        
+        self.track(key, None);
+        Ok(data.remove(key).is_some_and(|entry| !entry.is_expired(now_millis())))
+    }
+    
+    /// The write lock is held from the comparison until the new value is
+    /// in place, so no other write can land in between. Only a successful
+    /// swap is logged, as a plain `Set`; like one, it drops any TTL.
+    async fn cas(&self, key: String, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        let matches = data
+            .get(&key)
+            .is_some_and(|entry| !entry.is_expired(now) && entry.value.is(expected));
+        if !matches {
+            return Ok(false);
+        }
+        let new = self.store_value(new);
+        self.admit([(key.as_str(), new.held().len())])?;
+        
+        let new = match &self.wal {
+            Some(wal) => log_set(wal, &key, new, None).await?,
+            None => new,
+        };
+        let entry = Entry::written(data.get(&key), new, None, now);
+        self.track(&key, Some(&entry));
+        data.insert(key, entry);
+        drop(data);
+        self.evict().await?;
+        Ok(true)
+    }
+    
+    /// The write lock is held from checking the holder until the lease is
+    /// in place, so of several clients after a free lock exactly one gets
+    /// it. The lease is logged like a SET with a TTL, so it outlasts a
+    /// restart until it runs out.
+    async fn lock(&self, key: String, token: &str, ttl: Duration) -> Result<bool> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        let held_by_another = data
+            .get(&key)
+            .is_some_and(|entry| !entry.is_expired(now) && !entry.value.is(token.as_bytes()));
+        if held_by_another {
+            return Ok(false);
+        }
+        let value = self.store_value(token.as_bytes().to_vec());
+        self.admit([(key.as_str(), value.held().len())])?;
+        let expires_at = deadline(ttl);
+        
+        let value = match &self.wal {
+            Some(wal) => log_set(wal, &key, value, Some(expires_at)).await?,
+            None => value,
+        };
+        let entry = Entry::written(data.get(&key), value, Some(expires_at), now);
+        self.track(&key, Some(&entry));
+        data.insert(key, entry);
+        drop(data);
+        self.evict().await?;
+        Ok(true)
+    }
+    
+    /// Checked and removed under one write lock, and logged as a `Delete`.
+    /// A lease found run out is removed then and there.
+    async fn unlock(&self, key: &str, token: &str) -> Result<bool> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        let Some(entry) = data.get(key) else {
+            return Ok(false);
+        };
+        if entry.is_expired(now) {
+            data.remove(key);
+            self.track(key, None);
+            return Ok(false);
+        }
+        if !entry.value.is(token.as_bytes()) {
+            return Ok(false);
+        }
+        if let Some(wal) = &self.wal {
+            let command = Command::Delete {
+                key: key.to_string(),
+            };
+            wal.log_command(command).await?;
+        }
+        self.track(key, None);
+        data.remove(key);
+        Ok(true)
+    }
+    
+    /// The write lock is held from reading the old value until the new one
+    /// is in place, so concurrent increments can't lose an update. The
+    /// result is logged as a `Set`, followed by the key's `ExpireAt` if it
+    /// has a TTL; unlike SET, an increment keeps the TTL.
+    async fn incr(&self, key: &str, delta: i64) -> Result<i64> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        let live = data.get(key).filter(|entry| !entry.is_expired(now));
+        let current = match live {
+            Some(entry) => str::from_utf8(&entry.value.raw())
+                .ok()
+                .and_then(|text| text.parse::<i64>().ok())
+                .ok_or_else(|| RustVaultError::InvalidCommand("Value is not an integer".to_string()))?,
+            None => 0,
+        };
+        let expires_at = live.and_then(|entry| entry.expires_at);
+        let next = current
+            .checked_add(delta)
+            .ok_or_else(|| RustVaultError::InvalidCommand("Increment would overflow".to_string()))?;
+        let value = next.to_string().into_bytes();
+        self.admit([(key, value.len())])?;
+        
+        if let Some(wal) = &self.wal {
+            let mut commands = vec![Command::Set {
+                key: key.to_string(),
+                value: value.clone(),
+                condition: None,
+            }];
+            if let Some(unix_millis) = expires_at {
+                commands.push(Command::ExpireAt { key: key.to_string(), unix_millis });
+            }
+            wal.log_commands(commands).await?;
+        }
+        // Far too short to compress
+        let entry = Entry::written(data.get(key), StoredValue::Raw(value), expires_at, now);
+        self.track(key, Some(&entry));
+        data.insert(key.to_string(), entry);
+        drop(data);
+        self.evict().await?;
+        Ok(next)
+    }
+    
+    /// The value is extended in place under the write lock, so concurrent
+    /// appends can't lose a suffix. Unlike a counter, an append is logged as
+    /// itself rather than as the `Set` it performs, so a value built up a
+    /// piece at a time isn't logged over again with every piece; the entry
+    /// carries the time the key was checked for expiry at, so replaying it
+    /// gives the same value. Like INCR, it keeps the TTL.
+    async fn append(&self, key: &str, value: &[u8]) -> Result<usize> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        let current = data.get(key).filter(|entry| !entry.is_expired(now)).map_or(0, |entry| entry.value.len());
+        let len = current + value.len();
+        self.admit([(key, len)])?;
+        
+        if let Some(wal) = &self.wal {
+            let command = Command::Append {
+                key: key.to_string(),
+                value: value.to_vec(),
+            };
+            wal.write_entry(&WalEntry { timestamp: now, ..WalEntry::new(command) }).await?;
+        }
+        let entry = append_to(&mut data, key, value, now, self.compressor.as_deref());
+        self.track(key, Some(entry));
+        drop(data);
+        self.evict().await?;
+        Ok(len)
+    }
+    
+    /// The write lock is held from reading the old value until the new one
+    /// is in place, and while the `Set` is logged, so two swaps on one key
+    /// can't both see the same old value. Like SET, it drops any TTL.
+    async fn getset(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = self.store_value(value);
+        self.admit([(key.as_str(), value.held().len())])?;
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let value = match &self.wal {
+            Some(wal) => log_set(wal, &key, value, None).await?,
+            None => value,
+        };
+        let now = now_millis();
+        let entry = Entry::written(data.get(&key), value, None, now);
+        self.track(&key, Some(&entry));
+        let old = data.insert(key, entry).filter(|entry| !entry.is_expired(now));
+        drop(data);
+        self.evict().await?;
+        Ok(old.map(|entry| entry.value.into_raw()))
+    }
+    
+    /// Only a key that is there is logged, as a `Delete`, and removed under
+    /// the same write lock it was found under
+    async fn getdel(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        if data.get(key).is_none_or(|entry| entry.is_expired(now_millis())) {
+            return Ok(None);
+        }
+        if let Some(wal) = &self.wal {
+            let command = Command::Delete {
+                key: key.to_string(),
+            };
+            wal.log_command(command).await?;
+        }
+        self.track(key, None);
+        Ok(data.remove(key).map(|entry| entry.value.into_raw()))
+    }
+    
+    async fn strlen(&self, key: &str) -> Result<Option<usize>> {
+        let data = self.data.read().await;
+        Ok(data.get(key).filter(|entry| !entry.is_expired(now_millis())).map(|entry| entry.value.len()))
+    }
+    
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        self.expire_at(key, deadline(ttl)).await
+    }
+    
+    /// A deadline already in the past removes the key. The write lock is
+    /// held while the expiry is logged, so the key can't be deleted or
+    /// overwritten between the existence check and the update.
+    async fn expire_at(&self, key: &str, unix_millis: u64) -> Result<bool> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        if data.get(key).is_none_or(|entry| entry.is_expired(now)) {
+            return Ok(false);
+        }
+        
+        if let Some(wal) = &self.wal {
+            let command = Command::ExpireAt {
+                key: key.to_string(),
+                unix_millis,
+            };
+            wal.log_command(command).await?;
+        }
+        
+        if unix_millis <= now {
+            data.remove(key);
+            self.track(key, None);
+        } else if let Some(entry) = data.get_mut(key) {
+            entry.expires_at = Some(unix_millis);
+        }
+        Ok(true)
+    }
+    
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.live_value(key).await.is_some())
+    }
+    
+    async fn get_all(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let data = self.data.read().await;
+        let now = now_millis();
+        Ok(data
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(k, entry)| (k.clone(), entry.value.to_raw()))
+            .collect())
+    }
+    
+    /// Keys are visited in order of a stable hash and the cursor is the
+    /// hash to resume from, so a scan keeps no server-side state. A key
+    /// present for the whole scan is returned exactly once however the map
+    /// changes in between; keys written or deleted meanwhile may or may not
+    /// be. Keys that hash the same as the last one on a page join that page,
+    /// so a page can run over `count`. Each page walks the whole map.
+    async fn scan(&self, prefix: &str, cursor: u64, count: usize) -> Result<ScanPage> {
+        let count = count.max(1);
+        let data = self.data.read().await;
+        let now = now_millis();
+        let mut found: Vec<(u64, &String)> = data
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+            .map(|(key, _)| (scan_position(key), key))
+            .filter(|(position, _)| *position >= cursor)
+            .collect();
+        
+        // Keep the first `count` positions, and resume just past the last
+        let mut next = 0;
+        if found.len() > count {
+            let last = found.select_nth_unstable(count - 1).1 .0;
+            found.retain(|(position, _)| *position <= last);
+            next = last.checked_add(1).unwrap_or(0);
+        }
+        found.sort_unstable();
+        
+        Ok(ScanPage {
+            keys: found.into_iter().map(|(_, key)| key.clone()).collect(),
+            cursor: next,
+        })
+    }
+    
+    /// Each page walks the whole map under its read lock, as
+    /// [`Store::scan`] does, but only copies the values it returns.
+    async fn scan_page(&self, cursor: Option<&str>, limit: usize) -> Result<EntryPage> {
+        let data = self.data.read().await;
+        let now = now_millis();
+        let found: Vec<(&String, &Entry)> = data
+            .iter()
+            .filter(|(key, entry)| !entry.is_expired(now) && cursor.is_none_or(|cursor| key.as_str() > cursor))
+            .collect();
+        let (found, more) = first_by_key(found, limit);
+        let entries: Vec<(String, Vec<u8>)> =
+            found.into_iter().map(|(key, entry)| (key.clone(), entry.value.to_raw())).collect();
+        let next = if more { entries.last().map(|(key, _)| key.clone()) } else { None };
+        Ok(EntryPage { entries, next })
+    }
+    
+    /// Clear all data
+    ///
+    /// Logged as a `FlushAll` under the write lock, so it lands in the WAL
+    /// in the same place among other writes as it takes effect. The map is
+    /// swapped for an empty one, and the old one is freed after the lock is
+    /// released, in the background if it is large. Writers never wait on
+    /// the drop; see [`MemoryStore::pending_free`].
+    async fn clear(&self) -> Result<()> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        if let Some(wal) = &self.wal {
+            wal.log_command(Command::FlushAll).await?;
+        }
+        let empty = HashMap::with_hasher(data.hasher().clone());
+        let old = mem::replace(&mut *data, empty);
+        self.recount(slice::from_ref(&data));
+        drop(data);
+        
+        self.free_lazily(old.len(), old);
+        Ok(())
+    }
+    
+    /// Logged as a `FlushDb` naming the namespace, under the write lock as
+    /// `clear` logs a `FlushAll`. The namespace's entries are moved out of
+    /// the map, since the rest of it stays, and freed as `clear` frees the
+    /// old map.
+    async fn clear_namespace(&self, namespace: &str) -> Result<()> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        if let Some(wal) = &self.wal {
+            let namespace = Some(namespace.to_string());
+            wal.log_command(Command::FlushDb { namespace }).await?;
+        }
+        let removed: Vec<_> = data.extract_if(|key, _| namespace::contains(namespace, key)).collect();
+        self.recount(slice::from_ref(&data));
+        drop(data);
+        
+        self.free_lazily(removed.len(), removed);
+        Ok(())
+    }
+    
+    /// Logged as the `DeletePattern` itself, under the write lock as
+    /// `clear` logs a `FlushAll`, so readers see every matching key or none
+    /// of them. Nothing is logged when no key matches.
+    async fn delete_pattern(&self, pattern: &str) -> Result<Vec<String>> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        let mut maps = [data.deref_mut()];
+        let removed = remove_matching(&mut maps, pattern, self.wal.as_deref()).await?;
+        self.recount(slice::from_ref(&data));
+        Ok(removed)
+    }
+    
+    /// Number of stored items, including expired keys not yet removed
+    async fn len(&self) -> Result<usize> {
+        let data = self.data.read().await;
+        Ok(data.len())
+    }
+    
+    /// Counts expired keys not yet removed, as `len` does
+    async fn namespace_len(&self, namespace: &str) -> Result<usize> {
+        let data = self.data.read().await;
+        Ok(data.keys().filter(|key| namespace::contains(namespace, key)).count())
+    }
+    
+    /// The write lock is held from checking the watched keys until every
+    /// op is applied, and while the writes are logged.
+    async fn apply_batch(
+        &self,
+        watched: Vec<(String, Option<KeyStat>)>,
+        ops: Vec<BatchOp>,
+    ) -> Result<Option<Vec<BatchOutcome>>> {
+        let _in_flight = self.in_flight.read().await;
+        let mut data = self.data.write().await;
+        if !Self::watched_unchanged(&watched, slice::from_ref(&data), |_| 0) {
+            return Ok(None);
+        }
+        self.admit(batch_writes(&ops))?;
+        let keys = self.memory.is_some().then(|| batch_keys(&ops));
+        
+        let (steps, entries) = plan_batch(ops, self.compressor.as_deref());
+        if let Some(wal) = &self.wal {
+            if !entries.is_empty() {
+                wal.write_entries(&entries).await?;
+            }
+        }
+        let outcomes = Self::apply_steps(steps, slice::from_mut(&mut data), |_| 0);
+        for key in keys.iter().flatten() {
+            self.track(key, data.get(key));
+        }
+        drop(data);
+        self.evict().await?;
+        Ok(Some(outcomes))
+    }
+    
+    /// Copied under one read lock.
+    async fn live_entries(&self) -> Result<Vec<SnapshotEntry>> {
+        Ok(self.snapshot_entries().await)
+    }
+    
+    /// Digests are independent of the map's hasher. The scan runs under one
+    /// read lock, which holds off writers until it finishes; digests are
+    /// only comparable between stores that aren't being written.
+    async fn checksum(&self, prefix: &str) -> Result<u64> {
+        let data = self.data.read().await;
+        let now = now_millis();
+        Ok(prefix_digest(
+            prefix,
+            data.iter()
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, entry)| (key.as_str(), entry.value.raw())),
+        ))
+    }
+    
+    async fn checksum_ranges(&self, buckets: usize, prefix: &str) -> Result<Vec<u64>> {
+        let data = self.data.read().await;
+        let now = now_millis();
+        Ok(range_digests(
+            buckets,
+            prefix,
+            data.iter()
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, entry)| (key.as_str(), entry.value.raw())),
+        ))
+    }
+    
+    /// Rewrites the WAL down to one `Set` per live key, carrying its
+    /// metadata, plus an `ExpireAt` for keys with a TTL; does nothing
+    /// without a WAL. The keys are copied a page at a time, and writes only
+    /// wait while a page is copied: they are logged to the old file as
+    /// usual and carried over into the new one between the pages, so they
+    /// only wait again for the final swap.
+    async fn compact_wal(&self) -> Result<CompactionReport> {
+        let Some(wal) = &self.wal else {
+            return Ok(CompactionReport { before: 0, after: 0, keys: 0 });
+        };
+        let before = wal.size();
+        wal.begin_compaction()?;
+        let keys = self.add_compaction_pages(wal).await?;
+        wal.finish_compaction().await?;
+        Ok(CompactionReport { before, after: wal.size(), keys })
+    }
+    
+    /// Walks the whole map under one write lock. Nothing is logged: a
+    /// replay drops the same keys, having logged their deadlines.
+    async fn sweep_expired(&self) -> usize {
+        let mut data = self.data.write().await;
+        let now = now_millis();
+        let before = data.len();
+        data.retain(|key, entry| {
+            let expired = entry.is_expired(now);
+            if expired {
+                self.track(key, None);
+            }
+            !expired
+        });
+        before - data.len()
+    }
+    
+    fn compression_stats(&self) -> Option<CompressionStats> {
+        self.compressor.as_ref().map(|compressor| compressor.stats())
+    }
+    
+    /// Non-zero for a while after clearing a large store, or a large
+    /// namespace, while the removed entries are dropped on a blocking
+    /// thread.
+    fn pending_free(&self) -> usize {
+        self.pending_free.load(Ordering::Relaxed)
+    }
+    
+    /// Gives back capacity left behind by deleted keys and shrunken values.
+    /// Rebuilds the map sized to its current length and trims every key and
+    /// value to fit, under a single write lock. Readers and writers wait for
+    /// the rebuild but never observe a partial map.
+    async fn shrink(&self) -> ShrinkReport {
+        let mut data = self.data.write().await;
+        let before = allocated_bytes(&data);
+        
+        let mut rebuilt = HashMap::with_capacity_and_hasher(data.len(), data.hasher().clone());
+        for (mut key, mut entry) in data.drain() {
+            key.shrink_to_fit();
+            entry.value.shrink_to_fit();
+            rebuilt.insert(key, entry);
+        }
+        *data = rebuilt;
+        
+        ShrinkReport {
+            before,
+            after: allocated_bytes(&data),
+        }
+    }
+    
+    /// Walks every entry under the read lock.
+    async fn memory_usage(&self) -> MemoryUsage {
+        let data = self.data.read().await;
+        MemoryUsage {
+            allocated: allocated_bytes(&data),
+            live: live_bytes(&data),
+        }
+    }
+    
+    /// Records the WAL's checkpoint and copies the live entries with writes
+    /// quieted, then writes them out while writes carry on. A write that
+    /// landed in the copy would be replayed again on restore and counted
+    /// twice in its key's version.
+    async fn snapshot_to(&self, path: &Path) -> Result<()> {
+        let (checkpoint, entries) = {
+            let _quiet = self.in_flight.write().await;
+            (wal_checkpoint(self.wal.as_deref()).await?, self.snapshot_entries().await)
+        };
+        write_snapshot(path, checkpoint, entries).await
+    }
+    
+    fn restore_blocking<P>(&self, snapshot: Option<&Path>, progress: P) -> Result<()>
+    where
+        P: FnMut(ReplayProgress),
+    {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut maps = [self.data.blocking_write()];
+        restore_into(wal, snapshot, &mut maps, |_| 0, self.compressor.as_deref(), progress)?;
+        self.recount(&maps);
+        Ok(())
+    }
+}
+
+/// The WAL's checkpoint; the start of a log for a store without one
+///
+/// Take it with writes quieted: a write logged before the checkpoint but
+/// not yet applied would be in neither the snapshot nor the entries
+/// replayed after it.
+async fn wal_checkpoint(wal: Option<&WriteAheadLog>) -> Result<Checkpoint> {
+    match wal {
+        Some(wal) => wal.checkpoint().await,
+        None => Ok(Checkpoint::START),
+    }
+}
+
+/// Write a snapshot of `entries` to `path` on a blocking thread
+async fn write_snapshot(path: &Path, checkpoint: Checkpoint, entries: Vec<SnapshotEntry>) -> Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || snapshot::write(&path, checkpoint, &entries))
+        .await
+        .map_err(|e| RustVaultError::Snapshot(format!("Snapshot task failed: {}", e)))?
+}
+
+/// Replay `wal` into `maps`, starting from the snapshot file at `snapshot`
+/// when it covers the start of the log
+///
+/// Each key goes to the map `index` picks for it. A snapshot that can't be
+/// read, or whose checkpoint the log no longer continues from, is ignored
+/// and the whole log replayed instead. Keys that have expired are dropped
+/// once everything is applied.
+fn restore_into<S, M, P>(
+    wal: &WriteAheadLog,
+    snapshot: Option<&Path>,
+    maps: &mut [M],
+    index: impl Fn(&str) -> usize,
+    compressor: Option<&Compressor>,
+    mut progress: P,
+) -> Result<()>
+where
+    S: BuildHasher + Send + Sync + 'static,
+    M: DerefMut<Target = HashMap<String, Entry, S>>,
+    P: FnMut(ReplayProgress),
+{
+    if let Some(path) = snapshot {
+        let now = now_millis();
+        let loaded = snapshot::read(path, |entry| {
+            // Snapshots from before metadata was kept start every key over
+            let value = compression::store(compressor, entry.value);
+            let mut restored = Entry::written(None, value, entry.expires_at, now);
+            if let Some(stat) = entry.stat {
+                (restored.version, restored.created_at, restored.updated_at) =
+                    (stat.version, stat.created_at, stat.updated_at);
+            }
+            maps[index(&entry.key)].insert(entry.key, restored);
+        });
+        match loaded {
+            Ok(None) => {}
+            Ok(Some(checkpoint)) => {
+                let replayed = wal.replay_after(
+                    checkpoint,
+                    |entry| {
+                        MemoryStore::apply_replayed(entry, maps, &index, compressor);
+                        Ok(())
+                    },
+                    &mut progress,
+                )?;
+                if replayed {
+                    purge_expired(maps);
+                    return Ok(());
+                }
+                eprintln!(
+                    "Snapshot {} doesn't match the WAL; replaying the whole WAL",
+                    path.display()
+                );
+            }
+            Err(e) => eprintln!("Ignoring snapshot {}: {}; replaying the whole WAL", path.display(), e),
+        }
+        for map in maps.iter_mut() {
+            map.clear();
+        }
+    }
+    
+    wal.replay_with_progress(
+        |entry| {
+            MemoryStore::apply_replayed(entry, maps, &index, compressor);
+            Ok(())
+        },
+        progress,
+    )?;
+    purge_expired(maps);
+    Ok(())
+}
+
+/// Drop every expired key from `maps`, once a replay that may have needed
+/// them is done
+fn purge_expired<S, M>(maps: &mut [M])
+where
+    M: DerefMut<Target = HashMap<String, Entry, S>>,
+{
+    let now = now_millis();
+    for map in maps.iter_mut() {
+        map.retain(|_, entry| !entry.is_expired(now));
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::wal::SyncPolicy;
     use tempfile::NamedTempFile;
 
+    /// Standard store checks shared by every hasher configuration
+    async fn check_basic_operations(store: impl Store) {
+        // Test set and get
+        store.set("key1".to_string(), b"value1".to_vec()).await.unwrap();
+        let result = store.get("key1").await.unwrap();
+        assert_eq!(result, Some(b"value1".to_vec()));
+        
+        // Test exists
+        assert!(store.exists("key1").await.unwrap());
+        assert!(!store.exists("nonexistent").await.unwrap());
+        
+        // Test delete
+        assert!(store.delete("key1").await.unwrap());
+        assert!(!store.delete("key1").await.unwrap()); // Already deleted
+        
+        let result = store.get("key1").await.unwrap();
+        assert_eq!(result, None);
+        
+        // Test len, get_all and clear
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        assert_eq!(store.len().await.unwrap(), 100);
+        assert_eq!(store.get_all().await.unwrap().len(), 100);
+        store.clear().await.unwrap();
+        assert!(store.is_empty().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_basic_operations() {
+        check_basic_operations(MemoryStore::new()).await;
+    }
+    
+    #[cfg(feature = "ahash")]
+    #[tokio::test]
+    async fn test_memory_store_with_ahash() {
+        check_basic_operations(AHashMemoryStore::default()).await;
+    }
+    
+    #[cfg(feature = "fxhash")]
+    #[tokio::test]
+    async fn test_memory_store_with_fxhash() {
+        check_basic_operations(FxMemoryStore::default()).await;
+    }
+    
+    #[tokio::test]
+    async fn test_sharded_store_with_each_hasher() {
+        let kinds = [
+            HasherKind::SipHash,
+            #[cfg(feature = "ahash")]
+            HasherKind::AHash,
+            #[cfg(feature = "fxhash")]
+            HasherKind::FxHash,
+        ];
+        for kind in kinds {
+            check_basic_operations(ShardedMemoryStore::with_hasher(4, kind.build())).await;
+        }
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_full_disk_refuses_writes() {
+        // Every write to /dev/full fails with ENOSPC
+        let wal = Arc::new(WriteAheadLog::new("/dev/full", SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        
+        for _ in 0..2 {
+            match store.set("key".to_string(), b"value".to_vec()).await {
+                Err(crate::error::RustVaultError::Persistence(_)) => {}
+                other => panic!("expected a persistence error, got {:?}", other),
+            }
+        }
+        assert_eq!(store.get("key").await.unwrap(), None);
+        assert!(wal.persistence_error().is_some());
+        assert_eq!(wal.persistence_failures(), 1);
+    }
+    
+    fn sorted(mut entries: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+        entries.sort();
+        entries
+    }
+    
+    #[tokio::test]
+    async fn test_compact_wal_shrinks_and_replays() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        
+        for i in 0..10_000 {
+            store.set(format!("key{}", i % 50), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        store.delete("key0").await.unwrap();
+        store.set_with_ttl("key1".to_string(), b"expiring".to_vec(), Duration::from_secs(3600)).await.unwrap();
+        
+        let report = store.compact_wal().await.unwrap();
+        assert!(report.after < report.before / 10, "{:?}", report);
+        assert_eq!(report.after, std::fs::metadata(temp_file.path()).unwrap().len());
+        assert_eq!(report.keys, 49);
+        
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+        assert_eq!(restored.len().await.unwrap(), 49);
+        assert!(restored.ttl("key1").await.unwrap() > Duration::from_secs(3500));
+        assert_eq!(restored.ttl("key2").await, None);
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_writes_during_compaction_survive() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = Arc::new(MemoryStore::with_wal(wal));
+        for i in 0..1000 {
+            store.set(format!("old{}", i), b"value".to_vec()).await.unwrap();
+        }
+        
+        let writers: Vec<_> = (0..4)
+            .map(|w| {
+                let store = Arc::clone(&store);
+                tokio::spawn(async move {
+                    for i in 0..500 {
+                        store.set(format!("new{}-{}", w, i), b"value".to_vec()).await.unwrap();
+                        store.delete(&format!("old{}", w * 250 + i % 250)).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..5 {
+            store.compact_wal().await.unwrap();
+        }
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.len().await.unwrap(), 2000);
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_racing_writes_to_one_key_replay_as_applied() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = Arc::new(MemoryStore::with_wal(wal));
+        
+        // Each round SET, SET with a TTL, DELETE and CAS race on one key;
+        // replaying the log must then give what the map holds
+        for round in 0..300 {
+            store.set("key".to_string(), b"start".to_vec()).await.unwrap();
+            let writers: Vec<_> = (0..4)
+                .map(|op| {
+                    let store = Arc::clone(&store);
+                    tokio::spawn(async move {
+                        let value = format!("{}-{}", round, op).into_bytes();
+                        match op {
+                            0 => store.set("key".to_string(), value).await.unwrap(),
+                            1 => store.set_with_ttl("key".to_string(), value, Duration::from_secs(3600)).await.unwrap(),
+                            2 => {
+                                store.delete("key").await.unwrap();
+                            }
+                            _ => {
+                                store.cas("key".to_string(), b"start", value).await.unwrap();
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.await.unwrap();
+            }
+            
+            let replayed = MemoryStore::new();
+            replayed.restore_from_path(temp_file.path()).await.unwrap();
+            assert_eq!(replayed.get("key").await.unwrap(), store.get("key").await.unwrap(), "round {}", round);
+            assert_eq!(replayed.ttl("key").await.is_some(), store.ttl("key").await.is_some(), "round {}", round);
+        }
+        
+        // And so does restarting from it
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.get("key").await.unwrap(), store.get("key").await.unwrap());
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_compaction_never_stalls_writes() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = Arc::new(MemoryStore::with_wal(Arc::clone(&wal)));
+        let pairs: Vec<_> = (0..100_000).map(|i| (format!("key{}", i), vec![b'v'; 32])).collect();
+        for chunk in pairs.chunks(1000) {
+            store.mset(chunk.to_vec()).await.unwrap();
+        }
+        
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let (store, done) = (Arc::clone(&store), Arc::clone(&done));
+            tokio::spawn(async move {
+                let (mut writes, mut slowest) = (0u64, Duration::ZERO);
+                while !done.load(Ordering::Relaxed) {
+                    let started = std::time::Instant::now();
+                    store.set(format!("key{}", writes % 100_000), writes.to_string().into_bytes()).await.unwrap();
+                    slowest = slowest.max(started.elapsed());
+                    writes += 1;
+                }
+                (writes, slowest)
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let started = std::time::Instant::now();
+        store.compact_wal().await.unwrap();
+        let took = started.elapsed();
+        done.store(true, Ordering::Relaxed);
+        let (writes, slowest) = writer.await.unwrap();
+        
+        // Writes only ever wait for a page, never for the whole copy
+        assert!(writes > 0);
+        assert!(
+            slowest < Duration::from_millis(50).max(took / 10),
+            "a write waited {:?} during a {:?} compaction",
+            slowest,
+            took
+        );
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+        // A key's stamps come from its log entry, which can be a millisecond
+        // older, but no write is counted twice
+        for key in ["key0", "key99999", &format!("key{}", (writes - 1) % 100_000)] {
+            let version = |stat: Option<KeyStat>| stat.map(|stat| stat.version);
+            assert_eq!(version(restored.stat(key).await.unwrap()), version(store.stat(key).await.unwrap()));
+        }
+    }
+    
     #[tokio::test]
-    async fn test_memory_store_basic_operations() {
-        let store = MemoryStore::new();
+    async fn test_cas_swaps_only_on_match() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
         
-        // Test set and get
-        store.set("key1".to_string(), "value1".to_string()).await.unwrap();
-        let result = store.get("key1").await.unwrap();
-        assert_eq!(result, Some("value1".to_string()));
+        assert!(!store.cas("k".to_string(), b"", b"v1".to_vec()).await.unwrap());
+        store.set_with_ttl("k".to_string(), b"v1".to_vec(), Duration::from_secs(60)).await.unwrap();
+        let size = wal.size();
+        assert!(!store.cas("k".to_string(), b"v0", b"v2".to_vec()).await.unwrap());
+        assert_eq!(wal.size(), size, "a failed swap must not be logged");
         
-        // Test exists
-        assert!(store.exists("key1").await.unwrap());
-        assert!(!store.exists("nonexistent").await.unwrap());
+        assert!(store.cas("k".to_string(), b"v1", b"v2".to_vec()).await.unwrap());
+        assert_eq!(store.get("k").await.unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(store.ttl("k").await, None);
         
-        // Test delete
-        assert!(store.delete("key1").await.unwrap());
-        assert!(!store.delete("key1").await.unwrap()); // Already deleted
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.get("k").await.unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(restored.ttl("k").await, None);
+    }
+    
+    #[tokio::test]
+    async fn test_set_if_checks_and_writes_in_one_step() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        let set_if = |value: &str, ttl, condition| store.set_if("k".to_string(), value.as_bytes().to_vec(), ttl, condition);
         
-        let result = store.get("key1").await.unwrap();
-        assert_eq!(result, None);
+        assert!(!set_if("v0", None, SetCondition::IfPresent).await.unwrap());
+        assert_eq!(store.get("k").await.unwrap(), None);
+        assert!(set_if("v1", Some(Duration::from_secs(60)), SetCondition::IfAbsent).await.unwrap());
+        assert!(store.ttl("k").await.is_some());
+        let size = wal.size();
+        assert!(!set_if("v2", None, SetCondition::IfAbsent).await.unwrap());
+        assert_eq!(wal.size(), size, "a write not made must not be logged");
+        assert!(set_if("v3", None, SetCondition::IfPresent).await.unwrap());
+        assert_eq!(store.get("k").await.unwrap(), Some(b"v3".to_vec()));
+        assert_eq!(store.ttl("k").await, None);
+        
+        // An expired key counts as absent
+        store.set_with_ttl("gone".to_string(), b"old".to_vec(), Duration::ZERO).await.unwrap();
+        assert!(!store.set_if("gone".to_string(), b"v".to_vec(), None, SetCondition::IfPresent).await.unwrap());
+        assert!(store.set_if("gone".to_string(), b"new".to_vec(), None, SetCondition::IfAbsent).await.unwrap());
+        
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.get("k").await.unwrap(), Some(b"v3".to_vec()));
+        assert_eq!(restored.get("gone").await.unwrap(), Some(b"new".to_vec()));
+    }
+    
+    #[tokio::test]
+    async fn test_mset_mget() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(wal);
+        
+        store.set_with_ttl("a".to_string(), b"old".to_vec(), Duration::from_secs(60)).await.unwrap();
+        store
+            .mset(vec![
+                ("a".to_string(), b"1".to_vec()),
+                ("b".to_string(), b"2".to_vec()),
+                ("b".to_string(), b"3".to_vec()),
+            ])
+            .await
+            .unwrap();
+        store.mset(Vec::new()).await.unwrap();
+        assert_eq!(store.ttl("a").await, None);
+        
+        let keys = ["a", "missing", "b"].map(String::from);
+        let expected = vec![Some(b"1".to_vec()), None, Some(b"3".to_vec())];
+        assert_eq!(store.mget(&keys).await.unwrap(), expected);
+        assert!(store.mget(&[]).await.unwrap().is_empty());
+        
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.mget(&keys).await.unwrap(), expected);
+    }
+    
+    #[tokio::test]
+    async fn test_incr() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(wal);
+        
+        assert_eq!(store.incr("hits", 1).await.unwrap(), 1);
+        assert_eq!(store.incr("hits", 41).await.unwrap(), 42);
+        assert_eq!(store.incr("hits", -50).await.unwrap(), -8);
+        assert_eq!(store.get("hits").await.unwrap(), Some(b"-8".to_vec()));
+        
+        // A value that isn't a number, or an overflow, leaves the key alone
+        store.set("name".to_string(), b"rex".to_vec()).await.unwrap();
+        assert!(store.incr("name", 1).await.is_err());
+        assert_eq!(store.get("name").await.unwrap(), Some(b"rex".to_vec()));
+        store.set("max".to_string(), i64::MAX.to_string().into_bytes()).await.unwrap();
+        assert!(store.incr("max", 1).await.is_err());
+        assert_eq!(store.incr("max", 0).await.unwrap(), i64::MAX);
+        
+        store.set_with_ttl("limit".to_string(), b"0".to_vec(), Duration::from_secs(60)).await.unwrap();
+        assert_eq!(store.incr("limit", 1).await.unwrap(), 1);
+        assert!(store.ttl("limit").await.is_some());
+        
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.get("hits").await.unwrap(), Some(b"-8".to_vec()));
+        assert_eq!(restored.get("limit").await.unwrap(), Some(b"1".to_vec()));
+        assert!(restored.ttl("limit").await.is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_append_and_strlen() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = Arc::new(MemoryStore::with_wal(wal));
+        
+        assert_eq!(store.strlen("log").await.unwrap(), None);
+        assert_eq!(store.append("log", b"one ").await.unwrap(), 4);
+        assert_eq!(store.append("log", b"two").await.unwrap(), 7);
+        assert_eq!(store.get("log").await.unwrap(), Some(b"one two".to_vec()));
+        assert_eq!(store.strlen("log").await.unwrap(), Some(7));
+        assert_eq!(store.stat("log").await.unwrap().unwrap().version, 2);
+        
+        // A live key keeps its TTL; an expired one starts over without it
+        store.set_with_ttl("session".to_string(), b"a".to_vec(), Duration::from_secs(60)).await.unwrap();
+        assert_eq!(store.append("session", b"b").await.unwrap(), 2);
+        assert!(store.ttl("session").await.is_some());
+        store.set_with_ttl("gone".to_string(), b"old".to_vec(), Duration::from_millis(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(store.append("gone", b"new").await.unwrap(), 3);
+        assert_eq!(store.ttl("gone").await, None);
+        
+        let appenders: Vec<_> = (0..4u8)
+            .map(|i| {
+                let store = Arc::clone(&store);
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        store.append("shared", &[b'a' + i; 3]).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for appender in appenders {
+            appender.await.unwrap();
+        }
+        let shared = store.get("shared").await.unwrap().unwrap();
+        assert_eq!(shared.len(), 4 * 100 * 3);
+        for i in 0..4u8 {
+            assert_eq!(shared.iter().filter(|&&b| b == b'a' + i).count(), 300);
+        }
+        
+        // Replaying the logged appends rebuilds the same values
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        let sorted = |mut pairs: Vec<(String, Vec<u8>)>| {
+            pairs.sort();
+            pairs
+        };
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+        assert_eq!(restored.stat("log").await.unwrap().unwrap().version, 2);
+        assert!(restored.ttl("session").await.is_some());
+        
+        let keyspace = crate::recovery::Keyspace::replay(temp_file.path()).unwrap();
+        assert_eq!(keyspace.live["shared"].value, shared);
+    }
+    
+    #[tokio::test]
+    async fn test_getset_and_getdel() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(wal);
+        
+        assert_eq!(store.getset("key".to_string(), b"one".to_vec()).await.unwrap(), None);
+        assert_eq!(store.getset("key".to_string(), b"two".to_vec()).await.unwrap(), Some(b"one".to_vec()));
+        assert_eq!(store.get("key").await.unwrap(), Some(b"two".to_vec()));
+        assert_eq!(store.stat("key").await.unwrap().unwrap().version, 2);
+        
+        // Like SET it drops the TTL, and an expired value isn't returned
+        store.set_with_ttl("session".to_string(), b"a".to_vec(), Duration::from_secs(60)).await.unwrap();
+        assert_eq!(store.getset("session".to_string(), b"b".to_vec()).await.unwrap(), Some(b"a".to_vec()));
+        assert_eq!(store.ttl("session").await, None);
+        store.set_with_ttl("gone".to_string(), b"old".to_vec(), Duration::from_millis(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(store.getset("gone".to_string(), b"new".to_vec()).await.unwrap(), None);
+        
+        assert_eq!(store.getdel("session").await.unwrap(), Some(b"b".to_vec()));
+        assert_eq!(store.getdel("session").await.unwrap(), None);
+        assert!(!store.exists("session").await.unwrap());
+        
+        // Logged as the SETs and DELETE they perform
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.get("key").await.unwrap(), Some(b"two".to_vec()));
+        assert_eq!(restored.get("gone").await.unwrap(), Some(b"new".to_vec()));
+        assert_eq!(restored.get("session").await.unwrap(), None);
+    }
+    
+    #[tokio::test]
+    async fn test_locks_are_held_by_one_token_until_their_lease_runs_out() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let open = || Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(open());
+        let (lease, minute) = (Duration::from_millis(50), Duration::from_secs(60));
+        
+        assert!(store.lock("job".to_string(), "a", lease).await.unwrap());
+        assert!(!store.lock("job".to_string(), "b", minute).await.unwrap());
+        assert!(!store.unlock("job", "b").await.unwrap());
+        // Its holder renews it
+        assert!(store.lock("job".to_string(), "a", minute).await.unwrap());
+        assert!(store.unlock("job", "a").await.unwrap());
+        assert!(!store.unlock("job", "a").await.unwrap());
+        
+        // A lease that ran out releases nothing, and is free for anyone
+        assert!(store.lock("job".to_string(), "b", lease).await.unwrap());
+        tokio::time::sleep(lease * 2).await;
+        assert!(!store.unlock("job", "b").await.unwrap());
+        assert!(store.lock("job".to_string(), "c", minute).await.unwrap());
+        
+        // A held lock is still held after a restart
+        let restored = MemoryStore::with_wal(open());
+        restored.restore_from_wal().await.unwrap();
+        assert!(!restored.lock("job".to_string(), "b", minute).await.unwrap());
+        assert!(restored.ttl("job").await.unwrap() > minute / 2);
+        assert!(restored.unlock("job", "c").await.unwrap());
+        
+        // The sweep removes leases that ran out, untouched
+        store.lock("short".to_string(), "a", lease).await.unwrap();
+        tokio::time::sleep(lease * 2).await;
+        assert_eq!(store.data.read().await.len(), 2);
+        assert_eq!(store.sweep_expired().await, 1);
+        assert_eq!(store.data.read().await.keys().collect::<Vec<_>>(), ["job"]);
+    }
+    
+    #[tokio::test]
+    async fn test_compressed_values_survive_restart() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let open = || Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let lz4 = CompressionConfig { algorithm: CompressionAlgorithm::Lz4, min_size_bytes: 64 };
+        let zstd = CompressionConfig { algorithm: CompressionAlgorithm::Zstd, ..lz4 };
+        let compressible = b"{\"name\": \"value\"} ".repeat(1000);
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let random: Vec<u8> = (0..16 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        
+        let store = MemoryStore::with_wal(open())
+            .with_memory_limit(1 << 20, EvictionPolicy::NoEviction)
+            .with_compression(lz4);
+        store.set("compressible".to_string(), compressible.clone()).await.unwrap();
+        store.set("random".to_string(), random.clone()).await.unwrap();
+        store.append("compressible", b"tail").await.unwrap();
+        let compressible = [compressible, b"tail".to_vec()].concat();
+        assert_eq!(store.get("compressible").await.unwrap().as_ref(), Some(&compressible));
+        assert_eq!(store.strlen("compressible").await.unwrap(), Some(compressible.len()));
+        assert!(store.cas("random".to_string(), &random, random.clone()).await.unwrap());
+        assert!(store.memory_used().unwrap() < random.len() + compressible.len() / 10);
+        let stats = store.compression_stats().unwrap();
+        assert_eq!(stats.incompressible, 2);
+        assert!(stats.ratio() > 10.0, "{:?}", stats);
+        
+        // A store without compression adds plain entries to the same log,
+        // and the mix replays into a store set up either way
+        let plain = MemoryStore::with_wal(open());
+        plain.restore_from_wal().await.unwrap();
+        assert_eq!(plain.get("compressible").await.unwrap(), Some(compressible.clone()));
+        plain.set("later".to_string(), compressible.clone()).await.unwrap();
+        let expected = [("compressible", &compressible), ("later", &compressible), ("random", &random)]
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .to_vec();
+        for config in [None, Some(zstd)] {
+            let mut restored = MemoryStore::with_wal(open());
+            if let Some(config) = config {
+                restored = restored.with_compression(config);
+            }
+            restored.restore_from_wal().await.unwrap();
+            assert_eq!(sorted(restored.get_all().await.unwrap()), expected, "{:?}", config);
+            // The set, the append and the later set each compressed the value
+            assert_eq!(restored.compression_stats().map(|stats| stats.values), config.map(|_| 3));
+        }
+        
+        // Compaction keeps values as they are held, and the sharded store
+        // reads the result
+        let compacted = MemoryStore::with_wal(open()).with_compression(zstd);
+        compacted.restore_from_wal().await.unwrap();
+        compacted.compact_wal().await.unwrap();
+        let sharded = Arc::new(ShardedMemoryStore::with_wal(open(), 4).with_compression(lz4));
+        let replaying = Arc::clone(&sharded);
+        tokio::task::spawn_blocking(move || replaying.restore_blocking(None, |_| {}))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sorted(sharded.get_all().await.unwrap()), expected);
+        assert_eq!(sharded.checksum("").await.unwrap(), plain.checksum("").await.unwrap());
     }
     
     #[tokio::test]
     async fn test_memory_store_with_wal() {
         let temp_file = NamedTempFile::new().unwrap();
-        let wal = Arc::new(WriteAheadLog::new(temp_file.path()).unwrap());
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
         let store = MemoryStore::with_wal(wal);
         
         // Test operations with WAL
-        store.set("key1".to_string(), "value1".to_string()).await.unwrap();
-        store.set("key2".to_string(), "value2".to_string()).await.unwrap();
+        store.set("key1".to_string(), b"value1".to_vec()).await.unwrap();
+        store.set("key2".to_string(), b"value2".to_vec()).await.unwrap();
         
         let result1 = store.get("key1").await.unwrap();
         let result2 = store.get("key2").await.unwrap();
         
-        assert_eq!(result1, Some("value1".to_string()));
-        assert_eq!(result2, Some("value2".to_string()));
+        assert_eq!(result1, Some(b"value1".to_vec()));
+        assert_eq!(result2, Some(b"value2".to_vec()));
         
         // Test get_all
         let all_data = store.get_all().await.unwrap();
@@ -233,7 +2601,7 @@ mod tests {
             let store_clone = Arc::clone(&store);
             let handle = tokio::spawn(async move {
                 let key = format!("key{}", i);
-                let value = format!("value{}", i);
+                let value = format!("value{}", i).into_bytes();
                 store_clone.set(key.clone(), value.clone()).await.unwrap();
                 let result = store_clone.get(&key).await.unwrap();
                 assert_eq!(result, Some(value));
@@ -249,4 +2617,674 @@ mod tests {
         // Verify all data is present
         assert_eq!(store.len().await.unwrap(), 10);
     }
+    
+    #[tokio::test]
+    async fn test_shrink_reclaims_deleted_capacity() {
+        let store = MemoryStore::new();
+        for i in 0..1000 {
+            // Values with slack, as if they had been overwritten with shorter ones
+            let mut value = Vec::with_capacity(256);
+            value.extend_from_slice(format!("value{}", i).as_bytes());
+            store.set(format!("key{}", i), value).await.unwrap();
+        }
+        for i in 100..1000 {
+            store.delete(&format!("key{}", i)).await.unwrap();
+        }
+        
+        let report = store.shrink().await;
+        assert!(report.after < report.before / 4, "{:?}", report);
+        assert_eq!(report.reclaimed(), report.before - report.after);
+        
+        // Contents are untouched, and a second pass has nothing left to give
+        assert_eq!(store.len().await.unwrap(), 100);
+        assert_eq!(store.get("key42").await.unwrap(), Some(b"value42".to_vec()));
+        let again = store.shrink().await;
+        assert_eq!(again.before, report.after);
+        assert_eq!(again.reclaimed(), 0);
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shrink_during_concurrent_access() {
+        let store = Arc::new(MemoryStore::new());
+        for i in 0..500 {
+            store.set(format!("stable{}", i), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        
+        let mut handles = vec![];
+        for t in 0..4 {
+            let store = Arc::clone(&store);
+            handles.push(tokio::spawn(async move {
+                for i in 0..200 {
+                    let key = format!("churn{}_{}", t, i);
+                    store.set(key.clone(), b"x".repeat(100)).await.unwrap();
+                    assert_eq!(
+                        store.get(&format!("stable{}", i)).await.unwrap(),
+                        Some(format!("value{}", i).into_bytes())
+                    );
+                    assert!(store.delete(&key).await.unwrap());
+                }
+            }));
+        }
+        for _ in 0..20 {
+            store.shrink().await;
+            tokio::task::yield_now().await;
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        
+        assert_eq!(store.len().await.unwrap(), 500);
+    }    
+    #[tokio::test]
+    async fn test_checksum_ignores_order_and_hasher() {
+        let a = MemoryStore::new();
+        let b = MemoryStore::new();
+        for i in 0..100 {
+            a.set(format!("key{}", i), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        for i in (0..100).rev() {
+            b.set(format!("key{}", i), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        assert_eq!(a.checksum("").await.unwrap(), b.checksum("").await.unwrap());
+        assert_eq!(a.checksum_ranges(16, "").await.unwrap(), b.checksum_ranges(16, "").await.unwrap());
+        
+        // Moving bytes between key and value changes the digest
+        let c = MemoryStore::new();
+        let d = MemoryStore::new();
+        c.set("ab".to_string(), b"c".to_vec()).await.unwrap();
+        d.set("a".to_string(), b"bc".to_vec()).await.unwrap();
+        assert_ne!(c.checksum("").await.unwrap(), d.checksum("").await.unwrap());
+        
+        b.set("key42".to_string(), b"changed".to_vec()).await.unwrap();
+        assert_ne!(a.checksum("").await.unwrap(), b.checksum("").await.unwrap());
+        assert_eq!(a.checksum("key1").await.unwrap(), b.checksum("key1").await.unwrap());
+        assert_ne!(a.checksum("key4").await.unwrap(), b.checksum("key4").await.unwrap());
+        
+        // Under "key4", only the bucket for '2' differs
+        let ours = a.checksum_ranges(256, "key4").await.unwrap();
+        let theirs = b.checksum_ranges(256, "key4").await.unwrap();
+        let differing: Vec<usize> = (0..256).filter(|&i| ours[i] != theirs[i]).collect();
+        assert_eq!(differing, vec![b'2' as usize]);
+    }
+    
+    #[tokio::test]
+    async fn test_checksum_ranges_partition_keys() {
+        let store = MemoryStore::new();
+        for key in ["", "a", "m", "z", "~", "\u{e9}"] {
+            store.set(key.to_string(), b"v".to_vec()).await.unwrap();
+        }
+        
+        for buckets in [1, 3, 16, 256] {
+            let digests = store.checksum_ranges(buckets, "").await.unwrap();
+            assert_eq!(digests.len(), buckets);
+            // Every key but the empty one lands in exactly one bucket
+            let sum = digests.iter().fold(0u64, |sum, d| sum.wrapping_add(*d));
+            assert_eq!(sum, store.checksum("").await.unwrap().wrapping_sub(entry_digest("", b"v")));
+        }
+        
+        // Buckets are contiguous byte ranges
+        let digests = store.checksum_ranges(2, "").await.unwrap();
+        let ascii = ["a", "m", "z", "~"]
+            .iter()
+            .fold(0u64, |sum, key| sum.wrapping_add(entry_digest(key, b"v")));
+        assert_eq!(digests[0], ascii);
+        assert_eq!(digests[1], entry_digest("\u{e9}", b"v"));
+    }
+    
+    /// Wait for `store` to finish freeing what it removed, returning how
+    /// long that took from `started`
+    async fn wait_until_freed(store: &impl Store, started: std::time::Instant) -> Duration {
+        let deadline = started + Duration::from_secs(10);
+        while store.pending_free() > 0 {
+            assert!(std::time::Instant::now() < deadline, "removed entries were never freed");
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        started.elapsed()
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_writers_do_not_wait_for_clear_to_free() {
+        let store = Arc::new(MemoryStore::new());
+        {
+            let mut data = store.data.write().await;
+            for i in 0..300_000 {
+                let value = StoredValue::Raw(format!("value{}", i).into_bytes());
+                data.insert(format!("key{}", i), Entry::written(None, value, None, 0));
+            }
+        }
+        
+        // A writer setting a key over and over, timing each wait
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let (store, done) = (Arc::clone(&store), Arc::clone(&done));
+            tokio::spawn(async move {
+                let mut longest = Duration::ZERO;
+                while !done.load(Ordering::Relaxed) {
+                    let started = std::time::Instant::now();
+                    store.set("probe".to_string(), b"v".to_vec()).await.unwrap();
+                    longest = longest.max(started.elapsed());
+                    tokio::task::yield_now().await;
+                }
+                longest
+            })
+        };
+        
+        // Freeing the old map, once the lock is released, takes as long as
+        // writers would have waited had it been dropped under the lock
+        store.clear().await.unwrap();
+        let freed = wait_until_freed(&*store, std::time::Instant::now()).await;
+        done.store(true, Ordering::Relaxed);
+        let longest_wait = writer.await.unwrap();
+        assert!(store.len().await.unwrap() <= 1);
+        assert!(
+            longest_wait < freed,
+            "a writer waited {:?} during a clear whose old map took {:?} to free",
+            longest_wait,
+            freed
+        );
+        
+        // A namespace's entries are freed the same way
+        for i in 0..2_000 {
+            store.set(namespace::qualify("db1", &format!("key{}", i)), b"v".to_vec()).await.unwrap();
+        }
+        store.clear_namespace("db1").await.unwrap();
+        wait_until_freed(&*store, std::time::Instant::now()).await;
+        assert!(store.len().await.unwrap() <= 1);
+        
+        // Small maps skip the hand-off entirely
+        store.set("key".to_string(), b"value".to_vec()).await.unwrap();
+        store.clear().await.unwrap();
+        assert_eq!(store.pending_free(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_scan_pages_through_every_key() {
+        let store = MemoryStore::new();
+        for i in 0..100 {
+            store.set(format!("user:{}", i), b"v".to_vec()).await.unwrap();
+        }
+        store.set("other".to_string(), b"v".to_vec()).await.unwrap();
+        store.set_with_ttl("user:gone".to_string(), b"v".to_vec(), Duration::ZERO).await.unwrap();
+        
+        // Keys change under the scan: removed ones may or may not show up, but
+        // every key there throughout is returned exactly once
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        for page_no in 0.. {
+            let page = store.scan("user:", cursor, 7).await.unwrap();
+            assert!(page.keys.len() <= 7);
+            seen.extend(page.keys);
+            if page_no == 3 {
+                store.delete("user:0").await.unwrap();
+                store.set("user:new".to_string(), b"v".to_vec()).await.unwrap();
+            }
+            cursor = page.cursor;
+            if cursor == 0 {
+                break;
+            }
+        }
+        seen.sort();
+        seen.retain(|key| key != "user:0" && key != "user:new");
+        let mut expected: Vec<String> = (1..100).map(|i| format!("user:{}", i)).collect();
+        expected.sort();
+        assert_eq!(seen, expected);
+        
+        let page = store.scan("", 0, 1000).await.unwrap();
+        assert_eq!(page.keys.len(), 101);
+        assert_eq!(page.cursor, 0);
+        assert!(store.scan("nobody:", 0, 10).await.unwrap().keys.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_scan_page_walks_keys_in_order() {
+        let store = MemoryStore::new();
+        for i in 0..20 {
+            store.set(format!("key{:02}", i), i.to_string().into_bytes()).await.unwrap();
+        }
+        store.set_with_ttl("key05".to_string(), b"v".to_vec(), Duration::ZERO).await.unwrap();
+        
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = store.scan_page(cursor.as_deref(), 6).await.unwrap();
+            assert!(page.entries.len() <= 6);
+            seen.extend(page.entries);
+            cursor = page.next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, sorted(store.get_all().await.unwrap()));
+        assert_eq!(seen.len(), 19);
+        
+        let page = store.scan_page(Some("key10"), 4).await.unwrap();
+        let keys: Vec<&str> = page.entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["key11", "key12", "key13", "key14"]);
+        assert_eq!(page.next.as_deref(), Some("key14"));
+        let last = store.scan_page(Some("key14"), 5).await.unwrap();
+        assert_eq!(last.entries.len(), 5);
+        assert_eq!(last.next, None);
+    }
+    
+    #[tokio::test]
+    async fn test_keys_expire_lazily() {
+        let store = MemoryStore::new();
+        let minute = std::time::Duration::from_secs(60);
+        store.set_with_ttl("session".to_string(), b"abc".to_vec(), minute).await.unwrap();
+        assert_eq!(store.get("session").await.unwrap(), Some(b"abc".to_vec()));
+        let left = store.ttl("session").await.unwrap();
+        assert!(left <= minute && left > minute / 2, "{:?}", left);
+        
+        // A plain SET drops the TTL
+        store.set("session".to_string(), b"def".to_vec()).await.unwrap();
+        assert_eq!(store.ttl("session").await, None);
+        
+        assert!(!store.expire("missing", minute).await.unwrap());
+        assert!(store.expire("session", std::time::Duration::from_millis(50)).await.unwrap());
+        assert!(store.exists("session").await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        
+        // Expired keys read as missing, and the read removes them
+        assert_eq!(store.len().await.unwrap(), 1);
+        assert!(!store.exists("session").await.unwrap());
+        assert_eq!(store.get("session").await.unwrap(), None);
+        assert_eq!(store.len().await.unwrap(), 0);
+        assert!(!store.expire("session", minute).await.unwrap());
+        
+        // A deadline in the past removes the key straight away
+        store.set("old".to_string(), b"x".to_vec()).await.unwrap();
+        assert!(store.expire_at("old", 1).await.unwrap());
+        assert!(store.is_empty().await.unwrap());
+        assert!(!store.delete("old").await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_expiries_survive_replay() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(wal);
+        let minute = std::time::Duration::from_secs(60);
+        
+        store.set_with_ttl("kept".to_string(), b"a".to_vec(), minute).await.unwrap();
+        store.set("persisted".to_string(), b"b".to_vec()).await.unwrap();
+        store.expire("persisted", minute).await.unwrap();
+        store.set("persisted".to_string(), b"c".to_vec()).await.unwrap();
+        store.set("gone".to_string(), b"d".to_vec()).await.unwrap();
+        store.expire_at("gone", now_millis() + 50).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let restored = MemoryStore::with_wal(wal);
+        restored.restore_from_wal().await.unwrap();
+        
+        assert_eq!(restored.get("kept").await.unwrap(), Some(b"a".to_vec()));
+        assert!(restored.ttl("kept").await.unwrap() > minute / 2);
+        assert_eq!(restored.get("persisted").await.unwrap(), Some(b"c".to_vec()));
+        assert_eq!(restored.ttl("persisted").await, None);
+        assert_eq!(restored.get("gone").await.unwrap(), None);
+        assert_eq!(restored.len().await.unwrap(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_stat_counts_every_set() {
+        let store = MemoryStore::new();
+        let minute = std::time::Duration::from_secs(60);
+        assert_eq!(store.stat("key").await.unwrap(), None);
+        
+        let before = now_millis();
+        store.set("key".to_string(), b"1".to_vec()).await.unwrap();
+        let first = store.stat("key").await.unwrap().unwrap();
+        assert_eq!(first.version, 1);
+        assert!(first.created_at >= before && first.updated_at == first.created_at);
+        
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        store.set_with_ttl("key".to_string(), b"2".to_vec(), minute).await.unwrap();
+        store.mset(vec![("key".to_string(), b"3".to_vec())]).await.unwrap();
+        assert!(store.cas("key".to_string(), b"3", b"4".to_vec()).await.unwrap());
+        assert!(!store.cas("key".to_string(), b"3", b"5".to_vec()).await.unwrap());
+        assert_eq!(store.incr("key", 1).await.unwrap(), 5);
+        store.expire("key", minute).await.unwrap();
+        let stat = store.stat("key").await.unwrap().unwrap();
+        assert_eq!(stat.version, 5);
+        assert_eq!(stat.created_at, first.created_at);
+        assert!(stat.updated_at > first.updated_at);
+        
+        // A key that is deleted or expires starts over
+        store.delete("key").await.unwrap();
+        assert_eq!(store.stat("key").await.unwrap(), None);
+        store.set("key".to_string(), b"1".to_vec()).await.unwrap();
+        assert_eq!(store.stat("key").await.unwrap().unwrap().version, 1);
+        store.expire_at("key", now_millis() + 20).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        assert_eq!(store.stat("key").await.unwrap(), None);
+        store.set("key".to_string(), b"1".to_vec()).await.unwrap();
+        assert_eq!(store.stat("key").await.unwrap().unwrap().version, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_stat_survives_replay_compaction_and_snapshots() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let snapshot_path = dir.path().join("snapshot");
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        let short = std::time::Duration::from_millis(30);
+        
+        for i in 0..3 {
+            store.set("often".to_string(), format!("{}", i).into_bytes()).await.unwrap();
+        }
+        // Each value is set again before its TTL runs out, so it stays one key
+        for _ in 0..3 {
+            store.set_with_ttl("refreshed".to_string(), b"v".to_vec(), short).await.unwrap();
+        }
+        store.set("refreshed".to_string(), b"kept".to_vec()).await.unwrap();
+        
+        let versions = |store: MemoryStore| async move {
+            let mut stats = Vec::new();
+            for key in ["often", "refreshed"] {
+                stats.push(store.stat(key).await.unwrap().map(|stat| stat.version));
+            }
+            stats
+        };
+        let live = store.stat("often").await.unwrap().unwrap();
+        tokio::time::sleep(short * 2).await;
+        
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        let replayed = restored.stat("often").await.unwrap().unwrap();
+        assert!(replayed.created_at.abs_diff(live.created_at) < 1000, "{:?} {:?}", replayed, live);
+        assert_eq!(versions(restored).await, vec![Some(3), Some(4)]);
+        
+        // A compacted log carries each key's metadata over exactly
+        store.compact_wal().await.unwrap();
+        store.set("often".to_string(), b"3".to_vec()).await.unwrap();
+        let live = store.stat("often").await.unwrap().unwrap();
+        let restored = MemoryStore::with_wal(Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap()));
+        restored.restore_from_wal().await.unwrap();
+        let replayed = restored.stat("often").await.unwrap().unwrap();
+        assert_eq!((replayed.version, replayed.created_at), (4, live.created_at));
+        assert_eq!(versions(restored).await, vec![Some(4), Some(4)]);
+        
+        store.snapshot_to(&snapshot_path).await.unwrap();
+        store.set("often".to_string(), b"4".to_vec()).await.unwrap();
+        let restored = restore_with_snapshot(temp_file.path(), &snapshot_path).await;
+        assert_eq!(restored.stat("often").await.unwrap().unwrap().created_at, live.created_at);
+        assert_eq!(versions(restored).await, vec![Some(5), Some(4)]);
+    }
+    
+    /// Restore a store over the WAL at `path` from `snapshot` on a blocking thread
+    async fn restore_with_snapshot(path: &Path, snapshot: &Path) -> MemoryStore {
+        let wal = Arc::new(WriteAheadLog::new(path, SyncPolicy::Never).unwrap());
+        let restored = Arc::new(MemoryStore::with_wal(wal));
+        let (replaying, snapshot) = (Arc::clone(&restored), snapshot.to_path_buf());
+        tokio::task::spawn_blocking(move || replaying.restore_blocking(Some(&snapshot), |_| {}))
+            .await
+            .unwrap()
+            .unwrap();
+        Arc::into_inner(restored).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_snapshot_then_wal_tail_restores() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let snapshot_path = dir.path().join("snapshot");
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        let minute = Duration::from_secs(60);
+        
+        for i in 0..100 {
+            store.set(format!("key{}", i), b"before".to_vec()).await.unwrap();
+        }
+        store.set_with_ttl("expiring".to_string(), b"ttl".to_vec(), minute).await.unwrap();
+        store.snapshot_to(&snapshot_path).await.unwrap();
+        
+        store.set("key0".to_string(), b"after".to_vec()).await.unwrap();
+        store.delete("key1").await.unwrap();
+        store.set("new".to_string(), b"after".to_vec()).await.unwrap();
+        
+        let restored = restore_with_snapshot(temp_file.path(), &snapshot_path).await;
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+        assert!(restored.ttl("expiring").await.unwrap() > minute / 2);
+        
+        // Only the tail after the checkpoint is replayed: a snapshot holding a
+        // key the log never wrote keeps it
+        let entries = vec![SnapshotEntry {
+            key: "snapshot-only".to_string(),
+            value: b"value".to_vec(),
+            expires_at: None,
+            stat: None,
+        }];
+        snapshot::write(&snapshot_path, wal.checkpoint().await.unwrap(), &entries).unwrap();
+        store.set("tail".to_string(), b"value".to_vec()).await.unwrap();
+        let restored = restore_with_snapshot(temp_file.path(), &snapshot_path).await;
+        assert_eq!(restored.len().await.unwrap(), 2);
+        assert_eq!(restored.get("snapshot-only").await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(restored.get("tail").await.unwrap(), Some(b"value".to_vec()));
+    }
+    
+    #[tokio::test]
+    async fn test_stale_or_corrupt_snapshot_replays_whole_wal() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let snapshot_path = dir.path().join("snapshot");
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(wal);
+        
+        for i in 0..1000 {
+            store.set(format!("key{}", i % 10), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        store.snapshot_to(&snapshot_path).await.unwrap();
+        store.delete("key0").await.unwrap();
+        
+        // A compaction rewrites the log the checkpoint pointed into
+        store.compact_wal().await.unwrap();
+        store.set("key1".to_string(), b"after".to_vec()).await.unwrap();
+        let restored = restore_with_snapshot(temp_file.path(), &snapshot_path).await;
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+        assert_eq!(restored.get("key0").await.unwrap(), None);
+        
+        // A torn snapshot is ignored the same way
+        store.snapshot_to(&snapshot_path).await.unwrap();
+        let bytes = std::fs::read(&snapshot_path).unwrap();
+        std::fs::write(&snapshot_path, &bytes[..bytes.len() - 20]).unwrap();
+        let restored = restore_with_snapshot(temp_file.path(), &snapshot_path).await;
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+    }
+    
+    #[tokio::test]
+    async fn test_clear_is_logged_and_replayed() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        store.set("old1".to_string(), b"v".to_vec()).await.unwrap();
+        store.set_with_ttl("old2".to_string(), b"v".to_vec(), Duration::from_secs(60)).await.unwrap();
+        store.clear().await.unwrap();
+        store.set("new".to_string(), b"v".to_vec()).await.unwrap();
+        
+        let restored = MemoryStore::with_wal(Arc::clone(&wal));
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(restored.get_all().await.unwrap(), vec![("new".to_string(), b"v".to_vec())]);
+        
+        // Compaction keeps only what survived the clear
+        store.compact_wal().await.unwrap();
+        let compacted = MemoryStore::with_wal(wal);
+        compacted.restore_from_wal().await.unwrap();
+        assert_eq!(compacted.get_all().await.unwrap(), vec![("new".to_string(), b"v".to_vec())]);
+    }
+    
+    #[tokio::test]
+    async fn test_clear_namespace_leaves_the_others() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        for ns in [namespace::DEFAULT, "app", "other"] {
+            store.set(namespace::qualify(ns, "k"), ns.as_bytes().to_vec()).await.unwrap();
+        }
+        assert_eq!(store.namespace_len("app").await.unwrap(), 1);
+        assert_eq!(store.namespace_len(namespace::DEFAULT).await.unwrap(), 1);
+        store.clear_namespace("app").await.unwrap();
+        assert_eq!(store.namespace_len("app").await.unwrap(), 0);
+        assert_eq!(store.len().await.unwrap(), 2);
+        
+        let restored = MemoryStore::with_wal(wal);
+        restored.restore_from_wal().await.unwrap();
+        let mut entries = restored.get_all().await.unwrap();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![(" other k".to_string(), b"other".to_vec()), ("k".to_string(), b"0".to_vec())]
+        );
+        
+        let sharded = ShardedMemoryStore::with_shards(4);
+        sharded.mset(entries).await.unwrap();
+        sharded.clear_namespace("other").await.unwrap();
+        assert_eq!(sharded.get_all().await.unwrap(), vec![("k".to_string(), b"0".to_vec())]);
+        assert_eq!(sharded.namespace_len(namespace::DEFAULT).await.unwrap(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_delete_pattern_removes_only_matches_and_replays() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        for key in ["user:1", "user:10", "user:1:profile", "user:2", "users:1", "other"] {
+            store.set(key.to_string(), b"v".to_vec()).await.unwrap();
+        }
+        store.set(namespace::qualify("app", "user:1"), b"v".to_vec()).await.unwrap();
+        store.set_with_ttl("user:1:gone".to_string(), b"v".to_vec(), Duration::ZERO).await.unwrap();
+        
+        let mut removed = store.delete_pattern("user:1*").await.unwrap();
+        removed.sort();
+        assert_eq!(removed, ["user:1", "user:10", "user:1:profile"]);
+        assert_eq!(store.delete_pattern("user:?").await.unwrap(), ["user:2"]);
+        assert!(store.delete_pattern("nobody:*").await.unwrap().is_empty());
+        let size = wal.size();
+        assert!(store.delete_pattern("user:1*").await.unwrap().is_empty());
+        assert_eq!(wal.size(), size, "a pattern that matches nothing isn't logged");
+        
+        let survivors = vec![
+            (" app user:1".to_string(), b"v".to_vec()),
+            ("other".to_string(), b"v".to_vec()),
+            ("users:1".to_string(), b"v".to_vec()),
+        ];
+        assert_eq!(sorted(store.get_all().await.unwrap()), survivors);
+        let restored = MemoryStore::with_wal(wal);
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(sorted(restored.get_all().await.unwrap()), survivors);
+        
+        let sharded = ShardedMemoryStore::with_shards(4);
+        sharded.mset(survivors).await.unwrap();
+        assert_eq!(sharded.delete_pattern(&namespace::qualify("app", "*")).await.unwrap(), [" app user:1"]);
+        assert_eq!(sharded.len().await.unwrap(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_apply_batch_runs_in_order_and_replays() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = MemoryStore::with_wal(Arc::clone(&wal));
+        store.set("gone".to_string(), b"v".to_vec()).await.unwrap();
+        
+        let ops = vec![
+            BatchOp::Set { key: "a".to_string(), value: b"1".to_vec(), ttl: None },
+            BatchOp::Get { key: "a".to_string() },
+            BatchOp::Delete { key: "gone".to_string() },
+            BatchOp::Delete { key: "missing".to_string() },
+            BatchOp::Set { key: "t".to_string(), value: b"2".to_vec(), ttl: Some(Duration::from_secs(3600)) },
+        ];
+        let outcomes = store.apply_batch(Vec::new(), ops).await.unwrap().unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                BatchOutcome::Set,
+                BatchOutcome::Value(Some(b"1".to_vec())),
+                BatchOutcome::Deleted(true),
+                BatchOutcome::Deleted(false),
+                BatchOutcome::Set,
+            ]
+        );
+        
+        let restored = MemoryStore::with_wal(wal);
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+        assert!(restored.ttl("t").await.unwrap() > Duration::from_secs(3500));
+    }
+    
+    #[tokio::test]
+    async fn test_apply_batch_aborts_when_a_watched_key_changed() {
+        let store = MemoryStore::new();
+        store.set("watched".to_string(), b"old".to_vec()).await.unwrap();
+        let set = || vec![BatchOp::Set { key: "out".to_string(), value: b"x".to_vec(), ttl: None }];
+        
+        let seen = store.stat("watched").await.unwrap();
+        store.set("watched".to_string(), b"new".to_vec()).await.unwrap();
+        let stale = vec![("watched".to_string(), seen)];
+        assert_eq!(store.apply_batch(stale, set()).await.unwrap(), None);
+        assert_eq!(store.get("out").await.unwrap(), None);
+        
+        // Setting it back to the value seen still counts as a change
+        let seen = store.stat("watched").await.unwrap();
+        store.set("watched".to_string(), b"other".to_vec()).await.unwrap();
+        store.set("watched".to_string(), b"new".to_vec()).await.unwrap();
+        assert_eq!(store.apply_batch(vec![("watched".to_string(), seen)], set()).await.unwrap(), None);
+        
+        // A watched key that must still be missing
+        store.set("appeared".to_string(), b"v".to_vec()).await.unwrap();
+        assert_eq!(store.apply_batch(vec![("appeared".to_string(), None)], set()).await.unwrap(), None);
+        
+        let current = vec![("watched".to_string(), store.stat("watched").await.unwrap()), ("absent".to_string(), None)];
+        assert_eq!(store.apply_batch(current, set()).await.unwrap(), Some(vec![BatchOutcome::Set]));
+        assert_eq!(store.get("out").await.unwrap(), Some(b"x".to_vec()));
+    }
+    
+    #[tokio::test]
+    async fn test_lru_evicts_least_recently_used_keys() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        // Each key and value is 10 bytes, so ten fit
+        let store = MemoryStore::with_wal(Arc::clone(&wal)).with_memory_limit(100, EvictionPolicy::Lru);
+        for i in 0..10 {
+            store.set(format!("key{}", i), b"value!".to_vec()).await.unwrap();
+        }
+        assert_eq!(store.memory_used(), Some(100));
+        assert_eq!(store.get("key0").await.unwrap(), Some(b"value!".to_vec()));
+        store.mget(&["key1".to_string()]).await.unwrap();
+        
+        store.set("key10".to_string(), b"value!".to_vec()).await.unwrap();
+        store.set("key11".to_string(), b"value!!".to_vec()).await.unwrap();
+        assert!(store.exists("key0").await.unwrap());
+        assert!(store.exists("key1").await.unwrap());
+        for evicted in ["key2", "key3", "key4"] {
+            assert!(!store.exists(evicted).await.unwrap(), "{} should be evicted", evicted);
+        }
+        assert!(store.exists("key5").await.unwrap());
+        assert_eq!(store.evicted_keys(), 3);
+        assert_eq!(store.memory_used(), Some(93));
+        
+        // A restart agrees about which keys are gone
+        let restored = MemoryStore::with_wal(wal).with_memory_limit(100, EvictionPolicy::Lru);
+        restored.restore_from_wal().await.unwrap();
+        assert_eq!(sorted(restored.get_all().await.unwrap()), sorted(store.get_all().await.unwrap()));
+        assert_eq!(restored.memory_used(), Some(93));
+    }
+    
+    #[tokio::test]
+    async fn test_no_eviction_refuses_writes_over_the_limit() {
+        let store = MemoryStore::new().with_memory_limit(20, EvictionPolicy::NoEviction);
+        store.set("key1".to_string(), b"value1".to_vec()).await.unwrap();
+        store.set("key2".to_string(), b"value2".to_vec()).await.unwrap();
+        
+        let err = store.set("key3".to_string(), b"value3".to_vec()).await.unwrap_err();
+        assert!(matches!(err, RustVaultError::OutOfMemory), "{}", err);
+        assert!(matches!(store.incr("count", 1).await, Err(RustVaultError::OutOfMemory)));
+        assert!(!store.exists("key3").await.unwrap());
+        
+        // Overwriting in place and deleting still work
+        store.set("key1".to_string(), b"other1".to_vec()).await.unwrap();
+        assert!(store.delete("key2").await.unwrap());
+        store.set("key3".to_string(), b"value3".to_vec()).await.unwrap();
+        assert_eq!(store.memory_used(), Some(20));
+        assert_eq!(store.evicted_keys(), 0);
+    }
 }
\ No newline at end of file
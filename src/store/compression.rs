@@ -0,0 +1,306 @@
+//! Compression of large values
+//!
+//! A store given a [`CompressionConfig`] keeps every value of at least
+//! `min_size_bytes` compressed, in memory and in its WAL, and hands it back
+//! decompressed. A value that doesn't come out smaller is kept as it was,
+//! so small and incompressible values cost nothing more than the check.
+//!
+//! A compressed value is the length of the original as a little-endian
+//! u32, then the algorithm's output. The WAL tags each entry holding one
+//! with the algorithm, so a log mixing compressed and plain entries, as a
+//! change of configuration leaves, replays whatever the store is set to.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// zstd's default level, which favours speed
+const ZSTD_LEVEL: i32 = 3;
+
+/// `min_size_bytes` the server binary uses when it isn't given one
+pub const DEFAULT_MIN_SIZE_BYTES: usize = 1024;
+
+/// How values are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    /// Fast, with a modest ratio
+    Lz4,
+    /// Slower, and smaller
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// The byte a binary WAL record names the algorithm with
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            CompressionAlgorithm::Lz4 => 1,
+            CompressionAlgorithm::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(CompressionAlgorithm::Lz4),
+            2 => Some(CompressionAlgorithm::Zstd),
+            _ => None,
+        }
+    }
+
+    /// `raw` compressed, or `None` if that doesn't make it smaller
+    fn compress(self, raw: &[u8]) -> Option<Vec<u8>> {
+        let len = u32::try_from(raw.len()).ok()?;
+        let body = match self {
+            CompressionAlgorithm::Lz4 => lz4_flex::block::compress(raw),
+            CompressionAlgorithm::Zstd => zstd::bulk::compress(raw, ZSTD_LEVEL).ok()?,
+        };
+        let mut compressed = Vec::with_capacity(4 + body.len());
+        compressed.extend_from_slice(&len.to_le_bytes());
+        compressed.extend_from_slice(&body);
+        (compressed.len() < raw.len()).then_some(compressed)
+    }
+
+    /// The value `compressed` holds, or why it can't be read back
+    pub(crate) fn decompress(self, compressed: &[u8]) -> Result<Vec<u8>, String> {
+        let (len, body) = split_len(compressed).ok_or("compressed value too short")?;
+        let raw = match self {
+            CompressionAlgorithm::Lz4 => lz4_flex::block::decompress(body, len).map_err(|e| e.to_string())?,
+            CompressionAlgorithm::Zstd => zstd::bulk::decompress(body, len).map_err(|e| e.to_string())?,
+        };
+        if raw.len() != len {
+            return Err(format!("decompressed to {} bytes, not {}", raw.len(), len));
+        }
+        Ok(raw)
+    }
+}
+
+/// The original length a compressed value starts with, and the rest
+fn split_len(compressed: &[u8]) -> Option<(usize, &[u8])> {
+    let (len, body) = compressed.split_first_chunk::<4>()?;
+    Some((u32::from_le_bytes(*len) as usize, body))
+}
+
+/// Which values a store compresses, and how
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub algorithm: CompressionAlgorithm,
+    /// Values shorter than this are never compressed
+    pub min_size_bytes: usize,
+}
+
+/// What a store's compression has done since it was created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionStats {
+    /// Values stored compressed
+    pub values: u64,
+    /// Their bytes before compression
+    pub raw_bytes: u64,
+    /// Their bytes after
+    pub stored_bytes: u64,
+    /// Values long enough to compress that didn't come out smaller, and
+    /// were stored as they were
+    pub incompressible: u64,
+}
+
+impl CompressionStats {
+    /// Bytes before compression per byte after, over the values compressed;
+    /// 1 if none were
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            1.0
+        } else {
+            self.raw_bytes as f64 / self.stored_bytes as f64
+        }
+    }
+}
+
+/// A store's [`CompressionConfig`] and the counts behind its
+/// [`CompressionStats`], shared by every shard
+#[derive(Debug)]
+pub(crate) struct Compressor {
+    config: CompressionConfig,
+    values: AtomicU64,
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+    incompressible: AtomicU64,
+}
+
+impl Compressor {
+    pub(crate) fn new(config: CompressionConfig) -> Self {
+        Self {
+            config,
+            values: AtomicU64::new(0),
+            raw_bytes: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+            incompressible: AtomicU64::new(0),
+        }
+    }
+
+    /// Length from which values are compressed
+    pub(crate) fn min_size(&self) -> usize {
+        self.config.min_size_bytes
+    }
+    
+    pub(crate) fn stats(&self) -> CompressionStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        CompressionStats {
+            values: load(&self.values),
+            raw_bytes: load(&self.raw_bytes),
+            stored_bytes: load(&self.stored_bytes),
+            incompressible: load(&self.incompressible),
+        }
+    }
+}
+
+/// `value` as a store with `compressor`, if it has one, keeps it
+pub(crate) fn store(compressor: Option<&Compressor>, value: Vec<u8>) -> StoredValue {
+    let Some(compressor) = compressor.filter(|compressor| value.len() >= compressor.config.min_size_bytes) else {
+        return StoredValue::Raw(value);
+    };
+    let algorithm = compressor.config.algorithm;
+    match algorithm.compress(&value) {
+        Some(compressed) => {
+            compressor.values.fetch_add(1, Ordering::Relaxed);
+            compressor.raw_bytes.fetch_add(value.len() as u64, Ordering::Relaxed);
+            compressor.stored_bytes.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+            StoredValue::Compressed(algorithm, compressed)
+        }
+        None => {
+            compressor.incompressible.fetch_add(1, Ordering::Relaxed);
+            StoredValue::Raw(value)
+        }
+    }
+}
+
+/// A value as a store holds it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StoredValue {
+    Raw(Vec<u8>),
+    Compressed(CompressionAlgorithm, Vec<u8>),
+}
+
+impl StoredValue {
+    /// A value as the WAL logged it: compressed with `algorithm`, if that
+    /// is given
+    pub(crate) fn logged(algorithm: Option<CompressionAlgorithm>, bytes: Vec<u8>) -> Self {
+        match algorithm {
+            Some(algorithm) => StoredValue::Compressed(algorithm, bytes),
+            None => StoredValue::Raw(bytes),
+        }
+    }
+
+    /// The value as it is to be logged, and the algorithm it is compressed
+    /// with, if any
+    pub(crate) fn into_logged(self) -> (Option<CompressionAlgorithm>, Vec<u8>) {
+        match self {
+            StoredValue::Raw(bytes) => (None, bytes),
+            StoredValue::Compressed(algorithm, bytes) => (Some(algorithm), bytes),
+        }
+    }
+
+    /// The bytes held, compressed or not
+    pub(crate) fn held(&self) -> &[u8] {
+        match self {
+            StoredValue::Raw(bytes) | StoredValue::Compressed(_, bytes) => bytes,
+        }
+    }
+
+    /// The value itself, decompressed if need be
+    pub(crate) fn raw(&self) -> Cow<'_, [u8]> {
+        match self {
+            StoredValue::Raw(bytes) => Cow::Borrowed(bytes),
+            StoredValue::Compressed(algorithm, bytes) => Cow::Owned(
+                algorithm
+                    .decompress(bytes)
+                    .expect("values are only held compressed after compressing them"),
+            ),
+        }
+    }
+
+    pub(crate) fn to_raw(&self) -> Vec<u8> {
+        self.raw().into_owned()
+    }
+
+    pub(crate) fn into_raw(self) -> Vec<u8> {
+        match self {
+            StoredValue::Raw(bytes) => bytes,
+            compressed => compressed.to_raw(),
+        }
+    }
+
+    /// Whether the value is `other`, decompressing it only if the lengths
+    /// match
+    pub(crate) fn is(&self, other: &[u8]) -> bool {
+        match self {
+            StoredValue::Raw(bytes) => bytes == other,
+            StoredValue::Compressed(..) => self.len() == other.len() && *self.raw() == *other,
+        }
+    }
+
+    /// Length of the value itself
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            StoredValue::Raw(bytes) => bytes.len(),
+            StoredValue::Compressed(_, bytes) => split_len(bytes).map_or(0, |(len, _)| len),
+        }
+    }
+
+    /// Bytes allocated for what is held
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            StoredValue::Raw(bytes) | StoredValue::Compressed(_, bytes) => bytes.capacity(),
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        match self {
+            StoredValue::Raw(bytes) | StoredValue::Compressed(_, bytes) => bytes.shrink_to_fit(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_round_trip() {
+        let compressible = b"{\"name\": \"value\"} ".repeat(1000);
+        // An xorshift stream doesn't compress
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let random: Vec<u8> = (0..16 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        for algorithm in [CompressionAlgorithm::Lz4, CompressionAlgorithm::Zstd] {
+            let compressor = Compressor::new(CompressionConfig { algorithm, min_size_bytes: 64 });
+
+            let stored = store(Some(&compressor), compressible.clone());
+            assert_eq!(stored.clone().into_logged().0, Some(algorithm));
+            assert!(stored.held().len() < compressible.len() / 10, "{:?}", algorithm);
+            assert_eq!(stored.len(), compressible.len());
+            assert!(stored.is(&compressible) && !stored.is(b"other"));
+            assert_eq!(StoredValue::logged(Some(algorithm), stored.held().to_vec()).into_raw(), compressible);
+
+            assert_eq!(store(Some(&compressor), random.clone()), StoredValue::Raw(random.clone()));
+            assert_eq!(store(Some(&compressor), b"short".to_vec()), StoredValue::Raw(b"short".to_vec()));
+
+            let stats = compressor.stats();
+            assert_eq!((stats.values, stats.raw_bytes, stats.incompressible), (1, compressible.len() as u64, 1));
+            assert!(stats.ratio() > 10.0, "{:?}", stats);
+
+            // Damage is caught, not handed back
+            let mut damaged = stored.held().to_vec();
+            damaged.truncate(damaged.len() / 2);
+            assert!(algorithm.decompress(&damaged).is_err());
+            assert!(algorithm.decompress(b"ab").is_err());
+        }
+        assert_eq!(store(None, compressible.clone()), StoredValue::Raw(compressible));
+        assert_eq!(CompressionStats::default().ratio(), 1.0);
+    }
+}
@@ -0,0 +1,229 @@
+//! Keeping a store within a memory limit
+//!
+//! Usage is counted as the length of every key and value, which leaves out
+//! the map's own overhead and allocator slack, so it is an estimate of what
+//! the process holds rather than a measure of it. Every shard of a store
+//! shares one count, and one least-recently-used order across them all.
+
+use super::sharded::shard_for;
+use super::{scan_position, Entry};
+use crate::error::{Result, RustVaultError};
+use crate::protocol::Command;
+use crate::wal::WriteAheadLog;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+
+/// What a store does about writes once it reaches its memory limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse writes that would take it past the limit, with `ERROR out of
+    /// memory`; reads and deletes still go through
+    NoEviction,
+    /// Delete the least recently used keys until it is back within the
+    /// limit, logging each as a `DELETE`
+    Lru,
+}
+
+/// A shard's map, as the store holds it
+type SharedMap<S> = Arc<RwLock<HashMap<String, Entry, S>>>;
+
+/// Memory accounting for a store and all of its shards
+pub(super) struct Memory<S> {
+    max_bytes: usize,
+    policy: EvictionPolicy,
+    usage: Mutex<Usage>,
+    /// Every shard's map, in the order keys are assigned to shards
+    maps: Box<[SharedMap<S>]>,
+    wal: Option<Arc<WriteAheadLog>>,
+    evicted: AtomicU64,
+}
+
+#[derive(Default)]
+struct Usage {
+    bytes: usize,
+    /// Ticks once per use of a key
+    clock: u64,
+    /// Size and tick of last use of every key
+    keys: HashMap<String, (usize, u64)>,
+    /// Keys by the tick they were last used at, oldest first
+    order: BTreeMap<u64, String>,
+}
+
+impl Usage {
+    /// Record `key` as holding `size` bytes, or as gone for `None`; either
+    /// way it counts as just used
+    fn set(&mut self, key: &str, size: Option<usize>) {
+        let name = match self.keys.remove_entry(key) {
+            Some((name, (old, tick))) => {
+                self.bytes -= old;
+                self.order.remove(&tick);
+                name
+            }
+            None if size.is_some() => key.to_string(),
+            None => return,
+        };
+        if let Some(size) = size {
+            self.clock += 1;
+            self.bytes += size;
+            self.order.insert(self.clock, name.clone());
+            self.keys.insert(name, (size, self.clock));
+        }
+    }
+    
+    /// Move `key` to the back of the eviction order
+    fn touch(&mut self, key: &str) {
+        let Some((_, tick)) = self.keys.get_mut(key) else { return };
+        let name = self.order.remove(tick).expect("every tracked key is in the order");
+        self.clock += 1;
+        *tick = self.clock;
+        self.order.insert(self.clock, name);
+    }
+}
+
+/// Bytes `key` holding `entry` counts for
+fn entry_size(key: &str, entry: &Entry) -> usize {
+    key.len() + entry.value.held().len()
+}
+
+impl<S: BuildHasher> Memory<S> {
+    pub(super) fn new(
+        max_bytes: usize,
+        policy: EvictionPolicy,
+        maps: Box<[SharedMap<S>]>,
+        wal: Option<Arc<WriteAheadLog>>,
+    ) -> Self {
+        Self {
+            max_bytes,
+            policy,
+            usage: Mutex::new(Usage::default()),
+            maps,
+            wal,
+            evicted: AtomicU64::new(0),
+        }
+    }
+    
+    /// Bytes of keys and values held
+    pub(super) fn used(&self) -> usize {
+        self.usage.lock().unwrap().bytes
+    }
+    
+    /// Keys evicted so far
+    pub(super) fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+    
+    /// Account for `key` now holding `entry`, or for its removal
+    pub(super) fn track(&self, key: &str, entry: Option<&Entry>) {
+        let size = entry.map(|entry| entry_size(key, entry));
+        self.usage.lock().unwrap().set(key, size);
+    }
+    
+    /// Note a read of `key`
+    pub(super) fn touch(&self, key: &str) {
+        if self.policy == EvictionPolicy::Lru {
+            self.usage.lock().unwrap().touch(key);
+        }
+    }
+    
+    /// Recount from scratch after the maps were changed wholesale
+    pub(super) fn reset<M>(&self, maps: &[M])
+    where
+        M: Deref<Target = HashMap<String, Entry, S>>,
+    {
+        let mut usage = Usage::default();
+        for (key, entry) in maps.iter().flat_map(|map| map.iter()) {
+            usage.set(key, Some(entry_size(key, entry)));
+        }
+        *self.usage.lock().unwrap() = usage;
+    }
+    
+    /// Check that storing `writes`, pairs of a key and its new value's
+    /// length, leaves room under [`EvictionPolicy::NoEviction`]
+    pub(super) fn admit<'a>(&self, writes: impl IntoIterator<Item = (&'a str, usize)>) -> Result<()> {
+        if self.policy != EvictionPolicy::NoEviction {
+            return Ok(());
+        }
+        let usage = self.usage.lock().unwrap();
+        let mut bytes = usage.bytes;
+        for (key, len) in writes {
+            let old = usage.keys.get(key).map_or(0, |(size, _)| *size);
+            bytes = (bytes + key.len() + len).saturating_sub(old);
+        }
+        if bytes > self.max_bytes && bytes > usage.bytes {
+            return Err(RustVaultError::OutOfMemory);
+        }
+        Ok(())
+    }
+    
+    /// Evict least recently used keys until usage is back within the
+    /// limit, under [`EvictionPolicy::Lru`]
+    ///
+    /// Must be called without any shard locked. Each shard's victims are
+    /// logged as one batch of `Delete`s and removed under its write lock,
+    /// which compaction and snapshots wait on before reading the shard, so
+    /// eviction needs no `in_flight` guard. A victim used again between
+    /// being picked and its shard being locked is spared.
+    pub(super) async fn evict(&self) -> Result<()> {
+        if self.policy != EvictionPolicy::Lru {
+            return Ok(());
+        }
+        loop {
+            let mut victims = vec![Vec::new(); self.maps.len()];
+            {
+                let usage = self.usage.lock().unwrap();
+                let mut excess = usage.bytes.saturating_sub(self.max_bytes);
+                if excess == 0 {
+                    return Ok(());
+                }
+                for (&tick, key) in &usage.order {
+                    let index = shard_for(scan_position(key), self.maps.len());
+                    victims[index].push((key.clone(), tick));
+                    let (size, _) = usage.keys[key];
+                    if size >= excess {
+                        break;
+                    }
+                    excess -= size;
+                }
+            }
+            
+            for (map, victims) in self.maps.iter().zip(victims) {
+                if victims.is_empty() {
+                    continue;
+                }
+                let mut data = map.write().await;
+                let keys: Vec<String> = {
+                    let usage = self.usage.lock().unwrap();
+                    victims
+                        .into_iter()
+                        .filter(|(key, tick)| usage.keys.get(key).is_some_and(|(_, last)| last == tick))
+                        .map(|(key, _)| key)
+                        .collect()
+                };
+                if keys.is_empty() {
+                    continue;
+                }
+                
+                if let Some(wal) = &self.wal {
+                    let commands = keys.iter().map(|key| Command::Delete { key: key.clone() }).collect();
+                    wal.log_commands(commands).await?;
+                }
+                let mut usage = self.usage.lock().unwrap();
+                for key in &keys {
+                    data.remove(key);
+                    usage.set(key, None);
+                }
+                drop(usage);
+                self.evicted.fetch_add(keys.len() as u64, Ordering::Relaxed);
+                println!(
+                    "Evicted {} least recently used key(s) to stay within {} bytes",
+                    keys.len(),
+                    self.max_bytes
+                );
+            }
+        }
+    }
+}
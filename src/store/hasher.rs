@@ -0,0 +1,149 @@
+//! Map hasher picked at runtime
+//!
+//! [`MemoryStore`](super::MemoryStore) takes its hasher as a type
+//! parameter, which a server reading its config at startup can't choose.
+//! The server's [`ShardedMemoryStore`](super::ShardedMemoryStore) hashes
+//! with a [`ConfiguredHasher`] instead, built from the configured
+//! [`HasherKind`]; each hash costs one extra branch to find its hasher.
+
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
+
+/// Which hasher the store's maps use
+///
+/// SipHash, the default, is randomly seeded and resists HashDoS: a client
+/// that controls key names cannot craft collisions to degrade lookups.
+/// aHash (the `ahash` feature) is seeded but not cryptographically strong,
+/// and FxHash (the `fxhash` feature) is unseeded and trivially collidable.
+/// Both are faster; only pick them when keys come from trusted clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HasherKind {
+    #[default]
+    SipHash,
+    #[cfg(feature = "ahash")]
+    AHash,
+    #[cfg(feature = "fxhash")]
+    FxHash,
+}
+
+impl HasherKind {
+    /// A hasher of this kind, freshly seeded if it takes a seed
+    pub fn build(self) -> ConfiguredHasher {
+        ConfiguredHasher(match self {
+            HasherKind::SipHash => Builder::Sip(RandomState::new()),
+            #[cfg(feature = "ahash")]
+            HasherKind::AHash => Builder::Ahash(ahash::RandomState::new()),
+            #[cfg(feature = "fxhash")]
+            HasherKind::FxHash => Builder::Fx(rustc_hash::FxBuildHasher),
+        })
+    }
+}
+
+/// [`BuildHasher`] of whichever [`HasherKind`] it was built from
+#[derive(Clone)]
+pub struct ConfiguredHasher(Builder);
+
+#[derive(Clone)]
+enum Builder {
+    Sip(RandomState),
+    #[cfg(feature = "ahash")]
+    Ahash(ahash::RandomState),
+    #[cfg(feature = "fxhash")]
+    Fx(rustc_hash::FxBuildHasher),
+}
+
+impl ConfiguredHasher {
+    /// The kind this hasher was built from
+    pub fn kind(&self) -> HasherKind {
+        match self.0 {
+            Builder::Sip(_) => HasherKind::SipHash,
+            #[cfg(feature = "ahash")]
+            Builder::Ahash(_) => HasherKind::AHash,
+            #[cfg(feature = "fxhash")]
+            Builder::Fx(_) => HasherKind::FxHash,
+        }
+    }
+}
+
+impl Default for ConfiguredHasher {
+    fn default() -> Self {
+        HasherKind::default().build()
+    }
+}
+
+impl BuildHasher for ConfiguredHasher {
+    type Hasher = ConfiguredHashState;
+    
+    fn build_hasher(&self) -> ConfiguredHashState {
+        ConfiguredHashState(match &self.0 {
+            Builder::Sip(builder) => State::Sip(builder.build_hasher()),
+            #[cfg(feature = "ahash")]
+            Builder::Ahash(builder) => State::Ahash(builder.build_hasher()),
+            #[cfg(feature = "fxhash")]
+            Builder::Fx(builder) => State::Fx(builder.build_hasher()),
+        })
+    }
+}
+
+/// Hasher state made by a [`ConfiguredHasher`]
+pub struct ConfiguredHashState(State);
+
+enum State {
+    Sip(DefaultHasher),
+    #[cfg(feature = "ahash")]
+    Ahash(ahash::AHasher),
+    #[cfg(feature = "fxhash")]
+    Fx(rustc_hash::FxHasher),
+}
+
+/// Run `$body` with `$hasher` bound to whichever hasher `$state` holds
+macro_rules! dispatch {
+    ($state:expr, $hasher:ident => $body:expr) => {
+        match $state {
+            State::Sip($hasher) => $body,
+            #[cfg(feature = "ahash")]
+            State::Ahash($hasher) => $body,
+            #[cfg(feature = "fxhash")]
+            State::Fx($hasher) => $body,
+        }
+    };
+}
+
+impl Hasher for ConfiguredHashState {
+    fn finish(&self) -> u64 {
+        dispatch!(&self.0, hasher => hasher.finish())
+    }
+    
+    fn write(&mut self, bytes: &[u8]) {
+        dispatch!(&mut self.0, hasher => hasher.write(bytes))
+    }
+    
+    // A `str` key hashes its bytes and then this terminator
+    fn write_u8(&mut self, i: u8) {
+        dispatch!(&mut self.0, hasher => hasher.write_u8(i))
+    }
+    
+    fn write_usize(&mut self, i: usize) {
+        dispatch!(&mut self.0, hasher => hasher.write_usize(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_configured_hasher_seeding() {
+        let configured = HasherKind::SipHash.build();
+        assert_eq!(configured.kind(), HasherKind::SipHash);
+        assert_eq!(configured.hash_one("key"), configured.clone().hash_one("key"));
+        assert_ne!(configured.hash_one("key"), HasherKind::SipHash.build().hash_one("key"));
+        
+        #[cfg(feature = "fxhash")]
+        {
+            let configured = HasherKind::FxHash.build();
+            assert_eq!(configured.kind(), HasherKind::FxHash);
+            assert_eq!(configured.hash_one("key"), rustc_hash::FxBuildHasher.hash_one("key"));
+        }
+    }
+}
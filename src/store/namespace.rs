@@ -0,0 +1,155 @@
+//! Separate keyspaces within one store
+//!
+//! A key in the default namespace, [`DEFAULT`], is stored as it is, so data
+//! written before namespaces existed stays where it was. A key in any other
+//! namespace is stored as ` <namespace> <key>`. Keys sent by clients can't
+//! contain a space, so the two never collide, and every key outside the
+//! default namespace starts with [`MARKER`]. The WAL and snapshots record
+//! the stored form, so replay puts each key back in its namespace.
+
+use crate::protocol::{glob_match, Command};
+
+/// Namespace a connection starts in
+pub const DEFAULT: &str = "0";
+
+/// What every key stored outside the default namespace starts with
+pub const MARKER: &str = " ";
+
+/// Longest namespace name, in bytes
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Whether `name` can be selected: 1 to [`MAX_NAME_LEN`] ASCII letters,
+/// digits, `_` or `-`
+pub fn is_valid(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// What every key stored in `namespace` starts with; empty for the default
+pub fn prefix(namespace: &str) -> String {
+    if namespace == DEFAULT {
+        String::new()
+    } else {
+        format!("{}{}{}", MARKER, namespace, MARKER)
+    }
+}
+
+/// How `key` in `namespace` is stored
+pub fn qualify(namespace: &str, key: &str) -> String {
+    if namespace == DEFAULT {
+        key.to_string()
+    } else {
+        format!("{}{}", prefix(namespace), key)
+    }
+}
+
+/// The namespace and key a stored key stands for
+pub fn split(stored: &str) -> (&str, &str) {
+    stored
+        .strip_prefix(MARKER)
+        .and_then(|rest| rest.split_once(MARKER))
+        .unwrap_or((DEFAULT, stored))
+}
+
+/// Whether the stored key `stored` is in `namespace`
+pub fn contains(namespace: &str, stored: &str) -> bool {
+    split(stored).0 == namespace
+}
+
+/// Whether the stored key `stored` matches the glob `pattern`, in its
+/// stored form: it must be in the pattern's namespace, and match the rest
+/// of the pattern there
+pub fn matches(pattern: &str, stored: &str) -> bool {
+    let (namespace, pattern) = split(pattern);
+    let (key_namespace, key) = split(stored);
+    key_namespace == namespace && glob_match(pattern.as_bytes(), key.as_bytes())
+}
+
+/// `command` as sent by a connection in `namespace`, with its keys and
+/// prefixes in their stored form
+///
+/// `FLUSHDB` and `DBSIZE` without a namespace are given this one. Commands
+/// that name no key are returned as they are.
+pub fn qualify_command(namespace: &str, command: Command) -> Command {
+    let own = || Some(namespace.to_string());
+    let command = match command {
+        Command::FlushDb { namespace: None } => return Command::FlushDb { namespace: own() },
+        Command::DbSize { namespace: None } => return Command::DbSize { namespace: own() },
+        command if namespace == DEFAULT => return command,
+        command => command,
+    };
+    let q = |key: String| qualify(namespace, &key);
+    match command {
+        Command::Set { key, value, condition } => Command::Set { key: q(key), value, condition },
+        Command::SetEx { key, value, seconds, condition } => Command::SetEx { key: q(key), value, seconds, condition },
+        Command::Get { key } => Command::Get { key: q(key) },
+        Command::Exists { key } => Command::Exists { key: q(key) },
+        Command::Stat { key } => Command::Stat { key: q(key) },
+        Command::Delete { key } => Command::Delete { key: q(key) },
+        Command::DeletePattern { pattern } => Command::DeletePattern { pattern: q(pattern) },
+        Command::Expire { key, seconds } => Command::Expire { key: q(key), seconds },
+        Command::ExpireAt { key, unix_millis } => Command::ExpireAt { key: q(key), unix_millis },
+        Command::Incr { key, delta } => Command::Incr { key: q(key), delta },
+        Command::Decr { key, delta } => Command::Decr { key: q(key), delta },
+        Command::Append { key, value } => Command::Append { key: q(key), value },
+        Command::Strlen { key } => Command::Strlen { key: q(key) },
+        Command::GetSet { key, value } => Command::GetSet { key: q(key), value },
+        Command::GetDel { key } => Command::GetDel { key: q(key) },
+        Command::Lock { key, token, ttl_ms } => Command::Lock { key: q(key), token, ttl_ms },
+        Command::Unlock { key, token } => Command::Unlock { key: q(key), token },
+        Command::Cas { key, expected, new } => Command::Cas { key: q(key), expected, new },
+        Command::MSet { pairs } => Command::MSet {
+            pairs: pairs.into_iter().map(|(key, value)| (q(key), value)).collect(),
+        },
+        Command::MGet { keys } => Command::MGet { keys: keys.into_iter().map(q).collect() },
+        Command::Watch { keys } => Command::Watch { keys: keys.into_iter().map(q).collect() },
+        Command::Scan { prefix, cursor, count } => Command::Scan { prefix: q(prefix), cursor, count },
+        Command::Checksum { prefix } => Command::Checksum { prefix: q(prefix) },
+        Command::ChecksumRanges { buckets, prefix } => Command::ChecksumRanges { buckets, prefix: q(prefix) },
+        command => command,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_keys_round_trip_through_their_stored_form() {
+        assert_eq!(qualify(DEFAULT, "user:1"), "user:1");
+        assert_eq!(qualify("app", "user:1"), " app user:1");
+        assert_eq!(split(" app user:1"), ("app", "user:1"));
+        assert_eq!(split("user:1"), (DEFAULT, "user:1"));
+        assert!(contains("app", " app user:1"));
+        assert!(!contains(DEFAULT, " app user:1"));
+        assert!(contains(DEFAULT, "user:1"));
+        assert!(matches("user:*", "user:1"));
+        assert!(!matches("*", " app user:1"));
+        assert!(matches(" app user:?", " app user:1"));
+        assert!(!matches(" app *", "user:1"));
+        
+        assert!(is_valid("my-app_2"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("a b"));
+        assert!(!is_valid(&"a".repeat(MAX_NAME_LEN + 1)));
+    }
+    
+    #[test]
+    fn test_commands_are_qualified_for_their_namespace() {
+        let get = Command::Get { key: "k".to_string() };
+        assert_eq!(qualify_command(DEFAULT, get.clone()), get);
+        assert_eq!(qualify_command("app", get), Command::Get { key: " app k".to_string() });
+        assert_eq!(
+            qualify_command("app", Command::Scan { prefix: String::new(), cursor: 0, count: 10 }),
+            Command::Scan { prefix: " app ".to_string(), cursor: 0, count: 10 }
+        );
+        assert_eq!(
+            qualify_command(DEFAULT, Command::FlushDb { namespace: None }),
+            Command::FlushDb { namespace: Some(DEFAULT.to_string()) }
+        );
+        assert_eq!(
+            qualify_command("app", Command::DbSize { namespace: Some("other".to_string()) }),
+            Command::DbSize { namespace: Some("other".to_string()) }
+        );
+    }
+}
\ No newline at end of file
@@ -0,0 +1,808 @@
+//! Memory store split across independently locked shards
+//!
+//! Each shard is a [`MemoryStore`] with its own map and lock, so writes to
+//! different shards don't wait on each other. The shards share one WAL,
+//! logged to exactly as a single store logs it.
+
+use super::compression::{CompressionConfig, CompressionStats, Compressor};
+use super::{
+    batch_keys, batch_writes, namespace, plan_batch, remove_matching, restore_into, scan_position, set_entry, wal_checkpoint,
+    write_snapshot, BatchOp, BatchOutcome, CompactionReport, Entry, EntryPage, EvictionPolicy, KeyStat, Memory,
+    MemoryStore, MemoryUsage, ScanPage, ShrinkReport, Store,
+};
+use crate::error::Result;
+use crate::protocol::{Command, SetCondition};
+use crate::snapshot::SnapshotEntry;
+use super::hasher::ConfiguredHasher;
+use crate::wal::{now_millis, ReplayProgress, WriteAheadLog};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::mem;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Shards used when none are asked for: four per CPU
+pub fn default_shards() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get) * 4
+}
+
+/// Thread-safe in-memory key-value store of several locked shards
+///
+/// A key's shard is picked from its [`Store::scan`] position, so each shard
+/// holds one contiguous range of positions and a scan walks the shards in
+/// order, with the same cursors as a [`MemoryStore`]. That hash is
+/// unseeded: a client choosing key names can crowd them into one shard,
+/// which costs concurrency but not lookup speed, since each shard's map
+/// still hashes with `S`. By default that's a [`ConfiguredHasher`], so a
+/// server can pick it from its config; SipHash unless told otherwise.
+///
+/// Single-key operations behave exactly as on a `MemoryStore`. `MSET`,
+/// `MGET` and transactions lock every shard they touch at once, so they
+/// stay atomic.
+/// Operations on the whole store (`get_all`, `len`, `clear`, checksums)
+/// visit the shards one at a time and aren't a snapshot of a store being
+/// written.
+pub struct ShardedMemoryStore<S = ConfiguredHasher> {
+    shards: Box<[MemoryStore<S>]>,
+    wal: Option<Arc<WriteAheadLog>>,
+    /// Shared with every shard, so compaction can quiet them all at once
+    in_flight: Arc<RwLock<()>>,
+}
+
+impl ShardedMemoryStore {
+    /// Create a store of [`default_shards`] shards without WAL
+    pub fn new() -> Self {
+        Self::with_shards(0)
+    }
+    
+    /// Create a store of `shards` shards without WAL; 0 picks
+    /// [`default_shards`]
+    pub fn with_shards(shards: usize) -> Self {
+        Self::build(None, shards, ConfiguredHasher::default())
+    }
+    
+    /// Create a store of `shards` shards with WAL for persistence; 0 picks
+    /// [`default_shards`]
+    pub fn with_wal(wal: Arc<WriteAheadLog>, shards: usize) -> Self {
+        Self::build(Some(wal), shards, ConfiguredHasher::default())
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync + 'static> ShardedMemoryStore<S> {
+    /// Create a store of `shards` shards without WAL using the given hasher
+    pub fn with_hasher(shards: usize, hasher: S) -> Self {
+        Self::build(None, shards, hasher)
+    }
+    
+    /// Create a store of `shards` shards with WAL using the given hasher
+    pub fn with_wal_and_hasher(wal: Arc<WriteAheadLog>, shards: usize, hasher: S) -> Self {
+        Self::build(Some(wal), shards, hasher)
+    }
+    
+    fn build(wal: Option<Arc<WriteAheadLog>>, shards: usize, hasher: S) -> Self {
+        let shards = if shards == 0 { default_shards() } else { shards };
+        let pending_free = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new(RwLock::new(()));
+        let shards = (0..shards)
+            .map(|_| MemoryStore {
+                data: Arc::new(RwLock::new(HashMap::with_hasher(hasher.clone()))),
+                wal: wal.clone(),
+                pending_free: Arc::clone(&pending_free),
+                in_flight: Arc::clone(&in_flight),
+                memory: None,
+                compressor: None,
+            })
+            .collect();
+        Self { shards, wal, in_flight }
+    }
+    
+    /// Keep the keys and values of all shards together within `max_bytes`,
+    /// as `policy` says; see [`MemoryStore::with_memory_limit`]
+    ///
+    /// Eviction picks the least recently used keys of the whole store,
+    /// whichever shards they are in.
+    pub fn with_memory_limit(mut self, max_bytes: usize, policy: EvictionPolicy) -> Self {
+        let maps = self.shards.iter().map(|shard| Arc::clone(&shard.data)).collect();
+        let memory = Arc::new(Memory::new(max_bytes, policy, maps, self.wal.clone()));
+        for shard in self.shards.iter_mut() {
+            shard.memory = Some(Arc::clone(&memory));
+        }
+        self
+    }
+    
+    /// Keep large values compressed, with one count across all shards; see
+    /// [`MemoryStore::with_compression`]
+    pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+        let compressor = Arc::new(Compressor::new(config));
+        for shard in self.shards.iter_mut() {
+            shard.compressor = Some(Arc::clone(&compressor));
+        }
+        self
+    }
+    
+    /// Number of shards
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+    
+    /// Size of the WAL in bytes; 0 without one
+    pub fn wal_size(&self) -> u64 {
+        self.wal.as_ref().map_or(0, |wal| wal.size())
+    }
+    
+    /// Bytes of keys and values held, counted only with a memory limit;
+    /// see [`MemoryStore::memory_used`]
+    pub fn memory_used(&self) -> Option<usize> {
+        self.shards[0].memory_used()
+    }
+    
+    /// Keys evicted to stay within the memory limit
+    pub fn evicted_keys(&self) -> u64 {
+        self.shards[0].evicted_keys()
+    }
+    
+    /// Index of the shard holding `key`
+    fn index(&self, key: &str) -> usize {
+        self.shard_at(scan_position(key))
+    }
+    
+    /// Index of the shard whose range holds scan position `position`
+    fn shard_at(&self, position: u64) -> usize {
+        shard_for(position, self.shards.len())
+    }
+    
+    /// Lowest scan position in shard `index`'s range
+    fn first_position(&self, index: usize) -> u64 {
+        ((index as u128) << 64).div_ceil(self.shards.len() as u128) as u64
+    }
+    
+    fn shard(&self, key: &str) -> &MemoryStore<S> {
+        &self.shards[self.index(key)]
+    }
+}
+
+/// Index, among `shards` shards, of the one whose range holds scan position
+/// `position`
+pub(super) fn shard_for(position: u64, shards: usize) -> usize {
+    ((position as u128 * shards as u128) >> 64) as usize
+}
+
+/// Distinct shard indices in `indices`, in the ascending order multi-shard
+/// operations must lock them in
+fn lock_order(indices: &[usize]) -> Vec<usize> {
+    let mut order = indices.to_vec();
+    order.sort_unstable();
+    order.dedup();
+    order
+}
+
+/// Position of shard `index` in a `lock_order` list that contains it
+fn slot(order: &[usize], index: usize) -> usize {
+    order.partition_point(|&locked| locked < index)
+}
+
+impl<S: BuildHasher + Clone + Default + Send + Sync + 'static> Default for ShardedMemoryStore<S> {
+    fn default() -> Self {
+        Self::with_hasher(0, S::default())
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync + 'static> Store for ShardedMemoryStore<S> {
+    async fn set(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.shard(&key).set(key, value).await
+    }
+    
+    async fn set_with_ttl(&self, key: String, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.shard(&key).set_with_ttl(key, value, ttl).await
+    }
+    
+    async fn set_if(&self, key: String, value: Vec<u8>, ttl: Option<Duration>, condition: SetCondition) -> Result<bool> {
+        self.shard(&key).set_if(key, value, ttl, condition).await
+    }
+    
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.shard(key).get(key).await
+    }
+    
+    async fn get_with_deadline(&self, key: &str) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        self.shard(key).get_with_deadline(key).await
+    }
+    
+    async fn stat(&self, key: &str) -> Result<Option<KeyStat>> {
+        self.shard(key).stat(key).await
+    }
+    
+    /// The shards the pairs fall in are write-locked together, in shard
+    /// order, and held while the batch is logged, as a single store holds
+    /// its one lock.
+    async fn mset(&self, pairs: Vec<(String, Vec<u8>)>) -> Result<()> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let memory = &self.shards[0];
+        let pairs: Vec<_> = pairs.into_iter().map(|(key, value)| (key, memory.store_value(value))).collect();
+        memory.admit(pairs.iter().map(|(key, value)| (key.as_str(), value.held().len())))?;
+        let _in_flight = self.in_flight.read().await;
+        let indices: Vec<usize> = pairs.iter().map(|(key, _)| self.index(key)).collect();
+        let order = lock_order(&indices);
+        let mut maps = Vec::with_capacity(order.len());
+        for &index in &order {
+            maps.push(self.shards[index].data.write().await);
+        }
+        
+        if let Some(wal) = &self.wal {
+            let entries: Vec<_> = pairs.iter().map(|(key, value)| set_entry(key, value.clone())).collect();
+            wal.write_entries(&entries).await?;
+        }
+        let now = now_millis();
+        for ((key, value), index) in pairs.into_iter().zip(indices) {
+            let map = &mut maps[slot(&order, index)];
+            let entry = Entry::written(map.get(&key), value, None, now);
+            memory.track(&key, Some(&entry));
+            map.insert(key, entry);
+        }
+        drop(maps);
+        memory.evict().await
+    }
+    
+    /// The shards the keys fall in are read-locked together, so the values
+    /// are a consistent snapshot.
+    async fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>> {
+        let indices: Vec<usize> = keys.iter().map(|key| self.index(key)).collect();
+        let order = lock_order(&indices);
+        let mut maps = Vec::with_capacity(order.len());
+        for &index in &order {
+            maps.push(self.shards[index].data.read().await);
+        }
+        
+        let now = now_millis();
+        Ok(keys
+            .iter()
+            .zip(indices)
+            .map(|(key, index)| {
+                let value = maps[slot(&order, index)]
+                    .get(key)
+                    .filter(|entry| !entry.is_expired(now))
+                    .map(|entry| entry.value.to_raw());
+                if value.is_some() {
+                    self.shards[index].touch(key);
+                }
+                value
+            })
+            .collect())
+    }
+    
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.shard(key).delete(key).await
+    }
+    
+    async fn cas(&self, key: String, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        self.shard(&key).cas(key, expected, new).await
+    }
+    
+    async fn incr(&self, key: &str, delta: i64) -> Result<i64> {
+        self.shard(key).incr(key, delta).await
+    }
+    
+    async fn append(&self, key: &str, value: &[u8]) -> Result<usize> {
+        self.shard(key).append(key, value).await
+    }
+    
+    async fn strlen(&self, key: &str) -> Result<Option<usize>> {
+        self.shard(key).strlen(key).await
+    }
+    
+    async fn getset(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.shard(&key).getset(key, value).await
+    }
+    
+    async fn getdel(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.shard(key).getdel(key).await
+    }
+    
+    async fn lock(&self, key: String, token: &str, ttl: Duration) -> Result<bool> {
+        self.shard(&key).lock(key, token, ttl).await
+    }
+    
+    async fn unlock(&self, key: &str, token: &str) -> Result<bool> {
+        self.shard(key).unlock(key, token).await
+    }
+    
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        self.shard(key).expire(key, ttl).await
+    }
+    
+    async fn expire_at(&self, key: &str, unix_millis: u64) -> Result<bool> {
+        self.shard(key).expire_at(key, unix_millis).await
+    }
+    
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.shard(key).exists(key).await
+    }
+    
+    async fn get_all(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut all = Vec::new();
+        for shard in self.shards.iter() {
+            all.extend(shard.get_all().await?);
+        }
+        Ok(all)
+    }
+    
+    /// Each shard covers the next range of positions, so a page is taken
+    /// from the shard the cursor falls in and, if that runs out, the ones
+    /// after it. Pages come out as a `MemoryStore` with the same keys would
+    /// give them.
+    async fn scan(&self, prefix: &str, cursor: u64, count: usize) -> Result<ScanPage> {
+        let count = count.max(1);
+        let mut keys = Vec::new();
+        let mut position = cursor;
+        for index in self.shard_at(cursor)..self.shards.len() {
+            position = position.max(self.first_position(index));
+            let page = self.shards[index].scan(prefix, position, count - keys.len()).await?;
+            keys.extend(page.keys);
+            if page.cursor != 0 {
+                return Ok(ScanPage { keys, cursor: page.cursor });
+            }
+            if keys.len() >= count {
+                // Resume at the start of the next shard's range
+                let cursor = match index + 1 {
+                    next if next < self.shards.len() => self.first_position(next),
+                    _ => 0,
+                };
+                return Ok(ScanPage { keys, cursor });
+            }
+        }
+        Ok(ScanPage { keys, cursor: 0 })
+    }
+    
+    /// Takes a page from every shard in turn and keeps the first `limit`
+    /// of them.
+    async fn scan_page(&self, cursor: Option<&str>, limit: usize) -> Result<EntryPage> {
+        let mut found = Vec::new();
+        let mut more = false;
+        for shard in self.shards.iter() {
+            let page = shard.scan_page(cursor, limit).await?;
+            more |= page.next.is_some();
+            found.extend(page.entries);
+        }
+        let mut page = EntryPage::first(found, limit);
+        if more && page.next.is_none() {
+            page.next = page.entries.last().map(|(key, _)| key.clone());
+        }
+        Ok(page)
+    }
+    
+    /// Every shard is locked at once and one `FlushAll` is logged, so no
+    /// write lands in one shard between it being cleared and the next.
+    async fn clear(&self) -> Result<()> {
+        let _in_flight = self.in_flight.read().await;
+        let mut maps = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            maps.push(shard.data.write().await);
+        }
+        
+        if let Some(wal) = &self.wal {
+            wal.log_command(Command::FlushAll).await?;
+        }
+        let old: Vec<_> = maps
+            .iter_mut()
+            .map(|data| {
+                let empty = HashMap::with_hasher(data.hasher().clone());
+                mem::replace(&mut **data, empty)
+            })
+            .collect();
+        self.shards[0].recount(&maps);
+        drop(maps);
+        for (shard, old) in self.shards.iter().zip(old) {
+            shard.free_lazily(old.len(), old);
+        }
+        Ok(())
+    }
+    
+    /// Every shard is locked at once, as `clear` locks them, around one
+    /// logged `FlushDb`. The removed entries are freed after the locks are
+    /// released, as `clear` frees the old maps.
+    async fn clear_namespace(&self, namespace: &str) -> Result<()> {
+        let _in_flight = self.in_flight.read().await;
+        let mut maps = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            maps.push(shard.data.write().await);
+        }
+        
+        if let Some(wal) = &self.wal {
+            let namespace = Some(namespace.to_string());
+            wal.log_command(Command::FlushDb { namespace }).await?;
+        }
+        let removed: Vec<Vec<_>> = maps
+            .iter_mut()
+            .map(|data| data.extract_if(|key, _| namespace::contains(namespace, key)).collect())
+            .collect();
+        self.shards[0].recount(&maps);
+        drop(maps);
+        for (shard, removed) in self.shards.iter().zip(removed) {
+            shard.free_lazily(removed.len(), removed);
+        }
+        Ok(())
+    }
+    
+    /// Every shard is locked at once, as `clear` locks them, around one
+    /// logged `DeletePattern`, so readers see every matching key or none of
+    /// them.
+    async fn delete_pattern(&self, pattern: &str) -> Result<Vec<String>> {
+        let _in_flight = self.in_flight.read().await;
+        let mut maps = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            maps.push(shard.data.write().await);
+        }
+        
+        let removed = remove_matching(&mut maps, pattern, self.wal.as_deref()).await?;
+        self.shards[0].recount(&maps);
+        Ok(removed)
+    }
+    
+    /// The shards of every key involved, watched or written, are
+    /// write-locked together in shard order, as `mset` locks them.
+    async fn apply_batch(
+        &self,
+        watched: Vec<(String, Option<KeyStat>)>,
+        ops: Vec<BatchOp>,
+    ) -> Result<Option<Vec<BatchOutcome>>> {
+        let _in_flight = self.in_flight.read().await;
+        let indices: Vec<usize> = watched
+            .iter()
+            .map(|(key, _)| self.index(key))
+            .chain(ops.iter().map(|op| self.index(op.key())))
+            .collect();
+        let order = lock_order(&indices);
+        let mut maps = Vec::with_capacity(order.len());
+        for &index in &order {
+            maps.push(self.shards[index].data.write().await);
+        }
+        let index = |key: &str| slot(&order, self.index(key));
+        if !MemoryStore::<S>::watched_unchanged(&watched, &maps, index) {
+            return Ok(None);
+        }
+        let memory = &self.shards[0];
+        memory.admit(batch_writes(&ops))?;
+        let keys = memory.memory.is_some().then(|| batch_keys(&ops));
+        
+        let (steps, entries) = plan_batch(ops, memory.compressor.as_deref());
+        if let Some(wal) = &self.wal {
+            if !entries.is_empty() {
+                wal.write_entries(&entries).await?;
+            }
+        }
+        let outcomes = MemoryStore::<S>::apply_steps(steps, &mut maps, index);
+        for key in keys.iter().flatten() {
+            memory.track(key, maps[index(key)].get(key));
+        }
+        drop(maps);
+        memory.evict().await?;
+        Ok(Some(outcomes))
+    }
+    
+    async fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += shard.len().await?;
+        }
+        Ok(len)
+    }
+    
+    async fn namespace_len(&self, namespace: &str) -> Result<usize> {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += shard.namespace_len(namespace).await?;
+        }
+        Ok(len)
+    }
+    
+    async fn checksum(&self, prefix: &str) -> Result<u64> {
+        let mut sum = 0u64;
+        for shard in self.shards.iter() {
+            sum = sum.wrapping_add(shard.checksum(prefix).await?);
+        }
+        Ok(sum)
+    }
+    
+    async fn checksum_ranges(&self, buckets: usize, prefix: &str) -> Result<Vec<u64>> {
+        let mut sums = vec![0u64; buckets.clamp(1, 256)];
+        for shard in self.shards.iter() {
+            let digests = shard.checksum_ranges(buckets, prefix).await?;
+            for (sum, digest) in sums.iter_mut().zip(digests) {
+                *sum = sum.wrapping_add(digest);
+            }
+        }
+        Ok(sums)
+    }
+    
+    /// Shards are swept one at a time, so only one is locked at once.
+    async fn sweep_expired(&self) -> usize {
+        let mut swept = 0;
+        for shard in self.shards.iter() {
+            swept += shard.sweep_expired().await;
+        }
+        swept
+    }
+    
+    /// The shards share one count.
+    fn compression_stats(&self) -> Option<CompressionStats> {
+        self.shards[0].compression_stats()
+    }
+    
+    /// The shards share one count.
+    fn pending_free(&self) -> usize {
+        self.shards[0].pending_free()
+    }
+    
+    /// Shards are rebuilt one at a time, so only one is locked at once.
+    async fn shrink(&self) -> ShrinkReport {
+        let mut total = ShrinkReport { before: 0, after: 0 };
+        for shard in self.shards.iter() {
+            let report = shard.shrink().await;
+            total.before += report.before;
+            total.after += report.after;
+        }
+        total
+    }
+    
+    /// Shards are walked one at a time, so only one is locked at once.
+    async fn memory_usage(&self) -> MemoryUsage {
+        let mut total = MemoryUsage::default();
+        for shard in self.shards.iter() {
+            let usage = shard.memory_usage().await;
+            total.allocated += usage.allocated;
+            total.live += usage.live;
+        }
+        total
+    }
+    
+    /// The shards are copied in turn, a page at a time, and writes to
+    /// every shard are quieted together while each page is copied, so no
+    /// write is both in a page and carried over after it.
+    async fn compact_wal(&self) -> Result<CompactionReport> {
+        let Some(wal) = &self.wal else {
+            return Ok(CompactionReport { before: 0, after: 0, keys: 0 });
+        };
+        let before = wal.size();
+        wal.begin_compaction()?;
+        let mut keys = 0;
+        for shard in self.shards.iter() {
+            keys += shard.add_compaction_pages(wal).await?;
+        }
+        wal.finish_compaction().await?;
+        Ok(CompactionReport { before, after: wal.size(), keys })
+    }
+    
+    /// Writes are quieted while every shard is copied in turn, so the copy
+    /// is of one moment across them all.
+    async fn live_entries(&self) -> Result<Vec<SnapshotEntry>> {
+        let _quiet = self.in_flight.write().await;
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            entries.extend(shard.snapshot_entries().await);
+        }
+        Ok(entries)
+    }
+    
+    /// All shards are quieted together for the checkpoint and copied in
+    /// turn before writes carry on.
+    async fn snapshot_to(&self, path: &Path) -> Result<()> {
+        let mut entries = Vec::new();
+        let checkpoint = {
+            let _quiet = self.in_flight.write().await;
+            for shard in self.shards.iter() {
+                entries.extend(shard.snapshot_entries().await);
+            }
+            wal_checkpoint(self.wal.as_deref()).await?
+        };
+        write_snapshot(path, checkpoint, entries).await
+    }
+    
+    /// Every shard is write-locked for the whole restore, and each entry is
+    /// applied to the shard its key belongs to.
+    fn restore_blocking<P>(&self, snapshot: Option<&Path>, progress: P) -> Result<()>
+    where
+        P: FnMut(ReplayProgress),
+    {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut maps: Vec<_> = self.shards.iter().map(|shard| shard.data.blocking_write()).collect();
+        let compressor = self.shards[0].compressor.as_deref();
+        restore_into(wal, snapshot, &mut maps, |key| self.index(key), compressor, progress)?;
+        self.shards[0].recount(&maps);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::SyncPolicy;
+    use tempfile::NamedTempFile;
+    
+    #[tokio::test]
+    async fn test_operations_span_shards() {
+        let store = ShardedMemoryStore::with_shards(7);
+        assert_eq!(store.shards(), 7);
+        
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        assert_eq!(store.len().await.unwrap(), 200);
+        assert_eq!(store.get_all().await.unwrap().len(), 200);
+        assert_eq!(store.get("key42").await.unwrap(), Some(b"value42".to_vec()));
+        assert!(store.delete("key42").await.unwrap());
+        assert!(!store.exists("key42").await.unwrap());
+        assert_eq!(store.incr("key1000", 5).await.unwrap(), 5);
+        assert!(store.cas("key7".to_string(), b"value7", b"new".to_vec()).await.unwrap());
+        
+        // Every shard got some of the keys
+        for shard in store.shards.iter() {
+            assert!(shard.len().await.unwrap() > 0);
+        }
+        
+        store.mset(vec![
+            ("a".to_string(), b"1".to_vec()),
+            ("b".to_string(), b"2".to_vec()),
+            ("a".to_string(), b"3".to_vec()),
+        ]).await.unwrap();
+        let keys = ["a", "missing", "b"].map(String::from);
+        assert_eq!(
+            store.mget(&keys).await.unwrap(),
+            vec![Some(b"3".to_vec()), None, Some(b"2".to_vec())]
+        );
+        
+        store.clear().await.unwrap();
+        assert!(store.is_empty().await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_scan_and_checksums_match_memory_store() {
+        let sharded = ShardedMemoryStore::with_shards(5);
+        let single = MemoryStore::new();
+        for i in 0..300 {
+            let (key, value) = (format!("key{}", i), format!("value{}", i).into_bytes());
+            sharded.set(key.clone(), value.clone()).await.unwrap();
+            single.set(key, value).await.unwrap();
+        }
+        
+        let (mut cursor, mut pages) = (0, 0);
+        loop {
+            let ours = sharded.scan("key1", cursor, 7).await.unwrap();
+            let theirs = single.scan("key1", cursor, 7).await.unwrap();
+            assert_eq!(ours, theirs);
+            pages += 1;
+            cursor = ours.cursor;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!(pages > 1);
+        
+        let mut cursor = None;
+        loop {
+            let ours = sharded.scan_page(cursor.as_deref(), 40).await.unwrap();
+            assert_eq!(ours, single.scan_page(cursor.as_deref(), 40).await.unwrap());
+            cursor = ours.next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        
+        assert_eq!(sharded.checksum("").await.unwrap(), single.checksum("").await.unwrap());
+        assert_eq!(
+            sharded.checksum_ranges(16, "key").await.unwrap(),
+            single.checksum_ranges(16, "key").await.unwrap()
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_replays_and_compacts_into_shards() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = ShardedMemoryStore::with_wal(Arc::clone(&wal), 4);
+        for i in 0..100 {
+            store.set(format!("key{}", i % 10), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        store.set_with_ttl("ttl".to_string(), b"v".to_vec(), Duration::from_secs(3600)).await.unwrap();
+        store.delete("key3").await.unwrap();
+        
+        let before = wal.size();
+        let report = store.compact_wal().await.unwrap();
+        assert_eq!(report.before, before);
+        assert!(report.after < before);
+        assert_eq!(report.keys, 10);
+        
+        // A store with a different number of shards replays the same log
+        let reopened = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let restored = Arc::new(ShardedMemoryStore::with_wal(reopened, 3));
+        let replaying = Arc::clone(&restored);
+        tokio::task::spawn_blocking(move || replaying.restore_blocking(None, |_| {}))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.len().await.unwrap(), 10);
+        assert_eq!(restored.get("key3").await.unwrap(), None);
+        assert_eq!(restored.get("key5").await.unwrap(), Some(b"value95".to_vec()));
+        assert!(restored.shard("ttl").ttl("ttl").await.is_some());
+        assert_eq!(restored.checksum("").await.unwrap(), store.checksum("").await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_clear_empties_every_shard_once() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap());
+        let store = ShardedMemoryStore::with_wal(Arc::clone(&wal), 4);
+        for i in 0..20 {
+            store.set(format!("key{}", i), b"v".to_vec()).await.unwrap();
+        }
+        store.clear().await.unwrap();
+        store.set("after".to_string(), b"v".to_vec()).await.unwrap();
+        assert_eq!(store.len().await.unwrap(), 1);
+        
+        let mut flushes = 0;
+        wal.replay(|entry| {
+            flushes += usize::from(entry.command == Command::FlushAll);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(flushes, 1);
+        
+        let restored = Arc::new(ShardedMemoryStore::with_wal(wal, 3));
+        let replaying = Arc::clone(&restored);
+        tokio::task::spawn_blocking(move || replaying.restore_blocking(None, |_| {}))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.get_all().await.unwrap(), vec![("after".to_string(), b"v".to_vec())]);
+    }
+    
+    #[tokio::test]
+    async fn test_apply_batch_spans_shards() {
+        let store = ShardedMemoryStore::with_shards(4);
+        let ops: Vec<BatchOp> = (0..20)
+            .map(|i| BatchOp::Set { key: format!("key{}", i), value: b"v".to_vec(), ttl: None })
+            .chain((0..20).map(|i| BatchOp::Get { key: format!("key{}", i) }))
+            .collect();
+        let outcomes = store.apply_batch(Vec::new(), ops).await.unwrap().unwrap();
+        assert_eq!(outcomes.len(), 40);
+        assert!(outcomes[20..].iter().all(|outcome| *outcome == BatchOutcome::Value(Some(b"v".to_vec()))));
+        assert_eq!(store.len().await.unwrap(), 20);
+        
+        let stale = vec![("key3".to_string(), None)];
+        let delete = vec![BatchOp::Delete { key: "key4".to_string() }];
+        assert_eq!(store.apply_batch(stale, delete).await.unwrap(), None);
+        assert_eq!(store.len().await.unwrap(), 20);
+    }
+    
+    #[tokio::test]
+    async fn test_lru_eviction_spans_shards() {
+        // Each key and value is 11 bytes, so twenty fit
+        let store = ShardedMemoryStore::with_shards(4).with_memory_limit(220, EvictionPolicy::Lru);
+        for i in 10..30 {
+            store.set(format!("key{}", i), b"value!".to_vec()).await.unwrap();
+        }
+        store.get("key10").await.unwrap();
+        store.mset(vec![("key30".to_string(), b"value!".to_vec()), ("key31".to_string(), b"value!".to_vec())])
+            .await
+            .unwrap();
+        
+        // The oldest untouched keys went, whichever shard held them
+        let mut left: Vec<String> = store.get_all().await.unwrap().into_iter().map(|(key, _)| key).collect();
+        left.sort();
+        let mut expected: Vec<String> = (13..32).map(|i| format!("key{}", i)).collect();
+        expected.insert(0, "key10".to_string());
+        assert_eq!(left, expected);
+        assert_eq!(store.memory_used(), Some(220));
+        assert_eq!(store.evicted_keys(), 2);
+        
+        store.clear().await.unwrap();
+        assert_eq!(store.memory_used(), Some(0));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,550 @@
+//! Harness for durability and crash-recovery testing
+//!
+//! Available with the `test-util` feature. [`TestCluster`] runs servers on
+//! ephemeral ports, each with its own temporary data directory, and can crash
+//! and restart them. [`FaultyWal`] injects write failures, lost syncs and a
+//! full disk into a node's WAL, and [`History`] checks that the state
+//! surviving a crash agrees with what clients were told.
+
+use crate::client::Client;
+use crate::error::{Result, RustVaultError};
+use crate::server::{RustVaultServer, ServerConfig};
+use crate::wal::WriteAheadLog;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Fault state a [`WriteAheadLog`] consults on every write
+#[derive(Debug, Default)]
+pub(crate) struct Faults {
+    writes: AtomicU64,
+    /// Write number to fail; 0 for none
+    fail_at: AtomicU64,
+    drop_syncs: AtomicBool,
+    disk_full: AtomicBool,
+    /// File length as of the last write that counts as synced
+    durable_len: AtomicU64,
+    crashed: AtomicBool,
+}
+
+impl Faults {
+    /// Called on the WAL writer thread before an append is written
+    pub(crate) fn before_write(&self) -> Result<()> {
+        if self.crashed.load(Ordering::SeqCst) {
+            return Err(injected("WAL write after crash".to_string()));
+        }
+        let write = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if write == self.fail_at.load(Ordering::SeqCst) {
+            return Err(injected(format!("injected failure of WAL write {}", write)));
+        }
+        Ok(())
+    }
+    
+    /// Called before written bytes are flushed, leaving them buffered on
+    /// failure the way a real full disk does, and before a probe
+    pub(crate) fn check_disk(&self) -> Result<()> {
+        if self.disk_full.load(Ordering::SeqCst) {
+            let e = io::Error::new(io::ErrorKind::StorageFull, "injected: no space left on device");
+            return Err(RustVaultError::Io(e));
+        }
+        Ok(())
+    }
+    
+    /// Called on the WAL writer thread once a write has been flushed
+    pub(crate) fn synced(&self, len: u64) {
+        if !self.drop_syncs.load(Ordering::SeqCst) {
+            self.durable_len.store(len, Ordering::SeqCst);
+        }
+    }
+    
+    /// Refuse all further writes, returning the length the file is cut to
+    pub(crate) fn crash(&self) -> u64 {
+        self.crashed.store(true, Ordering::SeqCst);
+        self.durable_len.load(Ordering::SeqCst)
+    }
+}
+
+fn injected(message: String) -> RustVaultError {
+    RustVaultError::Io(io::Error::other(message))
+}
+
+/// Fault injection for a node's WAL
+///
+/// Each call that appends to the WAL (a single entry or a whole batch)
+/// counts as one write. A write is synced once it has been flushed to the
+/// OS, unless syncs are being dropped. A crash cuts the file back to its
+/// last synced length, the way a power failure loses data that never reached
+/// the disk; without dropped syncs it behaves like a process crash and keeps
+/// every acknowledged write.
+#[derive(Debug, Clone, Default)]
+pub struct FaultyWal {
+    faults: Arc<Faults>,
+}
+
+impl FaultyWal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Fail the `n`th write from now (1 for the very next one)
+    pub fn fail_nth_write(&self, n: u64) {
+        let writes = self.faults.writes.load(Ordering::SeqCst);
+        self.faults.fail_at.store(writes + n, Ordering::SeqCst);
+    }
+    
+    /// While set, writes still succeed but aren't synced, so a crash loses them
+    pub fn drop_syncs(&self, drop: bool) {
+        self.faults.drop_syncs.store(drop, Ordering::SeqCst);
+    }
+    
+    /// While set, every write fails as it would on a full disk, as does the
+    /// probe that checks whether space has been freed
+    pub fn fill_disk(&self, full: bool) {
+        self.faults.disk_full.store(full, Ordering::SeqCst);
+    }
+    
+    /// Writes attempted so far, including failed ones
+    pub fn writes(&self) -> u64 {
+        self.faults.writes.load(Ordering::SeqCst)
+    }
+    
+    /// Open the WAL at `path` with these faults injected
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<WriteAheadLog> {
+        let len = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        self.faults.durable_len.store(len, Ordering::SeqCst);
+        self.faults.crashed.store(false, Ordering::SeqCst);
+        WriteAheadLog::with_faults(path, Arc::clone(&self.faults))
+    }
+}
+
+/// A server started by [`TestNode::restart`] or [`TestNode::start`]
+struct Running {
+    server: Arc<RustVaultServer>,
+    wal: Arc<WriteAheadLog>,
+    task: JoinHandle<Result<()>>,
+}
+
+/// One server on an ephemeral port with a temporary data directory
+///
+/// The node keeps its address and data directory across restarts; the
+/// directory is removed when the node is dropped.
+pub struct TestNode {
+    dir: TempDir,
+    addr: String,
+    faults: FaultyWal,
+    running: Option<Running>,
+}
+
+impl TestNode {
+    /// Start a node whose WAL has no faults scheduled
+    pub async fn start() -> Result<Self> {
+        Self::start_with_faults(FaultyWal::new()).await
+    }
+    
+    /// Start a node whose WAL is controlled by `faults`
+    pub async fn start_with_faults(faults: FaultyWal) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut node = Self {
+            dir: TempDir::new()?,
+            addr: listener.local_addr()?.to_string(),
+            faults,
+            running: None,
+        };
+        node.launch(listener).await?;
+        Ok(node)
+    }
+    
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+    
+    pub fn data_dir(&self) -> &Path {
+        self.dir.path()
+    }
+    
+    pub fn wal_path(&self) -> PathBuf {
+        self.dir.path().join("vault.log")
+    }
+    
+    /// Fault controls for this node's WAL
+    pub fn faults(&self) -> &FaultyWal {
+        &self.faults
+    }
+    
+    /// The running server, if the node hasn't been stopped or crashed
+    pub fn server(&self) -> Option<&Arc<RustVaultServer>> {
+        self.running.as_ref().map(|running| &running.server)
+    }
+    
+    /// Connect a new client to the node
+    pub async fn client(&self) -> Result<Client> {
+        Client::connect(&self.addr).await
+    }
+    
+    /// Kill the server without letting it finish anything
+    ///
+    /// The WAL stops accepting writes first, so commands still in flight
+    /// fail rather than landing after the crash, and the file is cut back to
+    /// its last synced length. The server task is then aborted and its
+    /// connections dropped.
+    pub async fn crash(&mut self) -> Result<()> {
+        let Some(running) = self.running.take() else {
+            return Ok(());
+        };
+        running.wal.crash().await?;
+        running.task.abort();
+        let _ = running.server.shutdown();
+        let _ = running.task.await;
+        Ok(())
+    }
+    
+    /// Shut the server down gracefully
+    pub async fn stop(&mut self) -> Result<()> {
+        let Some(running) = self.running.take() else {
+            return Ok(());
+        };
+        running.server.shutdown()?;
+        match running.task.await {
+            Ok(result) => result,
+            Err(e) => Err(RustVaultError::Server(format!("Server task failed: {}", e))),
+        }
+    }
+    
+    /// Start the server again on the same address and data directory
+    ///
+    /// Returns once the WAL has been replayed.
+    pub async fn restart(&mut self) -> Result<()> {
+        self.crash().await?;
+        
+        // The old listener is released once its aborted tasks are dropped
+        let mut attempts = 0;
+        let listener = loop {
+            match TcpListener::bind(&self.addr).await {
+                Ok(listener) => break listener,
+                Err(e) if attempts < 100 && e.kind() == io::ErrorKind::AddrInUse => {
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        self.launch(listener).await
+    }
+    
+    async fn launch(&mut self, listener: TcpListener) -> Result<()> {
+        let config = ServerConfig {
+            bind_addr: self.addr.clone(),
+            wal_path: self.wal_path().to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let wal = Arc::new(self.faults.open(self.wal_path())?);
+        let server = Arc::new(RustVaultServer::with_wal(config, Arc::clone(&wal)));
+        let task = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run_with_listener(listener).await })
+        };
+        
+        while !server.is_ready() {
+            if task.is_finished() {
+                return match task.await {
+                    Ok(Err(e)) => Err(e),
+                    _ => Err(RustVaultError::Server("Server stopped during startup".to_string())),
+                };
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        
+        self.running = Some(Running { server, wal, task });
+        Ok(())
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.take() {
+            let _ = running.server.shutdown();
+            running.task.abort();
+        }
+    }
+}
+
+/// A set of independent [`TestNode`]s
+pub struct TestCluster {
+    nodes: Vec<TestNode>,
+}
+
+impl TestCluster {
+    /// Start `nodes` servers, each on its own port and data directory
+    pub async fn start(nodes: usize) -> Result<Self> {
+        let mut started = Vec::with_capacity(nodes);
+        for _ in 0..nodes {
+            started.push(TestNode::start().await?);
+        }
+        Ok(Self { nodes: started })
+    }
+    
+    pub fn node(&self, index: usize) -> &TestNode {
+        &self.nodes[index]
+    }
+    
+    pub fn node_mut(&mut self, index: usize) -> &mut TestNode {
+        &mut self.nodes[index]
+    }
+    
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+    
+    /// Stop every node gracefully
+    pub async fn shutdown(mut self) -> Result<()> {
+        for node in &mut self.nodes {
+            node.stop().await?;
+        }
+        Ok(())
+    }
+}
+
+/// One write in a [`History`]
+#[derive(Debug, Clone)]
+struct Op {
+    key: String,
+    /// `None` for a delete
+    value: Option<String>,
+    invoked: u64,
+    /// Set once the server acknowledged the write
+    acked: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct HistoryInner {
+    clock: u64,
+    ops: Vec<Op>,
+}
+
+/// Writes issued by concurrent clients, and which of them were acknowledged
+///
+/// After a crash and restart, [`History::check`] verifies the recovered
+/// state is one the history allows. An acknowledged write is allowed to be
+/// the final value of its key unless another acknowledged write to the key
+/// started after it was acknowledged; a write that was never acknowledged
+/// may or may not have been applied, so its value is always allowed. A key
+/// with no acknowledged writes may be absent. Anything else, including a
+/// value nobody wrote, is a violation.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    inner: Arc<Mutex<HistoryInner>>,
+}
+
+/// A write that has been sent but not yet answered
+#[must_use = "a pending write that is dropped counts as unacknowledged"]
+pub struct PendingWrite {
+    history: History,
+    index: usize,
+}
+
+impl PendingWrite {
+    /// Record that the server acknowledged the write
+    pub fn ack(self) {
+        let mut inner = self.history.inner.lock().unwrap();
+        inner.clock += 1;
+        let now = inner.clock;
+        inner.ops[self.index].acked = Some(now);
+    }
+}
+
+/// A key whose recovered value the history doesn't allow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub key: String,
+    pub found: Option<String>,
+    /// Values the key could legitimately hold; `None` means absent
+    pub allowed: Vec<Option<String>>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: found {:?}, expected one of {:?}",
+            self.key, self.found, self.allowed
+        )
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Record that a write of `value` (or a delete, for `None`) to `key` is
+    /// about to be sent
+    pub fn begin(&self, key: &str, value: Option<&str>) -> PendingWrite {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let invoked = inner.clock;
+        inner.ops.push(Op {
+            key: key.to_string(),
+            value: value.map(str::to_string),
+            invoked,
+            acked: None,
+        });
+        PendingWrite {
+            history: self.clone(),
+            index: inner.ops.len() - 1,
+        }
+    }
+    
+    /// SET through `client`, recording the outcome
+    pub async fn set(&self, client: &mut Client, key: &str, value: &str) -> Result<()> {
+        let pending = self.begin(key, Some(value));
+        client.set(key, value).await?;
+        pending.ack();
+        Ok(())
+    }
+    
+    /// DELETE through `client`, recording the outcome
+    pub async fn delete(&self, client: &mut Client, key: &str) -> Result<bool> {
+        let pending = self.begin(key, None);
+        let deleted = client.delete(key).await?;
+        pending.ack();
+        Ok(deleted)
+    }
+    
+    /// Every key written to, in first-write order
+    pub fn keys(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut keys: Vec<String> = Vec::new();
+        for op in &inner.ops {
+            if !keys.contains(&op.key) {
+                keys.push(op.key.clone());
+            }
+        }
+        keys
+    }
+    
+    /// Check a recovered keyspace against the history
+    pub fn check(&self, state: &HashMap<String, String>) -> Vec<Violation> {
+        let inner = self.inner.lock().unwrap();
+        let mut by_key: HashMap<&str, Vec<&Op>> = HashMap::new();
+        for op in &inner.ops {
+            by_key.entry(&op.key).or_default().push(op);
+        }
+        
+        let mut violations = Vec::new();
+        for (key, ops) in &by_key {
+            let allowed = allowed_values(ops);
+            let found = state.get(*key).cloned();
+            if !allowed.contains(&found) {
+                violations.push(Violation {
+                    key: key.to_string(),
+                    found,
+                    allowed,
+                });
+            }
+        }
+        for (key, value) in state {
+            if !by_key.contains_key(key.as_str()) {
+                violations.push(Violation {
+                    key: key.clone(),
+                    found: Some(value.clone()),
+                    allowed: vec![None],
+                });
+            }
+        }
+        
+        violations.sort_by(|a, b| a.key.cmp(&b.key));
+        violations
+    }
+    
+    /// Fetch every key in the history from a server and check the result
+    ///
+    /// Keys the history never wrote are not looked for.
+    pub async fn check_node(&self, client: &mut Client) -> Result<Vec<Violation>> {
+        let mut state = HashMap::new();
+        for key in self.keys() {
+            if let Some(value) = client.get(&key).await? {
+                state.insert(key, value);
+            }
+        }
+        Ok(self.check(&state))
+    }
+}
+
+/// Values a key may hold given the writes to it
+fn allowed_values(ops: &[&Op]) -> Vec<Option<String>> {
+    let started_after = |time: u64| ops.iter().any(|op| op.acked.is_some() && op.invoked > time);
+    
+    let mut allowed = Vec::new();
+    // The key's state before the first write, superseded by any acked write
+    if !started_after(0) {
+        allowed.push(None);
+    }
+    for op in ops {
+        let possible = match op.acked {
+            Some(acked) => !started_after(acked),
+            None => true,
+        };
+        if possible && !allowed.contains(&op.value) {
+            allowed.push(op.value.clone());
+        }
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn state(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+    
+    #[test]
+    fn test_history_allows_last_acked_or_in_flight_writes() {
+        let history = History::new();
+        history.begin("a", Some("1")).ack();
+        history.begin("a", Some("2")).ack();
+        // Sent, but the server went away before answering
+        let _ = history.begin("a", Some("3"));
+        history.begin("b", Some("1")).ack();
+        history.begin("b", None).ack();
+        let _ = history.begin("c", Some("1"));
+        
+        assert!(history.check(&state(&[("a", "2")])).is_empty());
+        assert!(history.check(&state(&[("a", "3")])).is_empty());
+        assert!(history.check(&state(&[("a", "2"), ("c", "1")])).is_empty());
+        
+        let violations = history.check(&state(&[("a", "1"), ("b", "1"), ("d", "x")]));
+        let keys: Vec<&str> = violations.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "b", "d"]);
+        assert_eq!(violations[0].allowed, vec![Some("2".to_string()), Some("3".to_string())]);
+        
+        // An acknowledged write can't silently vanish
+        let violations = history.check(&HashMap::new());
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].key, "a");
+    }
+    
+    #[test]
+    fn test_history_allows_either_order_of_concurrent_writes() {
+        let history = History::new();
+        let first = history.begin("k", Some("x"));
+        let second = history.begin("k", Some("y"));
+        second.ack();
+        first.ack();
+        
+        assert!(history.check(&state(&[("k", "x")])).is_empty());
+        assert!(history.check(&state(&[("k", "y")])).is_empty());
+        assert_eq!(history.check(&HashMap::new()).len(), 1);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,317 @@
+//! The store without the server
+//!
+//! A [`Vault`] is what [`RustVaultServer`](crate::RustVaultServer) serves:
+//! a store, the WAL it logs to and the snapshot it starts from. Opening one
+//! directly embeds the store in-process, with the same durability and no
+//! networking.
+
+use crate::backup::{self, BackupEntry};
+use crate::error::{Result, RustVaultError};
+use crate::server::ServerConfig;
+use crate::store::{BatchOp, CompactionReport, ShardedMemoryStore, Store};
+use crate::wal::{now_millis, ReplayProgress, WriteAheadLog};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An embedded key-value store, persisted to a WAL
+///
+/// Derefs to its store, so every [`Store`] method is called on the vault
+/// itself. Compaction only runs when asked for with [`Vault::compact`];
+/// the server's background jobs are the server's.
+///
+/// ```
+/// use rustvault::{Store, Vault};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> rustvault::Result<()> {
+/// let dir = tempfile::tempdir()?;
+/// let path = dir.path().join("vault.log");
+///
+/// let vault = Vault::open(&path).await?;
+/// vault.set("greeting".to_string(), b"hello".to_vec()).await?;
+/// vault.flush().await?;
+/// drop(vault);
+///
+/// let vault = Vault::open(&path).await?;
+/// assert_eq!(vault.get("greeting").await?, Some(b"hello".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Vault<S = ShardedMemoryStore> {
+    store: Arc<S>,
+    /// The store's WAL, when it was built around one
+    wal: Option<Arc<WriteAheadLog>>,
+    /// Where [`Vault::snapshot`] writes and restoring starts from
+    snapshot_path: Option<PathBuf>,
+}
+
+impl Vault {
+    /// Open the vault logged to `path`, creating it if needed, and load
+    /// what it holds
+    ///
+    /// Every other setting is the default [`ServerConfig`]'s.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let config = ServerConfig {
+            wal_path: path.as_ref().to_string_lossy().into_owned(),
+            ..ServerConfig::default()
+        };
+        Self::open_with_config(&config).await
+    }
+    
+    /// Open the vault `config` describes and load what it holds
+    ///
+    /// Only the WAL, store and snapshot settings are used; the rest are the
+    /// server's.
+    pub async fn open_with_config(config: &ServerConfig) -> Result<Self> {
+        let vault = Self::from_config(config)?;
+        vault.restore(|_| {}).await?;
+        Ok(vault)
+    }
+    
+    /// A vault that logs nothing, whose data goes when it is dropped
+    ///
+    /// ```
+    /// use rustvault::{Store, Vault};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> rustvault::Result<()> {
+    /// let vault = Vault::open_in_memory();
+    /// vault.set("counter".to_string(), b"1".to_vec()).await?;
+    /// assert_eq!(vault.incr("counter", 2).await?, 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_in_memory() -> Self {
+        Self::with_store(ShardedMemoryStore::new())
+    }
+    
+    /// Open the WAL `config` names, without loading it
+    pub(crate) fn from_config(config: &ServerConfig) -> Result<Self> {
+        let wal = match config.wal_segment_size_bytes {
+            Some(size) => WriteAheadLog::segmented(&config.wal_path, config.wal_sync, size)?,
+            None => WriteAheadLog::new(&config.wal_path, config.wal_sync)?,
+        };
+        let wal = wal
+            .with_recovery_mode(config.recovery_mode)
+            .with_group_delay(Duration::from_micros(config.wal_group_delay_micros))
+            .with_format(config.wal_format)?;
+        Ok(Self::with_wal(config, Arc::new(wal)))
+    }
+    
+    /// A vault around an already-open WAL, without loading it
+    pub(crate) fn with_wal(config: &ServerConfig, wal: Arc<WriteAheadLog>) -> Self {
+        let mut store = ShardedMemoryStore::with_wal_and_hasher(Arc::clone(&wal), config.shards, config.hasher.build());
+        if let Some(max_bytes) = config.max_memory_bytes {
+            store = store.with_memory_limit(max_bytes, config.eviction_policy);
+        }
+        if let Some(compression) = config.compression {
+            store = store.with_compression(compression);
+        }
+        Self {
+            store: Arc::new(store),
+            wal: Some(wal),
+            snapshot_path: config.snapshot_path.as_ref().map(PathBuf::from),
+        }
+    }
+}
+
+impl<S: Store> Vault<S> {
+    /// A vault around any store, which keeps its own data
+    pub fn with_store(store: S) -> Self {
+        Self::around(Arc::new(store))
+    }
+    
+    /// A vault around a store already shared elsewhere
+    pub(crate) fn around(store: Arc<S>) -> Self {
+        Self {
+            store,
+            wal: None,
+            snapshot_path: None,
+        }
+    }
+    
+    /// Take snapshots at `path` with [`Vault::snapshot`], and start from the
+    /// one there when restoring
+    pub fn with_snapshot_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot_path = Some(path.into());
+        self
+    }
+    
+    /// The store, shared with whatever else serves it
+    pub fn store(&self) -> &Arc<S> {
+        &self.store
+    }
+    
+    /// The WAL the store logs to, if it has one
+    pub fn wal(&self) -> Option<&Arc<WriteAheadLog>> {
+        self.wal.as_ref()
+    }
+    
+    /// Load the snapshot, if there is one, and what the store persists, on
+    /// a blocking thread; `progress` is told how far it has got
+    pub(crate) async fn restore<P>(&self, progress: P) -> Result<()>
+    where
+        S: 'static,
+        P: FnMut(ReplayProgress) + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        let snapshot = self.snapshot_path.clone();
+        tokio::task::spawn_blocking(move || store.restore_blocking(snapshot.as_deref(), progress))
+            .await
+            .map_err(|e| RustVaultError::Wal(format!("WAL restore task failed: {}", e)))?
+    }
+    
+    /// Rewrite the WAL down to the live data
+    pub async fn compact(&self) -> Result<CompactionReport> {
+        self.store.compact_wal().await
+    }
+    
+    /// Write a snapshot to the snapshot path
+    pub async fn snapshot(&self) -> Result<()> {
+        let path = self.snapshot_path.as_ref().ok_or_else(|| {
+            RustVaultError::Snapshot("No snapshot_path configured".to_string())
+        })?;
+        self.store.snapshot_to(path).await
+    }
+    
+    /// Write every live key to a standalone backup file at `path`, and
+    /// return how many were written
+    ///
+    /// The keys are copied as of one moment and written out while writes
+    /// carry on; see [`backup`](crate::backup) for the format.
+    pub async fn backup(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let entries: Vec<BackupEntry> = self.store.live_entries().await?.into_iter().map(Into::into).collect();
+        let count = entries.len();
+        let path = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || backup::write(&path, &entries))
+            .await
+            .map_err(|e| RustVaultError::Backup(format!("Backup task failed: {}", e)))??;
+        Ok(count)
+    }
+    
+    /// Load the backup file at `path` into the store, and return the keys
+    /// loaded
+    ///
+    /// Refused unless the store is empty, or `merge` is set, when the
+    /// backup's keys replace any the store already has. The whole file is
+    /// checked before anything is loaded, and its keys are then set, and
+    /// logged, as one batch. Keys whose deadline has passed since the
+    /// backup was taken are left out.
+    pub async fn restore_backup(&self, path: impl AsRef<Path>, merge: bool) -> Result<Vec<String>> {
+        if !merge && !self.store.is_empty().await? {
+            return Err(RustVaultError::Backup(
+                "The store isn't empty; merge to load the backup over its keys".to_string(),
+            ));
+        }
+        let path = path.as_ref().to_path_buf();
+        let entries = tokio::task::spawn_blocking(move || backup::read(&path))
+            .await
+            .map_err(|e| RustVaultError::Backup(format!("Restore task failed: {}", e)))??;
+        
+        let now = now_millis();
+        let mut keys = Vec::with_capacity(entries.len());
+        let mut ops = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let ttl = match entry.expires_at {
+                Some(unix_millis) if unix_millis <= now => continue,
+                Some(unix_millis) => Some(Duration::from_millis(unix_millis - now)),
+                None => None,
+            };
+            keys.push(entry.key.clone());
+            ops.push(BatchOp::Set { key: entry.key, value: entry.value, ttl });
+        }
+        if !ops.is_empty() {
+            self.store.apply_batch(Vec::new(), ops).await?;
+        }
+        Ok(keys)
+    }
+    
+    /// Sync everything logged so far to disk, whatever the sync policy
+    pub async fn flush(&self) -> Result<()> {
+        match &self.wal {
+            Some(wal) => wal.sync().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S> Deref for Vault<S> {
+    type Target = S;
+    
+    fn deref(&self) -> &S {
+        &self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::SyncPolicy;
+    
+    #[tokio::test]
+    async fn test_compacts_and_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            wal_path: dir.path().join("vault.log").to_string_lossy().into_owned(),
+            wal_sync: SyncPolicy::Never,
+            snapshot_path: Some(dir.path().join("vault.snap").to_string_lossy().into_owned()),
+            ..ServerConfig::default()
+        };
+        let vault = Vault::open_with_config(&config).await.unwrap();
+        for i in 0..100 {
+            vault.set("key".to_string(), i.to_string().into_bytes()).await.unwrap();
+        }
+        let report = vault.compact().await.unwrap();
+        assert!(report.after < report.before, "{:?}", report);
+        vault.snapshot().await.unwrap();
+        vault.flush().await.unwrap();
+        drop(vault);
+        
+        let vault = Vault::open_with_config(&config).await.unwrap();
+        assert_eq!(vault.get("key").await.unwrap(), Some(b"99".to_vec()));
+        
+        let in_memory = Vault::open_in_memory();
+        assert!(in_memory.wal().is_none());
+        assert!(in_memory.snapshot().await.is_err());
+        in_memory.flush().await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_backup_restores_into_an_empty_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.backup");
+        let vault = Vault::open_in_memory();
+        let pairs: Vec<_> = (0..10_000).map(|i| (format!("key{}", i), format!("value{}", i).into_bytes())).collect();
+        vault.mset(pairs.clone()).await.unwrap();
+        vault.set_with_ttl("ttl".to_string(), b"soon".to_vec(), Duration::from_secs(60)).await.unwrap();
+        vault.set_with_ttl("gone".to_string(), b"past".to_vec(), Duration::ZERO).await.unwrap();
+        assert_eq!(vault.backup(&path).await.unwrap(), 10_001);
+        
+        vault.clear().await.unwrap();
+        let mut loaded = vault.restore_backup(&path, false).await.unwrap();
+        loaded.sort();
+        assert_eq!(loaded.len(), 10_001);
+        assert_eq!(vault.len().await.unwrap(), 10_001);
+        for (key, value) in pairs.iter().step_by(997) {
+            assert_eq!(vault.get(key).await.unwrap().as_ref(), Some(value));
+        }
+        let (_, deadline) = vault.get_with_deadline("ttl").await.unwrap().unwrap();
+        assert!(deadline.is_some());
+        
+        // Only an empty store takes a backup, unless it is merged in
+        vault.set("key0".to_string(), b"changed".to_vec()).await.unwrap();
+        assert!(matches!(vault.restore_backup(&path, false).await, Err(RustVaultError::Backup(_))));
+        assert_eq!(vault.restore_backup(&path, true).await.unwrap().len(), 10_001);
+        assert_eq!(vault.get("key0").await.unwrap(), Some(b"value0".to_vec()));
+        
+        // A torn file loads nothing at all
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        vault.clear().await.unwrap();
+        let err = vault.restore_backup(&path, false).await.unwrap_err();
+        assert!(err.to_string().contains("Corrupt backup file: truncated"), "{}", err);
+        assert_eq!(vault.len().await.unwrap(), 0);
+    }
+}
@@ -2,63 +2,1101 @@
 //! 
 //! Provides durable persistence by logging all operations before applying them
 
+mod format;
+mod segment;
+
 use crate::error::{RustVaultError, Result};
 use crate::protocol::Command;
+use crate::store::compression::CompressionAlgorithm;
+use format::{encode_record, Record, RecordReader};
+pub use format::{WalFormat, BINARY_MAGIC};
+use segment::{log_files, numbered, segment_path, LogFile};
+pub use segment::Segment;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::future::Future;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
-use tokio::sync::Mutex;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Bytes written by a probe to check the disk has room again
+const PROBE_SIZE: usize = 4 * 1024;
+
+/// Bytes before a checkpoint's offset that its digest covers
+const CHECKPOINT_TAIL: u64 = 256;
 
 /// WAL entry representing a logged operation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WalEntry {
     pub timestamp: u64,
     pub command: Command,
+    /// What a compacted `Set` carries over from the writes it replaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<KeyHistory>,
+    /// What the value of a `Set` is compressed with, if it is; entries are
+    /// decompressed as they are read, so a replayed one never is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionAlgorithm>,
 }
 
 impl WalEntry {
     pub fn new(command: Command) -> Self {
         Self {
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
+            timestamp: now_millis(),
             command,
+            history: None,
+            compression: None,
+        }
+    }
+    
+    /// The entry with its value decompressed, if it was compressed
+    fn decompressed(mut self) -> std::result::Result<Self, String> {
+        if let Some(algorithm) = self.compression.take() {
+            match &mut self.command {
+                Command::Set { value, .. } => *value = algorithm.decompress(value)?,
+                _ => return Err("only a SET's value can be compressed".to_string()),
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// A key's past, as a compaction writes it on the `Set` that replaces the
+/// key's writes; the entry's timestamp is when the key was last set
+///
+/// Replaying a `Set` without one counts it as the next version of the key,
+/// so logs written before compaction kept this still replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyHistory {
+    /// Times the key had been set
+    pub version: u64,
+    /// When it was first set, in milliseconds since the Unix epoch
+    pub created_at: u64,
+}
+
+/// What a log has done since it was opened, as `WALSTATS` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalStats {
+    /// Bytes in the log, across every segment
+    pub size: u64,
+    /// Entries appended since the log was opened
+    pub entries: u64,
+    /// When the log was last compacted, in milliseconds since the Unix
+    /// epoch; `None` if it hasn't been since it was opened
+    pub last_compaction: Option<u64>,
+}
+
+impl WalStats {
+    /// The stats as `WALSTATS` sends them, `last_compaction_ms` 0 for none
+    pub fn fields(&self) -> Vec<(String, String)> {
+        vec![
+            ("size_bytes".to_string(), self.size.to_string()),
+            ("entries".to_string(), self.entries.to_string()),
+            ("last_compaction_ms".to_string(), self.last_compaction.unwrap_or(0).to_string()),
+        ]
+    }
+    
+    /// Read back the stats from their [`WalStats::fields`]
+    pub fn from_fields(fields: &[(String, String)]) -> Option<Self> {
+        let field = |name: &str| fields.iter().find(|(field, _)| field == name)?.1.parse::<u64>().ok();
+        Some(Self {
+            size: field("size_bytes")?,
+            entries: field("entries")?,
+            last_compaction: Some(field("last_compaction_ms")?).filter(|&millis| millis > 0),
+        })
+    }
+}
+
+/// Current wall-clock time in milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Marker delimiting a group of entries written by `write_entries`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchMarker {
+    Begin { count: usize },
+    Commit,
+}
+
+/// A single line of the WAL file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum WalRecord {
+    Entry(WalEntry),
+    Marker { timestamp: u64, batch: BatchMarker },
+}
+
+impl WalRecord {
+    fn marker(batch: BatchMarker) -> Self {
+        WalRecord::Marker {
+            timestamp: now_millis(),
+            batch,
+        }
+    }
+}
+
+/// When appends are forced from the OS page cache to the disk
+///
+/// Every append is flushed to the OS before it is acknowledged, so a crash
+/// of the server process alone never loses an acknowledged write whatever
+/// the policy. The policy decides what a power failure or kernel crash can
+/// take with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// `sync_data` after every append, before it is acknowledged. Nothing
+    /// acknowledged is ever lost, but every write waits for the disk;
+    /// appends that arrive together share one sync.
+    Always,
+    /// `sync_data` from a background task at most this many milliseconds
+    /// apart, when anything was appended since the last one. Up to that
+    /// long of acknowledged writes can be lost; writes don't wait for the
+    /// disk. Needs a tokio runtime when the log is opened.
+    EveryMillis(u64),
+    /// Never sync; the OS writes the log back on its own schedule (within
+    /// about 30 seconds on Linux by default). The fastest policy, and the
+    /// one that can lose the most.
+    Never,
+}
+
+/// What replay does about an entry that can't be parsed
+///
+/// A crash partway through an append leaves a torn final entry, which is
+/// always cut off: the write it held was never acknowledged. The mode only
+/// decides what happens to corruption with readable entries after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// Fail, leaving the log untouched for an operator to inspect
+    #[default]
+    Strict,
+    /// Cut the log back to the corrupt entry, dropping it and everything
+    /// after it
+    TruncateCorrupt,
+}
+
+/// State shared with the background task of [`SyncPolicy::EveryMillis`]
+#[derive(Debug)]
+struct Syncer {
+    /// The log file; replaced when compaction swaps in a new one
+    file: std::sync::Mutex<File>,
+    /// Set by appends not synced yet
+    dirty: AtomicBool,
+}
+
+impl Syncer {
+    /// Sync the file if anything was appended since the last sync
+    fn sync_if_dirty(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let result = self.file.lock().unwrap().sync_data();
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+}
+
+/// Sync `syncer` every `every` until the task is aborted
+fn spawn_syncer(syncer: Arc<Syncer>, every: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(every);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let syncer = Arc::clone(&syncer);
+            match tokio::task::spawn_blocking(move || syncer.sync_if_dirty()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("Background WAL sync failed: {}", e),
+                Err(e) => eprintln!("Background WAL sync task failed: {}", e),
+            }
+        }
+    })
+}
+
+/// Most appends the writer thread commits with a single write
+const MAX_GROUP: usize = 1024;
+
+/// The formats a log reads and writes
+#[derive(Debug, Clone, Copy)]
+struct Formats {
+    /// Format of the current file, which appends follow; only changed by
+    /// the writer thread
+    file: WalFormat,
+    /// Format new and compacted logs are written in
+    target: WalFormat,
+}
+
+/// State shared between a log and its writer thread
+struct Shared {
+    /// The log's file, or for a segmented log what its segments are named
+    /// after
+    path: String,
+    sync: SyncPolicy,
+    /// How long the writer thread waits for more appends to join a group
+    /// before committing it, in microseconds; see
+    /// [`WriteAheadLog::with_group_delay`]
+    group_delay_micros: AtomicU64,
+    /// Size a segment grows to before the next one is started; `None` for a
+    /// log that is a single file
+    segment_size: Option<u64>,
+    formats: std::sync::Mutex<Formats>,
+    /// Background sync state, under [`SyncPolicy::EveryMillis`]
+    syncer: Option<Arc<Syncer>>,
+    /// Why appends are refused; set by a write failure that retrying won't
+    /// fix, such as a full disk
+    degraded: std::sync::Mutex<Option<String>>,
+    /// Times the log has entered the degraded state
+    persistence_failures: AtomicU64,
+    /// Length of the log, across every segment, as of the last append
+    len: AtomicU64,
+    /// Entries appended since the log was opened
+    entries: AtomicU64,
+    /// When the last compaction finished, in milliseconds since the Unix
+    /// epoch; 0 before the first
+    last_compaction: AtomicU64,
+    /// Appends made since a compaction began, to be carried over into the
+    /// compacted log; `None` when no compaction is running
+    compacting: std::sync::Mutex<Option<Vec<u8>>>,
+    /// The compacted log as written so far, once a compaction has written
+    /// to it
+    compacted: std::sync::Mutex<Option<BufWriter<File>>>,
+    /// Injected failures; see `testing::FaultyWal`
+    #[cfg(feature = "test-util")]
+    faults: std::sync::OnceLock<Arc<crate::testing::Faults>>,
+}
+
+impl Shared {
+    /// Enter the degraded state after `e`, returning the error to report
+    fn degrade(&self, e: io::Error) -> RustVaultError {
+        let detail = e.to_string();
+        let mut degraded = self.degraded.lock().unwrap();
+        if degraded.is_none() {
+            self.persistence_failures.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "WAL {} can't be written ({}); refusing writes until it recovers",
+                self.path, detail
+            );
+        }
+        *degraded = Some(detail.clone());
+        RustVaultError::Persistence(detail)
+    }
+    
+    /// Leave the degraded state, if in it
+    fn recover(&self) {
+        if self.degraded.lock().unwrap().take().is_some() {
+            println!("WAL {} is writable again; accepting writes", self.path);
+        }
+    }
+    
+    fn persistence_error(&self) -> Option<String> {
+        self.degraded.lock().unwrap().clone()
+    }
+    
+    /// Where a compacted log is written before it replaces this one
+    fn compaction_path(&self) -> String {
+        format!("{}.tmp", self.path)
+    }
+    
+    /// Append `bytes` to the compacted log, creating it first if this is
+    /// the first write to it
+    fn write_compacted(&self, bytes: &[u8]) -> Result<()> {
+        let mut compacted = self.compacted.lock().unwrap();
+        let temp = match compacted.as_mut() {
+            Some(temp) => temp,
+            None => {
+                let format = self.formats.lock().unwrap().target;
+                let temp_file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(self.compaction_path())?;
+                let mut temp = BufWriter::new(temp_file);
+                temp.write_all(format.header())?;
+                compacted.insert(temp)
+            }
+        };
+        temp.write_all(bytes)?;
+        Ok(())
+    }
+    
+    #[cfg(feature = "test-util")]
+    fn faults(&self) -> Option<&crate::testing::Faults> {
+        self.faults.get().map(|faults| &**faults)
+    }
+}
+
+/// Work for the writer thread, done in the order it was sent
+enum Request {
+    Append(Append),
+    /// Anything else that needs the file in step with appends, such as a
+    /// sync or the swap at the end of a compaction
+    Run(Box<dyn FnOnce(&mut Writer) + Send>),
+    /// Finish what was sent before and exit
+    Stop,
+}
+
+/// Records to append, acknowledged through `done` once written
+struct Append {
+    /// The records in `format`, the file's format when they were encoded
+    bytes: Vec<u8>,
+    /// How many of the records are entries, rather than batch markers
+    entries: u64,
+    format: WalFormat,
+    /// The records in the target format, when that was a different one
+    converted: Option<Vec<u8>>,
+    done: oneshot::Sender<Result<()>>,
+}
+
+impl Append {
+    /// The records encoded in `format`
+    fn encoded(&self, format: WalFormat) -> &[u8] {
+        if format == self.format {
+            &self.bytes
+        } else {
+            // The only format the file can have changed to is the target
+            self.converted.as_deref().expect("append wasn't encoded in the target format")
         }
     }
 }
 
+/// The writer thread's end of a log, the only handle anything is appended
+/// through
+struct Writer {
+    file: File,
+    shared: Arc<Shared>,
+    /// The segment `file` is, for a segmented log
+    active: Option<Active>,
+}
+
+/// The segment a segmented log is appending to
+#[derive(Debug, Clone, Copy)]
+struct Active {
+    number: u64,
+    /// Where the segment starts in the log's offsets
+    start: u64,
+}
+
+impl Writer {
+    /// Serve `requests` until the log stops or is dropped
+    ///
+    /// Appends that queue up while a write is under way are committed
+    /// together by the next one: a single write, and under
+    /// [`SyncPolicy::Always`] a single sync, acknowledges all of them. Other
+    /// requests are done in order between them. With a group delay set, a
+    /// group that could take more appends waits that long, once, for them
+    /// before it is committed.
+    fn run(mut self, mut requests: mpsc::UnboundedReceiver<Request>) {
+        let mut group = Vec::new();
+        while let Some(first) = requests.blocking_recv() {
+            let mut request = Some(first);
+            let mut waited = false;
+            loop {
+                while let Some(next) = request.take() {
+                    match next {
+                        Request::Append(append) => group.push(append),
+                        Request::Run(job) => {
+                            self.commit(&mut group);
+                            job(&mut self);
+                        }
+                        Request::Stop => {
+                            self.commit(&mut group);
+                            return;
+                        }
+                    }
+                    if group.len() < MAX_GROUP {
+                        request = requests.try_recv().ok();
+                    }
+                }
+                let delay = self.shared.group_delay_micros.load(Ordering::Relaxed);
+                if waited || delay == 0 || group.is_empty() || group.len() >= MAX_GROUP {
+                    break;
+                }
+                std::thread::sleep(Duration::from_micros(delay));
+                waited = true;
+                request = requests.try_recv().ok();
+            }
+            self.commit(&mut group);
+        }
+    }
+    
+    /// Write `group` and acknowledge each append in it
+    ///
+    /// Each append still counts as one write to injected faults, so one that
+    /// fails them fails alone. A failure of the write itself fails the whole
+    /// group.
+    fn commit(&mut self, group: &mut Vec<Append>) {
+        if group.is_empty() {
+            return;
+        }
+        if let Some(detail) = self.shared.persistence_error() {
+            for append in group.drain(..) {
+                let _ = append.done.send(Err(RustVaultError::Persistence(detail.clone())));
+            }
+            return;
+        }
+        
+        let formats = *self.shared.formats.lock().unwrap();
+        let mut written = Vec::with_capacity(group.len());
+        for append in group.drain(..) {
+            #[cfg(feature = "test-util")]
+            if let Some(Err(e)) = self.shared.faults().map(|faults| faults.before_write()) {
+                let _ = append.done.send(Err(e));
+                continue;
+            }
+            written.push(append);
+        }
+        // A lone append, as a large value usually is, is written as it was
+        // encoded rather than copied into a group buffer
+        let bytes = match written.as_slice() {
+            [] => return,
+            [append] => Cow::Borrowed(append.encoded(formats.file)),
+            appends => {
+                let encoded: Vec<&[u8]> = appends.iter().map(|append| append.encoded(formats.file)).collect();
+                Cow::Owned(encoded.concat())
+            }
+        };
+        
+        match self.write(&bytes) {
+            Ok(()) => {
+                if let Some(carried) = self.shared.compacting.lock().unwrap().as_mut() {
+                    // Carried over in the format the compacted log is written in
+                    for append in &written {
+                        carried.extend_from_slice(append.encoded(formats.target));
+                    }
+                }
+                let entries = written.iter().map(|append| append.entries).sum();
+                self.shared.entries.fetch_add(entries, Ordering::Relaxed);
+                for append in written {
+                    let _ = append.done.send(Ok(()));
+                }
+                self.roll_if_full();
+            }
+            Err(e) => {
+                for append in written {
+                    let _ = append.done.send(Err(copy_error(&e)));
+                }
+            }
+        }
+    }
+    
+    /// Append `bytes`, leaving the file as it was on failure
+    ///
+    /// A failed write may have reached the file in part, which would make
+    /// an entry the caller saw fail durable after all, so it is cut off. A
+    /// failure that retrying won't fix degrades the log: further appends
+    /// fail with [`RustVaultError::Persistence`] until a probe or compaction
+    /// succeeds.
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let len = self.file.metadata()?.len();
+        let result = (|| -> Result<()> {
+            #[cfg(feature = "test-util")]
+            if let Some(faults) = self.shared.faults() {
+                faults.check_disk()?;
+            }
+            self.file.write_all(bytes)?;
+            if self.shared.sync == SyncPolicy::Always {
+                self.file.sync_data()?;
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            if let Err(cut) = self.file.set_len(len) {
+                eprintln!("Failed to cut failed write from WAL {}: {}", self.shared.path, cut);
+            }
+            return Err(match e {
+                RustVaultError::Io(e) if is_persistent_failure(&e) => self.shared.degrade(e),
+                e => e,
+            });
+        }
+        
+        if let Some(syncer) = &self.shared.syncer {
+            syncer.dirty.store(true, Ordering::Release);
+        }
+        let len = len + bytes.len() as u64;
+        self.shared.len.store(self.start() + len, Ordering::Relaxed);
+        #[cfg(feature = "test-util")]
+        if let Some(faults) = self.shared.faults() {
+            faults.synced(len);
+        }
+        Ok(())
+    }
+    
+    /// Where the file being appended to starts in the log's offsets
+    fn start(&self) -> u64 {
+        self.active.map_or(0, |active| active.start)
+    }
+    
+    /// The log's offset after the last append
+    fn end(&self) -> Result<u64> {
+        Ok(self.start() + self.file.metadata()?.len())
+    }
+    
+    /// Start the next segment if the active one has reached the segment
+    /// size
+    ///
+    /// The appends that filled it have been acknowledged already, so a
+    /// failure only means the active segment keeps growing until the next
+    /// append tries again.
+    fn roll_if_full(&mut self) {
+        let (Some(active), Some(limit)) = (self.active, self.shared.segment_size) else {
+            return;
+        };
+        let result = self.file.metadata().map_err(RustVaultError::from).and_then(|metadata| {
+            if metadata.len() < limit {
+                return Ok(());
+            }
+            self.roll(active, metadata.len())
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to start a new segment of WAL {}: {}", self.shared.path, e);
+        }
+    }
+    
+    /// Close the active segment, `len` bytes long, and append to a new one
+    /// from now on
+    ///
+    /// The new segment starts in the target format, as a compacted log does.
+    fn roll(&mut self, active: Active, len: u64) -> Result<()> {
+        // Synced first: a power failure mustn't keep entries in the new
+        // segment and lose ones before them in this one
+        if self.shared.sync != SyncPolicy::Never {
+            self.file.sync_data()?;
+        }
+        let number = active.number + 1;
+        let path = segment_path(&self.shared.path, number);
+        let mut file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+        let mut formats = self.shared.formats.lock().unwrap();
+        if let Err(e) = file.write_all(formats.target.header()) {
+            drop(file);
+            let _ = std::fs::remove_file(&path);
+            return Err(e.into());
+        }
+        formats.file = formats.target;
+        drop(formats);
+        
+        if let Some(syncer) = &self.shared.syncer {
+            *syncer.file.lock().unwrap() = file.try_clone()?;
+            syncer.dirty.store(true, Ordering::Release);
+        }
+        let start = active.start + len;
+        self.shared.len.store(start + file.metadata()?.len(), Ordering::Relaxed);
+        self.file = file;
+        self.active = Some(Active { number, start });
+        Ok(())
+    }
+    
+    /// Append to the last of the log's files again, after replay removed
+    /// the ones after a corrupt entry
+    fn reopen_last(&mut self) -> Result<()> {
+        let files = log_files(&self.shared.path)?;
+        let last = files.last().ok_or_else(|| {
+            RustVaultError::Wal(format!("WAL {} has no segments left", self.shared.path))
+        })?;
+        let file = OpenOptions::new().append(true).open(&last.path)?;
+        if let Some(syncer) = &self.shared.syncer {
+            *syncer.file.lock().unwrap() = file.try_clone()?;
+        }
+        self.file = file;
+        self.active = Some(Active { number: last.number, start: last.start });
+        self.shared.len.store(last.end(), Ordering::Relaxed);
+        Ok(())
+    }
+    
+    fn sync(&mut self) -> Result<()> {
+        // Cleared first, so an append racing the background task is synced
+        // by one of the two
+        if let Some(syncer) = &self.shared.syncer {
+            syncer.dirty.store(false, Ordering::Release);
+        }
+        if let Err(e) = self.file.sync_data() {
+            if let Some(syncer) = &self.shared.syncer {
+                syncer.dirty.store(true, Ordering::Release);
+            }
+            return Err(e.into());
+        }
+        #[cfg(feature = "test-util")]
+        self.synced()?;
+        Ok(())
+    }
+    
+    #[cfg(feature = "test-util")]
+    fn synced(&self) -> Result<()> {
+        if let Some(faults) = self.shared.faults() {
+            faults.synced(self.file.metadata()?.len());
+        }
+        Ok(())
+    }
+    
+    /// Write a small file next to the log and sync it, to see whether the
+    /// disk has room again
+    fn probe(&mut self) -> Result<io::Result<()>> {
+        #[cfg(feature = "test-util")]
+        if let Some(faults) = self.shared.faults() {
+            faults.check_disk()?;
+        }
+        let probe_path = format!("{}.probe", self.shared.path);
+        let result = (|| -> io::Result<()> {
+            let mut file = File::create(&probe_path)?;
+            file.write_all(&[0; PROBE_SIZE])?;
+            file.sync_all()
+        })();
+        let _ = std::fs::remove_file(&probe_path);
+        Ok(result)
+    }
+    
+    /// Finish the compacted log in `temp` with the appends carried over
+    /// into it, and put it in place of the current one
+    fn swap(&mut self, mut temp: BufWriter<File>, temp_path: &str) -> Result<()> {
+        let carried = self.shared.compacting.lock().unwrap().take().unwrap_or_default();
+        temp.write_all(&carried)?;
+        temp.flush()?;
+        // The old log is only safe to replace once its successor is on disk
+        if self.shared.sync != SyncPolicy::Never {
+            temp.get_ref().sync_data()?;
+        }
+        drop(temp);
+        
+        // Replace the original WAL with the compacted version; a segmented
+        // log gets it as a new segment, and the older ones go
+        let path = match self.active {
+            Some(active) => {
+                let number = active.number + 1;
+                let path = segment_path(&self.shared.path, number);
+                std::fs::rename(temp_path, &path)?;
+                // A crash before they are all gone replays them first, then
+                // the compacted segment over them, to the same state
+                for (older, older_path) in numbered(&self.shared.path)? {
+                    if older < number {
+                        if let Err(e) = std::fs::remove_file(&older_path) {
+                            eprintln!("Failed to remove compacted WAL segment {}: {}", older_path.display(), e);
+                        }
+                    }
+                }
+                self.active = Some(Active { number, start: 0 });
+                path
+            }
+            None => {
+                std::fs::rename(temp_path, &self.shared.path)?;
+                PathBuf::from(&self.shared.path)
+            }
+        };
+        
+        // Reopen the file
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        self.shared.len.store(file.metadata()?.len(), Ordering::Relaxed);
+        if let Some(syncer) = &self.shared.syncer {
+            *syncer.file.lock().unwrap() = file.try_clone()?;
+        }
+        self.file = file;
+        let mut formats = self.shared.formats.lock().unwrap();
+        formats.file = formats.target;
+        drop(formats);
+        #[cfg(feature = "test-util")]
+        self.synced()?;
+        Ok(())
+    }
+}
+
+/// A copy of `e` for each append in a group whose write failed with it
+fn copy_error(e: &RustVaultError) -> RustVaultError {
+    match e {
+        RustVaultError::Io(e) => RustVaultError::Io(io::Error::new(e.kind(), e.to_string())),
+        RustVaultError::Persistence(detail) => RustVaultError::Persistence(detail.clone()),
+        e => RustVaultError::Wal(e.to_string()),
+    }
+}
+
 /// Write-Ahead Log for durable persistence
+///
+/// Appends are handed to a dedicated writer thread, so the file I/O never
+/// blocks a tokio worker; each append resolves once its entries are written
+/// under the log's [`SyncPolicy`].
 pub struct WriteAheadLog {
-    writer: Mutex<BufWriter<File>>,
-    path: String,
+    shared: Arc<Shared>,
+    requests: mpsc::UnboundedSender<Request>,
+    writer: Option<std::thread::JoinHandle<()>>,
+    /// What replay does about corrupt entries
+    recovery: RecoveryMode,
+    /// Background sync task, under [`SyncPolicy::EveryMillis`]
+    syncer_task: Option<JoinHandle<()>>,
 }
 
 impl WriteAheadLog {
-    /// Create a new WAL instance
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
+    /// Create a new WAL instance that syncs appends according to `sync`
+    ///
+    /// Fails if the log at `path` has been split into segments; see
+    /// [`WriteAheadLog::segmented`].
+    pub fn new<P: AsRef<Path>>(path: P, sync: SyncPolicy) -> Result<Self> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        if !numbered(&path)?.is_empty() {
+            return Err(RustVaultError::Wal(format!(
+                "WAL {} is split into segments; open it with a segment size",
+                path
+            )));
+        }
+        Self::open(path, sync, None)
+    }
+    
+    /// Create a WAL instance that writes segment files named after `path`,
+    /// starting a new one once the last reaches `segment_size` bytes
+    ///
+    /// A log written as a single file at `path` becomes the first segment.
+    /// A last segment holding nothing, or only part of its header, as a
+    /// crash just after it was created leaves, is removed, and appends
+    /// carry on in the one before it.
+    pub fn segmented<P: AsRef<Path>>(path: P, sync: SyncPolicy, segment_size: u64) -> Result<Self> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        let mut segments = numbered(&path)?;
+        if segments.is_empty() {
+            let first = segment_path(&path, 1);
+            if Path::new(&path).exists() {
+                std::fs::rename(&path, &first)?;
+                println!("Split WAL {} into segments, starting with {}", path, first.display());
+            }
+            segments.push((1, first));
+        }
+        while let [.., before, (_, last)] = &segments[..] {
+            if !is_unwritten(last)? {
+                break;
+            }
+            eprintln!(
+                "Removing WAL segment {} left empty by a crash; appending to {}",
+                last.display(),
+                before.1.display()
+            );
+            std::fs::remove_file(last)?;
+            segments.pop();
+        }
+        
+        let mut start = 0;
+        for (_, earlier) in &segments[..segments.len() - 1] {
+            start += std::fs::metadata(earlier)?.len();
+        }
+        let &(number, ref last) = segments.last().expect("a segmented log has a segment");
+        let active = Active { number, start };
+        Self::open(path.clone(), sync, Some((segment_size.max(1), last.clone(), active)))
+    }
+    
+    /// Open the log at `path`, appending to `segment`'s file when the log
+    /// is segmented
+    fn open(path: String, sync: SyncPolicy, segment: Option<(u64, PathBuf, Active)>) -> Result<Self> {
+        let file_path = segment.as_ref().map_or_else(|| PathBuf::from(&path), |(_, file, _)| file.clone());
         let file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(&path)?;
+            .open(&file_path)?;
         
-        let writer = BufWriter::new(file);
+        let mut len = file.metadata()?.len();
+        let mut prefix = Vec::new();
+        File::open(&file_path)?.take(BINARY_MAGIC.len() as u64).read_to_end(&mut prefix)?;
+        let mut file_format = WalFormat::detect(&prefix);
+        if file_format == WalFormat::Binary && len < BINARY_MAGIC.len() as u64 {
+            // A header torn by a crash while the log was created
+            file.set_len(0)?;
+            len = 0;
+            file_format = WalFormat::Json;
+        }
+        let (syncer, syncer_task) = match sync {
+            SyncPolicy::EveryMillis(millis) => {
+                if tokio::runtime::Handle::try_current().is_err() {
+                    return Err(RustVaultError::Wal(
+                        "Periodic WAL sync needs a tokio runtime".to_string(),
+                    ));
+                }
+                let syncer = Arc::new(Syncer {
+                    file: std::sync::Mutex::new(file.try_clone()?),
+                    dirty: AtomicBool::new(false),
+                });
+                let every = Duration::from_millis(millis.max(1));
+                (Some(Arc::clone(&syncer)), Some(spawn_syncer(syncer, every)))
+            }
+            SyncPolicy::Always | SyncPolicy::Never => (None, None),
+        };
+        
+        let active = segment.as_ref().map(|&(_, _, active)| active);
+        let shared = Arc::new(Shared {
+            path,
+            sync,
+            group_delay_micros: AtomicU64::new(0),
+            segment_size: segment.map(|(size, _, _)| size),
+            formats: std::sync::Mutex::new(Formats {
+                file: file_format,
+                target: file_format,
+            }),
+            syncer,
+            degraded: std::sync::Mutex::new(None),
+            persistence_failures: AtomicU64::new(0),
+            len: AtomicU64::new(active.map_or(0, |active| active.start) + len),
+            entries: AtomicU64::new(0),
+            last_compaction: AtomicU64::new(0),
+            compacting: std::sync::Mutex::new(None),
+            compacted: std::sync::Mutex::new(None),
+            #[cfg(feature = "test-util")]
+            faults: std::sync::OnceLock::new(),
+        });
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let writer = Writer {
+            file,
+            shared: Arc::clone(&shared),
+            active,
+        };
+        let writer = std::thread::Builder::new()
+            .name("rustvault-wal".to_string())
+            .spawn(move || writer.run(receiver))?;
         
         Ok(Self {
-            writer: Mutex::new(writer),
-            path: path_str,
+            shared,
+            requests,
+            writer: Some(writer),
+            recovery: RecoveryMode::default(),
+            syncer_task,
         })
     }
+    
+    /// Replay corrupt entries according to `mode`
+    pub fn with_recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery = mode;
+        self
+    }
+    
+    /// Hold each group of appends for up to `delay` before committing it,
+    /// so that more writers can join it and share its write and sync
+    ///
+    /// Worth setting under [`SyncPolicy::Always`] with many concurrent
+    /// writers, where every commit pays for a sync; a lone writer waits up
+    /// to `delay` longer for each append. Zero, the default, commits as soon
+    /// as nothing more is queued.
+    pub fn with_group_delay(self, delay: Duration) -> Self {
+        let micros = u64::try_from(delay.as_micros()).unwrap_or(u64::MAX);
+        self.shared.group_delay_micros.store(micros, Ordering::Relaxed);
+        self
+    }
+    
+    /// Write the log in `format`
+    ///
+    /// An empty log, or an empty last segment, switches straight away. One
+    /// that already holds entries keeps its format for appends, so it stays
+    /// readable, until a compaction or the next segment starts one in
+    /// `format`.
+    pub fn with_format(self, format: WalFormat) -> Result<Self> {
+        let mut formats = self.shared.formats.lock().unwrap();
+        formats.target = format;
+        let files = log_files(&self.shared.path)?;
+        if let Some(last) = files.last().filter(|last| last.len == 0) {
+            // Nothing has been sent to the writer yet, so this is the only write
+            OpenOptions::new()
+                .append(true)
+                .open(&last.path)?
+                .write_all(format.header())?;
+            formats.file = format;
+            self.shared.len.store(last.start + format.header().len() as u64, Ordering::Relaxed);
+        }
+        drop(formats);
+        Ok(self)
+    }
+    
+    /// Format of the current log file
+    pub fn format(&self) -> WalFormat {
+        self.shared.formats.lock().unwrap().file
+    }
+    
+    /// Open a WAL whose writes consult `faults`
+    ///
+    /// The faults decide what a crash keeps, so the log itself never syncs.
+    #[cfg(feature = "test-util")]
+    pub(crate) fn with_faults<P: AsRef<Path>>(
+        path: P,
+        faults: std::sync::Arc<crate::testing::Faults>,
+    ) -> Result<Self> {
+        let wal = Self::new(path, SyncPolicy::Never)?;
+        let _ = wal.shared.faults.set(faults);
+        Ok(wal)
+    }
+    
+    /// Refuse further writes and cut the file back to its last synced length
+    #[cfg(feature = "test-util")]
+    pub(crate) async fn crash(&self) -> Result<()> {
+        self.run(|writer| {
+            if let Some(faults) = writer.shared.faults() {
+                writer.file.set_len(faults.crash())?;
+            }
+            Ok(())
+        })
+        .await
+    }
+    
+    /// Hand `request` to the writer thread
+    fn send(&self, request: Request) -> Result<()> {
+        self.requests.send(request).map_err(|_| self.writer_stopped())
+    }
+    
+    /// Run `job` on the writer thread once everything sent before it is done
+    async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Writer) -> Result<T> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        self.send(Request::Run(Box::new(move |writer| {
+            let _ = done.send(job(writer));
+        })))?;
+        result.await.map_err(|_| self.writer_stopped())?
+    }
+    
+    fn writer_stopped(&self) -> RustVaultError {
+        RustVaultError::Wal(format!("The writer for WAL {} has stopped", self.shared.path))
+    }
 
     /// Write an entry to the WAL
     pub async fn write_entry(&self, entry: &WalEntry) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        let json = serde_json::to_string(entry)?;
-        writeln!(writer, "{}", json)?;
-        writer.flush()?;
-        Ok(())
+        self.append(&[Record::Entry(entry)]).await
+    }
+    
+    /// Append `records`, resolving once the writer thread has written them
+    ///
+    /// The records are encoded here, on the caller's task; the writer only
+    /// copies bytes. See [`Writer::commit`] for how failures are reported.
+    async fn append(&self, records: &[Record<'_>]) -> Result<()> {
+        if let Some(detail) = self.persistence_error() {
+            return Err(RustVaultError::Persistence(detail));
+        }
+        let formats = *self.shared.formats.lock().unwrap();
+        let mut bytes = Vec::new();
+        for record in records {
+            encode_record(formats.file, &mut bytes, record)?;
+        }
+        let converted = if formats.target == formats.file {
+            None
+        } else {
+            let mut converted = Vec::new();
+            for record in records {
+                encode_record(formats.target, &mut converted, record)?;
+            }
+            Some(converted)
+        };
+        
+        let (done, written) = oneshot::channel();
+        self.send(Request::Append(Append {
+            bytes,
+            entries: records.iter().filter(|record| matches!(record, Record::Entry(_))).count() as u64,
+            format: formats.file,
+            converted,
+            done,
+        }))?;
+        written.await.map_err(|_| self.writer_stopped())?
+    }
+    
+    /// Force everything appended so far to the disk, whatever the policy
+    ///
+    /// Used on shutdown, so even [`SyncPolicy::Never`] leaves a log that
+    /// survives a power failure once the server has stopped.
+    pub async fn sync(&self) -> Result<()> {
+        self.run(Writer::sync).await
+    }
+    
+    /// The current end of the log, for a snapshot of the state it leads to
+    ///
+    /// The caller must hold off appends until it has captured that state,
+    /// as `MemoryStore::snapshot_to` does.
+    pub async fn checkpoint(&self) -> Result<Checkpoint> {
+        self.run(|writer| {
+            let offset = writer.end()?;
+            let tail = tail_digest(&writer.shared.path, offset)?.ok_or_else(|| {
+                RustVaultError::Wal(format!("WAL {} shrank while checkpointing", writer.shared.path))
+            })?;
+            Ok(Checkpoint { offset, tail })
+        })
+        .await
+    }
+    
+    /// Size of the log in bytes, across every segment
+    pub fn size(&self) -> u64 {
+        self.shared.len.load(Ordering::Relaxed)
+    }
+    
+    /// The log's size, and what it has done since it was opened
+    pub fn stats(&self) -> WalStats {
+        WalStats {
+            size: self.size(),
+            entries: self.shared.entries.load(Ordering::Relaxed),
+            last_compaction: Some(self.shared.last_compaction.load(Ordering::Relaxed)).filter(|&millis| millis > 0),
+        }
+    }
+    
+    /// The files the log is made of, oldest first, with their sizes
+    ///
+    /// A log that isn't segmented is a single file. Every segment but the
+    /// last is complete and no longer written, so backup tooling can copy
+    /// those as they are and the last one once the log has moved on.
+    pub fn segments(&self) -> Result<Vec<Segment>> {
+        Ok(log_files(&self.shared.path)?
+            .into_iter()
+            .map(|file| Segment { path: file.path, size: file.len })
+            .collect())
+    }
+    
+    /// The policy appends are synced under
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.shared.sync
+    }
+    
+    /// Why appends are being refused, if they are
+    pub fn persistence_error(&self) -> Option<String> {
+        self.shared.persistence_error()
+    }
+    
+    /// Number of times appends have started being refused
+    pub fn persistence_failures(&self) -> u64 {
+        self.shared.persistence_failures.load(Ordering::Relaxed)
+    }
+    
+    /// Check whether a degraded log can be written again
+    ///
+    /// Writes and syncs a small file next to the log, then removes it. On
+    /// success the log accepts appends again. Returns whether it had been
+    /// degraded.
+    pub async fn probe(&self) -> Result<bool> {
+        if self.persistence_error().is_none() {
+            return Ok(false);
+        }
+        
+        match self.run(Writer::probe).await? {
+            Ok(()) => {
+                self.shared.recover();
+                Ok(true)
+            }
+            Err(e) if is_persistent_failure(&e) => Err(self.shared.degrade(e)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write a group of entries that become durable together or not at all
+    ///
+    /// The entries are wrapped in begin/commit markers and written with a
+    /// single write and flush. On replay, a batch without its commit marker
+    /// is discarded as a whole.
+    pub async fn write_entries(&self, entries: &[WalEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        
+        let mut records = Vec::with_capacity(entries.len() + 2);
+        records.push(Record::Marker(BatchMarker::Begin { count: entries.len() }));
+        records.extend(entries.iter().map(Record::Entry));
+        records.push(Record::Marker(BatchMarker::Commit));
+        self.append(&records).await
     }
 
     /// Log a command to the WAL
@@ -67,73 +1105,608 @@ impl WriteAheadLog {
         self.write_entry(&entry).await
     }
 
+    /// Log several commands to the WAL as one atomic batch
+    pub async fn log_commands(&self, commands: Vec<Command>) -> Result<()> {
+        let entries: Vec<WalEntry> = commands.into_iter().map(WalEntry::new).collect();
+        self.write_entries(&entries).await
+    }
+
     /// Replay all entries from the WAL
-    pub fn replay<F>(&self, mut apply_fn: F) -> Result<()>
+    ///
+    /// An uncommitted trailing batch or a torn final entry is skipped and
+    /// truncated from the file so later appends replay cleanly; corrupt
+    /// entries before the end are handled according to the log's
+    /// [`RecoveryMode`].
+    pub fn replay<F>(&self, apply_fn: F) -> Result<()>
     where
-        F: FnMut(Command) -> Result<()>,
+        F: FnMut(WalEntry) -> Result<()>,
     {
-        if !Path::new(&self.path).exists() {
-            return Ok(());
+        self.replay_with_progress(apply_fn, |_| {})
+    }
+    
+    /// Replay all entries, calling `progress` after each record is read;
+    /// wrap it in [`every_entries`] to hear less often
+    pub fn replay_with_progress<F, P>(&self, apply_fn: F, progress: P) -> Result<()>
+    where
+        F: FnMut(WalEntry) -> Result<()>,
+        P: FnMut(ReplayProgress),
+    {
+        self.replay_from(0, apply_fn, progress)
+    }
+    
+    /// Replay the entries appended after `checkpoint`, as
+    /// [`WriteAheadLog::replay_with_progress`] does for the whole log
+    ///
+    /// Returns `false` without applying anything when the log no longer
+    /// continues from the checkpoint, as after a compaction rewrote it; the
+    /// caller should then start over from an empty state and replay it all.
+    pub fn replay_after<F, P>(&self, checkpoint: Checkpoint, apply_fn: F, progress: P) -> Result<bool>
+    where
+        F: FnMut(WalEntry) -> Result<()>,
+        P: FnMut(ReplayProgress),
+    {
+        if tail_digest(&self.shared.path, checkpoint.offset)? != Some(checkpoint.tail) {
+            return Ok(false);
         }
-
-        let file = File::open(&self.path)?;
-        let reader = BufReader::new(file);
-
-        for line in reader.lines() {
-            let line = line?;
-            if line.trim().is_empty() {
-                continue;
+        self.replay_from(checkpoint.offset, apply_fn, progress)?;
+        Ok(true)
+    }
+    
+    /// Replay from `start`, which runs before anything is appended, so the
+    /// writer thread is idle while a torn tail is cut off
+    fn replay_from<F, P>(&self, start: u64, mut apply_fn: F, progress: P) -> Result<()>
+    where
+        F: FnMut(WalEntry) -> Result<()>,
+        P: FnMut(ReplayProgress),
+    {
+        let torn_tail = read_committed_from(
+            &self.shared.path,
+            start,
+            self.recovery,
+            |_, entry| apply_fn(entry),
+            progress,
+        )?;
+        
+        // Drop what couldn't be replayed so later appends don't follow it
+        if let Some(torn) = torn_tail {
+            match &torn {
+                TornTail::Batch(batch) => eprintln!(
+                    "Discarding uncommitted WAL batch of {} entries at byte {}",
+                    batch.entries, batch.offset
+                ),
+                TornTail::Corrupt { offset, error } => eprintln!(
+                    "Discarding corrupt WAL entry at byte {} and everything after it: {}",
+                    offset, error
+                ),
             }
-
-            let entry: WalEntry = serde_json::from_str(&line)
-                .map_err(|e| RustVaultError::Wal(format!("Failed to parse WAL entry: {}", e)))?;
-            
-            apply_fn(entry.command)?;
+            let offset = torn.offset();
+            let files = log_files(&self.shared.path)?;
+            let Some(at) = files.iter().rposition(|file| file.start <= offset) else {
+                return Ok(());
+            };
+            OpenOptions::new().write(true).open(&files[at].path)?.set_len(offset - files[at].start)?;
+            if at + 1 < files.len() {
+                for later in &files[at + 1..] {
+                    std::fs::remove_file(&later.path)?;
+                }
+                // Later appends go to the segment that is last now
+                self.send(Request::Run(Box::new(|writer| {
+                    if let Err(e) = writer.reopen_last() {
+                        eprintln!("Failed to reopen WAL {} after truncating it: {}", writer.shared.path, e);
+                    }
+                })))?;
+            }
+            self.shared.len.store(offset, Ordering::Relaxed);
         }
 
         Ok(())
     }
 
-    /// Compact the WAL by rewriting it with current state
-    pub async fn compact<F>(&self, get_all_entries: F) -> Result<()>
+    /// Compact the WAL by rewriting it with current state, a page at a time
+    ///
+    /// `next_page` is called with `None`, then with the cursor each page
+    /// returns until one returns none. Appends logged while a page is being
+    /// taken are carried over after it, so it must hold every write logged
+    /// before it was asked for. Every pair becomes a plain `Set`, so TTLs
+    /// and key histories are lost; stores should use
+    /// [`WriteAheadLog::begin_compaction`] and
+    /// [`WriteAheadLog::add_compaction_page`], as `MemoryStore::compact_wal`
+    /// does.
+    pub async fn compact<F, Fut>(&self, mut next_page: F) -> Result<()>
     where
-        F: Fn() -> Vec<(String, String)>,
+        F: FnMut(Option<String>) -> Fut,
+        Fut: Future<Output = Result<(Vec<(String, Vec<u8>)>, Option<String>)>>,
     {
-        // Create a temporary file for the compacted WAL
-        let temp_path = format!("{}.tmp", self.path);
-        let temp_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&temp_path)?;
-        
-        let mut temp_writer = BufWriter::new(temp_file);
-        
-        // Write all current key-value pairs as SET commands
-        for (key, value) in get_all_entries() {
-            let command = Command::Set { key, value };
-            let entry = WalEntry::new(command);
-            let json = serde_json::to_string(&entry)?;
-            writeln!(temp_writer, "{}", json)?;
+        self.begin_compaction()?;
+        let mut cursor = None;
+        loop {
+            let mark = self.shared.compacting.lock().unwrap().as_ref().map_or(0, Vec::len);
+            let (pairs, next) = match next_page(cursor).await {
+                Ok(page) => page,
+                Err(e) => {
+                    self.abort_compaction();
+                    return Err(e);
+                }
+            };
+            let entries: Vec<WalEntry> = pairs
+                .into_iter()
+                .map(|(key, value)| WalEntry::new(Command::Set { key, value, condition: None }))
+                .collect();
+            self.insert_page(Some(mark), &entries)?;
+            self.flush_compaction().await?;
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
         }
+        self.finish_compaction().await
+    }
+    
+    /// Start carrying appends over into a compacted log
+    ///
+    /// Appends keep going to the current log as usual; from now on they are
+    /// also kept aside for the compacted log, in the order they are logged,
+    /// and [`WriteAheadLog::add_compaction_page`] adds the live data among
+    /// them. Fails if a compaction is already running.
+    pub fn begin_compaction(&self) -> Result<()> {
+        let mut compacting = self.shared.compacting.lock().unwrap();
+        if compacting.is_some() {
+            return Err(RustVaultError::CompactionInProgress);
+        }
+        *compacting = Some(Vec::new());
+        Ok(())
+    }
+    
+    /// Add `entries` to the compacted log, after every append carried over
+    /// so far
+    ///
+    /// Replaying the compacted log applies the page between the appends
+    /// logged before and after this call, so it must be taken with writes
+    /// quiet: every entry logged before it applied, and none logged while
+    /// it is copied. Entries are written as they are, timestamps and
+    /// histories included. Memory is only given back once the page is
+    /// flushed, by [`WriteAheadLog::flush_compaction`] or
+    /// [`WriteAheadLog::finish_compaction`].
+    pub fn add_compaction_page(&self, entries: &[WalEntry]) -> Result<()> {
+        self.insert_page(None, entries)
+    }
+    
+    /// Encode `entries` into the carried-over appends at `mark`, or at the
+    /// end
+    fn insert_page(&self, mark: Option<usize>, entries: &[WalEntry]) -> Result<()> {
+        let format = self.shared.formats.lock().unwrap().target;
+        let mut bytes = Vec::new();
+        for entry in entries {
+            if let Err(e) = encode_record(format, &mut bytes, &Record::Entry(entry)) {
+                self.abort_compaction();
+                return Err(e);
+            }
+        }
+        let mut compacting = self.shared.compacting.lock().unwrap();
+        let Some(carried) = compacting.as_mut() else {
+            return Err(RustVaultError::Wal("No compaction is running".to_string()));
+        };
+        let mark = mark.unwrap_or(carried.len());
+        carried.splice(mark..mark, bytes);
+        Ok(())
+    }
+    
+    /// Write what the compacted log has gathered so far to its file, on a
+    /// blocking thread while appends carry on
+    pub async fn flush_compaction(&self) -> Result<()> {
+        let bytes = match self.shared.compacting.lock().unwrap().as_mut() {
+            Some(carried) => std::mem::take(carried),
+            None => return Err(RustVaultError::Wal("No compaction is running".to_string())),
+        };
+        let shared = Arc::clone(&self.shared);
+        let result = tokio::task::spawn_blocking(move || shared.write_compacted(&bytes))
+            .await
+            .map_err(|e| RustVaultError::Wal(format!("WAL compaction task failed: {}", e)))
+            .and_then(|result| result);
+        if result.is_err() {
+            self.abort_compaction();
+        }
+        result
+    }
+    
+    /// Replace the log with the compacted one
+    ///
+    /// The writer thread only holds appends up for the final copy of those
+    /// carried over since the last flush and the rename. Replaying the
+    /// compacted log ends in the same state as replaying the old one, since
+    /// every entry in it is applied in the order it was logged. On failure
+    /// the old log stays in place.
+    pub async fn finish_compaction(&self) -> Result<()> {
+        let result = self.swap_compacted().await;
+        if result.is_err() {
+            self.abort_compaction();
+        }
+        result
+    }
+    
+    async fn swap_compacted(&self) -> Result<()> {
+        let shared = Arc::clone(&self.shared);
+        let temp_writer = tokio::task::spawn_blocking(move || -> Result<BufWriter<File>> {
+            // A log with nothing to carry over still gets a header
+            shared.write_compacted(&[])?;
+            let mut temp_writer = shared.compacted.lock().unwrap().take().expect("the compacted log was just written");
+            temp_writer.flush()?;
+            Ok(temp_writer)
+        })
+        .await
+        .map_err(|e| RustVaultError::Wal(format!("WAL compaction task failed: {}", e)))??;
         
-        temp_writer.flush()?;
-        drop(temp_writer);
-        
-        // Replace the original WAL with the compacted version
-        std::fs::rename(&temp_path, &self.path)?;
-        
-        // Reopen the writer
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)?;
-        
-        let new_writer = BufWriter::new(file);
-        *self.writer.lock().await = new_writer;
+        let temp_path = self.shared.compaction_path();
+        self.run(move |writer| writer.swap(temp_writer, &temp_path)).await?;
         
+        // The rewrite needed disk space too, so the log has room again
+        self.shared.recover();
+        self.shared.last_compaction.store(now_millis(), Ordering::Relaxed);
         Ok(())
     }
+    
+    /// Give up on the running compaction, leaving the log as it was
+    fn abort_compaction(&self) {
+        self.shared.compacting.lock().unwrap().take();
+        self.shared.compacted.lock().unwrap().take();
+        let _ = std::fs::remove_file(self.shared.compaction_path());
+    }
+}
+
+impl Drop for WriteAheadLog {
+    /// Stop the writer thread once it has written what it was sent, and the
+    /// background sync task, syncing whatever that hadn't got to
+    fn drop(&mut self) {
+        if let Some(task) = &self.syncer_task {
+            task.abort();
+        }
+        let _ = self.requests.send(Request::Stop);
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                eprintln!("The writer for WAL {} panicked", self.shared.path);
+            }
+        }
+        if let Some(syncer) = &self.shared.syncer {
+            if let Err(e) = syncer.sync_if_dirty() {
+                eprintln!("Failed to sync WAL {} on close: {}", self.shared.path, e);
+            }
+        }
+    }
+}
+
+/// Whether a failed write will keep failing until an operator steps in
+fn is_persistent_failure(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+/// A position in the log, recorded alongside a snapshot of the state the
+/// entries before it lead to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Length of the log when the snapshot was taken
+    pub offset: u64,
+    /// Digest of the bytes just before `offset`, telling the same log apart
+    /// from one rewritten since
+    pub tail: u64,
+}
+
+impl Checkpoint {
+    /// The start of an empty log
+    pub const START: Checkpoint = Checkpoint {
+        offset: 0,
+        tail: crate::store::FNV_OFFSET,
+    };
+}
+
+/// Digest of the last [`CHECKPOINT_TAIL`] bytes before `offset` in the log
+/// at `path`, or `None` if the log is shorter than that
+///
+/// The bytes may come from more than one segment.
+fn tail_digest(path: &str, offset: u64) -> io::Result<Option<u64>> {
+    let files = log_files(path)?;
+    let Some(last) = files.last() else {
+        return Ok((offset == 0).then_some(Checkpoint::START.tail));
+    };
+    if last.end() < offset {
+        return Ok(None);
+    }
+    let start = offset.saturating_sub(CHECKPOINT_TAIL);
+    let mut tail = Vec::with_capacity((offset - start) as usize);
+    for file in &files {
+        let (from, to) = (start.max(file.start), offset.min(file.end()));
+        if from >= to {
+            continue;
+        }
+        let mut reader = File::open(&file.path)?;
+        reader.seek(SeekFrom::Start(from - file.start))?;
+        reader.take(to - from).read_to_end(&mut tail)?;
+    }
+    if tail.len() as u64 != offset - start {
+        // A segment shrank while it was read
+        return Ok(None);
+    }
+    Ok(Some(crate::store::fnv1a(crate::store::FNV_OFFSET, &tail)))
+}
+
+/// Whether the segment at `path` holds nothing, or only the start of a
+/// binary header
+fn is_unwritten(path: &Path) -> io::Result<bool> {
+    let len = std::fs::metadata(path)?.len();
+    if len >= BINARY_MAGIC.len() as u64 {
+        return Ok(false);
+    }
+    let mut prefix = Vec::new();
+    File::open(path)?.read_to_end(&mut prefix)?;
+    Ok(len == 0 || WalFormat::detect(&prefix) == WalFormat::Binary)
+}
+
+/// Location of a batch that was started but never committed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TornBatch {
+    /// Byte offset of the batch's begin marker
+    pub offset: u64,
+    /// Number of entries of the batch found before the end of the file
+    pub entries: usize,
+}
+
+/// The end of a WAL that replay stopped short of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TornTail {
+    /// A batch that was started but never committed
+    Batch(TornBatch),
+    /// An entry that couldn't be parsed, and anything after it
+    Corrupt {
+        /// Byte offset of the entry, or of the begin marker of the batch
+        /// it was part of
+        offset: u64,
+        /// Why the entry couldn't be parsed
+        error: String,
+    },
+}
+
+impl TornTail {
+    /// Byte offset of the first byte not replayed
+    pub fn offset(&self) -> u64 {
+        match self {
+            TornTail::Batch(batch) => batch.offset,
+            TornTail::Corrupt { offset, .. } => *offset,
+        }
+    }
+}
+
+/// How far a replay has got, as reported to its progress callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayProgress {
+    /// Entries applied so far
+    pub entries: u64,
+    /// Bytes of the log read so far, counted from the start of its first
+    /// file
+    pub bytes_read: u64,
+    /// Size of the log when the replay started
+    pub total_bytes: u64,
+}
+
+impl ReplayProgress {
+    /// Percentage of the log read so far
+    pub fn percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+        (self.bytes_read as f64 * 100.0 / self.total_bytes as f64).min(100.0)
+    }
+}
+
+/// A progress callback that passes `report` one update each time another
+/// `interval` entries have been applied
+///
+/// A batch applied all at once counts as one update however many intervals
+/// it crosses. An `interval` of 0 never reports.
+pub fn every_entries<P>(interval: u64, mut report: P) -> impl FnMut(ReplayProgress)
+where
+    P: FnMut(ReplayProgress),
+{
+    let mut last = 0u64;
+    move |progress: ReplayProgress| {
+        if progress.entries.checked_div(interval) > last.checked_div(interval) {
+            report(progress);
+        }
+        last = progress.entries;
+    }
+}
+
+/// Read every committed entry from the WAL file at `path` without modifying it
+///
+/// `apply_fn` receives each entry with its 1-based sequence number in replay
+/// order. Batches are delivered only once their commit marker has been read.
+/// An uncommitted trailing batch or a torn final entry is skipped and
+/// reported in the return value; corruption before the end is an error, as
+/// under [`RecoveryMode::Strict`].
+pub fn read_committed<P, F>(path: P, apply_fn: F) -> Result<Option<TornTail>>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64, WalEntry) -> Result<()>,
+{
+    read_committed_with_progress(path, apply_fn, |_| {})
+}
+
+/// Like [`read_committed`], calling `progress` after each record is handled
+pub fn read_committed_with_progress<P, F, G>(
+    path: P,
+    apply_fn: F,
+    progress: G,
+) -> Result<Option<TornTail>>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64, WalEntry) -> Result<()>,
+    G: FnMut(ReplayProgress),
+{
+    read_committed_from(path, 0, RecoveryMode::Strict, apply_fn, progress)
+}
+
+/// Like [`read_committed_with_progress`], starting at byte `start` and
+/// handling corrupt entries according to `mode`
+///
+/// `start` must be the end of a record outside any batch, such as a
+/// [`Checkpoint`] offset. Sequence numbers count from the first entry read.
+/// A segmented log is read a segment at a time, in order; a torn or corrupt
+/// end to a segment other than the last has entries after it, so it is an
+/// error under [`RecoveryMode::Strict`].
+pub fn read_committed_from<P, F, G>(
+    path: P,
+    start: u64,
+    mode: RecoveryMode,
+    mut apply_fn: F,
+    mut progress: G,
+) -> Result<Option<TornTail>>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64, WalEntry) -> Result<()>,
+    G: FnMut(ReplayProgress),
+{
+    let files = log_files(&path.as_ref().to_string_lossy())?;
+    let total = files.last().map_or(0, LogFile::end);
+    let mut seq = 0u64;
+    for (index, file) in files.iter().enumerate() {
+        let last = index + 1 == files.len();
+        if file.end() <= start && !last {
+            continue;
+        }
+        let torn = read_file_from(
+            file,
+            start.saturating_sub(file.start),
+            mode,
+            &mut seq,
+            &mut apply_fn,
+            &mut |entries, bytes_read| {
+                progress(ReplayProgress {
+                    entries,
+                    bytes_read,
+                    total_bytes: total,
+                })
+            },
+        )?;
+        match torn {
+            Some(torn) if !last && mode == RecoveryMode::Strict => {
+                return Err(RustVaultError::Wal(format!(
+                    "WAL segment {} is cut short at byte {}, with more segments after it",
+                    file.path.display(),
+                    torn.offset()
+                )));
+            }
+            Some(torn) => return Ok(Some(torn)),
+            None => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Read the committed entries of one of a log's files from `start`, a
+/// byte offset into it, numbering them on from `seq`
+///
+/// `progress` is passed the entries applied so far and the offset reached
+/// after each record. Offsets passed to it and reported in errors and the
+/// torn tail are the log's, counted from the start of its first file.
+fn read_file_from<F, G>(
+    log_file: &LogFile,
+    start: u64,
+    mode: RecoveryMode,
+    seq: &mut u64,
+    apply_fn: &mut F,
+    progress: &mut G,
+) -> Result<Option<TornTail>>
+where
+    F: FnMut(u64, WalEntry) -> Result<()>,
+    G: FnMut(u64, u64),
+{
+    let base = log_file.start;
+    let mut file = File::open(&log_file.path)?;
+    let mut prefix = Vec::new();
+    (&mut file).take(BINARY_MAGIC.len() as u64).read_to_end(&mut prefix)?;
+    let format = WalFormat::detect(&prefix);
+    let start = start.max(format.header().len() as u64);
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = RecordReader::new(BufReader::new(file), format, start);
+    
+    // Entries of the batch currently being read, with the offset of its begin marker
+    let mut pending: Option<(u64, usize, Vec<WalEntry>)> = None;
+
+    while let Some((line_start, record)) = reader.next_record()? {
+        let line_start = base + line_start;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let last = reader.at_end()?;
+                if !last && mode == RecoveryMode::Strict {
+                    return Err(RustVaultError::Wal(format!(
+                        "Corrupt WAL entry at byte {} with more entries after it: {}",
+                        line_start, e
+                    )));
+                }
+                return Ok(Some(match pending {
+                    // A torn final line inside a batch just means the batch
+                    // never committed
+                    Some((begin, _, entries)) if last => TornTail::Batch(TornBatch {
+                        offset: begin,
+                        entries: entries.len(),
+                    }),
+                    pending => TornTail::Corrupt {
+                        offset: pending.map_or(line_start, |(begin, _, _)| begin),
+                        error: e,
+                    },
+                }));
+            }
+        };
+        
+        match record {
+            WalRecord::Entry(entry) => match &mut pending {
+                Some((_, _, entries)) => entries.push(entry),
+                None => {
+                    *seq += 1;
+                    apply_fn(*seq, entry)?;
+                }
+            },
+            WalRecord::Marker { batch: BatchMarker::Begin { count }, .. } => {
+                if pending.is_some() {
+                    return Err(RustVaultError::Wal(format!(
+                        "Nested WAL batch at byte {}",
+                        line_start
+                    )));
+                }
+                pending = Some((line_start, count, Vec::with_capacity(count)));
+            }
+            WalRecord::Marker { batch: BatchMarker::Commit, .. } => {
+                let (begin, count, entries) = pending.take().ok_or_else(|| {
+                    RustVaultError::Wal(format!(
+                        "WAL commit marker without a batch at byte {}",
+                        line_start
+                    ))
+                })?;
+                if entries.len() != count {
+                    return Err(RustVaultError::Wal(format!(
+                        "WAL batch at byte {} has {} entries, expected {}",
+                        begin,
+                        entries.len(),
+                        count
+                    )));
+                }
+                for entry in entries {
+                    *seq += 1;
+                    apply_fn(*seq, entry)?;
+                }
+            }
+        }
+        progress(*seq, base + reader.offset());
+    }
+
+    Ok(pending.map(|(offset, _, entries)| {
+        TornTail::Batch(TornBatch {
+            offset,
+            entries: entries.len(),
+        })
+    }))
 }
 
 #[cfg(test)]
@@ -144,12 +1717,13 @@ mod tests {
     #[tokio::test]
     async fn test_wal_write_and_replay() {
         let temp_file = NamedTempFile::new().unwrap();
-        let wal = WriteAheadLog::new(temp_file.path()).unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
         
         // Write some commands
         let cmd1 = Command::Set {
             key: "key1".to_string(),
-            value: "value1".to_string(),
+            value: b"value1".to_vec(),
+            condition: None,
         };
         let cmd2 = Command::Get {
             key: "key1".to_string(),
@@ -160,8 +1734,8 @@ mod tests {
         
         // Replay commands
         let mut replayed_commands = Vec::new();
-        wal.replay(|cmd| {
-            replayed_commands.push(cmd);
+        wal.replay(|entry| {
+            replayed_commands.push(entry.command);
             Ok(())
         }).unwrap();
         
@@ -169,4 +1743,685 @@ mod tests {
         assert_eq!(replayed_commands[0], cmd1);
         assert_eq!(replayed_commands[1], cmd2);
     }
+
+    fn set_command(key: &str, value: &str) -> Command {
+        Command::Set {
+            key: key.to_string(),
+            value: value.as_bytes().to_vec(),
+            condition: None,
+        }
+    }
+
+    fn replay_all(wal: &WriteAheadLog) -> Vec<Command> {
+        let mut replayed_commands = Vec::new();
+        wal.replay(|entry| {
+            replayed_commands.push(entry.command);
+            Ok(())
+        }).unwrap();
+        replayed_commands
+    }
+
+    #[tokio::test]
+    async fn test_wal_write_entries_batch() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        wal.log_commands(vec![
+            set_command("key2", "value2"),
+            Command::Delete { key: "key1".to_string() },
+        ]).await.unwrap();
+        wal.log_commands(Vec::new()).await.unwrap();
+        
+        let replayed_commands = replay_all(&wal);
+        assert_eq!(replayed_commands.len(), 3);
+        assert_eq!(replayed_commands[1], set_command("key2", "value2"));
+        assert_eq!(replayed_commands[2], Command::Delete { key: "key1".to_string() });
+    }
+
+    #[tokio::test]
+    async fn test_wal_replay_drops_uncommitted_batch() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        wal.log_commands(vec![
+            set_command("key2", "value2"),
+            set_command("key3", "value3"),
+        ]).await.unwrap();
+        
+        // Simulate a crash after the begin marker and first entry hit the disk
+        let contents = std::fs::read_to_string(temp_file.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 5);
+        let keep: usize = lines[..3].iter().map(|l| l.len() + 1).sum();
+        let file = OpenOptions::new().write(true).open(temp_file.path()).unwrap();
+        file.set_len(keep as u64).unwrap();
+        
+        let replayed_commands = replay_all(&wal);
+        assert_eq!(replayed_commands, vec![set_command("key1", "value1")]);
+        
+        // The torn batch is gone, so new appends replay normally
+        wal.log_command(set_command("key4", "value4")).await.unwrap();
+        let replayed_commands = replay_all(&wal);
+        assert_eq!(
+            replayed_commands,
+            vec![set_command("key1", "value1"), set_command("key4", "value4")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wal_replay_drops_torn_batch_line() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        wal.log_commands(vec![set_command("key2", "value2")]).await.unwrap();
+        
+        // Cut the file in the middle of the batched entry
+        let contents = std::fs::read_to_string(temp_file.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        let keep = lines[0].len() + 1 + lines[1].len() + 1 + lines[2].len() / 2;
+        let file = OpenOptions::new().write(true).open(temp_file.path()).unwrap();
+        file.set_len(keep as u64).unwrap();
+        
+        let replayed_commands = replay_all(&wal);
+        assert_eq!(replayed_commands, vec![set_command("key1", "value1")]);
+    }
+
+    #[tokio::test]
+    async fn test_wal_replay_reports_progress() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        
+        for i in 0..10 {
+            wal.log_command(set_command(&format!("key{}", i), "value")).await.unwrap();
+        }
+        let size = std::fs::metadata(temp_file.path()).unwrap().len();
+        
+        let mut reports = Vec::new();
+        let mut replayed = 0;
+        wal.replay_with_progress(
+            |_| {
+                replayed += 1;
+                Ok(())
+            },
+            |progress| reports.push(progress),
+        ).unwrap();
+        
+        assert_eq!(replayed, 10);
+        assert_eq!(reports.len(), 10);
+        assert!(reports.windows(2).all(|w| w[0].bytes_read < w[1].bytes_read));
+        assert!(reports.iter().all(|progress| progress.total_bytes == size));
+        assert_eq!(
+            reports.last(),
+            Some(&ReplayProgress { entries: 10, bytes_read: size, total_bytes: size })
+        );
+        assert_eq!(reports.last().unwrap().percent(), 100.0);
+    }
+    
+    #[tokio::test]
+    async fn test_wal_replay_reports_every_interval() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let mut lines = Vec::new();
+        for i in 0..100_000 {
+            serde_json::to_writer(&mut lines, &WalEntry::new(set_command(&format!("key{}", i), "value"))).unwrap();
+            lines.push(b'\n');
+        }
+        std::fs::write(temp_file.path(), lines).unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        
+        let mut reports = Vec::new();
+        wal.replay_with_progress(|_| Ok(()), every_entries(1000, |progress| reports.push(progress)))
+            .unwrap();
+        assert_eq!(reports.len(), 100);
+        assert!(reports.iter().enumerate().all(|(i, progress)| progress.entries == (i as u64 + 1) * 1000));
+        assert!(reports.windows(2).all(|w| w[0].percent() < w[1].percent()));
+        assert_eq!(reports.last().unwrap().percent(), 100.0);
+        
+        // A batch counts once, however many intervals it spans
+        wal.log_commands(vec![set_command("a", "1"), set_command("b", "2"), set_command("c", "3")])
+            .await
+            .unwrap();
+        let mut reports = 0;
+        wal.replay_with_progress(|_| Ok(()), every_entries(1, |_| reports += 1)).unwrap();
+        assert_eq!(reports, 100_001);
+        wal.replay_with_progress(|_| Ok(()), every_entries(0, |_| panic!("never reports"))).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_always_sync_survives_crash() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Always).unwrap();
+        wal.log_command(set_command("a", "1")).await.unwrap();
+        wal.log_commands(vec![set_command("b", "2"), set_command("c", "3")]).await.unwrap();
+        
+        // Lose the WAL without running any of its cleanup
+        std::mem::forget(wal);
+        
+        let reopened = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        assert_eq!(
+            replay_all(&reopened),
+            vec![set_command("a", "1"), set_command("b", "2"), set_command("c", "3")]
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_periodic_sync_runs_in_background() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::EveryMillis(10)).unwrap();
+        let syncer = Arc::clone(wal.shared.syncer.as_ref().unwrap());
+        
+        wal.log_command(set_command("a", "1")).await.unwrap();
+        assert!(syncer.dirty.load(Ordering::Acquire));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while syncer.dirty.load(Ordering::Acquire) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("background sync should run");
+        
+        // Dropping the log ends the task
+        drop(wal);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(Arc::strong_count(&syncer), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_sync_settles_pending_background_sync() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::EveryMillis(60_000)).unwrap();
+        let syncer = Arc::clone(wal.shared.syncer.as_ref().unwrap());
+        
+        wal.log_command(set_command("a", "1")).await.unwrap();
+        assert!(syncer.dirty.load(Ordering::Acquire));
+        wal.sync().await.unwrap();
+        assert!(!syncer.dirty.load(Ordering::Acquire));
+        assert_eq!(replay_all(&wal), vec![set_command("a", "1")]);
+    }
+    
+    #[test]
+    fn test_periodic_sync_needs_runtime() {
+        let temp_file = NamedTempFile::new().unwrap();
+        assert!(WriteAheadLog::new(temp_file.path(), SyncPolicy::EveryMillis(10)).is_err());
+        assert!(WriteAheadLog::new(temp_file.path(), SyncPolicy::Always).is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_replay_after_checkpoint() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        assert_eq!(wal.checkpoint().await.unwrap(), Checkpoint::START);
+        
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        let checkpoint = wal.checkpoint().await.unwrap();
+        assert_eq!(checkpoint.offset, wal.size());
+        wal.log_commands(vec![set_command("key2", "value2"), set_command("key3", "value3")])
+            .await
+            .unwrap();
+        
+        let mut replayed = Vec::new();
+        let continued = wal
+            .replay_after(checkpoint, |entry| {
+                replayed.push(entry.command);
+                Ok(())
+            }, |_| {})
+            .unwrap();
+        assert!(continued);
+        assert_eq!(replayed, vec![set_command("key2", "value2"), set_command("key3", "value3")]);
+        
+        // A rewritten log no longer continues from the checkpoint
+        wal.compact(|_| async { Ok((vec![("key1".to_string(), b"other".to_vec())], None)) }).await.unwrap();
+        let continued = wal
+            .replay_after(checkpoint, |_| panic!("nothing should be replayed"), |_| {})
+            .unwrap();
+        assert!(!continued);
+    }
+    
+    fn append_bytes(path: &Path, bytes: &[u8]) {
+        OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_wal_replay_truncates_corrupt_final_entry() {
+        let truncated_entry = serde_json::to_vec(&WalEntry::new(set_command("key2", "value2"))).unwrap();
+        let tails: [&[u8]; 3] = [
+            b"\x00\xff\x13 not a wal entry",
+            &truncated_entry[..truncated_entry.len() / 2],
+            b"{\"timestamp\":1,\"comm\n\n",
+        ];
+        for tail in tails {
+            let temp_file = NamedTempFile::new().unwrap();
+            let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+            wal.log_command(set_command("key1", "value1")).await.unwrap();
+            let good_len = wal.size();
+            append_bytes(temp_file.path(), tail);
+            
+            assert_eq!(replay_all(&wal), vec![set_command("key1", "value1")]);
+            assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len(), good_len);
+            assert_eq!(wal.size(), good_len);
+            
+            // Appends after the cut replay normally
+            wal.log_command(set_command("key3", "value3")).await.unwrap();
+            assert_eq!(
+                replay_all(&wal),
+                vec![set_command("key1", "value1"), set_command("key3", "value3")]
+            );
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wal_replay_corruption_before_the_end() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        let good_len = wal.size();
+        append_bytes(temp_file.path(), b"garbage\n");
+        wal.log_command(set_command("key2", "value2")).await.unwrap();
+        let corrupt_len = std::fs::metadata(temp_file.path()).unwrap().len();
+        
+        // Strict refuses and leaves the file alone
+        let err = wal.replay(|_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains(&format!("byte {}", good_len)), "{}", err);
+        assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len(), corrupt_len);
+        
+        // TruncateCorrupt keeps what came before the corruption
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never)
+            .unwrap()
+            .with_recovery_mode(RecoveryMode::TruncateCorrupt);
+        assert_eq!(replay_all(&wal), vec![set_command("key1", "value1")]);
+        assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len(), good_len);
+    }
+    
+    /// Flip one bit of `value` where it is stored in the log at `path`
+    fn flip_value_bit(path: &Path, value: &str) {
+        let mut bytes = std::fs::read(path).unwrap();
+        let at = bytes
+            .windows(value.len())
+            .position(|window| window == value.as_bytes())
+            .unwrap();
+        bytes[at] ^= 0x01;
+        std::fs::write(path, bytes).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_checksum_catches_flipped_value_byte() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        wal.log_command(set_command("key2", "flipped")).await.unwrap();
+        let good_len = wal.size();
+        wal.log_command(set_command("key3", "last")).await.unwrap();
+        
+        // "flipped" becomes "glipped", which still parses as JSON
+        flip_value_bit(temp_file.path(), "flipped");
+        let err = wal.replay(|_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        
+        // In the final entry it is cut off like a torn write
+        flip_value_bit(temp_file.path(), "glipped");
+        flip_value_bit(temp_file.path(), "last");
+        assert_eq!(
+            replay_all(&wal),
+            vec![set_command("key1", "value1"), set_command("key2", "flipped")]
+        );
+        assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len(), good_len);
+    }
+    
+    #[tokio::test]
+    async fn test_replays_entries_without_checksums() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let old_entry = serde_json::to_string(&WalEntry::new(set_command("old", "value"))).unwrap();
+        let old_batch = [
+            serde_json::to_string(&WalRecord::marker(BatchMarker::Begin { count: 1 })).unwrap(),
+            serde_json::to_string(&WalEntry::new(set_command("batched", "value"))).unwrap(),
+            serde_json::to_string(&WalRecord::marker(BatchMarker::Commit)).unwrap(),
+        ];
+        std::fs::write(temp_file.path(), format!("{}\n{}\n", old_entry, old_batch.join("\n"))).unwrap();
+        
+        // A log from before checksums keeps working, and new appends to it
+        // carry one
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        wal.log_command(set_command("new", "value")).await.unwrap();
+        assert_eq!(
+            replay_all(&wal),
+            vec![
+                set_command("old", "value"),
+                set_command("batched", "value"),
+                set_command("new", "value"),
+            ]
+        );
+        let contents = std::fs::read_to_string(temp_file.path()).unwrap();
+        let last = contents.lines().last().unwrap();
+        assert_eq!(last.find(' '), Some(8));
+        assert!(last[9..].starts_with('{'));
+    }
+    
+    fn binary_wal(path: &Path) -> WriteAheadLog {
+        WriteAheadLog::new(path, SyncPolicy::Never).unwrap().with_format(WalFormat::Binary).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_binary_wal_replays_in_fresh_instance() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let commands = vec![
+            set_command("key1", "value1"),
+            Command::Set { key: "binary".to_string(), value: vec![0, 255, b'\n', b'\r'], condition: None },
+            Command::ExpireAt { key: "key1".to_string(), unix_millis: 1_700_000_000_000 },
+            Command::Delete { key: "binary".to_string() },
+        ];
+        {
+            let wal = binary_wal(temp_file.path());
+            assert_eq!(wal.format(), WalFormat::Binary);
+            wal.log_command(commands[0].clone()).await.unwrap();
+            wal.log_commands(commands[1..3].to_vec()).await.unwrap();
+            wal.log_command(commands[3].clone()).await.unwrap();
+        }
+        assert!(std::fs::read(temp_file.path()).unwrap().starts_with(BINARY_MAGIC));
+        
+        // Detected from the header, whatever the new instance is configured with
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        assert_eq!(wal.format(), WalFormat::Binary);
+        assert_eq!(replay_all(&wal), commands);
+        let mut read = Vec::new();
+        read_committed(temp_file.path(), |_, entry| {
+            read.push(entry.command);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(read, commands);
+    }
+    
+    #[tokio::test]
+    async fn test_binary_wal_truncates_torn_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = binary_wal(temp_file.path());
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        let good_len = wal.size();
+        wal.log_commands(vec![set_command("key2", "value2")]).await.unwrap();
+        let full_len = wal.size();
+        OpenOptions::new().write(true).open(temp_file.path()).unwrap().set_len(full_len - 3).unwrap();
+        
+        assert_eq!(replay_all(&wal), vec![set_command("key1", "value1")]);
+        assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len(), good_len);
+        wal.log_command(set_command("key3", "value3")).await.unwrap();
+        assert_eq!(
+            replay_all(&wal),
+            vec![set_command("key1", "value1"), set_command("key3", "value3")]
+        );
+        
+        // A flipped value byte is caught by the record's checksum
+        flip_value_bit(temp_file.path(), "value1");
+        assert!(wal.replay(|_| Ok(())).unwrap_err().to_string().contains("checksum mismatch"));
+    }
+    
+    #[tokio::test]
+    async fn test_compaction_switches_format() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        drop(wal);
+        
+        // An existing JSON log keeps taking JSON appends until compacted
+        let wal = binary_wal(temp_file.path());
+        assert_eq!(wal.format(), WalFormat::Json);
+        wal.log_command(set_command("key2", "value2")).await.unwrap();
+        
+        wal.begin_compaction().unwrap();
+        let snapshot = vec![WalEntry::new(set_command("key1", "value1")), WalEntry::new(set_command("key2", "value2"))];
+        wal.add_compaction_page(&snapshot).unwrap();
+        wal.log_command(set_command("carried", "value")).await.unwrap();
+        wal.finish_compaction().await.unwrap();
+        assert_eq!(wal.format(), WalFormat::Binary);
+        assert!(std::fs::read(temp_file.path()).unwrap().starts_with(BINARY_MAGIC));
+        wal.log_command(set_command("key3", "value3")).await.unwrap();
+        
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        assert_eq!(
+            replay_all(&wal),
+            vec![
+                set_command("key1", "value1"),
+                set_command("key2", "value2"),
+                set_command("carried", "value"),
+                set_command("key3", "value3"),
+            ]
+        );
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_writers_each_logged_once_in_order() {
+        const WRITERS: usize = 100;
+        const ENTRIES: usize = 20;
+        
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = Arc::new(WriteAheadLog::new(temp_file.path(), SyncPolicy::Always).unwrap());
+        let tasks: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let wal = Arc::clone(&wal);
+                tokio::spawn(async move {
+                    for entry in 0..ENTRIES {
+                        let command = set_command(&format!("w{}", writer), &entry.to_string());
+                        wal.log_command(command).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        
+        // Every entry once, and each writer's in the order it logged them
+        let check = |commands: &[Command]| {
+            assert_eq!(commands.len(), WRITERS * ENTRIES);
+            let mut next = vec![0; WRITERS];
+            for command in commands {
+                let Command::Set { key, value, .. } = command else {
+                    panic!("unexpected command {:?}", command);
+                };
+                let writer: usize = key[1..].parse().unwrap();
+                assert_eq!(value, next[writer].to_string().as_bytes());
+                next[writer] += 1;
+            }
+            assert!(next.iter().all(|&logged| logged == ENTRIES));
+        };
+        let logged = replay_all(&wal);
+        check(&logged);
+        drop(wal);
+        
+        let reopened = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        assert_eq!(replay_all(&reopened), logged);
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_group_delay_logs_every_writer_once() {
+        const WRITERS: usize = 50;
+        
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Always)
+            .unwrap()
+            .with_group_delay(Duration::from_millis(1));
+        let wal = Arc::new(wal);
+        let tasks: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let wal = Arc::clone(&wal);
+                tokio::spawn(async move { wal.log_command(set_command(&format!("w{}", writer), "v")).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        
+        // A lone append after the rush waits out the delay on its own
+        wal.log_command(set_command("last", "v")).await.unwrap();
+        drop(wal);
+        
+        let reopened = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        let mut keys: Vec<String> = replay_all(&reopened)
+            .into_iter()
+            .map(|command| match command {
+                Command::Set { key, .. } => key,
+                command => panic!("unexpected command {:?}", command),
+            })
+            .collect();
+        assert_eq!(keys.pop().as_deref(), Some("last"));
+        keys.sort();
+        let mut expected: Vec<String> = (0..WRITERS).map(|writer| format!("w{}", writer)).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+    
+    #[tokio::test]
+    async fn test_segmented_wal_rolls_and_replays_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.log");
+        let wal = WriteAheadLog::segmented(&path, SyncPolicy::Never, 200).unwrap();
+        let commands: Vec<Command> = (0..20).map(|i| set_command(&format!("key{}", i), "value")).collect();
+        for command in &commands[..10] {
+            wal.log_command(command.clone()).await.unwrap();
+        }
+        let checkpoint = wal.checkpoint().await.unwrap();
+        for command in &commands[10..] {
+            wal.log_command(command.clone()).await.unwrap();
+        }
+        
+        let segments = wal.segments().unwrap();
+        assert!(segments.len() >= 3, "expected at least 3 segments, got {:?}", segments);
+        assert!(segments[0].path.ends_with("vault.log.000001"));
+        assert_eq!(segments.iter().map(|segment| segment.size).sum::<u64>(), wal.size());
+        assert!(!path.exists());
+        drop(wal);
+        
+        // A restart picks up the same segments and offsets
+        let wal = WriteAheadLog::segmented(&path, SyncPolicy::Never, 200).unwrap();
+        assert_eq!(replay_all(&wal), commands);
+        let mut replayed = Vec::new();
+        assert!(wal
+            .replay_after(checkpoint, |entry| {
+                replayed.push(entry.command);
+                Ok(())
+            }, |_| {})
+            .unwrap());
+        assert_eq!(replayed, commands[10..]);
+    }
+    
+    #[tokio::test]
+    async fn test_segmented_wal_drops_segment_left_empty_by_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.log");
+        let base = path.to_string_lossy().to_string();
+        
+        // An existing single-file log becomes the first segment
+        let wal = WriteAheadLog::new(&path, SyncPolicy::Never).unwrap();
+        wal.log_command(set_command("key1", "value1")).await.unwrap();
+        drop(wal);
+        let wal = WriteAheadLog::segmented(&path, SyncPolicy::Never, 1 << 20).unwrap();
+        assert!(!path.exists());
+        drop(wal);
+        
+        // Crashes after creating the next segment, before anything was written to it
+        std::fs::write(segment_path(&base, 2), b"").unwrap();
+        std::fs::write(segment_path(&base, 3), &BINARY_MAGIC[..3]).unwrap();
+        let wal = WriteAheadLog::segmented(&path, SyncPolicy::Never, 1 << 20).unwrap();
+        assert_eq!(wal.segments().unwrap().len(), 1);
+        wal.log_command(set_command("key2", "value2")).await.unwrap();
+        assert_eq!(replay_all(&wal), vec![set_command("key1", "value1"), set_command("key2", "value2")]);
+        
+        // A segmented log can't be opened as a single file
+        assert!(WriteAheadLog::new(&path, SyncPolicy::Never).is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_segmented_wal_truncates_torn_tail_of_last_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.log");
+        let wal = WriteAheadLog::segmented(&path, SyncPolicy::Never, 100).unwrap();
+        for i in 0..5 {
+            wal.log_command(set_command(&format!("key{}", i), "value")).await.unwrap();
+        }
+        let good_len = wal.size();
+        let last = wal.segments().unwrap().pop().unwrap();
+        append_bytes(&last.path, b"{\"timestamp\":1,\"comm");
+        
+        assert_eq!(replay_all(&wal).len(), 5);
+        assert_eq!(wal.size(), good_len);
+        assert_eq!(std::fs::metadata(&last.path).unwrap().len(), last.size);
+        
+        // A torn segment with more after it is corruption, not a crash
+        let first = wal.segments().unwrap().remove(0);
+        append_bytes(&first.path, b"garbage\n");
+        let err = wal.replay(|_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("more"), "{}", err);
+    }
+    
+    #[tokio::test]
+    async fn test_compact_takes_pages_between_appends() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        for key in ["a", "b", "c"] {
+            wal.log_command(set_command(key, "old")).await.unwrap();
+        }
+        
+        // A write logged while a page is taken replays after it
+        wal.compact(|cursor| {
+            let wal = &wal;
+            async move {
+                Ok(match cursor.as_deref() {
+                    None => (vec![("a".to_string(), b"old".to_vec())], Some("a".to_string())),
+                    Some("a") => {
+                        wal.log_command(set_command("b", "new")).await?;
+                        assert!(matches!(wal.begin_compaction(), Err(RustVaultError::CompactionInProgress)));
+                        (vec![("b".to_string(), b"old".to_vec())], Some("b".to_string()))
+                    }
+                    _ => (vec![("c".to_string(), b"old".to_vec())], None),
+                })
+            }
+        })
+        .await
+        .unwrap();
+        let stats = wal.stats();
+        assert_eq!((stats.size, stats.entries), (std::fs::metadata(temp_file.path()).unwrap().len(), 4));
+        assert!(stats.last_compaction.is_some());
+        
+        let wal = WriteAheadLog::new(temp_file.path(), SyncPolicy::Never).unwrap();
+        assert_eq!((wal.stats().entries, wal.stats().last_compaction), (0, None));
+        assert_eq!(
+            replay_all(&wal),
+            vec![set_command("a", "old"), set_command("b", "old"), set_command("b", "new"), set_command("c", "old")]
+        );
+        
+        // A page that fails leaves the log as it was
+        let size = wal.size();
+        let failed = wal
+            .compact(|_| async { Err(RustVaultError::Wal("no pages".to_string())) })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(wal.size(), size);
+        assert_eq!(wal.stats().last_compaction, None);
+        assert!(!Path::new(&format!("{}.tmp", temp_file.path().display())).exists());
+        wal.compact(|_| async { Ok((Vec::new(), None)) }).await.unwrap();
+        assert_eq!(replay_all(&wal), Vec::new());
+    }
+    
+    #[tokio::test]
+    async fn test_compacting_segmented_wal_leaves_one_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.log");
+        let wal = WriteAheadLog::segmented(&path, SyncPolicy::Never, 100).unwrap();
+        for i in 0..10 {
+            wal.log_command(set_command("key", &i.to_string())).await.unwrap();
+        }
+        let before = wal.segments().unwrap();
+        assert!(before.len() > 1);
+        
+        wal.compact(|_| async { Ok((vec![("key".to_string(), b"9".to_vec())], None)) }).await.unwrap();
+        let segments = wal.segments().unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_ne!(segments[0].path, before.last().unwrap().path);
+        assert_eq!(segments[0].size, wal.size());
+        
+        wal.log_command(set_command("other", "value")).await.unwrap();
+        let wal = WriteAheadLog::segmented(&path, SyncPolicy::Never, 100).unwrap();
+        assert_eq!(replay_all(&wal), vec![set_command("key", "9"), set_command("other", "value")]);
+    }
 }
\ No newline at end of file
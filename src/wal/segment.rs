@@ -0,0 +1,119 @@
+//! Splitting a log across numbered segment files
+//!
+//! A segmented log at `vault.log` is the files `vault.log.000001`,
+//! `vault.log.000002`, … read in number order; only the last is appended
+//! to. Each segment is a log of its own, with its own format header, and
+//! holds whole appends, so no batch spans two. Offsets into the log count
+//! through every segment in turn, as if they were one file.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Digits in a segment's number, zero-padded so names sort in order
+const NUMBER_DIGITS: usize = 6;
+
+/// One file of a log, as listed by `WriteAheadLog::segments`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub path: PathBuf,
+    /// Size in bytes when it was listed
+    pub size: u64,
+}
+
+/// A file of the log and where it starts in the log's offsets
+#[derive(Debug, Clone)]
+pub(crate) struct LogFile {
+    pub(crate) path: PathBuf,
+    /// Segment number; 0 for a log that isn't segmented
+    pub(crate) number: u64,
+    pub(crate) start: u64,
+    pub(crate) len: u64,
+}
+
+impl LogFile {
+    pub(crate) fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// Path of segment `number` of the log at `base`
+pub(crate) fn segment_path(base: &str, number: u64) -> PathBuf {
+    PathBuf::from(format!("{}.{:0width$}", base, number, width = NUMBER_DIGITS))
+}
+
+/// The numbered segments of the log at `base`, in order
+pub(crate) fn numbered(base: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+    let base = Path::new(base);
+    let Some(name) = base.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let dir = match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let number = file_name
+            .to_str()
+            .and_then(|file| file.strip_prefix(name)?.strip_prefix('.'))
+            .filter(|digits| digits.len() >= NUMBER_DIGITS && digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse().ok());
+        if let Some(number) = number {
+            segments.push((number, base.with_file_name(file_name)));
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Every file of the log at `base` in order: its segments if it has any,
+/// else the single file, if that exists
+pub(crate) fn log_files(base: &str) -> io::Result<Vec<LogFile>> {
+    let mut paths = numbered(base)?;
+    if paths.is_empty() && Path::new(base).exists() {
+        paths.push((0, PathBuf::from(base)));
+    }
+    let mut start = 0;
+    let mut files = Vec::with_capacity(paths.len());
+    for (number, path) in paths {
+        let len = fs::metadata(&path)?.len();
+        files.push(LogFile { path, number, start, len });
+        start += len;
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_segments_are_listed_in_number_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("vault.log").to_string_lossy().to_string();
+        assert!(log_files(&base).unwrap().is_empty());
+        
+        fs::write(&base, b"plain").unwrap();
+        let files = log_files(&base).unwrap();
+        assert_eq!((files.len(), files[0].number, files[0].len), (1, 0, 5));
+        
+        for (number, bytes) in [(10, &b"ccc"[..]), (2, b"a"), (3, b"bb")] {
+            fs::write(segment_path(&base, number), bytes).unwrap();
+        }
+        fs::write(format!("{}.tmp", base), b"ignored").unwrap();
+        fs::write(format!("{}.12", base), b"ignored").unwrap();
+        let files = log_files(&base).unwrap();
+        let numbers: Vec<(u64, u64, u64)> = files.iter().map(|file| (file.number, file.start, file.len)).collect();
+        assert_eq!(numbers, vec![(2, 0, 1), (3, 1, 2), (10, 3, 3)]);
+        assert!(files[2].path.ends_with("vault.log.000010"));
+    }
+}
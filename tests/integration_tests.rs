@@ -2,7 +2,9 @@
 //! 
 //! Tests the complete system including server, client, and persistence
 
-use rustvault::Client;
+use rustvault::testing::{FaultyWal, History, TestCluster, TestNode};
+use rustvault::{Client, Command, ErrorCode, Pipeline, RawResponse, Response, RustVaultError, Transaction};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use tempfile::NamedTempFile;
 use tokio::time::sleep;
@@ -14,6 +16,7 @@ async fn start_test_server(port: u16, wal_path: String) -> tokio::task::JoinHand
             bind_addr: format!("127.0.0.1:{}", port),
             wal_path,
             max_connections: 100,
+            ..Default::default()
         };
         
         let server = rustvault::RustVaultServer::new(config).await.unwrap();
@@ -21,12 +24,60 @@ async fn start_test_server(port: u16, wal_path: String) -> tokio::task::JoinHand
     })
 }
 
+/// Start a server on an ephemeral port, returning it with its address
+///
+/// The WAL file must outlive the server, so it is returned as well.
+async fn start_ephemeral_server() -> (
+    std::sync::Arc<rustvault::RustVaultServer>,
+    tokio::task::JoinHandle<rustvault::Result<()>>,
+    String,
+    NamedTempFile,
+) {
+    start_ephemeral_server_with(Default::default()).await
+}
+
+/// [`start_ephemeral_server`] with the rest of the settings from `config`
+async fn start_ephemeral_server_with(
+    config: rustvault::ServerConfig,
+) -> (
+    std::sync::Arc<rustvault::RustVaultServer>,
+    tokio::task::JoinHandle<rustvault::Result<()>>,
+    String,
+    NamedTempFile,
+) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let config = rustvault::ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        wal_path: temp_file.path().to_string_lossy().to_string(),
+        max_connections: 100,
+        ..config
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+    let server_task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    wait_for_server(&addr).await.unwrap();
+    
+    (server, server_task, addr, temp_file)
+}
+
 /// Helper function to wait for server to be ready
+///
+/// The server answers PING while it is still replaying its WAL, so once
+/// it does this waits until READY is no longer answered with LOADING.
 async fn wait_for_server(addr: &str) -> Result<(), Box<dyn std::error::Error>> {
     for _ in 0..50 {
-        if let Ok(client) = Client::connect(addr).await {
+        if let Ok(mut client) = Client::connect(addr).await {
+            let ready = client.ping().await.is_ok()
+                && client.execute_raw(&["READY"]).await.is_ok_and(|reply| reply == RawResponse::Ok);
             let _ = client.close().await;
-            return Ok(());
+            if ready {
+                return Ok(());
+            }
         }
         sleep(Duration::from_millis(100)).await;
     }
@@ -126,35 +177,22 @@ async fn test_concurrent_clients() {
 
 #[tokio::test]
 async fn test_persistence_and_recovery() {
-    let temp_file = NamedTempFile::new().unwrap();
-    let wal_path = temp_file.path().to_string_lossy().to_string();
-    let port = 18082;
-    let addr = format!("127.0.0.1:{}", port);
-    
-    // Start first server instance
-    let server_handle = start_test_server(port, wal_path.clone()).await;
-    wait_for_server(&addr).await.unwrap();
+    let mut node = TestNode::start().await.unwrap();
     
     // Connect and add some data
-    let mut client = Client::connect(&addr).await.unwrap();
+    let mut client = node.client().await.unwrap();
     client.set("persistent_key1", "persistent_value1").await.unwrap();
     client.set("persistent_key2", "persistent_value2").await.unwrap();
     client.set("persistent_key3", "persistent_value3").await.unwrap();
     client.delete("persistent_key2").await.unwrap();
     client.close().await.unwrap();
     
-    // Stop the server
-    server_handle.abort();
-    sleep(Duration::from_millis(500)).await;
-    
-    // Start a new server instance with the same WAL
-    let port2 = 18083;
-    let addr2 = format!("127.0.0.1:{}", port2);
-    let _server_handle2 = start_test_server(port2, wal_path).await;
-    wait_for_server(&addr2).await.unwrap();
+    // Kill the server and start it again on the same WAL
+    node.crash().await.unwrap();
+    node.restart().await.unwrap();
     
     // Connect to new server and verify data persistence
-    let mut client2 = Client::connect(&addr2).await.unwrap();
+    let mut client2 = node.client().await.unwrap();
     
     let value1 = client2.get("persistent_key1").await.unwrap();
     assert_eq!(value1, Some("persistent_value1".to_string()));
@@ -168,6 +206,400 @@ async fn test_persistence_and_recovery() {
     client2.close().await.unwrap();
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_compact_on_demand() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    
+    for batch in 0..10 {
+        let mut pipeline = Pipeline::new();
+        for i in batch * 1000..(batch + 1) * 1000 {
+            pipeline.set(&format!("key{}", i), format!("value{}", i));
+        }
+        pipeline.execute(&mut client).await.unwrap();
+    }
+    for batch in 1..10 {
+        let mut pipeline = Pipeline::new();
+        for i in batch * 1000..(batch + 1) * 1000 {
+            pipeline.delete(&format!("key{}", i));
+        }
+        pipeline.execute(&mut client).await.unwrap();
+    }
+    let stats = client.wal_stats().await.unwrap();
+    assert_eq!((stats.entries, stats.last_compaction), (19_000, None));
+    assert_eq!(stats.size, std::fs::metadata(node.wal_path()).unwrap().len());
+    
+    // Writes made while it runs land in the compacted log
+    let mut writer = node.client().await.unwrap();
+    let writes = tokio::spawn(async move {
+        for i in 0..200 {
+            writer.set(&format!("during{}", i), "v").await.unwrap();
+        }
+    });
+    let report = client.compact().await.unwrap();
+    writes.await.unwrap();
+    assert!(report.after < report.before / 10, "{:?}", report);
+    assert!((1000..=1200).contains(&report.keys), "{:?}", report);
+    let stats = client.wal_stats().await.unwrap();
+    assert!(stats.last_compaction.is_some());
+    assert_eq!(stats.entries, 19_200);
+    client.close().await.unwrap();
+    
+    node.crash().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.db_size().await.unwrap(), 1200);
+    assert_eq!(client.get("key999").await.unwrap().as_deref(), Some("value999"));
+    assert_eq!(client.get("key1000").await.unwrap(), None);
+    assert_eq!(client.get("during199").await.unwrap().as_deref(), Some("v"));
+    // Well under the ~100 bytes each of the 19,200 logged writes took
+    assert!(std::fs::metadata(node.wal_path()).unwrap().len() < 200 * 1024);
+    client.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_rate_limit() {
+    use rustvault::server::{RateLimit, RateLimitMode};
+    use std::time::Instant;
+    
+    // Delayed: 200 commands at 100 a second, 10 of them at once, take 1.9s
+    let config = rustvault::ServerConfig {
+        rate_limit: Some(RateLimit { ops_per_sec: 100.0, burst: 10 }),
+        ..Default::default()
+    };
+    let (server, server_task, addr, _wal) = start_ephemeral_server_with(config).await;
+    let mut busy = Client::connect(&addr).await.unwrap();
+    let flood = tokio::spawn(async move {
+        let start = Instant::now();
+        for _ in 0..200 {
+            busy.ping().await.unwrap();
+        }
+        start.elapsed()
+    });
+    // A connection within its limit isn't held up by the one over it
+    sleep(Duration::from_millis(300)).await;
+    let mut quiet = Client::connect(&addr).await.unwrap();
+    let start = Instant::now();
+    for _ in 0..5 {
+        quiet.ping().await.unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(200), "{:?}", start.elapsed());
+    let elapsed = flood.await.unwrap();
+    assert!(elapsed >= Duration::from_millis(1700), "{:?}", elapsed);
+    let delayed: u64 = quiet.info().await.unwrap()["ratelimit_delayed_commands"].parse().unwrap();
+    assert!(delayed >= 150, "{}", delayed);
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+    
+    // Refused: the burst goes through, then each command says when to retry
+    let config = rustvault::ServerConfig {
+        rate_limit: Some(RateLimit { ops_per_sec: 10.0, burst: 5 }),
+        rate_limit_mode: RateLimitMode::Reject,
+        ..Default::default()
+    };
+    let (server, server_task, addr, _wal) = start_ephemeral_server_with(config).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    let mut refused = 0;
+    for _ in 0..20 {
+        match client.ping().await {
+            Ok(_) => {}
+            Err(RustVaultError::Remote { code: ErrorCode::RateLimited, message }) => {
+                let millis: u64 = message.strip_prefix("retry_after_ms=").unwrap().parse().unwrap();
+                assert!((1..=100).contains(&millis), "{}", message);
+                refused += 1;
+            }
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+    assert!(refused >= 10, "{}", refused);
+    // Refusals take no tokens, so waiting for one lets a command through
+    sleep(Duration::from_millis(150)).await;
+    client.ping().await.unwrap();
+    
+    let info = Client::connect(&addr).await.unwrap().info().await.unwrap();
+    assert_eq!(info["ratelimit_rejected_commands"], refused.to_string());
+    assert_eq!(info["ratelimit_delayed_commands"], "0");
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+/// Append raw bytes to the WAL at `path`, as a torn or damaged write would leave
+fn append_to_wal(path: &std::path::Path, bytes: &[u8]) {
+    use std::io::Write;
+    std::fs::OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+}
+
+#[tokio::test]
+async fn test_corrupt_wal_tail_is_truncated_on_startup() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    client.set("key1", "value1").await.unwrap();
+    client.set("key2", "value2").await.unwrap();
+    client.close().await.unwrap();
+    node.stop().await.unwrap();
+    
+    // Garbage bytes, then half a JSON object, each cut off on restart
+    for tail in [&b"\x00\xde\xad\xbe\xef"[..], br#"{"timestamp":1700000000000,"command":{"Set":{"key":"#] {
+        append_to_wal(&node.wal_path(), tail);
+        node.restart().await.unwrap();
+        let mut client = node.client().await.unwrap();
+        assert_eq!(client.get("key1").await.unwrap(), Some("value1".to_string()));
+        assert_eq!(client.get("key2").await.unwrap(), Some("value2".to_string()));
+        client.close().await.unwrap();
+        node.stop().await.unwrap();
+    }
+    
+    // Writes after the cut replay as usual
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    client.set("key3", "value3").await.unwrap();
+    client.close().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.get("key3").await.unwrap(), Some("value3".to_string()));
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_corrupt_wal_middle_follows_recovery_mode() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    client.set("before", "value").await.unwrap();
+    client.close().await.unwrap();
+    node.stop().await.unwrap();
+    append_to_wal(&node.wal_path(), b"not an entry\n");
+    append_to_wal(
+        &node.wal_path(),
+        b"{\"timestamp\":1700000000000,\"command\":{\"Set\":{\"key\":\"after\",\"value\":\"value\"}}}\n",
+    );
+    
+    // Entries after the corruption: the default refuses to start
+    let err = node.restart().await.unwrap_err();
+    assert!(err.to_string().contains("Corrupt WAL entry"), "{}", err);
+    
+    // TruncateCorrupt starts with what came before it
+    let config = rustvault::ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        wal_path: node.wal_path().to_string_lossy().to_string(),
+        recovery_mode: rustvault::RecoveryMode::TruncateCorrupt,
+        ..Default::default()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+    let server_task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    wait_for_server(&addr).await.unwrap();
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.get("before").await.unwrap(), Some("value".to_string()));
+    assert_eq!(client.get("after").await.unwrap(), None);
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_restore_on_multi_thread_runtime() {
+    use rustvault::Store;
+    
+    // Restoring used to block_on inside the runtime, which panics on this flavor
+    let temp_file = NamedTempFile::new().unwrap();
+    {
+        let wal = std::sync::Arc::new(
+            rustvault::wal::WriteAheadLog::new(temp_file.path(), rustvault::SyncPolicy::Never).unwrap(),
+        );
+        let store = rustvault::MemoryStore::with_wal(wal);
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i).into_bytes()).await.unwrap();
+        }
+        store.delete("key0").await.unwrap();
+    }
+    
+    let config = rustvault::ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        wal_path: temp_file.path().to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+    let server_task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    wait_for_server(&addr).await.unwrap();
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.get("key0").await.unwrap(), None);
+    assert_eq!(client.get("key99").await.unwrap(), Some("value99".to_string()));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), server_task)
+        .await
+        .expect("server did not stop after shutdown")
+        .unwrap();
+    assert!(result.is_ok());
+}
+
+/// Small xorshift generator, so crash points vary by seed but reproduce
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+#[tokio::test]
+async fn test_random_crash_points_keep_acknowledged_writes() {
+    for seed in 1..=5u64 {
+        let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        let mut node = TestNode::start().await.unwrap();
+        let history = History::new();
+        
+        // Several clients hammer a small keyspace until the crash cuts them off
+        let mut writers = Vec::new();
+        for writer in 0..4 {
+            let mut client = node.client().await.unwrap();
+            let history = history.clone();
+            let mut rng = Rng(rng.below(u64::MAX) | 1);
+            writers.push(tokio::spawn(async move {
+                for i in 0.. {
+                    let key = format!("key{}", rng.below(8));
+                    let result = if rng.below(5) == 0 {
+                        history.delete(&mut client, &key).await.map(|_| ())
+                    } else {
+                        history.set(&mut client, &key, &format!("w{}-{}", writer, i)).await
+                    };
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        
+        sleep(Duration::from_millis(5 + rng.below(40))).await;
+        node.crash().await.unwrap();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        
+        node.restart().await.unwrap();
+        let mut client = node.client().await.unwrap();
+        let violations = history.check_node(&mut client).await.unwrap();
+        assert!(violations.is_empty(), "seed {}: {:?}", seed, violations);
+    }
+}
+
+#[tokio::test]
+async fn test_failed_wal_write_is_not_applied() {
+    let mut node = TestNode::start().await.unwrap();
+    let history = History::new();
+    let mut client = node.client().await.unwrap();
+    
+    history.set(&mut client, "a", "1").await.unwrap();
+    node.faults().fail_nth_write(2);
+    history.set(&mut client, "b", "1").await.unwrap();
+    assert!(history.set(&mut client, "a", "2").await.is_err());
+    history.set(&mut client, "c", "1").await.unwrap();
+    
+    // The failed write never reached the map...
+    assert_eq!(client.get("a").await.unwrap(), Some("1".to_string()));
+    
+    // ...or the log
+    node.crash().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.get("a").await.unwrap(), Some("1".to_string()));
+    assert!(history.check_node(&mut client).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_full_disk_degrades_to_read_only() {
+    let mut node = TestNode::start().await.unwrap();
+    let history = History::new();
+    let mut client = node.client().await.unwrap();
+    
+    history.set(&mut client, "a", "1").await.unwrap();
+    node.faults().fill_disk(true);
+    let err = history.set(&mut client, "b", "1").await.unwrap_err();
+    assert!(err.to_string().contains("PERSISTENCE"), "{}", err);
+    assert!(node.server().unwrap().persistence_error().is_some());
+    assert_eq!(node.server().unwrap().persistence_failures(), 1);
+    
+    // Further writes are refused without touching the log; reads still work
+    let writes = node.faults().writes();
+    assert!(history.delete(&mut client, "a").await.is_err());
+    assert_eq!(node.faults().writes(), writes);
+    assert_eq!(client.get("a").await.unwrap(), Some("1".to_string()));
+    assert_eq!(client.get("b").await.unwrap(), None);
+    
+    // Once space is freed the probe lifts the read-only state by itself
+    node.faults().fill_disk(false);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while node.server().unwrap().persistence_error().is_some() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("WAL should recover once the disk has room");
+    history.set(&mut client, "c", "1").await.unwrap();
+    
+    // The refused write was still buffered when the disk filled; it must not
+    // have gone out with the next append
+    node.crash().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.get("b").await.unwrap(), None);
+    assert!(history.check_node(&mut client).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_checker_catches_lost_synced_writes() {
+    let faults = FaultyWal::new();
+    let mut node = TestNode::start_with_faults(faults.clone()).await.unwrap();
+    let history = History::new();
+    let mut client = node.client().await.unwrap();
+    
+    history.set(&mut client, "kept", "1").await.unwrap();
+    faults.drop_syncs(true);
+    history.set(&mut client, "lost", "1").await.unwrap();
+    assert_eq!(faults.writes(), 2);
+    
+    // A power cut loses the write the client was told had succeeded
+    node.crash().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    let violations = history.check_node(&mut client).await.unwrap();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].key, "lost");
+    assert_eq!(violations[0].found, None);
+}
+
+#[tokio::test]
+async fn test_cluster_nodes_are_independent() {
+    let mut cluster = TestCluster::start(2).await.unwrap();
+    assert_ne!(cluster.node(0).addr(), cluster.node(1).addr());
+    assert_ne!(cluster.node(0).data_dir(), cluster.node(1).data_dir());
+    
+    let mut first = cluster.node(0).client().await.unwrap();
+    first.set("only", "here").await.unwrap();
+    let mut second = cluster.node(1).client().await.unwrap();
+    assert_eq!(second.get("only").await.unwrap(), None);
+    
+    cluster.node_mut(0).restart().await.unwrap();
+    let mut first = cluster.node(0).client().await.unwrap();
+    assert_eq!(first.get("only").await.unwrap(), Some("here".to_string()));
+    
+    cluster.shutdown().await.unwrap();
+}
+
 #[tokio::test]
 async fn test_large_values() {
     let temp_file = NamedTempFile::new().unwrap();
@@ -189,6 +621,68 @@ async fn test_large_values() {
     assert_eq!(retrieved, Some(large_value));
     
     client.close().await.unwrap();
+    
+    // And a 64MB binary value, length-prefixed, with and without a TTL
+    let size = 64 * 1024 * 1024;
+    let config = rustvault::ServerConfig {
+        max_value_bytes: size,
+        ..Default::default()
+    };
+    let (server, server_task, addr, _wal) = start_ephemeral_server_with(config).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set_stream_timeout(Some(Duration::from_secs(30)));
+    let huge: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    client.set_bytes("huge", &huge).await.unwrap();
+    client.set("after", "small").await.unwrap();
+    assert!(client.get_bytes("huge").await.unwrap() == Some(huge));
+    assert_eq!(client.get("after").await.unwrap(), Some("small".to_string()));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_size_limits() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    // The defaults: 1KB keys and 16MB values
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    let key = "k".repeat(1024);
+    client.set(&key, "value").await.unwrap();
+    assert_eq!(client.get(&key).await.unwrap(), Some("value".to_string()));
+    let result = client.set(&"k".repeat(1025), "value").await;
+    assert!(
+        matches!(
+            &result,
+            Err(RustVaultError::Remote { code: ErrorCode::TooLarge, message }) if message == "key too large (max 1024)"
+        ),
+        "{:?}",
+        result
+    );
+    
+    let value = vec![b'v'; 16 * 1024 * 1024];
+    client.set_bytes("big", &value).await.unwrap();
+    assert_eq!(client.get_bytes("big").await.unwrap(), Some(value));
+    
+    // One byte over is refused from the header, before the value is sent
+    let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+    stream.write_all(b"SET big $16777217\r\n").await.unwrap();
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"ERROR ERR_TOO_LARGE value too large (max 16777216)\r\n");
+    
+    // The refusals left the connection that made them usable
+    assert_eq!(client.get_bytes("big").await.unwrap().map(|v| v.len()), Some(16 * 1024 * 1024));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
 }
 
 #[tokio::test]
@@ -213,12 +707,2391 @@ async fn test_special_characters() {
     let retrieved = client.get(special_key).await.unwrap();
     assert_eq!(retrieved, Some(special_value.to_string()));
     
+    // A value that looks like a response doesn't put the client out of step
+    client.set("looks_like_ok", "\nOK").await.unwrap();
+    assert_eq!(client.get("looks_like_ok").await.unwrap().as_deref(), Some("\nOK"));
+    assert_eq!(client.get(special_key).await.unwrap(), Some(special_value.to_string()));
+    client.ping().await.unwrap();
+    
     client.close().await.unwrap();
 }
 
 #[tokio::test]
-async fn test_error_handling() {
-    // Test connection to non-existent server
-    let result = Client::connect("127.0.0.1:99999").await;
-    assert!(result.is_err());
+async fn test_json_values() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Address {
+        street: String,
+        zip: Option<u32>,
+    }
+    
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Role {
+        Admin,
+        Member { since: u16 },
+    }
+    
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        addresses: Vec<Address>,
+        role: Role,
+    }
+    
+    let (server, _, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    let user = User {
+        name: "line one\nline two".to_string(),
+        addresses: vec![Address { street: "1 Main St\r\n".to_string(), zip: Some(12345) }],
+        role: Role::Member { since: 2020 },
+    };
+    client.set_json("user:1", &user).await.unwrap();
+    assert_eq!(client.get_json::<User>("user:1").await.unwrap(), Some(user));
+    client.set_json("role", &Role::Admin).await.unwrap();
+    assert_eq!(client.get_json::<Role>("role").await.unwrap(), Some(Role::Admin));
+    assert_eq!(client.get_json::<Role>("missing").await.unwrap(), None);
+    
+    // The value is there, but isn't a `User`
+    let err = client.get_json::<User>("role").await.unwrap_err();
+    match &err {
+        RustVaultError::Deserialize { key, .. } => assert_eq!(key, "role"),
+        other => panic!("expected a deserialize error, got {:?}", other),
+    }
+    assert!(err.to_string().contains("role"), "{}", err);
+    client.set("text", "not json").await.unwrap();
+    assert!(matches!(
+        client.get_json::<u32>("text").await,
+        Err(RustVaultError::Deserialize { .. })
+    ));
+    
+    server.shutdown().unwrap();
+}
+
+/// The next value `watch` yields, failing if none comes within 5s
+async fn next_change(watch: &mut rustvault::ValueWatch) -> Option<String> {
+    tokio::time::timeout(Duration::from_secs(5), watch.next())
+        .await
+        .expect("no change seen")
+        .expect("watch ended")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_watch_value_yields_each_change() {
+    use futures_core::Stream;
+    
+    let (server, _, addr, _wal) = start_ephemeral_server().await;
+    let mut writer = Client::connect(&addr).await.unwrap();
+    writer.set("gauge", "0").await.unwrap();
+    let watcher = Client::connect(&addr).await.unwrap();
+    let mut watch = watcher.watch_value("gauge", Duration::from_millis(10)).await.unwrap();
+    
+    // Rewriting the same value isn't a change
+    writer.set("gauge", "0").await.unwrap();
+    writer.set("gauge", "1").await.unwrap();
+    assert_eq!(next_change(&mut watch).await, Some("1".to_string()));
+    writer.set("gauge", "2").await.unwrap();
+    let polled = std::future::poll_fn(|cx| std::pin::Pin::new(&mut watch).poll_next(cx));
+    assert_eq!(polled.await.unwrap().unwrap(), Some("2".to_string()));
+    writer.delete("gauge").await.unwrap();
+    assert_eq!(next_change(&mut watch).await, None);
+    
+    // Nothing else changed, so nothing else comes
+    assert!(tokio::time::timeout(Duration::from_millis(100), watch.next()).await.is_err());
+    
+    // Dropping the stream closes the watching connection
+    drop(watch);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while server.stats().connections > 1 {
+        assert!(tokio::time::Instant::now() < deadline, "watching connection still open");
+        sleep(Duration::from_millis(10)).await;
+    }
+    
+    server.shutdown().unwrap();
+}
+
+#[tokio::test]
+async fn test_read_only_mode_toggles_at_runtime() {
+    let config = rustvault::ServerConfig { read_only: true, ..Default::default() };
+    let (_server, _server_task, addr, _temp_file) = start_ephemeral_server_with(config).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    let refused = client.set("key", "1").await;
+    assert!(matches!(
+        refused,
+        Err(RustVaultError::Remote { code: ErrorCode::NotPermitted, message }) if message == "command not permitted"
+    ));
+    assert_eq!(client.get("key").await.unwrap(), None);
+    
+    client.config_set("readonly", "0").await.unwrap();
+    client.set("key", "1").await.unwrap();
+    client.config_set("readonly", "1").await.unwrap();
+    assert!(client.set("key", "2").await.is_err());
+    assert_eq!(client.get("key").await.unwrap(), Some("1".to_string()));
+    
+    let unknown = client.config_set("maxclients", "1").await;
+    assert!(matches!(
+        unknown,
+        Err(RustVaultError::Remote { code: ErrorCode::Invalid, message }) if message == "unknown config parameter maxclients"
+    ));
+    client.close().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("vault.sock");
+    // Left behind by a server that didn't stop cleanly
+    std::fs::write(&socket, b"").unwrap();
+    let addr = format!("unix://{}", socket.display());
+    let config = |wal: &str| rustvault::ServerConfig {
+        bind_addr: addr.clone(),
+        wal_path: dir.path().join(wal).to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config("vault.log")).await.unwrap());
+    let task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run().await })
+    };
+    wait_for_server(&addr).await.unwrap();
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("key", "over a socket").await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some("over a socket".to_string()));
+    client.delete("key").await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), None);
+    
+    // A second server can't take over a live socket
+    let second = rustvault::RustVaultServer::new(config("other.log")).await.unwrap();
+    let refused = second.run().await;
+    assert!(matches!(
+        refused,
+        Err(RustVaultError::BindFailed { source, .. }) if source.kind() == std::io::ErrorKind::AddrInUse
+    ));
+    
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    task.await.unwrap().unwrap();
+    assert!(!socket.exists());
+}
+
+#[tokio::test]
+async fn test_config_set_applies_to_later_commands() {
+    let (server, _server_task, addr, _temp_file) = start_ephemeral_server().await;
+    let mut admin = Client::connect(&addr).await.unwrap();
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("key", "0123456789").await.unwrap();
+    assert_eq!(admin.config_get("max_value_bytes").await.unwrap(), (16 * 1024 * 1024).to_string());
+    
+    // Lowered on one connection, enforced on the other
+    admin.config_set("max_value_bytes", "8").await.unwrap();
+    let refused = client.set("key", "0123456789").await;
+    assert!(matches!(
+        refused,
+        Err(RustVaultError::Remote { code: ErrorCode::TooLarge, message }) if message == "value too large (max 8)"
+    ));
+    client.set("key", "short").await.unwrap();
+    assert_eq!(admin.config_get("max_value_bytes").await.unwrap(), "8");
+    assert_eq!(server.runtime_config().max_value_bytes, 8);
+    
+    let fixed = admin.config_set("bind_addr", "127.0.0.1:1").await;
+    assert!(matches!(fixed, Err(RustVaultError::Remote { message, .. }) if message.contains("can't be changed")));
+    assert_eq!(admin.config_get("bind_addr").await.unwrap(), "127.0.0.1:0");
+    admin.close().await.unwrap();
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_values() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let large: Vec<u8> = (0..1024 * 1024).map(|_| rng.below(256) as u8).collect();
+    let values: [&[u8]; 4] = [b"nul\0byte", b"\r\n", b"\xff\xfe invalid utf-8", &large];
+    for (i, value) in values.iter().enumerate() {
+        client.set_bytes(&format!("bin{}", i), value).await.unwrap();
+    }
+    assert!(client.get("bin2").await.is_err());
+    
+    // Byte values survive a restart through the WAL
+    node.crash().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    for (i, value) in values.iter().enumerate() {
+        let key = format!("bin{}", i);
+        assert_eq!(client.get_bytes(&key).await.unwrap().as_deref(), Some(*value));
+        let mut streamed = Vec::new();
+        client.get_streaming(&key, &mut streamed).await.unwrap();
+        assert_eq!(streamed, *value);
+    }
+    assert_eq!(client.get("bin0").await.unwrap().as_deref(), Some("nul\0byte"));
+    
+    node.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    for i in 0..25 {
+        client.set(&format!("scan:{}", i), "v").await.unwrap();
+    }
+    client.set("unrelated", "v").await.unwrap();
+    
+    let mut keys = Vec::new();
+    let mut scan = client.scan_iter("scan:", 4);
+    while let Some(key) = scan.next().await.unwrap() {
+        keys.push(key);
+    }
+    keys.sort();
+    let mut expected: Vec<String> = (0..25).map(|i| format!("scan:{}", i)).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+    
+    let page = client.scan("", 0, 100).await.unwrap();
+    assert_eq!(page.keys.len(), 26);
+    assert_eq!(page.cursor, 0);
+    
+    // The connection stays framed after a raw multi-line reply
+    match client.execute_raw_str("SCAN 0 2 scan:").await.unwrap() {
+        RawResponse::Keys { keys, cursor } => {
+            assert_eq!(keys.len(), 2);
+            assert_ne!(cursor, 0);
+        }
+        other => panic!("expected keys, got {:?}", other),
+    }
+    assert!(matches!(
+        client.execute_raw_str("SCAN 0 0").await.unwrap(),
+        RawResponse::Error { .. }
+    ));
+    assert_eq!(client.get("unrelated").await.unwrap(), Some("v".to_string()));
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_bulk_load_from_iter() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let wal_path = temp_file.path().to_string_lossy().to_string();
+    let port = 18086;
+    let addr = format!("127.0.0.1:{}", port);
+    
+    // Start server
+    let _server_handle = start_test_server(port, wal_path).await;
+    wait_for_server(&addr).await.unwrap();
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    // Generate pairs with one key the server will reject (empty key)
+    let num_pairs = 20_000;
+    let items = (0..num_pairs)
+        .map(|i| (format!("bulk_key_{}", i), format!("bulk_value_{}", i)))
+        .chain(std::iter::once((String::new(), "orphan".to_string())));
+    
+    let report = client.load_from_iter(items).await.unwrap();
+    assert_eq!(report.loaded, num_pairs);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, "");
+    
+    // The connection is still usable and every pair landed
+    assert_eq!(
+        client.get("bulk_key_0").await.unwrap(),
+        Some("bulk_value_0".to_string())
+    );
+    let last_key = format!("bulk_key_{}", num_pairs - 1);
+    let last_value = format!("bulk_value_{}", num_pairs - 1);
+    assert_eq!(client.get(&last_key).await.unwrap(), Some(last_value));
+    
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_execute_raw_keeps_connection_usable() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let wal_path = temp_file.path().to_string_lossy().to_string();
+    let port = 18092;
+    let addr = format!("127.0.0.1:{}", port);
+    
+    // Start server
+    let _server_handle = start_test_server(port, wal_path).await;
+    wait_for_server(&addr).await.unwrap();
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    // Known commands through the raw path
+    assert_eq!(
+        client.execute_raw(&["SET", "raw_key", "raw value"]).await.unwrap(),
+        RawResponse::Ok
+    );
+    assert_eq!(
+        client.execute_raw(&["GET", "raw_key"]).await.unwrap(),
+        RawResponse::Value(b"raw value".to_vec())
+    );
+    assert_eq!(
+        client.execute_raw_str(r#"SET quoted "a b  c""#).await.unwrap(),
+        RawResponse::Ok
+    );
+    
+    // A command the server doesn't know is answered with a single error frame
+    match client.execute_raw(&["FROBNICATE", "raw_key"]).await.unwrap() {
+        RawResponse::Error { message, .. } => assert!(message.contains("unknown command")),
+        other => panic!("expected an error frame, got {:?}", other),
+    }
+    
+    // ...after which the typed API still lines up with its responses
+    assert_eq!(client.get("raw_key").await.unwrap(), Some("raw value".to_string()));
+    assert_eq!(client.get("quoted").await.unwrap(), Some("a b  c".to_string()));
+    assert_eq!(
+        client.execute_raw(&["DELETE", "raw_key"]).await.unwrap(),
+        RawResponse::Ok
+    );
+    assert_eq!(
+        client.execute_raw(&["GET", "raw_key"]).await.unwrap(),
+        RawResponse::NotFound
+    );
+    
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_run_with_prebound_listener() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let config = rustvault::ServerConfig {
+        // Never bound: the adopted listener replaces it
+        bind_addr: "127.0.0.1:1".to_string(),
+        wal_path: temp_file.path().to_string_lossy().to_string(),
+        max_connections: 100,
+        ..Default::default()
+    };
+    
+    // Bind first, as a service manager would, then hand the socket over
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+    let server_task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    wait_for_server(&addr).await.unwrap();
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("adopted", "socket").await.unwrap();
+    assert_eq!(client.get("adopted").await.unwrap(), Some("socket".to_string()));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), server_task)
+        .await
+        .expect("server did not stop after shutdown")
+        .unwrap();
+    assert!(result.is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_set_nx_racing_clients() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    
+    // Ten clients claim the same work item; exactly one gets it
+    let mut racers = Vec::new();
+    for i in 0..10 {
+        let mut client = Client::connect(&addr).await.unwrap();
+        racers.push(tokio::spawn(async move {
+            let worker = format!("worker-{}", i);
+            client.set_nx("job:1", worker.as_bytes()).await.unwrap().then_some(worker)
+        }));
+    }
+    let mut winners = Vec::new();
+    for racer in racers {
+        winners.extend(racer.await.unwrap());
+    }
+    assert_eq!(winners.len(), 1, "{:?}", winners);
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.get("job:1").await.unwrap(), Some(winners[0].clone()));
+    assert!(!client.set_xx("job:2", b"done").await.unwrap());
+    assert_eq!(client.get("job:2").await.unwrap(), None);
+    assert!(client.set_xx("job:1", b"done").await.unwrap());
+    assert_eq!(client.get("job:1").await.unwrap(), Some("done".to_string()));
+    
+    let reply = client.execute_raw(&["SET", "job:1", "again", "NX"]).await.unwrap();
+    assert_eq!(reply, RawResponse::NotApplied);
+    assert_eq!(client.execute_raw(&["MULTI"]).await.unwrap(), RawResponse::Ok);
+    let queued = client.execute_raw(&["SET", "job:3", "v", "NX"]).await.unwrap();
+    assert!(matches!(&queued, RawResponse::Error { code: Some(code), .. } if code == "ERR_INVALID"), "{:?}", queued);
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_cas_racing_clients() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("counter", "0").await.unwrap();
+    
+    // Each client increments by read-modify-write, retrying on conflict
+    let mut racers = Vec::new();
+    for _ in 0..2 {
+        let mut client = Client::connect(&addr).await.unwrap();
+        racers.push(tokio::spawn(async move {
+            let mut conflicts = 0;
+            for _ in 0..100 {
+                loop {
+                    let current = client.get("counter").await.unwrap().unwrap();
+                    let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                    if client.cas("counter", current.as_bytes(), next.as_bytes()).await.unwrap() {
+                        break;
+                    }
+                    conflicts += 1;
+                }
+            }
+            conflicts
+        }));
+    }
+    for racer in racers {
+        racer.await.unwrap();
+    }
+    assert_eq!(client.get("counter").await.unwrap(), Some("200".to_string()));
+    
+    assert!(!client.cas("counter", b"199", b"0").await.unwrap());
+    assert!(!client.cas("missing", b"", b"v").await.unwrap());
+    assert!(client.cas("counter", b"200", b"line\r\nbreak").await.unwrap());
+    assert_eq!(client.get("counter").await.unwrap(), Some("line\r\nbreak".to_string()));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_incr_decr() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    
+    let mut counters = Vec::new();
+    for _ in 0..4 {
+        let mut client = Client::connect(&addr).await.unwrap();
+        counters.push(tokio::spawn(async move {
+            for _ in 0..50 {
+                client.incr("views", 2).await.unwrap();
+                client.decr("views", 1).await.unwrap();
+            }
+        }));
+    }
+    for counter in counters {
+        counter.await.unwrap();
+    }
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.incr("views", 0).await.unwrap(), 200);
+    assert_eq!(client.decr("fresh", 5).await.unwrap(), -5);
+    
+    client.set("name", "rex").await.unwrap();
+    match client.incr("name", 1).await {
+        Err(RustVaultError::Remote { code: ErrorCode::Invalid, message }) => {
+            assert!(message.contains("not an integer"), "{}", message)
+        }
+        other => panic!("expected a server error, got {:?}", other),
+    }
+    // The failed increment left the connection in step
+    assert_eq!(client.get("name").await.unwrap(), Some("rex".to_string()));
+    assert!(client.decr("name", i64::MIN).await.is_err());
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_concurrent_appends_keep_every_suffix() {
+    let mut node = TestNode::start().await.unwrap();
+    
+    let mut writers = Vec::new();
+    for i in 0..4 {
+        let mut client = node.client().await.unwrap();
+        writers.push(tokio::spawn(async move {
+            let line = format!("writer {} line\n", i);
+            for _ in 0..50 {
+                client.append("log", line.as_bytes()).await.unwrap();
+            }
+            line.len() * 50
+        }));
+    }
+    let mut expected = 0;
+    for writer in writers {
+        expected += writer.await.unwrap();
+    }
+    
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.strlen("log").await.unwrap(), Some(expected));
+    assert_eq!(client.strlen("missing").await.unwrap(), None);
+    assert_eq!(client.append("bytes", b"\r\n\0 ").await.unwrap(), 4);
+    assert_eq!(client.get_bytes("bytes").await.unwrap(), Some(b"\r\n\0 ".to_vec()));
+    
+    drop(client);
+    node.stop().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    let log = client.get("log").await.unwrap().unwrap();
+    assert_eq!(log.len(), expected);
+    for i in 0..4 {
+        assert_eq!(log.matches(&format!("writer {} line\n", i)).count(), 50);
+    }
+}
+
+#[tokio::test]
+async fn test_one_of_two_contending_clients_takes_a_lock() {
+    let mut node = TestNode::start().await.unwrap();
+    let lease = Duration::from_millis(300);
+    
+    let mut contenders = Vec::new();
+    for _ in 0..2 {
+        let mut client = node.client().await.unwrap();
+        contenders.push(tokio::spawn(async move {
+            let guard = client.lock("jobs:nightly", lease).await.unwrap();
+            (client, guard)
+        }));
+    }
+    let mut winners = Vec::new();
+    let mut losers = Vec::new();
+    for contender in contenders {
+        match contender.await.unwrap() {
+            (client, Some(guard)) => winners.push((client, guard)),
+            (client, None) => losers.push(client),
+        }
+    }
+    assert_eq!((winners.len(), losers.len()), (1, 1));
+    let (mut winner, guard) = winners.pop().unwrap();
+    let mut loser = losers.pop().unwrap();
+    
+    // Until the lease runs out, only the winner's token counts
+    assert!(!loser.try_lock("jobs:nightly", "other", lease).await.unwrap());
+    assert!(!loser.unlock("jobs:nightly", "other").await.unwrap());
+    assert!(guard.renew(&mut winner, lease).await.unwrap());
+    
+    // Then the lock is anyone's, and the old guard releases nothing
+    sleep(lease * 2).await;
+    let taken = loser.lock("jobs:nightly", Duration::from_secs(60)).await.unwrap().unwrap();
+    assert!(!guard.release(&mut winner).await.unwrap());
+    assert!(winner.lock("jobs:nightly", lease).await.unwrap().is_none());
+    
+    // A held lease survives a restart, and a dropped guard releases it
+    drop((winner, loser));
+    node.stop().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    assert!(!client.try_lock("jobs:nightly", "other", lease).await.unwrap());
+    drop(taken);
+    let mut released = false;
+    for _ in 0..50 {
+        if client.try_lock("jobs:nightly", "other", lease).await.unwrap() {
+            released = true;
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert!(released);
+}
+
+#[tokio::test]
+async fn test_concurrent_getsets_see_each_old_value_once() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    client.set("audit", "start").await.unwrap();
+    
+    let mut swappers = Vec::new();
+    for name in ["a", "b"] {
+        let mut client = node.client().await.unwrap();
+        swappers.push(tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for i in 0..200 {
+                let old = client.getset("audit", format!("{}{}", name, i).as_bytes()).await.unwrap();
+                seen.push(String::from_utf8(old.unwrap()).unwrap());
+            }
+            seen
+        }));
+    }
+    let mut seen = Vec::new();
+    for swapper in swappers {
+        seen.extend(swapper.await.unwrap());
+    }
+    
+    // Every value but the last was replaced, and seen by exactly one swap
+    let last = client.getdel("audit").await.unwrap().map(|value| String::from_utf8(value).unwrap());
+    seen.extend(last);
+    seen.sort();
+    let mut written: Vec<String> = ["a", "b"]
+        .iter()
+        .flat_map(|name| (0..200).map(move |i| format!("{}{}", name, i)))
+        .chain(["start".to_string()])
+        .collect();
+    written.sort();
+    assert_eq!(seen, written);
+    assert_eq!(client.getdel("audit").await.unwrap(), None);
+    assert_eq!(client.getset("fresh", b"1").await.unwrap(), None);
+    
+    drop(client);
+    node.stop().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.get("audit").await.unwrap(), None);
+    assert_eq!(client.get("fresh").await.unwrap(), Some("1".to_string()));
+}
+
+#[tokio::test]
+async fn test_pipeline() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    // A failure in the middle takes only its own slot
+    let mut pipeline = Pipeline::new();
+    pipeline
+        .set("name", "rex")
+        .command(Command::Incr { key: "name".to_string(), delta: 1 })
+        .get("name")
+        .delete("name")
+        .get("name")
+        .set("multi", "line\r\nvalue")
+        .get("multi");
+    let responses = pipeline.execute(&mut client).await.unwrap();
+    assert_eq!(responses.len(), pipeline.len());
+    assert_eq!(responses[0], Response::Ok);
+    assert!(matches!(&responses[1], Response::Error(e) if e.contains("not an integer")));
+    assert_eq!(responses[2], Response::Value(b"rex".to_vec()));
+    assert_eq!(responses[3], Response::Ok);
+    assert_eq!(responses[4], Response::NotFound);
+    assert_eq!(responses[5], Response::Ok);
+    assert_eq!(responses[6], Response::Value(b"line\r\nvalue".to_vec()));
+    assert!(Pipeline::new().execute(&mut client).await.unwrap().is_empty());
+    
+    // Far more than the socket buffers hold, in both directions at once
+    let value = "x".repeat(4 * 1024);
+    let mut pipeline = Pipeline::new();
+    for i in 0..4_000 {
+        pipeline.set(&format!("bulk{}", i), &value).get(&format!("bulk{}", i));
+    }
+    let responses = tokio::time::timeout(Duration::from_secs(30), pipeline.execute(&mut client))
+        .await
+        .expect("pipeline stalled")
+        .unwrap();
+    for pair in responses.chunks(2) {
+        assert_eq!(pair, [Response::Ok, Response::Value(value.as_bytes().to_vec())]);
+    }
+    
+    // The connection is still in step afterwards
+    assert_eq!(client.get("bulk42").await.unwrap(), Some(value));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_mset_mget() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    client.mset(&[("a", "1"), ("b", "two words"), ("c", "")]).await.unwrap();
+    client.mset(&[]).await.unwrap();
+    assert_eq!(
+        client.mget(&["a", "missing", "b", "c"]).await.unwrap(),
+        vec![Some("1".to_string()), None, Some("two words".to_string()), Some(String::new())]
+    );
+    assert_eq!(client.mget(&[]).await.unwrap(), Vec::<Option<String>>::new());
+    assert_eq!(client.mget(&["missing"]).await.unwrap(), vec![None]);
+    
+    // The raw path frames the multi-value reply too
+    assert_eq!(
+        client.execute_raw(&["MGET", "a", "nope"]).await.unwrap(),
+        RawResponse::Values(vec![Some(b"1".to_vec()), None])
+    );
+    assert_eq!(client.get("b").await.unwrap(), Some("two words".to_string()));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_auth() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let config = rustvault::ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        wal_path: temp_file.path().to_string_lossy().to_string(),
+        auth_token: Some("s3cr3t".to_string()),
+        ..Default::default()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+    let server_task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    while !server.is_ready() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    
+    let mut client = Client::connect_with_auth(&addr, "s3cr3t").await.unwrap();
+    client.set("key", "value").await.unwrap();
+    
+    // Without the token every command is refused, but HELLO, which comes
+    // first, still settles on a version
+    let mut anonymous = Client::connect(&addr).await.unwrap();
+    assert_eq!(anonymous.protocol_version(), 2);
+    assert!(matches!(
+        anonymous.get("key").await,
+        Err(RustVaultError::Remote { code: ErrorCode::NoAuth, .. })
+    ));
+    assert!(matches!(
+        Client::connect_with_auth(&addr, "guess").await,
+        Err(rustvault::RustVaultError::Client(e)) if e == "invalid auth"
+    ));
+    
+    assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+    client.close().await.unwrap();
+    anonymous.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_info() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    client.set("a", "1").await.unwrap();
+    client.set("b", "2").await.unwrap();
+    client.get("a").await.unwrap();
+    client.get("missing").await.unwrap();
+    
+    let info = client.info().await.unwrap();
+    assert_eq!(info["keys"], "2");
+    assert_eq!(info["connections"], "1");
+    // The counters include wait_for_server's probes, a PING and a READY on
+    // a connection of its own. READYs answered with LOADING aren't executed
+    // but did connect.
+    assert_eq!(info["cmd_set"], "2");
+    assert_eq!(info["cmd_get"], "2");
+    assert_eq!(info["cmd_info"], "1");
+    assert_eq!(info["cmd_ready"], "1");
+    assert!(info["cmd_ping"].parse::<u64>().unwrap() >= 1);
+    assert_eq!(info["get_hits"], "1");
+    assert_eq!(info["get_misses"], "1");
+    assert!(info["total_connections"].parse::<u64>().unwrap() >= 2);
+    assert!(info["wal_size_bytes"].parse::<u64>().unwrap() > 0);
+    assert!(info.contains_key("uptime_secs"));
+    
+    assert!(matches!(
+        client.execute_raw(&["INFO"]).await.unwrap(),
+        RawResponse::Info(fields) if fields[0].0 == "uptime_secs"
+    ));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_prometheus_metrics() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    let temp_file = NamedTempFile::new().unwrap();
+    let config = rustvault::ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        wal_path: temp_file.path().to_string_lossy().to_string(),
+        metrics_addr: Some("127.0.0.1:0".to_string()),
+        ..Default::default()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+    let server_task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    while !server.is_ready() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    let metrics_addr = server.metrics_addr().unwrap();
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("a", "1").await.unwrap();
+    client.set("b", "2").await.unwrap();
+    client.set("c", "3").await.unwrap();
+    client.get("a").await.unwrap();
+    client.delete("c").await.unwrap();
+    
+    let mut scrape = tokio::net::TcpStream::connect(metrics_addr).await.unwrap();
+    scrape.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    scrape.read_to_string(&mut response).await.unwrap();
+    
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let lines: Vec<&str> = response.lines().collect();
+    for expected in [
+        "rustvault_commands_total{op=\"set\"} 3",
+        "rustvault_commands_total{op=\"get\"} 1",
+        "rustvault_commands_total{op=\"delete\"} 1",
+        "rustvault_keys 2",
+        "rustvault_connections 1",
+    ] {
+        assert!(lines.contains(&expected), "missing {:?} in:\n{}", expected, response);
+    }
+    let wal_bytes = lines.iter().find_map(|line| line.strip_prefix("rustvault_wal_bytes ")).unwrap();
+    assert!(wal_bytes.parse::<u64>().unwrap() > 0);
+    
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_sharded_store() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let serve = |shards| {
+        let config = rustvault::ServerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            wal_path: temp_file.path().to_string_lossy().to_string(),
+            shards,
+            ..Default::default()
+        };
+        async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+            let server_task = {
+                let server = std::sync::Arc::clone(&server);
+                tokio::spawn(async move { server.run_with_listener(listener).await })
+            };
+            wait_for_server(&addr).await.unwrap();
+            (server, server_task, addr)
+        }
+    };
+    
+    let (server, server_task, addr) = serve(8).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    for i in 0..50 {
+        client.set(&format!("key{}", i), &format!("value{}", i)).await.unwrap();
+    }
+    client.mset(&[("a", "1"), ("b", "2")]).await.unwrap();
+    client.delete("key7").await.unwrap();
+    let mut scanned = Vec::new();
+    let mut keys = client.scan_iter("key", 10);
+    while let Some(key) = keys.next().await.unwrap() {
+        scanned.push(key);
+    }
+    assert_eq!(scanned.len(), 49);
+    let checksum = client.checksum("").await.unwrap();
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+    
+    // The WAL replays into however many shards the next server has
+    let (server, server_task, addr) = serve(3).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(
+        client.mget(&["a", "b", "key7"]).await.unwrap(),
+        [Some("1".to_string()), Some("2".to_string()), None]
+    );
+    assert_eq!(client.get("key42").await.unwrap(), Some("value42".to_string()));
+    assert_eq!(client.checksum("").await.unwrap(), checksum);
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_snapshot_restart() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let snapshot_dir = tempfile::TempDir::new().unwrap();
+    let snapshot_path = snapshot_dir.path().join("vault.snap");
+    let serve = || {
+        let config = rustvault::ServerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            wal_path: temp_file.path().to_string_lossy().to_string(),
+            shards: 4,
+            snapshot_path: Some(snapshot_path.to_string_lossy().to_string()),
+            snapshot_interval_secs: None,
+            ..Default::default()
+        };
+        async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+            let server_task = {
+                let server = std::sync::Arc::clone(&server);
+                tokio::spawn(async move { server.run_with_listener(listener).await })
+            };
+            wait_for_server(&addr).await.unwrap();
+            (server, server_task, addr)
+        }
+    };
+    
+    let (server, server_task, addr) = serve().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    for i in 0..100 {
+        client.set(&format!("key{}", i), &format!("value{}", i)).await.unwrap();
+    }
+    server.snapshot().await.unwrap();
+    assert!(snapshot_path.exists());
+    
+    // Written after the snapshot, so only the WAL has these
+    client.set("key0", "changed").await.unwrap();
+    client.delete("key1").await.unwrap();
+    client.set("late", "value").await.unwrap();
+    let checksum = client.checksum("").await.unwrap();
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+    
+    let (server, server_task, addr) = serve().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(
+        client.mget(&["key0", "key1", "key2", "late"]).await.unwrap(),
+        [Some("changed".to_string()), None, Some("value2".to_string()), Some("value".to_string())]
+    );
+    assert_eq!(client.checksum("").await.unwrap(), checksum);
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+    
+    // A corrupt snapshot falls back to replaying the whole WAL
+    let mut bytes = std::fs::read(&snapshot_path).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    std::fs::write(&snapshot_path, &bytes).unwrap();
+    let (server, server_task, addr) = serve().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.checksum("").await.unwrap(), checksum);
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_wal_restart() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let serve = |wal_format| {
+        let config = rustvault::ServerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            wal_path: temp_file.path().to_string_lossy().to_string(),
+            wal_format,
+            ..Default::default()
+        };
+        async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+            let server_task = {
+                let server = std::sync::Arc::clone(&server);
+                tokio::spawn(async move { server.run_with_listener(listener).await })
+            };
+            wait_for_server(&addr).await.unwrap();
+            (server, server_task, addr)
+        }
+    };
+    
+    let (server, server_task, addr) = serve(rustvault::WalFormat::Binary).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("text", "value").await.unwrap();
+    client.set_bytes("bytes", &[0, 255, b'\r', b'\n']).await.unwrap();
+    client.mset(&[("a", "1"), ("b", "2")]).await.unwrap();
+    client.delete("a").await.unwrap();
+    let checksum = client.checksum("").await.unwrap();
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+    assert!(std::fs::read(temp_file.path()).unwrap().starts_with(rustvault::wal::BINARY_MAGIC));
+    
+    // A server configured for JSON still reads the binary log
+    let (server, server_task, addr) = serve(rustvault::WalFormat::Json).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.checksum("").await.unwrap(), checksum);
+    assert_eq!(
+        client.mget(&["text", "a", "b"]).await.unwrap(),
+        [Some("value".to_string()), None, Some("2".to_string())]
+    );
+    assert_eq!(client.get_bytes("bytes").await.unwrap(), Some(vec![0, 255, b'\r', b'\n']));
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    server_task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_command_info() {
+    use rustvault::CommandKind;
+    
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.command_info("GET").await.unwrap(), Some(CommandKind::Read));
+    assert_eq!(client.command_info("set").await.unwrap(), Some(CommandKind::Write));
+    assert_eq!(client.command_info("SHRINK").await.unwrap(), Some(CommandKind::Admin));
+    assert_eq!(client.command_info("FROB").await.unwrap(), None);
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_values_round_trip_verbatim() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    let values = [
+        "hello world",
+        "multiple   spaces",
+        "tabs\tand\ttabs",
+        "line one\r\nline two\r\n",
+        "bare\nnewline and bare\rreturn",
+        "  padded  ",
+        "",
+        "$5",
+        "looks like EX 10",
+    ];
+    for (i, value) in values.iter().enumerate() {
+        let key = format!("verbatim{}", i);
+        client.set(&key, value).await.unwrap();
+        assert_eq!(client.get(&key).await.unwrap().as_deref(), Some(*value));
+        
+        let mut streamed = Vec::new();
+        assert_eq!(
+            client.get_streaming(&key, &mut streamed).await.unwrap(),
+            Some(value.len() as u64)
+        );
+        assert_eq!(streamed, value.as_bytes());
+    }
+    
+    // The interactive client's path: quoted, escaped words through execute_raw
+    assert_eq!(
+        client.execute_raw_str(r#"SET quoted "a\tb\r\n c ""#).await.unwrap(),
+        RawResponse::Ok
+    );
+    assert_eq!(client.get("quoted").await.unwrap().as_deref(), Some("a\tb\r\n c "));
+    assert_eq!(
+        client.execute_raw_str("GET quoted").await.unwrap(),
+        RawResponse::Value(b"a\tb\r\n c ".to_vec())
+    );
+    
+    // A payload split across reads, pipelined with the next command
+    let mut raw = tokio::net::TcpStream::connect(&addr).await.unwrap();
+    raw.write_all(b"SET split $12\r\nhello").await.unwrap();
+    raw.flush().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    raw.write_all(b"\r\nworld\r\nGET split\r\n").await.unwrap();
+    let expected = b"OK\r\nVALUE $12\r\nhello\r\nworld\r\n";
+    let mut reply = vec![0; expected.len()];
+    raw.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, expected);
+    
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_protocol_version_negotiation() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.protocol_version(), rustvault::protocol::PROTOCOL_VERSION);
+    client.set("greeting", "hello").await.unwrap();
+    assert_eq!(client.get("greeting").await.unwrap().as_deref(), Some("hello"));
+    
+    async fn exchange(raw: &mut tokio::net::TcpStream, request: &[u8], expected: &[u8]) {
+        raw.write_all(request).await.unwrap();
+        let mut reply = vec![0; expected.len()];
+        raw.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected, "reply to {:?}", String::from_utf8_lossy(request));
+    }
+    
+    // A client that never sends HELLO gets version 1 replies
+    let mut v1 = tokio::net::TcpStream::connect(&addr).await.unwrap();
+    exchange(&mut v1, b"GET greeting\r\n", b"VALUE hello\r\n").await;
+    exchange(&mut v1, b"HELLO 1\r\n", b"HELLO 1\r\n").await;
+    exchange(&mut v1, b"GET greeting\r\n", b"VALUE hello\r\n").await;
+    
+    // One asking for more than the server speaks is offered its highest
+    let mut v2 = tokio::net::TcpStream::connect(&addr).await.unwrap();
+    exchange(&mut v2, b"HELLO 7\r\n", b"HELLO 2\r\n").await;
+    exchange(&mut v2, b"GET greeting\r\n", b"VALUE $5\r\nhello\r\n").await;
+    exchange(&mut v2, b"HELLO 0\r\n", b"ERROR ERR_INVALID protocol versions start at 1\r\n").await;
+    exchange(&mut v2, b"GET greeting\r\n", b"VALUE $5\r\nhello\r\n").await;
+    
+    client.close().await.unwrap();
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_key_expiry() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    // Expiring a missing key is NOT_FOUND, and a zero TTL expires at once
+    assert!(!client.expire("missing", 10).await.unwrap());
+    client.set("short", "lived").await.unwrap();
+    assert!(client.expire("short", 0).await.unwrap());
+    assert_eq!(client.get("short").await.unwrap(), None);
+    assert!(!client.delete("short").await.unwrap());
+    
+    // SET ... EX expires; a later plain SET of the key clears the TTL
+    client.set_with_ttl("session", "abc", 1).await.unwrap();
+    client.set_with_ttl("overwritten", "abc", 1).await.unwrap();
+    client.set("overwritten", "def").await.unwrap();
+    assert_eq!(client.get("session").await.unwrap(), Some("abc".to_string()));
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    assert_eq!(client.get("session").await.unwrap(), None);
+    assert_eq!(client.get("overwritten").await.unwrap(), Some("def".to_string()));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_exists() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    
+    assert!(!client.exists("key").await.unwrap());
+    client.set("key", "value").await.unwrap();
+    assert!(client.exists("key").await.unwrap());
+    
+    // An empty value still counts as present
+    client.set("empty", "").await.unwrap();
+    assert!(client.exists("empty").await.unwrap());
+    
+    assert!(client.delete("key").await.unwrap());
+    assert!(!client.exists("key").await.unwrap());
+    
+    // Expired keys are gone as far as EXISTS is concerned
+    client.set("short", "lived").await.unwrap();
+    assert!(client.expire("short", 0).await.unwrap());
+    assert!(!client.exists("short").await.unwrap());
+    
+    let reply = client.execute_raw(&["EXISTS", "empty"]).await.unwrap();
+    assert_eq!(reply, rustvault::RawResponse::Ok);
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_stat_survives_restart() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.stat("key").await.unwrap(), None);
+    for value in ["a", "b", "c"] {
+        client.set("key", value).await.unwrap();
+    }
+    let stat = client.stat("key").await.unwrap().unwrap();
+    assert_eq!(stat.version, 3);
+    assert!(stat.created_at <= stat.updated_at);
+    
+    // GET is unaffected, and the reply reads as it is sent
+    assert_eq!(client.get("key").await.unwrap(), Some("c".to_string()));
+    let reply = client.execute_raw(&["STAT", "key"]).await.unwrap();
+    assert_eq!(reply, rustvault::RawResponse::Stat(stat));
+    client.close().await.unwrap();
+    
+    node.crash().await.unwrap();
+    node.restart().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    let restored = client.stat("key").await.unwrap().unwrap();
+    assert_eq!(restored.version, 3);
+    assert!(restored.created_at.abs_diff(stat.created_at) < 1000);
+    client.set("key", "d").await.unwrap();
+    assert_eq!(client.stat("key").await.unwrap().unwrap().version, 4);
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_subscribe() {
+    use rustvault::KeyEvent;
+    
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let subscriber = Client::connect(&addr).await.unwrap();
+    let mut events = subscriber.subscribe("user:*").await.unwrap();
+    let mut writer = Client::connect(&addr).await.unwrap();
+    
+    writer.set("user:1", "alice").await.unwrap();
+    writer.set("order:1", "book").await.unwrap();
+    writer.mset(&[("user:2", "bob"), ("order:2", "pen")]).await.unwrap();
+    assert!(writer.delete("user:1").await.unwrap());
+    // Commands that change nothing send nothing
+    assert!(!writer.delete("user:9").await.unwrap());
+    assert!(!writer.cas("user:2", b"carol", b"dave").await.unwrap());
+    assert_eq!(writer.get("user:2").await.unwrap(), Some("bob".to_string()));
+    writer.incr("user:count", 1).await.unwrap();
+    assert_eq!(writer.getset("user:3", b"erin").await.unwrap(), None);
+    assert_eq!(writer.getdel("user:3").await.unwrap(), Some(b"erin".to_vec()));
+    assert_eq!(writer.getdel("user:3").await.unwrap(), None);
+    
+    let expected = [
+        KeyEvent::Set("user:1".to_string()),
+        KeyEvent::Set("user:2".to_string()),
+        KeyEvent::Del("user:1".to_string()),
+        KeyEvent::Set("user:count".to_string()),
+        KeyEvent::Set("user:3".to_string()),
+        KeyEvent::Del("user:3".to_string()),
+    ];
+    for event in expected {
+        let next = tokio::time::timeout(Duration::from_secs(5), events.next());
+        assert_eq!(next.await.unwrap().unwrap(), Some(event));
+    }
+    
+    // Nothing else was sent
+    let next = tokio::time::timeout(Duration::from_millis(200), events.next());
+    assert!(next.await.is_err());
+    
+    events.close().await.unwrap();
+    writer.close().await.unwrap();
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_transactions_are_never_seen_half_applied() {
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("a", "1000").await.unwrap();
+    client.set("b", "1000").await.unwrap();
+    
+    // Writers move one unit at a time between the two balances, retrying
+    // whenever the other writer got in first
+    let mut writers = Vec::new();
+    for (from, to) in [("a", "b"), ("b", "a")] {
+        let addr = addr.clone();
+        writers.push(tokio::spawn(async move {
+            let mut client = Client::connect(&addr).await.unwrap();
+            let mut conflicts = 0;
+            for _ in 0..50 {
+                loop {
+                    client.watch(&[from, to]).await.unwrap();
+                    let balances = client.mget(&[from, to]).await.unwrap();
+                    let [from_balance, to_balance]: [i64; 2] =
+                        [0, 1].map(|i| balances[i].as_deref().unwrap().parse().unwrap());
+                    let mut transaction = Transaction::new();
+                    transaction
+                        .set(from, (from_balance - 1).to_string())
+                        .set(to, (to_balance + 1).to_string());
+                    match transaction.exec(&mut client).await.unwrap() {
+                        Some(results) => {
+                            assert_eq!(results, vec![Response::Ok, Response::Ok]);
+                            break;
+                        }
+                        None => conflicts += 1,
+                    }
+                }
+            }
+            conflicts
+        }));
+    }
+    
+    let reader = {
+        let addr = addr.clone();
+        tokio::spawn(async move {
+            let mut client = Client::connect(&addr).await.unwrap();
+            let mut reads = 0;
+            loop {
+                let balances = client.mget(&["a", "b"]).await.unwrap();
+                let total: i64 = balances.iter().map(|b| b.as_deref().unwrap().parse::<i64>().unwrap()).sum();
+                assert_eq!(total, 2000, "saw a half-applied transfer: {:?}", balances);
+                reads += 1;
+                if client.exists("done").await.unwrap() {
+                    return reads;
+                }
+            }
+        })
+    };
+    
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    client.set("done", "1").await.unwrap();
+    assert!(reader.await.unwrap() > 0);
+    // Both writers moved 50 units each way
+    assert_eq!(client.get("a").await.unwrap(), Some("1000".to_string()));
+    
+    // Reads queued in the transaction see its own earlier writes
+    let mut transaction = Transaction::new();
+    transaction.set("c", "1").get("c").delete("c").get("c");
+    let results = transaction.exec(&mut client).await.unwrap().unwrap();
+    assert_eq!(results, vec![Response::Ok, Response::Value(b"1".to_vec()), Response::Ok, Response::NotFound]);
+    
+    // A command that can't be queued fails the whole transaction
+    assert_eq!(client.execute_raw(&["MULTI"]).await.unwrap(), RawResponse::Ok);
+    assert_eq!(client.execute_raw(&["SET", "c", "1"]).await.unwrap(), RawResponse::Queued);
+    assert!(matches!(client.execute_raw(&["INCR", "n"]).await.unwrap(), RawResponse::Error { .. }));
+    let reply = client.execute_raw(&["EXEC"]).await.unwrap();
+    assert!(matches!(reply, RawResponse::Error { code: Some(code), .. } if code == "ERR_EXECABORT"));
+    assert_eq!(client.get("c").await.unwrap(), None);
+    
+    assert_eq!(client.execute_raw(&["MULTI"]).await.unwrap(), RawResponse::Ok);
+    assert_eq!(client.execute_raw(&["GET", "a"]).await.unwrap(), RawResponse::Queued);
+    assert_eq!(
+        client.execute_raw(&["EXEC"]).await.unwrap(),
+        RawResponse::Results(vec![RawResponse::Value(b"1000".to_vec())])
+    );
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+/// Poll `key` on `client` until it reads as `expected`, failing after 5s
+async fn wait_for_value(client: &mut Client, key: &str, expected: Option<&str>) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let value = client.get(key).await.unwrap();
+        if value.as_deref() == expected {
+            return;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{} is {:?}, not {:?}", key, value, expected);
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_replica_follows_primary() {
+    let mut primary = TestNode::start().await.unwrap();
+    let mut writer = primary.client().await.unwrap();
+    for i in 0..50 {
+        writer.set(&format!("before:{}", i), &i.to_string()).await.unwrap();
+    }
+    writer.set_with_ttl("expiring", "soon", 3600).await.unwrap();
+    
+    let config = rustvault::ServerConfig {
+        replica_of: Some(primary.addr().to_string()),
+        ..Default::default()
+    };
+    let (replica, replica_task, replica_addr, _wal) = start_ephemeral_server_with(config).await;
+    let mut reader = Client::connect(&replica_addr).await.unwrap();
+    
+    // The full sync brings over what was there before the replica attached,
+    // in no particular order
+    wait_for_value(&mut reader, "before:49", Some("49")).await;
+    wait_for_value(&mut reader, "before:0", Some("0")).await;
+    wait_for_value(&mut reader, "expiring", Some("soon")).await;
+    
+    // Then every change as it happens
+    writer.set("live", "1").await.unwrap();
+    writer.delete("before:0").await.unwrap();
+    writer.incr("counter", 5).await.unwrap();
+    wait_for_value(&mut reader, "counter", Some("5")).await;
+    assert_eq!(reader.get("live").await.unwrap(), Some("1".to_string()));
+    assert_eq!(reader.get("before:0").await.unwrap(), None);
+    
+    // Keys outside the default namespace land in the same one on the replica
+    let mut app_writer = primary.client().await.unwrap();
+    app_writer.select("app").await.unwrap();
+    app_writer.set("live", "in app").await.unwrap();
+    let mut app_reader = Client::connect(&replica_addr).await.unwrap();
+    app_reader.select("app").await.unwrap();
+    wait_for_value(&mut app_reader, "live", Some("in app")).await;
+    assert_eq!(reader.get("live").await.unwrap(), Some("1".to_string()));
+    app_reader.close().await.unwrap();
+    
+    let refused = reader.set("live", "2").await.unwrap_err();
+    assert!(refused.to_string().contains("read only replica"), "{}", refused);
+    assert_eq!(reader.get("live").await.unwrap(), Some("1".to_string()));
+    
+    // The replica reconnects and catches up once the primary is back
+    primary.stop().await.unwrap();
+    primary.restart().await.unwrap();
+    let mut writer = primary.client().await.unwrap();
+    writer.set("after_restart", "yes").await.unwrap();
+    wait_for_value(&mut reader, "after_restart", Some("yes")).await;
+    wait_for_value(&mut reader, "counter", Some("5")).await;
+    
+    reader.close().await.unwrap();
+    replica.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), replica_task).await;
+}
+
+#[tokio::test]
+async fn test_namespaces_stay_apart_across_restart() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut plain = node.client().await.unwrap();
+    let mut app = node.client().await.unwrap();
+    app.select("app").await.unwrap();
+    
+    plain.set("user:1", "plain").await.unwrap();
+    app.set("user:1", "app").await.unwrap();
+    app.set("user:2", "app only").await.unwrap();
+    assert_eq!(plain.get("user:1").await.unwrap(), Some("plain".to_string()));
+    assert_eq!(app.get("user:1").await.unwrap(), Some("app".to_string()));
+    assert_eq!(plain.get("user:2").await.unwrap(), None);
+    assert_eq!(plain.db_size().await.unwrap(), 1);
+    assert_eq!(app.db_size().await.unwrap(), 2);
+    assert!(app.select("not a name").await.is_err());
+    
+    drop((plain, app));
+    node.stop().await.unwrap();
+    node.restart().await.unwrap();
+    
+    // The WAL recorded each key's namespace
+    let mut plain = node.client().await.unwrap();
+    let mut app = node.client().await.unwrap();
+    app.select("app").await.unwrap();
+    assert_eq!(plain.get("user:1").await.unwrap(), Some("plain".to_string()));
+    assert_eq!(app.get("user:1").await.unwrap(), Some("app".to_string()));
+    assert_eq!(app.get("user:2").await.unwrap(), Some("app only".to_string()));
+    assert_eq!(plain.get("user:2").await.unwrap(), None);
+    
+    let mut other = node.client().await.unwrap();
+    other.select("other").await.unwrap();
+    assert_eq!(other.get("user:1").await.unwrap(), None);
+    assert_eq!(other.db_size().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_backup_moves_keys_to_another_server() {
+    let source = TestNode::start().await.unwrap();
+    let mut client = source.client().await.unwrap();
+    for i in 0..100 {
+        client.set(&format!("key{}", i), &format!("value{}", i)).await.unwrap();
+    }
+    let path = source.data_dir().join("vault.backup");
+    let path = path.to_str().unwrap();
+    assert_eq!(client.backup(path).await.unwrap(), 100);
+    
+    let mut target = TestNode::start().await.unwrap();
+    let mut client = target.client().await.unwrap();
+    client.set("key0", "already here").await.unwrap();
+    let err = client.restore(path, false).await.unwrap_err();
+    assert_eq!(err.code(), Some(ErrorCode::Invalid));
+    assert_eq!(client.restore(path, true).await.unwrap(), 100);
+    assert_eq!(client.get("key0").await.unwrap(), Some("value0".to_string()));
+    
+    // The loaded keys were logged like any other SET
+    drop(client);
+    target.stop().await.unwrap();
+    target.restart().await.unwrap();
+    let mut client = target.client().await.unwrap();
+    assert_eq!(client.db_size().await.unwrap(), 100);
+    assert_eq!(client.get("key99").await.unwrap(), Some("value99".to_string()));
+}
+
+#[tokio::test]
+async fn test_delete_pattern_survives_restart() {
+    let mut node = TestNode::start().await.unwrap();
+    let mut client = node.client().await.unwrap();
+    for key in ["user:1", "user:10", "user:1:profile", "user:2", "users:1"] {
+        client.set(key, "v").await.unwrap();
+    }
+    assert_eq!(client.delete_pattern("user:1*").await.unwrap(), 3);
+    assert_eq!(client.delete_pattern("user:1*").await.unwrap(), 0);
+    client.set("user:10", "again").await.unwrap();
+    assert!(client.delete_pattern("*").await.is_err(), "every key needs allow_flush_all");
+    
+    drop(client);
+    node.stop().await.unwrap();
+    node.restart().await.unwrap();
+    
+    // Replay removed the same keys and kept the one written after
+    let mut client = node.client().await.unwrap();
+    assert_eq!(client.get("user:1").await.unwrap(), None);
+    assert_eq!(client.get("user:1:profile").await.unwrap(), None);
+    assert_eq!(client.get("user:10").await.unwrap(), Some("again".to_string()));
+    assert_eq!(client.db_size().await.unwrap(), 3);
+}
+
+/// Writer that records how much it was handed at once, optionally failing
+/// once a byte limit is reached
+struct ProbeWriter {
+    written: usize,
+    largest_write: usize,
+    fail_after: Option<usize>,
+}
+
+impl ProbeWriter {
+    fn new(fail_after: Option<usize>) -> Self {
+        Self {
+            written: 0,
+            largest_write: 0,
+            fail_after,
+        }
+    }
+}
+
+impl tokio::io::AsyncWrite for ProbeWriter {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        if self.fail_after.is_some_and(|limit| self.written >= limit) {
+            return std::task::Poll::Ready(Err(std::io::Error::other("disk full")));
+        }
+        self.written += buf.len();
+        self.largest_write = self.largest_write.max(buf.len());
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+    
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+    
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_get_streaming() {
+    use tokio::io::AsyncReadExt;
+    
+    // Large enough that buffering it whole would dwarf the read buffer
+    let size = 64 * 1024 * 1024;
+    let config = rustvault::ServerConfig {
+        max_value_bytes: size,
+        ..Default::default()
+    };
+    let (server, server_task, addr, _wal) = start_ephemeral_server_with(config).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set_stream_timeout(Some(Duration::from_secs(10)));
+    
+    let large: String = (0..size).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    client.set("large", &large).await.unwrap();
+    
+    let mut probe = ProbeWriter::new(None);
+    let copied = client.get_streaming("large", &mut probe).await.unwrap();
+    assert_eq!(copied, Some(size as u64));
+    assert_eq!(probe.written, size);
+    assert!(probe.largest_write <= 64 * 1024, "wrote {} bytes at once", probe.largest_write);
+    
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("large.bin");
+    let mut file = tokio::fs::File::create(&path).await.unwrap();
+    assert_eq!(client.get_streaming("large", &mut file).await.unwrap(), Some(size as u64));
+    drop(file);
+    let mut contents = String::new();
+    tokio::fs::File::open(&path).await.unwrap().read_to_string(&mut contents).await.unwrap();
+    assert!(contents == large);
+    
+    // Lone carriage returns are data, only the final CRLF is framing
+    let with_cr = "a\rb\r\rc";
+    client.set("cr", with_cr).await.unwrap();
+    let mut out = Vec::new();
+    assert_eq!(client.get_streaming("cr", &mut out).await.unwrap(), Some(with_cr.len() as u64));
+    assert_eq!(out, with_cr.as_bytes());
+    
+    let mut out = Vec::new();
+    assert_eq!(client.get_streaming("missing", &mut out).await.unwrap(), None);
+    assert!(out.is_empty());
+    
+    // The connection is still in sync after all of the above
+    assert_eq!(client.get("cr").await.unwrap(), Some(with_cr.to_string()));
+    
+    // A destination failing mid-value leaves the rest unread
+    let mut failing = ProbeWriter::new(Some(1024 * 1024));
+    assert!(client.get_streaming("large", &mut failing).await.is_err());
+    assert!(matches!(
+        client.get("cr").await,
+        Err(rustvault::RustVaultError::Client(_))
+    ));
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+#[tokio::test]
+async fn test_verify_replica_finds_diverged_keys() {
+    use rustvault::recovery::{self, ReplicaDivergence};
+    
+    let (primary_server, primary_task, primary_addr, _primary_wal) = start_ephemeral_server().await;
+    let (replica_server, replica_task, replica_addr, _replica_wal) = start_ephemeral_server().await;
+    let mut primary = Client::connect(&primary_addr).await.unwrap();
+    let mut replica = Client::connect(&replica_addr).await.unwrap();
+    
+    for i in 0..200 {
+        let (key, value) = (format!("user:{}", i), format!("profile{}", i));
+        primary.set(&key, &value).await.unwrap();
+        replica.set(&key, &value).await.unwrap();
+    }
+    assert!(recovery::verify_replica(&mut primary, &mut replica).await.unwrap().is_empty());
+    
+    // A stale value, a key the replica missed, one it shouldn't have, and a
+    // key that is a prefix of others
+    replica.set("user:42", "stale").await.unwrap();
+    primary.delete("user:7").await.unwrap();
+    replica.set("user:7x", "orphan").await.unwrap();
+    primary.set("user:1", "changed").await.unwrap();
+    
+    let divergences = recovery::verify_replica(&mut primary, &mut replica).await.unwrap();
+    let key = |key: &str, primary: Option<&str>, replica: Option<&str>| ReplicaDivergence::Key {
+        key: key.to_string(),
+        primary: primary.map(|v| v.as_bytes().to_vec()),
+        replica: replica.map(|v| v.as_bytes().to_vec()),
+    };
+    assert_eq!(
+        divergences,
+        vec![
+            key("user:1", Some("changed"), Some("profile1")),
+            key("user:42", Some("profile42"), Some("stale")),
+            key("user:7", None, Some("profile7")),
+            key("user:7x", None, Some("orphan")),
+        ]
+    );
+    
+    for (server, task) in [(primary_server, primary_task), (replica_server, replica_task)] {
+        server.shutdown().unwrap();
+        let _ = tokio::time::timeout(Duration::from_secs(5), task).await;
+    }
+}
+
+#[tokio::test]
+async fn test_consistency_checker_against_live_server() {
+    use rustvault::recovery::{self, Divergence, KeyState, Keyspace};
+    
+    let temp_file = NamedTempFile::new().unwrap();
+    let wal_path = temp_file.path().to_string_lossy().to_string();
+    let port = 18087;
+    let addr = format!("127.0.0.1:{}", port);
+    
+    let _server_handle = start_test_server(port, wal_path.clone()).await;
+    wait_for_server(&addr).await.unwrap();
+    
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("check_key1", "value1").await.unwrap();
+    client.set("check_key2", "value2").await.unwrap();
+    client.set("check_key3", "value3").await.unwrap();
+    client.set("check_key4", "value4").await.unwrap();
+    client.delete("check_key4").await.unwrap();
+    
+    // A consistent WAL passes the checker binary
+    let checker = env!("CARGO_BIN_EXE_rustvault-check");
+    let status = tokio::process::Command::new(checker)
+        .arg(&wal_path)
+        .arg(&addr)
+        .output()
+        .await
+        .unwrap()
+        .status;
+    assert_eq!(status.code(), Some(0));
+    
+    // Keep a stale copy of the WAL, then let the server move on
+    let stale_file = NamedTempFile::new().unwrap();
+    std::fs::copy(&wal_path, stale_file.path()).unwrap();
+    client.set("check_key2", "changed").await.unwrap();
+    client.delete("check_key3").await.unwrap();
+    client.set("check_key4", "back").await.unwrap();
+    
+    let stale = Keyspace::replay(stale_file.path()).unwrap();
+    let divergences = recovery::diff_against_server(&stale, &mut client).await.unwrap();
+    assert_eq!(
+        divergences,
+        vec![
+            Divergence::Differs {
+                key: "check_key2".to_string(),
+                expected: KeyState { value: b"value2".to_vec(), seq: 2 },
+                actual: b"changed".to_vec(),
+            },
+            Divergence::Missing {
+                key: "check_key3".to_string(),
+                expected: KeyState { value: b"value3".to_vec(), seq: 3 },
+            },
+            Divergence::Extra {
+                key: "check_key4".to_string(),
+                value: b"back".to_vec(),
+                deleted_seq: Some(5),
+            },
+        ]
+    );
+    
+    let output = tokio::process::Command::new(checker)
+        .arg(stale_file.path())
+        .arg(&addr)
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("missing  check_key3 (last written at WAL seq 3)"));
+    
+    client.close().await.unwrap();
+}
+
+/// Feed `script` to the interactive client binary connected to `addr`
+async fn run_cli(addr: &str, script: &str) -> String {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+    
+    let mut child = tokio::process::Command::new(env!("CARGO_BIN_EXE_client"))
+        .arg(addr)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(script.as_bytes()).await.unwrap();
+    drop(stdin);
+    
+    let output = tokio::time::timeout(Duration::from_secs(10), child.wait_with_output())
+        .await
+        .expect("client did not exit")
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[tokio::test]
+async fn test_cli_multi_server_session() {
+    let primary_wal = NamedTempFile::new().unwrap();
+    let replica_wal = NamedTempFile::new().unwrap();
+    let primary_addr = "127.0.0.1:18093";
+    let replica_addr = "127.0.0.1:18094";
+    
+    let _primary = start_test_server(18093, primary_wal.path().to_string_lossy().to_string()).await;
+    let _replica = start_test_server(18094, replica_wal.path().to_string_lossy().to_string()).await;
+    wait_for_server(primary_addr).await.unwrap();
+    wait_for_server(replica_addr).await.unwrap();
+    
+    let mut primary = Client::connect(primary_addr).await.unwrap();
+    primary.set("cli_key", "new").await.unwrap();
+    let mut replica = Client::connect(replica_addr).await.unwrap();
+    replica.set("cli_key", "old").await.unwrap();
+    
+    // Before the replica catches up, \all shows each server's own answer
+    let script = format!(
+        "\\connect {} replica\n\\list\n\\all get cli_key\n\\connect 127.0.0.1:1 down\n\\use {}\nget cli_key\n",
+        replica_addr, primary_addr
+    );
+    let stdout = run_cli(primary_addr, &script).await;
+    assert!(stdout.contains(&format!("[{}] new", primary_addr)), "{}", stdout);
+    assert!(stdout.contains("[replica] old"), "{}", stdout);
+    assert!(
+        stdout.lines().any(|l| l.starts_with("* replica") && l.ends_with(replica_addr)),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("failed to connect to 127.0.0.1:1"), "{}", stdout);
+    // The failed connection left the others usable, and the prompt follows \use
+    assert!(stdout.contains(&format!("{}> new", primary_addr)), "{}", stdout);
+    
+    // Once the values agree, so does \all
+    replica.set("cli_key", "new").await.unwrap();
+    let script = format!("\\connect {} replica\n\\all get cli_key\n", replica_addr);
+    let stdout = run_cli(primary_addr, &script).await;
+    assert!(stdout.contains(&format!("[{}] new", primary_addr)), "{}", stdout);
+    assert!(stdout.contains("[replica] new"), "{}", stdout);
+    
+    primary.close().await.unwrap();
+    replica.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_cli_dump_loads_into_another_server() {
+    use std::process::Stdio;
+    
+    let (_source, _source_task, source_addr, _source_wal) = start_ephemeral_server().await;
+    let (_target, _target_task, target_addr, _target_wal) = start_ephemeral_server().await;
+    let mut source = Client::connect(&source_addr).await.unwrap();
+    let mut pipeline = Pipeline::new();
+    for i in 0..5000 {
+        // Some values need escaping to fit on a dump line
+        let value = match i % 4 {
+            0 => format!("value {}", i),
+            1 => format!("tab\t{}", i),
+            2 => format!("line\n{}\r\n", i),
+            _ => format!("back\\slash\\t{}", i),
+        };
+        pipeline.set(&format!("dump:{:04}", i), value);
+    }
+    pipeline.set("other", "not dumped");
+    assert!(pipeline.execute(&mut source).await.unwrap().iter().all(|r| *r == Response::Ok));
+    
+    let dump = tokio::process::Command::new(env!("CARGO_BIN_EXE_client"))
+        .args([source_addr.as_str(), "dump", "dump:"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .unwrap();
+    assert!(dump.status.success(), "{}", String::from_utf8_lossy(&dump.stderr));
+    assert!(String::from_utf8_lossy(&dump.stderr).contains("Dumped 5000 keys"));
+    assert_eq!(dump.stdout.iter().filter(|&&b| b == b'\n').count(), 5000);
+    let dump_file = NamedTempFile::new().unwrap();
+    std::fs::write(dump_file.path(), &dump.stdout).unwrap();
+    
+    let load = tokio::process::Command::new(env!("CARGO_BIN_EXE_client"))
+        .arg(&target_addr)
+        .arg("load")
+        .arg(dump_file.path())
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .unwrap();
+    assert!(load.status.success(), "{}", String::from_utf8_lossy(&load.stderr));
+    assert_eq!(String::from_utf8_lossy(&load.stdout).trim(), "Loaded 5000 keys");
+    
+    async fn everything(client: &mut Client) -> Vec<(String, String)> {
+        let mut pairs = Vec::new();
+        let mut stream = client.scan_stream("");
+        while let Some(pair) = stream.next().await {
+            pairs.push(pair.unwrap());
+        }
+        pairs.sort();
+        pairs
+    }
+    let mut target = Client::connect(&target_addr).await.unwrap();
+    let mut expected = everything(&mut source).await;
+    expected.retain(|(key, _)| key != "other");
+    assert_eq!(expected.len(), 5000);
+    assert_eq!(everything(&mut target).await, expected);
+    
+    source.close().await.unwrap();
+    target.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_error_handling() {
+    // A port that doesn't fit in 16 bits is caught before connecting
+    let result = Client::connect("127.0.0.1:99999").await;
+    assert!(matches!(result, Err(RustVaultError::InvalidAddress(_))));
+    
+    // Nothing listens on a port just released
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let result = Client::connect(&format!("127.0.0.1:{}", port)).await;
+    assert!(matches!(result, Err(RustVaultError::Io(_))));
+}
+
+#[tokio::test]
+async fn test_error_replies_carry_codes() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    
+    let (server, server_task, addr, _wal) = start_ephemeral_server().await;
+    
+    // On the wire the code is the first word after ERROR
+    let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+    stream.write_all(b"FROB key\r\n").await.unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("ERROR ERR_PARSE "), "{:?}", line);
+    
+    // and the client hands it back apart from the message
+    let mut client = Client::connect(&addr).await.unwrap();
+    match client.set(&"k".repeat(1025), "v").await {
+        Err(RustVaultError::Remote { code, message }) => {
+            assert_eq!(code, ErrorCode::TooLarge);
+            assert_eq!(message, "key too large (max 1024)");
+        }
+        other => panic!("expected a coded error, got {:?}", other),
+    }
+    assert!(matches!(
+        client.execute_raw(&["GET"]).await.unwrap(),
+        RawResponse::Error { code: Some(code), .. } if code == "ERR_PARSE"
+    ));
+    client.close().await.unwrap();
+    
+    server.shutdown().unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), server_task).await;
+}
+
+/// Serve the WAL at `wal_path` with FLUSHALL enabled, until shut down
+async fn start_flushable_server(
+    wal_path: &std::path::Path,
+) -> (std::sync::Arc<rustvault::RustVaultServer>, tokio::task::JoinHandle<rustvault::Result<()>>, String) {
+    let config = rustvault::ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        wal_path: wal_path.to_string_lossy().to_string(),
+        allow_flush_all: true,
+        ..Default::default()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = std::sync::Arc::new(rustvault::RustVaultServer::new(config).await.unwrap());
+    let task = {
+        let server = std::sync::Arc::clone(&server);
+        tokio::spawn(async move { server.run_with_listener(listener).await })
+    };
+    wait_for_server(&addr).await.unwrap();
+    (server, task, addr)
+}
+
+#[tokio::test]
+async fn test_embedded_vault_reopens_with_its_data() {
+    use rustvault::{Store, Vault};
+    
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vault.log");
+    let vault = Vault::open(&path).await.unwrap();
+    vault.set("a".to_string(), b"1".to_vec()).await.unwrap();
+    vault.set_with_ttl("b".to_string(), b"2".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    vault.set("gone".to_string(), b"3".to_vec()).await.unwrap();
+    vault.delete("gone").await.unwrap();
+    assert_eq!(vault.incr("n", 5).await.unwrap(), 5);
+    vault.flush().await.unwrap();
+    drop(vault);
+    
+    let vault = Vault::open(&path).await.unwrap();
+    assert_eq!(vault.get("a").await.unwrap(), Some(b"1".to_vec()));
+    let (value, deadline) = vault.get_with_deadline("b").await.unwrap().unwrap();
+    assert_eq!((value, deadline.is_some()), (b"2".to_vec(), true));
+    assert!(!vault.exists("gone").await.unwrap());
+    assert_eq!(vault.get("n").await.unwrap(), Some(b"5".to_vec()));
+    assert_eq!(vault.len().await.unwrap(), 3);
+    
+    // A compacted log reopens the same way
+    vault.compact().await.unwrap();
+    drop(vault);
+    let vault = Vault::open(&path).await.unwrap();
+    assert_eq!(vault.len().await.unwrap(), 3);
+    assert_eq!(vault.get("a").await.unwrap(), Some(b"1".to_vec()));
+}
+
+#[tokio::test]
+async fn test_flush_all_survives_restart() {
+    // Disabled by default
+    let (_server, _server_task, addr, _temp_file) = start_ephemeral_server().await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("kept", "1").await.unwrap();
+    let refused = client.flush_all().await;
+    assert!(matches!(
+        refused,
+        Err(RustVaultError::Remote { code: ErrorCode::NotPermitted, message }) if message == "command disabled"
+    ));
+    assert_eq!(client.get("kept").await.unwrap(), Some("1".to_string()));
+    
+    let temp_file = NamedTempFile::new().unwrap();
+    let (server, task, addr) = start_flushable_server(temp_file.path()).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("old1", "1").await.unwrap();
+    client.set("old2", "2").await.unwrap();
+    client.flush_all().await.unwrap();
+    client.set("new", "3").await.unwrap();
+    assert_eq!(client.get("old1").await.unwrap(), None);
+    server.shutdown().unwrap();
+    task.await.unwrap().unwrap();
+    
+    // Replay empties the store where the flush was logged
+    let (server, _task, addr) = start_flushable_server(temp_file.path()).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    assert_eq!(client.get("old1").await.unwrap(), None);
+    assert_eq!(client.get("old2").await.unwrap(), None);
+    assert_eq!(client.get("new").await.unwrap(), Some("3".to_string()));
+    server.shutdown().unwrap();
+}
+
+#[tokio::test]
+async fn test_client_reconnects_after_server_restart() {
+    use rustvault::ClientConfig;
+    
+    let mut node = TestNode::start().await.unwrap();
+    let config = ClientConfig {
+        retries: 8,
+        backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let mut client = Client::connect_with_config(node.addr(), config).await.unwrap();
+    client.set("before", "1").await.unwrap();
+    
+    // Restarted between two commands: the closed connection is noticed
+    // before anything is written to it, so even a write goes through
+    node.restart().await.unwrap();
+    client.set("after", "2").await.unwrap();
+    assert_eq!(client.get("before").await.unwrap(), Some("1".to_string()));
+    
+    // Down when the command is sent: retried until the node is back
+    node.crash().await.unwrap();
+    let restart = tokio::spawn(async move {
+        sleep(Duration::from_millis(150)).await;
+        node.restart().await.unwrap();
+        node
+    });
+    assert_eq!(client.get("after").await.unwrap(), Some("2".to_string()));
+    let mut node = restart.await.unwrap();
+    
+    // Without retries the error surfaces, and the client stays broken
+    let mut plain = Client::connect(node.addr()).await.unwrap();
+    node.restart().await.unwrap();
+    assert!(plain.get("after").await.is_err());
+    assert!(plain.get("after").await.is_err());
+}
+
+/// Forward connections from a new address to `target`, tracking the most
+/// that were open at once
+async fn start_counting_proxy(target: String) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let open = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let proxy_peak = Arc::clone(&peak);
+    tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+            proxy_peak.fetch_max(now, Ordering::SeqCst);
+            let open = Arc::clone(&open);
+            let target = target.clone();
+            tokio::spawn(async move {
+                if let Ok(mut outbound) = tokio::net::TcpStream::connect(&target).await {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    (addr, peak)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_client_pool_shared_by_many_tasks() {
+    use rustvault::{ClientPool, PoolConfig};
+    
+    let (_server, _server_task, server_addr, _wal) = start_ephemeral_server().await;
+    let (addr, peak) = start_counting_proxy(server_addr).await;
+    let config = PoolConfig {
+        min: 2,
+        max: 8,
+        idle_timeout: Duration::from_secs(60),
+    };
+    let pool = ClientPool::connect(&addr, config).await.unwrap();
+    
+    let tasks: Vec<_> = (0..50)
+        .map(|task| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                for i in 0..20 {
+                    let key = format!("pool:{}:{}", task, i);
+                    let mut client = pool.get().await.unwrap();
+                    client.set(&key, &i.to_string()).await.unwrap();
+                    assert_eq!(client.get(&key).await.unwrap(), Some(i.to_string()));
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    
+    assert!(peak.load(std::sync::atomic::Ordering::SeqCst) <= 8);
+    let stats = pool.stats();
+    assert!(stats.connections <= 8 && stats.connections >= 2, "{:?}", stats);
+    assert_eq!(stats.idle, stats.connections);
+    assert_eq!(stats.discarded, 0);
+    
+    let mut client = pool.get().await.unwrap();
+    assert_eq!(client.get("pool:49:19").await.unwrap(), Some("19".to_string()));
+}
+
+/// A [`futures_core::Stream`] that yields an iterator's items as soon as they're polled
+struct IterStream<I>(I);
+
+impl<I: Iterator + Unpin> futures_core::Stream for IterStream<I> {
+    type Item = I::Item;
+    
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<I::Item>> {
+        std::task::Poll::Ready(self.0.next())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_pool_load_from_stream() {
+    use rustvault::{ClientPool, LoadOptions, OnLoadError, PoolConfig};
+    
+    let (_server, _server_task, server_addr, _wal) = start_ephemeral_server().await;
+    let (addr, peak) = start_counting_proxy(server_addr).await;
+    let config = PoolConfig {
+        min: 1,
+        max: 8,
+        idle_timeout: Duration::from_secs(60),
+    };
+    let pool = ClientPool::connect(&addr, config).await.unwrap();
+    
+    // Every 50_000th pair carries a key the server refuses as too large,
+    // which fails its whole MSET; one more key can't be sent at all
+    let num_pairs = 200_000;
+    let oversized = |i: usize| format!("{}{}", "k".repeat(2000), i);
+    let items = (0..num_pairs)
+        .map(move |i| {
+            let key = if i % 50_000 == 49_999 { oversized(i) } else { format!("bulk:{}", i) };
+            (key, format!("value_{}", i))
+        })
+        .chain(std::iter::once(("bad key".to_string(), "orphan".to_string())));
+    let options = LoadOptions {
+        concurrency: 4,
+        ..LoadOptions::default()
+    };
+    
+    let report = pool.load_from_stream(IterStream(items), options).await.unwrap();
+    assert_eq!(report.loaded, num_pairs - 4);
+    let mut failed: Vec<_> = report.failed.iter().map(|(key, _)| key.clone()).collect();
+    failed.sort();
+    let mut expected: Vec<_> = [49_999, 99_999, 149_999, 199_999].into_iter().map(oversized).collect();
+    expected.push("bad key".to_string());
+    expected.sort();
+    assert_eq!(failed, expected);
+    assert!(peak.load(std::sync::atomic::Ordering::SeqCst) <= 4);
+    
+    // The rest of each refused batch still landed
+    let mut client = pool.get().await.unwrap();
+    assert_eq!(client.db_size().await.unwrap(), num_pairs - 4);
+    assert_eq!(client.get("bulk:49998").await.unwrap(), Some("value_49998".to_string()));
+    assert_eq!(client.get("bulk:50000").await.unwrap(), Some("value_50000".to_string()));
+    drop(client);
+    
+    // Stopping at the first refusal reads no further than the batch after
+    // it, and every pair read is loaded, refused or left unsent
+    let read = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = std::sync::Arc::clone(&read);
+    let items = (0..10_000)
+        .map(|i| {
+            let key = if i == 10 { oversized(i) } else { format!("stop:{}", i) };
+            (key, b"v".to_vec())
+        })
+        .inspect(move |_| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+    let options = LoadOptions {
+        concurrency: 1,
+        batch_size: 100,
+        on_error: OnLoadError::Stop,
+        ..LoadOptions::default()
+    };
+    let report = pool.load_from_stream(IterStream(items), options).await.unwrap();
+    assert_eq!(report.failed.len(), 1);
+    assert!(report.loaded < 300, "{}", report.loaded);
+    assert!(!report.unsent.is_empty());
+    let read = read.load(std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(report.loaded + report.failed.len() + report.unsent.len(), read);
+    let mut client = pool.get().await.unwrap();
+    assert_eq!(client.get(&report.unsent[0]).await.unwrap(), None);
+    drop(client);
+    
+    // A key that can't be sent stops the load before the batch it ends
+    let items = (0..10_000).map(|i| {
+        let key = if i == 50 { "bad key".to_string() } else { format!("unsent:{}", i) };
+        (key, b"v".to_vec())
+    });
+    let report = pool.load_from_stream(IterStream(items), options).await.unwrap();
+    assert_eq!(report.loaded, 0);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.unsent, (0..50).map(|i| format!("unsent:{}", i)).collect::<Vec<_>>());
+    assert_eq!(pool.stats().discarded, 0);
+}
+
+#[cfg(feature = "redis-migrate")]
+mod redis_migrate {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    
+    /// Value held by the mock Redis server
+    enum MockValue {
+        String(&'static str, i64),
+        Hash,
+    }
+    
+    /// Start a minimal RESP server answering SCAN, TYPE, GET and PTTL
+    async fn start_mock_redis(port: u16, data: Vec<(&'static str, MockValue)>) {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await.unwrap();
+        let data = Arc::new(data);
+        
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let data = Arc::clone(&data);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut reader = BufReader::new(reader);
+                    while let Some(args) = read_request(&mut reader).await {
+                        let reply = mock_reply(&data, &args);
+                        if writer.write_all(&reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+    
+    async fn read_request(reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            args.push(String::from_utf8(arg).ok()?);
+        }
+        Some(args)
+    }
+    
+    fn bulk(value: &str) -> String {
+        format!("${}\r\n{}\r\n", value.len(), value)
+    }
+    
+    fn mock_reply(data: &[(&'static str, MockValue)], args: &[String]) -> Vec<u8> {
+        let lookup = |key: &str| data.iter().find(|(k, _)| *k == key).map(|(_, v)| v);
+        let reply = match args[0].to_uppercase().as_str() {
+            "SCAN" => {
+                let cursor: usize = args[1].parse().unwrap();
+                let prefix = args[3].trim_end_matches('*');
+                let count: usize = args[5].parse().unwrap();
+                let end = (cursor + count).min(data.len());
+                let keys: Vec<&str> = data[cursor..end]
+                    .iter()
+                    .map(|(k, _)| *k)
+                    .filter(|k| k.starts_with(prefix))
+                    .collect();
+                let next = if end == data.len() { 0 } else { end };
+                let mut reply = format!("*2\r\n{}*{}\r\n", bulk(&next.to_string()), keys.len());
+                for key in keys {
+                    reply.push_str(&bulk(key));
+                }
+                reply
+            }
+            "TYPE" => match lookup(&args[1]) {
+                Some(MockValue::String(..)) => "+string\r\n".to_string(),
+                Some(MockValue::Hash) => "+hash\r\n".to_string(),
+                None => "+none\r\n".to_string(),
+            },
+            "GET" => match lookup(&args[1]) {
+                Some(MockValue::String(value, _)) => bulk(value),
+                Some(MockValue::Hash) => "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string(),
+                None => "$-1\r\n".to_string(),
+            },
+            "PTTL" => match lookup(&args[1]) {
+                Some(MockValue::String(_, ttl)) => format!(":{}\r\n", ttl),
+                _ => ":-2\r\n".to_string(),
+            },
+            _ => "+OK\r\n".to_string(),
+        };
+        reply.into_bytes()
+    }
+    
+    fn mock_dataset() -> Vec<(&'static str, MockValue)> {
+        let mut data: Vec<(&'static str, MockValue)> = [
+            "user:0", "user:1", "user:2", "user:3", "user:4", "user:5", "user:6", "user:7", "user:8", "user:9",
+        ]
+        .into_iter()
+        .map(|key| (key, MockValue::String("profile", -1)))
+        .collect();
+        data.push(("user:ttl", MockValue::String("session", 60_000)));
+        data.push(("user:h", MockValue::Hash));
+        data.push(("user:multi", MockValue::String("line1\nline2", -1)));
+        data.push(("other:1", MockValue::String("elsewhere", -1)));
+        data.push(("user:empty", MockValue::String("", -1)));
+        data.push(("user:padded", MockValue::String("  padded  ", -1)));
+        data.push(("user:brief", MockValue::String("fleeting", 3_000)));
+        data.push(("user:a b", MockValue::String("spaced key", -1)));
+        data
+    }
+    
+    async fn run_migrate(args: &[&str]) -> std::process::Output {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_rustvault-migrate"))
+            .args(args)
+            .output()
+            .await
+            .unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_migrate_from_mock_redis() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal_path = temp_file.path().to_string_lossy().to_string();
+        let port = 18088;
+        let addr = format!("127.0.0.1:{}", port);
+        let redis_port = 18089;
+        
+        let _server_handle = start_test_server(port, wal_path).await;
+        wait_for_server(&addr).await.unwrap();
+        start_mock_redis(redis_port, mock_dataset()).await;
+        
+        let source = format!("redis://127.0.0.1:{}", redis_port);
+        let started = tokio::time::Instant::now();
+        let output = run_migrate(&[
+            "--source", &source, "--target", &addr, "--pattern", "user:*",
+            "--concurrency", "3", "--scan-count", "4",
+        ]).await;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(0), "{}", stdout);
+        assert!(stdout.contains("Keys migrated: 15"), "{}", stdout);
+        assert!(stdout.contains("Migrated with their TTL: 2"));
+        assert!(stdout.contains("Skipped (unsupported type): 1"));
+        assert!(stdout.contains("Skipped (key not representable): 1"));
+        assert!(stdout.contains("mismatched") && !stdout.contains("mismatch:"));
+        
+        // Values the line form can't carry arrive intact
+        let mut client = Client::connect(&addr).await.unwrap();
+        assert_eq!(client.get("user:5").await.unwrap(), Some("profile".to_string()));
+        assert_eq!(client.get("user:multi").await.unwrap(), Some("line1\nline2".to_string()));
+        assert_eq!(client.get("user:empty").await.unwrap(), Some(String::new()));
+        assert_eq!(client.get("user:padded").await.unwrap(), Some("  padded  ".to_string()));
+        assert_eq!(client.get("user:h").await.unwrap(), None);
+        assert_eq!(client.get("other:1").await.unwrap(), None);
+        
+        // TTLs carry over: the short one runs out, the long one doesn't
+        assert_eq!(client.get("user:ttl").await.unwrap(), Some("session".to_string()));
+        tokio::time::sleep_until(started + Duration::from_millis(3_500)).await;
+        assert_eq!(client.get("user:brief").await.unwrap(), None);
+        assert_eq!(client.get("user:ttl").await.unwrap(), Some("session".to_string()));
+        client.close().await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_migrate_resumes_from_cursor_file() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal_path = temp_file.path().to_string_lossy().to_string();
+        let port = 18090;
+        let addr = format!("127.0.0.1:{}", port);
+        let redis_port = 18091;
+        
+        let _server_handle = start_test_server(port, wal_path).await;
+        wait_for_server(&addr).await.unwrap();
+        start_mock_redis(redis_port, mock_dataset()).await;
+        
+        // Pretend an earlier run got as far as cursor 8
+        let cursor_dir = tempfile::tempdir().unwrap();
+        let cursor_file = cursor_dir.path().join("cursor");
+        std::fs::write(&cursor_file, "8").unwrap();
+        
+        let source = format!("redis://127.0.0.1:{}", redis_port);
+        let output = run_migrate(&[
+            "--source", &source, "--target", &addr, "--pattern", "user:*",
+            "--scan-count", "4", "--cursor-file", &cursor_file.to_string_lossy(),
+        ]).await;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(0), "{}", stdout);
+        assert!(stdout.contains("Resuming from SCAN cursor 8"));
+        assert!(stdout.contains("Keys migrated: 7"), "{}", stdout);
+        assert!(!cursor_file.exists());
+        
+        let mut client = Client::connect(&addr).await.unwrap();
+        assert_eq!(client.get("user:0").await.unwrap(), None);
+        assert_eq!(client.get("user:9").await.unwrap(), Some("profile".to_string()));
+        client.close().await.unwrap();
+    }
 }
\ No newline at end of file